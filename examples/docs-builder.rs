@@ -15,7 +15,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     toolchain.install(&workspace)?;
 
     // Fetch lazy_static from crates.io
-    let krate = Crate::crates_io("lazy_static", "1.0.0");
+    let krate = Crate::crates_io("lazy_static", "1.0.0")?;
     krate.fetch(&workspace)?;
 
     // Configure a sandbox with 1GB of RAM and no network access
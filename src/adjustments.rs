@@ -0,0 +1,77 @@
+use crate::cmd::{Command, SandboxBuilder};
+use crate::Crate;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub(crate) type AdjustmentsPolicy = Arc<dyn Fn(&Crate) -> SandboxAdjustments + Send + Sync>;
+
+/// Overrides of the sandbox limits for a single crate, returned by the policy configured with
+/// [`WorkspaceBuilder::sandbox_adjustments`](struct.WorkspaceBuilder.html#method.sandbox_adjustments).
+///
+/// Each limit that is set replaces the one configured on the
+/// [`SandboxBuilder`](cmd/struct.SandboxBuilder.html) of the build (or the default timeouts of
+/// the workspace), while the other limits are kept as they are. This allows known-heavy crates
+/// to get bigger limits in bulk runs, without raising them for every crate.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SandboxAdjustments {
+    memory_limit: Option<usize>,
+    cpu_limit: Option<f32>,
+    timeout: Option<Duration>,
+    no_output_timeout: Option<Duration>,
+}
+
+impl SandboxAdjustments {
+    /// Create new adjustments, keeping all the limits of the build.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the memory limit of the sandbox, in bytes.
+    pub fn memory_limit(mut self, limit: usize) -> Self {
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    /// Replace the CPU limit of the sandbox, as a fraction of the CPU cores.
+    pub fn cpu_limit(mut self, limit: f32) -> Self {
+        self.cpu_limit = Some(limit);
+        self
+    }
+
+    /// Replace the timeout of the commands executed inside the sandbox.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Replace the no output timeout of the commands executed inside the sandbox.
+    pub fn no_output_timeout(mut self, timeout: Duration) -> Self {
+        self.no_output_timeout = Some(timeout);
+        self
+    }
+
+    /// Check whether no limit is replaced.
+    pub fn is_empty(&self) -> bool {
+        *self == SandboxAdjustments::default()
+    }
+
+    pub(crate) fn apply_to_sandbox(&self, mut sandbox: SandboxBuilder) -> SandboxBuilder {
+        if let Some(limit) = self.memory_limit {
+            sandbox = sandbox.memory_limit(Some(limit));
+        }
+        if let Some(limit) = self.cpu_limit {
+            sandbox = sandbox.cpu_limit(Some(limit));
+        }
+        sandbox
+    }
+
+    pub(crate) fn apply_to_command<'w, 'pl>(&self, mut cmd: Command<'w, 'pl>) -> Command<'w, 'pl> {
+        if let Some(timeout) = self.timeout {
+            cmd = cmd.timeout(Some(timeout));
+        }
+        if let Some(timeout) = self.no_output_timeout {
+            cmd = cmd.no_output_timeout(Some(timeout));
+        }
+        cmd
+    }
+}
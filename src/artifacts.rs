@@ -0,0 +1,340 @@
+use crate::build::profile_dirs;
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::path::Path;
+
+/// Kind of an [`ArtifactSize`](struct.ArtifactSize.html).
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum ArtifactKind {
+    /// A Rust library (`.rlib`).
+    Rlib,
+    /// An executable.
+    Binary,
+    /// A dynamic library (`.so`, `.dylib` or `.dll`).
+    DynamicLibrary,
+    /// A static library (`.a` or `.lib`).
+    StaticLibrary,
+}
+
+/// Size of an artifact produced by a build.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArtifactSize {
+    path: String,
+    kind: ArtifactKind,
+    size: u64,
+    sections: Option<BTreeMap<String, u64>>,
+}
+
+impl ArtifactSize {
+    /// Return the path of the artifact, relative to the target directory (like `debug/foo`).
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Return the kind of the artifact.
+    pub fn kind(&self) -> ArtifactKind {
+        self.kind
+    }
+
+    /// Return the size of the artifact in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Return the size in bytes of each section of the artifact, if the breakdown was requested
+    /// and the artifact is a little-endian ELF binary or dynamic library.
+    pub fn sections(&self) -> Option<&BTreeMap<String, u64>> {
+        self.sections.as_ref()
+    }
+}
+
+/// Sizes of the artifacts produced by a build, created by
+/// [`Build::artifact_report`](struct.Build.html#method.artifact_report).
+///
+/// Only the final artifacts of the crate are included (the ones placed directly in the profile
+/// directories, like `target/debug`), not the artifacts of its dependencies. The report can be
+/// serialized, and compared with the report of the same crate built with another toolchain to
+/// track size regressions.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArtifactReport {
+    artifacts: Vec<ArtifactSize>,
+    doc_size: Option<u64>,
+}
+
+impl ArtifactReport {
+    pub(crate) fn collect(target_dir: &Path, sections: bool) -> Result<Self, Error> {
+        let mut report = ArtifactReport {
+            artifacts: Vec::new(),
+            doc_size: None,
+        };
+        if !target_dir.is_dir() {
+            return Ok(report);
+        }
+
+        for profile in profile_dirs(target_dir)? {
+            for entry in std::fs::read_dir(&profile)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_file() {
+                    continue;
+                }
+                let path = entry.path();
+                let kind = match artifact_kind(&path) {
+                    Some(kind) => kind,
+                    None => continue,
+                };
+
+                let sections = match kind {
+                    ArtifactKind::Binary | ArtifactKind::DynamicLibrary if sections => {
+                        elf_sections(&std::fs::read(&path)?)
+                    }
+                    _ => None,
+                };
+                report.artifacts.push(ArtifactSize {
+                    path: relative_path(target_dir, &path),
+                    kind,
+                    size: entry.metadata()?.len(),
+                    sections,
+                });
+            }
+        }
+        report.artifacts.sort_by(|a, b| a.path.cmp(&b.path));
+
+        // Documentation is generated either in `target/doc` or in `target/{target}/doc`.
+        for entry in walkdir::WalkDir::new(target_dir).min_depth(1).max_depth(2) {
+            let entry = entry?;
+            if entry.file_type().is_dir() && entry.file_name() == "doc" {
+                let size = crate::utils::dir_size(entry.path())?;
+                report.doc_size = Some(report.doc_size.unwrap_or(0) + size);
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Return the artifacts produced by the build, sorted by path.
+    pub fn artifacts(&self) -> &[ArtifactSize] {
+        &self.artifacts
+    }
+
+    /// Return the artifact at the provided path, relative to the target directory.
+    pub fn artifact(&self, path: &str) -> Option<&ArtifactSize> {
+        self.artifacts.iter().find(|artifact| artifact.path == path)
+    }
+
+    /// Return the total size in bytes of the generated documentation, if any was generated.
+    pub fn doc_size(&self) -> Option<u64> {
+        self.doc_size
+    }
+
+    /// Return the total size in bytes of all the artifacts, excluding the documentation.
+    pub fn total_size(&self) -> u64 {
+        self.artifacts.iter().map(|artifact| artifact.size).sum()
+    }
+
+    /// Get the paths of the artifacts whose size differs between the two reports, including the
+    /// ones present in only one of them, along with their size in each report.
+    pub fn size_differences<'a>(
+        &'a self,
+        other: &'a ArtifactReport,
+    ) -> Vec<(&'a str, Option<u64>, Option<u64>)> {
+        let size = |report: &ArtifactReport, path: &str| report.artifact(path).map(|a| a.size);
+        self.artifacts
+            .iter()
+            .chain(
+                other
+                    .artifacts
+                    .iter()
+                    .filter(|artifact| self.artifact(&artifact.path).is_none()),
+            )
+            .map(|artifact| artifact.path.as_str())
+            .map(|path| (path, size(self, path), size(other, path)))
+            .filter(|(_, before, after)| before != after)
+            .collect()
+    }
+}
+
+fn artifact_kind(path: &Path) -> Option<ArtifactKind> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("rlib") => Some(ArtifactKind::Rlib),
+        Some("so") | Some("dylib") | Some("dll") => Some(ArtifactKind::DynamicLibrary),
+        Some("a") | Some("lib") => Some(ArtifactKind::StaticLibrary),
+        Some("exe") => Some(ArtifactKind::Binary),
+        Some(_) => None,
+        None if is_executable(path) => Some(ArtifactKind::Binary),
+        None => None,
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path)
+        .map(|metadata| metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(windows)]
+fn is_executable(_path: &Path) -> bool {
+    // Executables on Windows always have the `.exe` extension.
+    false
+}
+
+fn relative_path(base: &Path, path: &Path) -> String {
+    path.strip_prefix(base)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+
+/// Type of the ELF sections not occupying any space in the file, like `.bss`.
+const SHT_NOBITS: u32 = 8;
+
+/// Read the size of each section of a little-endian ELF file, returning `None` if the content is
+/// not a valid ELF file.
+fn elf_sections(data: &[u8]) -> Option<BTreeMap<String, u64>> {
+    if data.get(0..4)? != b"\x7fELF" || *data.get(5)? != 1 {
+        return None;
+    }
+    let is_64 = match data.get(4)? {
+        1 => false,
+        2 => true,
+        _ => return None,
+    };
+
+    let u16_at = |off: usize| Some(u16::from_le_bytes(data.get(off..off + 2)?.try_into().ok()?));
+    let u32_at = |off: usize| Some(u32::from_le_bytes(data.get(off..off + 4)?.try_into().ok()?));
+    let u64_at = |off: usize| Some(u64::from_le_bytes(data.get(off..off + 8)?.try_into().ok()?));
+    let word_at = |off32: usize, off64: usize| {
+        if is_64 {
+            u64_at(off64)
+        } else {
+            u32_at(off32).map(u64::from)
+        }
+    };
+
+    let table: usize = word_at(0x20, 0x28)?.try_into().ok()?;
+    let (entry_size, count, names_index) = if is_64 {
+        (u16_at(0x3a)?, u16_at(0x3c)?, u16_at(0x3e)?)
+    } else {
+        (u16_at(0x2e)?, u16_at(0x30)?, u16_at(0x32)?)
+    };
+    let header = |index: u16| table + index as usize * entry_size as usize;
+    let names: usize = word_at(header(names_index) + 0x10, header(names_index) + 0x18)?
+        .try_into()
+        .ok()?;
+
+    let mut sections = BTreeMap::new();
+    // The first section is always the null section.
+    for index in 1..count {
+        let header = header(index);
+        if u32_at(header + 4)? == SHT_NOBITS {
+            continue;
+        }
+        let name_start = names + u32_at(header)? as usize;
+        let name_len = data.get(name_start..)?.iter().position(|&b| b == 0)?;
+        let name = String::from_utf8_lossy(&data[name_start..name_start + name_len]);
+        let size = word_at(header + 0x14, header + 0x20)?;
+        *sections.entry(name.into_owned()).or_insert(0) += size;
+    }
+    Some(sections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{elf_sections, ArtifactKind, ArtifactReport};
+    use failure::Error;
+    use std::collections::BTreeMap;
+
+    /// Build a minimal 64-bit little-endian ELF file with the provided sections.
+    fn elf(sections: &[(&str, u32, u64)]) -> Vec<u8> {
+        let mut names = vec![0u8];
+        let mut offsets = Vec::new();
+        for (name, _, _) in sections {
+            offsets.push(names.len() as u32);
+            names.extend(name.as_bytes());
+            names.push(0);
+        }
+        offsets.push(names.len() as u32);
+        names.extend(b".shstrtab\0");
+
+        let names_offset = 0x40u64;
+        let table = names_offset + names.len() as u64;
+        let mut data = vec![0u8; 0x40];
+        data[0..4].copy_from_slice(b"\x7fELF");
+        data[4] = 2;
+        data[5] = 1;
+        data[0x28..0x30].copy_from_slice(&table.to_le_bytes());
+        data[0x3a..0x3c].copy_from_slice(&64u16.to_le_bytes());
+        data[0x3c..0x3e].copy_from_slice(&(sections.len() as u16 + 2).to_le_bytes());
+        data[0x3e..0x40].copy_from_slice(&(sections.len() as u16 + 1).to_le_bytes());
+        data.extend(&names);
+
+        let section = |name: u32, kind: u32, offset: u64, size: u64| {
+            let mut header = vec![0u8; 64];
+            header[0..4].copy_from_slice(&name.to_le_bytes());
+            header[4..8].copy_from_slice(&kind.to_le_bytes());
+            header[0x18..0x20].copy_from_slice(&offset.to_le_bytes());
+            header[0x20..0x28].copy_from_slice(&size.to_le_bytes());
+            header
+        };
+        data.extend(section(0, 0, 0, 0));
+        for (i, (_, kind, size)) in sections.iter().enumerate() {
+            data.extend(section(offsets[i], *kind, 0, *size));
+        }
+        data.extend(section(
+            *offsets.last().unwrap(),
+            3,
+            names_offset,
+            names.len() as u64,
+        ));
+        data
+    }
+
+    #[test]
+    fn test_elf_sections() {
+        let data = elf(&[(".text", 1, 1000), (".rodata", 1, 200), (".bss", 8, 50)]);
+        let mut expected = BTreeMap::new();
+        expected.insert(".text".to_string(), 1000);
+        expected.insert(".rodata".to_string(), 200);
+        expected.insert(".shstrtab".to_string(), 30);
+        assert_eq!(elf_sections(&data), Some(expected));
+
+        assert_eq!(elf_sections(b"#!/bin/sh\n"), None);
+        assert_eq!(elf_sections(&data[..0x50]), None);
+    }
+
+    #[test]
+    fn test_collect() -> Result<(), Error> {
+        let target = tempfile::tempdir()?;
+        let debug = target.path().join("debug");
+        std::fs::create_dir_all(debug.join(".fingerprint"))?;
+        std::fs::create_dir_all(debug.join("deps"))?;
+        std::fs::create_dir_all(target.path().join("doc/foo"))?;
+        std::fs::write(debug.join("libfoo.rlib"), vec![0; 100])?;
+        std::fs::write(debug.join("libfoo.d"), "")?;
+        std::fs::write(debug.join("deps/libbar-0123456789abcdef.rlib"), vec![0; 10])?;
+        std::fs::write(target.path().join("doc/foo/index.html"), vec![0; 42])?;
+
+        let report = ArtifactReport::collect(target.path(), true)?;
+        assert_eq!(report.artifacts().len(), 1);
+        let rlib = report.artifact("debug/libfoo.rlib").unwrap();
+        assert_eq!(rlib.kind(), ArtifactKind::Rlib);
+        assert_eq!(rlib.size(), 100);
+        assert!(rlib.sections().is_none());
+        assert_eq!(report.doc_size(), Some(42));
+
+        std::fs::write(debug.join("libfoo.rlib"), vec![0; 120])?;
+        let other = ArtifactReport::collect(target.path(), true)?;
+        assert_eq!(
+            report.size_differences(&other),
+            vec![("debug/libfoo.rlib", Some(100), Some(120))]
+        );
+        assert!(report.size_differences(&report).is_empty());
+
+        Ok(())
+    }
+}
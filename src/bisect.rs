@@ -0,0 +1,387 @@
+use crate::build::CratePatch;
+use crate::cmd::SandboxBuilder;
+use crate::{Build, BuildDirectory, Crate, Toolchain, Workspace};
+use failure::{Error, ResultExt};
+use log::{info, warn};
+use serde::Deserialize;
+use std::path::Path;
+
+/// GitHub API endpoint comparing two commits of the rust-lang/rust repository.
+static GITHUB_COMPARE_URL: &str = "https://api.github.com/repos/rust-lang/rust/compare";
+/// Author of the merge commits of rust-lang/rust, the only ones with CI artifacts.
+static BORS: &str = "bors";
+
+/// Builder bisecting the CI artifacts of rust-lang/rust to find the commit changing the outcome of
+/// a build, created from
+/// [`BuildDirectory::bisect_ci`](struct.BuildDirectory.html#method.bisect_ci).
+///
+/// The merge commits between the good and the bad commit are retrieved from the GitHub API, and
+/// the CI toolchains of the commits are downloaded on demand while bisecting. This is the core
+/// of [cargo-bisect-rustc][bisect-rustc], available as a library primitive.
+///
+/// The crate's source code is fetched and prepared only once with the toolchain of the good
+/// commit, and the same source directory, lockfile and target directory are reused by every
+/// build. CI artifacts are only kept for a limited time, so old commits can't be bisected.
+///
+/// [bisect-rustc]: https://github.com/rust-lang/cargo-bisect-rustc
+pub struct CiBisection<'a> {
+    build_dir: &'a mut BuildDirectory,
+    krate: &'a Crate,
+    good: String,
+    bad: String,
+    alt: bool,
+    keep_toolchains: bool,
+    sandbox: SandboxBuilder,
+    patches: Vec<CratePatch>,
+}
+
+impl<'a> CiBisection<'a> {
+    pub(crate) fn new(
+        build_dir: &'a mut BuildDirectory,
+        krate: &'a Crate,
+        good: &str,
+        bad: &str,
+        sandbox: SandboxBuilder,
+    ) -> Self {
+        CiBisection {
+            build_dir,
+            krate,
+            good: good.into(),
+            bad: bad.into(),
+            alt: false,
+            keep_toolchains: false,
+            sandbox,
+            patches: Vec::new(),
+        }
+    }
+
+    /// Add a patch to this build.
+    /// Patches get added to the crate's Cargo.toml in the `patch.crates-io` table, and are shared
+    /// by the builds with all the toolchains.
+    pub fn patch_with_git(mut self, name: &str, uri: &str, branch: &str) -> Self {
+        self.patches.push(CratePatch {
+            name: name.into(),
+            uri: uri.into(),
+            branch: branch.into(),
+        });
+
+        self
+    }
+
+    /// Use the "alt" CI artifacts, built with extra compiler assertions enabled. By default the
+    /// normal artifacts are used.
+    pub fn alt(mut self, alt: bool) -> Self {
+        self.alt = alt;
+        self
+    }
+
+    /// Keep the CI toolchains installed after the bisection. By default each toolchain is
+    /// uninstalled right after building the crate with it, to save disk space.
+    pub fn keep_toolchains(mut self, keep: bool) -> Self {
+        self.keep_toolchains = keep;
+        self
+    }
+
+    /// Run the bisection. The closure will be called once per commit tried with an instance of
+    /// [`Build`](struct.Build.html) configured to use the toolchain of that commit, and its
+    /// outcome (succeeding or returning an error) is what's bisected.
+    ///
+    /// An error is returned if the outcome is the same for the good and the bad commit, if the
+    /// list of commits can't be retrieved, if a toolchain can't be installed or if preparing the
+    /// crate fails.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # async fn run() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init().await?;
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// let bisection = build_dir
+    ///     .bisect_ci(&krate, "<good sha>", "<bad sha>", sandbox)
+    ///     .run(|build| {
+    ///         build.cargo().args(&["test"]).run()?;
+    ///         Ok(())
+    ///     })
+    ///     .await?;
+    /// println!("regressed in {}", bisection.first_changed());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn run<F: FnMut(&Build) -> Result<(), Error>>(
+        self,
+        mut f: F,
+    ) -> Result<BisectionResult, Error> {
+        let workspace = self.build_dir.workspace();
+        let commits = list_ci_commits(workspace, &self.good, &self.bad).await?;
+        let toolchains: Vec<_> = commits
+            .iter()
+            .map(|sha| Toolchain::ci(sha, self.alt))
+            .collect();
+        info!(
+            "bisecting {} commits between {} and {}",
+            toolchains.len(),
+            self.good,
+            self.bad
+        );
+
+        install(workspace, &toolchains[0]).await?;
+        let source_dir = self
+            .build_dir
+            .prepare_shared(&toolchains[0], self.krate, self.patches, None)
+            .await?;
+
+        let mut runner = Runner {
+            build_dir: &*self.build_dir,
+            source_dir: &source_dir,
+            krate: self.krate,
+            toolchains: &toolchains,
+            sandbox: &self.sandbox,
+            keep_toolchains: self.keep_toolchains,
+            f: &mut f,
+            builds: 0,
+        };
+        let res = bisect_outcomes(&mut runner).await;
+        let builds = runner.builds;
+        remove_dir_all::remove_dir_all(&source_dir)?;
+
+        let (good_outcome, low, high) = match res? {
+            Some(res) => res,
+            None => failure::bail!(
+                "the outcome of the build is the same with {} and {}",
+                self.good,
+                self.bad
+            ),
+        };
+        Ok(BisectionResult {
+            first_changed: toolchains[high].clone(),
+            last_unchanged: toolchains[low].clone(),
+            good_outcome,
+            commits: toolchains.len(),
+            builds,
+        })
+    }
+}
+
+/// Find the first commit whose outcome differs from the good one, returning the outcome of the
+/// good commit and the indexes of the last unchanged and the first changed commit. Returns `None`
+/// if the bad commit has the same outcome as the good one.
+async fn bisect_outcomes<F: FnMut(&Build) -> Result<(), Error>>(
+    runner: &mut Runner<'_, F>,
+) -> Result<Option<(bool, usize, usize)>, Error> {
+    let last = runner.toolchains.len() - 1;
+    let good_outcome = runner.try_commit(0).await?;
+    if runner.try_commit(last).await? == good_outcome {
+        return Ok(None);
+    }
+
+    // The outcome with `low` is the same as the good commit, while with `high` it changed.
+    let (mut low, mut high) = (0, last);
+    while high - low > 1 {
+        let mid = low + (high - low) / 2;
+        if runner.try_commit(mid).await? == good_outcome {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    Ok(Some((good_outcome, low, high)))
+}
+
+struct Runner<'a, F> {
+    build_dir: &'a BuildDirectory,
+    source_dir: &'a Path,
+    krate: &'a Crate,
+    toolchains: &'a [Toolchain],
+    sandbox: &'a SandboxBuilder,
+    keep_toolchains: bool,
+    f: &'a mut F,
+    builds: usize,
+}
+
+impl<F: FnMut(&Build) -> Result<(), Error>> Runner<'_, F> {
+    /// Build the crate with the CI toolchain of a commit, returning whether the build succeeded.
+    async fn try_commit(&mut self, index: usize) -> Result<bool, Error> {
+        let workspace = self.build_dir.workspace();
+        let toolchain = &self.toolchains[index];
+        install(workspace, toolchain).await?;
+
+        info!("building with toolchain {}", toolchain);
+        self.builds += 1;
+        let success = self
+            .build_dir
+            .build_shared(self.source_dir, self.krate, toolchain, self.sandbox, self.f)
+            .await
+            .is_ok();
+        info!(
+            "the build with toolchain {} {}",
+            toolchain,
+            if success { "succeeded" } else { "failed" }
+        );
+
+        if !self.keep_toolchains {
+            if let Err(err) = toolchain.uninstall(workspace).await {
+                warn!("failed to uninstall toolchain {}: {}", toolchain, err);
+            }
+        }
+        Ok(success)
+    }
+}
+
+/// Install a CI toolchain, explaining the most likely cause of failures.
+async fn install(workspace: &Workspace, toolchain: &Toolchain) -> Result<(), Error> {
+    toolchain.install(workspace).await.with_context(|_| {
+        format!(
+            "failed to install the CI artifacts of {}, which might have expired",
+            toolchain
+        )
+    })?;
+    Ok(())
+}
+
+/// Result of a [`CiBisection`](struct.CiBisection.html).
+#[derive(Debug, Clone)]
+pub struct BisectionResult {
+    first_changed: Toolchain,
+    last_unchanged: Toolchain,
+    good_outcome: bool,
+    commits: usize,
+    builds: usize,
+}
+
+impl BisectionResult {
+    /// Return the toolchain of the first commit changing the outcome of the build.
+    pub fn first_changed(&self) -> &Toolchain {
+        &self.first_changed
+    }
+
+    /// Return the toolchain of the last commit with the same outcome as the good commit.
+    pub fn last_unchanged(&self) -> &Toolchain {
+        &self.last_unchanged
+    }
+
+    /// Check whether the build succeeded with the good commit. If it did the bisection found a
+    /// regression, otherwise it found a fix.
+    pub fn is_regression(&self) -> bool {
+        self.good_outcome
+    }
+
+    /// Return the number of commits in the bisected range, including the good and the bad one.
+    pub fn commits(&self) -> usize {
+        self.commits
+    }
+
+    /// Return the number of builds executed during the bisection.
+    pub fn builds(&self) -> usize {
+        self.builds
+    }
+}
+
+#[derive(Deserialize)]
+struct Comparison {
+    total_commits: usize,
+    commits: Vec<GithubCommit>,
+}
+
+#[derive(Deserialize)]
+struct GithubCommit {
+    sha: String,
+    commit: CommitDetails,
+}
+
+#[derive(Deserialize)]
+struct CommitDetails {
+    author: CommitAuthor,
+}
+
+#[derive(Deserialize)]
+struct CommitAuthor {
+    name: String,
+}
+
+/// List the merge commits with CI artifacts from `good` to `bad`, both included.
+async fn list_ci_commits(
+    workspace: &Workspace,
+    good: &str,
+    bad: &str,
+) -> Result<Vec<String>, Error> {
+    let url = format!("{}/{}...{}", GITHUB_COMPARE_URL, good, bad);
+    let content = crate::download::download_bytes(workspace, &url)
+        .await
+        .with_context(|_| format!("failed to list the commits between {} and {}", good, bad))?;
+    parse_ci_commits(&serde_json::from_slice(&content)?, good, bad)
+}
+
+fn parse_ci_commits(comparison: &Comparison, good: &str, bad: &str) -> Result<Vec<String>, Error> {
+    if comparison.commits.len() < comparison.total_commits {
+        failure::bail!(
+            "too many commits between {} and {} ({}), narrow down the range",
+            good,
+            bad,
+            comparison.total_commits
+        );
+    }
+
+    // The compared commits don't include the base one.
+    let mut commits = vec![good.to_string()];
+    commits.extend(
+        comparison
+            .commits
+            .iter()
+            .filter(|commit| commit.commit.author.name == BORS)
+            .map(|commit| commit.sha.clone()),
+    );
+    if commits.len() < 2 || !commits.last().unwrap().starts_with(bad) {
+        failure::bail!("{} is not a merge commit with CI artifacts", bad);
+    }
+    Ok(commits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_ci_commits, Comparison};
+    use failure::Error;
+
+    fn comparison(total: usize, commits: &[(&str, &str)]) -> Comparison {
+        let commits = commits
+            .iter()
+            .map(|(sha, author)| {
+                serde_json::json!({ "sha": sha, "commit": { "author": { "name": author } } })
+            })
+            .collect::<Vec<_>>();
+        serde_json::from_value(serde_json::json!({
+            "total_commits": total,
+            "commits": commits,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_ci_commits() -> Result<(), Error> {
+        let commits = comparison(
+            4,
+            &[
+                ("pr1", "alice"),
+                ("m1", "bors"),
+                ("pr2", "bob"),
+                ("m2", "bors"),
+            ],
+        );
+        assert_eq!(
+            parse_ci_commits(&commits, "m0", "m2")?,
+            vec!["m0", "m1", "m2"]
+        );
+
+        // The bad commit must have CI artifacts.
+        assert!(parse_ci_commits(&commits, "m0", "pr2").is_err());
+
+        // Truncated comparisons are rejected.
+        let commits = comparison(300, &[("m1", "bors")]);
+        assert!(parse_ci_commits(&commits, "m0", "m1").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+use failure::{Error, Fail};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Error returned when a build is started after the [`RunBudget`](struct.RunBudget.html) of the
+/// workspace was exhausted.
+#[derive(Debug, Fail)]
+pub enum BudgetExceeded {
+    /// More time than the budget allows passed since the first build of the run started. The
+    /// budget's value (in seconds) is the first value.
+    #[fail(display = "the run exceeded its wall time budget of {} seconds", _0)]
+    WallTime(u64),
+    /// The build directories used by the run take more disk space than the budget allows.
+    #[fail(
+        display = "the run uses {} bytes of disk space, more than the {} bytes allowed",
+        usage, limit
+    )]
+    DiskUsage {
+        /// Disk space used by the build directories, in bytes.
+        usage: u64,
+        /// Maximum disk space allowed by the budget, in bytes.
+        limit: u64,
+    },
+    /// The run already started as many builds as the budget allows. The budget's value is the
+    /// first value.
+    #[fail(display = "the run exceeded its budget of {} builds", _0)]
+    Builds(usize),
+    #[doc(hidden)]
+    #[fail(display = "this error shouldn't have happened")]
+    __NonExaustive,
+}
+
+#[derive(Default)]
+struct BudgetUsage {
+    started: Option<Instant>,
+    builds: usize,
+    disk_usage: HashMap<String, u64>,
+}
+
+/// Global limits of an experiment run, shared by all the builds of a workspace configured with
+/// [`WorkspaceBuilder::run_budget`](struct.WorkspaceBuilder.html#method.run_budget).
+///
+/// Before starting each build the budget is checked, and once any of its limits is reached the
+/// build fails right away with [`BudgetExceeded`](enum.BudgetExceeded.html), without preparing
+/// the crate. Builds already running are not interrupted. Comparisons, MSRV searches and CI
+/// bisections count as a single build.
+///
+/// The budget can be cloned, and all the clones share the same usage, allowing the caller to
+/// inspect it while the run progresses.
+///
+/// # Example
+///
+/// ```no_run
+/// # use rustwide::{RunBudget, WorkspaceBuilder};
+/// # use std::error::Error;
+/// # use std::time::Duration;
+/// # async fn run() -> Result<(), Box<dyn Error>> {
+/// let budget = RunBudget::new()
+///     .max_wall_time(Duration::from_secs(24 * 60 * 60))
+///     .max_disk_usage(500 * 1024 * 1024 * 1024)
+///     .max_builds(10_000);
+/// let workspace = WorkspaceBuilder::new("work".as_ref(), "my-app")
+///     .run_budget(budget.clone())
+///     .init()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct RunBudget {
+    usage: Arc<Mutex<BudgetUsage>>,
+    max_wall_time: Option<Duration>,
+    max_disk_usage: Option<u64>,
+    max_builds: Option<usize>,
+}
+
+impl RunBudget {
+    /// Create a new budget without any limit.
+    pub fn new() -> Self {
+        RunBudget {
+            usage: Arc::new(Mutex::new(BudgetUsage::default())),
+            max_wall_time: None,
+            max_disk_usage: None,
+            max_builds: None,
+        }
+    }
+
+    /// Limit the time passed since the first build of the run started.
+    pub fn max_wall_time(mut self, limit: Duration) -> Self {
+        self.max_wall_time = Some(limit);
+        self
+    }
+
+    /// Limit the disk space (in bytes) used by the build directories of the run. The size of
+    /// each build directory is measured at the end of every build made in it.
+    pub fn max_disk_usage(mut self, limit: u64) -> Self {
+        self.max_disk_usage = Some(limit);
+        self
+    }
+
+    /// Limit the number of builds started during the run.
+    pub fn max_builds(mut self, limit: usize) -> Self {
+        self.max_builds = Some(limit);
+        self
+    }
+
+    /// Get the time passed since the first build of the run started.
+    pub fn elapsed(&self) -> Duration {
+        self.usage
+            .lock()
+            .unwrap()
+            .started
+            .map(|started| started.elapsed())
+            .unwrap_or_default()
+    }
+
+    /// Get the disk space (in bytes) used by the build directories of the run, as measured at
+    /// the end of their latest build. Disk usage is only measured if it's limited.
+    pub fn disk_usage(&self) -> u64 {
+        self.usage.lock().unwrap().disk_usage.values().sum()
+    }
+
+    /// Get the number of builds started during the run.
+    pub fn builds(&self) -> usize {
+        self.usage.lock().unwrap().builds
+    }
+
+    /// Record the start of a build, failing if the budget is exhausted.
+    pub(crate) fn start_build(&self) -> Result<(), Error> {
+        let mut usage = self.usage.lock().unwrap();
+        let started = *usage.started.get_or_insert_with(Instant::now);
+
+        if let Some(limit) = self.max_wall_time {
+            if started.elapsed() >= limit {
+                return Err(BudgetExceeded::WallTime(limit.as_secs()).into());
+            }
+        }
+        if let Some(limit) = self.max_disk_usage {
+            let disk_usage = usage.disk_usage.values().sum();
+            if disk_usage >= limit {
+                return Err(BudgetExceeded::DiskUsage {
+                    usage: disk_usage,
+                    limit,
+                }
+                .into());
+            }
+        }
+        if let Some(limit) = self.max_builds {
+            if usage.builds >= limit {
+                return Err(BudgetExceeded::Builds(limit).into());
+            }
+        }
+
+        usage.builds += 1;
+        Ok(())
+    }
+
+    /// Check whether the disk usage needs to be measured.
+    pub(crate) fn limits_disk_usage(&self) -> bool {
+        self.max_disk_usage.is_some()
+    }
+
+    /// Record the size of a build directory, replacing its previous size.
+    pub(crate) fn record_disk_usage(&self, build_dir: &str, size: u64) {
+        self.usage
+            .lock()
+            .unwrap()
+            .disk_usage
+            .insert(build_dir.to_string(), size);
+    }
+}
+
+impl Default for RunBudget {
+    fn default() -> Self {
+        RunBudget::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BudgetExceeded, RunBudget};
+    use std::time::Duration;
+
+    #[test]
+    fn test_max_builds() {
+        let budget = RunBudget::new().max_builds(2);
+        let shared = budget.clone();
+        budget.start_build().unwrap();
+        shared.start_build().unwrap();
+
+        let err = budget.start_build().unwrap_err();
+        match err.downcast_ref::<BudgetExceeded>() {
+            Some(BudgetExceeded::Builds(2)) => {}
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert_eq!(shared.builds(), 2);
+    }
+
+    #[test]
+    fn test_max_disk_usage() {
+        let budget = RunBudget::new().max_disk_usage(100);
+        budget.start_build().unwrap();
+        budget.record_disk_usage("a", 60);
+        budget.start_build().unwrap();
+
+        // The size of a build directory is replaced when it's measured again.
+        budget.record_disk_usage("a", 30);
+        budget.start_build().unwrap();
+        budget.record_disk_usage("b", 70);
+        assert_eq!(budget.disk_usage(), 100);
+
+        let err = budget.start_build().unwrap_err();
+        match err.downcast_ref::<BudgetExceeded>() {
+            Some(BudgetExceeded::DiskUsage {
+                usage: 100,
+                limit: 100,
+            }) => {}
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_max_wall_time() {
+        let budget = RunBudget::new().max_wall_time(Duration::from_millis(50));
+        budget.start_build().unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(budget.start_build().is_err());
+        assert!(budget.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -1,11 +1,36 @@
-use crate::cmd::{Command, MountKind, Runnable, SandboxBuilder};
+use crate::adjustments::SandboxAdjustments;
+use crate::artifacts::ArtifactReport;
+use crate::cmd::{
+    Command, EnvironmentSnapshot, MountKind, NetworkRecorder, NetworkUsage, Runnable,
+    SandboxBuilder, SnapshotRecorder,
+};
+use crate::diff::BuildSummary;
+use crate::journal::{JournalEntry, Operation};
 use crate::prepare::Prepare;
-use crate::{Crate, Toolchain, Workspace};
+use crate::provenance::ProvenanceInputs;
+use crate::source_writes::SourceState;
+use crate::storage::{StorageBackend, StoredObject};
+use crate::{
+    CiBisection, CodeExecutionPolicy, ComparisonBuild, Crate, EditionMigration, MsrvSearch,
+    Provenance, SemverCheck, SourceWrites, StorageError, Toolchain, Workspace, WorkspaceMember,
+};
 use failure::Error;
+use futures_util::future::BoxFuture;
+use log::{info, warn};
 use remove_dir_all::remove_dir_all;
-use std::path::PathBuf;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
 use std::vec::Vec;
 
+/// File inside each build directory recording its name, as the directory on disk is named after a
+/// hash of it.
+static NAME_FILE: &str = ".rustwide-name";
+
+/// Value of `SOURCE_DATE_EPOCH` in deterministic builds: 1980-01-01T00:00:00Z, the earliest date
+/// that can be stored in zip archives.
+const DETERMINISTIC_SOURCE_DATE_EPOCH: u64 = 315_532_800;
+
 #[derive(Clone)]
 pub(crate) struct CratePatch {
     pub(crate) name: String,
@@ -30,6 +55,15 @@ pub struct BuildBuilder<'a> {
     krate: &'a Crate,
     sandbox: SandboxBuilder,
     patches: Vec<CratePatch>,
+    env_passthrough_prefixes: Vec<String>,
+    source_dir_name: Option<String>,
+    lockfile_toolchain: Option<&'a Toolchain>,
+    home_size_limit: Option<u64>,
+    code_execution_policy: CodeExecutionPolicy,
+    record_provenance: bool,
+    deterministic: bool,
+    record_source_writes: bool,
+    record_network_usage: bool,
 }
 
 impl<'a> BuildBuilder<'a> {
@@ -64,6 +98,304 @@ impl<'a> BuildBuilder<'a> {
         self
     }
 
+    /// Forward all the environment variables of the host whose name starts with the provided
+    /// prefix into the sandboxed build. This method can be called multiple times to forward
+    /// variables matching different prefixes.
+    ///
+    /// The values of the forwarded variables are redacted from the logs, as they might contain
+    /// secrets. Variables whose name or value isn't valid UTF-8 are not forwarded.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::dist("");
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// build_dir.build(&toolchain, &krate, sandbox)
+    ///     .env_passthrough_prefix("MYCI_")
+    ///     .run(|build| {
+    ///         build.cargo().args(&["test", "--all"]).run()?;
+    ///         Ok(())
+    ///     })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn env_passthrough_prefix(mut self, prefix: &str) -> Self {
+        self.env_passthrough_prefixes.push(prefix.into());
+        self
+    }
+
+    /// Prepare the crate's source code in a directory with the provided name, placed inside the
+    /// build directory, instead of the default `source` directory. This allows external tools to
+    /// locate the source code deterministically.
+    ///
+    /// The name must be a single path component, other than the `target` and `home` directories
+    /// used by the build directory: an error will be returned when running the build otherwise.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::dist("");
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// build_dir.build(&toolchain, &krate, sandbox)
+    ///     .source_dir_name("foo-1.0.0")
+    ///     .run(|build| {
+    ///         assert!(build.host_source_dir().ends_with("foo-1.0.0"));
+    ///         Ok(())
+    ///     })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn source_dir_name(mut self, name: &str) -> Self {
+        self.source_dir_name = Some(name.into());
+        self
+    }
+
+    /// Generate the crate's lockfile with a different toolchain than the one used for the build.
+    ///
+    /// This is useful for old crates whose dependency graph can only be resolved by an older
+    /// cargo, or to test the minimum supported Rust version of a crate with a lockfile generated
+    /// by a newer cargo. The lockfile toolchain is used only when the crate doesn't include a
+    /// lockfile, or when the included one is outdated. Both toolchains need to be installed.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let msrv = Toolchain::dist("1.31.0");
+    /// let stable = Toolchain::dist("stable");
+    ///
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// build_dir.build(&msrv, &krate, sandbox)
+    ///     .lockfile_toolchain(&stable)
+    ///     .run(|build| {
+    ///         build.cargo().args(&["test", "--all"]).run()?;
+    ///         Ok(())
+    ///     })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn lockfile_toolchain(mut self, toolchain: &'a Toolchain) -> Self {
+        self.lockfile_toolchain = Some(toolchain);
+        self
+    }
+
+    /// Limit the size of the scratch home directory of the build, in bytes. If the files written
+    /// to it exceed the limit, the build fails with
+    /// [`StorageError::HomeSizeLimitExceeded`](enum.StorageError.html) once the closure returns.
+    ///
+    /// By default no limit is present.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::dist("");
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// build_dir.build(&toolchain, &krate, sandbox)
+    ///     .home_size_limit(Some(512 * 1024 * 1024))
+    ///     .run(|build| {
+    ///         build.cargo().args(&["test", "--all"]).run()?;
+    ///         Ok(())
+    ///     })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn home_size_limit(mut self, limit: Option<u64>) -> Self {
+        self.home_size_limit = limit;
+        self
+    }
+
+    /// Restrict the code the crate and its dependencies can execute at build time, like build
+    /// scripts and procedural macros. See [`CodeExecutionPolicy`](enum.CodeExecutionPolicy.html)
+    /// for the available policies.
+    ///
+    /// By default all the code is allowed to execute.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, CodeExecutionPolicy, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::dist("");
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// build_dir.build(&toolchain, &krate, sandbox)
+    ///     .code_execution_policy(CodeExecutionPolicy::Forbid)
+    ///     .run(|build| {
+    ///         build.cargo().args(&["check", "--offline"]).run()?;
+    ///         Ok(())
+    ///     })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn code_execution_policy(mut self, policy: CodeExecutionPolicy) -> Self {
+        self.code_execution_policy = policy;
+        self
+    }
+
+    /// Record the inputs of the build, so that a [`Provenance`](struct.Provenance.html) record
+    /// can be created with [`Build::provenance`](struct.Build.html#method.provenance).
+    ///
+    /// When enabled, the prepared source code is hashed before the closure is called, which
+    /// requires reading all of its files. By default provenance is not recorded.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::dist("");
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// build_dir.build(&toolchain, &krate, sandbox)
+    ///     .record_provenance(true)
+    ///     .run(|build| {
+    ///         build.cargo().args(&["build", "--release"]).run()?;
+    ///         Ok(())
+    ///     })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn record_provenance(mut self, enable: bool) -> Self {
+        self.record_provenance = enable;
+        self
+    }
+
+    /// Run the build in a deterministic environment, removing the sources of variation between
+    /// builds of the same crate on different machines or at different times.
+    ///
+    /// When enabled, the sandbox image is resolved to its ID before the build starts, so every
+    /// command runs in the same image even if its tag is moved in the meantime. Every command then
+    /// gets `SOURCE_DATE_EPOCH` set to a fixed date, the `UTC` timezone, the `C` locale and
+    /// incremental compilation disabled, and `CARGO_BUILD_RUSTFLAGS` remaps the source directory,
+    /// `CARGO_HOME` and the target directory to fixed paths with `--remap-path-prefix`. Setting
+    /// `RUSTFLAGS` in the build overrides the remapping, as cargo ignores `CARGO_BUILD_RUSTFLAGS`
+    /// when it's present. By default builds are not deterministic.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::dist("");
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// build_dir.build(&toolchain, &krate, sandbox)
+    ///     .deterministic(true)
+    ///     .record_provenance(true)
+    ///     .run(|build| {
+    ///         build.cargo().args(&["build", "--release"]).run()?;
+    ///         Ok(())
+    ///     })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn deterministic(mut self, enable: bool) -> Self {
+        self.deterministic = enable;
+        self
+    }
+
+    /// Record the state of the prepared source directory before the closure is called, so that
+    /// the files the build writes outside of the target directory can be retrieved with
+    /// [`Build::source_writes`](struct.Build.html#method.source_writes).
+    ///
+    /// Only the metadata of the files is recorded, but every file of the source directory has
+    /// to be listed before and after the build. By default the source directory is not tracked.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::dist("");
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// build_dir.build(&toolchain, &krate, sandbox)
+    ///     .record_source_writes(true)
+    ///     .run(|build| {
+    ///         build.cargo().args(&["build"]).run()?;
+    ///         let writes = build.source_writes()?;
+    ///         if !writes.is_empty() {
+    ///             println!("the build wrote to the source directory: {:?}", writes);
+    ///         }
+    ///         Ok(())
+    ///     })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn record_source_writes(mut self, enable: bool) -> Self {
+        self.record_source_writes = enable;
+        self
+    }
+
+    /// Record the network traffic of the sandboxed commands of the build, so that what the build
+    /// did with the network can be audited with
+    /// [`Build::network_usage`](struct.Build.html#method.network_usage).
+    ///
+    /// Only the commands running with networking enabled are tracked. Their network namespace is
+    /// sampled every few seconds with `docker exec`, which requires `sh` and `cat` to be present
+    /// in the sandbox image. By default the network usage is not recorded.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::dist("");
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// let usage = build_dir.build(&toolchain, &krate, sandbox)
+    ///     .record_network_usage(true)
+    ///     .run(|build| {
+    ///         build.cargo().args(&["build"]).run()?;
+    ///         Ok(build.network_usage()?)
+    ///     })?;
+    /// println!("received {} bytes from {:?}", usage.bytes_received(), usage.hosts());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn record_network_usage(mut self, enable: bool) -> Self {
+        self.record_network_usage = enable;
+        self
+    }
+
     /// Run a sandboxed build of the provided crate with the provided toolchain. The closure will
     /// be provided an instance of [`Build`](struct.Build.html) that allows spawning new processes
     /// inside the sandbox.
@@ -88,18 +420,91 @@ impl<'a> BuildBuilder<'a> {
     /// # Ok(())
     /// # }
     pub async fn run<R, F: FnOnce(&Build) -> Result<R, Error>>(self, f: F) -> Result<R, Error> {
+        let (build, entry) = self.start().await?;
+        let res = f(&build);
+        self.build_dir
+            .finish(build, entry, res, self.home_size_limit)
+            .await
+    }
+
+    /// Run a sandboxed build of the provided crate with the provided toolchain, like
+    /// [`run`](#method.run), but with a closure returning a future. This allows running async
+    /// code with the [`Build`](struct.Build.html), like the methods of
+    /// [`DocBuild`](struct.DocBuild.html). The future has to be boxed, for example with
+    /// `Box::pin(async move { ... })`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # async fn run() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init().await?;
+    /// # let toolchain = Toolchain::dist("");
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// build_dir
+    ///     .build(&toolchain, &krate, sandbox)
+    ///     .run_async(|build| {
+    ///         Box::pin(async move {
+    ///             build.cargo().args(&["test", "--all"]).run().await?;
+    ///             Ok(())
+    ///         })
+    ///     })
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn run_async<R, F>(self, f: F) -> Result<R, Error>
+    where
+        F: for<'b> FnOnce(&'b Build<'b>) -> BoxFuture<'b, Result<R, Error>>,
+    {
+        let (build, entry) = self.start().await?;
+        let res = f(&build).await;
+        self.build_dir
+            .finish(build, entry, res, self.home_size_limit)
+            .await
+    }
+
+    async fn start(&self) -> Result<(Build<'_>, JournalEntry<'_>), Error> {
+        // Variables that aren't valid UTF-8 can't be passed to the sandbox, so they're skipped.
+        let env = std::env::vars_os()
+            .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+            .filter(|(key, _)| {
+                self.env_passthrough_prefixes
+                    .iter()
+                    .any(|prefix| key.starts_with(prefix.as_str()))
+            })
+            .collect();
+
         self.build_dir
-            .run(self.toolchain, self.krate, self.sandbox, self.patches, f)
+            .start(
+                self.toolchain,
+                self.krate,
+                self.sandbox.clone(),
+                self.patches.clone(),
+                env,
+                self.source_dir_name.as_deref(),
+                self.lockfile_toolchain,
+                self.code_execution_policy,
+                self.record_provenance,
+                self.deterministic,
+                self.record_source_writes,
+                self.record_network_usage,
+            )
             .await
     }
 }
 
 impl BuildDirectory {
     pub(crate) fn new(workspace: Workspace, name: &str) -> Self {
-        Self {
+        let build_dir = Self {
             workspace,
             name: name.into(),
-        }
+        };
+        build_dir.migrate_legacy_dir();
+        build_dir
     }
 
     /// Create a build in this build directory.  Returns a builder that can be used
@@ -135,57 +540,765 @@ impl BuildDirectory {
             krate,
             sandbox,
             patches: Vec::new(),
+            env_passthrough_prefixes: Vec::new(),
+            source_dir_name: None,
+            lockfile_toolchain: None,
+            home_size_limit: None,
+            code_execution_policy: CodeExecutionPolicy::Allow,
+            record_provenance: false,
+            deterministic: false,
+            record_source_writes: false,
+            record_network_usage: false,
         }
     }
 
-    pub(crate) async fn run<R, F: FnOnce(&Build) -> Result<R, Error>>(
-        &mut self,
-        toolchain: &Toolchain,
+    /// Prepare the source code and the directories of a build, returning the
+    /// [`Build`](struct.Build.html) its commands are run with along with its entry in the journal.
+    /// `finish` has to be called once the build is done.
+    #[allow(clippy::too_many_arguments)]
+    async fn start<'b>(
+        &'b self,
+        toolchain: &'b Toolchain,
         krate: &Crate,
         sandbox: SandboxBuilder,
         patches: Vec<CratePatch>,
-        f: F,
-    ) -> Result<R, Error> {
-        let source_dir = self.source_dir();
+        env: Vec<(String, String)>,
+        source_dir_name: Option<&str>,
+        lockfile_toolchain: Option<&Toolchain>,
+        code_execution_policy: CodeExecutionPolicy,
+        record_provenance: bool,
+        deterministic: bool,
+        record_source_writes: bool,
+        record_network_usage: bool,
+    ) -> Result<(Build<'b>, JournalEntry<'b>), Error> {
+        let source_dir = self.source_dir_named(source_dir_name)?;
         if source_dir.exists() {
             remove_dir_all(&source_dir)?;
         }
+        self.start_build()?;
 
-        let mut prepare = Prepare::new(&self.workspace, toolchain, krate, &source_dir, patches);
+        let entry = self.workspace.journal().begin(Operation::Build {
+            source_dir: source_dir.clone(),
+            home_dir: self.home_dir(),
+        });
+        let mut prepare = Prepare::new(&self.workspace, toolchain, krate, &source_dir, patches)
+            .code_execution_policy(code_execution_policy);
+        if let Some(lockfile_toolchain) = lockfile_toolchain {
+            prepare = prepare.lockfile_toolchain(lockfile_toolchain);
+        }
         prepare.prepare().await?;
 
-        tokio::fs::create_dir_all(self.target_dir()).await?;
-        let res = f(&Build {
+        self.create().await?;
+        self.reset_home_dir()?;
+        let provenance = if record_provenance {
+            Some(ProvenanceInputs::record(krate.to_string(), &source_dir)?)
+        } else {
+            None
+        };
+        let source_state = if record_source_writes {
+            Some(SourceState::record(&source_dir)?)
+        } else {
+            None
+        };
+        let sandbox = if deterministic {
+            sandbox.pin_image(&self.workspace).await?
+        } else {
+            sandbox
+        };
+        let build = Build {
             dir: self,
+            source_dir,
             toolchain,
             sandbox,
-        })?;
-
-        remove_dir_all(&source_dir)?;
-        Ok(res)
+            adjustments: self.sandbox_adjustments(krate),
+            env,
+            snapshots: self.snapshot_recorder(toolchain),
+            provenance,
+            deterministic,
+            source_state,
+            network: if record_network_usage {
+                Some(NetworkRecorder::new())
+            } else {
+                None
+            },
+        };
+        Ok((build, entry))
     }
 
-    /// Remove all the contents of the build directory, freeing disk space.
-    pub fn purge(&mut self) -> Result<(), Error> {
-        let build_dir = self.build_dir();
-        if build_dir.exists() {
-            remove_dir_all(build_dir)?;
-        }
-
-        Ok(())
-    }
+    /// Clean up after a build started with `start`, returning the result of the build.
+    async fn finish<R>(
+        &self,
+        build: Build<'_>,
+        _entry: JournalEntry<'_>,
+        res: Result<R, Error>,
+        home_size_limit: Option<u64>,
+    ) -> Result<R, Error> {
+        let source_dir = build.source_dir;
+        self.restore_mount_owners().await;
+        self.record_disk_usage();
+        let res = res?;
 
-    fn build_dir(&self) -> PathBuf {
-        self.workspace.builds_dir().join(&self.name)
+        let home_size = crate::utils::dir_size(&self.home_dir())?;
+        remove_dir_all(&source_dir)?;
+        remove_dir_all(&self.home_dir())?;
+        if let Some(limit) = home_size_limit {
+            if home_size > limit {
+                return Err(StorageError::HomeSizeLimitExceeded {
+                    size: home_size,
+                    limit,
+                }
+                .into());
+            }
+        }
+        Ok(res)
     }
 
-    fn source_dir(&self) -> PathBuf {
+    /// Create a comparison build in this build directory, building the crate with all the
+    /// provided toolchains. Returns a builder that can be used to configure the build and run it.
+    ///
+    /// The first toolchain is treated as the baseline the other ones are compared against. See
+    /// [`ComparisonBuild`](struct.ComparisonBuild.html) for more information.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let stable = Toolchain::dist("stable");
+    /// let beta = Toolchain::dist("beta");
+    ///
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// let result = build_dir.compare(&[&stable, &beta], &krate, sandbox).run(|build| {
+    ///     build.cargo().args(&["test", "--all"]).run()?;
+    ///     Ok(())
+    /// })?;
+    /// println!("{:?}", result.comparison());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn compare<'a>(
+        &'a mut self,
+        toolchains: &[&'a Toolchain],
+        krate: &'a Crate,
+        sandbox: SandboxBuilder,
+    ) -> ComparisonBuild<'a> {
+        ComparisonBuild::new(self, toolchains, krate, sandbox)
+    }
+
+    /// Search the oldest toolchain able to build the crate in this build directory, bisecting
+    /// across the versioned dist toolchains installed in the workspace. Returns a builder that can
+    /// be used to configure the search and run it. See [`MsrvSearch`](struct.MsrvSearch.html) for
+    /// more information.
+    pub fn find_msrv<'a>(
+        &'a mut self,
+        krate: &'a Crate,
+        sandbox: SandboxBuilder,
+    ) -> MsrvSearch<'a> {
+        MsrvSearch::new(self, krate, sandbox)
+    }
+
+    /// Bisect the rust-lang/rust merge commits between `good` and `bad` to find the first one
+    /// changing the outcome of building the crate in this build directory, using the CI artifacts
+    /// of each commit. Returns a builder that can be used to configure the bisection and run it.
+    /// See [`CiBisection`](struct.CiBisection.html) for more information.
+    pub fn bisect_ci<'a>(
+        &'a mut self,
+        krate: &'a Crate,
+        good: &str,
+        bad: &str,
+        sandbox: SandboxBuilder,
+    ) -> CiBisection<'a> {
+        CiBisection::new(self, krate, good, bad, sandbox)
+    }
+
+    /// Check whether the public API of `current` is semver-compatible with the one of `baseline`,
+    /// typically two versions of the same crate, with [cargo-semver-checks][semver-checks].
+    /// Returns a builder that can be used to configure the check and run it. See
+    /// [`SemverCheck`](struct.SemverCheck.html) for more information.
+    ///
+    /// [semver-checks]: https://github.com/obi1kenobi/cargo-semver-checks
+    pub fn semver_check<'a>(
+        &'a mut self,
+        toolchain: &'a Toolchain,
+        baseline: &'a Crate,
+        current: &'a Crate,
+        sandbox: SandboxBuilder,
+    ) -> SemverCheck<'a> {
+        SemverCheck::new(self, toolchain, baseline, current, sandbox)
+    }
+
+    pub(crate) async fn run_with_toolchains<F: FnMut(&Build) -> Result<(), Error>>(
+        &mut self,
+        toolchains: &[&Toolchain],
+        krate: &Crate,
+        sandbox: SandboxBuilder,
+        patches: Vec<CratePatch>,
+        source_dir_name: Option<&str>,
+        mut f: F,
+    ) -> Result<Vec<Result<(), Error>>, Error> {
+        let baseline = match toolchains.first() {
+            Some(baseline) => baseline,
+            None => failure::bail!("no toolchain to build the crate with"),
+        };
+
+        let source_dir = self
+            .prepare_shared(baseline, krate, patches, source_dir_name)
+            .await?;
+        let mut results = Vec::with_capacity(toolchains.len());
+        for toolchain in toolchains {
+            info!("building {} with toolchain {}", krate, toolchain);
+            results.push(
+                self.build_shared(&source_dir, krate, toolchain, &sandbox, &mut f)
+                    .await,
+            );
+        }
+
+        remove_dir_all(&source_dir)?;
+        Ok(results)
+    }
+
+    /// Prepare the source code of a crate to be built with multiple toolchains, returning the
+    /// source directory. The source directory has to be removed once all the builds are done.
+    pub(crate) async fn prepare_shared(
+        &mut self,
+        baseline: &Toolchain,
+        krate: &Crate,
+        patches: Vec<CratePatch>,
+        source_dir_name: Option<&str>,
+    ) -> Result<PathBuf, Error> {
+        let source_dir = self.source_dir_named(source_dir_name)?;
+        if source_dir.exists() {
+            remove_dir_all(&source_dir)?;
+        }
+        self.start_build()?;
+
+        // The source code and the lockfile are prepared only once with the baseline toolchain,
+        // so that every toolchain builds exactly the same dependency graph.
+        let mut prepare = Prepare::new(&self.workspace, baseline, krate, &source_dir, patches);
+        prepare.prepare().await?;
+
+        self.create().await?;
+        Ok(source_dir)
+    }
+
+    /// Build the source code prepared by `prepare_shared` with a toolchain.
+    pub(crate) async fn build_shared<R, F: FnMut(&Build) -> R>(
+        &self,
+        source_dir: &Path,
+        krate: &Crate,
+        toolchain: &Toolchain,
+        sandbox: &SandboxBuilder,
+        f: &mut F,
+    ) -> R {
+        let res = f(&self.start_shared(source_dir, krate, toolchain, sandbox));
+        self.finish_shared().await;
+        res
+    }
+
+    /// Start a build of the source code prepared by `prepare_shared`, for callers that need to
+    /// run async code with the [`Build`](struct.Build.html). `finish_shared` has to be called
+    /// once the build is done.
+    pub(crate) fn start_shared<'b>(
+        &'b self,
+        source_dir: &Path,
+        krate: &Crate,
+        toolchain: &'b Toolchain,
+        sandbox: &SandboxBuilder,
+    ) -> Build<'b> {
+        // Every toolchain starts with an empty home directory, so that the files written by a
+        // build can't affect the next one.
+        if let Err(err) = self.reset_home_dir() {
+            warn!("failed to clean the home directory: {}", err);
+        }
+        Build {
+            dir: self,
+            source_dir: source_dir.to_path_buf(),
+            toolchain,
+            sandbox: sandbox.clone(),
+            adjustments: self.sandbox_adjustments(krate),
+            env: Vec::new(),
+            snapshots: self.snapshot_recorder(toolchain),
+            provenance: None,
+            deterministic: false,
+            source_state: None,
+            network: None,
+        }
+    }
+
+    /// Clean up after a build started with `start_shared`.
+    pub(crate) async fn finish_shared(&self) {
+        self.restore_mount_owners().await;
+        self.record_disk_usage();
+        if let Err(err) = remove_dir_all(&self.home_dir()) {
+            warn!("failed to clean the home directory: {}", err);
+        }
+    }
+
+    /// Build a local crate, and build it again every time its source code changes, similarly to
+    /// `cargo watch`. The closure will be provided an instance of [`Build`](struct.Build.html)
+    /// that allows spawning new processes inside the sandbox.
+    ///
+    /// Only the changed files are copied into the build directory between builds, unless the
+    /// manifest, the lockfile or the cargo configuration changed: in that case the crate is
+    /// prepared again from scratch. Errors returned by the closure are logged without stopping
+    /// the watch, which continues until the returned future is dropped or the workspace is
+    /// shut down.
+    ///
+    /// The crate is prepared into the directory named `source_dir_name` if provided, like with
+    /// [`BuildBuilder::source_dir_name`](struct.BuildBuilder.html#method.source_dir_name).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::dist("");
+    /// # let sandbox = SandboxBuilder::new();
+    /// let krate = Crate::local("path/to/crate".as_ref());
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// build_dir.rebuild_on_change(&toolchain, &krate, sandbox, None, |build| {
+    ///     build.cargo().args(&["test", "--all"]).run()?;
+    ///     Ok(())
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn rebuild_on_change<F: FnMut(&Build) -> Result<(), Error>>(
+        &mut self,
+        toolchain: &Toolchain,
+        krate: &Crate,
+        sandbox: SandboxBuilder,
+        source_dir_name: Option<&str>,
+        mut f: F,
+    ) -> Result<(), Error> {
+        let source_dir = self.source_dir_named(source_dir_name)?;
+        let mut watcher = krate
+            .watch()
+            .await?
+            .runtime(self.workspace.runtime().cloned());
+        let mut prepared = false;
+
+        while !self.workspace.shutdown_tracker().is_shutting_down() {
+            if !prepared {
+                if source_dir.exists() {
+                    remove_dir_all(&source_dir)?;
+                }
+
+                let mut prepare =
+                    Prepare::new(&self.workspace, toolchain, krate, &source_dir, Vec::new());
+                match prepare.prepare().await {
+                    Ok(()) => prepared = true,
+                    // A broken manifest is likely to be fixed by the next change.
+                    Err(err) => warn!("failed to prepare {}: {}", krate, err),
+                }
+            }
+
+            if prepared {
+                self.create().await?;
+                self.reset_home_dir()?;
+                info!("building {}", krate);
+                let snapshots = self.snapshot_recorder(toolchain);
+                if let Err(err) = f(&Build {
+                    dir: self,
+                    source_dir: source_dir.clone(),
+                    toolchain,
+                    sandbox: sandbox.clone(),
+                    adjustments: self.sandbox_adjustments(krate),
+                    env: Vec::new(),
+                    snapshots,
+                    provenance: None,
+                    deterministic: false,
+                    source_state: None,
+                    network: None,
+                }) {
+                    warn!("build of {} failed: {}", krate, err);
+                }
+                self.restore_mount_owners().await;
+            }
+
+            let changes = watcher.changes().await?;
+            info!("{} changed, rebuilding it", krate);
+            if !prepared || changes.requires_prepare() {
+                prepared = false;
+            } else {
+                changes.apply(watcher.root(), &source_dir).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove all the contents of the build directory, freeing disk space.
+    pub fn purge(&mut self) -> Result<(), Error> {
+        let build_dir = crate::utils::long_path(&self.build_dir());
+        if build_dir.exists() {
+            remove_dir_all(build_dir)?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the source code and the cached build artifacts of a single crate from the build
+    /// directory, leaving the artifacts of all the other crates untouched.
+    ///
+    /// The build artifacts are removed for all the toolchains and targets sharing the build
+    /// directory. This is useful to reset a corrupted or stuck build without purging the whole
+    /// build directory.
+    pub async fn purge_crate(&mut self, krate: &Crate) -> Result<(), Error> {
+        let name = krate.package_name(&self.workspace).await?;
+
+        // The source directories are shared by all the crates built in this build directory, so
+        // they're removed only if they contain the crate being purged (or if they're broken).
+        for source_dir in self.source_dirs()? {
+            let source_name = tokio::fs::read_to_string(source_dir.join("Cargo.toml"))
+                .await
+                .ok()
+                .and_then(|manifest| crate::crates::manifest_package_name(&manifest).ok());
+            if source_name.map_or(true, |source_name| source_name == name) {
+                remove_dir_all(&source_dir)?;
+            }
+        }
+
+        let target_dir = self.target_dir();
+        crate::utils::spawn_blocking(self.workspace.runtime(), move || {
+            purge_crate_artifacts(&target_dir, &name)
+        })
+        .await??;
+
+        Ok(())
+    }
+
+    /// Get the name the build directory was opened with.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the name of the build directory on disk, a short hash of its name. The build directory
+    /// can be retrieved from it with
+    /// [`Workspace::lookup_build_dir`](struct.Workspace.html#method.lookup_build_dir).
+    pub fn dir_name(&self) -> String {
+        dir_name(&self.name)
+    }
+
+    pub(crate) fn workspace(&self) -> &Workspace {
+        &self.workspace
+    }
+
+    /// Create the build directory, recording its name inside it.
+    async fn create(&self) -> Result<(), Error> {
+        tokio::fs::create_dir_all(self.target_dir()).await?;
+        let name_file = self.build_dir().join(NAME_FILE);
+        if !name_file.exists() {
+            tokio::fs::write(name_file, self.name.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    /// Check that the workspace can start a new build, recording it in the run budget.
+    fn start_build(&self) -> Result<(), Error> {
+        self.workspace.check_builds_space()?;
+        if let Some(budget) = self.workspace.run_budget() {
+            budget.start_build()?;
+        }
+        Ok(())
+    }
+
+    /// Measure the size of the build directory, if the run budget limits the disk usage.
+    fn record_disk_usage(&self) {
+        if let Some(budget) = self.workspace.run_budget() {
+            if budget.limits_disk_usage() {
+                match crate::utils::dir_size(&self.build_dir()) {
+                    Ok(size) => budget.record_disk_usage(&self.dir_name(), size),
+                    Err(err) => warn!("failed to measure the size of the build directory: {}", err),
+                }
+            }
+        }
+    }
+
+    /// Retrieve the adjustments of the sandbox limits for the crate from the workspace's policy.
+    fn sandbox_adjustments(&self, krate: &Crate) -> SandboxAdjustments {
+        let adjustments = self.workspace.sandbox_adjustments(krate);
+        if !adjustments.is_empty() {
+            info!(
+                "adjusting the sandbox limits of {}: {:?}",
+                krate, adjustments
+            );
+        }
+        adjustments
+    }
+
+    fn snapshot_recorder(&self, toolchain: &Toolchain) -> SnapshotRecorder {
+        let tools = self.workspace.installed_tools().unwrap_or_else(|err| {
+            warn!("failed to retrieve the installed tools: {}", err);
+            BTreeMap::new()
+        });
+        SnapshotRecorder::new(toolchain.to_string(), tools)
+    }
+
+    fn build_dir(&self) -> PathBuf {
+        self.workspace.builds_dir().join(self.dir_name())
+    }
+
+    /// Move the build directory from the location used by older versions of rustwide, named after
+    /// the build directory itself, to the one named after its hash.
+    fn migrate_legacy_dir(&self) {
+        if !is_single_component(&self.name) {
+            return;
+        }
+        let legacy = self.workspace.builds_dir().join(&self.name);
+        let build_dir = self.build_dir();
+        // Directories named after a hash always contain the name file, so they're never mistaken
+        // for legacy ones.
+        if legacy == build_dir
+            || !legacy.is_dir()
+            || legacy.join(NAME_FILE).exists()
+            || build_dir.exists()
+        {
+            return;
+        }
+
+        info!(
+            "moving build directory {} to {}",
+            legacy.display(),
+            build_dir.display()
+        );
+        let res = std::fs::rename(&legacy, &build_dir)
+            .and_then(|()| std::fs::write(build_dir.join(NAME_FILE), self.name.as_bytes()));
+        if let Err(err) = res {
+            warn!(
+                "failed to move build directory {}: {}",
+                legacy.display(),
+                err
+            );
+        }
+    }
+
+    /// Give the directories changed to a custom [sandbox user](cmd/struct.SandboxBuilder.html#method.user)
+    /// by the commands of a build back to the current user. This is done once at the end of the
+    /// build, instead of after every command.
+    async fn restore_mount_owners(&self) {
+        let build_dir = self.build_dir();
+        if let Err(err) = crate::cmd::restore_mount_owners(&self.workspace, &build_dir).await {
+            warn!(
+                "failed to restore the owner of {}: {}",
+                build_dir.display(),
+                err
+            );
+        }
+    }
+
+    fn source_dir(&self) -> PathBuf {
         self.build_dir().join("source")
     }
 
+    /// Return the directory the source code is prepared into: the one with the provided name, or
+    /// the default one.
+    fn source_dir_named(&self, name: Option<&str>) -> Result<PathBuf, Error> {
+        match name {
+            Some(name) => {
+                if !is_single_component(name) || RESERVED_DIRS.contains(&name) {
+                    failure::bail!("invalid source directory name: {}", name);
+                }
+                Ok(self.build_dir().join(name))
+            }
+            None => Ok(self.source_dir()),
+        }
+    }
+
+    /// Find the source directories present in the build directory, including the ones with a
+    /// custom name.
+    fn source_dirs(&self) -> Result<Vec<PathBuf>, Error> {
+        let build_dir = self.build_dir();
+        if !build_dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut source_dirs = Vec::new();
+        for entry in std::fs::read_dir(&build_dir)? {
+            let entry = entry?;
+            let is_reserved = entry
+                .file_name()
+                .to_str()
+                .map_or(false, |name| RESERVED_DIRS.contains(&name));
+            if entry.file_type()?.is_dir() && !is_reserved {
+                source_dirs.push(entry.path());
+            }
+        }
+        Ok(source_dirs)
+    }
+
     fn target_dir(&self) -> PathBuf {
         self.build_dir().join("target")
     }
+
+    fn home_dir(&self) -> PathBuf {
+        self.build_dir().join("home")
+    }
+
+    /// Replace the home directory with an empty one, removing the files left by previous builds.
+    fn reset_home_dir(&self) -> Result<(), Error> {
+        let home = self.home_dir();
+        if home.exists() {
+            remove_dir_all(&home)?;
+        }
+        std::fs::create_dir_all(&home)?;
+        Ok(())
+    }
+}
+
+/// Name of the directory on disk of a build directory: a short hash of its name, to avoid long
+/// names exceeding the path length limits (especially on Windows, where crates' build artifacts
+/// are nested deeply inside the build directory) and names not valid as directory names.
+fn dir_name(name: &str) -> String {
+    let hash = format!("{:x}", Sha256::digest(name.as_bytes()));
+    hash[..16].to_string()
+}
+
+/// Find the name of the build directory stored on disk in the directory with the provided name.
+pub(crate) fn lookup_build_dir_name(
+    workspace: &Workspace,
+    dir_name: &str,
+) -> Result<Option<String>, Error> {
+    if !is_single_component(dir_name) {
+        return Ok(None);
+    }
+    let name_file = workspace.builds_dir().join(dir_name).join(NAME_FILE);
+    if !name_file.is_file() {
+        return Ok(None);
+    }
+    Ok(Some(std::fs::read_to_string(name_file)?))
+}
+
+// Directories of a build directory that can't hold source code.
+static RESERVED_DIRS: &[&str] = &["target", "home"];
+
+/// Check whether the name can be used as a directory name without escaping its parent.
+fn is_single_component(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    )
+}
+
+// Directories inside a cargo profile directory (like `target/debug`) containing artifacts.
+static PROFILE_SUBDIRS: &[&str] = &["deps", ".fingerprint", "build", "incremental", "examples"];
+
+/// Find the cargo profile directories inside a target directory.
+pub(crate) fn profile_dirs(target_dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    // Profile directories are either directly inside the target directory (`target/debug`), or
+    // inside a directory named after the target (`target/x86_64-unknown-linux-gnu/debug`).
+    let mut profiles = Vec::new();
+    for entry in std::fs::read_dir(target_dir)? {
+        let path = entry?.path();
+        if path.join(".fingerprint").is_dir() {
+            profiles.push(path);
+        } else if path.is_dir() {
+            for entry in std::fs::read_dir(&path)? {
+                let path = entry?.path();
+                if path.join(".fingerprint").is_dir() {
+                    profiles.push(path);
+                }
+            }
+        }
+    }
+    Ok(profiles)
+}
+
+fn purge_crate_artifacts(target_dir: &Path, name: &str) -> Result<(), Error> {
+    if !target_dir.is_dir() {
+        return Ok(());
+    }
+
+    for profile in profile_dirs(target_dir)? {
+        let dirs = std::iter::once(profile.clone())
+            .chain(PROFILE_SUBDIRS.iter().map(|subdir| profile.join(subdir)))
+            .filter(|dir| dir.is_dir());
+        for dir in dirs {
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                let is_artifact = entry
+                    .file_name()
+                    .to_str()
+                    .map_or(false, |file| is_crate_artifact(file, name));
+                if !is_artifact {
+                    continue;
+                }
+
+                info!("removing build artifact {}", entry.path().display());
+                if entry.file_type()?.is_dir() {
+                    remove_dir_all(entry.path())?;
+                } else {
+                    std::fs::remove_file(entry.path())?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check whether a file generated by cargo belongs to the crate with the provided name.
+///
+/// Cargo names artifacts `{name}`, `{name}-{hash}` or `lib{name}-{hash}`, with an optional
+/// extension, and replaces the dashes in the name with underscores for some of them.
+/// Environment of the commands in deterministic builds.
+fn deterministic_env() -> Vec<(&'static str, String)> {
+    use crate::cmd::container_dirs::{CARGO_HOME, TARGET_DIR, WORK_DIR};
+
+    let remaps = [
+        (&*WORK_DIR, "/rustwide/source"),
+        (&*CARGO_HOME, "/rustwide/cargo"),
+        (&*TARGET_DIR, "/rustwide/target"),
+    ];
+    let rustflags = remaps
+        .iter()
+        .map(|(from, to)| format!("--remap-path-prefix={}={}", from.display(), to))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    vec![
+        (
+            "SOURCE_DATE_EPOCH",
+            DETERMINISTIC_SOURCE_DATE_EPOCH.to_string(),
+        ),
+        ("TZ", "UTC".into()),
+        ("LC_ALL", "C".into()),
+        ("CARGO_INCREMENTAL", "0".into()),
+        ("CARGO_BUILD_RUSTFLAGS", rustflags),
+    ]
+}
+
+fn is_crate_artifact(file: &str, name: &str) -> bool {
+    let names = [name.to_string(), name.replace('-', "_")];
+    let files = std::iter::once(file).chain(file.strip_prefix("lib"));
+
+    for file in files {
+        for name in &names {
+            let rest = match file.strip_prefix(name.as_str()) {
+                Some(rest) => rest,
+                None => continue,
+            };
+            let stem = match rest.find('.') {
+                Some(dot) => &rest[..dot],
+                None => rest,
+            };
+            // The hash is required to avoid matching other crates sharing the same prefix (for
+            // example `foo-bar` when purging `foo`).
+            let is_hash =
+                |hash: &str| hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit());
+            if stem.is_empty() || stem.strip_prefix('-').map_or(false, is_hash) {
+                return true;
+            }
+        }
+    }
+
+    false
 }
 
 /// API to interact with a running build.
@@ -193,16 +1306,29 @@ impl BuildDirectory {
 /// This is created from [`BuildDirectory::build`](struct.BuildDirectory.html#method.build)
 pub struct Build<'b> {
     dir: &'b BuildDirectory,
+    source_dir: PathBuf,
     toolchain: &'b Toolchain,
     sandbox: SandboxBuilder,
+    adjustments: SandboxAdjustments,
+    env: Vec<(String, String)>,
+    snapshots: SnapshotRecorder,
+    provenance: Option<ProvenanceInputs>,
+    deterministic: bool,
+    source_state: Option<SourceState>,
+    network: Option<NetworkRecorder>,
 }
 
 impl Build<'_> {
     /// Run a command inside the sandbox.
     ///
-    /// Any `cargo` invocation will automatically be configured to use a target directory mounted
-    /// outside the sandbox. The crate's source directory will be the working directory for the
-    /// command.
+    /// Any binary available in the sandbox image can be executed, not just the tools managed by
+    /// rustwide, which allows running custom analyzers against the prepared source. The command
+    /// gets the same mounts and environment as the `cargo` invocations of the build: the crate's
+    /// source directory will be the working directory for the command, and any `cargo` invocation
+    /// will automatically be configured to use a target directory mounted outside the sandbox.
+    /// `RUSTUP_TOOLCHAIN` is set to the toolchain of the build, so the rustup proxies called by
+    /// the command use it as well. `$HOME` points to the scratch home directory of the build (see
+    /// [`host_home_dir`](struct.Build.html#method.host_home_dir)).
     ///
     /// # Example
     ///
@@ -217,6 +1343,8 @@ impl Build<'_> {
     /// let mut build_dir = workspace.build_dir("foo");
     /// build_dir.build(&toolchain, &krate, sandbox).run(|build| {
     ///     build.cmd("rustfmt").args(&["--check", "src/main.rs"]).run()?;
+    ///     let output = build.cmd("tokei").args(&["--output", "json"]).run_capture()?;
+    ///     println!("{}", output.stdout_lines().join("\n"));
     ///     Ok(())
     /// })?;
     /// # Ok(())
@@ -224,16 +1352,41 @@ impl Build<'_> {
     /// ```
     pub fn cmd<R: Runnable>(&self, bin: R) -> Command {
         let container_dir = &*crate::cmd::container_dirs::TARGET_DIR;
+        let home_dir = &*crate::cmd::container_dirs::HOME_DIR;
+
+        let mut sandbox = self
+            .adjustments
+            .apply_to_sandbox(self.sandbox.clone())
+            .record_environment(self.snapshots.clone())
+            .keep_mount_owners()
+            .mount(&self.dir.target_dir(), container_dir, MountKind::ReadWrite)
+            .mount(&self.dir.home_dir(), home_dir, MountKind::ReadWrite);
+        if let Some(recorder) = &self.network {
+            sandbox = sandbox.record_network_usage(recorder.clone());
+        }
 
-        Command::new_sandboxed(
-            &self.dir.workspace,
-            self.sandbox
-                .clone()
-                .mount(&self.dir.target_dir(), container_dir, MountKind::ReadWrite),
-            bin,
-        )
-        .cd(self.dir.source_dir())
-        .env("CARGO_TARGET_DIR", container_dir)
+        let mut cmd = Command::new_sandboxed(&self.dir.workspace, sandbox, bin)
+            .cd(&self.source_dir)
+            .env("CARGO_TARGET_DIR", container_dir)
+            .env("HOME", home_dir)
+            .env("XDG_CACHE_HOME", home_dir.join(".cache"))
+            .env("XDG_CONFIG_HOME", home_dir.join(".config"))
+            .env("XDG_DATA_HOME", home_dir.join(".local").join("share"));
+        // Host toolchains are not managed by rustup, so the proxies can't select them.
+        if self.toolchain.as_host().is_none() {
+            cmd = cmd.env("RUSTUP_TOOLCHAIN", self.toolchain.rustup_name());
+        }
+        cmd = self.adjustments.apply_to_command(cmd);
+        if self.deterministic {
+            for (key, value) in deterministic_env() {
+                cmd = cmd.env(key, value);
+            }
+        }
+
+        for (key, value) in &self.env {
+            cmd = cmd.env_redacted(key, value);
+        }
+        cmd
     }
 
     /// Run `cargo` inside the sandbox, using the toolchain chosen for the build.
@@ -263,13 +1416,421 @@ impl Build<'_> {
         self.cmd(self.toolchain.cargo())
     }
 
+    /// Type check the crate with `cargo check`, collecting the diagnostics emitted by the compiler.
+    /// If `all_targets` is true tests, examples and benchmarks are checked as well.
+    ///
+    /// No code is generated, which makes this much faster than a full build and keeps the target
+    /// directory small, for analyses only interested in whether the crate compiles. Compilation
+    /// errors are not errors of this method: they're recorded in the returned summary, along
+    /// with whether the check succeeded.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Build;
+    /// # use std::error::Error;
+    /// # async fn run(build: &Build<'_>) -> Result<(), Box<dyn Error>> {
+    /// let summary = build.check(true).await?;
+    /// for diagnostic in summary.diagnostics() {
+    ///     println!("{}: {}", diagnostic.level(), diagnostic.message());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn check(&self, all_targets: bool) -> Result<BuildSummary, Error> {
+        let mut summary = BuildSummary::new();
+        let mut cmd = self.cargo().args(&["check", "--message-format=json"]);
+        if all_targets {
+            cmd = cmd.args(&["--all-targets"]);
+        }
+        let res = cmd
+            .process_lines(&mut |line, _| summary.process_line(line))
+            .run()
+            .await;
+        summary.set_success(res.is_ok());
+        Ok(summary)
+    }
+
+    pub(crate) fn workspace(&self) -> &Workspace {
+        self.dir.workspace()
+    }
+
     /// Get the path to the source code on the host machine (outside the sandbox).
+    ///
+    /// The directory contains the prepared source of the crate: patches are already applied and
+    /// `Cargo.lock` is already generated. It's mounted as the working directory of the sandboxed
+    /// commands, so files can be inspected or added between commands, for example to collect the
+    /// lockfile or to inject test fixtures. The directory is removed when the build finishes.
+    ///
+    /// This is part of the stable API: the path points to the source code of the crate being
+    /// built for the whole duration of the build, regardless of the internal layout of the build
+    /// directory.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::dist("");
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// let lockfile = build_dir.build(&toolchain, &krate, sandbox).run(|build| {
+    ///     std::fs::write(build.host_source_dir().join("fixture.txt"), "hello")?;
+    ///     build.cargo().args(&["test"]).run()?;
+    ///     Ok(std::fs::read_to_string(build.host_source_dir().join("Cargo.lock"))?)
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
     pub fn host_source_dir(&self) -> PathBuf {
-        self.dir.source_dir()
+        self.source_dir.clone()
+    }
+
+    /// Get the path to the scratch home directory of the build on the host machine (outside the
+    /// sandbox).
+    ///
+    /// The directory is mounted as `$HOME` in the sandboxed commands, and the `XDG_CACHE_HOME`,
+    /// `XDG_CONFIG_HOME` and `XDG_DATA_HOME` directories are inside it, so that the files written
+    /// there by build scripts and tests don't pollute any shared state. It starts empty and it's
+    /// removed when the build finishes.
+    pub fn host_home_dir(&self) -> PathBuf {
+        self.dir.home_dir()
     }
 
     /// Get the path to the target directory on the host machine (outside the sandbox).
+    ///
+    /// The directory is mounted as `CARGO_TARGET_DIR` in the sandboxed commands, so the artifacts
+    /// produced by cargo can be read from it while the build is running. Unlike the source
+    /// directory, it's shared by all the builds of the build directory and is kept after the
+    /// build finishes, until the build directory is purged.
+    ///
+    /// This is part of the stable API, like
+    /// [`host_source_dir`](struct.Build.html#method.host_source_dir).
     pub fn host_target_dir(&self) -> PathBuf {
         self.dir.target_dir()
     }
+
+    /// Get the environment of the sandboxed commands executed by this build so far, in the order
+    /// they were started, along with the toolchain and the versions of the installed tools.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::dist("");
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// let snapshots = build_dir.build(&toolchain, &krate, sandbox).run(|build| {
+    ///     build.cargo().args(&["test", "--all"]).run()?;
+    ///     Ok(build.environment_snapshots())
+    /// })?;
+    /// println!("{}", serde_json::to_string(&snapshots)?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn environment_snapshots(&self) -> Vec<EnvironmentSnapshot> {
+        self.snapshots.snapshots()
+    }
+
+    /// Create the [`Provenance`](struct.Provenance.html) record of the build so far, including the
+    /// commands executed and the artifacts produced until now. The record can then be signed
+    /// with [`Provenance::sign`](struct.Provenance.html#method.sign).
+    ///
+    /// An error is returned if provenance recording wasn't enabled with
+    /// [`BuildBuilder::record_provenance`](struct.BuildBuilder.html#method.record_provenance).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Build;
+    /// # use std::error::Error;
+    /// # async fn run(build: &Build<'_>) -> Result<(), Box<dyn Error>> {
+    /// build.cargo().args(&["build", "--release"]).run().await?;
+    /// let provenance = build.provenance().await?;
+    /// for (path, checksum) in provenance.outputs() {
+    ///     println!("{}  {}", checksum, path);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn provenance(&self) -> Result<Provenance, Error> {
+        let inputs = self.provenance.as_ref().ok_or_else(|| {
+            failure::err_msg("provenance recording is not enabled for this build")
+        })?;
+        inputs
+            .provenance(
+                self.workspace(),
+                self.toolchain.to_string(),
+                self.environment_snapshots(),
+                &self.host_target_dir(),
+            )
+            .await
+    }
+
+    /// Return the files the build created, modified or removed in the source directory so far,
+    /// outside of the target directory. Build scripts writing there can be flagged this way.
+    ///
+    /// An error is returned if tracking wasn't enabled with
+    /// [`BuildBuilder::record_source_writes`](struct.BuildBuilder.html#method.record_source_writes).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Build;
+    /// # use std::error::Error;
+    /// # async fn run(build: &Build<'_>) -> Result<(), Box<dyn Error>> {
+    /// build.cargo().args(&["build"]).run().await?;
+    /// for path in build.source_writes()?.created() {
+    ///     println!("the build created {}", path);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn source_writes(&self) -> Result<SourceWrites, Error> {
+        let state = self
+            .source_state
+            .as_ref()
+            .ok_or_else(|| failure::err_msg("source writes are not recorded for this build"))?;
+        state.writes(&self.source_dir)
+    }
+
+    /// Return the network traffic of the sandboxed commands the build ran so far, and the remote
+    /// hosts they connected to. Commands running with networking disabled are not included.
+    ///
+    /// An error is returned if recording wasn't enabled with
+    /// [`BuildBuilder::record_network_usage`](struct.BuildBuilder.html#method.record_network_usage).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Build;
+    /// # use std::error::Error;
+    /// # async fn run(build: &Build<'_>) -> Result<(), Box<dyn Error>> {
+    /// build.cargo().args(&["fetch"]).run().await?;
+    /// let usage = build.network_usage()?;
+    /// println!("sent {} bytes", usage.bytes_sent());
+    /// for host in usage.hosts() {
+    ///     println!("connected to {}", host);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn network_usage(&self) -> Result<NetworkUsage, Error> {
+        let recorder = self
+            .network
+            .as_ref()
+            .ok_or_else(|| failure::err_msg("network usage is not recorded for this build"))?;
+        Ok(recorder.usage())
+    }
+
+    /// Compute the sizes of the artifacts produced by the build so far, and of the generated
+    /// documentation. If `sections` is true the size of each section of ELF binaries and dynamic
+    /// libraries is included as well.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::dist("");
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// let report = build_dir.build(&toolchain, &krate, sandbox).run(|build| {
+    ///     build.cargo().args(&["build", "--release"]).run()?;
+    ///     Ok(build.artifact_report(true)?)
+    /// })?;
+    /// println!("total size: {} bytes", report.total_size());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn artifact_report(&self, sections: bool) -> Result<ArtifactReport, Error> {
+        ArtifactReport::collect(&self.dir.target_dir(), sections)
+    }
+
+    /// Store the final artifacts produced by the build so far in the provided storage backend,
+    /// streaming them instead of reading them in memory. Each artifact is stored with its path
+    /// relative to the target directory appended to `prefix`, like `foo-1.0.0/debug/foo`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{storage::LocalStorage, Build};
+    /// # use std::error::Error;
+    /// # async fn run(build: &Build<'_>) -> Result<(), Box<dyn Error>> {
+    /// let storage = LocalStorage::new("/srv/artifacts".as_ref()).base_url("https://example.com");
+    /// for object in build.store_artifacts(&storage, "foo-1.0.0").await? {
+    ///     println!("{}", object.url());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn store_artifacts(
+        &self,
+        backend: &dyn StorageBackend,
+        prefix: &str,
+    ) -> Result<Vec<StoredObject>, Error> {
+        let target_dir = self.host_target_dir();
+        let prefix = prefix.trim_end_matches('/');
+        let mut stored = Vec::new();
+        for artifact in ArtifactReport::collect(&target_dir, false)?.artifacts() {
+            let key = format!("{}/{}", prefix, artifact.path());
+            stored.push(
+                backend
+                    .store(self.workspace(), &key, &target_dir.join(artifact.path()))
+                    .await?,
+            );
+        }
+        Ok(stored)
+    }
+
+    /// List the members of the cargo workspace being built, as reported by `cargo metadata`. If
+    /// the source isn't a workspace, the only member is the crate itself.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Build;
+    /// # use std::error::Error;
+    /// # async fn run(build: &Build<'_>) -> Result<(), Box<dyn Error>> {
+    /// for member in build.workspace_members().await? {
+    ///     println!("{} {} in {}", member.name(), member.version(), member.path().display());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn workspace_members(&self) -> Result<Vec<WorkspaceMember>, Error> {
+        crate::members::list(self).await
+    }
+
+    /// Run a cargo subcommand (like `build`, `test` or `doc`) on a subset of the workspace
+    /// members, identified by their package names. The whole workspace is selected if no package
+    /// is provided.
+    ///
+    /// Additional arguments can be added to the returned command, and are passed to the
+    /// subcommand after the package selection.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Build;
+    /// # use std::error::Error;
+    /// # async fn run(build: &Build<'_>) -> Result<(), Box<dyn Error>> {
+    /// build.cargo_members("test", &["foo", "foo-cli"]).args(&["--no-fail-fast"]).run().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn cargo_members(&self, subcommand: &str, packages: &[&str]) -> Command {
+        self.cargo()
+            .args(&[subcommand])
+            .args(&crate::members::selection_args(packages))
+    }
+
+    /// Migrate the crate to a newer edition (like `2021`) with `cargo fix --edition`, returning
+    /// whether the migration succeeded and the changes it made to the source code.
+    ///
+    /// Crates more than one edition behind are migrated one edition at a time, bumping the
+    /// `edition` of the root `Cargo.toml` after each step, and the migrated crate is then checked
+    /// with `cargo check --all-targets`. The source directory is left migrated, so the crate can
+    /// be built and tested with the new edition afterwards. An error is returned if the crate
+    /// already uses the provided edition or a newer one.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Build;
+    /// # use std::error::Error;
+    /// # async fn run(build: &Build<'_>) -> Result<(), Box<dyn Error>> {
+    /// let migration = build.cargo_fix_edition("2021").await?;
+    /// if !migration.success() {
+    ///     println!("failed to migrate from {}:\n{}", migration.from(), migration.diff());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn cargo_fix_edition(&self, target_edition: &str) -> Result<EditionMigration, Error> {
+        crate::edition::migrate(self, target_edition).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deterministic_env, dir_name, is_crate_artifact, is_single_component};
+
+    #[test]
+    fn test_is_single_component() {
+        assert!(is_single_component("source"));
+        assert!(is_single_component("foo-1.0.0"));
+
+        for name in &["", ".", "..", "foo/bar", "/foo", "../foo"] {
+            assert!(!is_single_component(name), "{} shouldn't be valid", name);
+        }
+    }
+
+    #[test]
+    fn test_dir_name() {
+        let name = dir_name("a-very-long-build-directory-name");
+        assert_eq!(name.len(), 16);
+        assert!(is_single_component(&name));
+        assert_eq!(name, dir_name("a-very-long-build-directory-name"));
+        assert_ne!(name, dir_name("a-very-long-build-directory-name-2"));
+
+        // Names that aren't valid directory names are hashed to valid ones.
+        assert!(is_single_component(&dir_name("foo/../bar")));
+    }
+
+    #[test]
+    fn test_is_crate_artifact() {
+        for file in &[
+            "foo-bar",
+            "foo-bar.d",
+            "foo-bar-0123456789abcdef",
+            "libfoo_bar-0123456789abcdef.rlib",
+            "foo_bar-0123456789abcdef.d",
+            "libfoo_bar.rlib",
+        ] {
+            assert!(is_crate_artifact(file, "foo-bar"), "{} should match", file);
+        }
+
+        for file in &[
+            "foo-bar-baz-0123456789abcdef",
+            "libfoo_bar_baz-0123456789abcdef.rlib",
+            "foo-barbaz",
+            "foo-bar-notahash",
+            "bar",
+        ] {
+            assert!(
+                !is_crate_artifact(file, "foo-bar"),
+                "{} shouldn't match",
+                file
+            );
+        }
+
+        assert!(is_crate_artifact("libc-0123456789abcdef", "libc"));
+        assert!(is_crate_artifact("liblibc-0123456789abcdef.rlib", "libc"));
+    }
+
+    #[test]
+    fn test_deterministic_env() {
+        let env = deterministic_env();
+        let get = |key| env.iter().find(|(k, _)| *k == key).map(|(_, v)| v.as_str());
+        assert_eq!(get("SOURCE_DATE_EPOCH"), Some("315532800"));
+        assert_eq!(get("TZ"), Some("UTC"));
+
+        let rustflags = get("CARGO_BUILD_RUSTFLAGS").unwrap();
+        assert_eq!(rustflags.split(' ').count(), 3);
+        assert!(rustflags
+            .split(' ')
+            .all(|flag| flag.starts_with("--remap-path-prefix=")));
+        assert!(rustflags.contains("=/rustwide/source"));
+    }
 }
@@ -0,0 +1,180 @@
+use crate::crates::unpack::unpack_without_first_dir;
+use crate::utils::is_temp_entry;
+use failure::{Error, ResultExt};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::info;
+use remove_dir_all::remove_dir_all;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use tar::{Archive, Builder};
+use walkdir::WalkDir;
+
+/// Directory all the entries of the archive are stored in.
+static ARCHIVE_DIR: &str = "rustwide-cache";
+
+/// Write the provided directories (relative to `base`) to a gzipped tarball. Missing directories
+/// and temporary entries are skipped.
+pub(crate) fn export(base: &Path, roots: &[PathBuf], tarball: &Path) -> Result<(), Error> {
+    let temp = crate::utils::temp_path(tarball);
+    let file =
+        File::create(&temp).with_context(|_| format!("failed to create {}", temp.display()))?;
+    let mut builder = Builder::new(GzEncoder::new(BufWriter::new(file), Compression::default()));
+    builder.follow_symlinks(false);
+
+    for root in roots {
+        let dir = base.join(root);
+        if !dir.is_dir() {
+            continue;
+        }
+        info!("exporting {} to {}", dir.display(), tarball.display());
+
+        let mut entries = WalkDir::new(&dir).into_iter();
+        while let Some(entry) = entries.next() {
+            let entry = entry?;
+            if is_temp_entry(entry.file_name()) {
+                if entry.file_type().is_dir() {
+                    entries.skip_current_dir();
+                }
+                continue;
+            }
+            let name = Path::new(ARCHIVE_DIR).join(entry.path().strip_prefix(base)?);
+            if entry.file_type().is_dir() {
+                builder.append_dir(&name, entry.path())?;
+            } else {
+                builder.append_path_with_name(entry.path(), &name)?;
+            }
+        }
+    }
+
+    builder.into_inner()?.finish()?;
+    std::fs::rename(&temp, tarball)?;
+    Ok(())
+}
+
+/// Extract a tarball created by [`export`](fn.export.html) into `base`, replacing the existing
+/// files. Only the entries inside the provided directories (relative to `base`) are extracted,
+/// the rest of the archive is ignored. The archive is first extracted into `staging`, which is
+/// removed afterwards.
+pub(crate) fn import(
+    base: &Path,
+    roots: &[PathBuf],
+    tarball: &Path,
+    staging: &Path,
+    parallel: bool,
+) -> Result<(), Error> {
+    if staging.exists() {
+        remove_dir_all(staging)?;
+    }
+    let result = import_inner(base, roots, tarball, staging, parallel);
+    if staging.exists() {
+        remove_dir_all(staging)?;
+    }
+    result
+}
+
+fn import_inner(
+    base: &Path,
+    roots: &[PathBuf],
+    tarball: &Path,
+    staging: &Path,
+    parallel: bool,
+) -> Result<(), Error> {
+    let file =
+        File::open(tarball).with_context(|_| format!("failed to open {}", tarball.display()))?;
+    let mut archive = Archive::new(GzDecoder::new(BufReader::new(file)));
+    unpack_without_first_dir(&mut archive, staging, parallel)
+        .with_context(|_| format!("failed to extract {}", tarball.display()))?;
+
+    for root in roots {
+        let src = staging.join(root);
+        if !src.is_dir() {
+            continue;
+        }
+        info!("importing {} from {}", root.display(), tarball.display());
+        merge_into(&src, &base.join(root))?;
+    }
+    Ok(())
+}
+
+/// Move all the files and links inside `src` into the same location inside `dest`, replacing
+/// the existing ones.
+fn merge_into(src: &Path, dest: &Path) -> Result<(), Error> {
+    for entry in WalkDir::new(src).follow_links(false) {
+        let entry = entry?;
+        let target = dest.join(entry.path().strip_prefix(src)?);
+        if entry.file_type().is_dir() {
+            if !target.is_dir() {
+                if target.symlink_metadata().is_ok() {
+                    std::fs::remove_file(&target)?;
+                }
+                std::fs::create_dir_all(&target)?;
+            }
+        } else {
+            if target.is_dir() {
+                remove_dir_all(&target)?;
+            }
+            std::fs::rename(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export, import};
+    use failure::Error;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_export_import() -> Result<(), Error> {
+        let source = tempfile::tempdir()?;
+        let write = |path: &str, content: &str| -> Result<(), Error> {
+            let path = source.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(path, content)?;
+            Ok(())
+        };
+        write("cache/cratesio-sources/foo/foo-1.0.0.crate", "foo")?;
+        write(
+            "cache/cratesio-sources/foo/foo-2.0.0.crate.rustwide-tmp",
+            "partial",
+        )?;
+        write("cargo-home/registry/cache/index/bar.crate", "bar")?;
+        write("cargo-home/bin/tool", "not exported")?;
+
+        let roots = vec![
+            PathBuf::from("cache"),
+            PathBuf::from("cargo-home/registry/cache"),
+            PathBuf::from("rustup-home/toolchains"),
+        ];
+        let tarball = source.path().join("cache.tar.gz");
+        export(source.path(), &roots, &tarball)?;
+
+        let dest = tempfile::tempdir()?;
+        let existing = dest
+            .path()
+            .join("cache/cratesio-sources/foo/foo-1.0.0.crate");
+        std::fs::create_dir_all(existing.parent().unwrap())?;
+        std::fs::write(&existing, "outdated")?;
+        let kept = dest
+            .path()
+            .join("cache/cratesio-sources/baz/baz-1.0.0.crate");
+        std::fs::create_dir_all(kept.parent().unwrap())?;
+        std::fs::write(&kept, "baz")?;
+
+        // Only the cache directory is imported.
+        let staging = dest.path().join("staging");
+        import(dest.path(), &roots[..1], &tarball, &staging, false)?;
+
+        assert_eq!(std::fs::read_to_string(&existing)?, "foo");
+        assert_eq!(std::fs::read_to_string(&kept)?, "baz");
+        assert!(!dest
+            .path()
+            .join("cache/cratesio-sources/foo/foo-2.0.0.crate.rustwide-tmp")
+            .exists());
+        assert!(!dest.path().join("cargo-home").exists());
+        assert!(!staging.exists());
+        Ok(())
+    }
+}
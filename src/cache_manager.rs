@@ -0,0 +1,362 @@
+use crate::utils::FileLock;
+use crate::Workspace;
+use failure::Error;
+use filetime::FileTime;
+use log::info;
+use remove_dir_all::remove_dir_all;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Directories inside the cache storing the fetched crates, along with how many levels inside
+/// each directory the cache entries are.
+pub(crate) const CACHE_DIRS: &[(&str, usize)] = &[
+    ("cratesio-sources", 2),
+    ("registry-sources", 3),
+    ("git-repos", 1),
+    ("hg-repos", 1),
+    ("tarballs", 1),
+];
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CacheEntry {
+    pub(crate) size: u64,
+    pub(crate) last_used: SystemTime,
+}
+
+/// Tracks when the cached crates were last used, evicting the least recently used ones when the
+/// cache grows over its maximum size.
+///
+/// Accesses are recorded in the modification time of the cache entries, so that they're shared
+/// with other processes using the workspace. The size of the entries is kept in memory after the
+/// cache is scanned for the first time.
+pub(crate) struct CacheManager {
+    max_size: Option<u64>,
+    entries: Mutex<Option<HashMap<PathBuf, CacheEntry>>>,
+}
+
+impl CacheManager {
+    pub(crate) fn new(max_size: Option<u64>) -> Self {
+        CacheManager {
+            max_size,
+            entries: Mutex::new(None),
+        }
+    }
+
+    /// Record that a cache entry was just used, evicting the least recently used entries if the
+    /// cache is now bigger than its maximum size. The used entry itself is never evicted.
+    pub(crate) fn record_access(&self, cache_dir: &Path, entry: &Path) -> Result<(), Error> {
+        if !entry.exists() {
+            return Ok(());
+        }
+        let now = SystemTime::now();
+        filetime::set_file_mtime(entry, FileTime::from_system_time(now))?;
+
+        let max_size = match self.max_size {
+            Some(max_size) => max_size,
+            None => return Ok(()),
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if entries.is_none() {
+            *entries = Some(scan(cache_dir)?);
+        }
+        let entries = entries.as_mut().unwrap();
+        entries.insert(
+            entry.into(),
+            CacheEntry {
+                size: entry_size(entry)?,
+                last_used: now,
+            },
+        );
+        evict(entries, max_size, Some(entry))?;
+        Ok(())
+    }
+
+    /// Scan the cache again and evict the least recently used entries until it fits in its
+    /// maximum size, returning the number of bytes freed.
+    pub(crate) fn gc(&self, cache_dir: &Path) -> Result<u64, Error> {
+        let mut entries = self.entries.lock().unwrap();
+        let mut scanned = scan(cache_dir)?;
+        let freed = match self.max_size {
+            Some(max_size) => evict(&mut scanned, max_size, None)?,
+            None => 0,
+        };
+        *entries = Some(scanned);
+        Ok(freed)
+    }
+}
+
+/// List all the entries of the cache, with their size and the last time they were used.
+pub(crate) fn scan(cache_dir: &Path) -> Result<HashMap<PathBuf, CacheEntry>, Error> {
+    let mut entries = HashMap::new();
+    for &(dir, depth) in CACHE_DIRS {
+        scan_dir(&cache_dir.join(dir), depth, &mut entries)?;
+    }
+    Ok(entries)
+}
+
+fn scan_dir(
+    dir: &Path,
+    depth: usize,
+    entries: &mut HashMap<PathBuf, CacheEntry>,
+) -> Result<(), Error> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if depth > 1 {
+            if path.is_dir() {
+                scan_dir(&path, depth - 1, entries)?;
+            }
+        } else if is_cache_entry(&path) {
+            let cached = CacheEntry {
+                size: entry_size(&path)?,
+                last_used: last_used(&path)?,
+            };
+            entries.insert(path, cached);
+        }
+    }
+    Ok(())
+}
+
+/// Remove the least recently used entries (except `keep`) until the cache fits in `max_size`,
+/// returning the number of bytes freed.
+///
+/// Entries locked by other processes, or used by them since the cache was scanned, are kept and
+/// their last use is refreshed.
+fn evict(
+    entries: &mut HashMap<PathBuf, CacheEntry>,
+    max_size: u64,
+    keep: Option<&Path>,
+) -> Result<u64, Error> {
+    let mut total: u64 = entries.values().map(|entry| entry.size).sum();
+    if total <= max_size {
+        return Ok(0);
+    }
+
+    let mut lru: Vec<_> = entries
+        .iter()
+        .filter(|(path, _)| Some(path.as_path()) != keep)
+        .map(|(path, entry)| (entry.last_used, path.clone()))
+        .collect();
+    lru.sort();
+
+    let mut freed = 0;
+    for (scanned, path) in lru {
+        if total <= max_size {
+            break;
+        }
+        if remove_unused_entry(&path, scanned)? {
+            let size = entries.remove(&path).map(|entry| entry.size).unwrap_or(0);
+            info!("evicted cache entry {} ({} bytes)", path.display(), size);
+            total -= size;
+            freed += size;
+        } else if path.exists() {
+            if let Some(entry) = entries.get_mut(&path) {
+                entry.last_used = last_used(&path)?;
+            }
+        } else {
+            // Other processes using the workspace might have removed the entry already.
+            total -= entries.remove(&path).map(|entry| entry.size).unwrap_or(0);
+        }
+    }
+    Ok(freed)
+}
+
+/// Return the path of the lock protecting a cache entry. The lock is held shared while the entry is
+/// read, and exclusively while it's updated or removed.
+pub(crate) fn entry_lock_path(entry: &Path) -> PathBuf {
+    let mut path = entry.as_os_str().to_os_string();
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+/// Lock a cache entry, blocking until the lock is available.
+pub(crate) async fn lock_entry(
+    workspace: &Workspace,
+    entry: &Path,
+    shared: bool,
+) -> Result<FileLock, Error> {
+    let path = entry_lock_path(entry);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let msg = format!("use the cache entry {}", entry.display());
+    crate::utils::lock_file(workspace.runtime(), &path, shared, &msg).await
+}
+
+/// Remove a cache entry if it wasn't used after `used_before`, returning whether it was removed.
+///
+/// The entry is removed while holding its exclusive lock, and skipped if another process holds
+/// the lock (as it's using the entry right now). Its last use is checked again after the lock is
+/// acquired, as other processes might have used it since it was scanned.
+pub(crate) fn remove_unused_entry(entry: &Path, used_before: SystemTime) -> Result<bool, Error> {
+    if !entry.exists() {
+        return Ok(false);
+    }
+    let _lock = match crate::utils::try_lock_file_exclusive(&entry_lock_path(entry))? {
+        Some(lock) => lock,
+        None => return Ok(false),
+    };
+
+    // Another process might have removed the entry before the lock was acquired.
+    if !entry.exists() || last_used(entry)? > used_before {
+        return Ok(false);
+    }
+    if entry.is_dir() {
+        remove_dir_all(entry)?;
+    } else {
+        std::fs::remove_file(entry)?;
+        let validators = crate::download::validators_path(entry);
+        if validators.exists() {
+            std::fs::remove_file(validators)?;
+        }
+    }
+    Ok(true)
+}
+
+/// Check whether a file inside the cache directories is a cache entry, rather than the lock or the
+/// download validators of an entry, or an entry still being written.
+pub(crate) fn is_cache_entry(path: &Path) -> bool {
+    let name = match path.file_name() {
+        Some(name) => name,
+        None => return false,
+    };
+    let extension = path.extension().and_then(|ext| ext.to_str());
+    extension != Some("lock")
+        && extension != Some(crate::download::VALIDATORS_EXTENSION)
+        && !crate::utils::is_temp_entry(name)
+}
+
+fn entry_size(path: &Path) -> Result<u64, Error> {
+    if path.is_dir() {
+        crate::utils::dir_size(path)
+    } else {
+        Ok(path.metadata()?.len())
+    }
+}
+
+/// Return the last time a cache entry was used, which is the most recent modification time of the
+/// entry itself or of its direct children (updating a git repository doesn't touch its root).
+pub(crate) fn last_used(path: &Path) -> Result<SystemTime, Error> {
+    let mut last = path.metadata()?.modified()?;
+    if path.is_dir() {
+        for child in std::fs::read_dir(path)? {
+            let modified = child?.metadata()?.modified()?;
+            if modified > last {
+                last = modified;
+            }
+        }
+    }
+    Ok(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{entry_lock_path, evict, scan, CacheManager};
+    use failure::Error;
+    use filetime::FileTime;
+    use std::path::Path;
+
+    fn cached_crate(cache: &Path, name: &str, size: usize, age: i64) -> Result<(), Error> {
+        let dir = cache.join("cratesio-sources").join(name);
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}-1.0.0.crate", name));
+        std::fs::write(&path, vec![0; size])?;
+        let mtime = FileTime::from_unix_time(FileTime::now().unix_seconds() - age, 0);
+        filetime::set_file_mtime(&path, mtime)?;
+        Ok(())
+    }
+
+    fn exists(cache: &Path, name: &str) -> bool {
+        cache
+            .join("cratesio-sources")
+            .join(name)
+            .join(format!("{}-1.0.0.crate", name))
+            .exists()
+    }
+
+    #[test]
+    fn test_gc() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        cached_crate(dir.path(), "old", 100, 300)?;
+        cached_crate(dir.path(), "middle", 100, 200)?;
+        cached_crate(dir.path(), "new", 100, 100)?;
+        std::fs::create_dir_all(dir.path().join("git-repos"))?;
+        std::fs::write(
+            dir.path().join("git-repos").join("repo.lock"),
+            vec![0; 1000],
+        )?;
+        let download = crate::utils::temp_path(&dir.path().join("tarballs").join("a"));
+        std::fs::create_dir_all(&download)?;
+        std::fs::write(download.join("a"), vec![0; 1000])?;
+        let validators = crate::download::validators_path(
+            &dir.path().join("cratesio-sources/old/old-1.0.0.crate"),
+        );
+        std::fs::write(&validators, vec![0; 1000])?;
+
+        // Nothing is evicted without a maximum size...
+        assert_eq!(CacheManager::new(None).gc(dir.path())?, 0);
+        assert!(exists(dir.path(), "old"));
+
+        // ...while the least recently used entries are evicted first (along with their
+        // validators), ignoring the lock files and the in-progress downloads.
+        assert_eq!(CacheManager::new(Some(150)).gc(dir.path())?, 200);
+        assert!(!exists(dir.path(), "old"));
+        assert!(!exists(dir.path(), "middle"));
+        assert!(exists(dir.path(), "new"));
+        assert!(dir.path().join("git-repos").join("repo.lock").exists());
+        assert!(download.exists());
+        assert!(!validators.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_access() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        cached_crate(dir.path(), "old", 100, 300)?;
+        cached_crate(dir.path(), "middle", 100, 200)?;
+        let manager = CacheManager::new(Some(250));
+        manager.gc(dir.path())?;
+
+        // Using the oldest entry makes it the most recently used one.
+        let old = dir.path().join("cratesio-sources/old/old-1.0.0.crate");
+        manager.record_access(dir.path(), &old)?;
+
+        cached_crate(dir.path(), "new", 100, 0)?;
+        let new = dir.path().join("cratesio-sources/new/new-1.0.0.crate");
+        manager.record_access(dir.path(), &new)?;
+        assert!(exists(dir.path(), "old"));
+        assert!(!exists(dir.path(), "middle"));
+        assert!(exists(dir.path(), "new"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_evict_entries_in_use() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        cached_crate(dir.path(), "old", 100, 300)?;
+        cached_crate(dir.path(), "middle", 100, 200)?;
+        cached_crate(dir.path(), "new", 100, 100)?;
+        let mut entries = scan(dir.path())?;
+
+        // Another process uses the oldest entry after the scan, and is reading the middle one.
+        cached_crate(dir.path(), "old", 100, 0)?;
+        let middle = dir
+            .path()
+            .join("cratesio-sources/middle/middle-1.0.0.crate");
+        let lock = crate::utils::try_lock_file_exclusive(&entry_lock_path(&middle))?;
+        assert!(lock.is_some());
+
+        assert_eq!(evict(&mut entries, 150, None)?, 100);
+        assert!(exists(dir.path(), "old"));
+        assert!(exists(dir.path(), "middle"));
+        assert!(!exists(dir.path(), "new"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+use crate::cache_manager::{last_used, scan};
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Kind of a [`CachedItem`](struct.CachedItem.html).
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum CacheKind {
+    /// A crate downloaded from crates.io.
+    CratesIo,
+    /// A crate downloaded from an alternative registry.
+    Registry,
+    /// A mirror of a git repository.
+    Git,
+    /// A clone of a Mercurial repository.
+    Mercurial,
+    /// A downloaded archive.
+    Tarball,
+    /// A toolchain installed in the workspace.
+    Toolchain,
+}
+
+impl CacheKind {
+    fn from_cache_dir(dir: &str) -> Option<Self> {
+        match dir {
+            "cratesio-sources" => Some(CacheKind::CratesIo),
+            "registry-sources" => Some(CacheKind::Registry),
+            "git-repos" => Some(CacheKind::Git),
+            "hg-repos" => Some(CacheKind::Mercurial),
+            "tarballs" => Some(CacheKind::Tarball),
+            _ => None,
+        }
+    }
+}
+
+/// Item stored in the caches of a workspace, along with its size on disk.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CachedItem {
+    kind: CacheKind,
+    name: String,
+    path: PathBuf,
+    size: u64,
+    last_used: SystemTime,
+}
+
+impl CachedItem {
+    /// Return the kind of the item.
+    pub fn kind(&self) -> CacheKind {
+        self.kind
+    }
+
+    /// Return the name of the item: the name of toolchains, and the path relative to their cache
+    /// directory for everything else (like `serde/serde-1.0.0.crate` for crates.io crates).
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the absolute path of the item on disk.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return the size of the item in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Return the last time the item was used, or the last time it was modified for toolchains.
+    pub fn last_used(&self) -> SystemTime {
+        self.last_used
+    }
+}
+
+/// Disk usage of the caches of a workspace, created by
+/// [`Workspace::cache_report`](struct.Workspace.html#method.cache_report).
+///
+/// The report includes the fetched crates, the mirrors of repositories, the downloaded archives
+/// and the toolchains installed in the workspace. Toolchains linked from elsewhere on the host
+/// are not included, as they don't use space in the workspace.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CacheReport {
+    items: Vec<CachedItem>,
+}
+
+impl CacheReport {
+    pub(crate) fn collect(cache_dir: &Path, rustup_home: &Path) -> Result<Self, Error> {
+        let mut items = Vec::new();
+        for (path, entry) in scan(cache_dir)? {
+            let relative = path.strip_prefix(cache_dir)?;
+            let mut components = relative.iter().filter_map(|c| c.to_str());
+            let kind = match components.next().and_then(CacheKind::from_cache_dir) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            items.push(CachedItem {
+                kind,
+                name: components.collect::<Vec<_>>().join("/"),
+                path,
+                size: entry.size,
+                last_used: entry.last_used,
+            });
+        }
+
+        let toolchains = rustup_home.join("toolchains");
+        if toolchains.is_dir() {
+            for entry in std::fs::read_dir(&toolchains)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_dir() {
+                    continue;
+                }
+                let path = entry.path();
+                items.push(CachedItem {
+                    kind: CacheKind::Toolchain,
+                    name: entry.file_name().to_string_lossy().into_owned(),
+                    size: crate::utils::dir_size(&path)?,
+                    last_used: last_used(&path)?,
+                    path,
+                });
+            }
+        }
+
+        items.sort_by(|a, b| (a.kind, &a.name).cmp(&(b.kind, &b.name)));
+        Ok(CacheReport { items })
+    }
+
+    /// Return all the cached items, sorted by kind and name.
+    pub fn items(&self) -> &[CachedItem] {
+        &self.items
+    }
+
+    /// Return the cached items of the provided kind, sorted by name.
+    pub fn items_of_kind(&self, kind: CacheKind) -> impl Iterator<Item = &CachedItem> {
+        self.items.iter().filter(move |item| item.kind == kind)
+    }
+
+    /// Return the cached items sorted from the least to the most recently used, which is the
+    /// order they're evicted in when the caches are too big.
+    pub fn least_recently_used(&self) -> Vec<&CachedItem> {
+        let mut items: Vec<_> = self
+            .items
+            .iter()
+            .filter(|item| item.kind != CacheKind::Toolchain)
+            .collect();
+        items.sort_by_key(|item| item.last_used);
+        items
+    }
+
+    /// Return the total size in bytes of the cached items of the provided kind.
+    pub fn size_of_kind(&self, kind: CacheKind) -> u64 {
+        self.items_of_kind(kind).map(|item| item.size).sum()
+    }
+
+    /// Return the total size in bytes of all the cached items.
+    pub fn total_size(&self) -> u64 {
+        self.items.iter().map(|item| item.size).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheKind, CacheReport};
+    use failure::Error;
+
+    #[test]
+    fn test_collect() -> Result<(), Error> {
+        let cache = tempfile::tempdir()?;
+        let serde = cache.path().join("cratesio-sources").join("serde");
+        std::fs::create_dir_all(&serde)?;
+        std::fs::write(serde.join("serde-1.0.0.crate"), vec![0; 100])?;
+        std::fs::write(serde.join("serde-1.0.0.crate.lock"), vec![])?;
+        let repo = cache.path().join("git-repos").join("example");
+        std::fs::create_dir_all(repo.join("objects"))?;
+        std::fs::write(repo.join("HEAD"), vec![0; 10])?;
+        std::fs::write(repo.join("objects").join("pack"), vec![0; 1000])?;
+
+        let rustup_home = tempfile::tempdir()?;
+        let toolchain = rustup_home.path().join("toolchains").join("stable");
+        std::fs::create_dir_all(toolchain.join("bin"))?;
+        std::fs::write(toolchain.join("bin").join("rustc"), vec![0; 5000])?;
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(
+            rustup_home.path(),
+            rustup_home.path().join("toolchains").join("linked"),
+        )?;
+
+        let report = CacheReport::collect(cache.path(), rustup_home.path())?;
+        let items: Vec<_> = report
+            .items()
+            .iter()
+            .map(|item| (item.kind(), item.name(), item.size()))
+            .collect();
+        assert_eq!(
+            items,
+            vec![
+                (CacheKind::CratesIo, "serde/serde-1.0.0.crate", 100),
+                (CacheKind::Git, "example", 1010),
+                (CacheKind::Toolchain, "stable", 5000),
+            ]
+        );
+        assert_eq!(report.size_of_kind(CacheKind::Git), 1010);
+        assert_eq!(report.size_of_kind(CacheKind::Tarball), 0);
+        assert_eq!(report.total_size(), 6110);
+        assert_eq!(report.least_recently_used().len(), 2);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+use crate::Workspace;
+use failure::Error;
+use std::fmt::Write as _;
+use std::time::Duration;
+use tokio::fs;
+
+/// Name of the cargo configuration file managed by rustwide, inside the workspace's `CARGO_HOME`.
+const CONFIG_FILE: &str = "config.toml";
+
+/// Network settings of the cargo commands executed by rustwide, configured with
+/// [`WorkspaceBuilder::cargo_network`](struct.WorkspaceBuilder.html#method.cargo_network).
+///
+/// The settings are written into the cargo configuration managed by rustwide, and are also passed
+/// as environment variables to the cargo commands, taking precedence over the configuration of
+/// the crates being built. Settings that are not set keep cargo's default.
+///
+/// # Example
+///
+/// ```no_run
+/// # use rustwide::{CargoNetworkConfig, WorkspaceBuilder};
+/// # use std::error::Error;
+/// # async fn run() -> Result<(), Box<dyn Error>> {
+/// let workspace = WorkspaceBuilder::new("work".as_ref(), "my-app")
+///     .cargo_network(CargoNetworkConfig::new().git_fetch_with_cli(true).retry(5))
+///     .init()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CargoNetworkConfig {
+    git_fetch_with_cli: Option<bool>,
+    retry: Option<u32>,
+    offline: Option<bool>,
+    http_timeout: Option<Duration>,
+    http_multiplexing: Option<bool>,
+}
+
+impl CargoNetworkConfig {
+    /// Create a new configuration, using cargo's default for all the settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set whether cargo uses the `git` executable to fetch git repositories instead of libgit2
+    /// (`net.git-fetch-with-cli`). The git CLI works in some network setups where libgit2 fails,
+    /// for example behind proxies it doesn't support.
+    pub fn git_fetch_with_cli(mut self, enable: bool) -> Self {
+        self.git_fetch_with_cli = Some(enable);
+        self
+    }
+
+    /// Set how many times cargo retries network requests failing with spurious errors
+    /// (`net.retry`).
+    pub fn retry(mut self, retries: u32) -> Self {
+        self.retry = Some(retries);
+        self
+    }
+
+    /// Set whether cargo runs without accessing the network (`net.offline`).
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.offline = Some(offline);
+        self
+    }
+
+    /// Set the timeout of cargo's HTTP requests (`http.timeout`). The timeout has a precision of
+    /// one second.
+    pub fn http_timeout(mut self, timeout: Duration) -> Self {
+        self.http_timeout = Some(timeout);
+        self
+    }
+
+    /// Set whether cargo uses HTTP/2 multiplexing (`http.multiplexing`).
+    pub fn http_multiplexing(mut self, enable: bool) -> Self {
+        self.http_multiplexing = Some(enable);
+        self
+    }
+
+    /// Get the environment variables applying this configuration to cargo.
+    pub(crate) fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = Vec::new();
+        if let Some(enable) = self.git_fetch_with_cli {
+            env.push(("CARGO_NET_GIT_FETCH_WITH_CLI", enable.to_string()));
+        }
+        if let Some(retries) = self.retry {
+            env.push(("CARGO_NET_RETRY", retries.to_string()));
+        }
+        if let Some(offline) = self.offline {
+            env.push(("CARGO_NET_OFFLINE", offline.to_string()));
+        }
+        if let Some(timeout) = self.http_timeout {
+            env.push(("CARGO_HTTP_TIMEOUT", timeout.as_secs().to_string()));
+        }
+        if let Some(enable) = self.http_multiplexing {
+            env.push(("CARGO_HTTP_MULTIPLEXING", enable.to_string()));
+        }
+        env
+    }
+
+    /// Render this configuration as a cargo configuration file.
+    fn to_toml(&self) -> String {
+        let mut net = String::new();
+        if let Some(enable) = self.git_fetch_with_cli {
+            writeln!(net, "git-fetch-with-cli = {}", enable).unwrap();
+        }
+        if let Some(retries) = self.retry {
+            writeln!(net, "retry = {}", retries).unwrap();
+        }
+        if let Some(offline) = self.offline {
+            writeln!(net, "offline = {}", offline).unwrap();
+        }
+
+        let mut http = String::new();
+        if let Some(timeout) = self.http_timeout {
+            writeln!(http, "timeout = {}", timeout.as_secs()).unwrap();
+        }
+        if let Some(enable) = self.http_multiplexing {
+            writeln!(http, "multiplexing = {}", enable).unwrap();
+        }
+
+        let mut config =
+            String::from("# This file is managed by rustwide, and it's overwritten.\n");
+        for (table, content) in &[("net", net), ("http", http)] {
+            if !content.is_empty() {
+                write!(config, "\n[{}]\n{}", table, content).unwrap();
+            }
+        }
+        config
+    }
+}
+
+/// Write the network configuration of the workspace into its `CARGO_HOME`, replacing the one
+/// written by previous initializations.
+pub(crate) async fn write(workspace: &Workspace) -> Result<(), Error> {
+    let cargo_home = workspace.cargo_home();
+    fs::create_dir_all(&cargo_home).await?;
+    crate::utils::write_atomic(
+        &cargo_home.join(CONFIG_FILE),
+        workspace.cargo_network().to_toml().as_bytes(),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CargoNetworkConfig;
+    use std::time::Duration;
+
+    #[test]
+    fn test_render() {
+        let config = CargoNetworkConfig::new()
+            .git_fetch_with_cli(true)
+            .retry(5)
+            .http_timeout(Duration::from_secs(60));
+
+        let parsed: toml::Value = toml::from_str(&config.to_toml()).unwrap();
+        assert_eq!(
+            parsed,
+            toml! {
+                [net]
+                git-fetch-with-cli = true
+                retry = 5
+
+                [http]
+                timeout = 60
+            }
+        );
+        assert_eq!(
+            config.env(),
+            vec![
+                ("CARGO_NET_GIT_FETCH_WITH_CLI", "true".to_string()),
+                ("CARGO_NET_RETRY", "5".to_string()),
+                ("CARGO_HTTP_TIMEOUT", "60".to_string()),
+            ]
+        );
+
+        let empty: toml::Value = toml::from_str(&CargoNetworkConfig::new().to_toml()).unwrap();
+        assert_eq!(empty, toml::Value::Table(Default::default()));
+    }
+}
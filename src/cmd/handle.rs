@@ -0,0 +1,87 @@
+use crate::cmd::SandboxInvocation;
+use std::sync::{Arc, Mutex};
+
+/// Handle to the process or the container of a running [`Command`](struct.Command.html), allowing
+/// external tools to inspect or kill it while it runs.
+///
+/// The handle is attached to a command with [`Command::handle`](struct.Command.html#method.handle),
+/// and it can be cloned and moved to other threads. Its details are only available while the
+/// command is running.
+///
+/// # Example
+///
+/// ```no_run
+/// # use rustwide::{cmd::{Command, CommandHandle}, WorkspaceBuilder};
+/// # use std::error::Error;
+/// # async fn run() -> Result<(), Box<dyn Error>> {
+/// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init().await?;
+/// let handle = CommandHandle::new();
+/// let supervisor = handle.clone();
+/// std::thread::spawn(move || loop {
+///     if let Some(pid) = supervisor.pid() {
+///         println!("cargo is running with PID {}", pid);
+///     }
+///     std::thread::sleep(std::time::Duration::from_secs(1));
+/// });
+///
+/// Command::new(&workspace, "cargo")
+///     .args(&["build"])
+///     .handle(&handle)
+///     .run()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct CommandHandle {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    pid: Option<u32>,
+    container_id: Option<String>,
+    sandbox_invocation: Option<SandboxInvocation>,
+}
+
+impl CommandHandle {
+    /// Create a new handle, not attached to any command yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the PID on the host of the running process, if the command is unsandboxed.
+    pub fn pid(&self) -> Option<u32> {
+        self.state.lock().unwrap().pid
+    }
+
+    /// Get the ID of the Docker container the command runs in, if the command is sandboxed.
+    pub fn container_id(&self) -> Option<String> {
+        self.state.lock().unwrap().container_id.clone()
+    }
+
+    /// Get how to run the last sandboxed command attached to the handle again by hand, outside of
+    /// rustwide. Unlike the other details, the invocation is kept once the command ends, so that
+    /// failed commands can be reproduced.
+    pub fn sandbox_invocation(&self) -> Option<SandboxInvocation> {
+        self.state.lock().unwrap().sandbox_invocation.clone()
+    }
+
+    /// Check whether the command is currently running.
+    pub fn is_running(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.pid.is_some() || state.container_id.is_some()
+    }
+
+    pub(super) fn set_pid(&self, pid: Option<u32>) {
+        self.state.lock().unwrap().pid = pid;
+    }
+
+    pub(super) fn set_container_id(&self, id: Option<String>) {
+        self.state.lock().unwrap().container_id = id;
+    }
+
+    pub(super) fn set_sandbox_invocation(&self, invocation: SandboxInvocation) {
+        self.state.lock().unwrap().sandbox_invocation = Some(invocation);
+    }
+}
@@ -0,0 +1,252 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Exact invocation of a sandboxed command, allowing it to be re-run by hand outside of rustwide.
+///
+/// The invocation is returned by [`ProcessOutput::sandbox_invocation`] for successful commands,
+/// and is kept by the [`CommandHandle`] attached to the command once it ends, including when it
+/// fails. The values redacted from the logs are redacted here as well, and have to be filled in by
+/// hand before running the command again.
+///
+/// [`ProcessOutput::sandbox_invocation`]: struct.ProcessOutput.html#method.sandbox_invocation
+/// [`CommandHandle`]: struct.CommandHandle.html
+///
+/// # Example
+///
+/// ```no_run
+/// # use rustwide::{cmd::{Command, CommandHandle, SandboxBuilder}, WorkspaceBuilder};
+/// # use std::error::Error;
+/// # async fn run() -> Result<(), Box<dyn Error>> {
+/// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init().await?;
+/// let handle = CommandHandle::new();
+/// let res = Command::new_sandboxed(&workspace, SandboxBuilder::new(), "cargo")
+///     .args(&["build"])
+///     .handle(&handle)
+///     .run()
+///     .await;
+/// if res.is_err() {
+///     if let Some(invocation) = handle.sandbox_invocation() {
+///         eprintln!("reproduce with: {}", invocation);
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SandboxInvocation {
+    image: String,
+    mounts: Vec<InvocationMount>,
+    env: BTreeMap<String, String>,
+    user: Option<String>,
+    workdir: Option<String>,
+    memory_limit: Option<usize>,
+    cpu_limit: Option<f32>,
+    networking: bool,
+    command: Vec<String>,
+    docker_args: Vec<String>,
+}
+
+impl SandboxInvocation {
+    /// Get the image the command ran in, identified by its ID when it could be resolved, so that
+    /// the same image is used even if its tag is moved later.
+    pub fn image(&self) -> &str {
+        &self.image
+    }
+
+    /// Get the paths mounted inside the sandbox.
+    pub fn mounts(&self) -> &[InvocationMount] {
+        &self.mounts
+    }
+
+    /// Get the environment variables set inside the sandbox.
+    pub fn env(&self) -> &BTreeMap<String, String> {
+        &self.env
+    }
+
+    /// Get the user the command ran as, if a custom one was set.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// Get the directory the command ran in, inside the sandbox.
+    pub fn workdir(&self) -> Option<&str> {
+        self.workdir.as_deref()
+    }
+
+    /// Get the memory limit of the sandbox in bytes, if any.
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory_limit
+    }
+
+    /// Get the CPU limit of the sandbox in cores, if any.
+    pub fn cpu_limit(&self) -> Option<f32> {
+        self.cpu_limit
+    }
+
+    /// Check whether networking was enabled inside the sandbox.
+    pub fn networking(&self) -> bool {
+        self.networking
+    }
+
+    /// Get the command executed inside the sandbox, including its arguments.
+    pub fn command(&self) -> &[String] {
+        &self.command
+    }
+
+    /// Get the arguments to pass to `docker` to run the command again, starting with `run`.
+    pub fn docker_args(&self) -> &[String] {
+        &self.docker_args
+    }
+
+    /// Get a shell command running the command again, with the arguments quoted for POSIX shells.
+    pub fn shell_command(&self) -> String {
+        std::iter::once("docker")
+            .chain(self.docker_args.iter().map(|arg| arg.as_str()))
+            .map(shell_quote)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl fmt::Display for SandboxInvocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.shell_command())
+    }
+}
+
+/// Path mounted inside the sandbox of a [`SandboxInvocation`](struct.SandboxInvocation.html).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InvocationMount {
+    host_path: PathBuf,
+    sandbox_path: PathBuf,
+    writable: bool,
+}
+
+impl InvocationMount {
+    pub(super) fn new(host_path: PathBuf, sandbox_path: PathBuf, writable: bool) -> Self {
+        InvocationMount {
+            host_path,
+            sandbox_path,
+            writable,
+        }
+    }
+
+    /// Get the mounted path on the host.
+    pub fn host_path(&self) -> &Path {
+        &self.host_path
+    }
+
+    /// Get the path the mount is available at inside the sandbox.
+    pub fn sandbox_path(&self) -> &Path {
+        &self.sandbox_path
+    }
+
+    /// Check whether the sandboxed code could change the mounted data.
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+}
+
+/// Arguments of `docker create` describing a sandbox, recorded as a
+/// [`SandboxInvocation`](struct.SandboxInvocation.html).
+pub(super) struct InvocationBuilder {
+    pub(super) image: String,
+    pub(super) mounts: Vec<InvocationMount>,
+    pub(super) env: BTreeMap<String, String>,
+    pub(super) user: Option<String>,
+    pub(super) workdir: Option<String>,
+    pub(super) memory_limit: Option<usize>,
+    pub(super) cpu_limit: Option<f32>,
+    pub(super) networking: bool,
+    pub(super) command: Vec<String>,
+}
+
+impl InvocationBuilder {
+    /// Build the invocation from the arguments passed to `docker create` after the container
+    /// label, which are passed to `docker run --rm -i` when the command is run again.
+    pub(super) fn build(self, create_args: &[String]) -> SandboxInvocation {
+        let mut docker_args = vec!["run".to_string(), "--rm".to_string(), "-i".to_string()];
+        docker_args.extend(create_args.iter().cloned());
+        SandboxInvocation {
+            image: self.image,
+            mounts: self.mounts,
+            env: self.env,
+            user: self.user,
+            workdir: self.workdir,
+            memory_limit: self.memory_limit,
+            cpu_limit: self.cpu_limit,
+            networking: self.networking,
+            command: self.command,
+            docker_args,
+        }
+    }
+}
+
+/// Quote an argument for POSIX shells, leaving it as is if it doesn't contain special characters.
+fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=,@%+".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{shell_quote, InvocationBuilder, InvocationMount};
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("cargo"), "cargo");
+        assert_eq!(
+            shell_quote("type=bind,src=/a,dst=/b"),
+            "type=bind,src=/a,dst=/b"
+        );
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+    }
+
+    #[test]
+    fn test_shell_command() {
+        let invocation = InvocationBuilder {
+            image: "sha256:1234".into(),
+            mounts: vec![InvocationMount::new(
+                PathBuf::from("/src"),
+                PathBuf::from("/opt/rustwide/workdir"),
+                false,
+            )],
+            env: BTreeMap::new(),
+            user: None,
+            workdir: None,
+            memory_limit: None,
+            cpu_limit: None,
+            networking: false,
+            command: vec!["cargo".into(), "build".into()],
+        }
+        .build(&[
+            "-v".into(),
+            "/src:/opt/rustwide/workdir:ro,Z".into(),
+            "-e".into(),
+            "RUSTFLAGS=--cfg foo".into(),
+            "--network".into(),
+            "none".into(),
+            "sha256:1234".into(),
+            "cargo".into(),
+            "build".into(),
+        ]);
+
+        assert_eq!(
+            invocation.shell_command(),
+            "docker run --rm -i -v /src:/opt/rustwide/workdir:ro,Z -e 'RUSTFLAGS=--cfg foo' \
+             --network none sha256:1234 cargo build"
+        );
+        assert_eq!(invocation.to_string(), invocation.shell_command());
+    }
+}
@@ -1,10 +1,23 @@
 //! Command execution and sandboxing.
 
+mod handle;
+mod invocation;
+mod network;
 mod process_lines_actions;
 mod sandbox;
-
+mod sandbox_command;
+mod snapshot;
+
+pub use handle::CommandHandle;
+use invocation::InvocationBuilder;
+pub use invocation::{InvocationMount, SandboxInvocation};
+pub(crate) use network::NetworkRecorder;
+pub use network::NetworkUsage;
 pub use process_lines_actions::ProcessLinesActions;
 pub use sandbox::*;
+pub use sandbox_command::SandboxCommand;
+pub use snapshot::EnvironmentSnapshot;
+pub(crate) use snapshot::SnapshotRecorder;
 
 use crate::native;
 use crate::workspace::Workspace;
@@ -15,6 +28,7 @@ use futures_util::{
 };
 use log::{error, info};
 use process_lines_actions::InnerState;
+use std::borrow::Cow;
 use std::convert::AsRef;
 use std::env::consts::EXE_SUFFIX;
 use std::ffi::{OsStr, OsString};
@@ -45,14 +59,16 @@ pub(crate) mod container_dirs {
     lazy_static! {
         pub(crate) static ref WORK_DIR: PathBuf = ROOT_DIR.join("workdir");
         pub(crate) static ref TARGET_DIR: PathBuf = ROOT_DIR.join("target");
-        pub(super) static ref CARGO_HOME: PathBuf = ROOT_DIR.join("cargo-home");
+        pub(crate) static ref HOME_DIR: PathBuf = ROOT_DIR.join("home");
+        pub(crate) static ref CARGO_HOME: PathBuf = ROOT_DIR.join("cargo-home");
         pub(super) static ref RUSTUP_HOME: PathBuf = ROOT_DIR.join("rustup-home");
         pub(super) static ref CARGO_BIN_DIR: PathBuf = CARGO_HOME.join("bin");
+        pub(super) static ref HOST_TOOLCHAIN: PathBuf = ROOT_DIR.join("host-toolchain");
     }
 }
 
 /// Error happened while executing a command.
-#[derive(Debug, Fail)]
+#[derive(Debug, Clone, Fail)]
 pub enum CommandError {
     /// The command didn't output anything to stdout or stderr for more than the timeout, and it
     /// was killed. The timeout's value (in seconds) is the first value.
@@ -65,6 +81,15 @@ pub enum CommandError {
     /// The sandbox ran out of memory and was killed.
     #[fail(display = "container ran out of memory")]
     SandboxOOM,
+    /// The command was cancelled because the workspace is shutting down, or because all the
+    /// running commands were cancelled with `Workspace::cancel_all`.
+    #[fail(display = "the command was cancelled")]
+    Cancelled,
+    /// The sandbox failed because of a known transient problem of the Docker daemon (like a
+    /// timeout, a race with the removal of the container or a restart of containerd) rather than
+    /// because of the command itself. The error reported by Docker is the first value.
+    #[fail(display = "spurious Docker failure: {}", _0)]
+    SpuriousDockerFailure(String),
     #[doc(hidden)]
     #[fail(display = "this error shouldn't have happened")]
     __NonExaustive,
@@ -133,12 +158,17 @@ pub struct Command<'w, 'pl> {
     binary: Binary,
     args: Vec<OsString>,
     env: Vec<(OsString, OsString)>,
-    process_lines: Option<&'pl mut dyn FnMut(&str, &mut ProcessLinesActions)>,
+    process_lines: Option<&'pl mut (dyn FnMut(&str, &mut ProcessLinesActions) + Send)>,
     cd: Option<PathBuf>,
     timeout: Option<Duration>,
     no_output_timeout: Option<Duration>,
     log_command: bool,
     log_output: bool,
+    low_priority: bool,
+    memory_limit: Option<usize>,
+    cpu_time_limit: Option<Duration>,
+    redacted: Vec<String>,
+    handle: Option<CommandHandle>,
 }
 
 impl<'w, 'pl> Command<'w, 'pl> {
@@ -148,6 +178,10 @@ impl<'w, 'pl> Command<'w, 'pl> {
     }
 
     /// Create a new, sandboxed command.
+    ///
+    /// The workspace's toolchains and cargo home are mounted inside the sandbox, and the directory
+    /// set with [`cd`](#method.cd) is mounted as the working directory. Use
+    /// [`SandboxCommand`](struct.SandboxCommand.html) to run commands without any of that setup.
     pub fn new_sandboxed<R: Runnable>(
         workspace: &'w Workspace,
         sandbox: SandboxBuilder,
@@ -169,13 +203,14 @@ impl<'w, 'pl> Command<'w, 'pl> {
         workspace: Option<&'w Workspace>,
         sandbox: Option<SandboxBuilder>,
     ) -> Self {
-        let (timeout, no_output_timeout) = if let Some(workspace) = workspace {
+        let (timeout, no_output_timeout, low_priority) = if let Some(workspace) = workspace {
             (
                 workspace.default_command_timeout(),
                 workspace.default_command_no_output_timeout(),
+                workspace.default_command_low_priority(),
             )
         } else {
-            (None, None)
+            (None, None, false)
         };
         Command {
             workspace,
@@ -189,9 +224,74 @@ impl<'w, 'pl> Command<'w, 'pl> {
             no_output_timeout,
             log_output: true,
             log_command: true,
+            low_priority,
+            memory_limit: None,
+            cpu_time_limit: None,
+            redacted: Vec::new(),
+            handle: None,
         }
     }
 
+    /// Execute the provided binary of a toolchain. The rustup proxy is called with the toolchain
+    /// name, unless the workspace uses standalone toolchains: in that case the binary is executed
+    /// directly from the toolchain's directory.
+    pub(crate) fn toolchain_binary(self, toolchain: &str, name: &str) -> Self {
+        let workspace = match self.workspace {
+            Some(workspace) if workspace.standalone_toolchains() => workspace,
+            _ => return self.args(&[format!("+{}", toolchain)]),
+        };
+
+        let bin_dir =
+            |rustup_home: &Path| rustup_home.join("toolchains").join(toolchain).join("bin");
+        let bin_dir = if self.sandbox.is_some() {
+            bin_dir(&container_dirs::RUSTUP_HOME)
+        } else {
+            crate::utils::normalize_path(&bin_dir(&workspace.rustup_home()))
+        };
+        self.toolchain_bin_dir(workspace, bin_dir, name)
+    }
+
+    /// Execute the provided binary of a toolchain installed on the host, mounting its sysroot
+    /// inside the sandbox if the command is sandboxed.
+    pub(crate) fn host_toolchain_binary(mut self, sysroot: &Path, name: &str) -> Self {
+        let workspace = self
+            .workspace
+            .expect("host toolchains without a workspace are not supported");
+
+        let sysroot = crate::utils::normalize_path(sysroot);
+        let bin_dir = if let Some(sandbox) = self.sandbox.take() {
+            self.sandbox = Some(sandbox.mount(
+                &sysroot,
+                &container_dirs::HOST_TOOLCHAIN,
+                MountKind::ReadOnly,
+            ));
+            container_dirs::HOST_TOOLCHAIN.join("bin")
+        } else {
+            sysroot.join("bin")
+        };
+        self.toolchain_bin_dir(workspace, bin_dir, name)
+    }
+
+    fn toolchain_bin_dir(mut self, workspace: &Workspace, bin_dir: PathBuf, name: &str) -> Self {
+        self.binary = Binary::Global(bin_dir.join(exe_suffix(name.as_ref())));
+
+        // Without the rustup proxies cargo would look for the other binaries in `$PATH`.
+        self = self
+            .env("RUSTC", bin_dir.join(exe_suffix("rustc".as_ref())))
+            .env("RUSTDOC", bin_dir.join(exe_suffix("rustdoc".as_ref())));
+        if self.sandbox.is_none() {
+            // The environment of global binaries is not tweaked when running them on the host.
+            self = self.env(
+                "CARGO_HOME",
+                crate::utils::normalize_path(&workspace.cargo_home()),
+            );
+            for (key, value) in workspace.cargo_network().env() {
+                self = self.env(key, value);
+            }
+        }
+        self
+    }
+
     /// Add command-line arguments to the command. This method can be called multiple times to add
     /// additional args.
     pub fn args<S: AsRef<OsStr>>(mut self, args: &[S]) -> Self {
@@ -209,6 +309,23 @@ impl<'w, 'pl> Command<'w, 'pl> {
         self
     }
 
+    /// Add an environment variable to the command, hiding its value from the logs.
+    ///
+    /// The value will be replaced by `[REDACTED]` both in the logged command line and in the
+    /// logged output of the command. The output returned by
+    /// [`run_capture`](struct.Command.html#method.run_capture) or passed to
+    /// [`process_lines`](struct.Command.html#method.process_lines) is not redacted.
+    pub fn env_redacted<S1: AsRef<OsStr>, S2: AsRef<OsStr>>(mut self, key: S1, value: S2) -> Self {
+        self.redacted
+            .push(value.as_ref().to_string_lossy().into_owned());
+        self.env(key, value)
+    }
+
+    pub(crate) fn redact<S: Into<String>>(mut self, value: S) -> Self {
+        self.redacted.push(value.into());
+        self
+    }
+
     /// Change the directory where the command will be executed in.
     pub fn cd<P: AsRef<Path>>(mut self, path: P) -> Self {
         self.cd = Some(path.as_ref().to_path_buf());
@@ -234,6 +351,28 @@ impl<'w, 'pl> Command<'w, 'pl> {
         self
     }
 
+    /// Limit the memory (in bytes) the command can use, killing it if the limit is exceeded.
+    ///
+    /// The limit is only enforced on unsandboxed commands, sandboxed commands are limited with
+    /// [`SandboxBuilder::memory_limit`](struct.SandboxBuilder.html#method.memory_limit). On Unix
+    /// systems the limit applies to the address space of each process separately, while on
+    /// Windows it applies to the command and all its children combined.
+    pub fn memory_limit(mut self, limit: Option<usize>) -> Self {
+        self.memory_limit = limit;
+        self
+    }
+
+    /// Limit the CPU time the command can use, killing it if the limit is exceeded. Unlike
+    /// [`timeout`](#method.timeout) the time spent waiting (for example on I/O) is not counted.
+    ///
+    /// The limit is only enforced on unsandboxed commands. On Unix systems it has a granularity
+    /// of one second and applies to each process separately, while on Windows it applies to the
+    /// command and all its children combined.
+    pub fn cpu_time_limit(mut self, limit: Option<Duration>) -> Self {
+        self.cpu_time_limit = limit;
+        self
+    }
+
     /// Set the function that will be called each time a line is outputted to either the standard
     /// output or the standard error. Only one function can be set at any time for a command.
     ///
@@ -257,7 +396,10 @@ impl<'w, 'pl> Command<'w, 'pl> {
     /// # Ok(())
     /// # }
     /// ```
-    pub fn process_lines(mut self, f: &'pl mut dyn FnMut(&str, &mut ProcessLinesActions)) -> Self {
+    pub fn process_lines(
+        mut self,
+        f: &'pl mut (dyn FnMut(&str, &mut ProcessLinesActions) + Send),
+    ) -> Self {
         self.process_lines = Some(f);
         self
     }
@@ -280,6 +422,27 @@ impl<'w, 'pl> Command<'w, 'pl> {
         self
     }
 
+    /// Enable or disable running the command with a low priority, to avoid interfering with
+    /// latency-sensitive processes on the same machine.
+    ///
+    /// Low priority commands run with the lowest CPU and I/O priority on Linux, with the lowest
+    /// CPU priority on other Unix systems, and with the below normal priority class on Windows.
+    /// Sandboxed commands also get the lowest CPU and block I/O cgroup weights.
+    ///
+    /// Its default value is configured through
+    /// [`WorkspaceBuilder::command_low_priority`](../struct.WorkspaceBuilder.html#method.command_low_priority).
+    pub fn low_priority(mut self, low_priority: bool) -> Self {
+        self.low_priority = low_priority;
+        self
+    }
+
+    /// Attach a [`CommandHandle`](struct.CommandHandle.html) to the command, exposing the PID of
+    /// its process (or the ID of its container, if it's sandboxed) while it runs.
+    pub fn handle(mut self, handle: &CommandHandle) -> Self {
+        self.handle = Some(handle.clone());
+        self
+    }
+
     /// Run the prepared command and return an error if it fails (for example with a non-zero exit
     /// code or a timeout).
     pub async fn run(self) -> Result<(), Error> {
@@ -299,6 +462,21 @@ impl<'w, 'pl> Command<'w, 'pl> {
     }
 
     async fn run_inner(self, capture: bool) -> Result<ProcessOutput, Error> {
+        if let Some(workspace) = self.workspace {
+            if workspace.shutdown_tracker().is_shutting_down() {
+                return Err(CommandError::Cancelled.into());
+            }
+        }
+        #[cfg(feature = "mock")]
+        {
+            if let Some(mock) = self.workspace.and_then(|ws| ws.mock_fixtures()) {
+                return self.run_mocked(mock, capture);
+            }
+        }
+        let cancellations = self
+            .workspace
+            .map(|workspace| workspace.shutdown_tracker().cancellations());
+
         if let Some(mut builder) = self.sandbox {
             let workspace = self
                 .workspace
@@ -330,16 +508,22 @@ impl<'w, 'pl> Command<'w, 'pl> {
                 .workdir(container_dirs::WORK_DIR.to_str().unwrap())
                 .cmd(cmd);
 
-            if let Some(user_id) = native::current_user() {
-                builder = builder.env("MAP_USER_ID", user_id.to_string());
+            for (key, value) in workspace.cargo_network().env() {
+                builder = builder.env(key, value);
             }
-
             for (key, value) in self.env {
                 builder = builder.env(
                     key.to_string_lossy().as_ref(),
                     value.to_string_lossy().as_ref(),
                 );
             }
+            for value in self.redacted {
+                builder = builder.redact(value);
+            }
+            builder = builder.low_priority(self.low_priority);
+            if let Some(handle) = self.handle {
+                builder = builder.handle(handle);
+            }
 
             builder = builder
                 .mount(
@@ -385,6 +569,14 @@ impl<'w, 'pl> Command<'w, 'pl> {
 
             let mut cmd = AsyncCommand::new(&binary);
             cmd.args(&self.args);
+            if self.low_priority {
+                native::lower_priority(&mut cmd);
+            }
+            let limits = native::ResourceLimits {
+                memory: self.memory_limit,
+                cpu_time: self.cpu_time_limit,
+            };
+            native::limit_resources(&mut cmd, limits, self.low_priority);
 
             if managed_by_rustwide {
                 let workspace = self
@@ -408,12 +600,13 @@ impl<'w, 'pl> Command<'w, 'pl> {
                     "RUSTUP_HOME",
                     crate::utils::normalize_path(rustup_home.as_ref()),
                 );
+                cmd.envs(workspace.cargo_network().env());
             }
             for &(ref k, ref v) in &self.env {
                 cmd.env(k, v);
             }
 
-            let cmdstr = format!("{:?}", cmd);
+            let cmdstr = redact(&format!("{:?}", cmd), &self.redacted).into_owned();
 
             if let Some(ref cd) = self.cd {
                 cmd.current_dir(cd);
@@ -425,11 +618,15 @@ impl<'w, 'pl> Command<'w, 'pl> {
 
             let out = log_command(
                 cmd,
+                self.workspace,
                 self.process_lines,
                 capture,
                 self.timeout,
                 self.no_output_timeout,
                 self.log_output,
+                &self.redacted,
+                self.handle.as_ref(),
+                limits,
             )
             .await
             .map_err(|e| {
@@ -437,6 +634,13 @@ impl<'w, 'pl> Command<'w, 'pl> {
                 e
             })?;
 
+            if let (Some(workspace), Some(cancellations)) = (self.workspace, cancellations) {
+                if !out.status.success() && workspace.shutdown_tracker().is_cancelled(cancellations)
+                {
+                    return Err(CommandError::Cancelled.into());
+                }
+            }
+
             if out.status.success() {
                 Ok(out.into())
             } else {
@@ -444,6 +648,64 @@ impl<'w, 'pl> Command<'w, 'pl> {
             }
         }
     }
+
+    /// Answer the command with the output scripted in the fixtures of a mock workspace.
+    #[cfg(feature = "mock")]
+    fn run_mocked(
+        self,
+        mock: &crate::mock::MockFixtures,
+        capture: bool,
+    ) -> Result<ProcessOutput, Error> {
+        let binary = match &self.binary {
+            Binary::Global(path) | Binary::ManagedByRustwide(path) => path,
+            Binary::__NonExaustive => panic!("do not create __NonExaustive variants manually"),
+        };
+        let scripted = mock.execute(binary, &self.args);
+        let cmdstr = redact(&format!("{:?} {:?}", binary, self.args), &self.redacted).into_owned();
+        if self.log_command {
+            info!("mocking `{}`", cmdstr);
+        }
+
+        let (mut process_lines, log_output, redacted) =
+            (self.process_lines, self.log_output, &self.redacted);
+        let mut process = |lines: &[String]| {
+            let mut output = Vec::new();
+            for line in lines {
+                let mut actions = ProcessLinesActions::new();
+                if let Some(f) = &mut process_lines {
+                    f(line, &mut actions);
+                }
+                let lines = match actions.take_lines() {
+                    InnerState::Removed => Vec::new(),
+                    InnerState::Original => vec![line.clone()],
+                    InnerState::Replaced(new_lines) => new_lines,
+                };
+                if log_output {
+                    for line in &lines {
+                        info!("[mock] {}", redact(line, redacted));
+                    }
+                }
+                if capture {
+                    output.extend(lines);
+                }
+            }
+            output
+        };
+        let stdout = process(scripted.stdout_lines());
+        let stderr = process(scripted.stderr_lines());
+
+        if let Some(err) = scripted.error_ref() {
+            Err(err.clone().into())
+        } else if scripted.is_success() {
+            Ok(ProcessOutput {
+                stdout,
+                stderr,
+                sandbox_invocation: None,
+            })
+        } else {
+            failure::bail!("command `{}` failed", cmdstr);
+        }
+    }
 }
 
 struct InnerProcessOutput {
@@ -457,6 +719,7 @@ impl From<InnerProcessOutput> for ProcessOutput {
         ProcessOutput {
             stdout: orig.stdout,
             stderr: orig.stderr,
+            sandbox_invocation: None,
         }
     }
 }
@@ -466,6 +729,7 @@ impl From<InnerProcessOutput> for ProcessOutput {
 pub struct ProcessOutput {
     stdout: Vec<String>,
     stderr: Vec<String>,
+    sandbox_invocation: Option<SandboxInvocation>,
 }
 
 impl ProcessOutput {
@@ -478,6 +742,11 @@ impl ProcessOutput {
     pub fn stderr_lines(&self) -> &[String] {
         &self.stderr
     }
+
+    /// Return how to run the command again by hand outside of rustwide, if it was sandboxed.
+    pub fn sandbox_invocation(&self) -> Option<&SandboxInvocation> {
+        self.sandbox_invocation.as_ref()
+    }
 }
 
 enum OutputKind {
@@ -494,13 +763,18 @@ impl OutputKind {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn log_command(
     mut cmd: AsyncCommand,
-    mut process_lines: Option<&mut dyn FnMut(&str, &mut ProcessLinesActions)>,
+    workspace: Option<&Workspace>,
+    mut process_lines: Option<&mut (dyn FnMut(&str, &mut ProcessLinesActions) + Send)>,
     capture: bool,
     timeout: Option<Duration>,
     no_output_timeout: Option<Duration>,
     log_output: bool,
+    redacted: &[String],
+    handle: Option<&CommandHandle>,
+    limits: native::ResourceLimits,
 ) -> Result<InnerProcessOutput, Error> {
     let timeout = if let Some(t) = timeout {
         t
@@ -516,9 +790,40 @@ async fn log_command(
         timeout
     };
 
-    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    // Timers and processes are registered with the workspace's runtime, if it has one.
+    let runtime = workspace.and_then(|workspace| workspace.runtime());
+
+    let mut child = crate::utils::enter(runtime, || {
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()
+    })?;
     let child_id = child.id();
 
+    // Windows can only limit the resources of processes after creating them, so they're created
+    // suspended and resumed once the limits are in place.
+    #[cfg(windows)]
+    let _job = native::limit_process(child_id, limits).map_err(|err| {
+        let _ = native::kill_process(child_id);
+        err
+    })?;
+    #[cfg(not(windows))]
+    let _ = limits;
+
+    // Track the process so that it can be killed if the workspace is shut down.
+    if let Some(workspace) = workspace {
+        workspace.shutdown_tracker().register_process(child_id);
+    }
+    if let Some(handle) = handle {
+        handle.set_pid(Some(child_id));
+    }
+    scopeguard::defer! {{
+        if let Some(workspace) = workspace {
+            workspace.shutdown_tracker().unregister_process(child_id);
+        }
+        if let Some(handle) = handle {
+            handle.set_pid(None);
+        }
+    }}
+
     let stdout = BufReader::new(child.stdout.take().unwrap())
         .lines()
         .map(|line| (OutputKind::Stdout, line));
@@ -529,63 +834,64 @@ async fn log_command(
     let start = Instant::now();
     let mut actions = ProcessLinesActions::new();
 
-    let output = stream::select(stdout, stderr)
-        .timeout(no_output_timeout)
-        .map(move |result| match result {
-            // If the timeout elapses, kill the process
-            Err(_timeout) => Err(match native::kill_process(child_id) {
-                Ok(()) => Error::from(CommandError::NoOutputFor(no_output_timeout.as_secs())),
-                Err(err) => err,
-            }),
+    let output = crate::utils::enter(runtime, || {
+        stream::select(stdout, stderr).timeout(no_output_timeout)
+    })
+    .map(move |result| match result {
+        // If the timeout elapses, kill the process
+        Err(_timeout) => Err(match native::kill_process(child_id) {
+            Ok(()) => Error::from(CommandError::NoOutputFor(no_output_timeout.as_secs())),
+            Err(err) => err,
+        }),
+
+        // If an error occurred reading the line, flatten the error
+        Ok((_, Err(read_err))) => Err(Error::from(read_err)),
+
+        // If the read was successful, return the `OutputKind` and the read line
+        Ok((out_kind, Ok(line))) => Ok((out_kind, line)),
+    })
+    .and_then(move |(kind, line): (OutputKind, String)| {
+        // If the process is in a tight output loop the timeout on the process might fail to
+        // be executed, so this extra check prevents the process to run without limits.
+        if start.elapsed() > timeout {
+            return future::err(Error::from(CommandError::Timeout(timeout.as_secs())));
+        }
 
-            // If an error occurred reading the line, flatten the error
-            Ok((_, Err(read_err))) => Err(Error::from(read_err)),
-
-            // If the read was successful, return the `OutputKind` and the read line
-            Ok((out_kind, Ok(line))) => Ok((out_kind, line)),
-        })
-        .and_then(move |(kind, line): (OutputKind, String)| {
-            // If the process is in a tight output loop the timeout on the process might fail to
-            // be executed, so this extra check prevents the process to run without limits.
-            if start.elapsed() > timeout {
-                return future::err(Error::from(CommandError::Timeout(timeout.as_secs())));
-            }
+        if let Some(f) = &mut process_lines {
+            f(&line, &mut actions);
+        }
+        // this is done here to avoid duplicating the output line
+        let lines = match actions.take_lines() {
+            InnerState::Removed => Vec::new(),
+            InnerState::Original => vec![line],
+            InnerState::Replaced(new_lines) => new_lines,
+        };
 
-            if let Some(f) = &mut process_lines {
-                f(&line, &mut actions);
+        if log_output {
+            for line in &lines {
+                info!("[{}] {}", kind.prefix(), redact(line, redacted));
             }
-            // this is done here to avoid duplicating the output line
-            let lines = match actions.take_lines() {
-                InnerState::Removed => Vec::new(),
-                InnerState::Original => vec![line],
-                InnerState::Replaced(new_lines) => new_lines,
-            };
+        }
 
-            if log_output {
-                for line in &lines {
-                    info!("[{}] {}", kind.prefix(), line);
+        future::ok((kind, lines))
+    })
+    .try_fold(
+        (Vec::<String>::new(), Vec::<String>::new()),
+        move |(mut stdout, mut stderr), (kind, mut lines)| async move {
+            // If stdio/stdout is supposed to be captured, append it to
+            // the accumulated stdio/stdout
+            if capture {
+                match kind {
+                    OutputKind::Stdout => stdout.append(&mut lines),
+                    OutputKind::Stderr => stderr.append(&mut lines),
                 }
             }
 
-            future::ok((kind, lines))
-        })
-        .try_fold(
-            (Vec::<String>::new(), Vec::<String>::new()),
-            move |(mut stdout, mut stderr), (kind, mut lines)| async move {
-                // If stdio/stdout is supposed to be captured, append it to
-                // the accumulated stdio/stdout
-                if capture {
-                    match kind {
-                        OutputKind::Stdout => stdout.append(&mut lines),
-                        OutputKind::Stderr => stderr.append(&mut lines),
-                    }
-                }
+            Ok((stdout, stderr))
+        },
+    );
 
-                Ok((stdout, stderr))
-            },
-        );
-
-    let child = time::timeout(timeout, child).map(move |result| {
+    let child = crate::utils::enter(runtime, || time::timeout(timeout, child)).map(move |result| {
         match result {
             // If the timeout elapses, kill the process
             Err(_timeout) => Err(match native::kill_process(child_id) {
@@ -615,8 +921,53 @@ async fn log_command(
     })
 }
 
-fn exe_suffix(file: &OsStr) -> OsString {
+/// Replace all the occurrences of the redacted values in the input with `[REDACTED]`.
+pub(crate) fn redact<'a>(input: &'a str, redacted: &[String]) -> Cow<'a, str> {
+    let mut result = Cow::Borrowed(input);
+    for value in redacted {
+        if !value.is_empty() && result.contains(value.as_str()) {
+            result = Cow::Owned(result.replace(value.as_str(), "[REDACTED]"));
+        }
+    }
+    result
+}
+
+pub(crate) fn exe_suffix(file: &OsStr) -> OsString {
     let mut path = OsString::from(file);
     path.push(EXE_SUFFIX);
     path
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{redact, Command, CommandHandle};
+    use failure::Error;
+
+    #[test]
+    fn test_redact() {
+        let secrets = vec!["hunter2".to_string(), String::new()];
+        assert_eq!(redact("nothing to see", &secrets), "nothing to see");
+        assert_eq!(
+            redact("TOKEN=hunter2 OTHER=hunter2", &secrets),
+            "TOKEN=[REDACTED] OTHER=[REDACTED]"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_handle_pid() -> Result<(), Error> {
+        let handle = CommandHandle::new();
+        let mut pid_while_running = None;
+        Command::new_workspaceless("echo")
+            .args(&["hello"])
+            .handle(&handle)
+            .process_lines(&mut |_, _| pid_while_running = handle.pid())
+            .run()
+            .await?;
+
+        assert!(pid_while_running.is_some());
+        assert!(handle.container_id().is_none());
+        assert!(!handle.is_running());
+        Ok(())
+    }
+}
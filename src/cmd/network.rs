@@ -0,0 +1,278 @@
+use crate::cmd::Command;
+use crate::Workspace;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often the network usage of a running sandbox is sampled.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Script printing the interface counters and the TCP sockets of the sandbox's network namespace.
+/// IPv6 might be disabled in the container, so missing files are ignored.
+const SAMPLE_SCRIPT: &str = "cat /proc/net/dev /proc/net/tcp /proc/net/tcp6 2>/dev/null; true";
+
+/// Network traffic of the sandboxed commands of a build, retrieved with
+/// [`Build::network_usage`](../struct.Build.html#method.network_usage).
+///
+/// The usage is sampled from the network namespace of each sandbox while its command runs, so
+/// traffic happening in the last moments before the command exits might not be counted, and
+/// connections opened and closed between two samples might be missed. The remote hosts are the
+/// IP addresses the sandbox had TCP connections with, excluding loopback addresses.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkUsage {
+    bytes_received: u64,
+    bytes_sent: u64,
+    hosts: BTreeSet<IpAddr>,
+}
+
+impl NetworkUsage {
+    /// Get the number of bytes received by the sandboxes.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    /// Get the number of bytes sent by the sandboxes.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// Get the addresses of the remote hosts the sandboxes connected to.
+    pub fn hosts(&self) -> &BTreeSet<IpAddr> {
+        &self.hosts
+    }
+
+    /// Check whether no network activity was recorded.
+    pub fn is_empty(&self) -> bool {
+        self.bytes_received == 0 && self.bytes_sent == 0 && self.hosts.is_empty()
+    }
+
+    /// Update the usage of a single sandbox with a new sample. The counters of the interfaces
+    /// only grow while the sandbox runs, so the latest sample replaces the previous ones.
+    fn update(&mut self, sample: NetworkUsage) {
+        self.bytes_received = self.bytes_received.max(sample.bytes_received);
+        self.bytes_sent = self.bytes_sent.max(sample.bytes_sent);
+        self.hosts.extend(sample.hosts);
+    }
+}
+
+/// Collects the network usage of the sandboxed commands executed by a build.
+#[derive(Clone, Default)]
+pub(crate) struct NetworkRecorder {
+    usage: Arc<Mutex<NetworkUsage>>,
+}
+
+impl NetworkRecorder {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the usage of a sandbox that finished running.
+    pub(super) fn record(&self, usage: NetworkUsage) {
+        let mut total = self.usage.lock().unwrap();
+        total.bytes_received += usage.bytes_received;
+        total.bytes_sent += usage.bytes_sent;
+        total.hosts.extend(usage.hosts);
+    }
+
+    pub(crate) fn usage(&self) -> NetworkUsage {
+        self.usage.lock().unwrap().clone()
+    }
+}
+
+/// Sample the network usage of a running container into `usage` until the future is dropped.
+pub(super) async fn monitor(workspace: &Workspace, container: &str, usage: &Mutex<NetworkUsage>) {
+    loop {
+        crate::utils::enter(workspace.runtime(), || {
+            tokio::time::delay_for(SAMPLE_INTERVAL)
+        })
+        .await;
+        let out = Command::new(workspace, "docker")
+            .args(&["exec", container, "sh", "-c", SAMPLE_SCRIPT])
+            .log_output(false)
+            .log_command(false)
+            .run_capture()
+            .await;
+        // The container might not be running yet, or might have exited since the last sample.
+        if let Ok(out) = out {
+            usage
+                .lock()
+                .unwrap()
+                .update(parse_sample(out.stdout_lines()));
+        }
+    }
+}
+
+/// Parse the output of `SAMPLE_SCRIPT`: the lines of `/proc/net/dev` are recognized by their
+/// interface name, while the ones of `/proc/net/tcp` and `/proc/net/tcp6` start with a number.
+fn parse_sample(lines: &[String]) -> NetworkUsage {
+    let mut usage = NetworkUsage::default();
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let (name, rest) = match (parts.next(), parts.next()) {
+            (Some(name), Some(rest)) => (name.trim(), rest),
+            _ => continue,
+        };
+
+        if name.chars().all(|c| c.is_ascii_digit()) {
+            // Socket entries: the remote address comes after the local one.
+            if let Some(addr) = rest.split_whitespace().nth(1).and_then(parse_address) {
+                if !addr.is_loopback() && !addr.is_unspecified() {
+                    usage.hosts.insert(addr);
+                }
+            }
+        } else if name != "lo" {
+            let counters: Vec<u64> = rest
+                .split_whitespace()
+                .filter_map(|field| field.parse().ok())
+                .collect();
+            if counters.len() == 16 {
+                usage.bytes_received += counters[0];
+                usage.bytes_sent += counters[8];
+            }
+        }
+    }
+    usage
+}
+
+/// Parse an `ADDRESS:PORT` pair of the kernel's socket tables, where the address is made of 32 bit
+/// words in hexadecimal, each one in the host's byte order.
+fn parse_address(pair: &str) -> Option<IpAddr> {
+    let hex = pair.split(':').next()?;
+    if hex.len() != 8 && hex.len() != 32 {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(16);
+    for start in (0..hex.len()).step_by(8) {
+        let word = u32::from_str_radix(hex.get(start..start + 8)?, 16).ok()?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+
+    if bytes.len() == 4 {
+        Some(IpAddr::V4(Ipv4Addr::new(
+            bytes[0], bytes[1], bytes[2], bytes[3],
+        )))
+    } else {
+        let mut octets = [0; 16];
+        octets.copy_from_slice(&bytes);
+        let addr = Ipv6Addr::from(octets);
+        // IPv4 connections made through IPv6 sockets use mapped addresses (`::ffff:a.b.c.d`).
+        if octets[..10].iter().all(|&b| b == 0) && octets[10] == 0xff && octets[11] == 0xff {
+            Some(IpAddr::V4(Ipv4Addr::new(
+                octets[12], octets[13], octets[14], octets[15],
+            )))
+        } else {
+            Some(IpAddr::V6(addr))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_sample, NetworkRecorder, NetworkUsage};
+    use std::net::IpAddr;
+
+    /// Format an address the way the kernel's socket tables do.
+    fn kernel_hex(octets: &[u8]) -> String {
+        octets
+            .chunks(4)
+            .map(|word| {
+                format!(
+                    "{:08X}",
+                    u32::from_ne_bytes([word[0], word[1], word[2], word[3]])
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_sample() {
+        let mut mapped = [0u8; 16];
+        mapped[10] = 0xff;
+        mapped[11] = 0xff;
+        mapped[12..].copy_from_slice(&[151, 101, 1, 137]);
+        let mut ipv6 = [0u8; 16];
+        ipv6[..4].copy_from_slice(&[0x2a, 0x04, 0x4e, 0x42]);
+        ipv6[15] = 1;
+
+        let lines: Vec<String> = vec![
+            "Inter-|   Receive                                                |  Transmit".into(),
+            " face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed".into(),
+            "    lo:     100       2    0    0    0     0          0         0      100       2    0    0    0     0       0          0".into(),
+            "  eth0: 1048576     900    0    0    0     0          0         0     4096      50    0    0    0     0       0          0".into(),
+            "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode".into(),
+            format!(
+                "   0: {}:0CEA {}:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 1 1 0 100 0 0 10 0",
+                kernel_hex(&[127, 0, 0, 1]),
+                kernel_hex(&[0, 0, 0, 0]),
+            ),
+            format!(
+                "   1: {}:D2F0 {}:01BB 01 00000000:00000000 00:00000000 00000000  1000        0 2 1 0 20 4 30 10 -1",
+                kernel_hex(&[172, 17, 0, 2]),
+                kernel_hex(&[13, 227, 219, 10]),
+            ),
+            format!(
+                "   2: {}:D2F2 {}:01BB 06 00000000:00000000 03:00000D2B 00000000     0        0 0 3 0",
+                kernel_hex(&[172, 17, 0, 2]),
+                kernel_hex(&[127, 0, 0, 1]),
+            ),
+            format!(
+                "   0: {}:D2F4 {}:01BB 01 00000000:00000000 00:00000000 00000000  1000        0 3 1 0 20 4 30 10 -1",
+                kernel_hex(&[0; 16]),
+                kernel_hex(&mapped),
+            ),
+            format!(
+                "   1: {}:D2F6 {}:01BB 01 00000000:00000000 00:00000000 00000000  1000        0 4 1 0 20 4 30 10 -1",
+                kernel_hex(&[0; 16]),
+                kernel_hex(&ipv6),
+            ),
+        ];
+
+        let usage = parse_sample(&lines);
+        assert_eq!(usage.bytes_received(), 1_048_576);
+        assert_eq!(usage.bytes_sent(), 4096);
+        assert_eq!(
+            usage.hosts().iter().cloned().collect::<Vec<_>>(),
+            vec![
+                "13.227.219.10".parse::<IpAddr>().unwrap(),
+                "151.101.1.137".parse().unwrap(),
+                "2a04:4e42::1".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_record() {
+        let host = |addr: &str| addr.parse::<IpAddr>().unwrap();
+
+        // Samples of the same sandbox replace each other...
+        let mut first = NetworkUsage::default();
+        first.update(NetworkUsage {
+            bytes_received: 100,
+            bytes_sent: 10,
+            hosts: vec![host("10.0.0.1")].into_iter().collect(),
+        });
+        first.update(NetworkUsage {
+            bytes_received: 300,
+            bytes_sent: 20,
+            hosts: vec![host("10.0.0.2")].into_iter().collect(),
+        });
+        assert_eq!(first.bytes_received(), 300);
+        assert_eq!(first.hosts().len(), 2);
+
+        // ...while the usage of different sandboxes is added up.
+        let recorder = NetworkRecorder::new();
+        assert!(recorder.usage().is_empty());
+        recorder.record(first);
+        recorder.record(NetworkUsage {
+            bytes_received: 50,
+            bytes_sent: 5,
+            hosts: vec![host("10.0.0.1")].into_iter().collect(),
+        });
+        let usage = recorder.usage();
+        assert_eq!(usage.bytes_received(), 350);
+        assert_eq!(usage.bytes_sent(), 25);
+        assert_eq!(usage.hosts().len(), 2);
+    }
+}
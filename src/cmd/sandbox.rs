@@ -1,13 +1,143 @@
-use crate::cmd::{Command, CommandError, ProcessLinesActions, ProcessOutput};
+use crate::cmd::{
+    container_dirs, redact, Command, CommandError, CommandHandle, InvocationBuilder,
+    InvocationMount, NetworkRecorder, NetworkUsage, ProcessLinesActions, ProcessOutput,
+    SandboxInvocation, SnapshotRecorder,
+};
+use crate::fair_share::CpuCoordinator;
+use crate::journal::Operation;
+use crate::native;
 use crate::Workspace;
-use failure::Error;
-use log::{error, info};
+use failure::{Error, ResultExt};
+use futures_util::future::{self, Either};
+use log::{error, info, warn};
 use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::time::Duration;
 use tokio::fs;
 
+/// Label recording the ID of the process that created a container, so that the containers of
+/// other processes sharing the workspace are only cleaned up after those processes exit.
+pub(crate) const CREATOR_LABEL: &str = "rustwide.pid";
+
+/// Prefixes of the errors printed by the Docker CLI itself, rather than by the sandboxed command.
+const DOCKER_ERROR_PREFIXES: &[&str] = &[
+    "Error response from daemon:",
+    "docker: Error response from daemon:",
+    "Error: No such container:",
+];
+
+/// Errors of the Docker daemon known to go away on their own, like races with the removal of a
+/// container, timeouts of the daemon or restarts of containerd.
+const SPURIOUS_DOCKER_ERRORS: &[&str] = &[
+    "No such container",
+    "context deadline exceeded",
+    "Client.Timeout exceeded",
+    "i/o timeout",
+    "transport is closing",
+    "connection reset by peer",
+    "containerd.sock",
+    "is already in progress",
+];
+
+/// Tracks the writable mounts whose owner was changed to run a sandbox as a custom user, so that
+/// the commands of a build don't change it again, and so it can be given back once at the end of
+/// the build.
+pub(crate) struct MountOwners {
+    owners: Mutex<HashMap<PathBuf, String>>,
+}
+
+impl MountOwners {
+    pub(crate) fn new() -> Self {
+        MountOwners {
+            owners: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn owner(&self, path: &Path) -> Option<String> {
+        self.owners.lock().unwrap().get(path).cloned()
+    }
+
+    fn set_owner(&self, path: &Path, owner: &str) {
+        self.owners
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), owner.into());
+    }
+
+    fn take_where(&self, filter: impl Fn(&Path) -> bool) -> Vec<PathBuf> {
+        let mut owners = self.owners.lock().unwrap();
+        let paths: Vec<_> = owners.keys().filter(|path| filter(path)).cloned().collect();
+        for path in &paths {
+            owners.remove(path);
+        }
+        paths
+    }
+}
+
+/// Give the writable mounts inside `dir` whose owner was changed by sandboxes running as a custom
+/// user back to the current user.
+pub(crate) async fn restore_mount_owners(workspace: &Workspace, dir: &Path) -> Result<(), Error> {
+    let paths = workspace
+        .mount_owners()
+        .take_where(|path| path.starts_with(dir));
+    give_back(workspace, paths).await
+}
+
+/// Give the provided paths back to the current user.
+async fn give_back(workspace: &Workspace, paths: Vec<PathBuf>) -> Result<(), Error> {
+    let owner = match native::current_owner() {
+        Some(owner) => owner,
+        None => return Ok(()),
+    };
+    let mounts: Vec<_> = paths
+        .into_iter()
+        .enumerate()
+        .map(|(i, host_path)| MountConfig {
+            host_path,
+            sandbox_path: Path::new("/opt/rustwide/chown").join(i.to_string()),
+            perm: MountKind::ReadWrite,
+        })
+        .collect();
+    chown(workspace, mounts, &owner).await
+}
+
+/// Recursively change the owner of the provided mounts. This is done in a separate container
+/// running as root, so that user names are resolved the same way the sandbox does.
+async fn chown(workspace: &Workspace, mounts: Vec<MountConfig>, owner: &str) -> Result<(), Error> {
+    if mounts.is_empty() {
+        return Ok(());
+    }
+
+    let mut cmd = vec!["-R".to_string(), owner.to_string()];
+    cmd.extend(
+        mounts
+            .iter()
+            .map(|mount| mount.sandbox_path.to_string_lossy().into_owned()),
+    );
+    let builder = SandboxBuilder {
+        mounts,
+        cmd,
+        user: Some("root".into()),
+        entrypoint: Some("chown".into()),
+        enable_networking: false,
+        ..SandboxBuilder::new()
+    };
+
+    info!("changing the owner of the sandbox mounts to {}", owner);
+    let container = builder.create(workspace).await?;
+    let _entry = workspace.journal().begin(Operation::Container {
+        id: container.id.clone(),
+    });
+    let res = container.run(None, None, None, false, false, false).await;
+    if let Err(err) = container.delete().await {
+        error!("failed to delete container {}: {}", container.id, err);
+    }
+    res.map(|_| ())
+}
+
 /// The Docker image used for sandboxing.
 pub struct SandboxImage {
     name: String,
@@ -25,6 +155,14 @@ impl SandboxImage {
         Ok(image)
     }
 
+    /// Placeholder image of mock workspaces, which never start containers.
+    #[cfg(feature = "mock")]
+    pub(crate) fn mock() -> Self {
+        SandboxImage {
+            name: "rustwide-mock".into(),
+        }
+    }
+
     /// Pull an image from its Docker registry.
     ///
     /// This will access the network to download the image from the registry. If pulling fails an
@@ -46,6 +184,74 @@ impl SandboxImage {
         Ok(image)
     }
 
+    /// Check whether the image provides what rustwide needs to run builds in it, returning the
+    /// problems found. No problems are returned if the image is compatible.
+    ///
+    /// The check starts a throwaway container with the same mounts used by builds, and checks the
+    /// processes run as the right user, the required binaries are present, the toolchains
+    /// installed in the workspace can be executed and the target directory is writable. This is
+    /// useful to detect issues with custom images before running builds with them.
+    ///
+    /// An error is returned if the container can't be started at all, for example because the
+    /// image doesn't contain a shell. Only Linux images can be validated.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, cmd::SandboxImage};
+    /// # use std::error::Error;
+    /// # async fn run() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init().await?;
+    /// let image = SandboxImage::local("my-build-env").await?;
+    /// for problem in image.validate(&workspace).await? {
+    ///     eprintln!("incompatible image: {}", problem);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn validate(&self, workspace: &Workspace) -> Result<Vec<ImageProblem>, Error> {
+        if cfg!(windows) {
+            failure::bail!("validating sandbox images is only supported on Linux");
+        }
+
+        info!("validating the sandbox image {}", self.name);
+        fs::create_dir_all(workspace.cache_dir()).await?;
+        let dir = tempfile::tempdir_in(workspace.cache_dir())?;
+        let (source_dir, target_dir) = (dir.path().join("source"), dir.path().join("target"));
+        fs::create_dir_all(&source_dir).await?;
+
+        let sandbox = SandboxBuilder::new()
+            .image(self.name.clone())
+            .enable_networking(false)
+            .mount(
+                &target_dir,
+                &*container_dirs::TARGET_DIR,
+                MountKind::ReadWrite,
+            );
+        let output = Command::new_sandboxed(workspace, sandbox, "sh")
+            .args(&["-c", VALIDATE_SCRIPT])
+            .env("TARGET_DIR", &*container_dirs::TARGET_DIR)
+            .cd(&source_dir)
+            .log_output(false)
+            .run_capture()
+            .await
+            .with_context(|_| {
+                format!(
+                    "failed to start a container with the image {} (is `sh` available?)",
+                    self.name
+                )
+            })?;
+
+        Ok(parse_validation(
+            &output.stdout_lines(),
+            native::current_user(),
+        ))
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
     async fn ensure_exists_locally(&self) -> Result<(), Error> {
         info!("checking the image {} is available locally", self.name);
         Command::new_workspaceless("docker")
@@ -75,6 +281,105 @@ impl SandboxImage {
     }
 }
 
+/// Retrieve the ID of a local Docker image, which is the digest of its configuration.
+pub(crate) async fn image_id(image: &str) -> Result<String, Error> {
+    let out = Command::new_workspaceless("docker")
+        .args(&["image", "inspect", image, "--format", "{{.Id}}"])
+        .log_output(false)
+        .run_capture()
+        .await?;
+    match out.stdout_lines().first() {
+        Some(id) => Ok(id.trim().to_string()),
+        None => failure::bail!("docker didn't report the ID of the image {}", image),
+    }
+}
+
+/// Script run inside the container by [`SandboxImage::validate`], printing a line for each check.
+const VALIDATE_SCRIPT: &str = r#"
+echo "user=$(id -u)"
+for binary in cc; do
+    command -v "$binary" >/dev/null 2>&1 || echo "missing=$binary"
+done
+"$CARGO_HOME/bin/cargo" --version >/dev/null 2>&1 || echo "cargo-broken"
+touch "$TARGET_DIR/.rustwide-validate" 2>/dev/null || echo "target-unwritable"
+"#;
+
+fn parse_validation(lines: &[String], expected_user: Option<u32>) -> Vec<ImageProblem> {
+    let mut problems = Vec::new();
+    for line in lines {
+        let mut parts = line.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("user"), Some(user)) => {
+                if let Some(expected) = expected_user {
+                    if user.parse::<u32>().ok() != Some(expected) {
+                        problems.push(ImageProblem::UserNotMapped {
+                            expected,
+                            actual: user.into(),
+                        });
+                    }
+                }
+            }
+            (Some("missing"), Some(binary)) => {
+                problems.push(ImageProblem::MissingBinary(binary.into()))
+            }
+            (Some("cargo-broken"), None) => problems.push(ImageProblem::ToolchainNotRunnable),
+            (Some("target-unwritable"), None) => problems.push(ImageProblem::TargetDirNotWritable),
+            _ => {}
+        }
+    }
+    problems
+}
+
+/// Problem with a sandbox image found by
+/// [`SandboxImage::validate`](struct.SandboxImage.html#method.validate).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ImageProblem {
+    /// The processes in the sandbox don't run as the current user. Images are expected to switch
+    /// to the user ID provided in the `MAP_USER_ID` environment variable.
+    UserNotMapped {
+        /// ID of the current user.
+        expected: u32,
+        /// ID of the user the processes run as.
+        actual: String,
+    },
+    /// A binary needed to build crates is missing from the `PATH` of the image.
+    MissingBinary(String),
+    /// The toolchains installed in the workspace can't be executed in the image.
+    ToolchainNotRunnable,
+    /// The target directory mounted in the sandbox isn't writable.
+    TargetDirNotWritable,
+}
+
+impl fmt::Display for ImageProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageProblem::UserNotMapped { expected, actual } => write!(
+                f,
+                "processes run as user {} instead of {}: the image must switch to the user ID \
+                 in the MAP_USER_ID environment variable, or a user must be set with \
+                 SandboxBuilder::user",
+                actual, expected
+            ),
+            ImageProblem::MissingBinary(binary) => write!(
+                f,
+                "the `{}` binary is missing: install it in the image or add it to the PATH",
+                binary
+            ),
+            ImageProblem::ToolchainNotRunnable => write!(
+                f,
+                "cargo can't be executed: the image must be compatible with the host's \
+                 toolchains (for example, use a glibc-based image on glibc hosts)"
+            ),
+            ImageProblem::TargetDirNotWritable => write!(
+                f,
+                "the target directory isn't writable: make sure the user running in the \
+                 sandbox can write to the mounts"
+            ),
+        }
+    }
+}
+
 /// Whether to mount a path in the sandbox with write permissions or not.
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub enum MountKind {
@@ -153,6 +458,16 @@ pub struct SandboxBuilder {
     workdir: Option<String>,
     cmd: Vec<String>,
     enable_networking: bool,
+    low_priority: bool,
+    user: Option<String>,
+    keep_mount_owners: bool,
+    entrypoint: Option<String>,
+    image: Option<String>,
+    redacted: Vec<String>,
+    handle: Option<CommandHandle>,
+    snapshots: Option<SnapshotRecorder>,
+    network: Option<NetworkRecorder>,
+    cpu_coordinator: Option<(CpuCoordinator, String)>,
 }
 
 impl SandboxBuilder {
@@ -166,6 +481,16 @@ impl SandboxBuilder {
             cpu_limit: None,
             cmd: Vec::new(),
             enable_networking: true,
+            low_priority: false,
+            user: None,
+            keep_mount_owners: false,
+            entrypoint: None,
+            image: None,
+            redacted: Vec::new(),
+            handle: None,
+            snapshots: None,
+            network: None,
+            cpu_coordinator: None,
         }
     }
 
@@ -200,6 +525,17 @@ impl SandboxBuilder {
         self
     }
 
+    /// Let the provided [`CpuCoordinator`](../struct.CpuCoordinator.html) adjust the CPU weight of
+    /// the sandbox, sharing the CPU fairly with the other builds attached to it. All the sandboxes
+    /// of the same build must use the same build name, which is also used to retrieve its CPU
+    /// usage from the coordinator.
+    ///
+    /// Low priority commands always keep the lowest CPU weight, and are not coordinated.
+    pub fn cpu_coordinator(mut self, coordinator: &CpuCoordinator, build: &str) -> Self {
+        self.cpu_coordinator = Some((coordinator.clone(), build.into()));
+        self
+    }
+
     /// Enable or disable the sandbox's networking. When it's disabled processes inside the sandbox
     /// won't be able to reach network service on the Internet or the host machine.
     ///
@@ -209,11 +545,41 @@ impl SandboxBuilder {
         self
     }
 
+    /// Run the processes inside the sandbox as the provided user, which can be a user name, a
+    /// numeric user ID or a `uid:gid` pair.
+    ///
+    /// By default the sandbox image is asked to run the processes with the same user ID as the
+    /// current user (through the `MAP_USER_ID` environment variable), which only works with images
+    /// following the [rustops/crates-build-env] convention. When a custom user is set, the
+    /// writable mounts are given to that user before the processes start, and they're given back
+    /// to the current user once they end (or once the build ends, for the commands of a build).
+    ///
+    /// [rustops/crates-build-env]: https://hub.docker.com/r/rustops/crates-build-env
+    pub fn user(mut self, user: &str) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
     pub(super) fn env<S1: Into<String>, S2: Into<String>>(mut self, key: S1, value: S2) -> Self {
         self.env.push((key.into(), value.into()));
         self
     }
 
+    pub(super) fn low_priority(mut self, enable: bool) -> Self {
+        self.low_priority = enable;
+        self
+    }
+
+    pub(super) fn redact(mut self, value: String) -> Self {
+        self.redacted.push(value);
+        self
+    }
+
+    pub(super) fn handle(mut self, handle: CommandHandle) -> Self {
+        self.handle = Some(handle);
+        self
+    }
+
     pub(super) fn cmd(mut self, cmd: Vec<String>) -> Self {
         self.cmd = cmd;
         self
@@ -224,11 +590,61 @@ impl SandboxBuilder {
         self
     }
 
+    /// Leave the writable mounts owned by the custom [`user`](#method.user) when the processes
+    /// end, as they're given back to the current user once for the whole build directory.
+    pub(crate) fn keep_mount_owners(mut self) -> Self {
+        self.keep_mount_owners = true;
+        self
+    }
+
+    /// Record the environment of the commands executed in this sandbox.
+    pub(crate) fn record_environment(mut self, recorder: SnapshotRecorder) -> Self {
+        self.snapshots = Some(recorder);
+        self
+    }
+
+    /// Record the network usage of the commands executed in this sandbox, if networking is
+    /// enabled.
+    pub(crate) fn record_network_usage(mut self, recorder: NetworkRecorder) -> Self {
+        self.network = Some(recorder);
+        self
+    }
+
+    /// Use the provided image instead of the workspace's one.
+    fn image(mut self, image: String) -> Self {
+        self.image = Some(image);
+        self
+    }
+
+    /// Replace the image with its ID, so that the sandbox keeps using the same image even if its
+    /// tag is moved while the build is running.
+    pub(crate) async fn pin_image(mut self, workspace: &Workspace) -> Result<Self, Error> {
+        let name = match self.image.take() {
+            Some(image) => image,
+            None => workspace.sandbox_image().name().to_string(),
+        };
+        self.image = Some(image_id(&name).await?);
+        Ok(self)
+    }
+
     async fn create(self, workspace: &Workspace) -> Result<Container<'_>, Error> {
         let mut args: Vec<String> = vec!["create".into()];
 
+        // Label the container, allowing stale containers to be found and cleaned up later.
+        args.push("--label".into());
+        args.push(workspace.container_label());
+        args.push("--label".into());
+        args.push(format!("{}={}", CREATOR_LABEL, std::process::id()));
+        let label_args = args.len();
+
+        let mut mounts = Vec::new();
         for mount in &self.mounts {
             fs::create_dir_all(&mount.host_path).await?;
+            mounts.push(InvocationMount::new(
+                mount.host_path(workspace)?,
+                mount.sandbox_path.clone(),
+                mount.perm == MountKind::ReadWrite,
+            ));
 
             // On Windows, we mount paths containing a colon which don't work with `-v`, but on
             // Linux we need the Z flag, which doesn't work with `--mount`, for SELinux relabeling.
@@ -246,9 +662,24 @@ impl SandboxBuilder {
             args.push(format! {"{}={}", var, value})
         }
 
-        if let Some(workdir) = self.workdir {
+        let mut env: BTreeMap<_, _> = self.env.iter().cloned().collect();
+        if let Some(user) = &self.user {
+            args.push("--user".into());
+            args.push(user.clone());
+        } else if let Some(user_id) = native::current_user() {
+            args.push("-e".into());
+            args.push(format!("MAP_USER_ID={}", user_id));
+            env.insert("MAP_USER_ID".into(), user_id.to_string());
+        }
+
+        if let Some(entrypoint) = &self.entrypoint {
+            args.push("--entrypoint".into());
+            args.push(entrypoint.clone());
+        }
+
+        if let Some(workdir) = &self.workdir {
             args.push("-w".into());
-            args.push(workdir);
+            args.push(workdir.clone());
         }
 
         if let Some(limit) = self.memory_limit {
@@ -261,6 +692,19 @@ impl SandboxBuilder {
             args.push(limit.to_string());
         }
 
+        if self.low_priority {
+            // The lowest weights accepted by Docker (the defaults are 1024 and 500).
+            args.push("--cpu-shares".into());
+            args.push("2".into());
+            if !cfg!(windows) {
+                args.push("--blkio-weight".into());
+                args.push("10".into());
+            }
+        } else if let Some((coordinator, build)) = &self.cpu_coordinator {
+            args.push("--cpu-shares".into());
+            args.push(coordinator.weight(build).to_string());
+        }
+
         if !self.enable_networking {
             args.push("--network".into());
             args.push("none".into());
@@ -270,38 +714,208 @@ impl SandboxBuilder {
             args.push("--isolation=process".into());
         }
 
-        args.push(workspace.sandbox_image().name.clone());
+        let image = self
+            .image
+            .clone()
+            .unwrap_or_else(|| workspace.sandbox_image().name.clone());
+        let image_arg = args.len();
+        args.push(image.clone());
+
+        // Tags can be moved, so the invocation refers to the image by its ID.
+        let image_id = if image.starts_with("sha256:") {
+            image.clone()
+        } else {
+            image_id(&image).await.unwrap_or_else(|_| image.clone())
+        };
+        let redacted = &self.redacted;
+        let invocation = InvocationBuilder {
+            image: image_id.clone(),
+            mounts,
+            env: env
+                .iter()
+                .map(|(key, value)| (key.clone(), redact(value, redacted).into_owned()))
+                .collect(),
+            user: self.user.clone(),
+            workdir: self.workdir.clone(),
+            memory_limit: self.memory_limit,
+            cpu_limit: self.cpu_limit,
+            networking: self.enable_networking,
+            command: self
+                .cmd
+                .iter()
+                .map(|arg| redact(arg, redacted).into_owned())
+                .collect(),
+        };
+
+        if let Some(recorder) = &self.snapshots {
+            let redacted = &self.redacted;
+            recorder.record(
+                self.cmd
+                    .iter()
+                    .map(|arg| redact(arg, redacted).into_owned())
+                    .collect(),
+                env.into_iter()
+                    .map(|(key, value)| (key, redact(&value, redacted).into_owned()))
+                    .collect(),
+                image,
+            );
+        }
 
         for arg in self.cmd {
             args.push(arg);
         }
 
-        let out = Command::new(workspace, "docker")
+        let redacted = &self.redacted;
+        let mut invocation_args: Vec<String> = args[label_args..]
+            .iter()
+            .map(|arg| redact(arg, redacted).into_owned())
+            .collect();
+        invocation_args[image_arg - label_args] = image_id;
+        let invocation = invocation.build(&invocation_args);
+
+        let mut detector = SpuriousFailureDetector::default();
+        let mut detect = |line: &str, _: &mut ProcessLinesActions| detector.check(line);
+        let mut cmd = Command::new(workspace, "docker")
             .args(&*args)
-            .run_capture()
-            .await?;
+            .process_lines(&mut detect);
+        for value in &self.redacted {
+            cmd = cmd.redact(value.as_str());
+        }
+        let res = cmd.run_capture().await;
+        let out = detector.classify(res)?;
+
+        let id = out.stdout_lines()[0].clone();
+        workspace.shutdown_tracker().register_container(&id);
 
         Ok(Container {
-            id: out.stdout_lines()[0].clone(),
+            id,
             workspace,
+            redacted: self.redacted,
+            invocation,
         })
     }
 
+    /// Give the writable mounts to the provided user, skipping the ones already owned by it.
+    async fn chown_mounts(&self, workspace: &Workspace, user: &str) -> Result<(), Error> {
+        let owners = workspace.mount_owners();
+        let mounts: Vec<_> = self
+            .mounts
+            .iter()
+            .filter(|mount| mount.perm == MountKind::ReadWrite)
+            .filter(|mount| owners.owner(&mount.host_path).as_deref() != Some(user))
+            .cloned()
+            .collect();
+        let paths: Vec<_> = mounts.iter().map(|m| m.host_path.clone()).collect();
+        chown(workspace, mounts, user).await?;
+        for path in &paths {
+            owners.set_owner(path, user);
+        }
+        Ok(())
+    }
+
+    /// Run the sandbox, running it once more in a new container if it fails because of the
+    /// Docker daemon and the workspace's retry policy allows it.
     #[allow(clippy::too_many_arguments)]
     pub(super) async fn run(
         self,
         workspace: &Workspace,
         timeout: Option<Duration>,
         no_output_timeout: Option<Duration>,
-        process_lines: Option<&mut dyn FnMut(&str, &mut ProcessLinesActions)>,
+        process_lines: Option<&mut (dyn FnMut(&str, &mut ProcessLinesActions) + Send)>,
+        log_output: bool,
+        log_command: bool,
+        capture: bool,
+    ) -> Result<ProcessOutput, Error> {
+        let mut process_lines = process_lines;
+        let res = self
+            .clone()
+            .run_once(
+                workspace,
+                timeout,
+                no_output_timeout,
+                process_lines
+                    .as_mut()
+                    .map(|f| -> &mut (dyn FnMut(&str, &mut ProcessLinesActions) + Send) { f }),
+                log_output,
+                log_command,
+                capture,
+            )
+            .await;
+
+        let spurious = matches!(
+            res.as_ref().map_err(|err| err.downcast_ref()),
+            Err(Some(CommandError::SpuriousDockerFailure(_)))
+        );
+        if spurious
+            && workspace.retry_policy().retries_docker_failures()
+            && !workspace.shutdown_tracker().is_shutting_down()
+        {
+            warn!("retrying the command in a new container");
+            self.run_once(
+                workspace,
+                timeout,
+                no_output_timeout,
+                process_lines,
+                log_output,
+                log_command,
+                capture,
+            )
+            .await
+        } else {
+            res
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn run_once(
+        self,
+        workspace: &Workspace,
+        timeout: Option<Duration>,
+        no_output_timeout: Option<Duration>,
+        process_lines: Option<&mut (dyn FnMut(&str, &mut ProcessLinesActions) + Send)>,
         log_output: bool,
         log_command: bool,
         capture: bool,
     ) -> Result<ProcessOutput, Error> {
+        // Changing the owner of the mounts is only needed (and possible) on Unix hosts.
+        let chown = match (&self.user, native::current_owner()) {
+            (Some(user), Some(_)) => Some((self.clone(), user.clone())),
+            _ => None,
+        };
+        if let Some((builder, user)) = &chown {
+            builder.chown_mounts(workspace, user).await?;
+        }
+
+        let handle = self.handle.clone();
+        // The network namespace of Windows containers can't be inspected the same way.
+        let network = match &self.network {
+            Some(recorder) if self.enable_networking && !cfg!(windows) => Some(recorder.clone()),
+            _ => None,
+        };
+        let coordinator = if self.low_priority {
+            None
+        } else {
+            self.cpu_coordinator.clone()
+        };
         let container = self.create(workspace).await?;
+        let _registration = coordinator.map(|(coordinator, build)| {
+            let weight = coordinator.weight(&build);
+            coordinator.register(&build, &container.id, weight)
+        });
+        let _entry = workspace.journal().begin(Operation::Container {
+            id: container.id.clone(),
+        });
+        let invocation = container.invocation.clone();
+        if let Some(handle) = &handle {
+            handle.set_container_id(Some(container.id.clone()));
+            handle.set_sandbox_invocation(invocation.clone());
+        }
 
         // Ensure the container is properly deleted even if something panics
         scopeguard::defer! {{
+            if let Some(handle) = &handle {
+                handle.set_container_id(None);
+            }
             if let Err(err) = container.delete() {
                 error!("failed to delete container {}", container.id);
                 error!("caused by: {}", err);
@@ -311,16 +925,50 @@ impl SandboxBuilder {
             }
         }}
 
-        container
-            .run(
-                timeout,
-                no_output_timeout,
-                process_lines,
-                log_output,
-                log_command,
-                capture,
-            )
-            .await
+        let run = container.run(
+            timeout,
+            no_output_timeout,
+            process_lines,
+            log_output,
+            log_command,
+            capture,
+        );
+        let res = if let Some(recorder) = &network {
+            let usage = Mutex::new(NetworkUsage::default());
+            let monitor = super::network::monitor(workspace, &container.id, &usage);
+            let res = match future::select(Box::pin(run), Box::pin(monitor)).await {
+                Either::Left((res, _)) => res,
+                Either::Right(_) => unreachable!("the network monitor never stops"),
+            };
+            recorder.record(usage.into_inner().unwrap());
+            res
+        } else {
+            run.await
+        };
+
+        if let Some((builder, _)) = &chown {
+            if !builder.keep_mount_owners {
+                let paths = workspace
+                    .mount_owners()
+                    .take_where(|path| builder.mounts.iter().any(|mount| mount.host_path == path));
+                if let Err(err) = give_back(workspace, paths).await {
+                    error!("failed to restore the owner of the sandbox mounts: {}", err);
+                }
+            }
+        }
+
+        match res {
+            Ok(mut output) => {
+                output.sandbox_invocation = Some(invocation);
+                Ok(output)
+            }
+            Err(err) => {
+                if !matches!(err.downcast_ref(), Some(CommandError::Cancelled)) {
+                    info!("the failed command can be re-run with `{}`", invocation);
+                }
+                Err(err)
+            }
+        }
     }
 }
 
@@ -341,6 +989,9 @@ struct Container<'w> {
     // Docker container ID
     id: String,
     workspace: &'w Workspace,
+    // Values to hide from the logged output
+    redacted: Vec<String>,
+    invocation: SandboxInvocation,
 }
 
 impl fmt::Display for Container<'_> {
@@ -351,11 +1002,15 @@ impl fmt::Display for Container<'_> {
 
 impl Container<'_> {
     async fn inspect(&self) -> Result<InspectContainer, Error> {
-        let output = Command::new(self.workspace, "docker")
+        let mut detector = SpuriousFailureDetector::default();
+        let mut detect = |line: &str, _: &mut ProcessLinesActions| detector.check(line);
+        let res = Command::new(self.workspace, "docker")
             .args(&["inspect", &self.id])
             .log_output(false)
+            .process_lines(&mut detect)
             .run_capture()
-            .await?;
+            .await;
+        let output = detector.classify(res)?;
 
         let mut data: Vec<InspectContainer> =
             ::serde_json::from_str(&output.stdout_lines().join("\n"))?;
@@ -367,23 +1022,42 @@ impl Container<'_> {
         &self,
         timeout: Option<Duration>,
         no_output_timeout: Option<Duration>,
-        process_lines: Option<&mut dyn FnMut(&str, &mut ProcessLinesActions)>,
+        process_lines: Option<&mut (dyn FnMut(&str, &mut ProcessLinesActions) + Send)>,
         log_output: bool,
         log_command: bool,
         capture: bool,
     ) -> Result<ProcessOutput, Error> {
+        let mut detector = SpuriousFailureDetector::default();
+        let mut process_lines = process_lines;
+        let mut detect = |line: &str, actions: &mut ProcessLinesActions| {
+            detector.check(line);
+            if let Some(f) = &mut process_lines {
+                f(line, actions);
+            }
+        };
+
         let mut cmd = Command::new(self.workspace, "docker")
             .args(&["start", "-a", &self.id])
+            .process_lines(&mut detect)
             .timeout(timeout)
             .log_output(log_output)
             .log_command(log_command)
             .no_output_timeout(no_output_timeout);
-
-        if let Some(f) = process_lines {
-            cmd = cmd.process_lines(f);
+        for value in &self.redacted {
+            cmd = cmd.redact(value.as_str());
         }
 
+        let cancellations = self.workspace.shutdown_tracker().cancellations();
         let res = cmd.run_inner(capture).await;
+
+        // The container was already removed if the command was cancelled.
+        if self
+            .workspace
+            .shutdown_tracker()
+            .is_cancelled(cancellations)
+        {
+            return res;
+        }
         let details = self.inspect().await?;
 
         // Return a different error if the container was killed due to an OOM
@@ -394,18 +1068,61 @@ impl Container<'_> {
                 Err(CommandError::SandboxOOM.into())
             }
         } else {
-            res
+            detector.classify(res)
         }
     }
 
     async fn delete(&self) -> Result<(), Error> {
-        Command::new(self.workspace, "docker")
-            .args(&["rm", "-f", &self.id])
-            .run()
-            .await
+        self.workspace
+            .shutdown_tracker()
+            .unregister_container(&self.id);
+        crate::shutdown::remove_container(&self.id).await
+    }
+}
+
+/// Watches the output of the Docker CLI for the errors caused by the flakiness of the daemon.
+#[derive(Default)]
+struct SpuriousFailureDetector {
+    message: Option<String>,
+}
+
+impl SpuriousFailureDetector {
+    fn check(&mut self, line: &str) {
+        if self.message.is_none() && is_spurious_docker_error(line) {
+            self.message = Some(line.trim().to_string());
+        }
+    }
+
+    /// Replace the error of a failed Docker command with a
+    /// [`CommandError::SpuriousDockerFailure`](enum.CommandError.html#variant.SpuriousDockerFailure)
+    /// if a spurious error was printed. Cancellations and timeouts are kept as they are.
+    fn classify<T>(self, res: Result<T, Error>) -> Result<T, Error> {
+        match (res, self.message) {
+            (Err(err), Some(message)) if err.downcast_ref::<CommandError>().is_none() => {
+                warn!("spurious Docker failure: {}", message);
+                Err(CommandError::SpuriousDockerFailure(message).into())
+            }
+            (res, _) => res,
+        }
     }
 }
 
+/// Check whether a line printed by the Docker CLI reports a spurious failure of the daemon.
+fn is_spurious_docker_error(line: &str) -> bool {
+    let line = line.trim();
+    if line.starts_with("Cannot connect to the Docker daemon")
+        || line.starts_with("error during connect:")
+    {
+        return true;
+    }
+    DOCKER_ERROR_PREFIXES
+        .iter()
+        .any(|prefix| line.starts_with(prefix))
+        && SPURIOUS_DOCKER_ERRORS
+            .iter()
+            .any(|error| line.contains(error))
+}
+
 /// Check whether the Docker daemon is running.
 ///
 /// The Docker daemon is required for sandboxing to work, and this function returns whether the
@@ -420,3 +1137,58 @@ pub async fn docker_running(workspace: &Workspace) -> bool {
         .await
         .is_ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{is_spurious_docker_error, parse_validation, ImageProblem};
+
+    #[test]
+    fn test_parse_validation() {
+        let lines = |lines: &[&str]| lines.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+
+        assert!(parse_validation(&lines(&["user=1000"]), Some(1000)).is_empty());
+        assert!(parse_validation(&lines(&["user=0"]), None).is_empty());
+
+        assert_eq!(
+            parse_validation(
+                &lines(&["user=0", "missing=cc", "cargo-broken", "target-unwritable"]),
+                Some(1000)
+            ),
+            vec![
+                ImageProblem::UserNotMapped {
+                    expected: 1000,
+                    actual: "0".into()
+                },
+                ImageProblem::MissingBinary("cc".into()),
+                ImageProblem::ToolchainNotRunnable,
+                ImageProblem::TargetDirNotWritable,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_spurious_docker_error() {
+        for line in &[
+            "Error response from daemon: No such container: 3f2a1b",
+            "Error: No such container: 3f2a1b",
+            "docker: Error response from daemon: failed to create shim: context deadline exceeded.",
+            "Error response from daemon: connection error: desc = \"transport: Error while dialing dial unix /run/containerd/containerd.sock: connect: connection refused\"",
+            "Error response from daemon: removal of container 3f2a1b is already in progress",
+            "Cannot connect to the Docker daemon at unix:///var/run/docker.sock. Is the docker daemon running?",
+            "error during connect: Get http://%2F%2F.%2Fpipe%2Fdocker_engine/v1.40/info: open //./pipe/docker_engine: The system cannot find the file specified.",
+        ] {
+            assert!(is_spurious_docker_error(line), "{}", line);
+        }
+
+        for line in &[
+            // Permanent errors of the daemon.
+            "Error response from daemon: pull access denied for foo, repository does not exist",
+            "docker: Error response from daemon: invalid mount config for type \"bind\"",
+            // Output of the sandboxed command.
+            "error[E0425]: cannot find value `containerd` in this scope",
+            "thread 'main' panicked at 'No such container'",
+        ] {
+            assert!(!is_spurious_docker_error(line), "{}", line);
+        }
+    }
+}
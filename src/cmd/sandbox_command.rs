@@ -0,0 +1,227 @@
+use crate::cmd::{CommandError, CommandHandle, ProcessLinesActions, ProcessOutput, SandboxBuilder};
+use crate::Workspace;
+use failure::Error;
+use std::time::Duration;
+
+/// Command executed inside a sandbox without any of the setup rustwide does for builds.
+///
+/// Unlike sandboxed [`Command`](struct.Command.html)s, which mount the workspace's toolchains and
+/// a source directory and configure cargo inside the sandbox, a `SandboxCommand` runs the program
+/// with only the mounts, limits and environment variables set on its
+/// [`SandboxBuilder`](struct.SandboxBuilder.html) and on the command itself. This allows reusing
+/// rustwide's container management for tasks unrelated to building crates, such as running a
+/// static site generator on the documentation produced by a build.
+///
+/// The program has to be available in the sandbox image, and runs in the image's default working
+/// directory unless [`workdir`](#method.workdir) is called. Containers are cleaned up when the
+/// command ends, and are removed when the workspace is shut down like the ones of builds.
+///
+/// # Example
+///
+/// ```no_run
+/// # use rustwide::{cmd::{MountKind, SandboxBuilder, SandboxCommand}, Workspace};
+/// # use std::error::Error;
+/// # async fn run(workspace: &Workspace) -> Result<(), Box<dyn Error>> {
+/// let sandbox = SandboxBuilder::new()
+///     .mount("target/doc".as_ref(), "/site/input".as_ref(), MountKind::ReadOnly)
+///     .mount("public".as_ref(), "/site/output".as_ref(), MountKind::ReadWrite)
+///     .memory_limit(Some(512 * 1024 * 1024))
+///     .enable_networking(false);
+///
+/// SandboxCommand::new(workspace, sandbox, "zola")
+///     .args(&["build", "--output-dir", "/site/output"])
+///     .workdir("/site/input")
+///     .run()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct SandboxCommand<'w, 'pl> {
+    workspace: &'w Workspace,
+    sandbox: SandboxBuilder,
+    program: String,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    redacted: Vec<String>,
+    workdir: Option<String>,
+    timeout: Option<Duration>,
+    no_output_timeout: Option<Duration>,
+    process_lines: Option<&'pl mut (dyn FnMut(&str, &mut ProcessLinesActions) + Send)>,
+    log_output: bool,
+    log_command: bool,
+    low_priority: bool,
+    handle: Option<CommandHandle>,
+}
+
+impl<'w, 'pl> SandboxCommand<'w, 'pl> {
+    /// Create a new command running the provided program inside the sandbox. The program is
+    /// either a path inside the sandbox or the name of a binary in the sandbox's `$PATH`.
+    pub fn new(workspace: &'w Workspace, sandbox: SandboxBuilder, program: &str) -> Self {
+        SandboxCommand {
+            workspace,
+            sandbox,
+            program: program.into(),
+            args: Vec::new(),
+            env: Vec::new(),
+            redacted: Vec::new(),
+            workdir: None,
+            timeout: workspace.default_command_timeout(),
+            no_output_timeout: workspace.default_command_no_output_timeout(),
+            process_lines: None,
+            log_output: true,
+            log_command: true,
+            low_priority: workspace.default_command_low_priority(),
+            handle: None,
+        }
+    }
+
+    /// Add command-line arguments to the command. This method can be called multiple times to add
+    /// additional args.
+    pub fn args<S: AsRef<str>>(mut self, args: &[S]) -> Self {
+        self.args
+            .extend(args.iter().map(|arg| arg.as_ref().to_string()));
+        self
+    }
+
+    /// Add an environment variable to the command.
+    pub fn env<S1: Into<String>, S2: Into<String>>(mut self, key: S1, value: S2) -> Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    /// Add an environment variable to the command, hiding its value from the logs and from the
+    /// recorded [`SandboxInvocation`](struct.SandboxInvocation.html).
+    pub fn env_redacted<S1: Into<String>, S2: Into<String>>(mut self, key: S1, value: S2) -> Self {
+        let value = value.into();
+        self.redacted.push(value.clone());
+        self.env(key, value)
+    }
+
+    /// Set the directory inside the sandbox the command is executed in.
+    pub fn workdir<S: Into<String>>(mut self, workdir: S) -> Self {
+        self.workdir = Some(workdir.into());
+        self
+    }
+
+    /// Set the timeout of this command. If it runs for more time the container will be killed.
+    ///
+    /// Its default value is configured through
+    /// [`WorkspaceBuilder::command_timeout`](../struct.WorkspaceBuilder.html#method.command_timeout).
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the no output timeout of this command. If it doesn't output anything for more time the
+    /// container will be killed.
+    ///
+    /// Its default value is configured through
+    /// [`WorkspaceBuilder::command_no_output_timeout`](../struct.WorkspaceBuilder.html#method.command_no_output_timeout).
+    pub fn no_output_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.no_output_timeout = timeout;
+        self
+    }
+
+    /// Set the function that will be called each time a line is outputted to either the standard
+    /// output or the standard error, like
+    /// [`Command::process_lines`](struct.Command.html#method.process_lines).
+    pub fn process_lines(
+        mut self,
+        f: &'pl mut (dyn FnMut(&str, &mut ProcessLinesActions) + Send),
+    ) -> Self {
+        self.process_lines = Some(f);
+        self
+    }
+
+    /// Enable or disable logging all the output lines to the [`log` crate][log]. By default
+    /// logging is enabled.
+    ///
+    /// [log]: https://crates.io/crates/log
+    pub fn log_output(mut self, log_output: bool) -> Self {
+        self.log_output = log_output;
+        self
+    }
+
+    /// Enable or disable logging the command before the execution. By default logging is enabled.
+    pub fn log_command(mut self, log_command: bool) -> Self {
+        self.log_command = log_command;
+        self
+    }
+
+    /// Enable or disable running the container with the lowest CPU and block I/O cgroup weights.
+    ///
+    /// Its default value is configured through
+    /// [`WorkspaceBuilder::command_low_priority`](../struct.WorkspaceBuilder.html#method.command_low_priority).
+    pub fn low_priority(mut self, low_priority: bool) -> Self {
+        self.low_priority = low_priority;
+        self
+    }
+
+    /// Attach a [`CommandHandle`](struct.CommandHandle.html) to the command, exposing the ID of
+    /// its container while it runs.
+    pub fn handle(mut self, handle: &CommandHandle) -> Self {
+        self.handle = Some(handle.clone());
+        self
+    }
+
+    /// Run the command and return an error if it fails (for example with a non-zero exit code or
+    /// a timeout).
+    pub async fn run(self) -> Result<(), Error> {
+        self.run_inner(false).await?;
+        Ok(())
+    }
+
+    /// Run the command and return its output if it succeeds. If it fails (for example with a
+    /// non-zero exit code or a timeout) an error will be returned instead.
+    pub async fn run_capture(self) -> Result<ProcessOutput, Error> {
+        self.run_inner(true).await
+    }
+
+    async fn run_inner(self, capture: bool) -> Result<ProcessOutput, Error> {
+        let workspace = self.workspace;
+        if workspace.shutdown_tracker().is_shutting_down() {
+            return Err(CommandError::Cancelled.into());
+        }
+        #[cfg(feature = "mock")]
+        {
+            if let Some(mock) = workspace.mock_fixtures() {
+                let mut cmd = super::Command::new(workspace, self.program.as_str())
+                    .args(&self.args)
+                    .log_output(self.log_output)
+                    .log_command(self.log_command);
+                if let Some(f) = self.process_lines {
+                    cmd = cmd.process_lines(f);
+                }
+                return cmd.run_mocked(mock, capture);
+            }
+        }
+
+        let mut cmd = vec![self.program];
+        cmd.extend(self.args);
+        let mut builder = self.sandbox.cmd(cmd).low_priority(self.low_priority);
+        if let Some(workdir) = self.workdir {
+            builder = builder.workdir(workdir);
+        }
+        for (key, value) in self.env {
+            builder = builder.env(key, value);
+        }
+        for value in self.redacted {
+            builder = builder.redact(value);
+        }
+        if let Some(handle) = self.handle {
+            builder = builder.handle(handle);
+        }
+
+        builder
+            .run(
+                workspace,
+                self.timeout,
+                self.no_output_timeout,
+                self.process_lines,
+                self.log_output,
+                self.log_command,
+                capture,
+            )
+            .await
+    }
+}
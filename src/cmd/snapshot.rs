@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Effective environment a sandboxed command of a build ran in, retrieved with
+/// [`Build::environment_snapshots`](../struct.Build.html#method.environment_snapshots).
+///
+/// Snapshots can be serialized and compared with each other, to find what differs between a build
+/// that works and one that doesn't. The values redacted from the logs are redacted here as well.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EnvironmentSnapshot {
+    command: Vec<String>,
+    env: BTreeMap<String, String>,
+    image: String,
+    toolchain: String,
+    tools: BTreeMap<String, String>,
+}
+
+impl EnvironmentSnapshot {
+    /// Get the command executed inside the sandbox, including its arguments.
+    pub fn command(&self) -> &[String] {
+        &self.command
+    }
+
+    /// Get the environment variables set inside the sandbox.
+    pub fn env(&self) -> &BTreeMap<String, String> {
+        &self.env
+    }
+
+    /// Get the name of the sandbox image the command ran in.
+    pub fn image(&self) -> &str {
+        &self.image
+    }
+
+    /// Get the name of the toolchain used by the build.
+    pub fn toolchain(&self) -> &str {
+        &self.toolchain
+    }
+
+    /// Get the name and the version of the tools installed in the workspace.
+    pub fn tools(&self) -> &BTreeMap<String, String> {
+        &self.tools
+    }
+
+    /// Get the names of the environment variables whose value differs between the two snapshots,
+    /// including the ones set in only one of them.
+    pub fn env_differences<'a>(&'a self, other: &'a EnvironmentSnapshot) -> Vec<&'a str> {
+        self.env
+            .keys()
+            .chain(other.env.keys().filter(|key| !self.env.contains_key(*key)))
+            .filter(|key| self.env.get(*key) != other.env.get(*key))
+            .map(|key| key.as_str())
+            .collect()
+    }
+}
+
+/// Collects the snapshots of the sandboxed commands executed by a build.
+#[derive(Clone)]
+pub(crate) struct SnapshotRecorder {
+    toolchain: String,
+    tools: BTreeMap<String, String>,
+    snapshots: Arc<Mutex<Vec<EnvironmentSnapshot>>>,
+}
+
+impl SnapshotRecorder {
+    pub(crate) fn new(toolchain: String, tools: BTreeMap<String, String>) -> Self {
+        SnapshotRecorder {
+            toolchain,
+            tools,
+            snapshots: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub(super) fn record(
+        &self,
+        command: Vec<String>,
+        env: BTreeMap<String, String>,
+        image: String,
+    ) {
+        self.snapshots.lock().unwrap().push(EnvironmentSnapshot {
+            command,
+            env,
+            image,
+            toolchain: self.toolchain.clone(),
+            tools: self.tools.clone(),
+        });
+    }
+
+    pub(crate) fn snapshots(&self) -> Vec<EnvironmentSnapshot> {
+        self.snapshots.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SnapshotRecorder;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_env_differences() {
+        let recorder = SnapshotRecorder::new("stable".into(), BTreeMap::new());
+        let env = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        recorder.record(
+            vec!["cargo".into()],
+            env(&[("A", "1"), ("B", "2"), ("C", "3")]),
+            "image".into(),
+        );
+        recorder.record(
+            vec!["cargo".into()],
+            env(&[("A", "1"), ("B", "changed"), ("D", "4")]),
+            "image".into(),
+        );
+
+        let snapshots = recorder.snapshots();
+        assert_eq!(snapshots[0].toolchain(), "stable");
+        assert_eq!(
+            snapshots[0].env_differences(&snapshots[1]),
+            vec!["B", "C", "D"]
+        );
+        assert!(snapshots[0].env_differences(&snapshots[0]).is_empty());
+    }
+}
@@ -0,0 +1,202 @@
+use crate::build::CratePatch;
+use crate::cmd::SandboxBuilder;
+use crate::{Build, BuildDirectory, Crate, Toolchain};
+use failure::Error;
+use serde::Serialize;
+
+/// Builder for comparing the behavior of a crate across multiple toolchains, created from
+/// [`BuildDirectory::compare`](struct.BuildDirectory.html#method.compare).
+///
+/// The crate's source code is fetched and prepared only once (with the first toolchain), and the
+/// same source directory, lockfile and target directory are then reused to build it with every
+/// toolchain. This is the core loop of [Crater][crater], available as a library primitive.
+///
+/// [crater]: https://github.com/rust-lang/crater
+pub struct ComparisonBuild<'a> {
+    build_dir: &'a mut BuildDirectory,
+    toolchains: Vec<&'a Toolchain>,
+    krate: &'a Crate,
+    sandbox: SandboxBuilder,
+    patches: Vec<CratePatch>,
+    source_dir_name: Option<String>,
+}
+
+impl<'a> ComparisonBuild<'a> {
+    pub(crate) fn new(
+        build_dir: &'a mut BuildDirectory,
+        toolchains: &[&'a Toolchain],
+        krate: &'a Crate,
+        sandbox: SandboxBuilder,
+    ) -> Self {
+        ComparisonBuild {
+            build_dir,
+            toolchains: toolchains.to_vec(),
+            krate,
+            sandbox,
+            patches: Vec::new(),
+            source_dir_name: None,
+        }
+    }
+
+    /// Add a patch to this build.
+    /// Patches get added to the crate's Cargo.toml in the `patch.crates-io` table, and are shared
+    /// by the builds with all the toolchains.
+    pub fn patch_with_git(mut self, name: &str, uri: &str, branch: &str) -> Self {
+        self.patches.push(CratePatch {
+            name: name.into(),
+            uri: uri.into(),
+            branch: branch.into(),
+        });
+
+        self
+    }
+
+    /// Prepare the crate into a directory with the provided name inside the build directory,
+    /// like [`BuildBuilder::source_dir_name`](struct.BuildBuilder.html#method.source_dir_name).
+    pub fn source_dir_name(mut self, name: &str) -> Self {
+        self.source_dir_name = Some(name.into());
+        self
+    }
+
+    /// Run a sandboxed build of the provided crate with each toolchain, in the order they were
+    /// provided. The closure will be called once per toolchain with an instance of
+    /// [`Build`](struct.Build.html) configured to use that toolchain.
+    ///
+    /// An error returned by the closure is recorded as a failure for that toolchain, and doesn't
+    /// prevent the other toolchains from being built. An error is returned by this method only if
+    /// preparing the crate fails.
+    pub async fn run<F: FnMut(&Build) -> Result<(), Error>>(
+        self,
+        f: F,
+    ) -> Result<ComparisonResult, Error> {
+        let results = self
+            .build_dir
+            .run_with_toolchains(
+                &self.toolchains,
+                self.krate,
+                self.sandbox,
+                self.patches,
+                self.source_dir_name.as_deref(),
+                f,
+            )
+            .await?;
+
+        Ok(ComparisonResult {
+            outcomes: self
+                .toolchains
+                .iter()
+                .zip(results.into_iter())
+                .map(|(toolchain, result)| {
+                    let outcome = match result {
+                        Ok(()) => BuildOutcome::Success,
+                        Err(err) => BuildOutcome::Failure(err),
+                    };
+                    ((*toolchain).clone(), outcome)
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Outcome of building a crate with a single toolchain in a
+/// [`ComparisonBuild`](struct.ComparisonBuild.html).
+#[derive(Debug)]
+pub enum BuildOutcome {
+    /// The build completed successfully.
+    Success,
+    /// The build failed with the contained error.
+    Failure(Error),
+}
+
+impl BuildOutcome {
+    /// Check whether the build completed successfully.
+    pub fn is_success(&self) -> bool {
+        match self {
+            BuildOutcome::Success => true,
+            BuildOutcome::Failure(_) => false,
+        }
+    }
+}
+
+/// Classification of a [`ComparisonBuild`](struct.ComparisonBuild.html), comparing the baseline
+/// toolchain (the first one) with all the others.
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum Comparison {
+    /// The crate built successfully with all the toolchains.
+    SamePass,
+    /// The crate failed to build with all the toolchains.
+    SameFail,
+    /// The crate built successfully with the baseline toolchain, but failed with at least one of
+    /// the other toolchains.
+    Regressed,
+    /// The crate failed to build with the baseline toolchain, but built successfully with at least
+    /// one of the other toolchains.
+    Fixed,
+}
+
+/// Result of a [`ComparisonBuild`](struct.ComparisonBuild.html), containing the outcome of the
+/// build with each toolchain.
+#[derive(Debug)]
+pub struct ComparisonResult {
+    outcomes: Vec<(Toolchain, BuildOutcome)>,
+}
+
+impl ComparisonResult {
+    /// Return the outcome with every toolchain, in the order the toolchains were built.
+    pub fn outcomes(&self) -> &[(Toolchain, BuildOutcome)] {
+        &self.outcomes
+    }
+
+    /// Return the outcome of the build with the provided toolchain, if it was part of the
+    /// comparison.
+    pub fn outcome(&self, toolchain: &Toolchain) -> Option<&BuildOutcome> {
+        self.outcomes
+            .iter()
+            .find(|(t, _)| t == toolchain)
+            .map(|(_, outcome)| outcome)
+    }
+
+    /// Classify the result of the comparison, comparing the baseline toolchain with all the
+    /// others.
+    pub fn comparison(&self) -> Comparison {
+        classify(
+            self.outcomes
+                .iter()
+                .map(|(_, outcome)| outcome.is_success()),
+        )
+    }
+}
+
+pub(crate) fn classify(mut results: impl Iterator<Item = bool>) -> Comparison {
+    let baseline = results.next().unwrap_or(true);
+    let all_same = results.all(|success| success == baseline);
+    match (baseline, all_same) {
+        (true, true) => Comparison::SamePass,
+        (false, true) => Comparison::SameFail,
+        (true, false) => Comparison::Regressed,
+        (false, false) => Comparison::Fixed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify, Comparison};
+
+    #[test]
+    fn test_classify() {
+        let check = |results: &[bool], expected| {
+            assert_eq!(classify(results.iter().cloned()), expected);
+        };
+
+        check(&[true], Comparison::SamePass);
+        check(&[false], Comparison::SameFail);
+        check(&[true, true, true], Comparison::SamePass);
+        check(&[false, false, false], Comparison::SameFail);
+        check(&[true, false], Comparison::Regressed);
+        check(&[true, true, false], Comparison::Regressed);
+        check(&[false, true], Comparison::Fixed);
+        check(&[false, false, true], Comparison::Fixed);
+    }
+}
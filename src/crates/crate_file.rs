@@ -0,0 +1,67 @@
+use super::unpack::{read_crate_manifest, unpack_crate};
+use super::CrateTrait;
+use crate::Workspace;
+use async_trait::async_trait;
+use failure::Error;
+use log::info;
+use remove_dir_all::remove_dir_all;
+use std::path::{Path, PathBuf};
+
+pub(super) struct CrateFile {
+    path: PathBuf,
+}
+
+impl CrateFile {
+    pub(super) fn new(path: &Path) -> Self {
+        CrateFile { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl CrateTrait for CrateFile {
+    async fn fetch(&self, _workspace: &Workspace) -> Result<(), Error> {
+        // There is nothing to download, but a missing file is reported as early as possible.
+        if !self.path.is_file() {
+            failure::bail!("{} doesn't exist", self);
+        }
+        Ok(())
+    }
+
+    async fn purge_from_cache(&self, _workspace: &Workspace) -> Result<(), Error> {
+        // The file is not stored in the cache, and it's not owned by rustwide.
+        Ok(())
+    }
+
+    async fn copy_source_to(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error> {
+        let parallel = workspace.parallel_extraction();
+
+        info!("extracting {} into {}", self, dest.display());
+        let path = self.path.clone();
+        let dest_owned = dest.to_path_buf();
+        let result = crate::utils::spawn_blocking(workspace.runtime(), move || {
+            unpack_crate(&path, &dest_owned, parallel)
+        })
+        .await?;
+
+        if let Err(err) = result {
+            let _ = remove_dir_all(dest);
+            Err(err
+                .context(format!("unable to extract {}", self.path.display()))
+                .into())
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn read_manifest(&self, workspace: &Workspace) -> Result<String, Error> {
+        let path = self.path.clone();
+        crate::utils::spawn_blocking(workspace.runtime(), move || read_crate_manifest(&path))
+            .await?
+    }
+}
+
+impl std::fmt::Display for CrateFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "crate file {}", self.path.display())
+    }
+}
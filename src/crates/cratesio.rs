@@ -1,73 +1,189 @@
+use super::registry::{find_checksum, prefix, resolve_version, validate_name, validate_version};
+use super::unpack::{read_crate_manifest, unpack_crate};
 use super::CrateTrait;
+use crate::prepare::PrepareError;
 use crate::Workspace;
 use async_trait::async_trait;
-use failure::Error;
+use failure::{Error, ResultExt};
 use flate2::read::GzDecoder;
-use log::info;
+use log::{info, warn};
 use remove_dir_all::remove_dir_all;
-use std::io::Read;
+use semver::VersionReq;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::BufReader;
 use std::path::{Path, PathBuf};
-use tar::Archive;
-use tokio::{
-    fs::{self, File},
-    io::{BufReader, BufWriter},
-};
+use std::sync::Mutex;
+use tokio::fs;
 
 static CRATES_ROOT: &str = "https://static.crates.io/crates";
+static SPARSE_INDEX: &str = "https://index.crates.io";
 
 impl CratesIOCrate {
-    pub(super) fn new(name: &str, version: &str) -> Self {
-        CratesIOCrate {
+    pub(super) fn new(name: &str, version: &str) -> Result<Self, PrepareError> {
+        let name = name.trim();
+        validate_name(name)?;
+        let version = validate_version(name, version)?;
+        Ok(CratesIOCrate {
             name: name.into(),
-            version: version.into(),
-        }
+            requirement: None,
+            version: Mutex::new(Some(version)),
+        })
+    }
+
+    pub(super) fn with_requirement(
+        name: &str,
+        requirement: VersionReq,
+    ) -> Result<Self, PrepareError> {
+        let name = name.trim();
+        validate_name(name)?;
+        Ok(CratesIOCrate {
+            name: name.into(),
+            requirement: Some(requirement),
+            version: Mutex::new(None),
+        })
+    }
+
+    pub(super) fn name(&self) -> &str {
+        &self.name
     }
 
-    fn cache_path(&self, workspace: &Workspace) -> PathBuf {
+    /// Get the exact version of the crate, or `None` if it wasn't resolved yet.
+    pub(super) fn version(&self) -> Option<String> {
+        self.version.lock().unwrap().clone()
+    }
+
+    fn cache_path(&self, workspace: &Workspace, version: &str) -> PathBuf {
         workspace
             .cache_dir()
             .join("cratesio-sources")
             .join(&self.name)
-            .join(format!("{}-{}.crate", self.name, self.version))
+            .join(format!("{}-{}.crate", self.name, version))
+    }
+
+    /// Fetch the entries of the crate in the crates.io sparse index.
+    async fn index_entries(&self, workspace: &Workspace) -> Result<String, Error> {
+        let url = format!(
+            "{}/{}/{}",
+            SPARSE_INDEX,
+            prefix(&self.name).to_lowercase(),
+            self.name.to_lowercase()
+        );
+        let entries = crate::download::download_bytes(workspace, &url)
+            .await
+            .with_context(|_| format!("failed to fetch the index entries of {}", self.name))?;
+        Ok(String::from_utf8_lossy(&entries).into_owned())
+    }
+
+    /// Resolve the requirement of the crate with its index entries. The resolved version is then
+    /// reused for the lifetime of the crate, even if newer versions are published in the
+    /// meantime.
+    fn resolve(&self, entries: &str) -> Result<String, Error> {
+        let requirement = self
+            .requirement
+            .as_ref()
+            .expect("crates without a version must have a requirement");
+        let version = resolve_version(entries, requirement).ok_or_else(|| {
+            failure::format_err!("no version of {} matches {}", self.name, requirement)
+        })?;
+
+        info!("resolved {} to version {}", self, version);
+        Ok(self.version.lock().unwrap().get_or_insert(version).clone())
+    }
+}
+
+/// Check the SHA-256 checksum of a downloaded `.crate` file against the one in the index.
+pub(super) fn verify_checksum(content: &[u8], expected: &str) -> Result<(), PrepareError> {
+    let actual = format!("{:x}", Sha256::digest(content));
+    if actual.eq_ignore_ascii_case(expected) {
+        Ok(())
+    } else {
+        Err(PrepareError::ChecksumMismatch {
+            expected: expected.to_string(),
+            actual,
+        })
     }
 }
 
+/// Check that a cached `.crate` file is a complete gzip stream. Files truncated by a crash or
+/// corrupted on disk fail the CRC check done at the end of the stream.
+fn check_integrity(path: &Path) -> Result<(), Error> {
+    let mut decoder = GzDecoder::new(BufReader::new(File::open(path)?));
+    std::io::copy(&mut decoder, &mut std::io::sink())?;
+    Ok(())
+}
+
 pub(super) struct CratesIOCrate {
     name: String,
-    version: String,
+    requirement: Option<VersionReq>,
+    version: Mutex<Option<String>>,
 }
 
 #[async_trait]
 impl CrateTrait for CratesIOCrate {
+    fn cache_entry(&self, workspace: &Workspace) -> Option<PathBuf> {
+        self.version()
+            .map(|version| self.cache_path(workspace, &version))
+    }
+
     async fn fetch(&self, workspace: &Workspace) -> Result<(), Error> {
-        let local = self.cache_path(workspace);
+        let mut entries = None;
+        let version = match self.version() {
+            Some(version) => version,
+            None => self.resolve(entries.get_or_insert(self.index_entries(workspace).await?))?,
+        };
+        let local = self.cache_path(workspace, &version);
         if local.exists() {
-            info!("crate {} {} is already in cache", self.name, self.version);
-            return Ok(());
+            let cached = local.clone();
+            let integrity =
+                crate::utils::spawn_blocking(workspace.runtime(), move || check_integrity(&cached))
+                    .await?;
+            match integrity {
+                Ok(()) => {
+                    info!("crate {} {} is already in cache", self.name, version);
+                    return Ok(());
+                }
+                // The corrupted file is atomically replaced by the new download, so other
+                // processes never see the crate missing from the cache.
+                Err(err) => warn!(
+                    "cached crate {} {} is corrupted, fetching it again: {}",
+                    self.name, version, err
+                ),
+            }
         }
 
-        info!("fetching crate {} {}...", self.name, self.version);
-        if let Some(parent) = local.parent() {
-            fs::create_dir_all(parent).await?;
-        }
-        let remote = format!(
-            "{0}/{1}/{1}-{2}.crate",
-            CRATES_ROOT, self.name, self.version
-        );
-        let mut resp = workspace
-            .http_client()
-            .get(&remote)
-            .send()
-            .await?
-            .error_for_status()?;
-        resp.copy_to(&mut BufWriter::new(File::create(&local).await?))
-            .await?;
+        let entries = match entries {
+            Some(entries) => entries,
+            None => self.index_entries(workspace).await?,
+        };
+        let expected = find_checksum(&entries, &version).ok_or_else(|| {
+            failure::format_err!("{} {} is not in the crates.io index", self.name, version)
+        })?;
+
+        // The crate is verified before being moved into the cache, so a corrupted download never
+        // ends up being built.
+        info!("fetching crate {} {}...", self.name, version);
+        let remote = format!("{0}/{1}/{1}-{2}.crate", CRATES_ROOT, self.name, version);
+        // The mismatch is returned as is, so that callers can match on it with `downcast_ref`.
+        let verify = |path: &Path| -> Result<(), Error> {
+            let content = std::fs::read(path)?;
+            verify_checksum(&content, &expected).map_err(|err| {
+                warn!("failed to verify crate {} {}: {}", self.name, version, err);
+                err.into()
+            })
+        };
+        crate::download::download_verified(workspace, &remote, &local, &verify).await?;
 
         Ok(())
     }
 
     async fn purge_from_cache(&self, workspace: &Workspace) -> Result<(), Error> {
-        let path = self.cache_path(workspace);
+        // Crates whose version wasn't resolved yet can't be in the cache.
+        let version = match self.version() {
+            Some(version) => version,
+            None => return Ok(()),
+        };
+        let path = self.cache_path(workspace, &version);
         if path.exists() {
             fs::remove_file(&path).await?;
         }
@@ -76,54 +192,102 @@ impl CrateTrait for CratesIOCrate {
     }
 
     async fn copy_source_to(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error> {
-        let cached = self.cache_path(workspace);
-        let mut file = File::open(cached).await?;
-        let mut tar = Archive::new(GzDecoder::new(BufReader::new(&mut file)));
+        let version = match self.version() {
+            Some(version) => version,
+            None => failure::bail!("{} must be fetched before its source is copied", self),
+        };
+        let cached = self.cache_path(workspace, &version);
+        let parallel = workspace.parallel_extraction();
 
         info!(
             "extracting crate {} {} into {}",
             self.name,
-            self.version,
+            version,
             dest.display()
         );
-        if let Err(err) = unpack_without_first_dir(&mut tar, dest) {
+        let dest_owned = dest.to_path_buf();
+        let result = crate::utils::spawn_blocking(workspace.runtime(), move || {
+            unpack_crate(&cached, &dest_owned, parallel)
+        })
+        .await?;
+
+        if let Err(err) = result {
             let _ = remove_dir_all(dest);
             Err(err
                 .context(format!(
                     "unable to download {} version {}",
-                    self.name, self.version
+                    self.name, version
                 ))
                 .into())
         } else {
             Ok(())
         }
     }
+
+    async fn read_manifest(&self, workspace: &Workspace) -> Result<String, Error> {
+        let version = match self.version() {
+            Some(version) => version,
+            None => failure::bail!("{} must be fetched before its manifest is read", self),
+        };
+        let cached = self.cache_path(workspace, &version);
+        crate::utils::spawn_blocking(workspace.runtime(), move || read_crate_manifest(&cached))
+            .await?
+    }
 }
 
 impl std::fmt::Display for CratesIOCrate {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "crates.io crate {} {}", self.name, self.version)
+        match (self.version(), &self.requirement) {
+            (Some(version), _) => write!(f, "crates.io crate {} {}", self.name, version),
+            (None, Some(req)) => write!(f, "crates.io crate {} {}", self.name, req),
+            (None, None) => write!(f, "crates.io crate {}", self.name),
+        }
     }
 }
 
-fn unpack_without_first_dir<R: Read>(archive: &mut Archive<R>, path: &Path) -> Result<(), Error> {
-    let entries = archive.entries()?;
-    for entry in entries {
-        let mut entry = entry?;
-        let relpath = {
-            let path = entry.path();
-            let path = path?;
-            path.into_owned()
-        };
-        let mut components = relpath.components();
-        // Throw away the first path component
-        components.next();
-        let full_path = path.join(&components.as_path());
-        if let Some(parent) = full_path.parent() {
-            std::fs::create_dir_all(parent)?;
+#[cfg(test)]
+mod tests {
+    use super::{check_integrity, verify_checksum};
+    use crate::prepare::PrepareError;
+    use failure::Error;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    #[test]
+    fn test_verify_checksum() {
+        let checksum = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        assert!(verify_checksum(b"hello", checksum).is_ok());
+        assert!(verify_checksum(b"hello", &checksum.to_uppercase()).is_ok());
+        match verify_checksum(b"hello!", checksum) {
+            Err(PrepareError::ChecksumMismatch { expected, .. }) => assert_eq!(expected, checksum),
+            other => panic!("unexpected result: {:?}", other),
         }
-        entry.unpack(&full_path)?;
     }
 
-    Ok(())
+    #[test]
+    fn test_check_integrity() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[42; 4096])?;
+        let content = encoder.finish()?;
+
+        let valid = dir.path().join("valid.crate");
+        std::fs::write(&valid, &content)?;
+        assert!(check_integrity(&valid).is_ok());
+
+        // A download interrupted halfway through...
+        let truncated = dir.path().join("truncated.crate");
+        std::fs::write(&truncated, &content[..content.len() / 2])?;
+        assert!(check_integrity(&truncated).is_err());
+
+        // ...and a file whose checksum doesn't match its content.
+        let mut content = content;
+        let last = content.len() - 1;
+        content[last] ^= 0xff;
+        let corrupted = dir.path().join("corrupted.crate");
+        std::fs::write(&corrupted, &content)?;
+        assert!(check_integrity(&corrupted).is_err());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,120 @@
+use super::CrateTrait;
+use crate::prepare::PrepareError;
+use crate::Workspace;
+use async_trait::async_trait;
+use failure::{Error, ResultExt};
+use std::path::Path;
+
+/// Source of crates implemented outside of rustwide, for example an internal artifact store or a
+/// database. Crates loaded from a custom source with [`Crate::custom`] are built like the ones
+/// loaded from the sources supported by rustwide.
+///
+/// The trait is implemented with the [`async-trait`] crate. The source's [`Display`]
+/// implementation is used in the logs and to identify the crate, so it should be unique for each
+/// crate.
+///
+/// # Example
+///
+/// ```no_run
+/// use async_trait::async_trait;
+/// use failure::Error;
+/// use rustwide::{Crate, CrateSource, Workspace};
+/// use std::path::Path;
+///
+/// struct ArtifactStore {
+///     name: String,
+/// }
+///
+/// #[async_trait]
+/// impl CrateSource for ArtifactStore {
+///     async fn fetch(&self, workspace: &Workspace) -> Result<(), Error> {
+///         // Download the crate into a cache.
+///         Ok(())
+///     }
+///
+///     async fn purge_from_cache(&self, workspace: &Workspace) -> Result<(), Error> {
+///         // Remove the crate from the cache.
+///         Ok(())
+///     }
+///
+///     async fn copy_source_to(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error> {
+///         // Copy the cached crate to `dest`.
+///         Ok(())
+///     }
+/// }
+///
+/// impl std::fmt::Display for ArtifactStore {
+///     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+///         write!(f, "artifact store crate {}", self.name)
+///     }
+/// }
+///
+/// let krate = Crate::custom(Box::new(ArtifactStore { name: "foo".into() }));
+/// ```
+///
+/// [`Crate::custom`]: struct.Crate.html#method.custom
+/// [`async-trait`]: https://docs.rs/async-trait
+/// [`Display`]: https://doc.rust-lang.org/std/fmt/trait.Display.html
+#[async_trait]
+pub trait CrateSource: std::fmt::Display + Send + Sync {
+    /// Fetch the crate's source code and cache it in the workspace, if the source needs a cache.
+    /// This is called by [`Crate::fetch`](struct.Crate.html#method.fetch).
+    async fn fetch(&self, workspace: &Workspace) -> Result<(), Error>;
+
+    /// Remove the cached copy of the crate, doing nothing if it isn't cached. This is called by
+    /// [`Crate::purge_from_cache`](struct.Crate.html#method.purge_from_cache).
+    async fn purge_from_cache(&self, workspace: &Workspace) -> Result<(), Error>;
+
+    /// Copy the source code of the crate to `dest`, which doesn't exist when this is called. The
+    /// crate is fetched before its source is copied.
+    async fn copy_source_to(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error>;
+
+    /// Return the contents of the crate's `Cargo.toml`, without building the crate.
+    ///
+    /// The default implementation copies the source code to a temporary directory and reads the
+    /// manifest from there: sources able to read it more efficiently should override it.
+    async fn read_manifest(&self, workspace: &Workspace) -> Result<String, Error> {
+        tokio::fs::create_dir_all(workspace.cache_dir()).await?;
+        let dir = tempfile::tempdir_in(workspace.cache_dir())?;
+        let source = dir.path().join("source");
+        self.copy_source_to(workspace, &source).await?;
+        Ok(tokio::fs::read_to_string(source.join("Cargo.toml"))
+            .await
+            .with_context(|_| PrepareError::MissingCargoToml)?)
+    }
+}
+
+pub(super) struct CustomCrate {
+    source: Box<dyn CrateSource>,
+}
+
+impl CustomCrate {
+    pub(super) fn new(source: Box<dyn CrateSource>) -> Self {
+        CustomCrate { source }
+    }
+}
+
+#[async_trait]
+impl CrateTrait for CustomCrate {
+    async fn fetch(&self, workspace: &Workspace) -> Result<(), Error> {
+        self.source.fetch(workspace).await
+    }
+
+    async fn purge_from_cache(&self, workspace: &Workspace) -> Result<(), Error> {
+        self.source.purge_from_cache(workspace).await
+    }
+
+    async fn copy_source_to(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error> {
+        self.source.copy_source_to(workspace, dest).await
+    }
+
+    async fn read_manifest(&self, workspace: &Workspace) -> Result<String, Error> {
+        self.source.read_manifest(workspace).await
+    }
+}
+
+impl std::fmt::Display for CustomCrate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.source.fmt(f)
+    }
+}
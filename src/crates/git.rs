@@ -1,14 +1,17 @@
 use super::CrateTrait;
-use crate::cmd::{Command, ProcessLinesActions};
+use crate::cmd::Command;
+use crate::git::{self, Depth, GitError, BRANCHES_REFSPEC};
+use crate::journal::Operation;
 use crate::prepare::PrepareError;
-use crate::Workspace;
+use crate::utils::FileLock;
+use crate::{GitCredentials, Workspace};
 use async_trait::async_trait;
 use failure::{Error, ResultExt};
 use log::{info, warn};
 use percent_encoding::{percent_encode, AsciiSet, CONTROLS};
 use std::path::{Path, PathBuf};
 
-const ENCODE_SET: AsciiSet = CONTROLS
+pub(super) const ENCODE_SET: AsciiSet = CONTROLS
     .add(b'/')
     .add(b'\\')
     .add(b'<')
@@ -20,37 +23,213 @@ const ENCODE_SET: AsciiSet = CONTROLS
     .add(b'*')
     .add(b' ');
 
+/// Reference of a git repository a crate can be pinned to with
+/// [`Crate::git_with_reference`](struct.Crate.html#method.git_with_reference).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GitReference {
+    /// Latest commit of a branch, at the time the crate is fetched.
+    Branch(String),
+    /// Commit a tag points to.
+    Tag(String),
+    /// Specific commit, identified by its full or abbreviated hash.
+    Commit(String),
+    #[doc(hidden)]
+    __NonExaustive,
+}
+
+impl GitReference {
+    /// Return the revision git commands have to use to refer to the reference.
+    fn revision(&self) -> String {
+        match self {
+            GitReference::Branch(branch) => format!("refs/heads/{}", branch),
+            GitReference::Tag(tag) => format!("refs/tags/{}", tag),
+            GitReference::Commit(commit) => commit.clone(),
+            GitReference::__NonExaustive => {
+                panic!("do not create __NonExaustive variants manually")
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for GitReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GitReference::Branch(branch) => write!(f, "branch {}", branch),
+            GitReference::Tag(tag) => write!(f, "tag {}", tag),
+            GitReference::Commit(commit) => write!(f, "commit {}", commit),
+            GitReference::__NonExaustive => {
+                panic!("do not create __NonExaustive variants manually")
+            }
+        }
+    }
+}
+
 pub(super) struct GitRepo {
     url: String,
+    reference: Option<GitReference>,
+    shallow: bool,
+    submodules: bool,
+    credentials: Option<GitCredentials>,
+    subdir: Option<String>,
 }
 
 impl GitRepo {
-    pub(super) fn new(url: &str) -> Self {
-        Self { url: url.into() }
+    pub(super) fn new(url: &str, reference: Option<GitReference>, shallow: bool) -> Self {
+        Self {
+            url: url.into(),
+            reference,
+            shallow,
+            submodules: true,
+            credentials: None,
+            subdir: None,
+        }
     }
 
-    pub(super) async fn git_commit(&self, workspace: &Workspace) -> Option<String> {
-        let res = Command::new(workspace, "git")
-            .args(&["rev-parse", "HEAD"])
-            .cd(&self.cached_path(workspace))
-            .run_capture()
-            .await;
+    /// Build only the provided directory of the repository, relative to its root.
+    pub(super) fn set_subdir(&mut self, subdir: &str) {
+        let subdir = subdir.replace('\\', "/").trim_matches('/').to_string();
+        self.subdir = if subdir.is_empty() {
+            None
+        } else {
+            Some(subdir)
+        };
+    }
 
-        match res {
-            Ok(out) => {
-                if let Some(shaline) = out.stdout_lines().get(0) {
-                    if !shaline.is_empty() {
-                        return Some(shaline.to_string());
-                    }
-                }
-                warn!("bad output from `git rev-parse HEAD`");
+    /// Return the path inside the repository of a file of the crate.
+    fn path_in_repo(&self, path: &str) -> String {
+        match &self.subdir {
+            Some(subdir) => format!("{}/{}", subdir, path),
+            None => path.to_string(),
+        }
+    }
+
+    pub(super) fn set_submodules(&mut self, enable: bool) {
+        self.submodules = enable;
+    }
+
+    pub(super) fn set_credentials(&mut self, credentials: GitCredentials) {
+        self.credentials = Some(credentials);
+    }
+
+    /// Return the credentials of the crate, falling back to the ones of the workspace.
+    fn credentials<'a>(&'a self, workspace: &'a Workspace) -> &'a GitCredentials {
+        self.credentials
+            .as_ref()
+            .unwrap_or_else(|| workspace.git_credentials())
+    }
+
+    /// Create a git command authenticated with the credentials of the crate.
+    fn git_command<'w, 'pl>(&self, workspace: &'w Workspace) -> Command<'w, 'pl> {
+        self.credentials(workspace)
+            .configure(workspace, Command::new(workspace, "git"))
+    }
+
+    pub(super) async fn git_commit(&self, workspace: &Workspace) -> Option<String> {
+        let _lock = match self.lock(workspace, true).await {
+            Ok(lock) => lock,
+            Err(e) => {
+                warn!("unable to lock the cached repository {}: {}", self.url, e);
+                return None;
+            }
+        };
+        match self.resolve(workspace) {
+            Ok(Some(sha)) => Some(sha),
+            Ok(None) => {
+                warn!(
+                    "unable to capture sha for {}: the revision doesn't exist",
+                    self
+                );
+                None
             }
             Err(e) => {
-                warn!("unable to capture sha for {}: {}", self.url, e);
+                warn!("unable to capture sha for {}: {}", self, e);
+                None
             }
         }
+    }
+
+    /// Return the revision of the cached repository the crate is built from.
+    fn revision(&self) -> String {
+        match &self.reference {
+            Some(reference) => reference.revision(),
+            None => "HEAD".into(),
+        }
+    }
+
+    /// Refspecs of the references fetched into the cached repository. Shallow clones of a branch
+    /// or a tag only fetch that reference, while full clones fetch all the branches.
+    fn refspecs(&self, depth: Depth) -> Vec<String> {
+        let refspec = match (depth, &self.reference) {
+            (Depth::Latest, Some(GitReference::Branch(branch))) => {
+                format!("+refs/heads/{0}:refs/heads/{0}", branch)
+            }
+            (Depth::Latest, Some(GitReference::Tag(tag))) => {
+                format!("+refs/tags/{0}:refs/tags/{0}", tag)
+            }
+            _ => format!("+{}", BRANCHES_REFSPEC),
+        };
+        vec![refspec]
+    }
 
-        None
+    /// Resolve the revision to the hash of the commit it points to in the cached repository,
+    /// returning `None` if it doesn't exist. The repository must be already locked.
+    fn resolve(&self, workspace: &Workspace) -> Result<Option<String>, Error> {
+        git::resolve(&self.cached_path(workspace), &self.revision())
+    }
+
+    /// Make sure the reference the crate is pinned to is available in the cached repository,
+    /// fetching commits that aren't reachable from any branch or tag. The repository must be
+    /// already locked for writing.
+    async fn fetch_reference(&self, workspace: &Workspace) -> Result<(), Error> {
+        let reference = match &self.reference {
+            Some(reference) => reference,
+            None => return Ok(()),
+        };
+        if self.resolve(workspace)?.is_some() {
+            return Ok(());
+        }
+
+        if let GitReference::Commit(commit) = reference {
+            // Historical commits are not in shallow clones, which are upgraded to full clones.
+            let path = self.cached_path(workspace);
+            let credentials = self.credentials(workspace);
+            if git::is_shallow(&path) {
+                info!("fetching the full history of repository {}", self.url);
+                let refspecs = self.refspecs(Depth::Full);
+                git::fetch(
+                    workspace,
+                    &self.url,
+                    credentials,
+                    &path,
+                    refspecs,
+                    Depth::Full,
+                )
+                .await?;
+                if self.resolve(workspace)?.is_some() {
+                    return Ok(());
+                }
+            }
+
+            info!("fetching {} of repository {}", reference, self.url);
+            let refspecs = vec![commit.clone()];
+            git::fetch(
+                workspace,
+                &self.url,
+                credentials,
+                &path,
+                refspecs,
+                Depth::Full,
+            )
+            .await?;
+            if self.resolve(workspace)?.is_some() {
+                return Ok(());
+            }
+        }
+        Err(GitError::RevisionNotFound {
+            url: self.url.clone(),
+            revision: reference.to_string(),
+        }
+        .into())
     }
 
     fn cached_path(&self, workspace: &Workspace) -> PathBuf {
@@ -60,70 +239,256 @@ impl GitRepo {
             .join(percent_encode(self.url.as_bytes(), &ENCODE_SET).to_string())
     }
 
-    fn suppress_password_prompt_args(&self, workspace: &Workspace) -> Vec<String> {
-        // The first `-c credential.helper=` clears the list of existing helpers
-        vec![
-            "-c".into(),
-            "credential.helper=".into(),
-            "-c".into(),
-            format!(
-                "credential.helper={}",
-                crate::tools::GIT_CREDENTIAL_NULL
-                    .binary_path(workspace)
-                    .to_str()
-                    .unwrap()
-                    .replace('\\', "/")
-            ),
-        ]
+    /// List the submodules of the revision. The repository must be already locked.
+    fn list_submodules(&self, workspace: &Workspace) -> Result<Vec<git::Submodule>, Error> {
+        git::submodules(&self.cached_path(workspace), &self.url, &self.revision())
+    }
+
+    /// Return the repository of a submodule, pinned to the commit the revision points to and
+    /// fetched with the same credentials.
+    fn submodule(&self, submodule: &git::Submodule) -> GitRepo {
+        GitRepo {
+            url: submodule.url.clone(),
+            reference: Some(GitReference::Commit(submodule.commit.clone())),
+            shallow: self.shallow,
+            submodules: true,
+            credentials: self.credentials.clone(),
+            subdir: None,
+        }
+    }
+
+    /// Fetch the submodules of the revision recursively. Each submodule is stored in its own
+    /// cached repository, as submodules can't be stored in the cached bare repository. The
+    /// repository must be already locked for writing.
+    async fn fetch_submodules(&self, workspace: &Workspace) -> Result<(), Error> {
+        for submodule in self.list_submodules(workspace)? {
+            info!("fetching submodule {} of {}", submodule.path, self);
+            self.submodule(&submodule)
+                .fetch(workspace)
+                .await
+                .with_context(|_| {
+                    format!(
+                        "failed to fetch the submodule {} of {}",
+                        submodule.path, self.url
+                    )
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Download the files of the revision stored in Git LFS into the cached repository, if its
+    /// `.gitattributes` uses LFS. The repository must be already locked for writing.
+    async fn fetch_lfs(&self, workspace: &Workspace) -> Result<(), Error> {
+        let attributes = git::read_file(
+            &self.cached_path(workspace),
+            &self.revision(),
+            ".gitattributes",
+        )?;
+        let uses_lfs = match attributes {
+            Some(attributes) => String::from_utf8_lossy(&attributes)
+                .lines()
+                .any(|line| line.contains("filter=lfs")),
+            None => false,
+        };
+        if !uses_lfs {
+            return Ok(());
+        } else if !workspace.git_lfs() {
+            warn!(
+                "{} uses Git LFS, but it's not enabled in the workspace: pointer files will be \
+                 extracted instead of the files stored in it",
+                self
+            );
+            return Ok(());
+        }
+
+        // gitoxide doesn't support Git LFS, so git-lfs is invoked through the `git` binary of the
+        // host, which must be able to find it in the `PATH`. git-lfs reads the remote from the
+        // configuration of the cached repository and authenticates with the crate's credentials.
+        let mut paths = vec![workspace.cargo_home().join("bin")];
+        if let Some(path) = std::env::var_os("PATH") {
+            paths.extend(std::env::split_paths(&path));
+        }
+        info!("fetching the Git LFS files of {}", self);
+        let result = self
+            .git_command(workspace)
+            .args(&["lfs", "fetch", "origin"])
+            .args(&[self.revision()])
+            .env("PATH", std::env::join_paths(paths)?)
+            .cd(&self.cached_path(workspace))
+            .run()
+            .await;
+        match result {
+            Err(err) if is_not_found(&err) => {
+                warn!(
+                    "{} uses Git LFS, but git is not installed: pointer files will be extracted \
+                     instead of the files stored in it",
+                    self
+                );
+                Ok(())
+            }
+            result => Ok(result
+                .with_context(|_| format!("failed to fetch the Git LFS files of {}", self.url))?),
+        }
+    }
+
+    /// Export the contents of the submodules fetched by `fetch_submodules` into the source
+    /// directory. The repository must be already locked.
+    async fn copy_submodules_to(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error> {
+        if !self.submodules {
+            return Ok(());
+        }
+
+        for submodule in self.list_submodules(workspace)? {
+            // Only the submodules inside the subdirectory being built are exported.
+            let dest_path = match &self.subdir {
+                Some(subdir) => match submodule.path.strip_prefix(&format!("{}/", subdir)) {
+                    Some(relative) => relative.to_string(),
+                    None => continue,
+                },
+                None => submodule.path.clone(),
+            };
+            self.submodule(&submodule)
+                .copy_source_to(workspace, &dest.join(dest_path))
+                .await
+                .with_context(|_| format!("failed to export the submodule {}", submodule.path))?;
+        }
+        Ok(())
+    }
+
+    /// Lock the cached repository. Builds reading from it take a shared lock, while updating it
+    /// requires an exclusive lock, so that a fetch never races with a checkout of the same
+    /// repository happening in another build.
+    async fn lock(&self, workspace: &Workspace, shared: bool) -> Result<FileLock, Error> {
+        let path = crate::cache_manager::entry_lock_path(&self.cached_path(workspace));
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let msg = format!("use the cached repository {}", self.url);
+        crate::utils::lock_file(workspace.runtime(), &path, shared, &msg).await
+    }
+}
+
+/// Check whether a command failed because its binary is not installed.
+fn is_not_found(err: &Error) -> bool {
+    err.iter_chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .map_or(false, |err| err.kind() == std::io::ErrorKind::NotFound)
+    })
+}
+
+/// Maximum size of the pointer files Git LFS stores in the repository instead of the files.
+const MAX_LFS_POINTER_SIZE: u64 = 1024;
+
+/// Replace the Git LFS pointer files in the source directory with the files they point to, which
+/// are stored in `objects` by `git lfs fetch`.
+fn replace_lfs_pointers(source: &Path, objects: &Path) -> Result<(), Error> {
+    for entry in walkdir::WalkDir::new(source) {
+        let entry = entry?;
+        if !entry.file_type().is_file() || entry.metadata()?.len() > MAX_LFS_POINTER_SIZE {
+            continue;
+        }
+        let content = match std::fs::read_to_string(entry.path()) {
+            Ok(content) => content,
+            // Binary files are never pointers.
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let oid = match parse_lfs_pointer(&content) {
+            Some(oid) => oid,
+            None => continue,
+        };
+        // Objects are stored in `{oid[0..2]}/{oid[2..4]}/{oid}`.
+        let object = objects.join(&oid[0..2]).join(&oid[2..4]).join(oid);
+        if object.is_file() {
+            std::fs::copy(&object, entry.path()).with_context(|_| {
+                format!("failed to extract the LFS file {}", entry.path().display())
+            })?;
+        } else {
+            warn!(
+                "the LFS file {} was not fetched, keeping its pointer",
+                entry.path().display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Parse a Git LFS pointer file, returning the SHA-256 object ID of the file it points to.
+fn parse_lfs_pointer(content: &str) -> Option<&str> {
+    let mut lines = content.lines();
+    if lines.next()? != "version https://git-lfs.github.com/spec/v1" {
+        return None;
     }
+    lines
+        .filter_map(|line| line.strip_prefix("oid sha256:"))
+        .find(|oid| oid.len() == 64 && oid.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Check whether a path inside the repository is relative and doesn't escape it.
+fn is_relative_path(path: &str) -> bool {
+    Path::new(path)
+        .components()
+        .all(|component| matches!(component, std::path::Component::Normal(_)))
 }
 
 #[async_trait]
 impl CrateTrait for GitRepo {
-    async fn fetch(&self, workspace: &Workspace) -> Result<(), Error> {
-        // The credential helper that suppresses the password prompt shows this message when a
-        // repository requires authentication:
-        //
-        //    fata: credential helper '{path}' told us to quit
-        //
-        let mut private_repository = false;
-        let mut detect_private_repositories = |line: &str, _actions: &mut ProcessLinesActions| {
-            if line.starts_with("fatal: credential helper") && line.ends_with("told us to quit") {
-                private_repository = true;
-            }
-        };
+    fn cache_entry(&self, workspace: &Workspace) -> Option<PathBuf> {
+        Some(self.cached_path(workspace))
+    }
 
+    async fn fetch(&self, workspace: &Workspace) -> Result<(), Error> {
+        let _lock = self.lock(workspace, false).await?;
         let path = self.cached_path(workspace);
-        let res = if path.join("HEAD").is_file() {
+        let credentials = self.credentials(workspace);
+        if path.join("HEAD").is_file() {
             info!("updating cached repository {}", self.url);
 
-            Command::new(workspace, "git")
-                .args(&self.suppress_password_prompt_args(workspace))
-                .args(&["-c", "remote.origin.fetch=refs/heads/*:refs/heads/*"])
-                .args(&["fetch", "origin", "--force", "--prune"])
-                .cd(&path)
-                .process_lines(&mut detect_private_repositories)
-                .run()
-                .await
-                .with_context(|_| format!("failed to update {}", self.url))
+            // Full clones are never made shallow again, as the history might be needed by other
+            // crates using the same repository, while shallow clones are upgraded when a full
+            // clone is requested.
+            let depth = if self.shallow && git::is_shallow(&path) {
+                Depth::Latest
+            } else {
+                Depth::Full
+            };
+            let refspecs = self.refspecs(depth);
+            git::fetch(workspace, &self.url, credentials, &path, refspecs, depth).await?;
         } else {
             info!("cloning repository {}", self.url);
 
-            Command::new(workspace, "git")
-                .args(&self.suppress_password_prompt_args(workspace))
-                .args(&["clone", "--bare", &self.url])
-                .args(&[&path])
-                .process_lines(&mut detect_private_repositories)
-                .run()
+            // The repository is cloned to a temporary location first, to avoid leaving a partial
+            // clone in the cache if the clone fails or the process crashes.
+            let temp = crate::utils::temp_path(&path);
+            let _entry = workspace
+                .journal()
+                .begin(Operation::Fetch { path: temp.clone() });
+            let depth = if self.shallow {
+                Depth::Latest
+            } else {
+                Depth::Full
+            };
+            let refspecs = self.refspecs(depth);
+            git::clone(workspace, &self.url, credentials, &temp, refspecs, depth)
                 .await
-                .with_context(|_| format!("failed to clone {}", self.url))
-        };
+                .and_then(|()| {
+                    std::fs::rename(&temp, &path)
+                        .with_context(|_| format!("failed to store the clone of {}", self.url))
+                        .map_err(Error::from)
+                })
+                .map_err(|err| {
+                    let _ = remove_dir_all::remove_dir_all(&temp);
+                    err
+                })?;
+        }
 
-        if private_repository && res.is_err() {
-            Err(PrepareError::PrivateGitRepository.into())
-        } else {
-            Ok(res?)
+        self.fetch_reference(workspace).await?;
+        if self.submodules {
+            self.fetch_submodules(workspace).await?;
         }
+        self.fetch_lfs(workspace).await
     }
 
     fn purge_from_cache(&self, workspace: &Workspace) -> Result<(), Error> {
@@ -136,19 +501,173 @@ impl CrateTrait for GitRepo {
     }
 
     async fn copy_source_to(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error> {
-        Command::new(workspace, "git")
-            .args(&["clone"])
-            .args(&[self.cached_path(workspace).as_path(), dest])
-            .run()
-            .await
-            .with_context(|_| format!("failed to checkout {}", self.url))?;
+        // Checkouts are materialized from the cached bare repository instead of cloning it, so
+        // concurrent builds of the same repository don't copy its objects or share a working copy.
+        let _lock = self.lock(workspace, true).await?;
 
-        Ok(())
+        // Subdirectories are checked out from their tree, which places them at the root.
+        let tree = match &self.subdir {
+            Some(subdir) => {
+                if !is_relative_path(subdir) {
+                    failure::bail!("invalid subdirectory of {}: {}", self.url, subdir);
+                }
+                format!("{}:{}", self.revision(), subdir)
+            }
+            None => self.revision(),
+        };
+        info!("checking out {} into {}", self, dest.display());
+        let result = git::checkout(workspace, &self.cached_path(workspace), &tree, dest).await;
+
+        let result = match result {
+            Ok(()) => self.copy_submodules_to(workspace, dest).await,
+            Err(err) => Err(err),
+        };
+        let lfs_objects = self.cached_path(workspace).join("lfs").join("objects");
+        let result = match result {
+            Ok(()) if workspace.git_lfs() && lfs_objects.is_dir() => {
+                let dest = dest.to_path_buf();
+                crate::utils::spawn_blocking(workspace.runtime(), move || {
+                    replace_lfs_pointers(&dest, &lfs_objects)
+                })
+                .await?
+            }
+            result => result,
+        };
+        if let Err(err) = result {
+            let _ = remove_dir_all::remove_dir_all(dest);
+            Err(err
+                .context(format!("failed to checkout {}", self.url))
+                .into())
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn read_manifest(&self, workspace: &Workspace) -> Result<String, Error> {
+        let _lock = self.lock(workspace, true).await?;
+        let manifest = git::read_file(
+            &self.cached_path(workspace),
+            &self.revision(),
+            &self.path_in_repo("Cargo.toml"),
+        )?
+        .ok_or(PrepareError::MissingCargoToml)?;
+
+        Ok(String::from_utf8(manifest).with_context(|_| PrepareError::MissingCargoToml)?)
     }
 }
 
 impl std::fmt::Display for GitRepo {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "git repo {}", self.url)
+        write!(f, "git repo {}", self.url)?;
+        if let Some(subdir) = &self.subdir {
+            write!(f, " in {}", subdir)?;
+        }
+        if let Some(reference) = &self.reference {
+            write!(f, " at {}", reference)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_not_found, is_relative_path, parse_lfs_pointer, GitReference, GitRepo};
+    use crate::git::{Depth, Submodule};
+    use crate::GitCredentials;
+    use failure::Error;
+
+    #[test]
+    fn test_refspecs() {
+        let url = "https://example.com/repo.git";
+        let branches = vec!["+refs/heads/*:refs/heads/*"];
+        assert_eq!(
+            GitRepo::new(url, None, true).refspecs(Depth::Latest),
+            branches
+        );
+
+        let branch = GitRepo::new(url, Some(GitReference::Branch("dev".into())), true);
+        assert_eq!(
+            branch.refspecs(Depth::Latest),
+            vec!["+refs/heads/dev:refs/heads/dev"]
+        );
+        // Full clones always fetch all the branches.
+        assert_eq!(branch.refspecs(Depth::Full), branches);
+
+        let tag = GitRepo::new(url, Some(GitReference::Tag("v1.0".into())), true);
+        assert_eq!(
+            tag.refspecs(Depth::Latest),
+            vec!["+refs/tags/v1.0:refs/tags/v1.0"]
+        );
+    }
+
+    #[test]
+    fn test_submodule() {
+        let mut repo = GitRepo::new("https://example.com/repo.git", None, true);
+        repo.set_subdir("crates/foo");
+        repo.set_submodules(false);
+        repo.set_credentials(GitCredentials::Token {
+            username: "user".into(),
+            token: "secret".into(),
+        });
+
+        let commit = "0123456789abcdef0123456789abcdef01234567";
+        let submodule = repo.submodule(&Submodule {
+            path: "vendor/zlib".into(),
+            url: "https://example.com/zlib.git".into(),
+            commit: commit.into(),
+        });
+        assert_eq!(submodule.url, "https://example.com/zlib.git");
+        assert_eq!(
+            submodule.reference,
+            Some(GitReference::Commit(commit.into()))
+        );
+        assert!(submodule.shallow);
+        // Nested submodules are always fetched, and the whole submodule is built.
+        assert!(submodule.submodules);
+        assert!(submodule.subdir.is_none());
+        assert!(submodule.credentials.is_some());
+    }
+
+    #[test]
+    fn test_is_not_found() {
+        let missing = std::io::Error::new(std::io::ErrorKind::NotFound, "no such file");
+        assert!(is_not_found(&Error::from(missing).context("git").into()));
+        let denied = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "denied");
+        assert!(!is_not_found(&denied.into()));
+        assert!(!is_not_found(&failure::err_msg("exit status: 1")));
+    }
+
+    #[test]
+    fn test_parse_lfs_pointer() {
+        let oid = "4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393";
+        assert_eq!(
+            parse_lfs_pointer(&format!(
+                "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 12345\n",
+                oid
+            )),
+            Some(oid)
+        );
+        assert_eq!(parse_lfs_pointer("fn main() {}\n"), None);
+        assert_eq!(
+            parse_lfs_pointer("version https://git-lfs.github.com/spec/v1\noid sha256:abc\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_subdir() {
+        let mut repo = GitRepo::new("https://example.com/repo.git", None, false);
+        repo.set_subdir("/crates/foo/");
+        assert_eq!(repo.path_in_repo("Cargo.toml"), "crates/foo/Cargo.toml");
+        assert_eq!(
+            repo.to_string(),
+            "git repo https://example.com/repo.git in crates/foo"
+        );
+        repo.set_subdir("");
+        assert_eq!(repo.path_in_repo("Cargo.toml"), "Cargo.toml");
+
+        assert!(is_relative_path("crates/foo"));
+        assert!(!is_relative_path("crates/../../foo"));
+        assert!(!is_relative_path("./crates"));
     }
 }
@@ -0,0 +1,165 @@
+use super::git::ENCODE_SET;
+use super::unpack::{unpack_without_first_dir, READ_BUFFER_SIZE};
+use super::CrateTrait;
+use crate::cmd::Command;
+use crate::journal::Operation;
+use crate::prepare::PrepareError;
+use crate::utils::FileLock;
+use crate::Workspace;
+use async_trait::async_trait;
+use failure::{Error, ResultExt};
+use log::info;
+use percent_encoding::percent_encode;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use tar::Archive;
+
+/// Revision crates are built from: the latest commit of the `default` branch, which is the one
+/// `hg clone` checks out.
+static REVISION: &str = "default";
+
+pub(super) struct HgRepo {
+    url: String,
+}
+
+impl HgRepo {
+    pub(super) fn new(url: &str) -> Self {
+        Self { url: url.into() }
+    }
+
+    /// Create a Mercurial command ignoring the user's configuration that could change its output,
+    /// and failing instead of prompting for input.
+    fn hg_command<'w, 'pl>(&self, workspace: &'w Workspace) -> Command<'w, 'pl> {
+        Command::new(workspace, "hg")
+            .args(&["--noninteractive"])
+            .env("HGPLAIN", "1")
+    }
+
+    fn cached_path(&self, workspace: &Workspace) -> PathBuf {
+        workspace
+            .cache_dir()
+            .join("hg-repos")
+            .join(percent_encode(self.url.as_bytes(), &ENCODE_SET).to_string())
+    }
+
+    /// Lock the cached repository, taking a shared lock to read from it and an exclusive lock to
+    /// update it.
+    async fn lock(&self, workspace: &Workspace, shared: bool) -> Result<FileLock, Error> {
+        let path = crate::cache_manager::entry_lock_path(&self.cached_path(workspace));
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let msg = format!("use the cached repository {}", self.url);
+        crate::utils::lock_file(workspace.runtime(), &path, shared, &msg).await
+    }
+}
+
+#[async_trait]
+impl CrateTrait for HgRepo {
+    fn cache_entry(&self, workspace: &Workspace) -> Option<PathBuf> {
+        Some(self.cached_path(workspace))
+    }
+
+    async fn fetch(&self, workspace: &Workspace) -> Result<(), Error> {
+        let _lock = self.lock(workspace, false).await?;
+        let path = self.cached_path(workspace);
+        if path.join(".hg").is_dir() {
+            info!("updating cached repository {}", self.url);
+            self.hg_command(workspace)
+                .args(&["pull"])
+                .cd(&path)
+                .run()
+                .await
+                .with_context(|_| format!("failed to update {}", self.url))?;
+        } else {
+            info!("cloning repository {}", self.url);
+
+            // The repository is cloned without a working copy, as the source code is exported
+            // from it with `hg archive`. It's cloned to a temporary location first, to avoid
+            // leaving a partial clone in the cache if the clone fails.
+            let temp = crate::utils::temp_path(&path);
+            let _entry = workspace
+                .journal()
+                .begin(Operation::Fetch { path: temp.clone() });
+            self.hg_command(workspace)
+                .args(&["clone", "--noupdate", self.url.as_str()])
+                .args(&[&temp])
+                .run()
+                .await
+                .with_context(|_| format!("failed to clone {}", self.url))
+                .and_then(|()| {
+                    std::fs::rename(&temp, &path)
+                        .with_context(|_| format!("failed to store the clone of {}", self.url))
+                })
+                .map_err(|err| {
+                    let _ = remove_dir_all::remove_dir_all(&temp);
+                    err
+                })?;
+        }
+        Ok(())
+    }
+
+    async fn purge_from_cache(&self, workspace: &Workspace) -> Result<(), Error> {
+        let path = self.cached_path(workspace);
+        if path.exists() {
+            remove_dir_all::remove_dir_all(&path)?;
+        }
+        Ok(())
+    }
+
+    async fn copy_source_to(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error> {
+        let _lock = self.lock(workspace, true).await?;
+        let temp = tempfile::tempdir()?;
+        let archive = temp.path().join("source.tar");
+
+        // `ui.archivemeta` is disabled to avoid adding `.hg_archival.txt` to the source code.
+        info!("exporting {} into {}", self, dest.display());
+        self.hg_command(workspace)
+            .args(&["--config", "ui.archivemeta=False"])
+            .args(&[
+                "archive", "--type", "tar", "--prefix", "source", "-r", REVISION,
+            ])
+            .args(&[&archive])
+            .cd(&self.cached_path(workspace))
+            .run()
+            .await
+            .with_context(|_| format!("failed to checkout {}", self.url))?;
+
+        let parallel = workspace.parallel_extraction();
+        let dest_owned = dest.to_path_buf();
+        let result = crate::utils::spawn_blocking(workspace.runtime(), move || {
+            let file = BufReader::with_capacity(READ_BUFFER_SIZE, std::fs::File::open(archive)?);
+            unpack_without_first_dir(&mut Archive::new(file), &dest_owned, parallel)
+        })
+        .await?;
+        if let Err(err) = result {
+            let _ = remove_dir_all::remove_dir_all(dest);
+            Err(err
+                .context(format!("failed to checkout {}", self.url))
+                .into())
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn read_manifest(&self, workspace: &Workspace) -> Result<String, Error> {
+        let _lock = self.lock(workspace, true).await?;
+        let out = self
+            .hg_command(workspace)
+            .args(&["cat", "-r", REVISION, "Cargo.toml"])
+            .cd(&self.cached_path(workspace))
+            .log_output(false)
+            .run_capture()
+            .await
+            .with_context(|_| PrepareError::MissingCargoToml)?;
+
+        Ok(out.stdout_lines().join("\n"))
+    }
+}
+
+impl std::fmt::Display for HgRepo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "mercurial repo {}", self.url)
+    }
+}
@@ -1,18 +1,163 @@
 use super::CrateTrait;
+use crate::prepare::PrepareError;
 use crate::Workspace;
 use async_trait::async_trait;
-use failure::Error;
+use failure::{Error, ResultExt};
+use filetime::FileTime;
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use glob::{MatchOptions, Pattern};
+use ignore::WalkBuilder;
 use log::info;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use tokio::{
+    fs::{self, File},
+    io::{self, AsyncWriteExt, BufReader, BufWriter},
+    runtime::Handle,
+};
 use walkdir::WalkDir;
 
+/// Maximum number of files copied at the same time.
+const COPY_CONCURRENCY: usize = 16;
+/// Size of the buffers used while copying a file.
+const COPY_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Options used to match the exclude patterns: `*` and `?` don't match the path separator, so
+/// `src/*.rs` doesn't match the files in the subdirectories of `src`.
+const EXCLUDE_MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// How the files of a local crate are copied.
+#[derive(Clone)]
+pub(super) struct CopyOptions {
+    excludes: Vec<Pattern>,
+    ignore_files: bool,
+    symlinks: bool,
+    mtimes: bool,
+    /// Names of the build output directories that are not copied.
+    skipped_dirs: Vec<String>,
+    /// Whether the build output directories are skipped at any depth, or only at the top level.
+    nested_skipped_dirs: bool,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        CopyOptions {
+            excludes: Vec::new(),
+            ignore_files: false,
+            symlinks: false,
+            mtimes: false,
+            skipped_dirs: vec!["target".into()],
+            nested_skipped_dirs: false,
+        }
+    }
+}
+
+impl CopyOptions {
+    /// Check whether an entry of the crate directory, relative to it, shouldn't be copied.
+    fn skips(&self, path: &Path, depth: usize, is_dir: bool) -> bool {
+        if is_dir && (depth == 1 || (depth > 1 && self.nested_skipped_dirs)) {
+            let name = path.file_name().and_then(|name| name.to_str());
+            if self
+                .skipped_dirs
+                .iter()
+                .any(|dir| Some(dir.as_str()) == name)
+            {
+                info!("ignoring build output directory {}", path.display());
+                return true;
+            }
+        }
+        is_excluded(path, &self.excludes)
+    }
+}
+
+/// Entries of a crate directory to copy, relative to it.
+#[derive(Default)]
+pub(super) struct Listing {
+    pub(super) dirs: Vec<PathBuf>,
+    pub(super) files: Vec<PathBuf>,
+    links: Vec<IntraTreeLink>,
+}
+
+impl Listing {
+    fn push(&mut self, path: PathBuf, is_dir: bool) {
+        if is_dir {
+            self.dirs.push(path);
+        } else {
+            self.files.push(path);
+        }
+    }
+}
+
+/// Symbolic link pointing inside the crate directory, recreated instead of being followed.
+struct IntraTreeLink {
+    path: PathBuf,
+    target: PathBuf,
+    /// Path the link points to, relative to the crate directory.
+    resolved: PathBuf,
+}
+
 pub(super) struct Local {
     path: PathBuf,
+    options: CopyOptions,
 }
 
 impl Local {
     pub(super) fn new(path: &Path) -> Self {
-        Local { path: path.into() }
+        Local {
+            path: path.into(),
+            options: CopyOptions::default(),
+        }
+    }
+
+    pub(super) fn with_excludes(path: &Path, excludes: &[&str]) -> Result<Self, Error> {
+        let excludes = excludes
+            .iter()
+            .map(|pattern| {
+                // `dir/**` excludes the whole directory, which is skipped without walking it.
+                let pattern = pattern.trim_start_matches("./");
+                let pattern = pattern.strip_suffix("/**").unwrap_or(pattern);
+                Pattern::new(pattern)
+                    .with_context(|_| format!("invalid exclude pattern: {}", pattern))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Local {
+            path: path.into(),
+            options: CopyOptions {
+                excludes,
+                ..CopyOptions::default()
+            },
+        })
+    }
+
+    pub(super) fn set_ignore_files(&mut self, enable: bool) {
+        self.options.ignore_files = enable;
+    }
+
+    pub(super) fn set_preserve_symlinks(&mut self, enable: bool) {
+        self.options.symlinks = enable;
+    }
+
+    pub(super) fn set_preserve_mtimes(&mut self, enable: bool) {
+        self.options.mtimes = enable;
+    }
+
+    pub(super) fn set_skipped_dirs(&mut self, names: &[&str]) {
+        self.options.skipped_dirs = names.iter().map(|name| name.to_string()).collect();
+    }
+
+    pub(super) fn set_nested_skipped_dirs(&mut self, enable: bool) {
+        self.options.nested_skipped_dirs = enable;
+    }
+
+    pub(super) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(super) fn options(&self) -> &CopyOptions {
+        &self.options
     }
 }
 
@@ -28,16 +173,22 @@ impl CrateTrait for Local {
         Ok(())
     }
 
-    async fn copy_source_to(&self, _workspace: &Workspace, dest: &Path) -> Result<(), Error> {
+    async fn copy_source_to(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error> {
         info!(
             "copying local crate from {} to {}",
             self.path.display(),
             dest.display()
         );
-        copy_dir(&self.path, dest).await?;
+        copy_dir(workspace.runtime(), &self.path, dest, &self.options).await?;
 
         Ok(())
     }
+
+    async fn read_manifest(&self, _workspace: &Workspace) -> Result<String, Error> {
+        Ok(fs::read_to_string(self.path.join("Cargo.toml"))
+            .await
+            .with_context(|_| PrepareError::MissingCargoToml)?)
+    }
 }
 
 impl std::fmt::Display for Local {
@@ -46,32 +197,243 @@ impl std::fmt::Display for Local {
     }
 }
 
-async fn copy_dir(src: &Path, dest: &Path) -> Result<(), Error> {
+async fn copy_dir(
+    runtime: Option<&Handle>,
+    src: &Path,
+    dest: &Path,
+    options: &CopyOptions,
+) -> Result<(), Error> {
     let src = crate::utils::normalize_path(src);
     let dest = crate::utils::normalize_path(dest);
 
+    // Walking the directory is blocking, so it's done outside of the async runtime.
+    let listing = {
+        let src = src.clone();
+        let options = options.clone();
+        crate::utils::spawn_blocking(runtime, move || list_dir(&src, &options)).await??
+    };
+
+    // Deep source trees can exceed the path length limit on Windows, so the copy uses
+    // extended-length paths.
+    let (src, dest) = (
+        crate::utils::long_path(&src),
+        crate::utils::long_path(&dest),
+    );
+
+    for dir in &listing.dirs {
+        fs::create_dir_all(dest.join(dir)).await?;
+    }
+
+    let mtimes = options.mtimes;
+    stream::iter(listing.files)
+        .map(|path| copy_file(src.join(&path), dest.join(&path), mtimes))
+        .buffer_unordered(COPY_CONCURRENCY)
+        .try_collect::<()>()
+        .await?;
+
+    // Links are created once all the files are copied, as on Windows the kind of link depends on
+    // whether the target is a directory.
+    for link in &listing.links {
+        crate::native::symlink(
+            &link.target,
+            &dest.join(&link.resolved),
+            &dest.join(&link.path),
+        )?;
+    }
+    Ok(())
+}
+
+/// List the directories and the files inside `src`, relative to it, skipping the ones that are
+/// not copied. `src` must be canonicalized.
+pub(super) fn list_dir(src: &Path, options: &CopyOptions) -> Result<Listing, Error> {
+    if options.ignore_files {
+        return list_dir_with_ignore_files(src, options);
+    }
+    let mut listing = Listing::default();
+
+    // Links are not followed by the walker when they're preserved, as a link pointing to one of
+    // its parent directories would be reported as a loop.
     let src_components = src.components().count();
-    let mut entries = WalkDir::new(&src).follow_links(true).into_iter();
+    let mut entries = WalkDir::new(src)
+        .follow_links(!options.symlinks)
+        .into_iter();
     while let Some(entry) = entries.next() {
         let entry = entry?;
+        let path = relative_path(entry.path(), src_components);
 
-        let mut components = entry.path().components();
-        for _ in 0..src_components {
-            components.next();
+        let is_link = entry.depth() > 0 && entry.path_is_symlink();
+        let is_dir = entry.file_type().is_dir() || (is_link && entry.path().is_dir());
+        if options.skips(&path, entry.depth(), is_dir) {
+            if is_dir {
+                entries.skip_current_dir();
+            }
+            continue;
         }
-        let path = components.as_path();
 
-        if entry.file_type().is_dir() {
-            // don't copy /target directory
-            if entry.file_name() == "target" && entry.depth() == 1 {
-                info!("ignoring top-level target directory {}", path.display());
+        if options.symlinks && is_link {
+            push_symlink(&mut listing, src, entry.path(), path, options)?;
+        } else {
+            listing.push(path, is_dir);
+        }
+    }
+
+    Ok(listing)
+}
+
+/// List the directories and the files inside `src` like [`list_dir`](fn.list_dir.html), also
+/// skipping the ones ignored by `.gitignore` and `.ignore` files, including the ones in the
+/// parent directories of `src` and `.git/info/exclude`. The user's global git excludes are not
+/// used, as they depend on the host.
+fn list_dir_with_ignore_files(src: &Path, options: &CopyOptions) -> Result<Listing, Error> {
+    let mut listing = Listing::default();
+
+    let src_components = src.components().count();
+    let entries = {
+        let options = options.clone();
+        WalkBuilder::new(src)
+            .standard_filters(false)
+            .git_ignore(true)
+            .git_exclude(true)
+            .ignore(true)
+            .parents(true)
+            .require_git(false)
+            .follow_links(!options.symlinks)
+            .filter_entry(move |entry| {
+                let is_link = entry.depth() > 0 && entry.path_is_symlink();
+                let is_dir = entry.file_type().map_or(false, |kind| kind.is_dir())
+                    || (is_link && entry.path().is_dir());
+                let path = relative_path(entry.path(), src_components);
+                !options.skips(&path, entry.depth(), is_dir)
+            })
+            .build()
+    };
+    for entry in entries {
+        let entry = entry?;
+        let path = relative_path(entry.path(), src_components);
+
+        if options.symlinks && entry.depth() > 0 && entry.path_is_symlink() {
+            push_symlink(&mut listing, src, entry.path(), path, options)?;
+        } else {
+            listing.push(path, entry.file_type().map_or(false, |kind| kind.is_dir()));
+        }
+    }
+
+    Ok(listing)
+}
+
+/// Record a symbolic link found while walking the crate directory `root` without following the
+/// links: the ones pointing inside the crate are recreated, while the other ones are followed.
+fn push_symlink(
+    listing: &mut Listing,
+    root: &Path,
+    link: &Path,
+    path: PathBuf,
+    options: &CopyOptions,
+) -> Result<(), Error> {
+    if let Some(link) = intra_tree_link(root, link, path.clone()) {
+        listing.links.push(link);
+        return Ok(());
+    }
+
+    let target = link
+        .canonicalize()
+        .with_context(|_| format!("failed to resolve symbolic link {}", link.display()))?;
+    if !target.is_dir() {
+        listing.files.push(path);
+        return Ok(());
+    }
+
+    // The ignore files of directories outside of the crate are not honored.
+    let target_components = target.components().count();
+    let mut entries = WalkDir::new(&target).follow_links(true).into_iter();
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+        let path = match entry.depth() {
+            0 => path.clone(),
+            _ => path.join(relative_path(entry.path(), target_components)),
+        };
+
+        let is_dir = entry.file_type().is_dir();
+        if is_excluded(&path, &options.excludes) {
+            if is_dir {
                 entries.skip_current_dir();
-            } else {
-                std::fs::create_dir_all(dest.join(path))?;
             }
+            continue;
+        }
+        listing.push(path, is_dir);
+    }
+    Ok(())
+}
+
+/// Check whether the symbolic link at `link` (`path` relative to the crate directory `root`)
+/// points inside the crate directory, returning how to recreate it.
+fn intra_tree_link(root: &Path, link: &Path, path: PathBuf) -> Option<IntraTreeLink> {
+    let target = std::fs::read_link(link).ok()?;
+    let resolved = link
+        .canonicalize()
+        .ok()?
+        .strip_prefix(root)
+        .ok()?
+        .to_path_buf();
+
+    // Absolute targets would point back to the original directory once copied, so they're
+    // replaced with relative ones.
+    let target = if target.is_absolute() {
+        let depth = path.components().count().saturating_sub(1);
+        let mut relative: PathBuf = (0..depth).map(|_| Component::ParentDir).collect();
+        relative.push(&resolved);
+        relative
+    } else {
+        target
+    };
+
+    Some(IntraTreeLink {
+        path,
+        target,
+        resolved,
+    })
+}
+
+/// Strip the first `components` components of the path of an entry of the crate directory.
+fn relative_path(path: &Path, components: usize) -> PathBuf {
+    let mut iter = path.components();
+    for _ in 0..components {
+        iter.next();
+    }
+    iter.as_path().to_path_buf()
+}
+
+/// Check whether a path relative to the crate root matches one of the exclude patterns. Patterns
+/// without a `/` are matched against the file name at any depth, like in `.gitignore` files.
+fn is_excluded(path: &Path, excludes: &[Pattern]) -> bool {
+    if excludes.is_empty() || path.as_os_str().is_empty() {
+        return false;
+    }
+    let path = path.to_string_lossy().replace('\\', "/");
+    let name = path.rsplit('/').next().unwrap_or(&path);
+    excludes.iter().any(|pattern| {
+        if pattern.as_str().contains('/') {
+            pattern.matches_with(&path, EXCLUDE_MATCH_OPTIONS)
         } else {
-            std::fs::copy(src.join(path), dest.join(path))?;
+            pattern.matches_with(name, EXCLUDE_MATCH_OPTIONS)
         }
+    })
+}
+
+/// Copy a file along with its permissions, and its modification time if `mtime` is true.
+pub(super) async fn copy_file(src: PathBuf, dest: PathBuf, mtime: bool) -> Result<(), Error> {
+    let input = File::open(&src).await?;
+    let metadata = input.metadata().await?;
+    let output = File::create(&dest).await?;
+
+    let mut reader = BufReader::with_capacity(COPY_BUFFER_SIZE, input);
+    let mut writer = BufWriter::with_capacity(COPY_BUFFER_SIZE, output);
+    io::copy(&mut reader, &mut writer).await?;
+    writer.flush().await?;
+
+    fs::set_permissions(&dest, metadata.permissions()).await?;
+    if mtime {
+        filetime::set_file_mtime(&dest, FileTime::from_last_modification_time(&metadata))?;
     }
     Ok(())
 }
@@ -91,7 +453,7 @@ mod tests {
         fs::write(tmp_src.path().join("foo"), b"Hello world").await?;
         fs::write(tmp_src.path().join("dir").join("bar"), b"Rustwide").await?;
 
-        super::copy_dir(tmp_src.path(), tmp_dest.path()).await?;
+        super::copy_dir(None, tmp_src.path(), tmp_dest.path(), &Default::default()).await?;
 
         assert_eq!(fs::read(tmp_dest.path().join("foo")).await?, b"Hello world");
         assert_eq!(
@@ -113,13 +475,203 @@ mod tests {
         .await?;
         println!("made subdirs and files");
 
-        super::copy_dir(src.path(), dest.path()).await?;
+        super::copy_dir(None, src.path(), dest.path(), &Default::default()).await?;
         println!("copied");
 
         assert!(!dest.path().join("target").exists());
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_copy_skipped_dirs() -> Result<(), Error> {
+        let src = tempfile::tempdir()?;
+        for dir in &["target", "node_modules", "sub/target", "sub/src/target"] {
+            fs::create_dir_all(src.path().join(dir)).await?;
+            fs::write(src.path().join(dir).join("a.out"), b"").await?;
+        }
+
+        // Only the top-level directories are skipped by default.
+        let mut options = super::CopyOptions::default();
+        options.skipped_dirs = vec!["target".into(), "node_modules".into()];
+        let dest = tempfile::tempdir()?;
+        super::copy_dir(None, src.path(), dest.path(), &options).await?;
+        assert!(!dest.path().join("target").exists());
+        assert!(!dest.path().join("node_modules").exists());
+        assert!(dest
+            .path()
+            .join("sub")
+            .join("target")
+            .join("a.out")
+            .exists());
+
+        options.nested_skipped_dirs = true;
+        let dest = tempfile::tempdir()?;
+        super::copy_dir(None, src.path(), dest.path(), &options).await?;
+        assert!(!dest.path().join("node_modules").exists());
+        assert!(!dest.path().join("sub").join("target").exists());
+        assert!(!dest.path().join("sub").join("src").join("target").exists());
+        assert!(dest.path().join("sub").join("src").exists());
+
+        // The target directory is copied if nothing is skipped.
+        options.skipped_dirs.clear();
+        let dest = tempfile::tempdir()?;
+        super::copy_dir(None, src.path(), dest.path(), &options).await?;
+        assert!(dest.path().join("target").join("a.out").exists());
+        assert!(dest
+            .path()
+            .join("sub")
+            .join("target")
+            .join("a.out")
+            .exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_copy_excludes() -> Result<(), Error> {
+        let (src, dest) = (tempfile::tempdir()?, tempfile::tempdir()?);
+        fs::create_dir_all(src.path().join("fixtures").join("huge").join("data")).await?;
+        fs::create_dir_all(src.path().join("src")).await?;
+        fs::write(src.path().join("build.log"), b"").await?;
+        fs::write(src.path().join("src").join("lib.rs"), b"").await?;
+        fs::write(src.path().join("src").join("test.log"), b"").await?;
+        fs::write(src.path().join("fixtures").join("small"), b"").await?;
+        fs::write(
+            src.path()
+                .join("fixtures")
+                .join("huge")
+                .join("data")
+                .join("0"),
+            b"",
+        )
+        .await?;
+
+        let local = super::Local::with_excludes(src.path(), &["*.log", "fixtures/huge/**"])?;
+        super::copy_dir(None, src.path(), dest.path(), local.options()).await?;
+
+        assert!(dest.path().join("src").join("lib.rs").exists());
+        assert!(dest.path().join("fixtures").join("small").exists());
+        assert!(!dest.path().join("build.log").exists());
+        assert!(!dest.path().join("src").join("test.log").exists());
+        assert!(!dest.path().join("fixtures").join("huge").exists());
+
+        assert!(super::Local::with_excludes(src.path(), &["[a-"]).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_copy_ignore_files() -> Result<(), Error> {
+        let (src, dest) = (tempfile::tempdir()?, tempfile::tempdir()?);
+        fs::create_dir_all(src.path().join("crate").join("sub").join("target")).await?;
+        fs::create_dir_all(src.path().join("crate").join("venv")).await?;
+        fs::write(src.path().join(".gitignore"), b"*.swp\n").await?;
+        fs::write(src.path().join("crate").join(".gitignore"), b"target/\n").await?;
+        fs::write(src.path().join("crate").join(".ignore"), b"/venv\n").await?;
+        fs::write(src.path().join("crate").join("Cargo.toml"), b"").await?;
+        fs::write(src.path().join("crate").join("Cargo.toml.swp"), b"").await?;
+        fs::write(src.path().join("crate").join("venv").join("python"), b"").await?;
+        fs::write(
+            src.path()
+                .join("crate")
+                .join("sub")
+                .join("target")
+                .join("a.out"),
+            b"",
+        )
+        .await?;
+
+        let root = src.path().join("crate");
+        let mut local = super::Local::new(&root);
+        super::copy_dir(None, &root, dest.path(), local.options()).await?;
+        assert!(dest.path().join("venv").join("python").exists());
+
+        let dest = tempfile::tempdir()?;
+        local.set_ignore_files(true);
+        super::copy_dir(None, &root, dest.path(), local.options()).await?;
+        assert!(dest.path().join("Cargo.toml").exists());
+        assert!(dest.path().join(".gitignore").exists());
+        assert!(!dest.path().join("Cargo.toml.swp").exists());
+        assert!(!dest.path().join("venv").exists());
+        assert!(!dest.path().join("sub").join("target").exists());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_copy_preserve_symlinks_and_mtimes() -> Result<(), Error> {
+        use filetime::FileTime;
+        use std::os::unix::fs::{symlink, PermissionsExt};
+        use std::path::Path;
+
+        let (src, dest, outside) = (
+            tempfile::tempdir()?,
+            tempfile::tempdir()?,
+            tempfile::tempdir()?,
+        );
+        fs::create_dir_all(src.path().join("scripts")).await?;
+        fs::write(src.path().join("scripts").join("build.sh"), b"#!/bin/sh\n").await?;
+        fs::set_permissions(
+            src.path().join("scripts").join("build.sh"),
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .await?;
+        fs::write(outside.path().join("data"), b"outside").await?;
+        filetime::set_file_mtime(
+            src.path().join("scripts").join("build.sh"),
+            FileTime::from_unix_time(1_000_000_000, 0),
+        )?;
+
+        symlink("scripts/build.sh", src.path().join("relative"))?;
+        symlink(
+            src.path().join("scripts"),
+            src.path().join("scripts").join("absolute"),
+        )?;
+        symlink(outside.path().join("data"), src.path().join("outside"))?;
+        symlink(outside.path(), src.path().join("outside-dir"))?;
+
+        let mut local = super::Local::new(src.path());
+        local.set_preserve_symlinks(true);
+        local.set_preserve_mtimes(true);
+        super::copy_dir(None, src.path(), dest.path(), local.options()).await?;
+
+        let script = dest.path().join("scripts").join("build.sh");
+        let metadata = std::fs::metadata(&script)?;
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+        assert_eq!(
+            FileTime::from_last_modification_time(&metadata),
+            FileTime::from_unix_time(1_000_000_000, 0)
+        );
+
+        let relative = dest.path().join("relative");
+        assert_eq!(
+            std::fs::read_link(&relative)?,
+            Path::new("scripts/build.sh")
+        );
+        let absolute = dest.path().join("scripts").join("absolute");
+        assert_eq!(std::fs::read_link(&absolute)?, Path::new("../scripts"));
+        assert!(absolute.join("build.sh").exists());
+
+        // Links pointing outside of the crate are still followed.
+        let outside = dest.path().join("outside");
+        assert!(!fs::symlink_metadata(&outside)
+            .await?
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read(&outside).await?, b"outside");
+        let outside_dir = dest.path().join("outside-dir");
+        assert!(fs::symlink_metadata(&outside_dir).await?.is_dir());
+        assert_eq!(fs::read(outside_dir.join("data")).await?, b"outside");
+
+        // Links are preserved when honoring the ignore files too.
+        let dest = tempfile::tempdir()?;
+        local.set_ignore_files(true);
+        super::copy_dir(None, src.path(), dest.path(), local.options()).await?;
+        let absolute = dest.path().join("scripts").join("absolute");
+        assert_eq!(std::fs::read_link(&absolute)?, Path::new("../scripts"));
+        assert!(dest.path().join("outside-dir").join("data").exists());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_copy_symlinks() -> Result<(), Error> {
         use std::{os, path::Path};
@@ -127,7 +679,8 @@ mod tests {
         let tmp_src = tempfile::tempdir()?;
         let tmp_dest = tempfile::tempdir()?;
         let assert_copy_err_has_filename = async {
-            match super::copy_dir(tmp_src.path(), tmp_dest.path()).await {
+            match super::copy_dir(None, tmp_src.path(), tmp_dest.path(), &Default::default()).await
+            {
                 Ok(_) => panic!("copy with bad symbolic link did not fail"),
                 Err(err) => assert!(err.downcast::<walkdir::Error>().unwrap().path().is_some()),
             };
@@ -152,7 +705,7 @@ mod tests {
 
         fs::remove_file(&bad_link).await?;
         // make sure it works without that link
-        super::copy_dir(tmp_src.path(), tmp_dest.path()).await?;
+        super::copy_dir(None, tmp_src.path(), tmp_dest.path(), &Default::default()).await?;
 
         // test link to self
         #[cfg(unix)]
@@ -1,25 +1,51 @@
+mod crate_file;
 mod cratesio;
+mod custom;
 mod git;
+mod hg;
 mod local;
+mod registry;
+mod tarball;
+pub(crate) mod unpack;
+mod watch;
 
-use crate::Workspace;
+use crate::prepare::PrepareError;
+use crate::{GitCredentials, ManifestInfo, Workspace};
 use async_trait::async_trait;
-use failure::Error;
+use failure::{Error, ResultExt};
 use log::info;
 use remove_dir_all::remove_dir_all;
-use std::path::Path;
+use semver::VersionReq;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub use self::custom::CrateSource;
+pub use self::git::GitReference;
+pub use self::watch::{CrateWatcher, SourceChanges};
 
 #[async_trait]
 trait CrateTrait: std::fmt::Display {
     async fn fetch(&self, workspace: &Workspace) -> Result<(), Error>;
     async fn purge_from_cache(&self, workspace: &Workspace) -> Result<(), Error>;
     async fn copy_source_to(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error>;
+    async fn read_manifest(&self, workspace: &Workspace) -> Result<String, Error>;
+
+    /// Return the path of the crate's entry in the workspace cache, if it's cached there.
+    fn cache_entry(&self, _workspace: &Workspace) -> Option<PathBuf> {
+        None
+    }
 }
 
 enum CrateType {
     CratesIO(cratesio::CratesIOCrate),
+    CrateFile(crate_file::CrateFile),
+    Custom(custom::CustomCrate),
     Git(git::GitRepo),
+    Hg(hg::HgRepo),
     Local(local::Local),
+    Registry(registry::RegistryCrate),
+    Tarball(tarball::TarballCrate),
 }
 
 /// A Rust crate that can be used with rustwide.
@@ -27,16 +53,261 @@ pub struct Crate(CrateType);
 
 impl Crate {
     /// Load a crate from the [crates.io registry](https://crates.io).
-    pub fn crates_io(name: &str, version: &str) -> Self {
-        Crate(CrateType::CratesIO(cratesio::CratesIOCrate::new(
+    ///
+    /// The name and the version are validated before anything is fetched: names must be accepted
+    /// by crates.io (ASCII letters, digits, `-` and `_`, starting with a letter) and versions must
+    /// be valid semver versions, optionally prefixed with `=`. Invalid specs are rejected with
+    /// [`PrepareError::InvalidCrateName`](enum.PrepareError.html#variant.InvalidCrateName) or
+    /// [`PrepareError::InvalidCrateVersion`](enum.PrepareError.html#variant.InvalidCrateVersion).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rustwide::Crate;
+    /// let krate = Crate::crates_io("lazy_static", "1.0.0").unwrap();
+    /// assert!(Crate::crates_io("lazy static", "1.0.0").is_err());
+    /// assert!(Crate::crates_io("lazy_static", "1.0").is_err());
+    /// ```
+    pub fn crates_io(name: &str, version: &str) -> Result<Self, PrepareError> {
+        Ok(Crate(CrateType::CratesIO(cratesio::CratesIOCrate::new(
             name, version,
+        )?)))
+    }
+
+    /// Load the newest version of a crate from the [crates.io registry](https://crates.io),
+    /// ignoring yanked versions and pre-releases. The name is validated like in
+    /// [`crates_io`](#method.crates_io).
+    ///
+    /// The version is resolved with the crates.io index the first time the crate is fetched,
+    /// and it's kept for the lifetime of the `Crate`: until then the crate is displayed as
+    /// `crates.io crate <name> *`.
+    pub fn crates_io_latest(name: &str) -> Result<Self, PrepareError> {
+        Ok(Crate(CrateType::CratesIO(
+            cratesio::CratesIOCrate::with_requirement(name, VersionReq::STAR)?,
+        )))
+    }
+
+    /// Load the newest version of a crate from the [crates.io registry](https://crates.io)
+    /// matching a semver requirement (like `^1.2`), ignoring yanked versions.
+    ///
+    /// The version is resolved with the crates.io index the first time the crate is fetched,
+    /// and it's kept for the lifetime of the `Crate`. An error is returned if the name or the
+    /// requirement is not valid.
+    pub fn crates_io_req(name: &str, requirement: &str) -> Result<Self, Error> {
+        let requirement = VersionReq::parse(requirement)
+            .with_context(|_| format!("invalid version requirement: {}", requirement))?;
+        Ok(Crate(CrateType::CratesIO(
+            cratesio::CratesIOCrate::with_requirement(name, requirement)?,
         )))
     }
 
+    /// Get the name of a crates.io or registry crate normalized the way crates.io compares
+    /// names, ignoring the case and treating `-` and `_` as the same character (like
+    /// `serde_json` for `Serde-JSON`), or `None` for other crate types. Crates with the same
+    /// normalized name in the same registry are the same crate.
+    pub fn normalized_name(&self) -> Option<String> {
+        match &self.0 {
+            CrateType::CratesIO(krate) => Some(registry::normalize_name(krate.name())),
+            CrateType::Registry(krate) => Some(registry::normalize_name(krate.name())),
+            _ => None,
+        }
+    }
+
+    /// Get the version of a crates.io crate, or `None` for other crate types. Crates created
+    /// with [`crates_io_latest`](#method.crates_io_latest) or
+    /// [`crates_io_req`](#method.crates_io_req) return `None` until they are fetched.
+    pub fn crates_io_version(&self) -> Option<String> {
+        match &self.0 {
+            CrateType::CratesIO(krate) => krate.version(),
+            _ => None,
+        }
+    }
+
+    /// Load a crate from an alternate registry, identified by the URL of its index. Sparse
+    /// indexes are prefixed with `sparse+`, like in cargo's configuration, while other URLs are
+    /// treated as git indexes.
+    ///
+    /// The download endpoint is read from the registry's `config.json`. Registries requiring
+    /// authentication need a token configured with
+    /// [`WorkspaceBuilder::registry_token`](struct.WorkspaceBuilder.html#method.registry_token).
+    ///
+    /// The name and the version are validated like in [`crates_io`](#method.crates_io), and the
+    /// downloaded crate is verified against the checksum recorded in the index.
+    pub fn registry(registry_url: &str, name: &str, version: &str) -> Result<Self, PrepareError> {
+        Ok(Crate(CrateType::Registry(registry::RegistryCrate::new(
+            registry_url,
+            name,
+            version,
+        )?)))
+    }
+
     /// Load a crate from a git repository. The full URL needed to clone the repo has to be
     /// provided.
     pub fn git(url: &str) -> Self {
-        Crate(CrateType::Git(git::GitRepo::new(url)))
+        Crate(CrateType::Git(git::GitRepo::new(url, None, false)))
+    }
+
+    /// Load a crate from a subdirectory of a git repository, for repositories containing multiple
+    /// crates. The full URL needed to clone the repo has to be provided, along with the path of
+    /// the crate relative to the root of the repository (like `crates/foo`).
+    ///
+    /// Only the subdirectory is copied into the build directory, so the crate can't depend on
+    /// files outside of it, like the other crates of the repository with path dependencies or
+    /// fields inherited from the workspace's `Cargo.toml`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Crate;
+    /// let krate = Crate::git_subdir("https://github.com/rust-lang/cargo", "crates/cargo-util");
+    /// ```
+    pub fn git_subdir(url: &str, path_in_repo: &str) -> Self {
+        let mut repo = git::GitRepo::new(url, None, false);
+        repo.set_subdir(path_in_repo);
+        Crate(CrateType::Git(repo))
+    }
+
+    /// Load a crate from a git repository, cloning only its latest commit instead of its whole
+    /// history. The full URL needed to clone the repo has to be provided.
+    ///
+    /// This avoids downloading the history of huge repositories just to build them. The cached
+    /// clone is shared with the other git crates of the same repository: it's upgraded to a full
+    /// clone if a crate without shallow cloning (or pinned to a historical commit) needs it.
+    pub fn git_shallow(url: &str) -> Self {
+        Crate(CrateType::Git(git::GitRepo::new(url, None, true)))
+    }
+
+    /// Load a crate from a git repository, pinned to a branch, tag or commit instead of the
+    /// repository's `HEAD`. The full URL needed to clone the repo has to be provided.
+    ///
+    /// Commits that aren't reachable from any branch or tag are fetched explicitly, which
+    /// requires the git server to allow it. [`git_commit`](#method.git_commit) reports the
+    /// commit the reference points to.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{Crate, GitReference};
+    /// let krate = Crate::git_with_reference(
+    ///     "https://github.com/rust-lang/rustwide",
+    ///     GitReference::Tag("0.10.0".into()),
+    /// );
+    /// ```
+    pub fn git_with_reference(url: &str, reference: GitReference) -> Self {
+        Crate(CrateType::Git(git::GitRepo::new(
+            url,
+            Some(reference),
+            false,
+        )))
+    }
+
+    /// Load a crate from a git repository pinned to a branch, tag or commit like
+    /// [`git_with_reference`](#method.git_with_reference), cloning only the latest commit of the
+    /// reference like [`git_shallow`](#method.git_shallow).
+    ///
+    /// Commits aren't known to be the latest commit of a branch, so pinning a commit that isn't
+    /// in the shallow clone upgrades it to a full clone.
+    pub fn git_shallow_with_reference(url: &str, reference: GitReference) -> Self {
+        Crate(CrateType::Git(git::GitRepo::new(
+            url,
+            Some(reference),
+            true,
+        )))
+    }
+
+    /// Choose whether the submodules of a git crate are fetched and included in its source code.
+    ///
+    /// Submodules are fetched recursively by default, as many crates (like the ones vendoring C
+    /// libraries) can't be built without them. Each submodule is cached as a separate repository,
+    /// fetched with the same [credentials](#method.git_credentials) as the crate. This has no
+    /// effect on other crate types.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Crate;
+    /// let krate = Crate::git("https://github.com/rust-lang/rustwide").git_submodules(false);
+    /// ```
+    pub fn git_submodules(mut self, enable: bool) -> Self {
+        if let CrateType::Git(repo) = &mut self.0 {
+            repo.set_submodules(enable);
+        }
+        self
+    }
+
+    /// Authenticate with the provided credentials when fetching a git crate, instead of the
+    /// [workspace's ones](struct.WorkspaceBuilder.html#method.git_credentials). This allows
+    /// fetching private repositories hosted on different servers. This has no effect on other
+    /// crate types.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{Crate, GitCredentials};
+    /// let krate = Crate::git("git@github.com:my-org/private-crate.git")
+    ///     .git_credentials(GitCredentials::SshKey("/etc/rustwide/deploy-key".into()));
+    /// ```
+    pub fn git_credentials(mut self, credentials: GitCredentials) -> Self {
+        if let CrateType::Git(repo) = &mut self.0 {
+            repo.set_credentials(credentials);
+        }
+        self
+    }
+
+    /// Load a crate from a Mercurial repository, building the latest commit of its `default`
+    /// branch. The full URL needed to clone the repo has to be provided.
+    ///
+    /// The repository is cloned in the workspace's cache and updated with `hg pull` every time
+    /// the crate is fetched, so the `hg` binary must be available on the host.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Crate;
+    /// let krate = Crate::mercurial("https://hg.mozilla.org/example-crate");
+    /// ```
+    pub fn mercurial(url: &str) -> Self {
+        Crate(CrateType::Hg(hg::HgRepo::new(url)))
+    }
+
+    /// Load a crate from an archive downloaded from the provided URL, like the source code
+    /// archives of GitHub releases or vendored source drops. Zip files, gzipped tarballs and
+    /// plain tarballs are supported, and the format is detected from the content of the archive.
+    ///
+    /// Like `.crate` files, the archive must contain a single top-level directory with the source
+    /// code of the crate in it: the directory is removed when extracting the archive. The archive
+    /// is downloaded with the workspace's HTTP client and cached, so it's downloaded only once.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Crate;
+    /// let krate = Crate::tarball_url(
+    ///     "https://github.com/rust-lang/rustwide/archive/refs/tags/0.10.0.tar.gz",
+    /// );
+    /// ```
+    pub fn tarball_url(url: &str) -> Self {
+        Crate(CrateType::Tarball(tarball::TarballCrate::new(url)))
+    }
+
+    /// Load a crate from a `.crate` file in the local filesystem, like the ones created by
+    /// `cargo package` or downloaded from a registry. The file is extracted like the crates
+    /// downloaded from crates.io, and it's never copied to or removed from the cache.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Crate;
+    /// let krate = Crate::crate_file("target/package/foo-1.0.0.crate".as_ref());
+    /// ```
+    pub fn crate_file(path: &Path) -> Self {
+        Crate(CrateType::CrateFile(crate_file::CrateFile::new(path)))
+    }
+
+    /// Load a crate from a source implemented outside of rustwide. See
+    /// [`CrateSource`](trait.CrateSource.html) for an example.
+    pub fn custom(source: Box<dyn CrateSource>) -> Self {
+        Crate(CrateType::Custom(custom::CustomCrate::new(source)))
     }
 
     /// Load a crate from a directory in the local filesystem.
@@ -44,10 +315,148 @@ impl Crate {
         Crate(CrateType::Local(local::Local::new(path)))
     }
 
+    /// Load a crate from a directory in the local filesystem like [`local`](#method.local),
+    /// without copying the files matching the provided glob patterns into the build directory.
+    ///
+    /// Patterns are matched against the paths relative to the crate directory, using `/` as the
+    /// separator. Patterns without a `/` match the file name at any depth, and `dir/**` excludes
+    /// the whole directory. An error is returned if a pattern is invalid.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Crate;
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// let krate = Crate::local_with_excludes("path/to/foo".as_ref(), &["*.log", "fixtures/huge/**"])?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn local_with_excludes(path: &Path, excludes: &[&str]) -> Result<Self, Error> {
+        Ok(Crate(CrateType::Local(local::Local::with_excludes(
+            path, excludes,
+        )?)))
+    }
+
+    /// Choose whether the files ignored by `.gitignore` and `.ignore` files are skipped when
+    /// copying a local crate, like git and most search tools do. This avoids copying editor
+    /// artifacts, virtual environments and nested `target` directories into every build
+    /// directory. This has no effect on other crate types.
+    ///
+    /// The ignore files of the parent directories of the crate and `.git/info/exclude` are
+    /// honored as well, even if the crate is not in a git repository, while the user's global git
+    /// excludes are not used. Ignored files are skipped by the [watcher](#method.watch) too. The
+    /// files are copied regardless of the ignore files by default.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Crate;
+    /// let krate = Crate::local("path/to/monorepo/foo".as_ref()).local_ignore_files(true);
+    /// ```
+    pub fn local_ignore_files(mut self, enable: bool) -> Self {
+        if let CrateType::Local(local) = &mut self.0 {
+            local.set_ignore_files(enable);
+        }
+        self
+    }
+
+    /// Choose whether symbolic links pointing inside a local crate are recreated in the build
+    /// directory instead of being followed, keeping bundled scripts and their links working. Links
+    /// with absolute targets are rewritten as relative ones, and links pointing outside of the
+    /// crate are still followed. This has no effect on other crate types.
+    ///
+    /// File permissions, including the executable bits, are always preserved. Symbolic links are
+    /// followed by default.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Crate;
+    /// let krate = Crate::local("path/to/foo".as_ref()).local_preserve_symlinks(true);
+    /// ```
+    pub fn local_preserve_symlinks(mut self, enable: bool) -> Self {
+        if let CrateType::Local(local) = &mut self.0 {
+            local.set_preserve_symlinks(enable);
+        }
+        self
+    }
+
+    /// Choose whether the modification times of the files of a local crate are preserved when
+    /// copying them, so that Cargo doesn't consider every file changed when the build directory
+    /// is reused. This has no effect on other crate types.
+    ///
+    /// Copied files get the current time as their modification time by default.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Crate;
+    /// let krate = Crate::local("path/to/foo".as_ref()).local_preserve_mtimes(true);
+    /// ```
+    pub fn local_preserve_mtimes(mut self, enable: bool) -> Self {
+        if let CrateType::Local(local) = &mut self.0 {
+            local.set_preserve_mtimes(enable);
+        }
+        self
+    }
+
+    /// Set the names of the build output directories that are not copied from a local crate,
+    /// replacing the default list containing only `target`. Passing an empty list copies the
+    /// `target` directory too, for example to experiment with incremental builds. This has no
+    /// effect on other crate types.
+    ///
+    /// The directories are skipped only at the top level of the crate, unless
+    /// [`local_skip_nested_dirs`](#method.local_skip_nested_dirs) is enabled. Like excluded
+    /// files, skipped directories are ignored by the [watcher](#method.watch) too.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Crate;
+    /// let krate = Crate::local("path/to/foo".as_ref())
+    ///     .local_skip_dirs(&["target", "node_modules", ".git"]);
+    /// ```
+    pub fn local_skip_dirs(mut self, names: &[&str]) -> Self {
+        if let CrateType::Local(local) = &mut self.0 {
+            local.set_skipped_dirs(names);
+        }
+        self
+    }
+
+    /// Choose whether the build output directories set with
+    /// [`local_skip_dirs`](#method.local_skip_dirs) are skipped at any depth of a local crate,
+    /// such as the `target` directories of the members of a workspace, instead of only at the top
+    /// level. This has no effect on other crate types.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Crate;
+    /// let krate = Crate::local("path/to/workspace".as_ref()).local_skip_nested_dirs(true);
+    /// ```
+    pub fn local_skip_nested_dirs(mut self, enable: bool) -> Self {
+        if let CrateType::Local(local) = &mut self.0 {
+            local.set_nested_skipped_dirs(enable);
+        }
+        self
+    }
+
     /// Fetch the crate's source code and cache it in the workspace. This method will reach out to
     /// the network for some crate types.
     pub async fn fetch(&self, workspace: &Workspace) -> Result<(), Error> {
-        self.as_trait().fetch(workspace).await
+        #[cfg(feature = "mock")]
+        {
+            if let Some(mock) = workspace.mock_fixtures() {
+                return self.mocked(mock).map(|_| ());
+            }
+        }
+        workspace.check_cache_space()?;
+        self.as_trait().fetch(workspace).await?;
+        if let Some(entry) = self.as_trait().cache_entry(workspace) {
+            workspace.record_cache_access(entry).await;
+        }
+        Ok(())
     }
 
     /// Remove the cached copy of this crate. The method will do nothing if the crate isn't cached.
@@ -55,8 +464,25 @@ impl Crate {
         self.as_trait().purge_from_cache(workspace).await
     }
 
+    /// Start watching the source code of a local crate for changes, returning a
+    /// [`CrateWatcher`](struct.CrateWatcher.html) that can be used to wait for the changes.
+    ///
+    /// Only local crates can be watched: an error will be returned for other crate types. See
+    /// [`BuildDirectory::rebuild_on_change`](struct.BuildDirectory.html#method.rebuild_on_change)
+    /// to automatically rebuild the crate when it changes.
+    pub async fn watch(&self) -> Result<CrateWatcher, Error> {
+        match &self.0 {
+            CrateType::Local(local) => CrateWatcher::new(local.path(), local.options()).await,
+            _ => failure::bail!("only local crates can be watched, but {} is not", self),
+        }
+    }
+
     /// Get this crate's git commit. This method is best-effort, and currently works just for git
     /// crates. If the commit can't be retrieved `None` will be returned.
+    ///
+    /// Git crates pinned with [`git_with_reference`](#method.git_with_reference) report the
+    /// commit their reference points to, otherwise the commit of the repository's `HEAD` is
+    /// returned.
     pub async fn git_commit(&self, workspace: &Workspace) -> Option<String> {
         if let CrateType::Git(repo) = &self.0 {
             repo.git_commit(workspace).await
@@ -65,6 +491,57 @@ impl Crate {
         }
     }
 
+    /// Read the metadata of the crate from its `Cargo.toml`, without preparing or building it.
+    ///
+    /// The crate must be [fetched](#method.fetch) before calling this method: for crates.io and
+    /// alternate registries the manifest is read from the cached `.crate` file, for crate files
+    /// and archives from the file itself, for git and Mercurial repos from the latest commit of
+    /// the cached repository, for local crates from the directory itself, and for custom sources
+    /// with [`CrateSource::read_manifest`](trait.CrateSource.html#method.read_manifest).
+    pub async fn manifest_info(&self, workspace: &Workspace) -> Result<ManifestInfo, Error> {
+        #[cfg(feature = "mock")]
+        {
+            if let Some(mock) = workspace.mock_fixtures() {
+                return ManifestInfo::from_manifest(&self.mocked(mock)?.path().join("Cargo.toml"))
+                    .await;
+            }
+        }
+        let _lock = match self.as_trait().cache_entry(workspace) {
+            Some(entry) => Some(crate::cache_manager::lock_entry(workspace, &entry, true).await?),
+            None => None,
+        };
+        ManifestInfo::from_str(&self.as_trait().read_manifest(workspace).await?)
+    }
+
+    /// Return the name of the package, as defined in its `Cargo.toml`.
+    pub(crate) async fn package_name(&self, workspace: &Workspace) -> Result<String, Error> {
+        #[cfg(feature = "mock")]
+        {
+            if let Some(mock) = workspace.mock_fixtures() {
+                let path = self.mocked(mock)?.path().join("Cargo.toml");
+                return manifest_package_name(&tokio::fs::read_to_string(path).await?);
+            }
+        }
+        match &self.0 {
+            CrateType::CratesIO(krate) => Ok(krate.name().into()),
+            CrateType::Registry(krate) => Ok(krate.name().into()),
+            CrateType::Git(repo) => manifest_package_name(&repo.read_manifest(workspace).await?),
+            CrateType::Hg(repo) => manifest_package_name(&repo.read_manifest(workspace).await?),
+            CrateType::Tarball(krate) => {
+                manifest_package_name(&krate.read_manifest(workspace).await?)
+            }
+            CrateType::CrateFile(krate) => {
+                manifest_package_name(&krate.read_manifest(workspace).await?)
+            }
+            CrateType::Custom(krate) => {
+                manifest_package_name(&krate.read_manifest(workspace).await?)
+            }
+            CrateType::Local(local) => manifest_package_name(
+                &tokio::fs::read_to_string(local.path().join("Cargo.toml")).await?,
+            ),
+        }
+    }
+
     pub(crate) async fn copy_source_to(
         &self,
         workspace: &Workspace,
@@ -78,18 +555,127 @@ impl Crate {
             remove_dir_all(dest)?;
         }
 
+        #[cfg(feature = "mock")]
+        {
+            if let Some(mock) = workspace.mock_fixtures() {
+                return self.mocked(mock)?.copy_source_to(workspace, dest).await;
+            }
+        }
+        // The entry is locked while it's copied so that other processes don't remove it, and
+        // marking it as used makes it the last one to be evicted afterwards.
+        let _lock = match self.as_trait().cache_entry(workspace) {
+            Some(entry) => {
+                let lock = crate::cache_manager::lock_entry(workspace, &entry, true).await?;
+                workspace.record_cache_access(entry).await;
+                Some(lock)
+            }
+            None => None,
+        };
         self.as_trait().copy_source_to(workspace, dest).await
     }
 
+    /// Get the local directory serving this crate in a mock workspace.
+    #[cfg(feature = "mock")]
+    fn mocked(&self, mock: &crate::mock::MockFixtures) -> Result<local::Local, Error> {
+        match mock.crate_source_of(self) {
+            Some(path) => Ok(local::Local::new(&path)),
+            None => failure::bail!("no mock fixture for {}", self),
+        }
+    }
+
     fn as_trait(&self) -> &dyn CrateTrait {
         match &self.0 {
             CrateType::CratesIO(krate) => krate,
+            CrateType::CrateFile(krate) => krate,
+            CrateType::Custom(krate) => krate,
             CrateType::Git(repo) => repo,
+            CrateType::Hg(repo) => repo,
             CrateType::Local(local) => local,
+            CrateType::Registry(krate) => krate,
+            CrateType::Tarball(krate) => krate,
         }
     }
 }
 
+/// Result of a fetch, shared with the callers waiting for it.
+type FetchReceiver = tokio::sync::watch::Receiver<Option<Result<(), Arc<Error>>>>;
+
+/// Crate fetches in progress in a workspace, so that concurrent callers wanting the same crate
+/// wait for the running fetch instead of starting another one.
+#[derive(Default)]
+pub(crate) struct InFlightFetches {
+    fetches: Mutex<HashMap<String, FetchReceiver>>,
+}
+
+impl InFlightFetches {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fetch the crate, or wait for the fetch already in progress for it. If the caller running
+    /// the fetch is cancelled the fetch is started again by one of the waiting callers.
+    pub(crate) async fn fetch(&self, workspace: &Workspace, krate: &Crate) -> Result<(), Error> {
+        let key = krate.to_string();
+        loop {
+            let (sender, mut receiver) = {
+                let mut fetches = self.fetches.lock().unwrap();
+                match fetches.get(&key) {
+                    Some(receiver) => (None, receiver.clone()),
+                    None => {
+                        let (sender, receiver) = tokio::sync::watch::channel(None);
+                        fetches.insert(key.clone(), receiver.clone());
+                        (Some(sender), receiver)
+                    }
+                }
+            };
+
+            if let Some(sender) = sender {
+                let _remove = RemoveFetch {
+                    fetches: &self.fetches,
+                    key: &key,
+                };
+                let res = krate.fetch(workspace).await;
+                let shared = res
+                    .as_ref()
+                    .map(|_| ())
+                    .map_err(|err| Arc::new(crate::utils::clone_error(err)));
+                let _ = sender.broadcast(Some(shared));
+                return res;
+            }
+
+            while let Some(shared) = receiver.recv().await {
+                if let Some(res) = shared {
+                    return res.map_err(|err| crate::utils::clone_error(&err));
+                }
+            }
+        }
+    }
+}
+
+/// Remove a fetch from the ones in progress when it's done or cancelled.
+struct RemoveFetch<'a> {
+    fetches: &'a Mutex<HashMap<String, FetchReceiver>>,
+    key: &'a str,
+}
+
+impl Drop for RemoveFetch<'_> {
+    fn drop(&mut self) {
+        self.fetches.lock().unwrap().remove(self.key);
+    }
+}
+
+/// Extract the package name from the contents of a `Cargo.toml`.
+pub(crate) fn manifest_package_name(manifest: &str) -> Result<String, Error> {
+    let manifest: toml::Value =
+        toml::from_str(manifest).with_context(|_| PrepareError::InvalidCargoTomlSyntax)?;
+    manifest
+        .get("package")
+        .and_then(|package| package.get("name"))
+        .and_then(|name| name.as_str())
+        .map(|name| name.to_string())
+        .ok_or_else(|| PrepareError::InvalidCargoTomlSyntax.into())
+}
+
 impl std::fmt::Display for Crate {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{}", self.as_trait())
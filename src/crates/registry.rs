@@ -0,0 +1,491 @@
+use super::cratesio::verify_checksum;
+use super::unpack::{read_crate_manifest, unpack_crate};
+use super::CrateTrait;
+use crate::git::{Depth, BRANCHES_REFSPEC};
+use crate::prepare::PrepareError;
+use crate::Workspace;
+use async_trait::async_trait;
+use failure::{Error, ResultExt};
+use log::{info, warn};
+use percent_encoding::{percent_encode, AsciiSet, CONTROLS};
+use remove_dir_all::remove_dir_all;
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+const ENCODE_SET: AsciiSet = CONTROLS
+    .add(b'/')
+    .add(b'\\')
+    .add(b'<')
+    .add(b'>')
+    .add(b':')
+    .add(b'"')
+    .add(b'|')
+    .add(b'?')
+    .add(b'*')
+    .add(b' ');
+
+/// Maximum length of the crate names accepted by crates.io.
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Prefix of the URLs of sparse registry indexes, like in cargo's configuration.
+static SPARSE_PREFIX: &str = "sparse+";
+
+/// Markers replaced in the download URL template of the registry.
+static DL_MARKERS: &[&str] = &[
+    "{crate}",
+    "{version}",
+    "{prefix}",
+    "{lowerprefix}",
+    "{sha256-checksum}",
+];
+
+/// Configuration of a registry, stored in the `config.json` file at the root of its index.
+#[derive(Deserialize)]
+struct IndexConfig {
+    dl: String,
+}
+
+/// Entry of a version of a crate in the registry index.
+#[derive(Deserialize)]
+struct IndexEntry {
+    vers: String,
+    cksum: String,
+    #[serde(default)]
+    yanked: bool,
+}
+
+pub(super) struct RegistryCrate {
+    index: String,
+    name: String,
+    version: String,
+}
+
+impl RegistryCrate {
+    pub(super) fn new(index: &str, name: &str, version: &str) -> Result<Self, PrepareError> {
+        // The name and the version end up in the path of the cached crate, so they're validated
+        // like the ones of crates.io crates.
+        let name = name.trim();
+        validate_name(name)?;
+        let version = validate_version(name, version)?;
+        Ok(RegistryCrate {
+            index: index.into(),
+            name: name.into(),
+            version,
+        })
+    }
+
+    pub(super) fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn cache_path(&self, workspace: &Workspace) -> PathBuf {
+        workspace
+            .cache_dir()
+            .join("registry-sources")
+            .join(percent_encode(self.index.as_bytes(), &ENCODE_SET).to_string())
+            .join(&self.name)
+            .join(format!("{}-{}.crate", self.name, self.version))
+    }
+
+    /// Resolve the URL the `.crate` file can be downloaded from, as configured by the registry.
+    async fn download_url(&self, workspace: &Workspace, checksum: &str) -> Result<String, Error> {
+        let config: IndexConfig =
+            serde_json::from_slice(&self.read_index_file(workspace, "config.json").await?)
+                .with_context(|_| {
+                    format!("invalid configuration of the registry {}", self.index)
+                })?;
+        Ok(expand_dl(
+            &config.dl,
+            &self.name,
+            &self.version,
+            Some(checksum),
+        ))
+    }
+
+    /// Read the checksum of the crate from its entry in the registry index.
+    async fn checksum(&self, workspace: &Workspace) -> Result<String, Error> {
+        let path = format!(
+            "{}/{}",
+            prefix(&self.name).to_lowercase(),
+            self.name.to_lowercase()
+        );
+        let entries = self.read_index_file(workspace, &path).await?;
+        find_checksum(&String::from_utf8_lossy(&entries), &self.version)
+            .ok_or_else(|| failure::format_err!("{} is not in the index of the registry", self))
+    }
+
+    /// Read a file from the registry index, either over HTTP for sparse indexes or from a shallow
+    /// clone for git indexes.
+    async fn read_index_file(&self, workspace: &Workspace, path: &str) -> Result<Vec<u8>, Error> {
+        if let Some(url) = self.index.strip_prefix(SPARSE_PREFIX) {
+            let url = format!("{}/{}", url.trim_end_matches('/'), path);
+            let token = workspace.registry_token(&self.index);
+            return crate::download::download_bytes_authenticated(workspace, &url, token)
+                .await
+                .with_context(|_| format!("failed to fetch {}", url))
+                .map_err(Into::into);
+        }
+
+        let temp = tempfile::tempdir()?;
+        let repo = temp.path().join("index");
+        crate::git::clone(
+            workspace,
+            &self.index,
+            workspace.git_credentials(),
+            &repo,
+            vec![format!("+{}", BRANCHES_REFSPEC)],
+            Depth::Latest,
+        )
+        .await?;
+        crate::git::read_file(&repo, "HEAD", path)?
+            .ok_or_else(|| failure::format_err!("{} doesn't exist in {}", path, self.index))
+    }
+}
+
+/// Directory of a crate inside a registry index, which depends on the length of its name.
+///
+/// Valid names are ASCII, but the name is split on characters so that invalid ones never panic.
+pub(super) fn prefix(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let part = |range: std::ops::Range<usize>| chars[range].iter().collect::<String>();
+    match chars.len() {
+        0 => String::new(),
+        1 => "1".into(),
+        2 => "2".into(),
+        3 => format!("3/{}", part(0..1)),
+        _ => format!("{}/{}", part(0..2), part(2..4)),
+    }
+}
+
+/// Check whether a crate name is accepted by crates.io: up to 64 ASCII letters, digits, `-` and
+/// `_`, starting with a letter.
+pub(super) fn validate_name(name: &str) -> Result<(), PrepareError> {
+    let reason = if name.is_empty() {
+        "the name is empty".to_string()
+    } else if name.len() > MAX_NAME_LENGTH {
+        format!("the name is longer than {} characters", MAX_NAME_LENGTH)
+    } else if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        "the name must start with a letter".to_string()
+    } else if let Some(c) = name
+        .chars()
+        .find(|&c| !c.is_ascii_alphanumeric() && c != '-' && c != '_')
+    {
+        format!("the name contains the invalid character {:?}", c)
+    } else {
+        return Ok(());
+    };
+    Err(PrepareError::InvalidCrateName {
+        name: name.into(),
+        reason,
+    })
+}
+
+/// Check whether the version of a crate is a valid semver version, accepting a leading `=` like
+/// cargo's exact requirements. The version is returned without the `=` and the whitespace.
+pub(super) fn validate_version(name: &str, version: &str) -> Result<String, PrepareError> {
+    let trimmed = version.trim();
+    let trimmed = trimmed.strip_prefix('=').unwrap_or(trimmed).trim_start();
+    match Version::parse(trimmed) {
+        Ok(_) => Ok(trimmed.to_string()),
+        Err(err) => Err(PrepareError::InvalidCrateVersion {
+            name: name.into(),
+            version: version.into(),
+            reason: err.to_string(),
+        }),
+    }
+}
+
+/// Normalize a crate name the way crates.io compares them, ignoring the case and treating `-`
+/// and `_` as the same character.
+pub(super) fn normalize_name(name: &str) -> String {
+    name.to_ascii_lowercase().replace('-', "_")
+}
+
+/// Expand the download URL template of a registry for a version of a crate. When the template
+/// contains no markers the crate and version are appended to it, like cargo does.
+fn expand_dl(dl: &str, name: &str, version: &str, checksum: Option<&str>) -> String {
+    if !DL_MARKERS.iter().any(|marker| dl.contains(marker)) {
+        return format!("{}/{}/{}/download", dl.trim_end_matches('/'), name, version);
+    }
+    let prefix = prefix(name);
+    dl.replace("{crate}", name)
+        .replace("{version}", version)
+        .replace("{prefix}", &prefix)
+        .replace("{lowerprefix}", &prefix.to_lowercase())
+        .replace("{sha256-checksum}", checksum.unwrap_or(""))
+}
+
+/// Find the checksum of a version in the index entries of a crate.
+pub(super) fn find_checksum(entries: &str, version: &str) -> Option<String> {
+    entries
+        .lines()
+        .filter_map(|line| serde_json::from_str::<IndexEntry>(line).ok())
+        .find(|entry| entry.vers == version)
+        .map(|entry| entry.cksum)
+}
+
+/// Find the newest version matching the requirement in the index entries of a crate, ignoring
+/// yanked versions and versions that aren't valid semver.
+pub(super) fn resolve_version(entries: &str, req: &VersionReq) -> Option<String> {
+    entries
+        .lines()
+        .filter_map(|line| serde_json::from_str::<IndexEntry>(line).ok())
+        .filter(|entry| !entry.yanked)
+        .filter_map(|entry| Version::parse(&entry.vers).ok())
+        .filter(|version| req.matches(version))
+        .max()
+        .map(|version| version.to_string())
+}
+
+#[async_trait]
+impl CrateTrait for RegistryCrate {
+    fn cache_entry(&self, workspace: &Workspace) -> Option<PathBuf> {
+        Some(self.cache_path(workspace))
+    }
+
+    async fn fetch(&self, workspace: &Workspace) -> Result<(), Error> {
+        // The checksum is always read from the index, so that cached crates are verified too.
+        let expected = self.checksum(workspace).await?;
+        let local = self.cache_path(workspace);
+        // The mismatch is returned as is, so that callers can match on it with `downcast_ref`.
+        let verify = |path: &Path| -> Result<(), Error> {
+            let content = std::fs::read(path)?;
+            verify_checksum(&content, &expected).map_err(|err| {
+                warn!("failed to verify {}: {}", self, err);
+                err.into()
+            })
+        };
+        if local.exists() {
+            let cached = local.clone();
+            let checksum = expected.clone();
+            let result = crate::utils::spawn_blocking(workspace.runtime(), move || {
+                verify_checksum(&std::fs::read(&cached)?, &checksum).map_err(Error::from)
+            })
+            .await?;
+            match result {
+                Ok(()) => {
+                    info!("{} is already in cache", self);
+                    return Ok(());
+                }
+                Err(err) => warn!("cached {} is corrupted, fetching it again: {}", self, err),
+            }
+        }
+
+        info!("fetching {}...", self);
+        let remote = self.download_url(workspace, &expected).await?;
+        let token = workspace.registry_token(&self.index);
+        crate::download::download_verified_authenticated(
+            workspace, &remote, &local, token, &verify,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn purge_from_cache(&self, workspace: &Workspace) -> Result<(), Error> {
+        let path = self.cache_path(workspace);
+        if path.exists() {
+            fs::remove_file(&path).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn copy_source_to(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error> {
+        let cached = self.cache_path(workspace);
+        let parallel = workspace.parallel_extraction();
+
+        info!("extracting {} into {}", self, dest.display());
+        let dest_owned = dest.to_path_buf();
+        let result = crate::utils::spawn_blocking(workspace.runtime(), move || {
+            unpack_crate(&cached, &dest_owned, parallel)
+        })
+        .await?;
+
+        if let Err(err) = result {
+            let _ = remove_dir_all(dest);
+            Err(err.context(format!("unable to extract {}", self)).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn read_manifest(&self, workspace: &Workspace) -> Result<String, Error> {
+        let cached = self.cache_path(workspace);
+        crate::utils::spawn_blocking(workspace.runtime(), move || read_crate_manifest(&cached))
+            .await?
+    }
+}
+
+impl std::fmt::Display for RegistryCrate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "crate {} {} from registry {}",
+            self.name, self.version, self.index
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        expand_dl, find_checksum, normalize_name, prefix, resolve_version, validate_name,
+        validate_version, RegistryCrate,
+    };
+    use crate::prepare::PrepareError;
+    use semver::VersionReq;
+
+    #[test]
+    fn test_prefix() {
+        assert_eq!(prefix("a"), "1");
+        assert_eq!(prefix("ab"), "2");
+        assert_eq!(prefix("abc"), "3/a");
+        assert_eq!(prefix("Serde"), "Se/rd");
+        assert_eq!(prefix(""), "");
+        assert_eq!(prefix("é"), "1");
+        assert_eq!(prefix("éàbc"), "éà/bc");
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("serde").is_ok());
+        assert!(validate_name("Serde_json-2").is_ok());
+        assert!(validate_name(&"a".repeat(64)).is_ok());
+        for name in &[
+            "",
+            "1foo",
+            "_foo",
+            "foo bar",
+            "foo.rs",
+            "fóo",
+            &"a".repeat(65),
+        ] {
+            match validate_name(name) {
+                Err(PrepareError::InvalidCrateName { name: invalid, .. }) => {
+                    assert_eq!(&invalid, name)
+                }
+                other => panic!("unexpected result for {:?}: {:?}", name, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_validate_version() {
+        assert_eq!(validate_version("foo", "1.0.0").unwrap(), "1.0.0");
+        assert_eq!(
+            validate_version("foo", " =1.2.3-beta.1 ").unwrap(),
+            "1.2.3-beta.1"
+        );
+        for version in &["", "1.0", "^1.0.0", "latest", "1.0.0.0"] {
+            match validate_version("foo", version) {
+                Err(PrepareError::InvalidCrateVersion {
+                    version: invalid, ..
+                }) => {
+                    assert_eq!(&invalid, version)
+                }
+                other => panic!("unexpected result for {:?}: {:?}", version, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_new_validates_name_and_version() {
+        let index = "sparse+https://example.com/index";
+        let krate = RegistryCrate::new(index, " foo ", "=1.0.0").unwrap();
+        assert_eq!(krate.name, "foo");
+        assert_eq!(krate.version, "1.0.0");
+
+        for name in &["", "../foo", "fóo"] {
+            match RegistryCrate::new(index, name, "1.0.0") {
+                Err(PrepareError::InvalidCrateName { .. }) => {}
+                Err(other) => panic!("unexpected error for {:?}: {:?}", name, other),
+                Ok(_) => panic!("{:?} should be rejected", name),
+            }
+        }
+        match RegistryCrate::new(index, "foo", "../../1.0.0") {
+            Err(PrepareError::InvalidCrateVersion { .. }) => {}
+            Err(other) => panic!("unexpected error: {:?}", other),
+            Ok(_) => panic!("the version should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_normalize_name() {
+        assert_eq!(normalize_name("serde_json"), "serde_json");
+        assert_eq!(normalize_name("Serde-JSON"), "serde_json");
+    }
+
+    #[test]
+    fn test_expand_dl() {
+        assert_eq!(
+            expand_dl("https://example.com/api/v1/crates", "foo", "1.0.0", None),
+            "https://example.com/api/v1/crates/foo/1.0.0/download"
+        );
+        assert_eq!(
+            expand_dl(
+                "https://example.com/{lowerprefix}/{crate}/{crate}-{version}.crate",
+                "FooBar",
+                "0.2.1",
+                None
+            ),
+            "https://example.com/fo/ob/FooBar/FooBar-0.2.1.crate"
+        );
+        assert_eq!(
+            expand_dl(
+                "https://example.com/{prefix}/{sha256-checksum}",
+                "abc",
+                "1.0.0",
+                Some("1234")
+            ),
+            "https://example.com/3/a/1234"
+        );
+    }
+
+    #[test]
+    fn test_find_checksum() {
+        let entries = concat!(
+            r#"{"name":"foo","vers":"1.0.0","deps":[],"cksum":"aaaa","features":{},"yanked":false}"#,
+            "\n",
+            r#"{"name":"foo","vers":"1.1.0","deps":[],"cksum":"bbbb","features":{},"yanked":false}"#,
+            "\n",
+        );
+        assert_eq!(find_checksum(entries, "1.1.0"), Some("bbbb".into()));
+        assert_eq!(find_checksum(entries, "2.0.0"), None);
+    }
+
+    #[test]
+    fn test_resolve_version() {
+        let entries = concat!(
+            r#"{"name":"foo","vers":"1.2.0","cksum":"aaaa","yanked":false}"#,
+            "\n",
+            r#"{"name":"foo","vers":"1.10.0","cksum":"bbbb","yanked":false}"#,
+            "\n",
+            r#"{"name":"foo","vers":"1.11.0","cksum":"cccc","yanked":true}"#,
+            "\n",
+            r#"{"name":"foo","vers":"2.0.0-beta.1","cksum":"dddd","yanked":false}"#,
+            "\n",
+            r#"{"name":"foo","vers":"0.9.0","cksum":"eeee"}"#,
+            "\n",
+        );
+        assert_eq!(
+            resolve_version(entries, &VersionReq::STAR),
+            Some("1.10.0".into())
+        );
+        assert_eq!(
+            resolve_version(entries, &"^1.2".parse().unwrap()),
+            Some("1.10.0".into())
+        );
+        assert_eq!(
+            resolve_version(entries, &"<1".parse().unwrap()),
+            Some("0.9.0".into())
+        );
+        assert_eq!(
+            resolve_version(entries, &"^2.0.0-beta".parse().unwrap()),
+            Some("2.0.0-beta.1".into())
+        );
+        assert_eq!(resolve_version(entries, &"^3".parse().unwrap()), None);
+    }
+}
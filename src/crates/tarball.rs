@@ -0,0 +1,179 @@
+use super::unpack::{
+    read_file_without_first_dir, read_zip_file_without_first_dir, unpack_without_first_dir,
+    unpack_zip_without_first_dir, READ_BUFFER_SIZE,
+};
+use super::CrateTrait;
+use crate::prepare::PrepareError;
+use crate::Workspace;
+use async_trait::async_trait;
+use failure::{Error, ResultExt};
+use flate2::read::GzDecoder;
+use log::info;
+use remove_dir_all::remove_dir_all;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use tar::Archive;
+use zip::ZipArchive;
+
+/// Format of a downloaded archive, detected from its first bytes as the URL doesn't always end
+/// with an extension.
+#[derive(Debug, PartialEq, Eq)]
+enum ArchiveFormat {
+    Zip,
+    TarGz,
+    Tar,
+}
+
+impl ArchiveFormat {
+    fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(b"PK\x03\x04") {
+            ArchiveFormat::Zip
+        } else if magic.starts_with(&[0x1f, 0x8b]) {
+            ArchiveFormat::TarGz
+        } else {
+            ArchiveFormat::Tar
+        }
+    }
+
+    fn of_file(path: &Path) -> Result<Self, Error> {
+        let mut magic = Vec::with_capacity(4);
+        File::open(path)?.take(4).read_to_end(&mut magic)?;
+        Ok(ArchiveFormat::detect(&magic))
+    }
+}
+
+pub(super) struct TarballCrate {
+    url: String,
+}
+
+impl TarballCrate {
+    pub(super) fn new(url: &str) -> Self {
+        TarballCrate { url: url.into() }
+    }
+
+    /// Return the path of the downloaded archive, named after the hash of its URL as URLs can be
+    /// longer than the file names allowed by the filesystem.
+    fn cache_path(&self, workspace: &Workspace) -> PathBuf {
+        workspace
+            .cache_dir()
+            .join("tarballs")
+            .join(format!("{:x}", Sha256::digest(self.url.as_bytes())))
+    }
+}
+
+#[async_trait]
+impl CrateTrait for TarballCrate {
+    fn cache_entry(&self, workspace: &Workspace) -> Option<PathBuf> {
+        Some(self.cache_path(workspace))
+    }
+
+    async fn fetch(&self, workspace: &Workspace) -> Result<(), Error> {
+        let local = self.cache_path(workspace);
+        // The archive at an URL can change, so the cached one is revalidated with the server,
+        // which only sends it again if it changed. Archives served without validators can't be
+        // revalidated, and are never downloaded again.
+        if local.exists() && !crate::download::can_revalidate(&local) {
+            info!("archive {} is already in cache", self.url);
+            return Ok(());
+        }
+
+        info!("fetching archive {}...", self.url);
+        crate::download::download(workspace, &self.url, &local)
+            .await
+            .with_context(|_| format!("failed to download {}", self.url))?;
+        Ok(())
+    }
+
+    async fn purge_from_cache(&self, workspace: &Workspace) -> Result<(), Error> {
+        let path = self.cache_path(workspace);
+        if path.exists() {
+            tokio::fs::remove_file(&path).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn copy_source_to(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error> {
+        let cached = self.cache_path(workspace);
+        let parallel = workspace.parallel_extraction();
+
+        info!("extracting archive {} into {}", self.url, dest.display());
+        let dest_owned = dest.to_path_buf();
+        let result = crate::utils::spawn_blocking(workspace.runtime(), move || {
+            let format = ArchiveFormat::of_file(&cached)?;
+            let file = BufReader::with_capacity(READ_BUFFER_SIZE, File::open(&cached)?);
+            match format {
+                ArchiveFormat::Zip => {
+                    unpack_zip_without_first_dir(&mut ZipArchive::new(file)?, &dest_owned)
+                }
+                ArchiveFormat::TarGz => unpack_without_first_dir(
+                    &mut Archive::new(GzDecoder::new(file)),
+                    &dest_owned,
+                    parallel,
+                ),
+                ArchiveFormat::Tar => {
+                    unpack_without_first_dir(&mut Archive::new(file), &dest_owned, parallel)
+                }
+            }
+        })
+        .await?;
+
+        if let Err(err) = result {
+            let _ = remove_dir_all(dest);
+            Err(err
+                .context(format!("unable to extract {}", self.url))
+                .into())
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn read_manifest(&self, workspace: &Workspace) -> Result<String, Error> {
+        let cached = self.cache_path(workspace);
+        crate::utils::spawn_blocking(workspace.runtime(), move || {
+            let format = ArchiveFormat::of_file(&cached)?;
+            let file = BufReader::with_capacity(READ_BUFFER_SIZE, File::open(&cached)?);
+            let manifest = Path::new("Cargo.toml");
+            match format {
+                ArchiveFormat::Zip => {
+                    read_zip_file_without_first_dir(&mut ZipArchive::new(file)?, manifest)
+                }
+                ArchiveFormat::TarGz => {
+                    read_file_without_first_dir(&mut Archive::new(GzDecoder::new(file)), manifest)
+                }
+                ArchiveFormat::Tar => {
+                    read_file_without_first_dir(&mut Archive::new(file), manifest)
+                }
+            }?
+            .ok_or_else(|| PrepareError::MissingCargoToml.into())
+        })
+        .await?
+    }
+}
+
+impl std::fmt::Display for TarballCrate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "archive {}", self.url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ArchiveFormat;
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(
+            ArchiveFormat::detect(b"PK\x03\x04\x14\x00"),
+            ArchiveFormat::Zip
+        );
+        assert_eq!(
+            ArchiveFormat::detect(&[0x1f, 0x8b, 0x08, 0x00]),
+            ArchiveFormat::TarGz
+        );
+        assert_eq!(ArchiveFormat::detect(b"foo/\0\0\0\0"), ArchiveFormat::Tar);
+        assert_eq!(ArchiveFormat::detect(b""), ArchiveFormat::Tar);
+    }
+}
@@ -0,0 +1,773 @@
+use crate::prepare::PrepareError;
+use failure::{bail, format_err, Error, ResultExt};
+use flate2::read::GzDecoder;
+use log::warn;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, Read, Seek};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use tar::Archive;
+use zip::ZipArchive;
+
+/// Device names reserved by Windows, which can't be used as file names even with an extension.
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+/// Characters that can't be used in file names on Windows, in addition to control characters.
+const WINDOWS_INVALID_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*', '\\'];
+
+/// Size of the buffer archives are read with.
+pub(crate) const READ_BUFFER_SIZE: usize = 256 * 1024;
+/// Number of threads writing the extracted files when extracting in parallel.
+const WRITE_THREADS: usize = 8;
+/// Files bigger than this are always written by the thread reading the archive, to limit the
+/// memory used by parallel extraction.
+const MAX_PARALLEL_FILE_SIZE: u64 = 1024 * 1024;
+/// Bits of a Unix file mode storing the type of the file, and the type of symbolic links.
+const S_IFMT: u32 = 0o170_000;
+const S_IFLNK: u32 = 0o120_000;
+
+/// Extract the archive into `dest`, removing the first component of the paths inside it.
+///
+/// Entries with absolute paths or paths escaping the destination are rejected, as are links
+/// pointing outside of it. Links are created after all the other entries are extracted, so no
+/// file is ever written through a link coming from the archive. On Windows, entries with names
+/// that are not valid there are rejected with
+/// [`PrepareError::InvalidWindowsPath`](../../enum.PrepareError.html#variant.InvalidWindowsPath).
+pub(crate) fn unpack_without_first_dir<R: Read>(
+    archive: &mut Archive<R>,
+    dest: &Path,
+    parallel: bool,
+) -> Result<(), Error> {
+    let dest = crate::utils::long_path(dest);
+    std::fs::create_dir_all(&dest)?;
+    // On Windows the canonical path is an extended-length path, so deep archives can be
+    // extracted without hitting the path length limit.
+    let dest = dest.canonicalize()?;
+
+    let writer = if parallel {
+        Some(ParallelWriter::new())
+    } else {
+        None
+    };
+    let mut links = Vec::new();
+    // Paths of the files handed to the parallel writer. When an archive contains the same path
+    // more than once, the later entries are handed to the writer too, so that the last one wins
+    // like when extracting sequentially.
+    let mut queued = HashSet::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let relpath = match strip_first_dir(&path)? {
+            Some(relpath) => relpath,
+            // The first directory itself
+            None => continue,
+        };
+        check_windows_path(&relpath)?;
+        let full_path = dest.join(&relpath);
+
+        let kind = entry.header().entry_type();
+        if kind.is_symlink() || kind.is_hard_link() {
+            let target = entry
+                .link_name()?
+                .ok_or_else(|| format_err!("link {} has no target", path.display()))?
+                .into_owned();
+            links.push(Link {
+                path: relpath,
+                target,
+                hard: kind.is_hard_link(),
+            });
+            continue;
+        } else if !kind.is_file() && !kind.is_dir() {
+            warn!("skipping unsupported archive entry {}", path.display());
+            continue;
+        }
+
+        if let Some(parent) = full_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        match &writer {
+            Some(writer)
+                if kind.is_file()
+                    && (entry.size() <= MAX_PARALLEL_FILE_SIZE || queued.contains(&full_path)) =>
+            {
+                let mut content = Vec::with_capacity(entry.size() as usize);
+                entry.read_to_end(&mut content)?;
+                queued.insert(full_path.clone());
+                writer.write(PendingFile {
+                    path: full_path,
+                    content,
+                    mode: entry.header().mode()?,
+                });
+            }
+            _ => {
+                entry.unpack(&full_path)?;
+            }
+        }
+    }
+
+    if let Some(writer) = writer {
+        writer.finish()?;
+    }
+    for link in &links {
+        link.create(&dest)
+            .with_context(|_| format!("failed to create link {}", link.path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Extract the zip archive into `dest`, removing the first component of the paths inside it like
+/// [`unpack_without_first_dir`](fn.unpack_without_first_dir.html). Symbolic links stored in the
+/// archive are skipped, as there's no portable way to represent them in zip files.
+pub(crate) fn unpack_zip_without_first_dir<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    dest: &Path,
+) -> Result<(), Error> {
+    let dest = crate::utils::long_path(dest);
+    std::fs::create_dir_all(&dest)?;
+    let dest = dest.canonicalize()?;
+
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let path = PathBuf::from(entry.name());
+        let relpath = match strip_first_dir(&path)? {
+            Some(relpath) => relpath,
+            // The first directory itself
+            None => continue,
+        };
+        check_windows_path(&relpath)?;
+        let full_path = dest.join(&relpath);
+
+        if entry.is_dir() {
+            std::fs::create_dir_all(&full_path)?;
+            continue;
+        }
+        let mode = entry.unix_mode();
+        if mode.map(|mode| mode & S_IFMT == S_IFLNK).unwrap_or(false) {
+            warn!("skipping symbolic link {}", path.display());
+            continue;
+        }
+
+        if let Some(parent) = full_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = std::fs::File::create(&full_path)?;
+        std::io::copy(&mut entry, &mut file)
+            .with_context(|_| format!("failed to extract {}", path.display()))?;
+        #[cfg(unix)]
+        {
+            if let Some(mode) = mode {
+                use std::os::unix::fs::PermissionsExt;
+                file.set_permissions(std::fs::Permissions::from_mode(mode & 0o777))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the contents of a file from the zip archive, given its path without the first component.
+/// `None` is returned if the archive doesn't contain the file.
+pub(crate) fn read_zip_file_without_first_dir<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    file: &Path,
+) -> Result<Option<String>, Error> {
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let path = PathBuf::from(entry.name());
+        if entry.is_file() && strip_first_dir(&path)?.as_deref() == Some(file) {
+            let mut content = String::new();
+            entry
+                .read_to_string(&mut content)
+                .with_context(|_| format!("failed to read {}", path.display()))?;
+            return Ok(Some(content));
+        }
+    }
+    Ok(None)
+}
+
+/// Read the contents of a file from the archive, given its path without the first component.
+/// `None` is returned if the archive doesn't contain the file.
+pub(crate) fn read_file_without_first_dir<R: Read>(
+    archive: &mut Archive<R>,
+    file: &Path,
+) -> Result<Option<String>, Error> {
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if entry.header().entry_type().is_file() && strip_first_dir(&path)?.as_deref() == Some(file)
+        {
+            let mut content = String::new();
+            entry
+                .read_to_string(&mut content)
+                .with_context(|_| format!("failed to read {}", path.display()))?;
+            return Ok(Some(content));
+        }
+    }
+    Ok(None)
+}
+
+/// Extract a `.crate` file, which is a gzipped tarball, into `dest`.
+pub(crate) fn unpack_crate(path: &Path, dest: &Path, parallel: bool) -> Result<(), Error> {
+    let file = BufReader::with_capacity(READ_BUFFER_SIZE, std::fs::File::open(path)?);
+    let mut tar = Archive::new(GzDecoder::new(file));
+    unpack_without_first_dir(&mut tar, dest, parallel)
+}
+
+/// Read the `Cargo.toml` at the root of a `.crate` file, without extracting it.
+pub(crate) fn read_crate_manifest(path: &Path) -> Result<String, Error> {
+    let file = BufReader::with_capacity(READ_BUFFER_SIZE, std::fs::File::open(path)?);
+    let mut tar = Archive::new(GzDecoder::new(file));
+    read_file_without_first_dir(&mut tar, Path::new("Cargo.toml"))?
+        .ok_or_else(|| PrepareError::MissingCargoToml.into())
+}
+
+/// Remove the first component from a path inside the archive, rejecting absolute paths and
+/// paths containing `..`. `None` is returned for the first component itself.
+fn strip_first_dir(path: &Path) -> Result<Option<PathBuf>, Error> {
+    let mut stripped = PathBuf::new();
+    let mut first_dir = true;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) if first_dir => first_dir = false,
+            Component::Normal(part) => stripped.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                bail!(
+                    "archive entry {} escapes the destination directory",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    Ok(if stripped.as_os_str().is_empty() {
+        None
+    } else {
+        Some(stripped)
+    })
+}
+
+/// Reject the paths that can't be created on Windows when running on it.
+fn check_windows_path(relpath: &Path) -> Result<(), PrepareError> {
+    if cfg!(windows) {
+        if let Some(reason) = windows_path_problem(relpath) {
+            return Err(PrepareError::InvalidWindowsPath {
+                path: relpath.to_string_lossy().replace('\\', "/"),
+                reason: reason.into(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Check whether a path extracted from an archive can be created on Windows, returning the reason
+/// if it can't. Windows would otherwise fail with an opaque I/O error, or silently create a file
+/// with a different name.
+fn windows_path_problem(path: &Path) -> Option<&'static str> {
+    for component in path.components() {
+        let name = match component {
+            Component::Normal(name) => name.to_string_lossy(),
+            _ => continue,
+        };
+        if name.ends_with('.') || name.ends_with(' ') {
+            return Some("names can't end with a dot or a space");
+        }
+        if name
+            .chars()
+            .any(|c| c.is_control() || WINDOWS_INVALID_CHARS.contains(&c))
+        {
+            return Some("names can't contain control characters or any of <>:\"|?*\\");
+        }
+        let stem = name.split('.').next().unwrap_or("").trim_end();
+        if WINDOWS_RESERVED_NAMES
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+        {
+            return Some("the name is reserved for a device");
+        }
+    }
+    None
+}
+
+struct Link {
+    path: PathBuf,
+    target: PathBuf,
+    hard: bool,
+}
+
+impl Link {
+    fn create(&self, dest: &Path) -> Result<(), Error> {
+        let full_path = dest.join(&self.path);
+        let (parent, name) = match (full_path.parent(), full_path.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => bail!("invalid link path"),
+        };
+        std::fs::create_dir_all(parent)?;
+
+        // Resolve the links already created in the parent directories, to make sure the link
+        // isn't created outside of the destination through one of them.
+        let parent = parent.canonicalize()?;
+        if !parent.starts_with(dest) {
+            bail!("the link escapes the destination directory");
+        }
+        let full_path = parent.join(name);
+
+        if self.hard {
+            // Hard link targets are relative to the root of the archive.
+            let target = match strip_first_dir(&self.target)? {
+                Some(target) => dest.join(target).canonicalize()?,
+                None => bail!("hard links to directories are not supported"),
+            };
+            if !target.starts_with(dest) {
+                bail!("the link target escapes the destination directory");
+            }
+            std::fs::hard_link(&target, &full_path)?;
+        } else {
+            let resolved = match resolve_symlink(&parent, &self.target) {
+                Some(resolved) if resolved.starts_with(dest) => resolved,
+                _ => bail!("the link target escapes the destination directory"),
+            };
+            crate::native::symlink(&self.target, &resolved, &full_path)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Resolve the target of a symbolic link located in `parent`.
+///
+/// Only relative targets with `..` at the start are accepted: a `..` after a normal component
+/// could move outside of the destination when the component is itself a link.
+fn resolve_symlink(parent: &Path, target: &Path) -> Option<PathBuf> {
+    let mut resolved = parent.to_path_buf();
+    let mut only_parents = true;
+    for component in target.components() {
+        match component {
+            Component::ParentDir if only_parents => {
+                if !resolved.pop() {
+                    return None;
+                }
+            }
+            Component::Normal(part) => {
+                only_parents = false;
+                resolved.push(part);
+            }
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(resolved)
+}
+
+struct PendingFile {
+    path: PathBuf,
+    content: Vec<u8>,
+    mode: u32,
+}
+
+impl PendingFile {
+    fn write(self) -> Result<(), Error> {
+        std::fs::write(&self.path, &self.content)?;
+        crate::native::set_mode(&self.path, self.mode)?;
+        Ok(())
+    }
+}
+
+/// Pool of threads writing the extracted files to disk. Each path is always written by the same
+/// thread, so multiple writes to the same path happen in the order they were queued.
+struct ParallelWriter {
+    senders: Vec<mpsc::SyncSender<PendingFile>>,
+    threads: Vec<JoinHandle<()>>,
+    error: Arc<Mutex<Option<Error>>>,
+}
+
+impl ParallelWriter {
+    fn new() -> Self {
+        let error = Arc::new(Mutex::new(None));
+        let mut senders = Vec::with_capacity(WRITE_THREADS);
+        let mut threads = Vec::with_capacity(WRITE_THREADS);
+
+        for _ in 0..WRITE_THREADS {
+            // The channels are bounded to avoid reading the whole archive in memory if writing
+            // the files is slower than reading it.
+            let (sender, receiver) = mpsc::sync_channel::<PendingFile>(4);
+            let error = error.clone();
+            senders.push(sender);
+            threads.push(std::thread::spawn(move || {
+                for file in receiver {
+                    let path = file.path.clone();
+                    if let Err(err) = file.write() {
+                        let mut error = error.lock().unwrap();
+                        if error.is_none() {
+                            *error = Some(
+                                err.context(format!("failed to write {}", path.display()))
+                                    .into(),
+                            );
+                        }
+                    }
+                }
+            }));
+        }
+
+        ParallelWriter {
+            senders,
+            threads,
+            error,
+        }
+    }
+
+    fn write(&self, file: PendingFile) {
+        let mut hasher = DefaultHasher::new();
+        file.path.hash(&mut hasher);
+        let thread = hasher.finish() as usize % self.senders.len();
+        // Sending can't fail, as the threads only stop after the senders are dropped.
+        let _ = self.senders[thread].send(file);
+    }
+
+    /// Wait for all the pending files to be written, returning the first error encountered.
+    fn finish(mut self) -> Result<(), Error> {
+        self.join();
+        match self.error.lock().unwrap().take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn join(&mut self) {
+        self.senders.clear();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ParallelWriter {
+    fn drop(&mut self) {
+        // Make sure nothing is written after the extraction returns, even if it failed.
+        self.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        read_file_without_first_dir, read_zip_file_without_first_dir, unpack_without_first_dir,
+        unpack_zip_without_first_dir, windows_path_problem,
+    };
+    use failure::Error;
+    use std::io::{Cursor, Write};
+    use std::path::Path;
+    use tar::{Archive, Builder, EntryType, Header};
+    use zip::write::{FileOptions, ZipWriter};
+    use zip::ZipArchive;
+
+    /// Build an archive from `(path, kind, link target, content, mode)` entries. The paths are
+    /// written directly in the headers, bypassing the validation done by the tar crate.
+    fn archive(entries: &[(&str, EntryType, &str, &str, u32)]) -> Result<Vec<u8>, Error> {
+        let mut builder = Builder::new(Vec::new());
+        for (path, kind, link, content, mode) in entries {
+            let mut header = Header::new_gnu();
+            {
+                let gnu = header.as_gnu_mut().unwrap();
+                gnu.name[..path.len()].copy_from_slice(path.as_bytes());
+                gnu.linkname[..link.len()].copy_from_slice(link.as_bytes());
+            }
+            header.set_entry_type(*kind);
+            header.set_size(content.len() as u64);
+            header.set_mode(*mode);
+            header.set_cksum();
+            builder.append(&header, content.as_bytes())?;
+        }
+        Ok(builder.into_inner()?)
+    }
+
+    fn unpack(archive: &[u8], dest: &Path, parallel: bool) -> Result<(), Error> {
+        unpack_without_first_dir(&mut Archive::new(archive), dest, parallel)
+    }
+
+    #[test]
+    fn test_read_file() -> Result<(), Error> {
+        let archive = archive(&[
+            ("foo-1.0.0/", EntryType::Directory, "", "", 0o755),
+            (
+                "foo-1.0.0/src/Cargo.toml",
+                EntryType::Regular,
+                "",
+                "nested",
+                0o644,
+            ),
+            (
+                "foo-1.0.0/Cargo.toml",
+                EntryType::Regular,
+                "",
+                "root",
+                0o644,
+            ),
+        ])?;
+        assert_eq!(
+            read_file_without_first_dir(&mut Archive::new(&archive[..]), "Cargo.toml".as_ref())?,
+            Some("root".into())
+        );
+        assert_eq!(
+            read_file_without_first_dir(&mut Archive::new(&archive[..]), "README.md".as_ref())?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unpack() -> Result<(), Error> {
+        let content = archive(&[
+            ("foo-1.0/", EntryType::Directory, "", "", 0o755),
+            (
+                "foo-1.0/Cargo.toml",
+                EntryType::Regular,
+                "",
+                "[package]",
+                0o644,
+            ),
+            (
+                "foo-1.0/src/main.rs",
+                EntryType::Regular,
+                "",
+                "fn main() {}",
+                0o644,
+            ),
+            (
+                "foo-1.0/build.sh",
+                EntryType::Regular,
+                "",
+                "#!/bin/sh",
+                0o755,
+            ),
+        ])?;
+
+        for &parallel in &[false, true] {
+            let dir = tempfile::tempdir()?;
+            unpack(&content, dir.path(), parallel)?;
+
+            let read = |path: &str| std::fs::read_to_string(dir.path().join(path));
+            assert_eq!(read("Cargo.toml")?, "[package]");
+            assert_eq!(read("src/main.rs")?, "fn main() {}");
+            assert_eq!(read("build.sh")?, "#!/bin/sh");
+            assert!(!dir.path().join("foo-1.0").exists());
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = |path: &str| -> Result<u32, Error> {
+                    Ok(std::fs::metadata(dir.path().join(path))?
+                        .permissions()
+                        .mode()
+                        & 0o777)
+                };
+                assert_eq!(mode("build.sh")?, 0o755);
+                assert_eq!(mode("Cargo.toml")?, 0o644);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_entries_last_wins() -> Result<(), Error> {
+        let versions: Vec<String> = (0..20).map(|i| format!("// version {}", i)).collect();
+        let big = "x".repeat(super::MAX_PARALLEL_FILE_SIZE as usize + 1);
+        let mut entries: Vec<_> = versions
+            .iter()
+            .map(|content| {
+                (
+                    "foo-1.0/src/lib.rs",
+                    EntryType::Regular,
+                    "",
+                    content.as_str(),
+                    0o644,
+                )
+            })
+            .collect();
+        // A small file replaced by one too big to be written in parallel.
+        entries.push(("foo-1.0/big", EntryType::Regular, "", "small", 0o644));
+        entries.push(("foo-1.0/big", EntryType::Regular, "", &big, 0o644));
+        let content = archive(&entries)?;
+
+        for &parallel in &[false, true] {
+            let dir = tempfile::tempdir()?;
+            unpack(&content, dir.path(), parallel)?;
+
+            let read = |path: &str| std::fs::read_to_string(dir.path().join(path));
+            assert_eq!(read("src/lib.rs")?, "// version 19");
+            assert_eq!(read("big")?, big);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_path_traversal() -> Result<(), Error> {
+        for path in &[
+            "foo-1.0/../../evil",
+            "/foo-1.0/evil",
+            "foo-1.0/src/../../../evil",
+        ] {
+            let content = archive(&[(path, EntryType::Regular, "", "evil", 0o644)])?;
+            let dir = tempfile::tempdir()?;
+            let dest = dir.path().join("dest");
+            assert!(unpack(&content, &dest, false).is_err());
+            assert!(!dir.path().join("evil").exists());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_windows_path_problem() {
+        for path in &[
+            "src/lib.rs",
+            "src/auxiliary.rs",
+            "tests/con_test.rs",
+            ".gitignore",
+        ] {
+            assert_eq!(windows_path_problem(Path::new(path)), None, "{}", path);
+        }
+        for path in &[
+            "src/aux.rs",
+            "src/AUX",
+            "src/nul.tar.gz",
+            "com1/mod.rs",
+            "docs/file.",
+            "docs/file ",
+            "tests/a:b.rs",
+            "tests/what?.txt",
+            "tests/tab\t.rs",
+        ] {
+            assert!(windows_path_problem(Path::new(path)).is_some(), "{}", path);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_links() -> Result<(), Error> {
+        let content = archive(&[
+            ("foo-1.0/src/lib.rs", EntryType::Regular, "", "lib", 0o644),
+            (
+                "foo-1.0/src/alias.rs",
+                EntryType::Symlink,
+                "lib.rs",
+                "",
+                0o777,
+            ),
+            (
+                "foo-1.0/tests/lib.rs",
+                EntryType::Symlink,
+                "../src/lib.rs",
+                "",
+                0o777,
+            ),
+            (
+                "foo-1.0/copy.rs",
+                EntryType::Link,
+                "foo-1.0/src/lib.rs",
+                "",
+                0o644,
+            ),
+        ])?;
+        let dir = tempfile::tempdir()?;
+        unpack(&content, dir.path(), false)?;
+
+        let read = |path: &str| std::fs::read_to_string(dir.path().join(path));
+        assert_eq!(read("src/alias.rs")?, "lib");
+        assert_eq!(read("tests/lib.rs")?, "lib");
+        assert_eq!(read("copy.rs")?, "lib");
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_reject_escaping_links() -> Result<(), Error> {
+        let archives = vec![
+            archive(&[("foo-1.0/evil", EntryType::Symlink, "../..", "", 0o777)])?,
+            archive(&[("foo-1.0/evil", EntryType::Symlink, "/etc", "", 0o777)])?,
+            archive(&[("foo-1.0/evil", EntryType::Link, "/etc/passwd", "", 0o644)])?,
+            archive(&[(
+                "foo-1.0/evil",
+                EntryType::Link,
+                "foo-1.0/../../x",
+                "",
+                0o644,
+            )])?,
+            // Each link stays inside the destination, but following both escapes it.
+            archive(&[
+                ("foo-1.0/a/b", EntryType::Symlink, "..", "", 0o777),
+                ("foo-1.0/a/evil", EntryType::Symlink, "b/..", "", 0o777),
+            ])?,
+            archive(&[
+                ("foo-1.0/a/b", EntryType::Symlink, "..", "", 0o777),
+                ("foo-1.0/a/b/evil", EntryType::Symlink, "..", "", 0o777),
+            ])?,
+        ];
+
+        for content in &archives {
+            let dir = tempfile::tempdir()?;
+            let dest = dir.path().join("dest");
+            assert!(unpack(content, &dest, false).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unpack_zip() -> Result<(), Error> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer.add_directory("foo-1.0.0/", FileOptions::default())?;
+        writer.start_file("foo-1.0.0/Cargo.toml", FileOptions::default())?;
+        writer.write_all(b"[package]\n")?;
+        writer.start_file(
+            "foo-1.0.0/src/build.sh",
+            FileOptions::default().unix_permissions(0o755),
+        )?;
+        writer.write_all(b"#!/bin/sh\n")?;
+        let archive = writer.finish()?.into_inner();
+
+        assert_eq!(
+            read_zip_file_without_first_dir(
+                &mut ZipArchive::new(Cursor::new(&archive))?,
+                "Cargo.toml".as_ref()
+            )?,
+            Some("[package]\n".into())
+        );
+
+        let dest = tempfile::tempdir()?;
+        unpack_zip_without_first_dir(&mut ZipArchive::new(Cursor::new(&archive))?, dest.path())?;
+        assert_eq!(
+            std::fs::read_to_string(dest.path().join("Cargo.toml"))?,
+            "[package]\n"
+        );
+        let script = dest.path().join("src").join("build.sh");
+        assert_eq!(std::fs::read_to_string(&script)?, "#!/bin/sh\n");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(script.metadata()?.permissions().mode() & 0o777, 0o755);
+        }
+
+        // Paths escaping the destination are rejected like in tarballs.
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer.start_file("foo-1.0.0/../../evil", FileOptions::default())?;
+        let archive = writer.finish()?.into_inner();
+        let dest = tempfile::tempdir()?;
+        assert!(unpack_zip_without_first_dir(
+            &mut ZipArchive::new(Cursor::new(&archive))?,
+            dest.path()
+        )
+        .is_err());
+        Ok(())
+    }
+}
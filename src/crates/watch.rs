@@ -0,0 +1,248 @@
+use super::local::{copy_file, list_dir, CopyOptions};
+use failure::Error;
+use log::info;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::{fs, runtime::Handle};
+
+/// Default interval between two scans of the watched directory.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Files that require the crate to be prepared again when changed, rather than just copied.
+static PREPARE_FILES: &[&str] = &["Cargo.toml", "Cargo.lock", ".cargo"];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct FileState {
+    modified: SystemTime,
+    len: u64,
+}
+
+type Snapshot = HashMap<PathBuf, FileState>;
+
+/// Watcher of the source code of a local crate, created by
+/// [`Crate::watch`](struct.Crate.html#method.watch).
+///
+/// The watcher periodically scans the crate directory (ignoring the skipped build output
+/// directories and the files excluded from the crate), comparing the modification time and the size of each file with the previous scan.
+pub struct CrateWatcher {
+    root: PathBuf,
+    options: CopyOptions,
+    interval: Duration,
+    snapshot: Snapshot,
+    runtime: Option<Handle>,
+}
+
+impl CrateWatcher {
+    pub(super) async fn new(root: &Path, options: &CopyOptions) -> Result<Self, Error> {
+        let root = crate::utils::normalize_path(root);
+        let options = options.clone();
+        let snapshot = scan(None, root.clone(), options.clone()).await?;
+        Ok(CrateWatcher {
+            root,
+            options,
+            interval: DEFAULT_POLL_INTERVAL,
+            snapshot,
+            runtime: None,
+        })
+    }
+
+    /// Change how often the crate directory is scanned for changes. The default is 500
+    /// milliseconds.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Wait until some files of the crate change, returning which files changed since the last
+    /// call to this method (or since the watcher was created).
+    pub async fn changes(&mut self) -> Result<SourceChanges, Error> {
+        loop {
+            let interval = self.interval;
+            crate::utils::enter(self.runtime.as_ref(), || tokio::time::delay_for(interval)).await;
+
+            let snapshot = scan(
+                self.runtime.as_ref(),
+                self.root.clone(),
+                self.options.clone(),
+            )
+            .await?;
+            let changes = diff(&self.snapshot, &snapshot);
+            self.snapshot = snapshot;
+
+            if !changes.is_empty() {
+                return Ok(changes);
+            }
+        }
+    }
+
+    pub(crate) fn runtime(mut self, runtime: Option<Handle>) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+/// List of files changed in a watched crate, returned by
+/// [`CrateWatcher::changes`](struct.CrateWatcher.html#method.changes).
+///
+/// All the paths are relative to the root of the crate.
+#[derive(Debug, Default)]
+pub struct SourceChanges {
+    changed: Vec<PathBuf>,
+    removed: Vec<PathBuf>,
+}
+
+impl SourceChanges {
+    /// Files that were created or modified.
+    pub fn changed(&self) -> &[PathBuf] {
+        &self.changed
+    }
+
+    /// Files that were removed.
+    pub fn removed(&self) -> &[PathBuf] {
+        &self.removed
+    }
+
+    fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    /// Whether the changes affect how the crate is prepared, requiring a full copy.
+    pub(crate) fn requires_prepare(&self) -> bool {
+        self.changed
+            .iter()
+            .chain(self.removed.iter())
+            .any(|path| match path.components().next() {
+                Some(first) => PREPARE_FILES.iter().any(|file| first.as_os_str() == *file),
+                None => false,
+            })
+    }
+
+    /// Apply the changes made to the files in `src` to the copy of the crate in `dest`.
+    pub(crate) async fn apply(&self, src: &Path, dest: &Path) -> Result<(), Error> {
+        for path in &self.removed {
+            let dest = dest.join(path);
+            if dest.exists() {
+                info!("removing {}", dest.display());
+                fs::remove_file(&dest).await?;
+            }
+        }
+        for path in &self.changed {
+            let dest = dest.join(path);
+            info!("copying changed file {}", src.join(path).display());
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            copy_file(src.join(path), dest, false).await?;
+        }
+        Ok(())
+    }
+}
+
+async fn scan(
+    runtime: Option<&Handle>,
+    root: PathBuf,
+    options: CopyOptions,
+) -> Result<Snapshot, Error> {
+    crate::utils::spawn_blocking(runtime, move || {
+        let files = list_dir(&root, &options)?.files;
+
+        let mut snapshot = HashMap::with_capacity(files.len());
+        for file in files {
+            let metadata = root.join(&file).metadata()?;
+            let state = FileState {
+                modified: metadata.modified()?,
+                len: metadata.len(),
+            };
+            snapshot.insert(file, state);
+        }
+        Ok(snapshot)
+    })
+    .await?
+}
+
+fn diff(old: &Snapshot, new: &Snapshot) -> SourceChanges {
+    let mut changes = SourceChanges::default();
+    for (path, state) in new {
+        if old.get(path) != Some(state) {
+            changes.changed.push(path.clone());
+        }
+    }
+    for path in old.keys() {
+        if !new.contains_key(path) {
+            changes.removed.push(path.clone());
+        }
+    }
+    changes.changed.sort();
+    changes.removed.sort();
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use failure::Error;
+    use std::path::PathBuf;
+    use tokio::fs;
+
+    #[tokio::test]
+    async fn test_changes() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir(dir.path().join("src")).await?;
+        fs::create_dir(dir.path().join("target")).await?;
+        fs::write(dir.path().join("src").join("lib.rs"), b"").await?;
+        fs::write(dir.path().join("src").join("foo.rs"), b"").await?;
+
+        let old = super::scan(None, dir.path().into(), Default::default()).await?;
+        fs::write(dir.path().join("src").join("lib.rs"), b"mod bar;").await?;
+        fs::write(dir.path().join("src").join("bar.rs"), b"").await?;
+        fs::remove_file(dir.path().join("src").join("foo.rs")).await?;
+        fs::write(dir.path().join("target").join("a.out"), b"").await?;
+        let new = super::scan(None, dir.path().into(), Default::default()).await?;
+
+        let changes = super::diff(&old, &new);
+        assert_eq!(
+            changes.changed(),
+            &[PathBuf::from("src/bar.rs"), PathBuf::from("src/lib.rs")]
+        );
+        assert_eq!(changes.removed(), &[PathBuf::from("src/foo.rs")]);
+        assert!(!changes.requires_prepare());
+
+        let dest = tempfile::tempdir()?;
+        fs::create_dir(dest.path().join("src")).await?;
+        fs::write(dest.path().join("src").join("foo.rs"), b"").await?;
+        changes.apply(dir.path(), dest.path()).await?;
+        assert_eq!(
+            fs::read(dest.path().join("src").join("lib.rs")).await?,
+            b"mod bar;"
+        );
+        assert!(dest.path().join("src").join("bar.rs").exists());
+        assert!(!dest.path().join("src").join("foo.rs").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_requires_prepare() {
+        let changes = super::SourceChanges {
+            changed: vec![PathBuf::from("Cargo.toml")],
+            removed: Vec::new(),
+        };
+        assert!(changes.requires_prepare());
+
+        let changes = super::SourceChanges {
+            changed: Vec::new(),
+            removed: vec![PathBuf::from(".cargo/config")],
+        };
+        assert!(changes.requires_prepare());
+
+        let changes = super::SourceChanges {
+            changed: vec![PathBuf::from("src/Cargo.toml")],
+            removed: Vec::new(),
+        };
+        assert!(!changes.requires_prepare());
+    }
+}
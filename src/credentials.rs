@@ -0,0 +1,208 @@
+use crate::cmd::Command;
+use crate::Workspace;
+use gix::credentials::helper::{Action, Cascade};
+use gix::credentials::protocol::{Outcome, Result as CredentialsResult};
+use log::warn;
+use std::path::PathBuf;
+
+/// Environment variables the token credentials are passed to git with, to avoid leaking them in
+/// the command line.
+const USERNAME_ENV: &str = "RUSTWIDE_GIT_USERNAME";
+const TOKEN_ENV: &str = "RUSTWIDE_GIT_TOKEN";
+
+/// SSH command used when authenticating over SSH, failing instead of prompting for passwords,
+/// passphrases or unknown host keys.
+const SSH_COMMAND: &str = "ssh -o BatchMode=yes";
+
+/// Credentials used by git when interacting with remote repositories, configured for the whole
+/// workspace with
+/// [`WorkspaceBuilder::git_credentials`](struct.WorkspaceBuilder.html#method.git_credentials), or
+/// for a single git crate with [`Crate::git_credentials`](struct.Crate.html#method.git_credentials).
+#[derive(Clone)]
+#[non_exhaustive]
+pub enum GitCredentials {
+    /// Never provide any credentials: fetching a repository requiring authentication fails with
+    /// [`PrepareError::PrivateGitRepository`](enum.PrepareError.html#variant.PrivateGitRepository).
+    /// This is the default.
+    Null,
+    /// Ask the provided [git credential helper](https://git-scm.com/docs/gitcredentials) for the
+    /// credentials.
+    Helper(PathBuf),
+    /// Authenticate over HTTPS with the provided username and token, for example a GitHub
+    /// personal access token. The token is redacted from the logs, and it's never sent over
+    /// unencrypted HTTP.
+    Token {
+        /// Username to authenticate with.
+        username: String,
+        /// Token to authenticate with.
+        token: String,
+    },
+    /// Authenticate over SSH with the private key at the provided path, which must not be
+    /// protected by a passphrase. The host keys of the servers must be already present in the
+    /// `known_hosts` file of the user running rustwide.
+    SshKey(PathBuf),
+    /// Authenticate over SSH with the keys of the agent rustwide's process has access to, through
+    /// the `SSH_AUTH_SOCK` environment variable. The host keys of the servers must be already
+    /// present in the `known_hosts` file of the user running rustwide.
+    SshAgent,
+}
+
+impl Default for GitCredentials {
+    fn default() -> Self {
+        GitCredentials::Null
+    }
+}
+
+impl GitCredentials {
+    /// Configure a git command to use these credentials.
+    pub(crate) fn configure<'w, 'pl>(
+        &self,
+        workspace: &Workspace,
+        cmd: Command<'w, 'pl>,
+    ) -> Command<'w, 'pl> {
+        // The first `-c credential.helper=` clears the list of existing helpers
+        let cmd = cmd.args(&["-c", "credential.helper="]);
+        let null_helper = || {
+            format!(
+                "credential.helper={}",
+                helper_path(crate::tools::GIT_CREDENTIAL_NULL.binary_path(workspace))
+            )
+        };
+
+        match self {
+            GitCredentials::Null => cmd.args(&["-c".into(), null_helper()]),
+            GitCredentials::Helper(path) => cmd
+                .args(&[
+                    "-c".into(),
+                    format!("credential.helper={}", helper_path(path.clone())),
+                ])
+                .env("GIT_TERMINAL_PROMPT", "0"),
+            GitCredentials::Token { username, token } => cmd
+                .args(&[
+                    "-c".into(),
+                    format!(
+                        "credential.helper=!f() {{ echo username=${}; echo password=${}; }}; f",
+                        USERNAME_ENV, TOKEN_ENV
+                    ),
+                ])
+                .env(USERNAME_ENV, username)
+                .env_redacted(TOKEN_ENV, token)
+                .env("GIT_TERMINAL_PROMPT", "0"),
+            // HTTPS remotes never get credentials when authenticating over SSH.
+            GitCredentials::SshKey(path) => cmd.args(&["-c".into(), null_helper()]).env(
+                "GIT_SSH_COMMAND",
+                format!(
+                    "{} -i {} -o IdentitiesOnly=yes",
+                    SSH_COMMAND,
+                    shell_quote(&helper_path(path.clone()))
+                ),
+            ),
+            GitCredentials::SshAgent => {
+                let cmd = cmd
+                    .args(&["-c".into(), null_helper()])
+                    .env("GIT_SSH_COMMAND", SSH_COMMAND);
+                match std::env::var_os("SSH_AUTH_SOCK") {
+                    Some(socket) => cmd.env("SSH_AUTH_SOCK", socket),
+                    None => {
+                        warn!("SSH_AUTH_SOCK is not set, the SSH agent won't be used");
+                        cmd
+                    }
+                }
+            }
+        }
+    }
+
+    /// Return the function gitoxide calls to get the credentials of remote repositories over
+    /// HTTPS. Users are never prompted: without credentials the authentication fails.
+    pub(crate) fn authenticate(
+        &self,
+    ) -> impl FnMut(Action) -> CredentialsResult + Clone + Send + 'static {
+        let credentials = self.clone();
+        move |action| match (&credentials, action) {
+            (GitCredentials::Helper(path), action) => Cascade::default()
+                .extend(Some(gix::credentials::Program::from_custom_definition(
+                    helper_path(path.clone()),
+                )))
+                .invoke(
+                    action,
+                    gix::prompt::Options {
+                        askpass: None,
+                        mode: gix::prompt::Mode::Disable,
+                    },
+                ),
+            (GitCredentials::Token { username, token }, Action::Get(context)) => {
+                Ok(Some(Outcome {
+                    identity: gix::sec::identity::Account {
+                        username: username.clone(),
+                        password: token.clone(),
+                        oauth_refresh_token: None,
+                    },
+                    next: context.into(),
+                }))
+            }
+            // Tokens are never stored nor erased, and HTTPS remotes never get credentials when
+            // authenticating over SSH.
+            _ => Ok(None),
+        }
+    }
+
+    /// Return the configuration overrides making gitoxide run `ssh` with these credentials for
+    /// SSH remotes.
+    pub(crate) fn ssh_config(&self) -> Vec<String> {
+        let command = match self {
+            GitCredentials::SshKey(path) => format!(
+                "{} -i {} -o IdentitiesOnly=yes",
+                SSH_COMMAND,
+                shell_quote(&helper_path(path.clone()))
+            ),
+            GitCredentials::SshAgent => {
+                // The agent socket is inherited by `ssh` from rustwide's environment.
+                if std::env::var_os("SSH_AUTH_SOCK").is_none() {
+                    warn!("SSH_AUTH_SOCK is not set, the SSH agent won't be used");
+                }
+                SSH_COMMAND.to_string()
+            }
+            _ => SSH_COMMAND.to_string(),
+        };
+        vec![
+            format!("core.sshCommand={}", command),
+            "ssh.variant=ssh".to_string(),
+        ]
+    }
+}
+
+/// Quote a string so it's treated as a single argument by the shell running the SSH command.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+fn helper_path(path: PathBuf) -> String {
+    // Git runs the helpers through a shell even on Windows, which doesn't like backslashes.
+    path.to_str().unwrap().replace('\\', "/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{shell_quote, GitCredentials};
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(
+            shell_quote("/home/bot/.ssh/id_ed25519"),
+            "'/home/bot/.ssh/id_ed25519'"
+        );
+        assert_eq!(shell_quote("/keys/bot's key"), "'/keys/bot'\\''s key'");
+    }
+
+    #[test]
+    fn test_ssh_config() {
+        assert_eq!(
+            GitCredentials::Null.ssh_config(),
+            vec!["core.sshCommand=ssh -o BatchMode=yes", "ssh.variant=ssh"]
+        );
+        assert_eq!(
+            GitCredentials::SshKey("/keys/id_ed25519".into()).ssh_config()[0],
+            "core.sshCommand=ssh -o BatchMode=yes -i '/keys/id_ed25519' -o IdentitiesOnly=yes"
+        );
+    }
+}
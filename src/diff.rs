@@ -0,0 +1,970 @@
+//! Structured differences between two builds of the same crate.
+//!
+//! A [`BuildSummary`] collects the outcome of a build, the diagnostics emitted by the compiler
+//! and the results of the tests, by processing the output lines of the commands executed during
+//! the build. Two summaries of the same crate (built for example with different toolchains or
+//! different flags) can then be compared, producing a [`BuildDiff`] with the changes between
+//! them, which is the data regression reports are built from.
+//!
+//! Diagnostics are only collected from cargo's JSON messages, so cargo needs to be invoked with
+//! `--message-format=json` for them to be included. Test results are collected both from the
+//! human-readable output of libtest and from its JSON events, which is also the format the
+//! results of [`NextestRunner`](../struct.NextestRunner.html) are parsed from.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use rustwide::{diff::BuildSummary, Build};
+//! # use std::error::Error;
+//!
+//! # async fn run(build: &Build<'_>) -> Result<BuildSummary, Box<dyn Error>> {
+//! let mut summary = BuildSummary::new();
+//! let res = build
+//!     .cargo()
+//!     .args(&["test", "--message-format=json"])
+//!     .process_lines(&mut |line, _| summary.process_line(line))
+//!     .run()
+//!     .await;
+//! summary.set_success(res.is_ok());
+//! # Ok(summary)
+//! # }
+//! ```
+//!
+//! Diagnostics can also be compared as a [`DiagnosticSet`], which deduplicates them by their
+//! [`DiagnosticFingerprint`]. Fingerprints ignore the details changing between otherwise
+//! identical runs (like line numbers, the location of cargo's cache and the versions of the
+//! dependencies), and keep the diagnostics of the crate apart from the ones of its dependencies.
+//!
+//! [`BuildSummary`]: struct.BuildSummary.html
+//! [`BuildDiff`]: struct.BuildDiff.html
+//! [`DiagnosticSet`]: struct.DiagnosticSet.html
+//! [`DiagnosticFingerprint`]: struct.DiagnosticFingerprint.html
+
+use crate::comparison::classify;
+use crate::Comparison;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Primary location of a [`Diagnostic`](struct.Diagnostic.html) in the source code.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DiagnosticSpan {
+    file: String,
+    line: usize,
+    column: usize,
+}
+
+impl DiagnosticSpan {
+    /// Return the path of the file, relative to the package root.
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+
+    /// Return the line the span starts at (starting from 1).
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Return the column the span starts at (starting from 1).
+    pub fn column(&self) -> usize {
+        self.column
+    }
+}
+
+/// Error or warning emitted by the compiler during a build.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    level: String,
+    code: Option<String>,
+    message: String,
+    span: Option<DiagnosticSpan>,
+    #[serde(default)]
+    package_id: Option<String>,
+}
+
+impl Diagnostic {
+    /// Return the level of the diagnostic, like `error` or `warning`.
+    pub fn level(&self) -> &str {
+        &self.level
+    }
+
+    /// Return the code of the diagnostic, like `E0308` or `unused_variables`, if it has one.
+    pub fn code(&self) -> Option<&str> {
+        self.code.as_deref()
+    }
+
+    /// Return the main message of the diagnostic.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Return the primary span of the diagnostic, if it points to the source code.
+    pub fn span(&self) -> Option<&DiagnosticSpan> {
+        self.span.as_ref()
+    }
+
+    /// Return the ID of the package being compiled when the diagnostic was emitted, if cargo
+    /// reported it.
+    pub fn package_id(&self) -> Option<&str> {
+        self.package_id.as_deref()
+    }
+
+    /// Return whether the diagnostic was emitted by the crate being built or by one of its
+    /// dependencies. Packages loaded from a path, like the members of a workspace, are considered
+    /// part of the crate being built.
+    pub fn origin(&self) -> DiagnosticOrigin {
+        match &self.package_id {
+            Some(id) if !id.contains("path+file:") => DiagnosticOrigin::Dependency {
+                name: crate::timings::parse_package_id(id)
+                    .map(|(name, _)| name)
+                    .unwrap_or_else(|| id.clone()),
+            },
+            _ => DiagnosticOrigin::Root,
+        }
+    }
+
+    /// Return the fingerprint of the diagnostic, identifying it across different runs.
+    pub fn fingerprint(&self) -> DiagnosticFingerprint {
+        DiagnosticFingerprint {
+            origin: self.origin(),
+            level: self.level.clone(),
+            code: self.code.clone(),
+            file: self
+                .span
+                .as_ref()
+                .map(|span| normalize_span_file(&span.file)),
+            template: message_template(&self.message),
+        }
+    }
+
+    /// Diagnostics are matched across builds by code and span, as their message often changes
+    /// between compiler versions. The message is used only for diagnostics without a code.
+    fn key(&self) -> (&str, Option<&DiagnosticSpan>) {
+        (
+            self.code.as_deref().unwrap_or(&self.message),
+            self.span.as_ref(),
+        )
+    }
+}
+
+/// Package a [`Diagnostic`](struct.Diagnostic.html) was emitted by.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum DiagnosticOrigin {
+    /// The crate being built, or one of the other packages loaded from a path.
+    Root,
+    /// A dependency loaded from a registry or a git repository.
+    Dependency {
+        /// Name of the dependency.
+        name: String,
+    },
+}
+
+/// Identity of a [`Diagnostic`](struct.Diagnostic.html) that stays the same across runs,
+/// returned by [`Diagnostic::fingerprint`](struct.Diagnostic.html#method.fingerprint).
+///
+/// The fingerprint is made of the origin of the diagnostic, its level and code, the file of its
+/// primary span and its message template. The file is made relative to the crate (or to the
+/// dependency) it belongs to, and the version of dependencies is removed from it. The template
+/// replaces the code snippets quoted in the message and the numbers with placeholders, so that
+/// for example ``expected `u8`, found `i32` `` and ``expected `u16`, found `i64` `` have the same
+/// template. Line and column numbers are not part of the fingerprint.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DiagnosticFingerprint {
+    origin: DiagnosticOrigin,
+    level: String,
+    code: Option<String>,
+    file: Option<String>,
+    template: String,
+}
+
+impl DiagnosticFingerprint {
+    /// Return the package the diagnostic was emitted by.
+    pub fn origin(&self) -> &DiagnosticOrigin {
+        &self.origin
+    }
+
+    /// Check whether the diagnostic was emitted by the crate being built.
+    pub fn is_root(&self) -> bool {
+        self.origin == DiagnosticOrigin::Root
+    }
+
+    /// Return the level of the diagnostic, like `error` or `warning`.
+    pub fn level(&self) -> &str {
+        &self.level
+    }
+
+    /// Return the code of the diagnostic, if it has one.
+    pub fn code(&self) -> Option<&str> {
+        self.code.as_deref()
+    }
+
+    /// Return the normalized path of the file of the diagnostic's primary span, if it has one.
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    /// Return the message of the diagnostic with the variable parts replaced by placeholders.
+    pub fn template(&self) -> &str {
+        &self.template
+    }
+
+    /// Return a short hexadecimal hash of the fingerprint, suitable as a stable identifier of the
+    /// diagnostic in reports and databases.
+    pub fn id(&self) -> String {
+        let origin = match &self.origin {
+            DiagnosticOrigin::Root => "",
+            DiagnosticOrigin::Dependency { name } => name.as_str(),
+        };
+        let parts = [
+            origin,
+            &self.level,
+            self.code.as_deref().unwrap_or(""),
+            self.file.as_deref().unwrap_or(""),
+            &self.template,
+        ];
+        let mut id = format!("{:x}", Sha256::digest(parts.join("\0").as_bytes()));
+        id.truncate(16);
+        id
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct FingerprintCount {
+    fingerprint: DiagnosticFingerprint,
+    count: usize,
+}
+
+/// Set of diagnostics deduplicated by their [fingerprint](struct.DiagnosticFingerprint.html),
+/// counting how many times each of them was emitted.
+///
+/// Sets can be stored (for example serialized as JSON) and compared with the set of a later run,
+/// to find the diagnostics that appeared or disappeared. The diagnostics of the crate being
+/// built and the ones of its dependencies are kept apart, as the latter are usually out of the
+/// crate's control.
+///
+/// # Example
+///
+/// ```no_run
+/// use rustwide::diff::{BuildSummary, DiagnosticSet};
+///
+/// # fn run(before: &BuildSummary, after: &BuildSummary) {
+/// let before = DiagnosticSet::new(before.diagnostics());
+/// let after = DiagnosticSet::new(after.diagnostics());
+/// for fingerprint in before.added_in(&after) {
+///     if fingerprint.is_root() {
+///         println!("new diagnostic {}: {}", fingerprint.id(), fingerprint.template());
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiagnosticSet {
+    diagnostics: BTreeMap<String, FingerprintCount>,
+}
+
+impl DiagnosticSet {
+    /// Create a set containing the provided diagnostics.
+    pub fn new(diagnostics: &[Diagnostic]) -> Self {
+        let mut set = DiagnosticSet::default();
+        for diagnostic in diagnostics {
+            set.insert(diagnostic);
+        }
+        set
+    }
+
+    /// Add a diagnostic to the set, increasing its count if it's already present.
+    pub fn insert(&mut self, diagnostic: &Diagnostic) {
+        let fingerprint = diagnostic.fingerprint();
+        self.diagnostics
+            .entry(fingerprint.id())
+            .or_insert(FingerprintCount {
+                fingerprint,
+                count: 0,
+            })
+            .count += 1;
+    }
+
+    /// Return the fingerprints in the set along with how many times each of them was emitted,
+    /// ordered by their ID.
+    pub fn iter(&self) -> impl Iterator<Item = (&DiagnosticFingerprint, usize)> {
+        self.diagnostics
+            .values()
+            .map(|entry| (&entry.fingerprint, entry.count))
+    }
+
+    /// Return the fingerprints of the diagnostics emitted by the crate being built.
+    pub fn root(&self) -> impl Iterator<Item = &DiagnosticFingerprint> {
+        self.iter()
+            .map(|(fingerprint, _)| fingerprint)
+            .filter(|fingerprint| fingerprint.is_root())
+    }
+
+    /// Return the fingerprints of the diagnostics emitted by the dependencies.
+    pub fn dependencies(&self) -> impl Iterator<Item = &DiagnosticFingerprint> {
+        self.iter()
+            .map(|(fingerprint, _)| fingerprint)
+            .filter(|fingerprint| !fingerprint.is_root())
+    }
+
+    /// Return how many times the diagnostic with the provided fingerprint was emitted.
+    pub fn count(&self, fingerprint: &DiagnosticFingerprint) -> usize {
+        self.diagnostics
+            .get(&fingerprint.id())
+            .map_or(0, |entry| entry.count)
+    }
+
+    /// Return the number of distinct diagnostics in the set.
+    pub fn len(&self) -> usize {
+        self.diagnostics.len()
+    }
+
+    /// Check whether the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+
+    /// Return the fingerprints present in the later set but not in this one.
+    pub fn added_in<'a>(&self, after: &'a DiagnosticSet) -> Vec<&'a DiagnosticFingerprint> {
+        after
+            .diagnostics
+            .iter()
+            .filter(|(id, _)| !self.diagnostics.contains_key(*id))
+            .map(|(_, entry)| &entry.fingerprint)
+            .collect()
+    }
+
+    /// Return the fingerprints present in this set but not in the later one.
+    pub fn removed_in<'a>(&'a self, after: &DiagnosticSet) -> Vec<&'a DiagnosticFingerprint> {
+        after.added_in(self)
+    }
+}
+
+/// Result of a single test.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum TestOutcome {
+    /// The test passed.
+    Passed,
+    /// The test failed.
+    Failed,
+    /// The test was ignored.
+    Ignored,
+}
+
+/// Summary of a build, collected from the output of the commands it executed. See the [module
+/// documentation](index.html) for an example.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BuildSummary {
+    success: bool,
+    diagnostics: Vec<Diagnostic>,
+    tests: BTreeMap<String, TestOutcome>,
+}
+
+impl BuildSummary {
+    /// Create a new, empty summary of a successful build.
+    pub fn new() -> Self {
+        BuildSummary {
+            success: true,
+            diagnostics: Vec::new(),
+            tests: BTreeMap::new(),
+        }
+    }
+
+    /// Record whether the build completed successfully.
+    pub fn set_success(&mut self, success: bool) {
+        self.success = success;
+    }
+
+    /// Process a line of output of a command, recording the diagnostic or the test result it
+    /// contains. Lines without either of them are ignored.
+    pub fn process_line(&mut self, line: &str) {
+        if let Some(diagnostic) = parse_diagnostic(line) {
+            // Cargo emits the same diagnostic once per target sharing the source file.
+            if !self.diagnostics.contains(&diagnostic) {
+                self.diagnostics.push(diagnostic);
+            }
+        } else if let Some((name, outcome)) = parse_test_result(line) {
+            self.tests.insert(name.into(), outcome);
+        } else if let Some((name, outcome)) = parse_test_event(line) {
+            self.tests.insert(name, outcome);
+        }
+    }
+
+    /// Check whether the build completed successfully.
+    pub fn success(&self) -> bool {
+        self.success
+    }
+
+    /// Return the diagnostics emitted during the build, in the order they were emitted.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Return the result of each test executed during the build, keyed by the name of the test.
+    pub fn tests(&self) -> &BTreeMap<String, TestOutcome> {
+        &self.tests
+    }
+
+    fn diagnostic_keys(&self) -> BTreeSet<(&str, Option<&DiagnosticSpan>)> {
+        self.diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.key())
+            .collect()
+    }
+
+    /// Compare this build (the baseline) with a later one, returning the changes between them.
+    pub fn diff(&self, after: &BuildSummary) -> BuildDiff {
+        let (before_keys, after_keys) = (self.diagnostic_keys(), after.diagnostic_keys());
+
+        let mut test_changes = Vec::new();
+        for name in self.tests.keys().chain(
+            after
+                .tests
+                .keys()
+                .filter(|name| !self.tests.contains_key(*name)),
+        ) {
+            let (before, after) = (self.tests.get(name), after.tests.get(name));
+            if before != after {
+                test_changes.push(TestChange {
+                    name: name.clone(),
+                    before: before.cloned(),
+                    after: after.cloned(),
+                });
+            }
+        }
+
+        BuildDiff {
+            outcome: classify(vec![self.success, after.success].into_iter()),
+            new_diagnostics: after
+                .diagnostics
+                .iter()
+                .filter(|diagnostic| !before_keys.contains(&diagnostic.key()))
+                .cloned()
+                .collect(),
+            removed_diagnostics: self
+                .diagnostics
+                .iter()
+                .filter(|diagnostic| !after_keys.contains(&diagnostic.key()))
+                .cloned()
+                .collect(),
+            test_changes,
+        }
+    }
+}
+
+/// Change of the result of a test between two builds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TestChange {
+    name: String,
+    before: Option<TestOutcome>,
+    after: Option<TestOutcome>,
+}
+
+impl TestChange {
+    /// Return the name of the test.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the result of the test in the baseline build, or `None` if it wasn't executed.
+    pub fn before(&self) -> Option<TestOutcome> {
+        self.before
+    }
+
+    /// Return the result of the test in the later build, or `None` if it wasn't executed.
+    pub fn after(&self) -> Option<TestOutcome> {
+        self.after
+    }
+}
+
+/// Changes between two builds, created by [`BuildSummary::diff`](struct.BuildSummary.html#method.diff).
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct BuildDiff {
+    outcome: Comparison,
+    new_diagnostics: Vec<Diagnostic>,
+    removed_diagnostics: Vec<Diagnostic>,
+    test_changes: Vec<TestChange>,
+}
+
+impl BuildDiff {
+    /// Return how the outcome of the build changed.
+    pub fn outcome(&self) -> Comparison {
+        self.outcome
+    }
+
+    /// Return the diagnostics emitted only by the later build.
+    pub fn new_diagnostics(&self) -> &[Diagnostic] {
+        &self.new_diagnostics
+    }
+
+    /// Return the diagnostics emitted only by the baseline build.
+    pub fn removed_diagnostics(&self) -> &[Diagnostic] {
+        &self.removed_diagnostics
+    }
+
+    /// Return the tests whose result changed, including the ones executed by only one build.
+    pub fn test_changes(&self) -> &[TestChange] {
+        &self.test_changes
+    }
+
+    /// Check whether nothing changed between the two builds.
+    pub fn is_empty(&self) -> bool {
+        match self.outcome {
+            Comparison::SamePass | Comparison::SameFail => {}
+            _ => return false,
+        }
+        self.new_diagnostics.is_empty()
+            && self.removed_diagnostics.is_empty()
+            && self.test_changes.is_empty()
+    }
+}
+
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    package_id: Option<String>,
+    message: Option<CompilerMessage>,
+}
+
+#[derive(Deserialize)]
+struct CompilerMessage {
+    message: String,
+    level: String,
+    code: Option<CompilerCode>,
+    spans: Vec<CompilerSpan>,
+}
+
+#[derive(Deserialize)]
+struct CompilerCode {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct CompilerSpan {
+    file_name: String,
+    line_start: usize,
+    column_start: usize,
+    is_primary: bool,
+}
+
+/// Parse a `compiler-message` emitted by cargo with `--message-format=json`.
+fn parse_diagnostic(line: &str) -> Option<Diagnostic> {
+    if !line.starts_with('{') {
+        return None;
+    }
+    let parsed: CargoMessage = serde_json::from_str(line).ok()?;
+    if parsed.reason != "compiler-message" {
+        return None;
+    }
+    let message = parsed.message?;
+
+    Some(Diagnostic {
+        level: message.level,
+        code: message.code.map(|code| code.code),
+        message: message.message,
+        span: message
+            .spans
+            .into_iter()
+            .find(|span| span.is_primary)
+            .map(|span| DiagnosticSpan {
+                file: span.file_name,
+                line: span.line_start,
+                column: span.column_start,
+            }),
+        package_id: parsed.package_id,
+    })
+}
+
+/// Normalize the path of a span's file, removing the parts depending on the host and on the
+/// versions of the dependencies. Files of registry dependencies are made relative to the
+/// registry's source directory without the version (like `serde/src/de.rs`), files of git
+/// dependencies are made relative to their checkout, and the other files are made relative to
+/// the source directory inside the sandbox.
+fn normalize_span_file(file: &str) -> String {
+    let file = file.replace('\\', "/");
+
+    if let Some(position) = file.find("/registry/src/") {
+        let mut components = file[position + "/registry/src/".len()..].splitn(3, '/');
+        if let (Some(_index), Some(package), Some(rest)) =
+            (components.next(), components.next(), components.next())
+        {
+            return format!("{}/{}", strip_version(package), rest);
+        }
+    }
+    if let Some(position) = file.find("/git/checkouts/") {
+        let mut components = file[position + "/git/checkouts/".len()..].splitn(3, '/');
+        if let (Some(_repo), Some(_rev), Some(rest)) =
+            (components.next(), components.next(), components.next())
+        {
+            return rest.to_string();
+        }
+    }
+
+    let work_dir = crate::cmd::container_dirs::WORK_DIR
+        .to_string_lossy()
+        .replace('\\', "/");
+    match file.strip_prefix(&work_dir) {
+        Some(rest) => rest.trim_start_matches('/').to_string(),
+        None => file,
+    }
+}
+
+/// Remove the version from the name of a directory in cargo's registry, like `serde-1.0.104`.
+fn strip_version(package: &str) -> &str {
+    package
+        .match_indices('-')
+        .find(|(position, _)| {
+            package[position + 1..]
+                .chars()
+                .next()
+                .map_or(false, |c| c.is_ascii_digit())
+        })
+        .map_or(package, |(position, _)| &package[..position])
+}
+
+/// Replace the variable parts of a diagnostic's message (the code snippets quoted with
+/// backticks, and the numbers) with placeholders.
+fn message_template(message: &str) -> String {
+    let mut template = String::with_capacity(message.len());
+    let mut quoted = false;
+    let mut previous: Option<char> = None;
+    let mut chars = message.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '`' {
+            template.push_str(if quoted { "_`" } else { "`" });
+            quoted = !quoted;
+        } else if quoted {
+            // The content of code snippets is replaced by the placeholder.
+        } else if c.is_ascii_digit() && !previous.map_or(false, |p| p.is_alphanumeric() || p == '_')
+        {
+            while chars.peek().map_or(false, |c| c.is_ascii_digit()) {
+                chars.next();
+            }
+            template.push('N');
+        } else {
+            template.push(c);
+        }
+        previous = Some(c);
+    }
+    template
+}
+
+#[derive(Deserialize)]
+struct TestEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    event: String,
+    name: Option<String>,
+}
+
+/// Parse the JSON event libtest (or nextest, with `--message-format libtest-json`) emits when a
+/// test finishes, like `{"type":"test","event":"ok","name":"foo::bar"}`.
+fn parse_test_event(line: &str) -> Option<(String, TestOutcome)> {
+    if !line.starts_with('{') {
+        return None;
+    }
+    let event: TestEvent = serde_json::from_str(line).ok()?;
+    if event.kind != "test" {
+        return None;
+    }
+    let outcome = match event.event.as_str() {
+        "ok" => TestOutcome::Passed,
+        "failed" | "timeout" => TestOutcome::Failed,
+        "ignored" => TestOutcome::Ignored,
+        _ => return None,
+    };
+
+    // Nextest prefixes the test names with the binary they're in, like `foo::bin/foo$bar`, while
+    // libtest only prints the name of the test: the prefix is removed to keep them comparable.
+    let name = event.name?;
+    let name = match name.find('$') {
+        Some(separator) => name[separator + 1..].to_string(),
+        None => name,
+    };
+    Some((name, outcome))
+}
+
+/// Parse the line libtest prints for each test, like `test foo::bar ... ok`.
+fn parse_test_result(line: &str) -> Option<(&str, TestOutcome)> {
+    let rest = line.strip_prefix("test ")?;
+    let separator = rest.find(" ... ")?;
+    let (name, result) = (&rest[..separator], &rest[separator + 5..]);
+    let outcome = if result == "ok" {
+        TestOutcome::Passed
+    } else if result == "FAILED" {
+        TestOutcome::Failed
+    } else if result.starts_with("ignored") {
+        TestOutcome::Ignored
+    } else {
+        return None;
+    };
+    Some((name, outcome))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        message_template, normalize_span_file, parse_diagnostic, parse_test_event,
+        parse_test_result, BuildSummary, DiagnosticOrigin, DiagnosticSet, TestOutcome,
+    };
+    use crate::Comparison;
+
+    fn message(code: Option<&str>, message: &str, line: usize) -> String {
+        serde_json::json!({
+            "reason": "compiler-message",
+            "package_id": "foo 0.1.0 (path+file:///foo)",
+            "message": {
+                "message": message,
+                "level": "error",
+                "code": code.map(|code| serde_json::json!({ "code": code, "explanation": null })),
+                "spans": [
+                    { "file_name": "src/main.rs", "line_start": 1, "column_start": 1, "is_primary": false },
+                    { "file_name": "src/lib.rs", "line_start": line, "column_start": 5, "is_primary": true },
+                ],
+                "rendered": message,
+            },
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_parse_diagnostic() {
+        let diagnostic = parse_diagnostic(&message(Some("E0308"), "mismatched types", 3)).unwrap();
+        assert_eq!(diagnostic.level(), "error");
+        assert_eq!(diagnostic.code(), Some("E0308"));
+        assert_eq!(diagnostic.message(), "mismatched types");
+        let span = diagnostic.span().unwrap();
+        assert_eq!(
+            (span.file(), span.line(), span.column()),
+            ("src/lib.rs", 3, 5)
+        );
+
+        assert!(parse_diagnostic("{\"reason\":\"build-finished\",\"success\":true}").is_none());
+        assert!(parse_diagnostic("   Compiling foo v0.1.0").is_none());
+    }
+
+    fn dependency_message(file: &str, message: &str, line: usize) -> String {
+        serde_json::json!({
+            "reason": "compiler-message",
+            "package_id": "registry+https://github.com/rust-lang/crates.io-index#bar@1.2.3",
+            "message": {
+                "message": message,
+                "level": "warning",
+                "code": null,
+                "spans": [
+                    { "file_name": file, "line_start": line, "column_start": 1, "is_primary": true },
+                ],
+                "rendered": message,
+            },
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_message_template() {
+        assert_eq!(
+            message_template("expected `u8`, found `i32`"),
+            "expected `_`, found `_`"
+        );
+        assert_eq!(
+            message_template("this function takes 2 arguments but 10 were supplied"),
+            "this function takes N arguments but N were supplied"
+        );
+        assert_eq!(
+            message_template("unused import: u32x4"),
+            "unused import: u32x4"
+        );
+    }
+
+    #[test]
+    fn test_normalize_span_file() {
+        assert_eq!(normalize_span_file("src/lib.rs"), "src/lib.rs");
+        assert_eq!(
+            normalize_span_file(
+                "/opt/rustwide/cargo-home/registry/src/index.crates.io-6f17d22bba15001f/\
+                 serde-1.0.104/src/de/mod.rs"
+            ),
+            "serde/src/de/mod.rs"
+        );
+        assert_eq!(
+            normalize_span_file(
+                "/opt/rustwide/cargo-home/registry/src/github.com-1ecc6299db9ec823/\
+                 tokio-util-0.3.1/src/lib.rs"
+            ),
+            "tokio-util/src/lib.rs"
+        );
+        assert_eq!(
+            normalize_span_file(
+                "/opt/rustwide/cargo-home/git/checkouts/foo-1234/abcdef0/src/lib.rs"
+            ),
+            "src/lib.rs"
+        );
+        #[cfg(not(windows))]
+        assert_eq!(
+            normalize_span_file("/opt/rustwide/workdir/src/main.rs"),
+            "src/main.rs"
+        );
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let root = parse_diagnostic(&message(Some("E0308"), "expected `u8`, found `i32`", 3))
+            .unwrap()
+            .fingerprint();
+        assert!(root.is_root());
+        assert_eq!(root.level(), "error");
+        assert_eq!(root.code(), Some("E0308"));
+        assert_eq!(root.file(), Some("src/lib.rs"));
+        assert_eq!(root.template(), "expected `_`, found `_`");
+        assert_eq!(root.id().len(), 16);
+
+        // The fingerprint doesn't depend on the line or on the quoted snippets.
+        let moved = parse_diagnostic(&message(Some("E0308"), "expected `u16`, found `i64`", 30))
+            .unwrap()
+            .fingerprint();
+        assert_eq!(root, moved);
+        assert_eq!(root.id(), moved.id());
+
+        let dependency = parse_diagnostic(&dependency_message(
+            "/cargo-home/registry/src/index-1234/bar-1.2.3/src/lib.rs",
+            "unused variable: `x`",
+            1,
+        ))
+        .unwrap()
+        .fingerprint();
+        assert!(!dependency.is_root());
+        assert_eq!(
+            dependency.origin(),
+            &DiagnosticOrigin::Dependency { name: "bar".into() }
+        );
+        assert_eq!(dependency.file(), Some("bar/src/lib.rs"));
+        assert_ne!(root.id(), dependency.id());
+    }
+
+    #[test]
+    fn test_diagnostic_set() {
+        let mut before = BuildSummary::new();
+        before.process_line(&message(Some("E0308"), "expected `u8`, found `i32`", 3));
+        before.process_line(&message(Some("E0308"), "expected `u16`, found `i32`", 8));
+        before.process_line(&dependency_message(
+            "/cargo-home/registry/src/index-1234/bar-1.2.3/src/lib.rs",
+            "unused variable: `x`",
+            1,
+        ));
+        let before = DiagnosticSet::new(before.diagnostics());
+        assert_eq!(before.len(), 2);
+        assert_eq!(before.root().count(), 1);
+        assert_eq!(before.dependencies().count(), 1);
+        let root = before.root().next().unwrap().clone();
+        assert_eq!(before.count(&root), 2);
+
+        let mut after = BuildSummary::new();
+        // The dependency was updated, and the diagnostic moved.
+        after.process_line(&dependency_message(
+            "/cargo-home/registry/src/index-1234/bar-1.3.0/src/lib.rs",
+            "unused variable: `y`",
+            5,
+        ));
+        after.process_line(&message(Some("E0599"), "no method named `foo`", 7));
+        let after = DiagnosticSet::new(after.diagnostics());
+
+        let added = before.added_in(&after);
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].code(), Some("E0599"));
+        assert_eq!(before.removed_in(&after), vec![&root]);
+
+        let json = serde_json::to_string(&after).unwrap();
+        assert_eq!(serde_json::from_str::<DiagnosticSet>(&json).unwrap(), after);
+    }
+
+    #[test]
+    fn test_parse_test_result() {
+        assert_eq!(
+            parse_test_result("test tests::foo ... ok"),
+            Some(("tests::foo", TestOutcome::Passed))
+        );
+        assert_eq!(
+            parse_test_result("test src/lib.rs - foo (line 3) ... FAILED"),
+            Some(("src/lib.rs - foo (line 3)", TestOutcome::Failed))
+        );
+        assert_eq!(
+            parse_test_result("test bar ... ignored, slow"),
+            Some(("bar", TestOutcome::Ignored))
+        );
+        assert_eq!(parse_test_result("test result: ok. 1 passed"), None);
+    }
+
+    #[test]
+    fn test_parse_test_event() {
+        assert_eq!(
+            parse_test_event(r#"{"type":"test","event":"ok","name":"tests::foo"}"#),
+            Some(("tests::foo".into(), TestOutcome::Passed))
+        );
+        assert_eq!(
+            parse_test_event(
+                r#"{"type":"test","event":"failed","name":"foo::bin/foo$tests::bar","stdout":""}"#
+            ),
+            Some(("tests::bar".into(), TestOutcome::Failed))
+        );
+        assert_eq!(
+            parse_test_event(r#"{"type":"test","event":"ignored","name":"baz"}"#),
+            Some(("baz".into(), TestOutcome::Ignored))
+        );
+        assert_eq!(
+            parse_test_event(r#"{"type":"test","event":"started","name":"baz"}"#),
+            None
+        );
+        assert_eq!(
+            parse_test_event(r#"{"type":"suite","event":"ok","passed":1}"#),
+            None
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut before = BuildSummary::new();
+        before.process_line(&message(Some("E0308"), "mismatched types", 3));
+        before.process_line(&message(None, "old message", 10));
+        before.process_line("test a ... ok");
+        before.process_line("test b ... ok");
+        before.process_line("test c ... ignored");
+
+        let mut after = BuildSummary::new();
+        after.set_success(false);
+        // The message changed, but the code and the span didn't.
+        after.process_line(&message(Some("E0308"), "mismatched types here", 3));
+        after.process_line(&message(Some("E0308"), "mismatched types here", 3));
+        after.process_line(&message(Some("E0599"), "no method", 7));
+        after.process_line("test a ... ok");
+        after.process_line("test b ... FAILED");
+        after.process_line("test d ... ok");
+        assert_eq!(after.diagnostics().len(), 2);
+
+        let diff = before.diff(&after);
+        assert_eq!(diff.outcome(), Comparison::Regressed);
+        assert_eq!(diff.new_diagnostics().len(), 1);
+        assert_eq!(diff.new_diagnostics()[0].code(), Some("E0599"));
+        assert_eq!(diff.removed_diagnostics().len(), 1);
+        assert_eq!(diff.removed_diagnostics()[0].message(), "old message");
+
+        let changes: Vec<_> = diff
+            .test_changes()
+            .iter()
+            .map(|change| (change.name(), change.before(), change.after()))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("b", Some(TestOutcome::Passed), Some(TestOutcome::Failed)),
+                ("c", Some(TestOutcome::Ignored), None),
+                ("d", None, Some(TestOutcome::Passed)),
+            ]
+        );
+
+        assert!(!diff.is_empty());
+        assert!(before.diff(&before).is_empty());
+    }
+}
@@ -0,0 +1,98 @@
+use failure::{Error, Fail, ResultExt};
+use std::path::{Path, PathBuf};
+
+/// Error happened while checking the disk space available to the workspace.
+#[derive(Debug, Fail)]
+pub enum StorageError {
+    /// The volume backing a directory of the workspace has less free space than the configured
+    /// threshold, so the operation was not started.
+    #[fail(
+        display = "insufficient disk space for {}: {} bytes available, at least {} required",
+        path, available, required
+    )]
+    InsufficientDiskSpace {
+        /// Path of the directory whose volume is running out of space.
+        path: String,
+        /// Free space available on the volume, in bytes.
+        available: u64,
+        /// Minimum free space required by the workspace, in bytes.
+        required: u64,
+    },
+    /// The files written by the build to its scratch home directory exceeded the limit set with
+    /// [`BuildBuilder::home_size_limit`](../struct.BuildBuilder.html#method.home_size_limit).
+    #[fail(
+        display = "the build wrote {} bytes to its home directory, more than the {} bytes allowed",
+        size, limit
+    )]
+    HomeSizeLimitExceeded {
+        /// Size of the home directory at the end of the build, in bytes.
+        size: u64,
+        /// Maximum size allowed for the home directory, in bytes.
+        limit: u64,
+    },
+    #[doc(hidden)]
+    #[fail(display = "this error shouldn't have happened")]
+    __NonExaustive,
+}
+
+/// Fail with `StorageError::InsufficientDiskSpace` if the volume backing `path` has less than
+/// `required` bytes of free space. Nothing is checked if no threshold is configured.
+pub(crate) fn ensure_free_space(path: &Path, required: Option<u64>) -> Result<(), Error> {
+    let required = match required {
+        Some(required) => required,
+        None => return Ok(()),
+    };
+
+    // The directory might not have been created yet, so the space is checked on the nearest
+    // existing ancestor, which is on the same volume unless a mount point is created later.
+    let existing = nearest_existing(path);
+    let available = fs2::available_space(&existing).with_context(|_| {
+        format!(
+            "failed to check the available disk space for {}",
+            existing.display()
+        )
+    })?;
+
+    if available < required {
+        return Err(StorageError::InsufficientDiskSpace {
+            path: path.display().to_string(),
+            available,
+            required,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+fn nearest_existing(path: &Path) -> PathBuf {
+    path.ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(path)
+        .to_path_buf()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ensure_free_space, nearest_existing, StorageError};
+    use failure::Error;
+
+    #[test]
+    fn test_ensure_free_space() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let missing = dir.path().join("not").join("created");
+        assert_eq!(nearest_existing(&missing), dir.path());
+
+        ensure_free_space(&missing, None)?;
+        ensure_free_space(&missing, Some(0))?;
+
+        let err = ensure_free_space(&missing, Some(u64::max_value())).unwrap_err();
+        match err.downcast_ref::<StorageError>() {
+            Some(StorageError::InsufficientDiskSpace { required, .. }) => {
+                assert_eq!(*required, u64::max_value())
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,321 @@
+use crate::cmd::Command;
+use crate::prepare::PrepareError;
+use crate::Build;
+use failure::{Error, ResultExt};
+use log::{info, warn};
+use serde::Deserialize;
+use std::path::Path;
+
+/// Targets docs.rs builds documentation for when a crate doesn't specify its own list.
+static DEFAULT_TARGETS: &[&str] = &[
+    "x86_64-unknown-linux-gnu",
+    "x86_64-apple-darwin",
+    "x86_64-pc-windows-msvc",
+    "i686-unknown-linux-gnu",
+    "i686-pc-windows-msvc",
+];
+
+/// Documentation metadata of a crate, read from the `[package.metadata.docs.rs]` table of its
+/// `Cargo.toml`.
+///
+/// The keys supported are the same ones [docs.rs][docsrs] supports. A crate without the table
+/// will get the default metadata, which builds documentation with the default features for all
+/// the default targets.
+///
+/// [docsrs]: https://docs.rs/about/metadata
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct DocsMetadata {
+    features: Option<Vec<String>>,
+    all_features: bool,
+    no_default_features: bool,
+    default_target: Option<String>,
+    targets: Option<Vec<String>>,
+    rustc_args: Option<Vec<String>>,
+    rustdoc_args: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    package: Option<ManifestPackage>,
+}
+
+#[derive(Deserialize)]
+struct ManifestPackage {
+    metadata: Option<ManifestMetadata>,
+}
+
+#[derive(Deserialize)]
+struct ManifestMetadata {
+    docs: Option<ManifestDocs>,
+}
+
+#[derive(Deserialize)]
+struct ManifestDocs {
+    rs: Option<DocsMetadata>,
+}
+
+impl DocsMetadata {
+    /// Load the metadata from the `Cargo.toml` at the provided path.
+    pub async fn from_manifest(path: &Path) -> Result<Self, Error> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|_| PrepareError::MissingCargoToml)?;
+        Self::from_str(&content)
+    }
+
+    pub(crate) fn from_str(content: &str) -> Result<Self, Error> {
+        let manifest: Manifest =
+            toml::from_str(content).with_context(|_| PrepareError::InvalidCargoTomlSyntax)?;
+        Ok(manifest
+            .package
+            .and_then(|package| package.metadata)
+            .and_then(|metadata| metadata.docs)
+            .and_then(|docs| docs.rs)
+            .unwrap_or_default())
+    }
+
+    /// Return the list of features to enable, if any was specified.
+    pub fn features(&self) -> Option<&[String]> {
+        self.features.as_deref()
+    }
+
+    /// Check whether all the features of the crate should be enabled.
+    pub fn all_features(&self) -> bool {
+        self.all_features
+    }
+
+    /// Check whether the default features of the crate should be disabled.
+    pub fn no_default_features(&self) -> bool {
+        self.no_default_features
+    }
+
+    /// Return the extra arguments passed to `rustc`.
+    pub fn rustc_args(&self) -> &[String] {
+        self.rustc_args.as_deref().unwrap_or(&[])
+    }
+
+    /// Return the extra arguments passed to `rustdoc`.
+    pub fn rustdoc_args(&self) -> &[String] {
+        self.rustdoc_args.as_deref().unwrap_or(&[])
+    }
+
+    /// Return the targets documentation should be built for, in the order they should be built.
+    ///
+    /// The first target is the default one: it's the `default-target` key if present, otherwise
+    /// the first entry of `targets` (or the host target if `targets` is empty). The remaining
+    /// targets are all the other entries of `targets`, or the docs.rs default targets when the key
+    /// is missing.
+    pub fn targets(&self) -> Vec<String> {
+        let others: Vec<String> = match &self.targets {
+            Some(targets) => targets.clone(),
+            None => DEFAULT_TARGETS.iter().map(|t| t.to_string()).collect(),
+        };
+
+        let default = self
+            .default_target
+            .clone()
+            .or_else(|| self.targets.as_ref().and_then(|t| t.first().cloned()))
+            .unwrap_or_else(|| crate::HOST_TARGET.to_string());
+
+        let mut result = vec![default];
+        for target in others {
+            if !result.contains(&target) {
+                result.push(target);
+            }
+        }
+        result
+    }
+}
+
+/// Preset building documentation the same way [docs.rs][docsrs] does.
+///
+/// The preset runs `cargo doc` with `--cfg docsrs` passed to both `rustc` and `rustdoc` (allowing
+/// crates to enable `#[doc(cfg)]` with `#![cfg_attr(docsrs, feature(doc_cfg))]`), and applies the
+/// configuration present in the crate's `[package.metadata.docs.rs]` table. Since it relies on
+/// unstable `rustdoc` flags it requires a nightly toolchain.
+///
+/// The preset runs async commands, so it has to be used in builds started with
+/// [`BuildBuilder::run_async`](struct.BuildBuilder.html#method.run_async).
+///
+/// # Example
+///
+/// ```no_run
+/// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, DocBuild, cmd::SandboxBuilder};
+/// # use std::error::Error;
+/// # async fn run() -> Result<(), Box<dyn Error>> {
+/// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init().await?;
+/// # let krate = Crate::local("".as_ref());
+/// let toolchain = Toolchain::dist("nightly");
+/// let mut build_dir = workspace.build_dir("docs");
+/// let built = build_dir
+///     .build(&toolchain, &krate, SandboxBuilder::new())
+///     .run_async(|build| {
+///         Box::pin(async move {
+///             let docs = DocBuild::from_build(build).await?;
+///             docs.run(build).await
+///         })
+///     })
+///     .await?;
+/// println!("documented targets: {:?}", built);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [docsrs]: https://docs.rs
+pub struct DocBuild {
+    metadata: DocsMetadata,
+    targets: Vec<String>,
+}
+
+impl DocBuild {
+    /// Create a new preset from the provided metadata.
+    pub fn new(metadata: DocsMetadata) -> Self {
+        let targets = metadata.targets();
+        DocBuild { metadata, targets }
+    }
+
+    /// Create a new preset, reading the metadata from the manifest of the crate being built.
+    pub async fn from_build(build: &Build<'_>) -> Result<Self, Error> {
+        let manifest = build.host_source_dir().join("Cargo.toml");
+        Ok(Self::new(DocsMetadata::from_manifest(&manifest).await?))
+    }
+
+    /// Override the list of targets to build documentation for. The first target in the list will
+    /// be treated as the default one.
+    pub fn targets(mut self, targets: &[&str]) -> Self {
+        self.targets = targets.iter().map(|t| t.to_string()).collect();
+        self
+    }
+
+    /// Return the metadata used by this preset.
+    pub fn metadata(&self) -> &DocsMetadata {
+        &self.metadata
+    }
+
+    /// Return the default target of this build.
+    pub fn default_target(&self) -> Option<&str> {
+        self.targets.first().map(|t| t.as_str())
+    }
+
+    /// Return the command that builds documentation for a single target inside the build.
+    ///
+    /// The command can be tweaked further before running it, for example to process its output.
+    pub fn command<'b>(&self, build: &'b Build<'_>, target: &str) -> Command<'b, 'b> {
+        let mut args: Vec<String> = vec!["doc".into(), "--lib".into(), "--no-deps".into()];
+        if let Some(features) = self.metadata.features() {
+            args.push("--features".into());
+            args.push(features.join(" "));
+        }
+        if self.metadata.all_features() {
+            args.push("--all-features".into());
+        }
+        if self.metadata.no_default_features() {
+            args.push("--no-default-features".into());
+        }
+        args.push("--target".into());
+        args.push(target.into());
+
+        let mut rustflags = vec!["--cfg".to_string(), "docsrs".to_string()];
+        rustflags.extend(self.metadata.rustc_args().iter().cloned());
+
+        let mut rustdocflags = vec![
+            "-Z".to_string(),
+            "unstable-options".to_string(),
+            "--cfg".to_string(),
+            "docsrs".to_string(),
+        ];
+        rustdocflags.extend(self.metadata.rustdoc_args().iter().cloned());
+
+        build
+            .cargo()
+            .args(&args)
+            .env("RUSTFLAGS", rustflags.join(" "))
+            .env("RUSTDOCFLAGS", rustdocflags.join(" "))
+            .env("DOCS_RS", "1")
+    }
+
+    /// Build the documentation for every target, starting from the default one.
+    ///
+    /// A failure while documenting the default target fails the whole build, while failures on
+    /// the other targets are logged and skipped. The list of targets that were documented
+    /// successfully is returned.
+    pub async fn run(&self, build: &Build<'_>) -> Result<Vec<String>, Error> {
+        let mut targets = self.targets.iter();
+        let default = match targets.next() {
+            Some(default) => default,
+            None => failure::bail!("no target to build documentation for"),
+        };
+
+        info!("building documentation for default target {}", default);
+        self.command(build, default)
+            .run()
+            .await
+            .with_context(|_| format!("failed to build documentation for {}", default))?;
+
+        let mut built = vec![default.clone()];
+        for target in targets {
+            info!("building documentation for target {}", target);
+            match self.command(build, target).run().await {
+                Ok(()) => built.push(target.clone()),
+                Err(err) => warn!("failed to build documentation for {}: {}", target, err),
+            }
+        }
+
+        Ok(built)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DocsMetadata;
+    use failure::Error;
+
+    #[test]
+    fn test_missing_metadata() -> Result<(), Error> {
+        let metadata = DocsMetadata::from_str(
+            r#"
+            [package]
+            name = "foo"
+            version = "1.0.0"
+            "#,
+        )?;
+        assert_eq!(metadata, DocsMetadata::default());
+        assert_eq!(metadata.targets()[0], crate::HOST_TARGET);
+        for target in super::DEFAULT_TARGETS {
+            assert!(metadata.targets().contains(&target.to_string()));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_metadata() -> Result<(), Error> {
+        let metadata = DocsMetadata::from_str(
+            r#"
+            [package]
+            name = "foo"
+            version = "1.0.0"
+
+            [package.metadata.docs.rs]
+            features = ["a", "b"]
+            no-default-features = true
+            default-target = "x86_64-pc-windows-msvc"
+            targets = ["x86_64-unknown-linux-gnu", "x86_64-pc-windows-msvc"]
+            rustdoc-args = ["--document-private-items"]
+            "#,
+        )?;
+
+        assert_eq!(metadata.features(), Some(&["a".into(), "b".into()][..]));
+        assert!(metadata.no_default_features());
+        assert!(!metadata.all_features());
+        assert!(metadata.rustc_args().is_empty());
+        assert_eq!(metadata.rustdoc_args(), &["--document-private-items"]);
+        assert_eq!(
+            metadata.targets(),
+            vec!["x86_64-pc-windows-msvc", "x86_64-unknown-linux-gnu"]
+        );
+
+        Ok(())
+    }
+}
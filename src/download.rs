@@ -0,0 +1,741 @@
+use crate::Workspace;
+use failure::Error;
+use log::info;
+use reqwest::{header, Client, Response, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+
+/// Minimum time between two progress reports of the same download.
+pub(crate) const REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+pub(crate) type ProgressObserver = Arc<dyn Fn(&DownloadProgress) + Send + Sync>;
+
+/// Progress of a download made by rustwide, reported to the observer configured with
+/// [`WorkspaceBuilder::download_progress`](struct.WorkspaceBuilder.html#method.download_progress).
+#[derive(Debug, Clone)]
+pub struct DownloadProgress {
+    url: String,
+    downloaded: u64,
+    total: Option<u64>,
+    elapsed: Duration,
+    finished: bool,
+}
+
+impl DownloadProgress {
+    /// Get the URL being downloaded.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Get the number of bytes downloaded so far.
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    /// Get the total size of the download in bytes, if the server provided it.
+    pub fn total(&self) -> Option<u64> {
+        self.total
+    }
+
+    /// Get the average download rate so far, in bytes per second.
+    pub fn bytes_per_second(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.downloaded as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Check whether the download completed. The last report of each successful download has
+    /// this flag set.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+/// Keeps track of the progress of a download, reporting it to the observer.
+pub(crate) struct Tracker<'a> {
+    observer: Option<&'a ProgressObserver>,
+    progress: DownloadProgress,
+    started: Instant,
+    last_report: Option<Instant>,
+}
+
+impl<'a> Tracker<'a> {
+    fn new(observer: Option<&'a ProgressObserver>, url: &str, resp: &Response) -> Self {
+        Self::resumed(observer, url, 0, resp.content_length())
+    }
+
+    /// Track a download resumed after `downloaded` bytes were already downloaded.
+    pub(crate) fn resumed(
+        observer: Option<&'a ProgressObserver>,
+        url: &str,
+        downloaded: u64,
+        total: Option<u64>,
+    ) -> Self {
+        Tracker {
+            observer,
+            progress: DownloadProgress {
+                url: url.into(),
+                downloaded,
+                total,
+                elapsed: Duration::from_secs(0),
+                finished: false,
+            },
+            started: Instant::now(),
+            last_report: None,
+        }
+    }
+
+    pub(crate) fn advance(&mut self, bytes: usize) {
+        self.progress.downloaded += bytes as u64;
+        let now = Instant::now();
+        if self
+            .last_report
+            .map(|last| now - last >= REPORT_INTERVAL)
+            .unwrap_or(true)
+        {
+            self.last_report = Some(now);
+            self.report();
+        }
+    }
+
+    pub(crate) fn finish(mut self) {
+        self.progress.finished = true;
+        self.report();
+    }
+
+    fn report(&mut self) {
+        if let Some(observer) = self.observer {
+            self.progress.elapsed = self.started.elapsed();
+            observer(&self.progress);
+        }
+    }
+}
+
+/// Limits the rate of the requests made to crates.io, to comply with its
+/// [crawling policy](https://crates.io/policies#crawlers).
+pub(crate) struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(interval: Duration) -> Self {
+        RateLimiter {
+            interval,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until the next request to crates.io is allowed.
+    async fn wait(&self, workspace_runtime: Option<&tokio::runtime::Handle>) {
+        let mut next = self.next.lock().await;
+        let now = Instant::now();
+        if *next > now {
+            let delay = crate::utils::enter(workspace_runtime, || {
+                tokio::time::delay_until((*next).into())
+            });
+            delay.await;
+        }
+        *next = std::cmp::max(*next, now) + self.interval;
+    }
+}
+
+/// Check whether the URL points to the crates.io API. The crawling policy only applies to the API:
+/// static.crates.io and index.crates.io are served by a CDN, and are not rate limited.
+fn is_cratesio_api(url: &str) -> bool {
+    Url::parse(url)
+        .map(|url| url.host_str() == Some("crates.io") && url.path().starts_with("/api/"))
+        .unwrap_or(false)
+}
+
+/// Wait for the workspace's rate limiter if the URL points to the crates.io API.
+async fn rate_limit(workspace: &Workspace, url: &str) {
+    if let Some(limiter) = workspace.cratesio_rate_limiter() {
+        if is_cratesio_api(url) {
+            limiter.wait(workspace.runtime()).await;
+        }
+    }
+}
+
+/// Extension of the files storing the validators of the downloaded files.
+pub(crate) const VALIDATORS_EXTENSION: &str = "http-cache";
+
+/// Check run on a downloaded file before it's moved into its final location.
+pub(crate) type Verifier<'a> = dyn Fn(&Path) -> Result<(), Error> + Send + Sync + 'a;
+
+/// Validators returned by the server for a downloaded file, stored next to it.
+#[derive(Serialize, Deserialize)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Get the value of the `If-Range` header ensuring a download is resumed only if the file
+    /// didn't change. Weak ETags can't be used for that.
+    fn if_range(&self) -> Option<&str> {
+        self.etag
+            .as_deref()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// Download `url` to `dest`, returning whether the file was actually downloaded.
+///
+/// When `dest` was previously downloaded by this function the request is made conditional with
+/// the `ETag` and `Last-Modified` returned by the server at the time, and the file is downloaded
+/// again only if it changed since then.
+pub(crate) async fn download(workspace: &Workspace, url: &str, dest: &Path) -> Result<bool, Error> {
+    download_authenticated(workspace, url, dest, None).await
+}
+
+/// Download `url` to `dest` like [`download`](fn.download.html), sending the provided token in
+/// the `Authorization` header.
+pub(crate) async fn download_authenticated(
+    workspace: &Workspace,
+    url: &str,
+    dest: &Path,
+    token: Option<&str>,
+) -> Result<bool, Error> {
+    workspace.check_cache_space()?;
+    let _lock = lock_download(workspace, dest).await?;
+    crate::retry::retry(workspace, &format!("downloading {}", url), || async {
+        rate_limit(workspace, url).await;
+        download_with(
+            workspace.http_client(),
+            workspace.download_progress(),
+            url,
+            dest,
+            token,
+            None,
+        )
+        .await
+    })
+    .await
+}
+
+/// Download `url` to `dest` like [`download`](fn.download.html), running the provided check on
+/// the downloaded file before moving it to `dest`. If the check fails the file is discarded.
+///
+/// The file is always downloaded again, unless another process sharing the workspace downloaded
+/// a copy passing the check while this one was waiting for the lock.
+pub(crate) async fn download_verified(
+    workspace: &Workspace,
+    url: &str,
+    dest: &Path,
+    verify: &Verifier<'_>,
+) -> Result<(), Error> {
+    download_verified_authenticated(workspace, url, dest, None, verify).await
+}
+
+/// Download `url` to `dest` like [`download_verified`](fn.download_verified.html), sending the
+/// provided token in the `Authorization` header.
+pub(crate) async fn download_verified_authenticated(
+    workspace: &Workspace,
+    url: &str,
+    dest: &Path,
+    token: Option<&str>,
+    verify: &Verifier<'_>,
+) -> Result<(), Error> {
+    workspace.check_cache_space()?;
+    let _lock = lock_download(workspace, dest).await?;
+    if dest.is_file() && verify(dest).is_ok() {
+        return Ok(());
+    }
+
+    // A file failing the check must not be revalidated with the server.
+    let validators_path = validators_path(dest);
+    if validators_path.exists() {
+        fs::remove_file(&validators_path).await?;
+    }
+    crate::retry::retry(workspace, &format!("downloading {}", url), || async {
+        rate_limit(workspace, url).await;
+        download_with(
+            workspace.http_client(),
+            workspace.download_progress(),
+            url,
+            dest,
+            token,
+            Some(verify),
+        )
+        .await
+    })
+    .await?;
+    Ok(())
+}
+
+/// Lock the download of `dest`, so that processes sharing the workspace don't write to the same
+/// partial download at the same time. The partial download is kept if the process crashes, and
+/// the next process downloading the same file resumes it.
+async fn lock_download(
+    workspace: &Workspace,
+    dest: &Path,
+) -> Result<crate::utils::FileLock, Error> {
+    crate::cache_manager::lock_entry(workspace, dest, false).await
+}
+
+/// Download `url` in memory, reporting the progress to the workspace's observer.
+pub(crate) async fn download_bytes(workspace: &Workspace, url: &str) -> Result<Vec<u8>, Error> {
+    download_bytes_authenticated(workspace, url, None).await
+}
+
+/// Download `url` in memory like [`download_bytes`](fn.download_bytes.html), sending the provided
+/// token in the `Authorization` header.
+pub(crate) async fn download_bytes_authenticated(
+    workspace: &Workspace,
+    url: &str,
+    token: Option<&str>,
+) -> Result<Vec<u8>, Error> {
+    crate::retry::retry(workspace, &format!("downloading {}", url), || async {
+        rate_limit(workspace, url).await;
+        let mut request = workspace.http_client().get(url);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, token);
+        }
+        let mut resp = request.send().await?.error_for_status()?;
+
+        let mut tracker = Tracker::new(workspace.download_progress(), url, &resp);
+        let mut content = Vec::new();
+        while let Some(chunk) = resp.chunk().await? {
+            content.extend_from_slice(&chunk);
+            tracker.advance(chunk.len());
+        }
+        tracker.finish();
+
+        Ok(content)
+    })
+    .await
+}
+
+/// Retrieve the size of the file at `url` from the `Content-Length` returned by a `HEAD` request,
+/// without downloading it.
+pub(crate) async fn content_length(workspace: &Workspace, url: &str) -> Result<u64, Error> {
+    let resp = crate::retry::retry(
+        workspace,
+        &format!("requesting the size of {}", url),
+        || async {
+            rate_limit(workspace, url).await;
+            Ok(workspace
+                .http_client()
+                .head(url)
+                .send()
+                .await?
+                .error_for_status()?)
+        },
+    )
+    .await?;
+    header_value(&resp, header::CONTENT_LENGTH)
+        .and_then(|length| length.parse().ok())
+        .ok_or_else(|| failure::format_err!("the server didn't report the size of {}", url))
+}
+
+/// Download `url` to `dest`, going through a temporary file which is moved to `dest` only once
+/// the whole file is downloaded and verified.
+///
+/// When an attempt fails partway through, the downloaded part is kept along with the validators
+/// of the file, and the next attempt asks the server for the rest of the file if it didn't change
+/// in the meantime. Servers not supporting range requests send the whole file again.
+async fn download_with(
+    client: &Client,
+    observer: Option<&ProgressObserver>,
+    url: &str,
+    dest: &Path,
+    token: Option<&str>,
+    verify: Option<&Verifier<'_>>,
+) -> Result<bool, Error> {
+    let validators_path = validators_path(dest);
+    let temp = crate::utils::resumable_temp_path(dest);
+    let partial_path = crate::utils::resumable_temp_path(&validators_path);
+
+    let (mut resp, offset, total, validators) = loop {
+        let partial = match fs::metadata(&temp).await {
+            Ok(metadata) if metadata.len() > 0 => read_validators(&partial_path)
+                .await
+                .filter(|validators| validators.if_range().is_some())
+                .map(|validators| (metadata.len(), validators)),
+            _ => None,
+        };
+        let cached = if partial.is_none() && dest.is_file() {
+            read_validators(&validators_path).await
+        } else {
+            None
+        };
+
+        let mut request = client.get(url);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, token);
+        }
+        if let Some((offset, validators)) = &partial {
+            request = request
+                .header(header::RANGE, format!("bytes={}-", offset))
+                .header(header::IF_RANGE, validators.if_range().unwrap());
+        }
+        if let Some(cached) = &cached {
+            if let Some(etag) = &cached.etag {
+                request = request.header(header::IF_NONE_MATCH, etag.as_str());
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                request = request.header(header::IF_MODIFIED_SINCE, last_modified.as_str());
+            }
+        }
+
+        let resp = request.send().await?;
+        if cached.is_some() && resp.status() == StatusCode::NOT_MODIFIED {
+            info!("{} didn't change since it was last downloaded", url);
+            return Ok(false);
+        }
+        if let Some((offset, validators)) = partial {
+            if resp.status() == StatusCode::PARTIAL_CONTENT {
+                match content_range(&resp) {
+                    Some((start, total)) if start == offset => {
+                        info!("resuming download of {} after {} bytes", url, offset);
+                        break (resp, offset, total, validators);
+                    }
+                    // The server sent a different part of the file: start over.
+                    _ => {
+                        discard_partial(&temp, &partial_path).await?;
+                        continue;
+                    }
+                }
+            } else if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+                discard_partial(&temp, &partial_path).await?;
+                continue;
+            }
+        }
+
+        let resp = resp.error_for_status()?;
+        let validators = Validators {
+            etag: header_value(&resp, header::ETAG),
+            last_modified: header_value(&resp, header::LAST_MODIFIED),
+        };
+        let total = resp.content_length();
+        break (resp, 0, total, validators);
+    };
+
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    // The validators are stored before the download starts, so that it can be resumed if it
+    // fails partway through.
+    let mut file = if offset > 0 {
+        OpenOptions::new().append(true).open(&temp).await?
+    } else {
+        if validators.is_empty() {
+            if partial_path.exists() {
+                fs::remove_file(&partial_path).await?;
+            }
+        } else {
+            crate::utils::write_atomic(&partial_path, &serde_json::to_vec(&validators)?)?;
+        }
+        File::create(&temp).await?
+    };
+
+    let mut tracker = Tracker::resumed(observer, url, offset, total);
+    let mut downloaded = offset;
+    let body = loop {
+        match resp.chunk().await {
+            Ok(Some(chunk)) => {
+                file.write_all(&chunk).await?;
+                downloaded += chunk.len() as u64;
+                tracker.advance(chunk.len());
+            }
+            Ok(None) => break Ok(()),
+            Err(err) => break Err(err),
+        }
+    };
+    // The part downloaded so far is written to disk even if the download failed.
+    file.flush().await?;
+    drop(file);
+    body?;
+
+    if let Some(total) = total {
+        if downloaded != total {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "the download of {} ended after {} of {} bytes",
+                    url, downloaded, total
+                ),
+            )
+            .into());
+        }
+    }
+    if let Some(verify) = verify {
+        if let Err(err) = verify(&temp) {
+            discard_partial(&temp, &partial_path).await?;
+            return Err(err);
+        }
+    }
+    tracker.finish();
+
+    if validators_path.exists() {
+        fs::remove_file(&validators_path).await?;
+    }
+    fs::rename(&temp, dest).await?;
+    if !validators.is_empty() {
+        fs::rename(&partial_path, &validators_path).await?;
+    }
+
+    Ok(true)
+}
+
+/// Remove the part of a file downloaded by a previous attempt, along with its validators.
+async fn discard_partial(temp: &Path, partial_path: &Path) -> Result<(), Error> {
+    for path in &[temp, partial_path] {
+        if path.exists() {
+            fs::remove_file(path).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Parse the `Content-Range` header of a partial response, returning the offset of the first
+/// byte and the total size of the file, if known.
+fn content_range(resp: &Response) -> Option<(u64, Option<u64>)> {
+    parse_content_range(&header_value(resp, header::CONTENT_RANGE)?)
+}
+
+fn parse_content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let mut parts = value.strip_prefix("bytes ")?.splitn(2, '/');
+    let (range, total) = (parts.next()?, parts.next()?);
+    let start = range.split('-').next()?.trim().parse().ok()?;
+    let total = match total.trim() {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+    Some((start, total))
+}
+
+async fn read_validators(path: &Path) -> Option<Validators> {
+    let content = fs::read(path).await.ok()?;
+    serde_json::from_slice(&content).ok()
+}
+
+fn header_value(resp: &Response, name: header::HeaderName) -> Option<String> {
+    resp.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+/// Check whether the file at `dest` was downloaded along with validators, allowing the next
+/// [`download`](fn.download.html) to only download it again if it changed.
+pub(crate) fn can_revalidate(dest: &Path) -> bool {
+    validators_path(dest).is_file()
+}
+
+/// Return the path of the file storing the validators of `dest`. It's not a cache entry on its
+/// own, and it's removed along with `dest` when it's evicted from the cache.
+pub(crate) fn validators_path(dest: &Path) -> PathBuf {
+    dest.with_file_name(format!("{}.{}", file_name(dest), VALIDATORS_EXTENSION))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .expect("downloads need a file name")
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DownloadProgress, ProgressObserver};
+    use failure::Error;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    fn serve() -> Result<String, Error> {
+        let server =
+            tiny_http::Server::http("localhost:0").map_err(|e| failure::err_msg(e.to_string()))?;
+        let url = format!("http://localhost:{}/file", server.server_addr().port());
+        std::thread::spawn(move || {
+            while let Ok(req) = server.recv() {
+                let header = |name: &'static str| {
+                    req.headers()
+                        .iter()
+                        .find(|header| header.field.equiv(name))
+                        .map(|header| header.value.as_str().to_string())
+                };
+                let cached = header("If-None-Match").as_deref() == Some("\"v1\"");
+                let range = header("Range")
+                    .filter(|_| header("If-Range").as_deref() == Some("\"v1\""))
+                    .and_then(|range| {
+                        range
+                            .strip_prefix("bytes=")?
+                            .strip_suffix('-')?
+                            .parse()
+                            .ok()
+                    });
+                let resp = if cached {
+                    tiny_http::Response::from_string("").with_status_code(304)
+                } else if let Some(start) = range {
+                    tiny_http::Response::from_string(&"content"[start..])
+                        .with_status_code(206)
+                        .with_header(tiny_http::Header {
+                            field: "Content-Range".parse().unwrap(),
+                            value: format!("bytes {}-6/7", start).parse().unwrap(),
+                        })
+                } else {
+                    tiny_http::Response::from_string("content")
+                };
+                let _ = req.respond(resp.with_header(tiny_http::Header {
+                    field: "ETag".parse().unwrap(),
+                    value: "\"v1\"".parse().unwrap(),
+                }));
+            }
+        });
+        Ok(url)
+    }
+
+    #[tokio::test]
+    async fn test_etag_revalidation() -> Result<(), Error> {
+        let url = serve()?;
+        let dir = tempfile::tempdir()?;
+        let dest = dir.path().join("file");
+        let client = reqwest::Client::new();
+
+        assert!(super::download_with(&client, None, &url, &dest, None, None).await?);
+        assert_eq!(std::fs::read_to_string(&dest)?, "content");
+
+        // The second download is revalidated, and the file is kept as is.
+        assert!(!super::download_with(&client, None, &url, &dest, None, None).await?);
+        assert_eq!(std::fs::read_to_string(&dest)?, "content");
+
+        // Without the file the server is not asked to revalidate it.
+        std::fs::remove_file(&dest)?;
+        assert!(super::download_with(&client, None, &url, &dest, None, None).await?);
+        assert_eq!(std::fs::read_to_string(&dest)?, "content");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resume() -> Result<(), Error> {
+        let url = serve()?;
+        let dir = tempfile::tempdir()?;
+        let dest = dir.path().join("file");
+        let temp = crate::utils::resumable_temp_path(&dest);
+        let partial = crate::utils::resumable_temp_path(&super::validators_path(&dest));
+        let client = reqwest::Client::new();
+
+        // Only the rest of the file is requested, as the server still has the same file.
+        std::fs::write(&temp, "con")?;
+        std::fs::write(&partial, r#"{"etag": "\"v1\"", "last_modified": null}"#)?;
+        assert!(super::download_with(&client, None, &url, &dest, None, None).await?);
+        assert_eq!(std::fs::read_to_string(&dest)?, "content");
+        assert!(!temp.exists());
+        assert!(!partial.exists());
+
+        // The partial download is discarded if the file changed.
+        std::fs::remove_file(&dest)?;
+        std::fs::write(&temp, "old")?;
+        std::fs::write(&partial, r#"{"etag": "\"v0\"", "last_modified": null}"#)?;
+        assert!(super::download_with(&client, None, &url, &dest, None, None).await?);
+        assert_eq!(std::fs::read_to_string(&dest)?, "content");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify() -> Result<(), Error> {
+        let url = serve()?;
+        let dir = tempfile::tempdir()?;
+        let dest = dir.path().join("file");
+        let client = reqwest::Client::new();
+
+        let reject = |_: &std::path::Path| Err(failure::err_msg("invalid file"));
+        let res = super::download_with(&client, None, &url, &dest, None, Some(&reject)).await;
+        assert!(res.is_err());
+        assert!(!dest.exists());
+        assert!(!crate::utils::resumable_temp_path(&dest).exists());
+
+        let accept = |path: &std::path::Path| {
+            assert_eq!(std::fs::read_to_string(path)?, "content");
+            Ok(())
+        };
+        assert!(super::download_with(&client, None, &url, &dest, None, Some(&accept)).await?);
+        assert_eq!(std::fs::read_to_string(&dest)?, "content");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        use super::parse_content_range;
+        assert_eq!(parse_content_range("bytes 3-6/7"), Some((3, Some(7))));
+        assert_eq!(parse_content_range("bytes 3-6/*"), Some((3, None)));
+        assert_eq!(parse_content_range("bytes */7"), None);
+        assert_eq!(parse_content_range("3-6/7"), None);
+    }
+
+    #[test]
+    fn test_is_cratesio_api() {
+        assert!(super::is_cratesio_api(
+            "https://crates.io/api/v1/crates/foo"
+        ));
+        assert!(!super::is_cratesio_api(
+            "https://static.crates.io/crates/foo/foo-1.0.0.crate"
+        ));
+        assert!(!super::is_cratesio_api("https://index.crates.io/3/f/foo"));
+        assert!(!super::is_cratesio_api("https://crates.io/crates/foo"));
+        assert!(!super::is_cratesio_api(
+            "https://github.com/rust-lang/crates.io"
+        ));
+        assert!(!super::is_cratesio_api("https://notcrates.io/api/v1"));
+        assert!(!super::is_cratesio_api("not a url"));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        let limiter = super::RateLimiter::new(Duration::from_millis(50));
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.wait(None).await;
+        }
+        // The first request is not delayed, the other two wait for the interval.
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_progress() -> Result<(), Error> {
+        let url = serve()?;
+        let dir = tempfile::tempdir()?;
+        let dest = dir.path().join("file");
+        let client = reqwest::Client::new();
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let reports_clone = reports.clone();
+        let observer: ProgressObserver = Arc::new(move |progress: &DownloadProgress| {
+            reports_clone.lock().unwrap().push(progress.clone());
+        });
+        super::download_with(&client, Some(&observer), &url, &dest, None, None).await?;
+
+        let reports = reports.lock().unwrap();
+        let last = reports.last().unwrap();
+        assert_eq!(last.url(), url);
+        assert_eq!(last.downloaded(), 7);
+        assert_eq!(last.total(), Some(7));
+        assert!(last.is_finished());
+        assert!(reports[..reports.len() - 1]
+            .iter()
+            .all(|r| !r.is_finished()));
+
+        Ok(())
+    }
+}
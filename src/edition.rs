@@ -0,0 +1,213 @@
+use crate::cmd::Command;
+use crate::prepare::PrepareError;
+use crate::{Build, ManifestInfo, Workspace};
+use failure::{bail, Error, ResultExt};
+use log::info;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use toml::value::{Table, Value};
+
+/// Editions of Rust, in the order they were released.
+static EDITIONS: &[&str] = &["2015", "2018", "2021", "2024"];
+
+/// Outcome of migrating a crate to a newer edition, returned by
+/// [`Build::cargo_fix_edition`](struct.Build.html#method.cargo_fix_edition).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EditionMigration {
+    from: String,
+    to: String,
+    success: bool,
+    diff: String,
+}
+
+impl EditionMigration {
+    /// Return the edition the crate used before the migration.
+    pub fn from(&self) -> &str {
+        &self.from
+    }
+
+    /// Return the edition the crate was migrated to.
+    pub fn to(&self) -> &str {
+        &self.to
+    }
+
+    /// Check whether `cargo fix` succeeded for every edition and the migrated crate compiles.
+    pub fn success(&self) -> bool {
+        self.success
+    }
+
+    /// Return the changes made to the source code by the migration, as a unified diff with paths
+    /// relative to the source directory. Changes are included even if the migration failed.
+    pub fn diff(&self) -> &str {
+        &self.diff
+    }
+}
+
+pub(crate) async fn migrate(build: &Build<'_>, target: &str) -> Result<EditionMigration, Error> {
+    let manifest_path = build.host_source_dir().join("Cargo.toml");
+    let from = ManifestInfo::from_manifest(&manifest_path)
+        .await?
+        .edition()
+        .map(|edition| edition.to_string())
+        .ok_or_else(|| failure::err_msg("the edition is inherited from the workspace"))?;
+    let steps = editions_between(&from, target)?;
+
+    let snapshot = SourceSnapshot::new(build.workspace(), &build.host_source_dir()).await?;
+    let mut success = true;
+    for edition in steps {
+        info!("migrating the crate to the {} edition", edition);
+        // `cargo fix --edition` migrates the code to the edition after the one in the manifest,
+        // which is then bumped for the next step.
+        let res = build
+            .cargo()
+            .args(&["fix", "--edition", "--allow-no-vcs", "--allow-dirty"])
+            .args(&["--workspace", "--all-targets"])
+            .run()
+            .await;
+        if res.is_err() {
+            success = false;
+            break;
+        }
+        let manifest = tokio::fs::read_to_string(&manifest_path).await?;
+        tokio::fs::write(&manifest_path, set_edition(&manifest, edition)?).await?;
+    }
+    if success {
+        let res = build
+            .cargo()
+            .args(&["check", "--workspace", "--all-targets"])
+            .run()
+            .await;
+        success = res.is_ok();
+    }
+
+    Ok(EditionMigration {
+        from,
+        to: target.to_string(),
+        success,
+        diff: snapshot.diff().await?,
+    })
+}
+
+/// Return the editions a crate has to go through to be migrated between two editions.
+fn editions_between(from: &str, to: &str) -> Result<&'static [&'static str], Error> {
+    let position = |edition| EDITIONS.iter().position(|e| *e == edition);
+    let from_pos = match position(from) {
+        Some(pos) => pos,
+        None => bail!("unknown edition: {}", from),
+    };
+    let to_pos = match position(to) {
+        Some(pos) => pos,
+        None => bail!("unknown edition: {}", to),
+    };
+    if to_pos <= from_pos {
+        bail!(
+            "the crate already uses the {} edition, newer than {}",
+            from,
+            to
+        );
+    }
+    Ok(&EDITIONS[from_pos + 1..=to_pos])
+}
+
+/// Replace the edition in the contents of a `Cargo.toml`.
+fn set_edition(manifest: &str, edition: &str) -> Result<String, Error> {
+    let mut table: Table =
+        toml::from_str(manifest).with_context(|_| PrepareError::InvalidCargoTomlSyntax)?;
+    match table.get_mut("package").and_then(Value::as_table_mut) {
+        Some(package) => {
+            package.insert("edition".into(), Value::String(edition.into()));
+        }
+        None => return Err(PrepareError::InvalidCargoTomlSyntax.into()),
+    }
+    Ok(toml::to_string(&table)?)
+}
+
+/// Snapshot of the source code of a build, stored in a git repository outside of the source
+/// directory so that the crate doesn't see it.
+struct SourceSnapshot<'w> {
+    workspace: &'w Workspace,
+    git_dir: TempDir,
+    source_dir: PathBuf,
+}
+
+impl<'w> SourceSnapshot<'w> {
+    async fn new(workspace: &'w Workspace, source_dir: &Path) -> Result<Self, Error> {
+        let snapshot = SourceSnapshot {
+            workspace,
+            git_dir: tempfile::tempdir()?,
+            source_dir: source_dir.into(),
+        };
+        Command::new(workspace, "git")
+            .args(&["init", "--bare", "--quiet"])
+            .args(&[snapshot.git_dir.path()])
+            .log_output(false)
+            .run()
+            .await
+            .with_context(|_| "failed to create the snapshot of the source code")?;
+        snapshot.git().args(&["add", "--all"]).run().await?;
+        snapshot
+            .git()
+            .args(&[
+                "-c",
+                "user.name=rustwide",
+                "-c",
+                "user.email=rustwide@localhost",
+            ])
+            .args(&["commit", "--quiet", "--no-gpg-sign", "-m", "source"])
+            .run()
+            .await
+            .with_context(|_| "failed to create the snapshot of the source code")?;
+        Ok(snapshot)
+    }
+
+    fn git(&self) -> Command<'w, '_> {
+        let mut git_dir = std::ffi::OsString::from("--git-dir=");
+        git_dir.push(self.git_dir.path());
+        let mut work_tree = std::ffi::OsString::from("--work-tree=");
+        work_tree.push(&self.source_dir);
+        Command::new(self.workspace, "git")
+            .args(&[git_dir, work_tree])
+            .log_output(false)
+    }
+
+    /// Return the changes made to the source code since the snapshot was taken.
+    async fn diff(&self) -> Result<String, Error> {
+        self.git().args(&["add", "--all"]).run().await?;
+        let out = self
+            .git()
+            .args(&["diff", "--cached", "--no-color"])
+            .run_capture()
+            .await
+            .with_context(|_| "failed to compare the source code with its snapshot")?;
+        let mut diff = out.stdout_lines().join("\n");
+        if !diff.is_empty() {
+            diff.push('\n');
+        }
+        Ok(diff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{editions_between, set_edition};
+
+    #[test]
+    fn test_editions_between() {
+        assert_eq!(editions_between("2015", "2018").unwrap(), &["2018"]);
+        assert_eq!(editions_between("2015", "2021").unwrap(), &["2018", "2021"]);
+        assert!(editions_between("2021", "2018").is_err());
+        assert!(editions_between("2021", "2021").is_err());
+        assert!(editions_between("2015", "2030").is_err());
+    }
+
+    #[test]
+    fn test_set_edition() {
+        let manifest = set_edition("[package]\nname = \"foo\"\n", "2018").unwrap();
+        let manifest = set_edition(&manifest, "2021").unwrap();
+        let table: toml::value::Table = toml::from_str(&manifest).unwrap();
+        assert_eq!(table["package"]["name"].as_str(), Some("foo"));
+        assert_eq!(table["package"]["edition"].as_str(), Some("2021"));
+
+        assert!(set_edition("[workspace]\n", "2018").is_err());
+    }
+}
@@ -0,0 +1,389 @@
+use crate::cmd::Command;
+use crate::Workspace;
+use failure::Error;
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+/// CPU weight of the containers of builds that didn't use any CPU yet, which is Docker's default.
+const MAX_WEIGHT: u64 = 1024;
+/// Lowest CPU weight accepted by Docker.
+const MIN_WEIGHT: u64 = 2;
+
+/// Coordinator sharing the CPU fairly between the builds running at the same time on the host,
+/// created by [`Workspace::spawn_cpu_coordinator`](struct.Workspace.html#method.spawn_cpu_coordinator).
+///
+/// The sandboxes attached to the coordinator with
+/// [`SandboxBuilder::cpu_coordinator`](cmd/struct.SandboxBuilder.html#method.cpu_coordinator)
+/// have their CPU usage sampled at the end of every time slice. The cgroup CPU weight of each
+/// container is then adjusted based on how much CPU time its build used so far: a build starts
+/// with Docker's default weight, which is divided by one plus the number of time slices of CPU
+/// time it used. New and short builds are thus favored over the long ones, which still get all
+/// the CPU time the other builds don't need.
+///
+/// The CPU time used by each build and its share of the CPU time used by all the coordinated
+/// builds can be retrieved with [`report`](#method.report) once the build ends.
+///
+/// # Example
+///
+/// ```no_run
+/// # use rustwide::{cmd::SandboxBuilder, Workspace};
+/// # use std::time::Duration;
+/// # fn run(workspace: &Workspace) {
+/// let coordinator = workspace.spawn_cpu_coordinator(Duration::from_secs(10));
+///
+/// // Every build gets its own name, and its sandboxes are attached to the coordinator.
+/// let sandbox = SandboxBuilder::new().cpu_coordinator(&coordinator, "foo");
+///
+/// // ...run the build with the sandbox...
+///
+/// if let Some(report) = coordinator.report("foo") {
+///     println!("used {:.0}% of the CPU time", report.share() * 100.0);
+/// }
+/// coordinator.forget("foo");
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct CpuCoordinator {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    time_slice: Duration,
+    state: Mutex<State>,
+}
+
+impl CpuCoordinator {
+    /// Return the CPU usage of a build so far, or `None` if none of its sandboxes ran yet.
+    pub fn report(&self, build: &str) -> Option<CpuShareReport> {
+        let state = self.inner.state.lock().unwrap();
+        state.builds.get(build).map(|usage| CpuShareReport {
+            cpu_time_secs: usage.cpu_time,
+            share: if usage.contended_time > 0.0 {
+                usage.cpu_time / usage.contended_time
+            } else {
+                0.0
+            },
+        })
+    }
+
+    /// Stop tracking the CPU usage of a build, so that a new build with the same name starts
+    /// from scratch. Builds should be forgotten once they end and their report was retrieved.
+    pub fn forget(&self, build: &str) {
+        let mut state = self.inner.state.lock().unwrap();
+        if let Some(usage) = state.builds.get(build) {
+            if usage.containers.is_empty() {
+                state.builds.remove(build);
+            }
+        }
+    }
+
+    /// Return the CPU weight the next container of the build should start with.
+    pub(crate) fn weight(&self, build: &str) -> u64 {
+        let state = self.inner.state.lock().unwrap();
+        state
+            .builds
+            .get(build)
+            .map(|usage| usage.weight)
+            .unwrap_or(MAX_WEIGHT)
+    }
+
+    /// Start sampling the CPU usage of a container until the returned guard is dropped.
+    pub(crate) fn register(&self, build: &str, container: &str, weight: u64) -> CpuRegistration {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.last_sample.is_none() {
+            state.last_sample = Some(Instant::now());
+        }
+        state
+            .builds
+            .entry(build.into())
+            .or_default()
+            .containers
+            .insert(container.into(), weight);
+
+        CpuRegistration {
+            inner: self.inner.clone(),
+            build: build.into(),
+            container: container.into(),
+        }
+    }
+}
+
+/// Guard unregistering a container from the coordinator when dropped.
+pub(crate) struct CpuRegistration {
+    inner: Arc<Inner>,
+    build: String,
+    container: String,
+}
+
+impl Drop for CpuRegistration {
+    fn drop(&mut self) {
+        let mut state = self.inner.state.lock().unwrap();
+        if let Some(usage) = state.builds.get_mut(&self.build) {
+            usage.containers.remove(&self.container);
+        }
+        if state
+            .builds
+            .values()
+            .all(|usage| usage.containers.is_empty())
+        {
+            state.last_sample = None;
+        }
+    }
+}
+
+/// CPU usage of a build coordinated by a [`CpuCoordinator`](struct.CpuCoordinator.html).
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CpuShareReport {
+    cpu_time_secs: f64,
+    share: f64,
+}
+
+impl CpuShareReport {
+    /// Return the CPU time used by the build, estimated from the samples.
+    pub fn cpu_time(&self) -> Duration {
+        Duration::from_secs_f64(self.cpu_time_secs)
+    }
+
+    /// Return the fraction of the CPU time used by all the coordinated builds the build got while
+    /// it was running, between `0.0` and `1.0`.
+    pub fn share(&self) -> f64 {
+        self.share
+    }
+}
+
+struct BuildUsage {
+    /// Running containers of the build, with the CPU weight applied to them.
+    containers: HashMap<String, u64>,
+    weight: u64,
+    cpu_time: f64,
+    /// CPU time used by all the builds while this build was running.
+    contended_time: f64,
+}
+
+impl Default for BuildUsage {
+    fn default() -> Self {
+        BuildUsage {
+            containers: HashMap::new(),
+            weight: MAX_WEIGHT,
+            cpu_time: 0.0,
+            contended_time: 0.0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    builds: HashMap<String, BuildUsage>,
+    last_sample: Option<Instant>,
+}
+
+impl State {
+    /// Account the CPU usage of the containers (in cores, keyed by their short ID) over the
+    /// elapsed time, returning the containers whose weight has to change.
+    fn record(
+        &mut self,
+        usage: &[(String, f64)],
+        elapsed: Duration,
+        time_slice: Duration,
+    ) -> Vec<(String, u64)> {
+        let elapsed = elapsed.as_secs_f64();
+        let mut total = 0.0;
+        for build in self.builds.values_mut() {
+            let cores: f64 = usage
+                .iter()
+                .filter(|(id, _)| build.containers.keys().any(|full| full.starts_with(id)))
+                .map(|(_, cores)| cores)
+                .sum();
+            build.cpu_time += cores * elapsed;
+            total += cores * elapsed;
+        }
+
+        let mut updates = Vec::new();
+        for build in self.builds.values_mut() {
+            if build.containers.is_empty() {
+                continue;
+            }
+            build.contended_time += total;
+            build.weight = weight(build.cpu_time, time_slice);
+            for (container, applied) in &mut build.containers {
+                if *applied != build.weight {
+                    *applied = build.weight;
+                    updates.push((container.clone(), build.weight));
+                }
+            }
+        }
+        updates
+    }
+}
+
+/// Compute the CPU weight of a build that used `cpu_time` seconds of CPU time.
+fn weight(cpu_time: f64, time_slice: Duration) -> u64 {
+    let slices = cpu_time / time_slice.as_secs_f64();
+    ((MAX_WEIGHT as f64 / (1.0 + slices)).round() as u64).max(MIN_WEIGHT)
+}
+
+/// Parse the output of `docker stats --format "{{.ID}} {{.CPUPerc}}"`, returning the CPU usage of
+/// each container in cores.
+fn parse_stats(lines: &[String]) -> Vec<(String, f64)> {
+    lines
+        .iter()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let id = parts.next()?;
+            let percent: f64 = parts.next()?.trim_end_matches('%').parse().ok()?;
+            Some((id.to_string(), percent / 100.0))
+        })
+        .collect()
+}
+
+async fn rebalance(inner: &Inner, workspace: &Workspace) -> Result<(), Error> {
+    let containers: Vec<String> = {
+        let state = inner.state.lock().unwrap();
+        state
+            .builds
+            .values()
+            .flat_map(|usage| usage.containers.keys().cloned())
+            .collect()
+    };
+    if containers.is_empty() {
+        return Ok(());
+    }
+
+    let out = Command::new(workspace, "docker")
+        .args(&["stats", "--no-stream", "--format", "{{.ID}} {{.CPUPerc}}"])
+        .args(&containers)
+        .log_output(false)
+        .log_command(false)
+        .run_capture()
+        .await?;
+    let usage = parse_stats(out.stdout_lines());
+
+    let updates = {
+        let mut state = inner.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = state
+            .last_sample
+            .replace(now)
+            .map(|last| now - last)
+            .unwrap_or_default();
+        state.record(&usage, elapsed, inner.time_slice)
+    };
+
+    for (container, weight) in updates {
+        info!(
+            "changing the CPU weight of container {} to {}",
+            container, weight
+        );
+        let res = Command::new(workspace, "docker")
+            .args(&["update", "--cpu-shares", &weight.to_string(), &container])
+            .log_output(false)
+            .run()
+            .await;
+        // The container might have exited since the sample was taken.
+        if let Err(err) = res {
+            warn!("failed to change the CPU weight of {}: {}", container, err);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn spawn(workspace: Workspace, time_slice: Duration) -> CpuCoordinator {
+    let inner = Arc::new(Inner {
+        time_slice,
+        state: Mutex::new(State::default()),
+    });
+
+    // The task only keeps a weak reference, so that it stops once the coordinator is dropped.
+    let weak: Weak<Inner> = Arc::downgrade(&inner);
+    let runtime = workspace.runtime().cloned();
+    crate::utils::spawn(runtime.as_ref(), async move {
+        loop {
+            crate::utils::enter(workspace.runtime(), || tokio::time::delay_for(time_slice)).await;
+            if workspace.shutdown_tracker().is_shutting_down() {
+                break;
+            }
+            let inner = match weak.upgrade() {
+                Some(inner) => inner,
+                None => break,
+            };
+            if let Err(err) = rebalance(&inner, &workspace).await {
+                warn!("failed to rebalance the CPU weights: {}", err);
+            }
+        }
+    });
+
+    CpuCoordinator { inner }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_stats, weight, State, MAX_WEIGHT, MIN_WEIGHT};
+    use std::time::Duration;
+
+    #[test]
+    fn test_weight() {
+        let slice = Duration::from_secs(10);
+        assert_eq!(weight(0.0, slice), MAX_WEIGHT);
+        assert_eq!(weight(10.0, slice), MAX_WEIGHT / 2);
+        assert_eq!(weight(30.0, slice), MAX_WEIGHT / 4);
+        assert_eq!(weight(1_000_000.0, slice), MIN_WEIGHT);
+    }
+
+    #[test]
+    fn test_parse_stats() {
+        let lines = vec![
+            "0123456789ab 150.25%".to_string(),
+            "ba9876543210 0.00%".to_string(),
+            "cdef01234567 --".to_string(),
+        ];
+        assert_eq!(
+            parse_stats(&lines),
+            vec![
+                ("0123456789ab".to_string(), 1.5025),
+                ("ba9876543210".to_string(), 0.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_record() {
+        let slice = Duration::from_secs(10);
+        let mut state = State::default();
+        for (build, container) in &[("long", "aaaaaaaaaaaa0000"), ("short", "bbbbbbbbbbbb0000")] {
+            state
+                .builds
+                .entry(build.to_string())
+                .or_default()
+                .containers
+                .insert(container.to_string(), MAX_WEIGHT);
+        }
+
+        let usage = vec![
+            ("aaaaaaaaaaaa".to_string(), 3.0),
+            ("bbbbbbbbbbbb".to_string(), 1.0),
+        ];
+        let mut updates = state.record(&usage, slice, slice);
+        updates.sort();
+        assert_eq!(
+            updates,
+            vec![
+                ("aaaaaaaaaaaa0000".to_string(), MAX_WEIGHT / 4),
+                ("bbbbbbbbbbbb0000".to_string(), MAX_WEIGHT / 2),
+            ]
+        );
+
+        let long = &state.builds["long"];
+        assert_eq!(long.cpu_time, 30.0);
+        assert_eq!(long.contended_time, 40.0);
+
+        // Weights that didn't change are not applied again.
+        let idle = vec![
+            ("aaaaaaaaaaaa".to_string(), 0.0),
+            ("bbbbbbbbbbbb".to_string(), 0.0),
+        ];
+        assert!(state.record(&idle, slice, slice).is_empty());
+    }
+}
@@ -0,0 +1,190 @@
+use crate::cmd::Command;
+use crate::Build;
+use failure::Error;
+use log::info;
+use std::collections::BTreeSet;
+
+/// Maximum number of builds executed by default while probing a crate.
+const DEFAULT_MAX_ROUNDS: usize = 16;
+
+/// Build variant finding which unstable features a crate (and its dependencies) require.
+///
+/// The crate is checked with `-Zallow-features` restricting the unstable features that can be
+/// enabled, and the features rejected by the compiler are collected from its output. As the
+/// compiler stops at the first crate using a rejected feature, the build is repeated allowing the
+/// features found so far, until it succeeds or no new feature is found. Since it relies on
+/// unstable compiler flags it requires a nightly toolchain.
+///
+/// This is useful to analyze the impact of stabilizing or removing a feature on the ecosystem.
+///
+/// # Example
+///
+/// ```no_run
+/// # use rustwide::{Build, FeatureProbe};
+/// # use std::error::Error;
+/// # async fn run(build: &Build<'_>) -> Result<(), Box<dyn Error>> {
+/// let usage = FeatureProbe::new().run(build).await?;
+/// println!("required unstable features: {:?}", usage.features());
+/// # Ok(())
+/// # }
+/// ```
+pub struct FeatureProbe {
+    allowed: Vec<String>,
+    args: Vec<String>,
+    max_rounds: usize,
+}
+
+impl FeatureProbe {
+    /// Create a new probe, running `cargo check --all-targets` without allowing any unstable
+    /// feature.
+    pub fn new() -> Self {
+        FeatureProbe {
+            allowed: Vec::new(),
+            args: vec!["check".into(), "--all-targets".into()],
+            max_rounds: DEFAULT_MAX_ROUNDS,
+        }
+    }
+
+    /// Allow the provided unstable features from the first build. The features allowed this way
+    /// are not included in the results, even if the crate uses them.
+    pub fn allow(mut self, features: &[&str]) -> Self {
+        self.allowed
+            .extend(features.iter().map(|feature| feature.to_string()));
+        self
+    }
+
+    /// Override the arguments passed to cargo, for example to build the crate with `build` instead
+    /// of `check`.
+    pub fn args(mut self, args: &[&str]) -> Self {
+        self.args = args.iter().map(|arg| arg.to_string()).collect();
+        self
+    }
+
+    /// Set the maximum number of builds executed while probing. By default it's 16.
+    pub fn max_rounds(mut self, rounds: usize) -> Self {
+        self.max_rounds = rounds;
+        self
+    }
+
+    /// Return the command that builds the crate allowing only the provided unstable features.
+    ///
+    /// The command can be tweaked further before running it, for example to process its output.
+    pub fn command<'b>(&self, build: &'b Build<'_>, allowed: &[String]) -> Command<'b, 'b> {
+        build.cargo().args(&self.args).env(
+            "RUSTFLAGS",
+            format!("-Zallow-features={}", allowed.join(",")),
+        )
+    }
+
+    /// Build the crate until all the unstable features it requires are found.
+    ///
+    /// Build failures are not errors: they're reported by
+    /// [`FeatureUsage::build_succeeded`](struct.FeatureUsage.html#method.build_succeeded).
+    pub async fn run(&self, build: &Build<'_>) -> Result<FeatureUsage, Error> {
+        let mut allowed = self.allowed.clone();
+        let mut features = BTreeSet::new();
+
+        for round in 1..=self.max_rounds {
+            info!(
+                "probing unstable features (round {}), allowing: {:?}",
+                round, allowed
+            );
+
+            let mut found = BTreeSet::new();
+            let res = self
+                .command(build, &allowed)
+                .process_lines(&mut |line, _| {
+                    if let Some(feature) = parse_disallowed_feature(line) {
+                        found.insert(feature.to_string());
+                    }
+                })
+                .run()
+                .await;
+
+            let new_features: Vec<_> = found.difference(&features).cloned().collect();
+            if res.is_ok() || new_features.is_empty() {
+                return Ok(FeatureUsage {
+                    features,
+                    build_succeeded: res.is_ok(),
+                    rounds: round,
+                });
+            }
+
+            allowed.extend(new_features.iter().cloned());
+            features.extend(new_features);
+        }
+
+        Ok(FeatureUsage {
+            features,
+            build_succeeded: false,
+            rounds: self.max_rounds,
+        })
+    }
+}
+
+/// Unstable features required by a crate, found by a [`FeatureProbe`](struct.FeatureProbe.html).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeatureUsage {
+    features: BTreeSet<String>,
+    build_succeeded: bool,
+    rounds: usize,
+}
+
+impl FeatureUsage {
+    /// Return the unstable features the crate and its dependencies require.
+    pub fn features(&self) -> &BTreeSet<String> {
+        &self.features
+    }
+
+    /// Check whether the build succeeded once all the features found were allowed. If it didn't,
+    /// the build failed for other reasons, or the maximum number of builds was reached, and the
+    /// list of features might be incomplete.
+    pub fn build_succeeded(&self) -> bool {
+        self.build_succeeded
+    }
+
+    /// Return the number of builds executed while probing.
+    pub fn rounds(&self) -> usize {
+        self.rounds
+    }
+}
+
+/// Extract the name of the feature from the error the compiler emits when a feature is used
+/// without being allowed by `-Zallow-features`.
+fn parse_disallowed_feature(line: &str) -> Option<&str> {
+    let rest = line
+        .trim_start()
+        .strip_prefix("error[E0725]: the feature `")?;
+    let end = rest.find('`')?;
+    if rest[end..].starts_with("` is not in the list of allowed features") {
+        Some(&rest[..end])
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_disallowed_feature;
+
+    #[test]
+    fn test_parse_disallowed_feature() {
+        assert_eq!(
+            parse_disallowed_feature(
+                "error[E0725]: the feature `doc_cfg` is not in the list of allowed features"
+            ),
+            Some("doc_cfg")
+        );
+        assert_eq!(
+            parse_disallowed_feature("error[E0725]: the feature `doc_cfg` is weird"),
+            None
+        );
+        assert_eq!(
+            parse_disallowed_feature(
+                "error[E0554]: `#![feature]` may not be used on the stable release channel"
+            ),
+            None
+        );
+        assert_eq!(parse_disallowed_feature("   Compiling foo v0.1.0"), None);
+    }
+}
@@ -0,0 +1,539 @@
+//! Git operations on the repositories cached by rustwide, implemented with
+//! [gitoxide](https://github.com/Byron/gitoxide) instead of running the `git` binary.
+//!
+//! Repositories are cloned and updated with gitoxide's own fetch machinery, which negotiates the
+//! protocol version with the server and authenticates with the configured `GitCredentials`. Only
+//! two transports rely on external programs: SSH remotes run `ssh`, and `file://` remotes run
+//! `git-upload-pack`. Git LFS isn't supported by gitoxide, so its files are still fetched by
+//! running `git lfs` when it's enabled in the workspace.
+
+use crate::download::{Tracker, REPORT_INTERVAL};
+use crate::prepare::PrepareError;
+use crate::{GitCredentials, Workspace};
+use failure::{Error, Fail};
+use gix::credentials::helper::Action;
+use gix::progress::tree::{Item, Root};
+use gix::remote::fetch::{Shallow, Tags};
+use gix::remote::Direction;
+use log::warn;
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+type GixError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Name of the remote the cached repositories are fetched from.
+const REMOTE: &str = "origin";
+
+/// Refspec fetching all the branches of the remote into a bare repository.
+pub(crate) static BRANCHES_REFSPEC: &str = "refs/heads/*:refs/heads/*";
+
+/// Error happened while fetching or reading a git repository.
+#[derive(Debug, Fail)]
+pub enum GitError {
+    /// The repository couldn't be cloned or updated from its remote.
+    #[fail(display = "failed to fetch {}: {}", url, reason)]
+    FetchFailed {
+        /// URL of the repository.
+        url: String,
+        /// Description of the error, including its causes.
+        reason: String,
+    },
+    /// The revision doesn't exist in the repository, even after fetching it from the remote.
+    #[fail(display = "{} doesn't exist in {}", revision, url)]
+    RevisionNotFound {
+        /// URL of the repository.
+        url: String,
+        /// Revision that was requested.
+        revision: String,
+    },
+    /// The files of a revision couldn't be checked out.
+    #[fail(display = "failed to check out {}: {}", revision, reason)]
+    CheckoutFailed {
+        /// Revision, or tree of a revision, that was checked out.
+        revision: String,
+        /// Description of the error, including its causes.
+        reason: String,
+    },
+    #[doc(hidden)]
+    #[fail(display = "this error shouldn't have happened")]
+    __NonExaustive,
+}
+
+/// How much of the history of a repository is fetched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Depth {
+    /// Only the latest commit of the references, without tags.
+    Latest,
+    /// The whole history of the references and all the tags, turning shallow clones into full
+    /// ones.
+    Full,
+}
+
+impl Depth {
+    fn shallow(self, is_shallow: bool) -> Shallow {
+        match self {
+            Depth::Latest => Shallow::DepthAtRemote(NonZeroU32::new(1).unwrap()),
+            Depth::Full if is_shallow => Shallow::undo(),
+            Depth::Full => Shallow::NoChange,
+        }
+    }
+
+    fn tags(self) -> Tags {
+        match self {
+            Depth::Latest => Tags::None,
+            Depth::Full => Tags::All,
+        }
+    }
+}
+
+/// Clone the repository at `url` into a new bare repository at `dest`, fetching the references
+/// matching `refspecs`. `HEAD` points to the default branch of the remote.
+pub(crate) async fn clone(
+    workspace: &Workspace,
+    url: &str,
+    credentials: &GitCredentials,
+    dest: &Path,
+    refspecs: Vec<String>,
+    depth: Depth,
+) -> Result<(), Error> {
+    let owned_url = url.to_string();
+    let dest = dest.to_path_buf();
+    let credentials = credentials.clone();
+    run(workspace, url, move |progress, shared| {
+        let authenticate = authenticate(&credentials, &shared);
+        let mut prepare = gix::clone::PrepareFetch::new(
+            owned_url.as_str(),
+            &dest,
+            gix::create::Kind::Bare,
+            gix::create::Options::default(),
+            open_options(&credentials),
+        )?
+        .configure_remote(move |mut remote| {
+            remote.replace_refspecs(refspecs.iter().map(String::as_str), Direction::Fetch)?;
+            Ok(remote.with_fetch_tags(depth.tags()))
+        })
+        .configure_connection(move |connection| {
+            connection.set_credentials(authenticate.clone());
+            Ok(())
+        })
+        .with_shallow(depth.shallow(false));
+        prepare.fetch_only(progress, &shared.interrupt)?;
+        Ok(())
+    })
+    .await
+}
+
+/// Update the bare repository at `repo` from its remote, fetching the references matching
+/// `refspecs`. References are never removed, and full clones are never made shallow again.
+pub(crate) async fn fetch(
+    workspace: &Workspace,
+    url: &str,
+    credentials: &GitCredentials,
+    repo: &Path,
+    refspecs: Vec<String>,
+    depth: Depth,
+) -> Result<(), Error> {
+    let repo = repo.to_path_buf();
+    let credentials = credentials.clone();
+    run(workspace, url, move |mut progress, shared| {
+        let repo = gix::open_opts(&repo, open_options(&credentials))?;
+        let shallow = depth.shallow(repo.is_shallow());
+        let mut remote = repo.find_remote(REMOTE)?.with_fetch_tags(depth.tags());
+        remote.replace_refspecs(refspecs.iter().map(String::as_str), Direction::Fetch)?;
+        remote
+            .connect(Direction::Fetch)?
+            .with_credentials(authenticate(&credentials, &shared))
+            .prepare_fetch(progress.add_child("references"), Default::default())?
+            .with_shallow(shallow)
+            .receive(progress, &shared.interrupt)?;
+        Ok(())
+    })
+    .await
+}
+
+/// Resolve a revision to the hash of the commit it points to in the repository at `repo`,
+/// returning `None` if the revision doesn't exist.
+pub(crate) fn resolve(repo: &Path, revision: &str) -> Result<Option<String>, Error> {
+    let repo = gix::open_opts(repo, gix::open::Options::isolated())?;
+    Ok(repo
+        .rev_parse_single(format!("{}^{{commit}}", revision).as_str())
+        .ok()
+        .map(|id| id.to_string()))
+}
+
+/// Read the file at `path` in a revision of the repository at `repo`, returning `None` if the
+/// revision or the file don't exist.
+pub(crate) fn read_file(repo: &Path, revision: &str, path: &str) -> Result<Option<Vec<u8>>, Error> {
+    let repo = gix::open_opts(repo, gix::open::Options::isolated())?;
+    let id = match repo.rev_parse_single(format!("{}:{}", revision, path).as_str()) {
+        Ok(id) => id,
+        Err(_) => return Ok(None),
+    };
+    let object = id.object()?;
+    if object.kind != gix::object::Kind::Blob {
+        return Ok(None);
+    }
+    Ok(Some(object.detach().data))
+}
+
+/// Submodule recorded in a revision of a repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Submodule {
+    /// Path of the submodule inside the repository.
+    pub(crate) path: String,
+    /// URL of the repository of the submodule, resolved against the URL of the superproject if
+    /// `.gitmodules` contains a relative one.
+    pub(crate) url: String,
+    /// Commit of the submodule the revision points to.
+    pub(crate) commit: String,
+}
+
+/// List the submodules of a revision of the repository at `repo`, which is fetched from `url`.
+/// Like `git submodule update`, submodules listed in `.gitmodules` without a commit in the
+/// revision are ignored.
+pub(crate) fn submodules(repo: &Path, url: &str, revision: &str) -> Result<Vec<Submodule>, Error> {
+    let modules = match read_file(repo, revision, ".gitmodules")? {
+        Some(modules) => modules,
+        None => return Ok(Vec::new()),
+    };
+    let modules = gix::submodule::File::from_bytes(&modules, None, &Default::default())?;
+
+    let repo = gix::open_opts(repo, gix::open::Options::isolated())?;
+    let tree = repo.rev_parse_single(revision)?.object()?.peel_to_tree()?;
+    let mut submodules = Vec::new();
+    for name in modules.names() {
+        let path = modules.path(name)?.to_string();
+        let commit = match tree.lookup_entry_by_path(&path)? {
+            Some(entry) if entry.mode().is_commit() => entry.object_id().to_string(),
+            _ => {
+                warn!(
+                    "submodule {} is not recorded in {}, skipping it",
+                    path, revision
+                );
+                continue;
+            }
+        };
+        let submodule_url = modules
+            .config()
+            .string(format!("submodule.{}.url", name))
+            .ok_or_else(|| failure::format_err!("submodule {} has no url", path))?;
+        submodules.push(Submodule {
+            url: submodule_url_relative_to(url, &submodule_url.to_string()),
+            path,
+            commit,
+        });
+    }
+    Ok(submodules)
+}
+
+/// Resolve the URL of a submodule against the URL of its superproject. Like git, relative URLs
+/// (starting with `./` or `../`) are relative to the superproject's URL, whose last component is
+/// removed for each `../`.
+fn submodule_url_relative_to(superproject: &str, url: &str) -> String {
+    if !url.starts_with("./") && !url.starts_with("../") {
+        return url.to_string();
+    }
+
+    let mut base = superproject.trim_end_matches('/').to_string();
+    // scp-like URLs (`host:path`) keep the colon when all the components of the path are removed.
+    let mut separator = '/';
+    let mut rest = url;
+    loop {
+        if let Some(stripped) = rest.strip_prefix("./") {
+            rest = stripped;
+        } else if let Some(stripped) = rest.strip_prefix("../") {
+            if let Some(idx) = base.rfind(|c| c == '/' || c == ':') {
+                separator = if base[idx..].starts_with(':') {
+                    ':'
+                } else {
+                    '/'
+                };
+                base.truncate(idx);
+            }
+            rest = stripped;
+        } else {
+            break;
+        }
+    }
+    format!("{}{}{}", base, separator, rest)
+}
+
+/// Check whether the repository at `repo` is a shallow clone.
+pub(crate) fn is_shallow(repo: &Path) -> bool {
+    gix::open_opts(repo, gix::open::Options::isolated())
+        .map(|repo| repo.is_shallow())
+        .unwrap_or(false)
+}
+
+/// Check out the tree `treeish` (like `HEAD` or `HEAD:subdir`) of the repository at `repo` into
+/// `dest`, without touching the repository's own index.
+///
+/// The configuration of the system and of the user is ignored, so filters like the Git LFS one
+/// never run and files stored in Git LFS are checked out as pointers.
+pub(crate) async fn checkout(
+    workspace: &Workspace,
+    repo: &Path,
+    treeish: &str,
+    dest: &Path,
+) -> Result<(), Error> {
+    let repo = repo.to_path_buf();
+    let treeish = treeish.to_string();
+    let dest = dest.to_path_buf();
+    crate::utils::spawn_blocking(workspace.runtime(), move || {
+        checkout_tree(&repo, &treeish, &dest).map_err(|err| {
+            GitError::CheckoutFailed {
+                revision: treeish.clone(),
+                reason: describe(&*err),
+            }
+            .into()
+        })
+    })
+    .await?
+}
+
+fn checkout_tree(repo: &Path, treeish: &str, dest: &Path) -> Result<(), GixError> {
+    let repo = gix::open_opts(repo, gix::open::Options::isolated())?;
+    let tree = repo.rev_parse_single(treeish)?.object()?.peel_to_tree()?.id;
+    let mut index = repo.index_from_tree(&tree)?;
+    let mut options =
+        repo.checkout_options(gix::worktree::stack::state::attributes::Source::IdMapping)?;
+    options.overwrite_existing = true;
+
+    std::fs::create_dir_all(dest)?;
+    gix::worktree::state::checkout(
+        &mut index,
+        dest,
+        repo.objects.clone().into_arc()?,
+        &gix::progress::Discard,
+        &gix::progress::Discard,
+        &AtomicBool::new(false),
+        options,
+    )?;
+    Ok(())
+}
+
+/// State shared between a network operation running on a blocking thread and its caller.
+#[derive(Default)]
+struct Shared {
+    /// Set by the caller to stop the operation.
+    interrupt: AtomicBool,
+    /// Set when the remote asked for credentials that weren't configured.
+    missing_credentials: AtomicBool,
+}
+
+/// Run a network operation on a blocking thread, reporting the bytes it receives to the download
+/// progress observer of the workspace, and interrupting it when the commands of the workspace are
+/// cancelled.
+async fn run<F>(workspace: &Workspace, url: &str, operation: F) -> Result<(), Error>
+where
+    F: FnOnce(Item, Arc<Shared>) -> Result<(), GixError> + Send + 'static,
+{
+    let root: Arc<Root> = gix::progress::tree::root::Options::default().into();
+    let shared = Arc::new(Shared::default());
+    let mut job = {
+        let progress = root.add_child(url);
+        let shared = shared.clone();
+        crate::utils::spawn_blocking(workspace.runtime(), move || operation(progress, shared))
+    };
+
+    let cancellations = workspace.shutdown_tracker().cancellations();
+    let mut tracker = Tracker::resumed(workspace.download_progress(), url, 0, None);
+    let mut received = 0;
+    let result = loop {
+        match tokio::time::timeout(REPORT_INTERVAL, &mut job).await {
+            Ok(result) => break result?,
+            Err(_) => {
+                if workspace.shutdown_tracker().is_cancelled(cancellations) {
+                    shared.interrupt.store(true, Ordering::SeqCst);
+                }
+                let bytes = received_bytes(&root);
+                tracker.advance(bytes.saturating_sub(received) as usize);
+                received = received.max(bytes);
+            }
+        }
+    };
+
+    match result {
+        Ok(()) => {
+            tracker.advance(received_bytes(&root).saturating_sub(received) as usize);
+            tracker.finish();
+            Ok(())
+        }
+        Err(err) => Err(fetch_error(url, &*err, &shared)),
+    }
+}
+
+/// Return the number of bytes of the packs received so far by the operation.
+fn received_bytes(root: &Root) -> u64 {
+    let read_pack_bytes: gix::progress::Id =
+        gix::odb::pack::bundle::write::ProgressId::ReadPackBytes.into();
+    let mut tasks = Vec::new();
+    root.sorted_snapshot(&mut tasks);
+    tasks
+        .iter()
+        .filter(|(_, task)| task.id == read_pack_bytes)
+        .filter_map(|(_, task)| task.progress.as_ref())
+        .map(|value| value.step.load(Ordering::Relaxed) as u64)
+        .sum()
+}
+
+/// Return the function providing the credentials to the remote, recording whether it was asked
+/// for credentials that weren't configured.
+fn authenticate(
+    credentials: &GitCredentials,
+    shared: &Arc<Shared>,
+) -> impl FnMut(Action) -> gix::credentials::protocol::Result + Clone + Send + 'static {
+    let mut inner = credentials.authenticate();
+    let shared = shared.clone();
+    move |action| {
+        let get = matches!(action, Action::Get(_));
+        let result = inner(action);
+        if get {
+            if let Ok(None) = result {
+                shared.missing_credentials.store(true, Ordering::SeqCst);
+            }
+        }
+        result
+    }
+}
+
+/// Options to open repositories for network operations. The configuration of the system and of
+/// the user is ignored, so the credentials and the SSH command are only the ones of rustwide.
+fn open_options(credentials: &GitCredentials) -> gix::open::Options {
+    gix::open::Options::isolated().config_overrides(credentials.ssh_config())
+}
+
+/// Convert the error of a network operation, detecting the repositories requiring credentials.
+fn fetch_error(url: &str, err: &(dyn std::error::Error + 'static), shared: &Shared) -> Error {
+    if shared.missing_credentials.load(Ordering::SeqCst) || is_permission_denied(err) {
+        PrepareError::PrivateGitRepository.into()
+    } else {
+        GitError::FetchFailed {
+            url: url.into(),
+            reason: describe(err),
+        }
+        .into()
+    }
+}
+
+/// Check whether the error was caused by the remote denying access, which is how both the HTTP
+/// transport (on 401 responses) and the SSH transport report authentication failures.
+fn is_permission_denied(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(err) = current {
+        if let Some(io) = err.downcast_ref::<std::io::Error>() {
+            if io.kind() == std::io::ErrorKind::PermissionDenied {
+                return true;
+            }
+        }
+        current = err.source();
+    }
+    false
+}
+
+/// Describe an error with all its causes.
+fn describe(err: &(dyn std::error::Error + 'static)) -> String {
+    let mut messages: Vec<String> = Vec::new();
+    let mut current = Some(err);
+    while let Some(err) = current {
+        let message = err.to_string();
+        if messages.last() != Some(&message) {
+            messages.push(message);
+        }
+        current = err.source();
+    }
+    messages.join(": ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{describe, fetch_error, submodule_url_relative_to, Depth, GitError, Shared};
+    use crate::prepare::PrepareError;
+    use gix::remote::fetch::{Shallow, Tags};
+    use std::io;
+    use std::sync::atomic::Ordering;
+
+    #[derive(Debug)]
+    struct Wrapper(io::Error);
+
+    impl std::fmt::Display for Wrapper {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "handshake failed")
+        }
+    }
+
+    impl std::error::Error for Wrapper {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn test_submodule_url_relative_to() {
+        let url = "https://github.com/rust-lang/rustwide.git";
+        assert_eq!(
+            submodule_url_relative_to(url, "https://example.com/foo.git"),
+            "https://example.com/foo.git"
+        );
+        assert_eq!(
+            submodule_url_relative_to(url, "./foo.git"),
+            "https://github.com/rust-lang/rustwide.git/foo.git"
+        );
+        assert_eq!(
+            submodule_url_relative_to(url, "../foo.git"),
+            "https://github.com/rust-lang/foo.git"
+        );
+        assert_eq!(
+            submodule_url_relative_to("https://github.com/rust-lang/rustwide/", "../../a/foo"),
+            "https://github.com/a/foo"
+        );
+        assert_eq!(
+            submodule_url_relative_to("git@github.com:rust-lang/rustwide.git", "../foo.git"),
+            "git@github.com:rust-lang/foo.git"
+        );
+        assert_eq!(
+            submodule_url_relative_to("git@github.com:rust-lang/rustwide.git", "../../a/foo.git"),
+            "git@github.com:a/foo.git"
+        );
+    }
+
+    #[test]
+    fn test_depth() {
+        assert_eq!(Depth::Full.shallow(false), Shallow::NoChange);
+        assert_eq!(Depth::Full.shallow(true), Shallow::undo());
+        assert_eq!(
+            Depth::Latest.shallow(true),
+            Shallow::DepthAtRemote(std::num::NonZeroU32::new(1).unwrap())
+        );
+        assert_eq!(Depth::Full.tags(), Tags::All);
+        assert_eq!(Depth::Latest.tags(), Tags::None);
+    }
+
+    #[test]
+    fn test_fetch_error() {
+        let url = "https://example.com/repo.git";
+        let shared = Shared::default();
+
+        let err = Wrapper(io::Error::new(io::ErrorKind::Other, "connection reset"));
+        assert_eq!(describe(&err), "handshake failed: connection reset");
+        match fetch_error(url, &err, &shared).downcast_ref() {
+            Some(GitError::FetchFailed { url: err_url, .. }) => assert_eq!(err_url, url),
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        let err = Wrapper(io::Error::new(io::ErrorKind::PermissionDenied, "denied"));
+        assert!(matches!(
+            fetch_error(url, &err, &shared).downcast_ref(),
+            Some(PrepareError::PrivateGitRepository)
+        ));
+
+        let err = Wrapper(io::Error::new(io::ErrorKind::Other, "no credentials"));
+        shared.missing_credentials.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            fetch_error(url, &err, &shared).downcast_ref(),
+            Some(PrepareError::PrivateGitRepository)
+        ));
+    }
+}
@@ -0,0 +1,326 @@
+use failure::Error;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Operation leaving state behind in the workspace if the process crashes while it's running.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub(crate) enum Operation {
+    /// A crate is being fetched into the provided cache path.
+    Fetch { path: PathBuf },
+    /// A crate is being built in the provided directories.
+    Build {
+        source_dir: PathBuf,
+        home_dir: PathBuf,
+    },
+    /// A sandbox container with the provided ID is running.
+    Container { id: String },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Record {
+    Start {
+        id: String,
+        pid: u32,
+        operation: Operation,
+    },
+    End {
+        id: String,
+    },
+}
+
+/// Append-only journal of the operations in progress in the workspace, shared by all the
+/// processes using it. Each operation is recorded when it starts and when it ends, so that the
+/// operations interrupted by a crash can be found and cleaned up by
+/// [`Workspace::recover`](struct.Workspace.html#method.recover).
+pub(crate) struct Journal {
+    path: PathBuf,
+    next_id: AtomicU64,
+    lock: Mutex<()>,
+}
+
+impl Journal {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Journal {
+            path,
+            next_id: AtomicU64::new(0),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Record the start of an operation, returning a guard recording its end when dropped.
+    ///
+    /// Failing to write to the journal doesn't stop the operation, as the journal is only needed
+    /// to recover from crashes.
+    pub(crate) fn begin(&self, operation: Operation) -> JournalEntry<'_> {
+        let id = format!(
+            "{}-{}",
+            std::process::id(),
+            self.next_id.fetch_add(1, Ordering::SeqCst)
+        );
+        self.append(&Record::Start {
+            id: id.clone(),
+            pid: std::process::id(),
+            operation,
+        });
+        JournalEntry { journal: self, id }
+    }
+
+    fn append(&self, record: &Record) {
+        let _guard = self.lock.lock().unwrap();
+        if let Err(err) = self.append_inner(record) {
+            warn!("failed to write to the workspace journal: {}", err);
+        }
+    }
+
+    fn append_inner(&self, record: &Record) -> Result<(), Error> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        // Each record is written with a single call, so records appended by different processes
+        // are not interleaved.
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Return the operations started by other processes that never ended, and compact the
+    /// journal to only contain the operations of this process that are still in progress.
+    pub(crate) fn take_interrupted(&self) -> Result<Vec<Operation>, Error> {
+        let _guard = self.lock.lock().unwrap();
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let current = std::process::id();
+        let mut interrupted = Vec::new();
+        let mut kept = String::new();
+        for (pid, id, operation) in pending(&content) {
+            if pid == current {
+                kept.push_str(&serde_json::to_string(&Record::Start {
+                    id,
+                    pid,
+                    operation,
+                })?);
+                kept.push('\n');
+            } else {
+                interrupted.push(operation);
+            }
+        }
+
+        crate::utils::write_atomic(&self.path, kept.as_bytes())?;
+        Ok(interrupted)
+    }
+}
+
+/// Guard recording the end of an operation in the journal when dropped.
+pub(crate) struct JournalEntry<'a> {
+    journal: &'a Journal,
+    id: String,
+}
+
+impl Drop for JournalEntry<'_> {
+    fn drop(&mut self) {
+        self.journal.append(&Record::End {
+            id: std::mem::take(&mut self.id),
+        });
+    }
+}
+
+/// Return the operations started but never ended in the journal, in the order they started.
+/// Malformed lines (like the last one, if a crash happened while writing it) are ignored.
+fn pending(content: &str) -> Vec<(u32, String, Operation)> {
+    let mut order = Vec::new();
+    let mut started = BTreeMap::new();
+    for line in content.lines() {
+        match serde_json::from_str(line) {
+            Ok(Record::Start { id, pid, operation }) => {
+                order.push(id.clone());
+                started.insert(id, (pid, operation));
+            }
+            Ok(Record::End { id }) => {
+                started.remove(&id);
+            }
+            Err(_) => warn!("ignoring malformed line in the workspace journal: {}", line),
+        }
+    }
+
+    order
+        .into_iter()
+        .filter_map(|id| {
+            let (pid, operation) = started.remove(&id)?;
+            Some((pid, id, operation))
+        })
+        .collect()
+}
+
+/// State left behind by interrupted operations and removed by
+/// [`Workspace::recover`](struct.Workspace.html#method.recover).
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RecoveryReport {
+    containers: Vec<String>,
+    paths: Vec<PathBuf>,
+}
+
+impl RecoveryReport {
+    /// Return the IDs of the sandbox containers that were removed.
+    pub fn removed_containers(&self) -> &[String] {
+        &self.containers
+    }
+
+    /// Return the paths of the partially fetched crates and of the build directories that were
+    /// removed.
+    pub fn removed_paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Check whether no leftover state was found.
+    pub fn is_empty(&self) -> bool {
+        self.containers.is_empty() && self.paths.is_empty()
+    }
+}
+
+/// Clean up the state left behind by the interrupted operations recorded in the journal.
+pub(crate) async fn recover(journal: &Journal) -> Result<RecoveryReport, Error> {
+    let mut report = RecoveryReport::default();
+    for operation in journal.take_interrupted()? {
+        info!("cleaning up after interrupted operation {:?}", operation);
+        match operation {
+            Operation::Fetch { path } => remove_path(&path, &mut report)?,
+            Operation::Build {
+                source_dir,
+                home_dir,
+            } => {
+                remove_path(&source_dir, &mut report)?;
+                remove_path(&home_dir, &mut report)?;
+            }
+            Operation::Container { id } => {
+                // The container might have been removed before the crash.
+                match crate::shutdown::remove_container(&id).await {
+                    Ok(()) => report.containers.push(id),
+                    Err(err) => warn!("failed to remove container {}: {}", id, err),
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn remove_path(path: &Path, report: &mut RecoveryReport) -> Result<(), Error> {
+    if path.is_dir() {
+        remove_dir_all::remove_dir_all(path)?;
+    } else if path.exists() {
+        std::fs::remove_file(path)?;
+    } else {
+        return Ok(());
+    }
+    report.paths.push(path.to_path_buf());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pending, recover, Journal, Operation};
+    use failure::Error;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_pending() {
+        let content = concat!(
+            r#"{"start":{"id":"1-0","pid":1,"operation":{"kind":"container","id":"abc"}}}"#,
+            "\n",
+            r#"{"start":{"id":"1-1","pid":1,"operation":{"kind":"fetch","path":"/foo"}}}"#,
+            "\n",
+            r#"{"end":{"id":"1-0"}}"#,
+            "\n",
+            r#"{"start":{"id":"2-0","pid":2,"operation":{"kind":"container","id":"def"}}}"#,
+            "\n",
+            r#"{"start":{"id":"2-1","pid":2,"operation":{"kind":"fetch","#,
+        );
+
+        assert_eq!(
+            pending(content),
+            vec![
+                (
+                    1,
+                    "1-1".into(),
+                    Operation::Fetch {
+                        path: PathBuf::from("/foo")
+                    }
+                ),
+                (2, "2-0".into(), Operation::Container { id: "def".into() }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_journal() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let journal = Journal::new(dir.path().join("journal"));
+
+        let finished = journal.begin(Operation::Container { id: "abc".into() });
+        let running = journal.begin(Operation::Container { id: "def".into() });
+        drop(finished);
+
+        // Operations of the current process are still in progress, and they're not recovered.
+        assert!(journal.take_interrupted()?.is_empty());
+        let content = std::fs::read_to_string(dir.path().join("journal"))?;
+        assert_eq!(pending(&content).len(), 1);
+
+        drop(running);
+        let content = std::fs::read_to_string(dir.path().join("journal"))?;
+        assert!(pending(&content).is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_recover() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let source_dir = dir.path().join("source");
+        std::fs::create_dir_all(source_dir.join("src"))?;
+        let cached = dir.path().join("cached.crate");
+        std::fs::write(&cached, b"")?;
+
+        let path = dir.path().join("journal");
+        std::fs::write(
+            &path,
+            format!(
+                "{}\n{}\n",
+                serde_json::json!({"start": {"id": "0-0", "pid": 0, "operation": {
+                    "kind": "build",
+                    "source_dir": source_dir,
+                    "home_dir": dir.path().join("missing"),
+                }}}),
+                serde_json::json!({"start": {"id": "0-1", "pid": 0, "operation": {
+                    "kind": "fetch",
+                    "path": cached,
+                }}}),
+            ),
+        )?;
+
+        let report = recover(&Journal::new(path.clone())).await?;
+        assert_eq!(
+            report.removed_paths(),
+            &[source_dir.clone(), cached.clone()]
+        );
+        assert!(report.removed_containers().is_empty());
+        assert!(!source_dir.exists());
+        assert!(!cached.exists());
+
+        // The journal is compacted after recovering.
+        assert!(std::fs::read_to_string(&path)?.is_empty());
+        assert!(recover(&Journal::new(path)).await?.is_empty());
+        Ok(())
+    }
+}
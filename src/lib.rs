@@ -12,6 +12,10 @@
 //! * **unstable**: allow Rustwide to use unstable Rust and Cargo features. While this feature also
 //!   works on Rust stable it might cause Rustwide to break, and **no stability guarantee is
 //!   present when using it!**
+//! * **mock**: provide [`Workspace::mock`](struct.Workspace.html#method.mock) and the [`mock`]
+//!   module, to test code embedding Rustwide without Docker, network access or rustup.
+//! * **queue**: provide the [`queue`] module, a durable on-disk queue of builds processed by
+//!   worker loops, for deployments that don't need their own orchestration.
 //!
 //! [crater]: https://github.com/rust-lang/crater
 //! [docsrs]: https://github.com/rust-lang/docs.rs
@@ -20,22 +24,94 @@
 #[macro_use]
 extern crate toml;
 
+mod adjustments;
+mod artifacts;
+mod bisect;
+mod budget;
 mod build;
+mod cache_archive;
+mod cache_manager;
+mod cache_report;
+mod cargo_config;
 pub mod cmd;
+mod comparison;
 mod crates;
+mod credentials;
+pub mod diff;
+mod disk;
+mod docbuild;
+mod download;
+mod edition;
+mod fair_share;
+mod feature_probe;
+mod git;
 mod inside_docker;
+mod journal;
 pub mod logging;
+mod maintenance;
+mod manifest;
+mod members;
+#[cfg(feature = "mock")]
+#[cfg_attr(docs_rs, doc(cfg(feature = "mock")))]
+pub mod mock;
+mod msrv;
 mod native;
+mod nextest;
+mod policy;
 mod prepare;
+mod provenance;
+#[cfg(feature = "queue")]
+#[cfg_attr(docs_rs, doc(cfg(feature = "queue")))]
+pub mod queue;
+pub mod report;
+mod retry;
+mod schedule;
+mod semver_checks;
+mod shutdown;
+mod source_writes;
+mod staged;
+mod standalone;
+pub mod storage;
+mod timings;
 pub mod toolchain;
 mod tools;
 mod utils;
 mod workspace;
 
+pub use crate::adjustments::SandboxAdjustments;
+pub use crate::artifacts::{ArtifactKind, ArtifactReport, ArtifactSize};
+pub use crate::bisect::{BisectionResult, CiBisection};
+pub use crate::budget::{BudgetExceeded, RunBudget};
 pub use crate::build::{Build, BuildBuilder, BuildDirectory};
-pub use crate::crates::Crate;
+pub use crate::cache_report::{CacheKind, CacheReport, CachedItem};
+pub use crate::cargo_config::CargoNetworkConfig;
+pub use crate::comparison::{BuildOutcome, Comparison, ComparisonBuild, ComparisonResult};
+pub use crate::crates::{Crate, CrateSource, CrateWatcher, GitReference, SourceChanges};
+pub use crate::credentials::GitCredentials;
+pub use crate::disk::StorageError;
+pub use crate::docbuild::{DocBuild, DocsMetadata};
+pub use crate::download::DownloadProgress;
+pub use crate::edition::EditionMigration;
+pub use crate::fair_share::{CpuCoordinator, CpuShareReport};
+pub use crate::feature_probe::{FeatureProbe, FeatureUsage};
+pub use crate::git::GitError;
+pub use crate::journal::RecoveryReport;
+pub use crate::maintenance::MaintenanceHandle;
+pub use crate::manifest::ManifestInfo;
+pub use crate::members::WorkspaceMember;
+pub use crate::msrv::MsrvSearch;
+pub use crate::nextest::NextestRunner;
+pub use crate::policy::CodeExecutionPolicy;
 pub use crate::prepare::PrepareError;
+pub use crate::provenance::Provenance;
+pub use crate::retry::RetryPolicy;
+pub use crate::schedule::{BuildScheduler, SchedulePlan};
+pub use crate::semver_checks::{SemverCheck, SemverFinding, SemverReport};
+pub use crate::source_writes::SourceWrites;
+pub use crate::staged::{StagedBuild, StagedOutcome};
+pub use crate::timings::{BuildTimings, TimingCapture, UnitTiming};
 pub use crate::toolchain::Toolchain;
+pub use crate::tools::{InstallableTool, RustupSelfUpdate};
 pub use crate::workspace::{Workspace, WorkspaceBuilder};
 
 pub(crate) static HOST_TARGET: &str = include_str!(concat!(env!("OUT_DIR"), "/target"));
@@ -176,7 +176,7 @@ impl SealedLog for LogStorage {
                 return;
             }
         }
-        let message = record.args().to_string();
+        let message = collapse_carriage_returns(record.args().to_string());
         if let Some(max_size) = self.max_size {
             if inner.size + message.len() >= max_size {
                 inner.records.push(StoredRecord {
@@ -197,6 +197,24 @@ impl SealedLog for LogStorage {
     fn flush(&self) {}
 }
 
+/// Collapse the progress bars redrawn with carriage returns, keeping only the text before the
+/// first carriage return and the last update of each line.
+fn collapse_carriage_returns(message: String) -> String {
+    if !message.contains('\r') {
+        return message;
+    }
+    message
+        .split('\n')
+        .map(|line| {
+            let mut segments = line.split('\r');
+            let first = segments.next().unwrap_or("");
+            let last = segments.filter(|segment| !segment.trim().is_empty()).last();
+            format!("{}{}", first, last.unwrap_or(""))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 impl fmt::Display for LogStorage {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let inner = self.inner.lock().unwrap();
@@ -280,7 +298,7 @@ fn init_inner(logger: Option<Box<dyn Log>>) {
 
 #[cfg(test)]
 mod tests {
-    use super::{LogStorage, StoredRecord};
+    use super::{collapse_carriage_returns, LogStorage, StoredRecord};
     use crate::logging;
     use log::{info, trace, warn, Level, LevelFilter};
 
@@ -310,6 +328,22 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_collapse_carriage_returns() {
+        logging::init();
+
+        let storage = LogStorage::new(LevelFilter::Info);
+        logging::capture(&storage, || {
+            info!("[stderr] \r 10 %\r 50 %\r100 %\r");
+        });
+        assert_eq!(storage.to_string(), "[INFO] [stderr] 100 %\n");
+
+        assert_eq!(
+            collapse_carriage_returns("a\rb\rc\nd\ne\r".into()),
+            "ac\nd\ne"
+        );
+    }
+
     #[test]
     fn test_too_much_content() {
         logging::init();
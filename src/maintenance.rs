@@ -0,0 +1,210 @@
+use crate::cache_manager::{is_cache_entry, remove_unused_entry, CACHE_DIRS};
+use crate::cmd::{Command, CREATOR_LABEL};
+use crate::Workspace;
+use failure::Error;
+use futures_util::future::{self, Either};
+use log::{error, info, warn};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// Cached crates not used for longer than this are removed by the maintenance task.
+const CACHE_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Locks held for longer than this are reported by the maintenance task.
+const LOCK_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Handle to the background maintenance task of a workspace, returned by
+/// [`Workspace::spawn_maintenance`](struct.Workspace.html#method.spawn_maintenance).
+///
+/// Dropping the handle stops the task after the current maintenance run completes.
+pub struct MaintenanceHandle {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl MaintenanceHandle {
+    /// Stop the maintenance task, waiting for the current maintenance run (if any) to complete.
+    pub async fn stop(self) -> Result<(), Error> {
+        // The task might have already exited if the workspace was shut down.
+        let _ = self.stop.send(());
+        self.task.await?;
+        Ok(())
+    }
+}
+
+pub(crate) fn spawn(workspace: Workspace, interval: Duration) -> MaintenanceHandle {
+    let (stop, mut stopped) = oneshot::channel();
+    let runtime = workspace.runtime().cloned();
+    let task = crate::utils::spawn(runtime.as_ref(), async move {
+        loop {
+            let delay =
+                crate::utils::enter(workspace.runtime(), || tokio::time::delay_for(interval));
+            match future::select(delay, stopped).await {
+                Either::Left((_, still_running)) => stopped = still_running,
+                Either::Right(_) => break,
+            }
+            if workspace.shutdown_tracker().is_shutting_down() {
+                break;
+            }
+
+            info!("running workspace maintenance");
+            if let Err(err) = run(&workspace).await {
+                error!("workspace maintenance failed: {}", err);
+                for cause in err.iter_causes() {
+                    error!("caused by: {}", cause);
+                }
+            }
+        }
+    });
+
+    MaintenanceHandle { stop, task }
+}
+
+async fn run(workspace: &Workspace) -> Result<(), Error> {
+    remove_stale_containers(workspace).await?;
+
+    let cache_dir = workspace.cache_dir();
+    crate::utils::spawn_blocking(workspace.runtime(), move || -> Result<(), Error> {
+        for &(dir, depth) in CACHE_DIRS {
+            gc_cache_entries(&cache_dir.join(dir), depth, CACHE_MAX_AGE)?;
+        }
+        Ok(())
+    })
+    .await??;
+    workspace.gc_caches().await?;
+
+    workspace.remove_stale_temp_entries()?;
+
+    let lock = workspace.lock_path();
+    crate::utils::spawn_blocking(workspace.runtime(), move || {
+        crate::utils::check_lock_staleness(&lock, LOCK_MAX_AGE)
+    })
+    .await??;
+
+    Ok(())
+}
+
+/// Remove the containers created by this workspace whose creator process exited, for example
+/// because a previous process using the workspace crashed before cleaning them up.
+///
+/// The containers of other processes sharing the workspace are kept while those processes run,
+/// and the ones of this process are cleaned up when their commands end. Containers created by
+/// versions of rustwide not recording their creator are never removed.
+async fn remove_stale_containers(workspace: &Workspace) -> Result<(), Error> {
+    let out = Command::new(workspace, "docker")
+        .args(&[
+            "ps",
+            "--all",
+            "--no-trunc",
+            "--filter",
+            &format!("label={}", workspace.container_label()),
+            "--format",
+            &format!("{{{{.ID}}}} {{{{.Label \"{}\"}}}}", CREATOR_LABEL),
+        ])
+        .log_output(false)
+        .run_capture()
+        .await?;
+
+    for (id, creator) in stale_containers(out.stdout_lines(), crate::native::process_exists) {
+        info!(
+            "removing stale container {} created by process {}",
+            id, creator
+        );
+        if let Err(err) = crate::shutdown::remove_container(id).await {
+            warn!("failed to remove stale container {}: {}", id, err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse the output of `docker ps`, returning the containers whose creator isn't running anymore.
+fn stale_containers(lines: &[String], is_running: impl Fn(u32) -> bool) -> Vec<(&str, u32)> {
+    let current = std::process::id();
+    lines
+        .iter()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let id = parts.next()?;
+            let creator = parts.next()?.parse().ok()?;
+            Some((id, creator))
+        })
+        .filter(|&(_, creator)| creator != current && !is_running(creator))
+        .collect()
+}
+
+/// Remove the entries `depth` levels inside `dir` that were not used for longer than `max_age`.
+/// Entries locked by other processes are kept, as they're being used right now.
+fn gc_cache_entries(dir: &Path, depth: usize, max_age: Duration) -> Result<(), Error> {
+    let used_before = match SystemTime::now().checked_sub(max_age) {
+        Some(time) => time,
+        None => return Ok(()),
+    };
+    if !dir.is_dir() {
+        return Ok(());
+    }
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if depth > 1 {
+            if path.is_dir() {
+                gc_cache_entries(&path, depth - 1, max_age)?;
+            }
+        } else if is_cache_entry(&path) && remove_unused_entry(&path, used_before)? {
+            info!("removed unused cache entry {}", path.display());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use failure::Error;
+    use std::time::Duration;
+
+    #[test]
+    fn test_gc_cache_entries() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let krate = dir.path().join("foo").join("foo-1.0.0.crate");
+        std::fs::create_dir_all(krate.parent().unwrap())?;
+        std::fs::write(&krate, b"")?;
+
+        // Recently used entries are kept...
+        super::gc_cache_entries(dir.path(), 2, Duration::from_secs(60 * 60))?;
+        assert!(krate.exists());
+
+        // ...as well as the ones in use by other processes...
+        std::thread::sleep(Duration::from_millis(10));
+        let lock = crate::cache_manager::entry_lock_path(&krate);
+        let held = crate::utils::try_lock_file_exclusive(&lock)?;
+        super::gc_cache_entries(dir.path(), 2, Duration::from_millis(1))?;
+        assert!(krate.exists());
+        drop(held);
+
+        // ...while old ones are removed, without removing the lock files.
+        super::gc_cache_entries(dir.path(), 2, Duration::from_millis(1))?;
+        assert!(!krate.exists());
+        assert!(lock.exists());
+        assert!(dir.path().join("foo").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stale_containers() {
+        let current = std::process::id();
+        let lines: Vec<String> = vec![
+            "aaa 1".into(),
+            "bbb 2".into(),
+            format!("ccc {}", current),
+            // Containers created without the label.
+            "ddd".into(),
+            "eee ".into(),
+        ];
+        let stale = super::stale_containers(&lines, |pid| pid == 2);
+        assert_eq!(stale, vec![("aaa", 1)]);
+    }
+}
@@ -0,0 +1,192 @@
+use crate::prepare::PrepareError;
+use failure::{Error, ResultExt};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Metadata of a crate, read from the `[package]` and `[features]` tables of its `Cargo.toml`.
+///
+/// The metadata is available as soon as the crate is fetched with
+/// [`Crate::manifest_info`](struct.Crate.html#method.manifest_info), without preparing or
+/// building it, so crates can be filtered and reported on before spending any build time.
+///
+/// Fields inherited from a workspace (like `version.workspace = true`) can't be resolved from
+/// the manifest alone, and are reported as missing. Crates published on a registry never
+/// inherit fields, as cargo normalizes their manifest when packaging them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestInfo {
+    name: String,
+    version: Option<String>,
+    edition: Option<String>,
+    license: Option<String>,
+    license_file: Option<String>,
+    rust_version: Option<String>,
+    features: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    package: Option<ManifestPackage>,
+    #[serde(default)]
+    features: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ManifestPackage {
+    name: String,
+    version: Option<MaybeInherited>,
+    edition: Option<MaybeInherited>,
+    license: Option<MaybeInherited>,
+    license_file: Option<MaybeInherited>,
+    rust_version: Option<MaybeInherited>,
+}
+
+/// Value of a field that can be inherited from the workspace.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MaybeInherited {
+    Value(String),
+    Inherited { workspace: bool },
+}
+
+impl MaybeInherited {
+    fn into_value(field: Option<Self>) -> Option<String> {
+        match field {
+            Some(MaybeInherited::Value(value)) => Some(value),
+            Some(MaybeInherited::Inherited { .. }) | None => None,
+        }
+    }
+}
+
+impl ManifestInfo {
+    /// Load the metadata from the `Cargo.toml` at the provided path.
+    pub async fn from_manifest(path: &Path) -> Result<Self, Error> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|_| PrepareError::MissingCargoToml)?;
+        Self::from_str(&content)
+    }
+
+    pub(crate) fn from_str(content: &str) -> Result<Self, Error> {
+        let manifest: Manifest =
+            toml::from_str(content).with_context(|_| PrepareError::InvalidCargoTomlSyntax)?;
+        let package = manifest
+            .package
+            .ok_or(PrepareError::InvalidCargoTomlSyntax)?;
+
+        Ok(ManifestInfo {
+            name: package.name,
+            version: MaybeInherited::into_value(package.version),
+            // Cargo defaults to the 2015 edition when the field is missing.
+            edition: match package.edition {
+                None => Some("2015".into()),
+                edition => MaybeInherited::into_value(edition),
+            },
+            license: MaybeInherited::into_value(package.license),
+            license_file: MaybeInherited::into_value(package.license_file),
+            rust_version: MaybeInherited::into_value(package.rust_version),
+            features: manifest.features,
+        })
+    }
+
+    /// Return the name of the package.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the version of the package, if it's present in the manifest.
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    /// Return the edition of the package, defaulting to `2015` when it's not specified like
+    /// cargo does. `None` is returned only when the edition is inherited from the workspace.
+    pub fn edition(&self) -> Option<&str> {
+        self.edition.as_deref()
+    }
+
+    /// Return the SPDX license expression of the package, if any.
+    pub fn license(&self) -> Option<&str> {
+        self.license.as_deref()
+    }
+
+    /// Return the path of the license file of the package, if any. Packages with a non-standard
+    /// license usually set this instead of [`license`](#method.license).
+    pub fn license_file(&self) -> Option<&str> {
+        self.license_file.as_deref()
+    }
+
+    /// Return the minimum supported Rust version declared in the `rust-version` field, if any.
+    pub fn rust_version(&self) -> Option<&str> {
+        self.rust_version.as_deref()
+    }
+
+    /// Return the features declared in the `[features]` table, with the features and
+    /// dependencies each of them enables. Optional dependencies that are not mentioned in the
+    /// table are not included.
+    pub fn features(&self) -> &BTreeMap<String, Vec<String>> {
+        &self.features
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ManifestInfo;
+
+    #[test]
+    fn test_from_str() {
+        let info = ManifestInfo::from_str(
+            r#"
+            [package]
+            name = "foo"
+            version = "1.2.3"
+            edition = "2018"
+            license = "MIT OR Apache-2.0"
+            rust-version = "1.40"
+
+            [features]
+            default = ["std"]
+            std = []
+            "#,
+        )
+        .unwrap();
+        assert_eq!(info.name(), "foo");
+        assert_eq!(info.version(), Some("1.2.3"));
+        assert_eq!(info.edition(), Some("2018"));
+        assert_eq!(info.license(), Some("MIT OR Apache-2.0"));
+        assert_eq!(info.license_file(), None);
+        assert_eq!(info.rust_version(), Some("1.40"));
+        assert_eq!(info.features().len(), 2);
+        assert_eq!(info.features()["default"], vec!["std".to_string()]);
+    }
+
+    #[test]
+    fn test_defaults_and_inherited_fields() {
+        let info = ManifestInfo::from_str(
+            r#"
+            [package]
+            name = "foo"
+            version.workspace = true
+            license-file = "LICENSE"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(info.version(), None);
+        assert_eq!(info.edition(), Some("2015"));
+        assert_eq!(info.license(), None);
+        assert_eq!(info.license_file(), Some("LICENSE"));
+        assert!(info.features().is_empty());
+
+        let info =
+            ManifestInfo::from_str("[package]\nname = \"foo\"\nedition = { workspace = true }\n")
+                .unwrap();
+        assert_eq!(info.edition(), None);
+    }
+
+    #[test]
+    fn test_invalid_manifest() {
+        assert!(ManifestInfo::from_str("[workspace]\nmembers = []\n").is_err());
+        assert!(ManifestInfo::from_str("[package\n").is_err());
+    }
+}
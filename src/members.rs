@@ -0,0 +1,159 @@
+use crate::cmd::container_dirs::WORK_DIR;
+use crate::Build;
+use failure::{Error, ResultExt};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// Member of the cargo workspace being built, returned by
+/// [`Build::workspace_members`](struct.Build.html#method.workspace_members).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkspaceMember {
+    name: String,
+    version: String,
+    path: PathBuf,
+}
+
+impl WorkspaceMember {
+    /// Get the name of the member's package.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the version of the member's package.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Get the directory containing the member's `Cargo.toml`, relative to the source directory
+    /// of the build. The root package of the workspace has an empty path.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    packages: Vec<Package>,
+    workspace_members: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    id: String,
+    name: String,
+    version: String,
+    manifest_path: PathBuf,
+}
+
+pub(crate) async fn list(build: &Build<'_>) -> Result<Vec<WorkspaceMember>, Error> {
+    let output = build
+        .cargo()
+        .args(&["metadata", "--no-deps", "--format-version", "1"])
+        .log_output(false)
+        .run_capture()
+        .await
+        .with_context(|_| "failed to list the members of the workspace")?;
+    parse_members(&output.stdout_lines().join("\n"), &WORK_DIR)
+}
+
+/// Extract the workspace members from the output of `cargo metadata`, making their paths relative
+/// to the source directory (mounted at `source_dir` when the command ran).
+fn parse_members(metadata: &str, source_dir: &Path) -> Result<Vec<WorkspaceMember>, Error> {
+    let metadata: Metadata = serde_json::from_str(metadata)?;
+    let members: BTreeSet<_> = metadata.workspace_members.iter().collect();
+
+    let mut result = Vec::new();
+    for package in metadata.packages {
+        if !members.contains(&package.id) {
+            continue;
+        }
+        let dir = package
+            .manifest_path
+            .parent()
+            .unwrap_or_else(|| Path::new(""));
+        let path = dir.strip_prefix(source_dir).with_context(|_| {
+            format!(
+                "workspace member {} is outside the source directory",
+                package.name
+            )
+        })?;
+        result.push(WorkspaceMember {
+            name: package.name,
+            version: package.version,
+            path: path.to_path_buf(),
+        });
+    }
+    result.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(result)
+}
+
+/// Return the cargo arguments selecting the provided packages, or the whole workspace if no
+/// package is provided.
+pub(crate) fn selection_args(packages: &[&str]) -> Vec<String> {
+    if packages.is_empty() {
+        return vec!["--workspace".into()];
+    }
+    packages
+        .iter()
+        .flat_map(|package| vec!["-p".to_string(), package.to_string()])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_members, selection_args};
+    use failure::Error;
+    use std::path::Path;
+
+    #[test]
+    fn test_parse_members() -> Result<(), Error> {
+        let metadata = serde_json::json!({
+            "packages": [
+                {
+                    "id": "foo-cli 0.1.0 (path+file:///opt/rustwide/workdir/cli)",
+                    "name": "foo-cli",
+                    "version": "0.1.0",
+                    "manifest_path": "/opt/rustwide/workdir/cli/Cargo.toml",
+                },
+                {
+                    "id": "foo 0.2.0 (path+file:///opt/rustwide/workdir)",
+                    "name": "foo",
+                    "version": "0.2.0",
+                    "manifest_path": "/opt/rustwide/workdir/Cargo.toml",
+                },
+                {
+                    "id": "excluded 0.1.0 (path+file:///opt/rustwide/workdir/excluded)",
+                    "name": "excluded",
+                    "version": "0.1.0",
+                    "manifest_path": "/opt/rustwide/workdir/excluded/Cargo.toml",
+                },
+            ],
+            "workspace_members": [
+                "foo-cli 0.1.0 (path+file:///opt/rustwide/workdir/cli)",
+                "foo 0.2.0 (path+file:///opt/rustwide/workdir)",
+            ],
+        })
+        .to_string();
+
+        let members = parse_members(&metadata, Path::new("/opt/rustwide/workdir"))?;
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].name(), "foo");
+        assert_eq!(members[0].version(), "0.2.0");
+        assert_eq!(members[0].path(), Path::new(""));
+        assert_eq!(members[1].name(), "foo-cli");
+        assert_eq!(members[1].path(), Path::new("cli"));
+
+        assert!(parse_members(&metadata, Path::new("/somewhere/else")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_selection_args() {
+        assert_eq!(selection_args(&[]), vec!["--workspace"]);
+        assert_eq!(
+            selection_args(&["foo", "foo-cli"]),
+            vec!["-p", "foo", "-p", "foo-cli"]
+        );
+    }
+}
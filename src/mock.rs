@@ -0,0 +1,272 @@
+//! Simulated workspaces, to test code embedding rustwide without Docker, network or rustup.
+//!
+//! A workspace created with [`Workspace::mock`](../struct.Workspace.html#method.mock) doesn't
+//! install anything during initialization, and instead of executing commands (sandboxed or not)
+//! it answers them with the output scripted in its [`MockFixtures`](struct.MockFixtures.html).
+//! Toolchain installs are commands too, so they're simulated as well. Crates are fetched from the
+//! local directories provided as fixtures rather than from their source.
+//!
+//! This module is only available when the `mock` feature is enabled.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use rustwide::{Crate, Toolchain, Workspace, mock::{MockCommand, MockFixtures}};
+//! # use std::error::Error;
+//! # async fn run() -> Result<(), Box<dyn Error>> {
+//! let krate = Crate::crates_io("lazy_static", "1.0.0")?;
+//! let fixtures = MockFixtures::new()
+//!     .crate_source(&krate, "fixtures/lazy_static".as_ref())
+//!     .command(MockCommand::new("cargo").args(&["build"]).stderr("error: oops").fail());
+//! let workspace = Workspace::mock("work".as_ref(), fixtures.clone())?;
+//!
+//! // Code under test:
+//! krate.fetch(&workspace).await?;
+//! Toolchain::dist("stable").install(&workspace).await?;
+//!
+//! assert!(fixtures
+//!     .executed_commands()
+//!     .iter()
+//!     .any(|cmd| cmd.starts_with("rustup toolchain install stable")));
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::cmd::CommandError;
+use crate::Crate;
+use std::collections::HashMap;
+use std::env::consts::EXE_SUFFIX;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Scripted answers of a mock workspace.
+///
+/// The fixtures are shared between all their clones, so a clone kept by the test can be used to
+/// inspect the commands executed by the workspace.
+#[derive(Clone, Default)]
+pub struct MockFixtures {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    crates: HashMap<String, PathBuf>,
+    commands: Vec<MockCommand>,
+    executed: Vec<String>,
+}
+
+impl MockFixtures {
+    /// Create new fixtures. Without any fixture every command succeeds without output, and
+    /// fetching any crate fails.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve the source code of a crate from a local directory, regardless of where the crate
+    /// actually comes from.
+    pub fn crate_source(self, krate: &Crate, source: &Path) -> Self {
+        self.lock()
+            .crates
+            .insert(krate.to_string(), source.to_path_buf());
+        self
+    }
+
+    /// Answer the commands matching the provided [`MockCommand`](struct.MockCommand.html) with
+    /// its scripted output. When multiple fixtures match a command the first one added is used.
+    pub fn command(self, command: MockCommand) -> Self {
+        self.lock().commands.push(command);
+        self
+    }
+
+    /// Get the commands executed by the workspace so far, in order. Each command is represented
+    /// by the name of its binary followed by its arguments, separated by spaces.
+    pub fn executed_commands(&self) -> Vec<String> {
+        self.lock().executed.clone()
+    }
+
+    pub(crate) fn crate_source_of(&self, krate: &Crate) -> Option<PathBuf> {
+        self.lock().crates.get(&krate.to_string()).cloned()
+    }
+
+    /// Record the execution of a command, returning the output scripted for it.
+    pub(crate) fn execute(&self, binary: &Path, args: &[OsString]) -> MockCommand {
+        let binary = binary_name(binary);
+        let args = args
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+
+        let mut inner = self.lock();
+        inner.executed.push(
+            std::iter::once(binary.clone())
+                .chain(args.iter().cloned())
+                .collect::<Vec<_>>()
+                .join(" "),
+        );
+        inner
+            .commands
+            .iter()
+            .find(|cmd| cmd.matches(&binary, &args))
+            .cloned()
+            .unwrap_or_else(|| MockCommand::new(&binary))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap()
+    }
+}
+
+/// Scripted command of a mock workspace, added with
+/// [`MockFixtures::command`](struct.MockFixtures.html#method.command).
+///
+/// A command matches when its binary has the same name (without the directory and the extension)
+/// and when the arguments provided with [`args`](#method.args) appear consecutively in its
+/// arguments. By default the scripted command succeeds without any output.
+#[derive(Debug, Clone)]
+pub struct MockCommand {
+    binary: String,
+    args: Vec<String>,
+    stdout: Vec<String>,
+    stderr: Vec<String>,
+    success: bool,
+    error: Option<CommandError>,
+}
+
+impl MockCommand {
+    /// Script the commands executing the binary with the provided name.
+    pub fn new(binary: &str) -> Self {
+        MockCommand {
+            binary: binary.into(),
+            args: Vec::new(),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            success: true,
+            error: None,
+        }
+    }
+
+    /// Only match the commands containing these arguments, in this order and next to each other.
+    pub fn args(mut self, args: &[&str]) -> Self {
+        self.args.extend(args.iter().map(|arg| arg.to_string()));
+        self
+    }
+
+    /// Add lines to the standard output of the command. This method can be called multiple times.
+    pub fn stdout(mut self, output: &str) -> Self {
+        self.stdout
+            .extend(output.lines().map(|line| line.to_string()));
+        self
+    }
+
+    /// Add lines to the standard error of the command. This method can be called multiple times.
+    pub fn stderr(mut self, output: &str) -> Self {
+        self.stderr
+            .extend(output.lines().map(|line| line.to_string()));
+        self
+    }
+
+    /// Make the command exit with a failure status after printing its output.
+    pub fn fail(mut self) -> Self {
+        self.success = false;
+        self
+    }
+
+    /// Make the command fail with the provided error after printing its output, to simulate for
+    /// example a timeout or a sandbox running out of memory.
+    pub fn error(mut self, error: CommandError) -> Self {
+        self.error = Some(error);
+        self
+    }
+
+    pub(crate) fn stdout_lines(&self) -> &[String] {
+        &self.stdout
+    }
+
+    pub(crate) fn stderr_lines(&self) -> &[String] {
+        &self.stderr
+    }
+
+    pub(crate) fn is_success(&self) -> bool {
+        self.success
+    }
+
+    pub(crate) fn error_ref(&self) -> Option<&CommandError> {
+        self.error.as_ref()
+    }
+
+    fn matches(&self, binary: &str, args: &[String]) -> bool {
+        self.binary == binary
+            && (self.args.is_empty()
+                || args
+                    .windows(self.args.len())
+                    .any(|window| window == &self.args[..]))
+    }
+}
+
+/// Get the name of a binary, without its directory and its extension.
+fn binary_name(binary: &Path) -> String {
+    let name = binary
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    name.strip_suffix(EXE_SUFFIX).unwrap_or(&name).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MockCommand, MockFixtures};
+    use crate::cmd::CommandError;
+    use std::ffi::OsString;
+    use std::path::Path;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn test_command_matching() {
+        let fixtures = MockFixtures::new()
+            .command(MockCommand::new("cargo").args(&["build"]).stdout("built"))
+            .command(MockCommand::new("cargo").stdout("other").fail());
+
+        let build = fixtures.execute(
+            Path::new("/cargo-home/bin/cargo"),
+            &args(&["+stable", "build", "--frozen"]),
+        );
+        assert_eq!(build.stdout_lines(), ["built"]);
+        assert!(build.is_success());
+
+        let test = fixtures.execute(Path::new("cargo"), &args(&["+stable", "test"]));
+        assert_eq!(test.stdout_lines(), ["other"]);
+        assert!(!test.is_success());
+
+        let unknown = fixtures.execute(Path::new("rustup"), &args(&["update"]));
+        assert!(unknown.error_ref().is_none());
+        assert!(unknown.stdout_lines().is_empty());
+        assert!(unknown.is_success());
+
+        assert_eq!(
+            fixtures.executed_commands(),
+            [
+                "cargo +stable build --frozen",
+                "cargo +stable test",
+                "rustup update"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_command_error() {
+        let fixtures = MockFixtures::new().command(
+            MockCommand::new("cargo")
+                .args(&["test"])
+                .stdout("running 1 test")
+                .error(CommandError::Timeout(10)),
+        );
+
+        let test = fixtures.execute(Path::new("cargo"), &args(&["+stable", "test"]));
+        assert_eq!(test.stdout_lines(), ["running 1 test"]);
+        assert!(matches!(test.error_ref(), Some(CommandError::Timeout(10))));
+    }
+}
@@ -0,0 +1,248 @@
+use crate::build::CratePatch;
+use crate::cmd::SandboxBuilder;
+use crate::{Build, BuildDirectory, Crate, Toolchain};
+use failure::Error;
+use log::info;
+
+/// Builder searching the oldest toolchain able to build a crate (its Minimum Supported Rust
+/// Version), created from [`BuildDirectory::find_msrv`](struct.BuildDirectory.html#method.find_msrv).
+///
+/// The search bisects across the stable dist toolchains installed in the workspace (the ones
+/// named after a version, like `1.40.0`), assuming a crate that builds with a toolchain also
+/// builds with all the newer ones. The crate's source code is fetched and prepared only once with
+/// the newest toolchain, and the same source directory, lockfile and target directory are reused
+/// by every build.
+pub struct MsrvSearch<'a> {
+    build_dir: &'a mut BuildDirectory,
+    krate: &'a Crate,
+    sandbox: SandboxBuilder,
+    patches: Vec<CratePatch>,
+    oldest: Option<Version>,
+    newest: Option<Version>,
+}
+
+impl<'a> MsrvSearch<'a> {
+    pub(crate) fn new(
+        build_dir: &'a mut BuildDirectory,
+        krate: &'a Crate,
+        sandbox: SandboxBuilder,
+    ) -> Self {
+        MsrvSearch {
+            build_dir,
+            krate,
+            sandbox,
+            patches: Vec::new(),
+            oldest: None,
+            newest: None,
+        }
+    }
+
+    /// Add a patch to this build.
+    /// Patches get added to the crate's Cargo.toml in the `patch.crates-io` table, and are shared
+    /// by the builds with all the toolchains.
+    pub fn patch_with_git(mut self, name: &str, uri: &str, branch: &str) -> Self {
+        self.patches.push(CratePatch {
+            name: name.into(),
+            uri: uri.into(),
+            branch: branch.into(),
+        });
+
+        self
+    }
+
+    /// Only consider the toolchains with this version or a newer one, like `1.31.0`.
+    pub fn oldest(mut self, version: &str) -> Result<Self, Error> {
+        self.oldest = Some(parse_bound(version)?);
+        Ok(self)
+    }
+
+    /// Only consider the toolchains with this version or an older one, like `1.50.0`.
+    pub fn newest(mut self, version: &str) -> Result<Self, Error> {
+        self.newest = Some(parse_bound(version)?);
+        Ok(self)
+    }
+
+    /// Run the search, returning the oldest toolchain able to build the crate, or `None` if not
+    /// even the newest toolchain can build it. The closure will be called once per toolchain
+    /// tried with an instance of [`Build`](struct.Build.html) configured to use that toolchain,
+    /// and an error returned by it is treated as a failure of the build.
+    ///
+    /// An error is returned by this method only if no toolchain is installed in the range, or if
+    /// preparing the crate fails.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # async fn run() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init().await?;
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// let msrv = build_dir
+    ///     .find_msrv(&krate, sandbox)
+    ///     .oldest("1.31.0")?
+    ///     .run(|build| {
+    ///         build.cargo().args(&["check"]).run()?;
+    ///         Ok(())
+    ///     })
+    ///     .await?;
+    /// match msrv {
+    ///     Some(toolchain) => println!("MSRV: {}", toolchain),
+    ///     None => println!("the crate doesn't build"),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn run<F: FnMut(&Build) -> Result<(), Error>>(
+        self,
+        mut f: F,
+    ) -> Result<Option<Toolchain>, Error> {
+        let mut toolchains = self
+            .build_dir
+            .workspace()
+            .installed_toolchains()?
+            .into_iter()
+            .filter_map(|toolchain| {
+                let version = parse_version(toolchain.as_dist()?.name())?;
+                Some((version, toolchain))
+            })
+            .filter(|(version, _)| self.oldest.map(|oldest| *version >= oldest).unwrap_or(true))
+            .filter(|(version, _)| self.newest.map(|newest| *version <= newest).unwrap_or(true))
+            .collect::<Vec<_>>();
+        toolchains.sort_by_key(|(version, _)| *version);
+        let toolchains: Vec<_> = toolchains.into_iter().map(|(_, t)| t).collect();
+
+        let newest = match toolchains.last() {
+            Some(newest) => newest,
+            None => failure::bail!("no versioned dist toolchain is installed in the range"),
+        };
+
+        let source_dir = self
+            .build_dir
+            .prepare_shared(newest, self.krate, self.patches, None)
+            .await?;
+        let build_dir = &*self.build_dir;
+        let (krate, sandbox) = (self.krate, &self.sandbox);
+        let mut bisection = Bisection::new(toolchains.len());
+        while let Some(index) = bisection.next_index() {
+            let toolchain = &toolchains[index];
+            info!("building {} with toolchain {}", krate, toolchain);
+            let builds = build_dir
+                .build_shared(&source_dir, krate, toolchain, sandbox, &mut f)
+                .await
+                .is_ok();
+            bisection.record(index, builds);
+        }
+        let msrv = bisection.result();
+
+        remove_dir_all::remove_dir_all(&source_dir)?;
+        Ok(msrv.map(|index| toolchains[index].clone()))
+    }
+}
+
+type Version = (u64, u64, u64);
+
+/// Parse the version of a toolchain name like `1.40.0` or `1.40.0-x86_64-unknown-linux-gnu`,
+/// treating a missing patch version as zero.
+fn parse_version(name: &str) -> Option<Version> {
+    let version = name.splitn(2, '-').next()?;
+    let mut parts = version.split('.').map(|part| part.parse::<u64>().ok());
+    let major = parts.next()??;
+    let minor = parts.next()??;
+    let patch = parts.next().unwrap_or(Some(0))?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((major, minor, patch))
+}
+
+fn parse_bound(version: &str) -> Result<Version, Error> {
+    parse_version(version).ok_or_else(|| failure::format_err!("invalid version: {}", version))
+}
+
+/// Find the first index in `0..len` for which `builds` returns true, assuming it keeps returning
+/// true for all the following indexes. Returns `None` if it never returns true.
+/// Binary search of the first index that builds, assuming every index after one that builds
+/// builds as well. The newest index is tried first, as nothing builds if it doesn't.
+struct Bisection {
+    /// Whether the newest index builds, or `None` if it wasn't tried yet.
+    newest_builds: Option<bool>,
+    // `high` always builds, while everything before `low` doesn't.
+    low: usize,
+    high: usize,
+}
+
+impl Bisection {
+    fn new(len: usize) -> Self {
+        Bisection {
+            newest_builds: if len == 0 { Some(false) } else { None },
+            low: 0,
+            high: len.saturating_sub(1),
+        }
+    }
+
+    /// Return the next index to build, or `None` if the search is over.
+    fn next_index(&self) -> Option<usize> {
+        match self.newest_builds {
+            None => Some(self.high),
+            Some(true) if self.low < self.high => Some(self.low + (self.high - self.low) / 2),
+            Some(_) => None,
+        }
+    }
+
+    /// Record whether the index returned by `next` builds.
+    fn record(&mut self, index: usize, builds: bool) {
+        if self.newest_builds.is_none() {
+            self.newest_builds = Some(builds);
+        } else if builds {
+            self.high = index;
+        } else {
+            self.low = index + 1;
+        }
+    }
+
+    fn result(&self) -> Option<usize> {
+        match self.newest_builds {
+            Some(true) => Some(self.high),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_version, Bisection};
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("1.40.0"), Some((1, 40, 0)));
+        assert_eq!(parse_version("1.40"), Some((1, 40, 0)));
+        assert_eq!(
+            parse_version("1.40.1-x86_64-unknown-linux-gnu"),
+            Some((1, 40, 1))
+        );
+        assert_eq!(parse_version("stable-x86_64-unknown-linux-gnu"), None);
+        assert_eq!(parse_version("nightly-2020-07-01"), None);
+        assert_eq!(parse_version("1.40.0.1"), None);
+    }
+
+    #[test]
+    fn test_bisect() {
+        for len in 0..10 {
+            for first in 0..=len {
+                let mut tried = Vec::new();
+                let mut bisection = Bisection::new(len);
+                while let Some(index) = bisection.next_index() {
+                    tried.push(index);
+                    bisection.record(index, index >= first);
+                }
+                let res = bisection.result();
+                assert_eq!(res, if first < len { Some(first) } else { None });
+                // Only a logarithmic number of builds is needed.
+                assert!(tried.len() <= (len as f64).log2().ceil() as usize + 1);
+            }
+        }
+    }
+}
@@ -7,3 +7,20 @@ pub(crate) use self::unix::*;
 mod windows;
 #[cfg(windows)]
 pub(crate) use self::windows::*;
+
+use std::time::Duration;
+
+/// Resource limits enforced by the host operating system on unsandboxed commands.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct ResourceLimits {
+    /// Maximum memory the process can allocate, in bytes.
+    pub(crate) memory: Option<usize>,
+    /// Maximum CPU time the process can use.
+    pub(crate) cpu_time: Option<Duration>,
+}
+
+impl ResourceLimits {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.memory.is_none() && self.cpu_time.is_none()
+    }
+}
@@ -1,5 +1,8 @@
+use super::ResourceLimits;
 use failure::Error;
 use nix::{
+    errno::Errno,
+    libc,
     sys::signal::{kill, Signal},
     unistd::{Gid, Pid, Uid},
 };
@@ -9,11 +12,77 @@ use std::path::Path;
 
 const EXECUTABLE_BITS: u32 = 0o5;
 
+const LOW_PRIORITY_NICENESS: libc::c_int = 19;
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+#[cfg(target_os = "linux")]
+const IOPRIO_IDLE: libc::c_long = 3 << 13;
+
 pub(crate) fn kill_process(id: u32) -> Result<(), Error> {
     kill(Pid::from_raw(id as i32), Signal::SIGKILL)?;
     Ok(())
 }
 
+/// Check whether a process with the provided ID is running. Processes owned by other users are
+/// reported as running even if they can't be signaled.
+pub(crate) fn process_exists(id: u32) -> bool {
+    !matches!(
+        kill(Pid::from_raw(id as i32), None),
+        Err(nix::Error::Sys(Errno::ESRCH))
+    )
+}
+
+pub(crate) fn lower_priority(cmd: &mut tokio::process::Command) {
+    // Lowering the priority is best-effort, so errors are ignored.
+    unsafe {
+        cmd.pre_exec(|| {
+            libc::setpriority(libc::PRIO_PROCESS as _, 0, LOW_PRIORITY_NICENESS);
+            #[cfg(target_os = "linux")]
+            libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, IOPRIO_IDLE);
+            Ok(())
+        });
+    }
+}
+
+/// Apply the limits to the process with `setrlimit` before executing it. The limits are inherited
+/// by its children, but each of them is limited separately.
+///
+/// The memory limit restricts the address space of the process, which is enforced on Linux but
+/// only partially on macOS.
+pub(crate) fn limit_resources(
+    cmd: &mut tokio::process::Command,
+    limits: ResourceLimits,
+    _low_priority: bool,
+) {
+    if limits.is_empty() {
+        return;
+    }
+    unsafe {
+        cmd.pre_exec(move || {
+            let set = |resource, value: libc::rlim_t| {
+                let limit = libc::rlimit {
+                    rlim_cur: value,
+                    rlim_max: value,
+                };
+                if libc::setrlimit(resource, &limit) == 0 {
+                    Ok(())
+                } else {
+                    Err(std::io::Error::last_os_error())
+                }
+            };
+            if let Some(memory) = limits.memory {
+                set(libc::RLIMIT_AS, memory as libc::rlim_t)?;
+            }
+            if let Some(cpu_time) = limits.cpu_time {
+                // The limit has a granularity of one second, rounded up to never be zero.
+                let secs = cpu_time.as_secs() + u64::from(cpu_time.subsec_nanos() > 0);
+                set(libc::RLIMIT_CPU, secs as libc::rlim_t)?;
+            }
+            Ok(())
+        });
+    }
+}
+
 pub(crate) fn current_user() -> Option<u32> {
     Some(Uid::effective().into())
 }
@@ -22,6 +91,14 @@ fn current_group() -> u32 {
     Gid::effective().into()
 }
 
+pub(crate) fn current_owner() -> Option<String> {
+    Some(format!(
+        "{}:{}",
+        u32::from(Uid::effective()),
+        current_group()
+    ))
+}
+
 fn executable_mode_for(path: &Path) -> Result<u32, Error> {
     let metadata = path.metadata()?;
 
@@ -53,12 +130,26 @@ pub(crate) fn make_executable<P: AsRef<Path>>(path: P) -> Result<(), Error> {
     Ok(())
 }
 
+/// Set the permissions of a file from a Unix mode, ignoring the special bits.
+pub(crate) fn set_mode(path: &Path, mode: u32) -> Result<(), Error> {
+    ::std::fs::set_permissions(path, ::std::fs::Permissions::from_mode(mode & 0o777))?;
+    Ok(())
+}
+
+/// Create a symbolic link at `link` pointing to `target`. `resolved` is the path the link will
+/// point to, which is ignored on Unix.
+pub(crate) fn symlink(target: &Path, _resolved: &Path, link: &Path) -> Result<(), Error> {
+    ::std::os::unix::fs::symlink(target, link)?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use nix::unistd::{Gid, Uid};
     use std::fs::File;
     use std::os::unix::process::ExitStatusExt;
     use std::process::Command;
+    use std::time::Duration;
 
     #[test]
     fn test_kill_process() {
@@ -70,6 +161,39 @@ mod tests {
         assert_eq!(cmd.wait().unwrap().signal(), Some(9));
     }
 
+    #[tokio::test]
+    async fn test_lower_priority() {
+        let mut cmd = tokio::process::Command::new("nice");
+        super::lower_priority(&mut cmd);
+        let out = cmd.output().await.unwrap();
+
+        let niceness: i32 = String::from_utf8(out.stdout)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        assert_eq!(niceness, super::LOW_PRIORITY_NICENESS);
+    }
+
+    #[tokio::test]
+    async fn test_limit_resources() {
+        let mut cmd = tokio::process::Command::new("sh");
+        cmd.args(&["-c", "ulimit -v; ulimit -t"]);
+        super::limit_resources(
+            &mut cmd,
+            super::ResourceLimits {
+                memory: Some(512 * 1024 * 1024),
+                cpu_time: Some(Duration::from_millis(1500)),
+            },
+            false,
+        );
+        let out = cmd.output().await.unwrap();
+
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        let limits: Vec<_> = stdout.lines().collect();
+        assert_eq!(limits, vec!["524288", "2"]);
+    }
+
     #[test]
     fn test_current_user() {
         assert_eq!(super::current_user(), Some(u32::from(Uid::effective())));
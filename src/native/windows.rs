@@ -1,9 +1,24 @@
+use super::ResourceLimits;
 use failure::{bail, Error};
 use std::fs::File;
 use std::path::Path;
+use winapi::shared::minwindef::{DWORD, LPVOID};
+use winapi::shared::winerror::ERROR_INVALID_PARAMETER;
+use winapi::um::errhandlingapi::GetLastError;
 use winapi::um::handleapi::CloseHandle;
-use winapi::um::processthreadsapi::{OpenProcess, TerminateProcess};
-use winapi::um::winnt::PROCESS_TERMINATE;
+use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+use winapi::um::jobapi2::{AssignProcessToJobObject, CreateJobObjectW, SetInformationJobObject};
+use winapi::um::processthreadsapi::{
+    GetExitCodeProcess, OpenProcess, OpenThread, ResumeThread, TerminateProcess,
+};
+use winapi::um::tlhelp32::{
+    CreateToolhelp32Snapshot, Thread32First, Thread32Next, TH32CS_SNAPTHREAD, THREADENTRY32,
+};
+use winapi::um::winnt::{
+    JobObjectExtendedLimitInformation, HANDLE, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+    JOB_OBJECT_LIMIT_JOB_MEMORY, JOB_OBJECT_LIMIT_JOB_TIME, JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+    PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_SET_QUOTA, PROCESS_TERMINATE, THREAD_SUSPEND_RESUME,
+};
 
 pub(crate) fn kill_process(id: u32) -> Result<(), Error> {
     unsafe {
@@ -22,10 +37,158 @@ pub(crate) fn kill_process(id: u32) -> Result<(), Error> {
     Ok(())
 }
 
+// Exit code reported by `GetExitCodeProcess` for running processes, from `minwinbase.h`.
+const STILL_ACTIVE: DWORD = 259;
+
+/// Check whether a process with the provided ID is running. Processes that can't be opened for
+/// other reasons than not existing (like access being denied) are reported as running.
+pub(crate) fn process_exists(id: u32) -> bool {
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, id);
+        if handle.is_null() {
+            return GetLastError() != ERROR_INVALID_PARAMETER;
+        }
+        let mut code: DWORD = 0;
+        let res = GetExitCodeProcess(handle, &mut code);
+        CloseHandle(handle);
+        res == 0 || code == STILL_ACTIVE
+    }
+}
+
+// Process creation flags from `winbase.h`.
+const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+const CREATE_SUSPENDED: u32 = 0x0000_0004;
+
+pub(crate) fn lower_priority(cmd: &mut tokio::process::Command) {
+    cmd.creation_flags(BELOW_NORMAL_PRIORITY_CLASS);
+}
+
+/// Limits can only be applied to a process after it's created on Windows, so the process is
+/// created suspended and only resumed by [`limit_process`](fn.limit_process.html) once it's
+/// assigned to the job object. Creation flags replace each other, so the priority is kept here.
+pub(crate) fn limit_resources(
+    cmd: &mut tokio::process::Command,
+    limits: ResourceLimits,
+    low_priority: bool,
+) {
+    if limits.is_empty() {
+        return;
+    }
+    if low_priority {
+        cmd.creation_flags(CREATE_SUSPENDED | BELOW_NORMAL_PRIORITY_CLASS);
+    } else {
+        cmd.creation_flags(CREATE_SUSPENDED);
+    }
+}
+
+/// Job object limiting the resources of the processes assigned to it. The processes are killed
+/// when the job is dropped.
+pub(crate) struct JobObject(HANDLE);
+
+// The handle can be closed from any thread.
+unsafe impl Send for JobObject {}
+
+impl Drop for JobObject {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.0);
+        }
+    }
+}
+
+/// Assign the suspended process to a new job object enforcing the limits, and resume it. Processes
+/// spawned by it are assigned to the same job, and the limits apply to all of them combined.
+pub(crate) fn limit_process(id: u32, limits: ResourceLimits) -> Result<Option<JobObject>, Error> {
+    if limits.is_empty() {
+        return Ok(None);
+    }
+    unsafe {
+        let job = CreateJobObjectW(std::ptr::null_mut(), std::ptr::null());
+        if job.is_null() {
+            bail!("CreateJobObjectW for process {} failed", id);
+        }
+        let job = JobObject(job);
+
+        let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = std::mem::zeroed();
+        info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        if let Some(memory) = limits.memory {
+            info.BasicLimitInformation.LimitFlags |= JOB_OBJECT_LIMIT_JOB_MEMORY;
+            info.JobMemoryLimit = memory;
+        }
+        if let Some(cpu_time) = limits.cpu_time {
+            // The time limit is expressed in 100-nanosecond ticks.
+            info.BasicLimitInformation.LimitFlags |= JOB_OBJECT_LIMIT_JOB_TIME;
+            *info
+                .BasicLimitInformation
+                .PerJobUserTimeLimit
+                .QuadPart_mut() = (cpu_time.as_nanos() / 100) as i64;
+        }
+        if SetInformationJobObject(
+            job.0,
+            JobObjectExtendedLimitInformation,
+            &mut info as *mut _ as LPVOID,
+            std::mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as DWORD,
+        ) == 0
+        {
+            bail!("SetInformationJobObject for process {} failed", id);
+        }
+
+        let handle = OpenProcess(PROCESS_SET_QUOTA | PROCESS_TERMINATE, 0, id);
+        if handle.is_null() {
+            bail!("OpenProcess for process {} failed", id);
+        }
+        let assigned = AssignProcessToJobObject(job.0, handle);
+        CloseHandle(handle);
+        if assigned == 0 {
+            bail!("AssignProcessToJobObject for process {} failed", id);
+        }
+
+        resume_process(id)?;
+        Ok(Some(job))
+    }
+}
+
+/// Resume the threads of a process created with `CREATE_SUSPENDED`. There is no documented API to
+/// resume a whole process, so its threads are looked up in a snapshot of the system's threads.
+fn resume_process(id: u32) -> Result<(), Error> {
+    unsafe {
+        let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0);
+        if snapshot == INVALID_HANDLE_VALUE {
+            bail!("CreateToolhelp32Snapshot for process {} failed", id);
+        }
+
+        let mut entry: THREADENTRY32 = std::mem::zeroed();
+        entry.dwSize = std::mem::size_of::<THREADENTRY32>() as DWORD;
+        let mut resumed = false;
+        let mut found = Thread32First(snapshot, &mut entry) != 0;
+        while found {
+            if entry.th32OwnerProcessID == id {
+                let thread = OpenThread(THREAD_SUSPEND_RESUME, 0, entry.th32ThreadID);
+                if !thread.is_null() {
+                    resumed |= ResumeThread(thread) != DWORD::MAX;
+                    CloseHandle(thread);
+                }
+            }
+            found = Thread32Next(snapshot, &mut entry) != 0;
+        }
+        CloseHandle(snapshot);
+
+        if !resumed {
+            bail!("failed to resume the threads of process {}", id);
+        }
+    }
+
+    Ok(())
+}
+
 pub(crate) fn current_user() -> Option<u32> {
     None
 }
 
+pub(crate) fn current_owner() -> Option<String> {
+    None
+}
+
 fn path_ends_in_exe<P: AsRef<Path>>(path: P) -> Result<bool, Error> {
     path.as_ref()
         .extension()
@@ -49,6 +212,22 @@ pub(crate) fn make_executable<P: AsRef<Path>>(path: P) -> Result<(), Error> {
     }
 }
 
+/// Unix modes have no equivalent on Windows, so nothing is done.
+pub(crate) fn set_mode(_path: &Path, _mode: u32) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Create a symbolic link at `link` pointing to `target`. `resolved` is the path the link will
+/// point to, needed to know whether to create a file or a directory link.
+pub(crate) fn symlink(target: &Path, resolved: &Path, link: &Path) -> Result<(), Error> {
+    if resolved.is_dir() {
+        std::os::windows::fs::symlink_dir(target, link)?;
+    } else {
+        std::os::windows::fs::symlink_file(target, link)?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
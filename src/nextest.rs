@@ -0,0 +1,72 @@
+use crate::diff::BuildSummary;
+use crate::tools::CARGO_NEXTEST;
+use crate::Build;
+use failure::{bail, Error};
+
+/// Test runner executing the tests of a crate with [cargo-nextest][nextest] instead of libtest's
+/// default harness, as some test suites only behave correctly when each test runs in its own
+/// process.
+///
+/// Nextest needs to be installed in the workspace with
+/// [`WorkspaceBuilder::nextest`](struct.WorkspaceBuilder.html#method.nextest). Its machine
+/// readable output is parsed into a [`BuildSummary`](diff/struct.BuildSummary.html), the same
+/// type the results of libtest are collected into, so results of the two runners can be compared
+/// with each other.
+///
+/// # Example
+///
+/// ```no_run
+/// # use rustwide::{Build, NextestRunner};
+/// # use std::error::Error;
+/// # async fn run(build: &Build<'_>) -> Result<(), Box<dyn Error>> {
+/// let summary = NextestRunner::new().run(build).await?;
+/// for (name, outcome) in summary.tests() {
+///     println!("{}: {:?}", name, outcome);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [nextest]: https://nexte.st
+pub struct NextestRunner {
+    args: Vec<String>,
+}
+
+impl NextestRunner {
+    /// Create a new runner, executing all the tests without stopping at the first failure.
+    pub fn new() -> Self {
+        NextestRunner {
+            args: vec!["--no-fail-fast".into()],
+        }
+    }
+
+    /// Override the arguments passed to `cargo nextest run`, for example to filter the tests or
+    /// to select a nextest profile.
+    pub fn args(mut self, args: &[&str]) -> Self {
+        self.args = args.iter().map(|arg| arg.to_string()).collect();
+        self
+    }
+
+    /// Run the tests of the crate, collecting their results.
+    ///
+    /// Test failures are not errors: they're recorded in the returned summary, along with
+    /// whether the command succeeded.
+    pub async fn run(&self, build: &Build<'_>) -> Result<BuildSummary, Error> {
+        if !build.workspace().nextest() {
+            bail!("nextest is not enabled in the workspace");
+        }
+
+        let mut summary = BuildSummary::new();
+        let res = build
+            .cmd(&CARGO_NEXTEST)
+            .args(&["run", "--message-format", "libtest-json"])
+            .args(&self.args)
+            // The libtest-compatible output is still experimental in nextest.
+            .env("NEXTEST_EXPERIMENTAL_LIBTEST_JSON", "1")
+            .process_lines(&mut |line, _| summary.process_line(line))
+            .run()
+            .await;
+        summary.set_success(res.is_ok());
+        Ok(summary)
+    }
+}
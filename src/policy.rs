@@ -0,0 +1,156 @@
+use crate::cmd::Command;
+use crate::prepare::PrepareError;
+use crate::{Toolchain, Workspace};
+use failure::{Error, ResultExt};
+use serde::Deserialize;
+use std::path::Path;
+
+/// Policy restricting which code provided by the crate (and by its dependencies) is executed on
+/// the host while building it, configured with
+/// [`BuildBuilder::code_execution_policy`](struct.BuildBuilder.html#method.code_execution_policy).
+///
+/// Build scripts and procedural macros are arbitrary programs executed by the compiler: even
+/// though they run inside the sandbox, some threat models don't allow executing them at all.
+/// The restrictions are enforced while preparing the crate, and preparation fails with
+/// [`PrepareError::ForbiddenCodeExecution`](enum.PrepareError.html) when a dependency would
+/// execute forbidden code. Dependencies only needed on other platforms or only by tests are
+/// checked as well.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CodeExecutionPolicy {
+    /// Build scripts and procedural macros are executed normally. This is the default.
+    Allow,
+    /// The build script of the crate is disabled (as if its manifest contained `build = false`),
+    /// and dependencies with build scripts are rejected. Procedural macros are still executed.
+    NoBuildScripts,
+    /// Like `NoBuildScripts`, but the crate and its dependencies can't be procedural macros
+    /// either: no code from the crate is executed at build time, making only `cargo check` and
+    /// metadata-only builds meaningful.
+    Forbid,
+}
+
+impl Default for CodeExecutionPolicy {
+    fn default() -> Self {
+        CodeExecutionPolicy::Allow
+    }
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    packages: Vec<Package>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    name: String,
+    targets: Vec<Target>,
+}
+
+#[derive(Deserialize)]
+struct Target {
+    kind: Vec<String>,
+}
+
+/// Check that no package in the dependency graph of the prepared crate executes code forbidden
+/// by the policy. Dependencies need to be already fetched.
+pub(crate) async fn check(
+    workspace: &Workspace,
+    toolchain: &Toolchain,
+    source_dir: &Path,
+    policy: CodeExecutionPolicy,
+) -> Result<(), Error> {
+    if policy == CodeExecutionPolicy::Allow {
+        return Ok(());
+    }
+
+    let output = Command::new(workspace, toolchain.cargo())
+        .args(&[
+            "metadata",
+            "--format-version",
+            "1",
+            "--offline",
+            "--manifest-path",
+            "Cargo.toml",
+        ])
+        .cd(source_dir)
+        .log_output(false)
+        .run_capture()
+        .await
+        .with_context(|_| "failed to inspect the dependencies of the crate")?;
+
+    if let Some(package) = find_forbidden(&output.stdout_lines().join("\n"), policy)? {
+        return Err(PrepareError::ForbiddenCodeExecution { package }.into());
+    }
+    Ok(())
+}
+
+/// Return the name of the first package in the output of `cargo metadata` that executes code
+/// forbidden by the policy, if any.
+fn find_forbidden(metadata: &str, policy: CodeExecutionPolicy) -> Result<Option<String>, Error> {
+    let metadata: Metadata = serde_json::from_str(metadata)?;
+    let forbidden: &[&str] = match policy {
+        CodeExecutionPolicy::Allow => &[],
+        CodeExecutionPolicy::NoBuildScripts => &["custom-build"],
+        CodeExecutionPolicy::Forbid => &["custom-build", "proc-macro"],
+    };
+
+    Ok(metadata
+        .packages
+        .into_iter()
+        .find(|package| {
+            package
+                .targets
+                .iter()
+                .flat_map(|target| &target.kind)
+                .any(|kind| forbidden.contains(&kind.as_str()))
+        })
+        .map(|package| package.name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_forbidden, CodeExecutionPolicy};
+    use failure::Error;
+
+    #[test]
+    fn test_find_forbidden() -> Result<(), Error> {
+        let metadata =
+            |packages: serde_json::Value| serde_json::json!({ "packages": packages }).to_string();
+        let pure = metadata(serde_json::json!([
+            { "name": "foo", "targets": [{ "kind": ["lib"] }, { "kind": ["bin"] }] },
+        ]));
+        let proc_macro = metadata(serde_json::json!([
+            { "name": "foo", "targets": [{ "kind": ["lib"] }] },
+            { "name": "serde_derive", "targets": [{ "kind": ["proc-macro"] }] },
+        ]));
+        let build_script = metadata(serde_json::json!([
+            { "name": "foo", "targets": [{ "kind": ["lib"] }] },
+            { "name": "libc", "targets": [{ "kind": ["lib"] }, { "kind": ["custom-build"] }] },
+        ]));
+
+        for policy in &[
+            CodeExecutionPolicy::Allow,
+            CodeExecutionPolicy::NoBuildScripts,
+            CodeExecutionPolicy::Forbid,
+        ] {
+            assert_eq!(find_forbidden(&pure, *policy)?, None);
+        }
+
+        assert_eq!(
+            find_forbidden(&build_script, CodeExecutionPolicy::Allow)?,
+            None
+        );
+        assert_eq!(
+            find_forbidden(&build_script, CodeExecutionPolicy::NoBuildScripts)?,
+            Some("libc".into())
+        );
+        assert_eq!(
+            find_forbidden(&proc_macro, CodeExecutionPolicy::NoBuildScripts)?,
+            None
+        );
+        assert_eq!(
+            find_forbidden(&proc_macro, CodeExecutionPolicy::Forbid)?,
+            Some("serde_derive".into())
+        );
+        Ok(())
+    }
+}
@@ -1,5 +1,5 @@
 use crate::cmd::Command;
-use crate::{build::CratePatch, Crate, Toolchain, Workspace};
+use crate::{build::CratePatch, CodeExecutionPolicy, Crate, Toolchain, Workspace};
 use failure::{Error, Fail, ResultExt};
 use log::info;
 use std::path::Path;
@@ -12,10 +12,12 @@ use toml::{
 pub(crate) struct Prepare<'a> {
     workspace: &'a Workspace,
     toolchain: &'a Toolchain,
+    lockfile_toolchain: &'a Toolchain,
     krate: &'a Crate,
     source_dir: &'a Path,
     lockfile_captured: bool,
     patches: Vec<CratePatch>,
+    code_execution_policy: CodeExecutionPolicy,
 }
 
 impl<'a> Prepare<'a> {
@@ -29,13 +31,28 @@ impl<'a> Prepare<'a> {
         Self {
             workspace,
             toolchain,
+            lockfile_toolchain: toolchain,
             krate,
             source_dir,
             lockfile_captured: false,
             patches,
+            code_execution_policy: CodeExecutionPolicy::Allow,
         }
     }
 
+    /// Use a different toolchain to generate the lockfile than the one used for the rest of the
+    /// preparation.
+    pub(crate) fn lockfile_toolchain(mut self, toolchain: &'a Toolchain) -> Self {
+        self.lockfile_toolchain = toolchain;
+        self
+    }
+
+    /// Restrict the code the crate and its dependencies can execute at build time.
+    pub(crate) fn code_execution_policy(mut self, policy: CodeExecutionPolicy) -> Self {
+        self.code_execution_policy = policy;
+        self
+    }
+
     pub(crate) async fn prepare(&mut self) -> Result<(), Error> {
         self.krate
             .copy_source_to(self.workspace, self.source_dir)
@@ -45,6 +62,13 @@ impl<'a> Prepare<'a> {
         self.tweak_toml().await?;
         self.capture_lockfile(false).await?;
         self.fetch_deps().await?;
+        crate::policy::check(
+            self.workspace,
+            self.toolchain,
+            self.source_dir,
+            self.code_execution_policy,
+        )
+        .await?;
 
         Ok(())
     }
@@ -87,6 +111,9 @@ impl<'a> Prepare<'a> {
         let path = self.source_dir.join("Cargo.toml");
         let mut tweaker = TomlTweaker::new(&self.krate, &path, &self.patches).await?;
         tweaker.tweak();
+        if self.code_execution_policy != CodeExecutionPolicy::Allow {
+            tweaker.disable_build_script();
+        }
         tweaker.save(&path).await?;
 
         Ok(())
@@ -102,8 +129,13 @@ impl<'a> Prepare<'a> {
             return Ok(());
         }
 
+        info!(
+            "generating lockfile of {} with toolchain {}",
+            self.krate, self.lockfile_toolchain
+        );
+
         let mut yanked_deps = false;
-        let mut cmd = Command::new(self.workspace, self.toolchain.cargo()).args(&[
+        let mut cmd = Command::new(self.workspace, self.lockfile_toolchain.cargo()).args(&[
             "generate-lockfile",
             "--manifest-path",
             "Cargo.toml",
@@ -353,6 +385,13 @@ impl<'a> TomlTweaker<'a> {
         }
     }
 
+    fn disable_build_script(&mut self) {
+        if let Some(&mut Value::Table(ref mut package)) = self.table.get_mut("package") {
+            package.insert("build".into(), Value::Boolean(false));
+            info!("disabled the build script of {}", self.krate);
+        }
+    }
+
     // This is not a method to avoid borrow checker problems
     fn remove_dependencies_from_table(table: &mut Table, krate: &str) {
         // Convert path dependencies to registry dependencies
@@ -386,7 +425,7 @@ impl<'a> TomlTweaker<'a> {
 }
 
 /// Error happened while preparing a crate for a build.
-#[derive(Debug, Fail)]
+#[derive(Debug, Clone, Fail)]
 pub enum PrepareError {
     /// The git repository isn't publicly available.
     #[fail(display = "can't fetch private git repositories")]
@@ -401,6 +440,55 @@ pub enum PrepareError {
     /// Some of this crate's dependencies were yanked, preventing Crater from fetching them.
     #[fail(display = "the crate depends on yanked dependencies")]
     YankedDependencies,
+    /// A package in the dependency graph of the crate executes code at build time, which is not
+    /// allowed by the [`CodeExecutionPolicy`](enum.CodeExecutionPolicy.html) of the build.
+    #[fail(
+        display = "package {} executes code at build time, which is forbidden by the policy",
+        package
+    )]
+    ForbiddenCodeExecution {
+        /// Name of the package executing forbidden code.
+        package: String,
+    },
+    /// The downloaded crate doesn't match the checksum recorded in the registry index, meaning
+    /// it was corrupted while being downloaded or served.
+    #[fail(display = "checksum mismatch: expected {}, found {}", expected, actual)]
+    ChecksumMismatch {
+        /// SHA-256 checksum recorded in the registry index.
+        expected: String,
+        /// SHA-256 checksum of the downloaded crate.
+        actual: String,
+    },
+    /// The source code of the crate contains a file whose name is not valid on Windows, like
+    /// `aux.rs` or a name ending with a dot, so it can't be extracted.
+    #[fail(display = "{} can't be extracted on Windows: {}", path, reason)]
+    InvalidWindowsPath {
+        /// Path of the file, relative to the root of the crate.
+        path: String,
+        /// Why the path is not valid on Windows.
+        reason: String,
+    },
+    /// The name of the crate is not accepted by crates.io.
+    #[fail(display = "invalid crate name {:?}: {}", name, reason)]
+    InvalidCrateName {
+        /// The invalid name.
+        name: String,
+        /// Why the name is not valid.
+        reason: String,
+    },
+    /// The version of the crate is not a valid semver version.
+    #[fail(
+        display = "invalid version {:?} of crate {}: {}",
+        version, name, reason
+    )]
+    InvalidCrateVersion {
+        /// Name of the crate.
+        name: String,
+        /// The invalid version.
+        version: String,
+        /// Why the version is not valid.
+        reason: String,
+    },
     #[doc(hidden)]
     #[fail(display = "this error shouldn't have happened")]
     __NonExaustive,
@@ -494,6 +582,31 @@ mod tests {
         assert_eq!(Value::Table(tweaker.table), result);
     }
 
+    #[test]
+    fn test_disable_build_script() {
+        let toml = toml! {
+            [package]
+            name = "foo"
+            version = "1.0"
+            build = "src/build.rs"
+        };
+
+        let result = toml! {
+            [package]
+            name = "foo"
+            version = "1.0"
+            build = false
+        };
+
+        let krate = Crate::local("/dev/null".as_ref());
+        let patches: Vec<CratePatch> = Vec::new();
+        let mut tweaker =
+            TomlTweaker::new_with_table(&krate, toml.as_table().unwrap().clone(), &patches);
+        tweaker.disable_build_script();
+
+        assert_eq!(Value::Table(tweaker.table), result);
+    }
+
     #[test]
     fn test_tweak_table_patches() {
         let toml = toml! {
@@ -0,0 +1,271 @@
+use crate::artifacts::ArtifactReport;
+use crate::cmd::{image_id, EnvironmentSnapshot};
+use crate::utils::{hex, hmac_sha256};
+use crate::Workspace;
+use failure::{Error, ResultExt};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+
+/// Provenance record of a build, retrieved with
+/// [`Build::provenance`](struct.Build.html#method.provenance).
+///
+/// The record describes what went into the build and what came out of it: a digest of the
+/// prepared source code, the toolchain, the sandbox image with its digest, the commands executed
+/// in the sandbox and the SHA-256 checksum of each final artifact. It can be serialized and
+/// stored along with the build results, so they can be audited and verified later.
+///
+/// Records can be signed with a secret key using HMAC-SHA256, allowing anyone with the same key to
+/// check the record wasn't tampered with after the build.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    #[serde(rename = "crate")]
+    krate: String,
+    inputs_digest: String,
+    toolchain: String,
+    image: String,
+    image_digest: Option<String>,
+    commands: Vec<Vec<String>>,
+    outputs: BTreeMap<String, String>,
+    signature: Option<String>,
+}
+
+impl Provenance {
+    /// Return the name of the crate that was built.
+    pub fn krate(&self) -> &str {
+        &self.krate
+    }
+
+    /// Return the SHA-256 digest of the prepared source code, covering the path and the content
+    /// of every file. The digest is computed before any command runs, after the patches are
+    /// applied and the lockfile is generated.
+    pub fn inputs_digest(&self) -> &str {
+        &self.inputs_digest
+    }
+
+    /// Return the name of the toolchain used by the build.
+    pub fn toolchain(&self) -> &str {
+        &self.toolchain
+    }
+
+    /// Return the name of the sandbox image the commands ran in.
+    pub fn image(&self) -> &str {
+        &self.image
+    }
+
+    /// Return the digest of the sandbox image (like `sha256:...`), if Docker reported it.
+    pub fn image_digest(&self) -> Option<&str> {
+        self.image_digest.as_deref()
+    }
+
+    /// Return the commands executed in the sandbox, in the order they were started. The values
+    /// redacted from the logs are redacted here as well.
+    pub fn commands(&self) -> &[Vec<String>] {
+        &self.commands
+    }
+
+    /// Return the SHA-256 checksum of each final artifact produced by the build, keyed by their
+    /// path relative to the target directory (like `debug/foo`).
+    pub fn outputs(&self) -> &BTreeMap<String, String> {
+        &self.outputs
+    }
+
+    /// Return the hex-encoded HMAC-SHA256 signature of the record, if it was signed.
+    pub fn signature(&self) -> Option<&str> {
+        self.signature.as_deref()
+    }
+
+    /// Sign the record with the provided secret key, replacing the previous signature if any.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::Build;
+    /// # use std::error::Error;
+    /// # async fn run(build: &Build<'_>) -> Result<(), Box<dyn Error>> {
+    /// let mut provenance = build.provenance().await?;
+    /// provenance.sign(b"secret key")?;
+    /// println!("{}", serde_json::to_string(&provenance)?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn sign(&mut self, key: &[u8]) -> Result<(), Error> {
+        self.signature = Some(hex(&hmac_sha256(key, &self.payload()?)));
+        Ok(())
+    }
+
+    /// Check whether the record is signed with the provided secret key and wasn't modified after
+    /// being signed. Unsigned records are never valid.
+    pub fn verify(&self, key: &[u8]) -> Result<bool, Error> {
+        let signature = match &self.signature {
+            Some(signature) => signature,
+            None => return Ok(false),
+        };
+        let expected = hex(&hmac_sha256(key, &self.payload()?));
+        // Compare all the bytes, so the time taken doesn't reveal how much of the signature is
+        // correct.
+        Ok(signature.len() == expected.len()
+            && signature
+                .bytes()
+                .zip(expected.bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0)
+    }
+
+    /// Serialize the record without its signature, which is the data being signed.
+    fn payload(&self) -> Result<Vec<u8>, Error> {
+        let unsigned = Provenance {
+            signature: None,
+            ..self.clone()
+        };
+        Ok(serde_json::to_vec(&unsigned)?)
+    }
+}
+
+/// Inputs of a build recorded before it starts, used to create its
+/// [`Provenance`](struct.Provenance.html).
+pub(crate) struct ProvenanceInputs {
+    krate: String,
+    digest: String,
+}
+
+impl ProvenanceInputs {
+    pub(crate) fn record(krate: String, source_dir: &Path) -> Result<Self, Error> {
+        Ok(ProvenanceInputs {
+            krate,
+            digest: digest_dir(source_dir)
+                .with_context(|_| "failed to compute the digest of the source code")?,
+        })
+    }
+
+    pub(crate) async fn provenance(
+        &self,
+        workspace: &Workspace,
+        toolchain: String,
+        snapshots: Vec<EnvironmentSnapshot>,
+        target_dir: &Path,
+    ) -> Result<Provenance, Error> {
+        let image = snapshots
+            .last()
+            .map(|snapshot| snapshot.image().to_string())
+            .unwrap_or_else(|| workspace.sandbox_image().name().to_string());
+
+        let mut outputs = BTreeMap::new();
+        for artifact in ArtifactReport::collect(target_dir, false)?.artifacts() {
+            let path = target_dir.join(artifact.path());
+            outputs.insert(artifact.path().to_string(), digest_file(&path)?);
+        }
+
+        Ok(Provenance {
+            krate: self.krate.clone(),
+            inputs_digest: self.digest.clone(),
+            toolchain,
+            image_digest: match image_id(&image).await {
+                Ok(id) => Some(id),
+                Err(err) => {
+                    warn!(
+                        "failed to retrieve the digest of the image {}: {}",
+                        image, err
+                    );
+                    None
+                }
+            },
+            image,
+            commands: snapshots
+                .into_iter()
+                .map(|snapshot| snapshot.command().to_vec())
+                .collect(),
+            outputs,
+            signature: None,
+        })
+    }
+}
+
+/// Compute the digest of all the files in a directory, including their path relative to it.
+/// Symlinks are included with their target, without following them.
+fn digest_dir(dir: &Path) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    let entries = walkdir::WalkDir::new(dir)
+        .min_depth(1)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()));
+    for entry in entries {
+        let entry = entry?;
+        let content = if entry.file_type().is_file() {
+            digest_file(entry.path())?
+        } else if entry.file_type().is_symlink() {
+            format!("-> {}", std::fs::read_link(entry.path())?.display())
+        } else {
+            continue;
+        };
+        let path = entry.path().strip_prefix(dir)?.to_string_lossy();
+        hasher.update(path.replace('\\', "/").as_bytes());
+        hasher.update(b"\0");
+        hasher.update(content.as_bytes());
+        hasher.update(b"\n");
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn digest_file(path: &Path) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{digest_dir, Provenance};
+    use failure::Error;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_sign_and_verify() -> Result<(), Error> {
+        let mut provenance = Provenance {
+            krate: "foo 1.0.0".into(),
+            inputs_digest: "0".repeat(64),
+            toolchain: "stable".into(),
+            image: "rustops/crates-build-env".into(),
+            image_digest: None,
+            commands: vec![vec!["cargo".into(), "build".into()]],
+            outputs: BTreeMap::new(),
+            signature: None,
+        };
+        assert!(!provenance.verify(b"key")?);
+
+        provenance.sign(b"key")?;
+        assert!(provenance.verify(b"key")?);
+        assert!(!provenance.verify(b"other key")?);
+
+        let json = serde_json::to_string(&provenance)?;
+        let mut parsed: Provenance = serde_json::from_str(&json)?;
+        assert!(parsed.verify(b"key")?);
+        parsed.toolchain = "nightly".into();
+        assert!(!parsed.verify(b"key")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_digest_dir() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("src"))?;
+        std::fs::write(dir.path().join("Cargo.toml"), "[package]\n")?;
+        std::fs::write(dir.path().join("src").join("lib.rs"), "")?;
+        let digest = digest_dir(dir.path())?;
+        assert_eq!(digest, digest_dir(dir.path())?);
+
+        // Renaming a file changes the digest even if the content is the same.
+        std::fs::rename(
+            dir.path().join("src").join("lib.rs"),
+            dir.path().join("src").join("main.rs"),
+        )?;
+        let renamed = digest_dir(dir.path())?;
+        assert_ne!(digest, renamed);
+
+        std::fs::write(dir.path().join("src").join("main.rs"), "fn main() {}")?;
+        assert_ne!(renamed, digest_dir(dir.path())?);
+        Ok(())
+    }
+}
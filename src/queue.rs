@@ -0,0 +1,600 @@
+//! Durable on-disk queue of builds, processed by worker loops.
+//!
+//! A [`BuildQueue`] stores [`BuildConfig`]s in a directory, so that queued builds survive restarts
+//! and can be shared by multiple processes. Workers claim the queued builds one at a time, run them
+//! in their own build directory and record a typed [`QueueOutcome`] for each of them. Claiming a
+//! build atomically moves it out of the queue, so every build is run by exactly one worker.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use rustwide::queue::{BuildConfig, BuildQueue, QueuedCrate};
+//! use rustwide::{Toolchain, WorkspaceBuilder};
+//! # use std::error::Error;
+//! # async fn run() -> Result<(), Box<dyn Error>> {
+//! # let workspace = WorkspaceBuilder::new("".as_ref(), "").init().await?;
+//! let queue = BuildQueue::open("queue".as_ref())?;
+//! queue.push(
+//!     &BuildConfig::new(
+//!         QueuedCrate::crates_io("lazy_static", "1.0.0"),
+//!         Toolchain::dist("stable"),
+//!     )
+//!     .args(&["test"]),
+//! )?;
+//!
+//! let mut build_dir = workspace.build_dir("worker-1");
+//! queue
+//!     .work(&mut build_dir, |build, config| {
+//!         build.cargo().args(config.cargo_args()).run()?;
+//!         Ok(())
+//!     })
+//!     .await?;
+//!
+//! for record in queue.outcomes()? {
+//!     println!("{}: {:?}", record.config().krate(), record.outcome());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`BuildQueue`]: struct.BuildQueue.html
+//! [`BuildConfig`]: struct.BuildConfig.html
+//! [`QueueOutcome`]: enum.QueueOutcome.html
+
+use crate::cmd::SandboxBuilder;
+use crate::{Build, BuildDirectory, Crate, PrepareError, Toolchain};
+use failure::{Context, Error, ResultExt};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const PENDING_DIR: &str = "pending";
+const RUNNING_DIR: &str = "running";
+const DONE_DIR: &str = "done";
+
+/// Source of the crate built by a [`BuildConfig`](struct.BuildConfig.html).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum QueuedCrate {
+    /// A crate published on crates.io, with its name and version.
+    CratesIo {
+        /// Name of the crate.
+        name: String,
+        /// Version of the crate.
+        version: String,
+    },
+    /// A crate published on an alternative registry, with its index URL, name and version.
+    Registry {
+        /// URL of the registry index.
+        registry_url: String,
+        /// Name of the crate.
+        name: String,
+        /// Version of the crate.
+        version: String,
+    },
+    /// The default branch of a git repository.
+    Git {
+        /// URL of the repository.
+        url: String,
+    },
+    /// A directory on the local filesystem.
+    Local {
+        /// Path to the directory.
+        path: PathBuf,
+    },
+    #[doc(hidden)]
+    #[serde(skip)]
+    __NonExaustive,
+}
+
+impl QueuedCrate {
+    /// Queue a crate published on crates.io.
+    pub fn crates_io(name: &str, version: &str) -> Self {
+        QueuedCrate::CratesIo {
+            name: name.into(),
+            version: version.into(),
+        }
+    }
+
+    fn to_crate(&self) -> Result<Crate, Error> {
+        Ok(match self {
+            QueuedCrate::CratesIo { name, version } => Crate::crates_io(name, version)?,
+            QueuedCrate::Registry {
+                registry_url,
+                name,
+                version,
+            } => Crate::registry(registry_url, name, version)?,
+            QueuedCrate::Git { url } => Crate::git(url),
+            QueuedCrate::Local { path } => Crate::local(path),
+            QueuedCrate::__NonExaustive => panic!("do not create __NonExaustive variants manually"),
+        })
+    }
+}
+
+impl fmt::Display for QueuedCrate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueuedCrate::CratesIo { name, version } => write!(f, "{}-{}", name, version),
+            QueuedCrate::Registry {
+                registry_url,
+                name,
+                version,
+            } => write!(f, "{}-{} from {}", name, version, registry_url),
+            QueuedCrate::Git { url } => write!(f, "{}", url),
+            QueuedCrate::Local { path } => write!(f, "{}", path.display()),
+            QueuedCrate::__NonExaustive => panic!("do not create __NonExaustive variants manually"),
+        }
+    }
+}
+
+fn default_networking() -> bool {
+    true
+}
+
+/// Build stored in a [`BuildQueue`](struct.BuildQueue.html): the crate to build, the toolchain to
+/// use and the configuration of the sandbox.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BuildConfig {
+    krate: QueuedCrate,
+    toolchain: Toolchain,
+    #[serde(default)]
+    cargo_args: Vec<String>,
+    #[serde(default)]
+    memory_limit: Option<usize>,
+    #[serde(default)]
+    cpu_limit: Option<f32>,
+    #[serde(default = "default_networking")]
+    enable_networking: bool,
+}
+
+impl BuildConfig {
+    /// Create a new build of the crate with the provided toolchain.
+    pub fn new(krate: QueuedCrate, toolchain: Toolchain) -> Self {
+        BuildConfig {
+            krate,
+            toolchain,
+            cargo_args: Vec::new(),
+            memory_limit: None,
+            cpu_limit: None,
+            enable_networking: true,
+        }
+    }
+
+    /// Add arguments to pass to Cargo, retrieved by the workers with
+    /// [`cargo_args`](#method.cargo_args). The queue doesn't interpret them.
+    pub fn args(mut self, args: &[&str]) -> Self {
+        self.cargo_args
+            .extend(args.iter().map(|arg| arg.to_string()));
+        self
+    }
+
+    /// Set the memory limit of the sandbox in bytes. By default no memory limit is present.
+    pub fn memory_limit(mut self, limit: Option<usize>) -> Self {
+        self.memory_limit = limit;
+        self
+    }
+
+    /// Set the CPU limit of the sandbox in cores. By default no CPU limit is present.
+    pub fn cpu_limit(mut self, limit: Option<f32>) -> Self {
+        self.cpu_limit = limit;
+        self
+    }
+
+    /// Enable or disable the sandbox's networking. By default networking is enabled.
+    pub fn enable_networking(mut self, enable: bool) -> Self {
+        self.enable_networking = enable;
+        self
+    }
+
+    /// Get the crate to build.
+    pub fn krate(&self) -> &QueuedCrate {
+        &self.krate
+    }
+
+    /// Get the toolchain to build the crate with.
+    pub fn toolchain(&self) -> &Toolchain {
+        &self.toolchain
+    }
+
+    /// Get the arguments to pass to Cargo.
+    pub fn cargo_args(&self) -> &[String] {
+        &self.cargo_args
+    }
+
+    fn sandbox(&self) -> SandboxBuilder {
+        SandboxBuilder::new()
+            .memory_limit(self.memory_limit)
+            .cpu_limit(self.cpu_limit)
+            .enable_networking(self.enable_networking)
+    }
+}
+
+/// Outcome of a build processed by a [`BuildQueue`](struct.BuildQueue.html) worker. The errors
+/// are stored as their messages, including their causes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "outcome", rename_all = "kebab-case")]
+pub enum QueueOutcome {
+    /// The build completed successfully.
+    Success,
+    /// The closure provided to the worker failed, for example because the crate doesn't compile.
+    BuildFailed {
+        /// Message of the error returned by the closure.
+        error: String,
+    },
+    /// The crate couldn't be prepared for the build, for example because it's missing its
+    /// `Cargo.toml` or its dependencies can't be resolved.
+    PrepareFailed {
+        /// Message of the error that occurred while preparing the crate.
+        error: String,
+    },
+    /// The build couldn't be run for another reason, for example because the crate couldn't be
+    /// downloaded.
+    Error {
+        /// Message of the error.
+        error: String,
+    },
+    #[doc(hidden)]
+    #[serde(skip)]
+    __NonExaustive,
+}
+
+impl QueueOutcome {
+    /// Check whether the build completed successfully.
+    pub fn is_success(&self) -> bool {
+        matches!(self, QueueOutcome::Success)
+    }
+
+    fn from_result(res: Result<Result<(), Error>, Error>) -> Self {
+        match res {
+            Ok(Ok(())) => QueueOutcome::Success,
+            Ok(Err(err)) => QueueOutcome::BuildFailed {
+                error: error_message(&err),
+            },
+            Err(err) if is_prepare_error(&err) => QueueOutcome::PrepareFailed {
+                error: error_message(&err),
+            },
+            Err(err) => QueueOutcome::Error {
+                error: error_message(&err),
+            },
+        }
+    }
+}
+
+/// Outcome of a build processed by a [`BuildQueue`](struct.BuildQueue.html), along with the build
+/// itself.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QueueRecord {
+    id: String,
+    config: BuildConfig,
+    outcome: QueueOutcome,
+    duration_secs: f64,
+}
+
+impl QueueRecord {
+    /// Get the ID assigned to the build when it was queued.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Get the build that was run.
+    pub fn config(&self) -> &BuildConfig {
+        &self.config
+    }
+
+    /// Get the outcome of the build.
+    pub fn outcome(&self) -> &QueueOutcome {
+        &self.outcome
+    }
+
+    /// Get how long the build took.
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.duration_secs)
+    }
+}
+
+/// Durable queue of builds stored in a directory. See the [module documentation](index.html) for
+/// an example.
+///
+/// The queue can be opened by multiple processes at the same time, as long as the directory is on
+/// a filesystem supporting atomic renames. Builds are run in the order they were queued.
+pub struct BuildQueue {
+    dir: PathBuf,
+    next_id: AtomicU64,
+}
+
+impl BuildQueue {
+    /// Open the queue stored in the provided directory, creating it if it doesn't exist.
+    pub fn open(dir: &Path) -> Result<Self, Error> {
+        for subdir in &[PENDING_DIR, RUNNING_DIR, DONE_DIR] {
+            std::fs::create_dir_all(dir.join(subdir))
+                .with_context(|_| format!("failed to create the queue at {}", dir.display()))?;
+        }
+        Ok(BuildQueue {
+            dir: dir.into(),
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    /// Add a build at the end of the queue, returning the ID assigned to it.
+    pub fn push(&self, config: &BuildConfig) -> Result<String, Error> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        // IDs sort in the order the builds were queued, and are unique across processes.
+        let id = format!(
+            "{:016}-{}-{}",
+            millis,
+            std::process::id(),
+            self.next_id.fetch_add(1, Ordering::SeqCst)
+        );
+        crate::utils::write_atomic(
+            &self.entry_path(PENDING_DIR, &id),
+            &serde_json::to_vec(config)?,
+        )?;
+        Ok(id)
+    }
+
+    /// Return the builds waiting in the queue, in the order they're going to be run.
+    pub fn pending(&self) -> Result<Vec<(String, BuildConfig)>, Error> {
+        let mut pending = Vec::new();
+        for id in self.ids(PENDING_DIR)? {
+            // The build might have been claimed since the directory was listed.
+            if let Some(config) = read_entry(&self.entry_path(PENDING_DIR, &id))? {
+                pending.push((id, config));
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Return the outcomes of the builds processed so far, in the order they were queued.
+    pub fn outcomes(&self) -> Result<Vec<QueueRecord>, Error> {
+        let mut outcomes = Vec::new();
+        for id in self.ids(DONE_DIR)? {
+            if let Some(record) = read_entry(&self.entry_path(DONE_DIR, &id))? {
+                outcomes.push(record);
+            }
+        }
+        Ok(outcomes)
+    }
+
+    /// Put the builds that were claimed but never completed back in the queue, returning how many
+    /// of them were found. This recovers the builds interrupted by a crash, and must only be called
+    /// while no worker is running, as their builds would be run twice otherwise.
+    pub fn requeue_interrupted(&self) -> Result<usize, Error> {
+        let ids = self.ids(RUNNING_DIR)?;
+        for id in &ids {
+            info!("putting the interrupted build {} back in the queue", id);
+            std::fs::rename(
+                self.entry_path(RUNNING_DIR, id),
+                self.entry_path(PENDING_DIR, id),
+            )?;
+        }
+        Ok(ids.len())
+    }
+
+    /// Run the queued builds in the provided build directory until the queue is empty, returning
+    /// how many builds were processed. Multiple workers can process the same queue at the same
+    /// time, each with its own build directory.
+    ///
+    /// The closure is called for every build with an instance of [`Build`](../struct.Build.html)
+    /// configured as requested by the [`BuildConfig`](struct.BuildConfig.html), and the error it
+    /// returns is recorded as a [`BuildFailed`](enum.QueueOutcome.html#variant.BuildFailed)
+    /// outcome. The worker stops early if the workspace is shutting down, leaving the build it
+    /// was running in the queue. An error is returned only if the queue itself can't be updated.
+    pub async fn work<F: FnMut(&Build, &BuildConfig) -> Result<(), Error>>(
+        &self,
+        build_dir: &mut BuildDirectory,
+        mut f: F,
+    ) -> Result<usize, Error> {
+        let mut processed = 0;
+        while let Some((id, config)) = self.claim()? {
+            info!("running queued build {} of {}", id, config.krate);
+            let start = Instant::now();
+            let res = match config.krate.to_crate() {
+                Ok(krate) => {
+                    build_dir
+                        .build(&config.toolchain, &krate, config.sandbox())
+                        .run(|build| Ok(f(build, &config)))
+                        .await
+                }
+                Err(err) => Err(err),
+            };
+            let outcome = QueueOutcome::from_result(res);
+
+            // Builds failing because the workspace is shutting down are run again later.
+            if !outcome.is_success() && build_dir.workspace().shutdown_tracker().is_shutting_down()
+            {
+                warn!("the workspace is shutting down, putting build {} back", id);
+                std::fs::rename(
+                    self.entry_path(RUNNING_DIR, &id),
+                    self.entry_path(PENDING_DIR, &id),
+                )?;
+                break;
+            }
+
+            self.complete(QueueRecord {
+                id,
+                config,
+                outcome,
+                duration_secs: start.elapsed().as_secs_f64(),
+            })?;
+            processed += 1;
+        }
+        Ok(processed)
+    }
+
+    /// Take the oldest build out of the queue, or return `None` if the queue is empty.
+    fn claim(&self) -> Result<Option<(String, BuildConfig)>, Error> {
+        for id in self.ids(PENDING_DIR)? {
+            let running = self.entry_path(RUNNING_DIR, &id);
+            // Renaming is atomic, so only one worker can claim each build.
+            match std::fs::rename(self.entry_path(PENDING_DIR, &id), &running) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            }
+            match read_entry(&running)? {
+                Some(config) => return Ok(Some((id, config))),
+                None => continue,
+            }
+        }
+        Ok(None)
+    }
+
+    fn complete(&self, record: QueueRecord) -> Result<(), Error> {
+        crate::utils::write_atomic(
+            &self.entry_path(DONE_DIR, &record.id),
+            &serde_json::to_vec(&record)?,
+        )?;
+        std::fs::remove_file(self.entry_path(RUNNING_DIR, &record.id))?;
+        Ok(())
+    }
+
+    fn entry_path(&self, subdir: &str, id: &str) -> PathBuf {
+        self.dir.join(subdir).join(format!("{}.json", id))
+    }
+
+    /// List the IDs of the entries in a subdirectory of the queue, sorted in the queue's order.
+    fn ids(&self, subdir: &str) -> Result<Vec<String>, Error> {
+        let mut ids = Vec::new();
+        for entry in std::fs::read_dir(self.dir.join(subdir))? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            if let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) {
+                ids.push(id.to_string());
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+}
+
+/// Read an entry of the queue, returning `None` if it was moved by another worker.
+fn read_entry<T: serde::de::DeserializeOwned>(path: &Path) -> Result<Option<T>, Error> {
+    let content = match std::fs::read(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(Some(serde_json::from_slice(&content).with_context(
+        |_| format!("invalid queue entry {}", path.display()),
+    )?))
+}
+
+/// Check whether an error occurred while preparing the crate, either directly or as the context
+/// of a lower level error.
+fn is_prepare_error(err: &Error) -> bool {
+    err.iter_chain().any(|cause| {
+        cause.downcast_ref::<PrepareError>().is_some()
+            || cause.downcast_ref::<Context<PrepareError>>().is_some()
+    })
+}
+
+fn error_message(err: &Error) -> String {
+    err.iter_chain()
+        .map(|cause| cause.to_string())
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuildConfig, BuildQueue, QueueOutcome, QueueRecord, QueuedCrate};
+    use crate::{PrepareError, Toolchain};
+    use failure::{Error, Fail};
+
+    fn config(name: &str) -> BuildConfig {
+        BuildConfig::new(
+            QueuedCrate::crates_io(name, "1.0.0"),
+            Toolchain::dist("stable"),
+        )
+    }
+
+    #[test]
+    fn test_queue() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let queue = BuildQueue::open(dir.path())?;
+        let first = queue.push(&config("foo").args(&["test"]))?;
+        let second = queue.push(&config("bar"))?;
+        assert_eq!(
+            queue
+                .pending()?
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>(),
+            vec![first.clone(), second.clone()]
+        );
+
+        // Another process opening the same queue sees the same builds.
+        let other = BuildQueue::open(dir.path())?;
+        let (id, claimed) = other.claim()?.unwrap();
+        assert_eq!(id, first);
+        assert_eq!(claimed.cargo_args(), &["test".to_string()]);
+        assert_eq!(queue.pending()?.len(), 1);
+
+        other.complete(QueueRecord {
+            id: id.clone(),
+            config: claimed,
+            outcome: QueueOutcome::Success,
+            duration_secs: 1.0,
+        })?;
+        let outcomes = queue.outcomes()?;
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(outcomes[0].id(), first);
+        assert!(outcomes[0].outcome().is_success());
+
+        // Interrupted builds are put back in the queue.
+        let (id, _) = queue.claim()?.unwrap();
+        assert_eq!(id, second);
+        assert!(queue.claim()?.is_none());
+        assert_eq!(queue.requeue_interrupted()?, 1);
+        assert_eq!(queue.claim()?.unwrap().0, second);
+        Ok(())
+    }
+
+    #[test]
+    fn test_outcome() {
+        assert_eq!(QueueOutcome::from_result(Ok(Ok(()))), QueueOutcome::Success);
+        assert_eq!(
+            QueueOutcome::from_result(Ok(Err(failure::err_msg("does not compile")))),
+            QueueOutcome::BuildFailed {
+                error: "does not compile".into()
+            }
+        );
+        assert_eq!(
+            QueueOutcome::from_result(Err(PrepareError::MissingCargoToml.into())),
+            QueueOutcome::PrepareFailed {
+                error: PrepareError::MissingCargoToml.to_string()
+            }
+        );
+        let err = std::io::Error::new(std::io::ErrorKind::NotFound, "no such file");
+        assert_eq!(
+            QueueOutcome::from_result(Err(err.context(PrepareError::MissingCargoToml).into())),
+            QueueOutcome::PrepareFailed {
+                error: format!("{}: no such file", PrepareError::MissingCargoToml)
+            }
+        );
+        assert_eq!(
+            QueueOutcome::from_result(Err(failure::err_msg("download failed"))),
+            QueueOutcome::Error {
+                error: "download failed".into()
+            }
+        );
+    }
+
+    #[test]
+    fn test_serde_repr() -> Result<(), Error> {
+        let config: BuildConfig = serde_json::from_str(
+            r#"{"krate": {"type": "crates-io", "name": "foo", "version": "1.0.0"},
+                "toolchain": {"type": "dist", "name": "stable"}}"#,
+        )?;
+        assert_eq!(config, self::config("foo"));
+        Ok(())
+    }
+}
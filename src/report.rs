@@ -0,0 +1,382 @@
+//! Structured reports of builds, renderable to JSON or to a self-contained HTML page.
+//!
+//! A [`BuildReport`] collects the outcome of a build (the phases it went through and how long
+//! they took, its log, the resources it used and the artifacts it produced), and renders it in a
+//! format suitable to be attached to CI runs or issue reports.
+//!
+//! ## Example
+//!
+//! ```
+//! use rustwide::{report::BuildReport, Crate, Toolchain};
+//! use std::time::Duration;
+//!
+//! let krate = Crate::crates_io("lazy_static", "1.0.0").unwrap();
+//! let report = BuildReport::new(&krate, &Toolchain::dist("stable"))
+//!     .phase("prepare", Duration::from_secs(12), true)
+//!     .phase("build", Duration::from_secs(42), false);
+//!
+//! assert!(!report.success());
+//! let json = report.to_json().unwrap();
+//! let html = report.to_html();
+//! ```
+//!
+//! [`BuildReport`]: struct.BuildReport.html
+
+use crate::logging::LogStorage;
+use crate::{ArtifactReport, BuildTimings, Crate, Toolchain, UnitTiming};
+use failure::Error;
+use serde::Serialize;
+use std::fmt::Write;
+use std::path::Path;
+use std::time::Duration;
+
+const DEFAULT_MAX_LOG_SIZE: usize = 64 * 1024;
+
+/// A single phase of a build, for example preparing the crate or running the tests.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Phase {
+    name: String,
+    duration_secs: f64,
+    success: bool,
+}
+
+impl Phase {
+    /// Return the name of the phase.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return how long the phase took.
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.duration_secs)
+    }
+
+    /// Check whether the phase completed successfully.
+    pub fn success(&self) -> bool {
+        self.success
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+struct ResourceUsage {
+    peak_memory_bytes: Option<u64>,
+    cpu_time_secs: Option<f64>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+struct Artifact {
+    path: String,
+    size: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+struct ReportLog {
+    content: String,
+    truncated: bool,
+}
+
+/// Structured report of a build. See the [module documentation](index.html) for an example.
+#[derive(Serialize, Debug, Clone)]
+pub struct BuildReport {
+    #[serde(rename = "crate")]
+    krate: String,
+    toolchain: Toolchain,
+    success: bool,
+    phases: Vec<Phase>,
+    resources: ResourceUsage,
+    artifacts: Vec<Artifact>,
+    units: Vec<UnitTiming>,
+    log: Option<ReportLog>,
+    #[serde(skip)]
+    max_log_size: usize,
+}
+
+impl BuildReport {
+    /// Create a new, empty report for a build of the provided crate with the provided toolchain.
+    pub fn new(krate: &Crate, toolchain: &Toolchain) -> Self {
+        BuildReport {
+            krate: krate.to_string(),
+            toolchain: toolchain.clone(),
+            success: true,
+            phases: Vec::new(),
+            resources: ResourceUsage::default(),
+            artifacts: Vec::new(),
+            units: Vec::new(),
+            log: None,
+            max_log_size: DEFAULT_MAX_LOG_SIZE,
+        }
+    }
+
+    /// Record a phase of the build. Phases are rendered in the order they are added, and a single
+    /// failed phase marks the whole build as failed.
+    pub fn phase(mut self, name: &str, duration: Duration, success: bool) -> Self {
+        self.success &= success;
+        self.phases.push(Phase {
+            name: name.into(),
+            duration_secs: duration.as_secs_f64(),
+            success,
+        });
+        self
+    }
+
+    /// Set the maximum amount of bytes of the log included in the report. Only the end of longer
+    /// logs will be included. By default the limit is 64 KiB.
+    ///
+    /// This method needs to be called before [`log`](#method.log) to have any effect.
+    pub fn max_log_size(mut self, size: usize) -> Self {
+        self.max_log_size = size;
+        self
+    }
+
+    /// Include the contents of a [`LogStorage`](../logging/struct.LogStorage.html) in the report.
+    pub fn log(mut self, storage: &LogStorage) -> Self {
+        let content = storage.to_string();
+        let (content, truncated) = truncate_start(&content, self.max_log_size);
+        self.log = Some(ReportLog {
+            content: content.into(),
+            truncated,
+        });
+        self
+    }
+
+    /// Record the peak memory usage (in bytes) of the build.
+    pub fn peak_memory(mut self, bytes: u64) -> Self {
+        self.resources.peak_memory_bytes = Some(bytes);
+        self
+    }
+
+    /// Record the CPU time spent by the build.
+    pub fn cpu_time(mut self, time: Duration) -> Self {
+        self.resources.cpu_time_secs = Some(time.as_secs_f64());
+        self
+    }
+
+    /// Record an artifact produced by the build, along with its size in bytes.
+    pub fn artifact(mut self, path: &Path, size: u64) -> Self {
+        self.artifacts.push(Artifact {
+            path: path.to_string_lossy().into(),
+            size,
+        });
+        self
+    }
+
+    /// Record all the artifacts of an [`ArtifactReport`](../struct.ArtifactReport.html), along
+    /// with the size of the generated documentation (recorded as the `doc` artifact).
+    pub fn artifacts(mut self, report: &ArtifactReport) -> Self {
+        for artifact in report.artifacts() {
+            self = self.artifact(Path::new(artifact.path()), artifact.size());
+        }
+        if let Some(size) = report.doc_size() {
+            self = self.artifact(Path::new("doc"), size);
+        }
+        self
+    }
+
+    /// Record the compile times of the units in [`BuildTimings`](../struct.BuildTimings.html).
+    pub fn timings(mut self, timings: &BuildTimings) -> Self {
+        self.units.extend(timings.units().iter().cloned());
+        self
+    }
+
+    /// Check whether all the phases of the build completed successfully.
+    pub fn success(&self) -> bool {
+        self.success
+    }
+
+    /// Return the phases recorded in the report.
+    pub fn phases(&self) -> &[Phase] {
+        &self.phases
+    }
+
+    /// Return the total duration of all the phases recorded in the report.
+    pub fn total_duration(&self) -> Duration {
+        self.phases.iter().map(|phase| phase.duration()).sum()
+    }
+
+    /// Render the report as JSON.
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Render the report as a self-contained HTML page, without any external resource.
+    pub fn to_html(&self) -> String {
+        // Writing to a String never fails, so the results are ignored.
+        let mut html = String::new();
+        let status = if self.success { "success" } else { "failure" };
+
+        let _ = write!(
+            html,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>{krate} on {toolchain}: {status}</title>\n<style>{style}</style>\n\
+             </head>\n<body>\n<h1>{krate} on {toolchain}: \
+             <span class=\"{status}\">{status}</span></h1>\n",
+            krate = escape_html(&self.krate),
+            toolchain = escape_html(&self.toolchain.to_string()),
+            status = status,
+            style = HTML_STYLE,
+        );
+
+        html.push_str(
+            "<h2>Phases</h2>\n<table>\n<tr><th>Phase</th><th>Duration</th><th>Status</th></tr>\n",
+        );
+        for phase in &self.phases {
+            let status = if phase.success { "success" } else { "failure" };
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td>{:.2}s</td><td class=\"{status}\">{status}</td></tr>",
+                escape_html(&phase.name),
+                phase.duration_secs,
+                status = status,
+            );
+        }
+        let _ = writeln!(
+            html,
+            "<tr><th>Total</th><th>{:.2}s</th><th></th></tr>\n</table>",
+            self.total_duration().as_secs_f64()
+        );
+
+        if self.resources != ResourceUsage::default() {
+            html.push_str("<h2>Resource usage</h2>\n<ul>\n");
+            if let Some(bytes) = self.resources.peak_memory_bytes {
+                let _ = writeln!(html, "<li>Peak memory: {} bytes</li>", bytes);
+            }
+            if let Some(secs) = self.resources.cpu_time_secs {
+                let _ = writeln!(html, "<li>CPU time: {:.2}s</li>", secs);
+            }
+            html.push_str("</ul>\n");
+        }
+
+        if !self.artifacts.is_empty() {
+            html.push_str("<h2>Artifacts</h2>\n<ul>\n");
+            for artifact in &self.artifacts {
+                let _ = writeln!(
+                    html,
+                    "<li><code>{}</code> ({} bytes)</li>",
+                    escape_html(&artifact.path),
+                    artifact.size
+                );
+            }
+            html.push_str("</ul>\n");
+        }
+
+        if !self.units.is_empty() {
+            html.push_str(
+                "<h2>Compile times</h2>\n<table>\n\
+                 <tr><th>Package</th><th>Target</th><th>Mode</th><th>Duration</th></tr>\n",
+            );
+            let mut units: Vec<_> = self.units.iter().collect();
+            units.sort_by(|a, b| b.duration().cmp(&a.duration()));
+            for unit in units {
+                let _ = writeln!(
+                    html,
+                    "<tr><td>{} {}</td><td>{}</td><td>{}</td><td>{:.2}s</td></tr>",
+                    escape_html(unit.name()),
+                    escape_html(unit.version()),
+                    escape_html(unit.target()),
+                    escape_html(unit.mode()),
+                    unit.duration().as_secs_f64(),
+                );
+            }
+            html.push_str("</table>\n");
+        }
+
+        if let Some(log) = &self.log {
+            html.push_str("<h2>Log</h2>\n");
+            if log.truncated {
+                html.push_str("<p><em>The log was truncated, only its end is shown.</em></p>\n");
+            }
+            let _ = writeln!(html, "<pre>{}</pre>", escape_html(&log.content));
+        }
+
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+}
+
+static HTML_STYLE: &str = "body{font-family:sans-serif;margin:2em;}\
+    table{border-collapse:collapse;}th,td{border:1px solid #ccc;padding:.3em .6em;text-align:left;}\
+    .success{color:#2a7a2a;}.failure{color:#b22222;}\
+    pre{background:#f6f6f6;padding:1em;overflow-x:auto;}";
+
+/// Keep at most `max` bytes from the end of `content`, without splitting lines or characters.
+fn truncate_start(content: &str, max: usize) -> (&str, bool) {
+    if content.len() <= max {
+        return (content, false);
+    }
+
+    let mut start = content.len() - max;
+    while !content.is_char_boundary(start) {
+        start += 1;
+    }
+    let truncated = &content[start..];
+    match truncated.find('\n') {
+        Some(newline) => (&truncated[newline + 1..], true),
+        None => (truncated, true),
+    }
+}
+
+fn escape_html(input: &str) -> String {
+    let mut result = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            other => result.push(other),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_html, truncate_start, BuildReport};
+    use crate::{Crate, Toolchain};
+    use failure::Error;
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn test_truncate_start() {
+        assert_eq!(truncate_start("foo\nbar\n", 100), ("foo\nbar\n", false));
+        assert_eq!(truncate_start("foo\nbar\nbaz\n", 6), ("baz\n", true));
+        assert_eq!(truncate_start("ééé", 3), ("é", true));
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html("<a href=\"x\">&'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn test_render() -> Result<(), Error> {
+        let krate = Crate::crates_io("foo", "1.0.0")?;
+        let report = BuildReport::new(&krate, &Toolchain::dist("stable"))
+            .phase("prepare", Duration::from_secs(1), true)
+            .phase("<build>", Duration::from_secs(2), false)
+            .peak_memory(1024)
+            .artifact(Path::new("target/debug/foo"), 42);
+
+        assert!(!report.success());
+        assert_eq!(report.total_duration(), Duration::from_secs(3));
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()?)?;
+        assert_eq!(json["success"], false);
+        assert_eq!(json["toolchain"]["name"], "stable");
+        assert_eq!(json["phases"][1]["name"], "<build>");
+        assert_eq!(json["resources"]["peak_memory_bytes"], 1024);
+        assert_eq!(json["artifacts"][0]["size"], 42);
+
+        let html = report.to_html();
+        assert!(html.contains("&lt;build&gt;"));
+        assert!(!html.contains("<build>"));
+        assert!(html.contains("target/debug/foo"));
+
+        Ok(())
+    }
+}
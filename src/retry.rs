@@ -0,0 +1,256 @@
+use crate::Workspace;
+use failure::Error;
+use log::warn;
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+/// Status codes returned by servers for errors that usually go away on their own.
+const DEFAULT_STATUS_CODES: &[u16] = &[408, 429, 500, 502, 503, 504];
+
+/// Policy used to retry the network operations of the workspace failing with transient errors,
+/// configured with
+/// [`WorkspaceBuilder::retry_policy`](struct.WorkspaceBuilder.html#method.retry_policy).
+///
+/// The policy applies to the downloads made by rustwide (such as crate tarballs, registry
+/// metadata, the rustup installer and standalone toolchains) and to the toolchain installations
+/// made through rustup. Failed attempts are retried after a delay starting at the initial backoff
+/// and doubling after each attempt, up to the maximum backoff. No operation is retried once the
+/// workspace is shutting down.
+///
+/// By default operations are attempted 3 times, with a backoff between 1 and 30 seconds, retrying
+/// connection errors, timeouts and the 408, 429, 500, 502, 503 and 504 status codes. Sandboxed
+/// commands failing because of the Docker daemon are not retried unless
+/// [`retry_docker_failures`](#method.retry_docker_failures) is enabled.
+///
+/// # Example
+///
+/// ```no_run
+/// # use rustwide::{RetryPolicy, WorkspaceBuilder};
+/// # use std::error::Error;
+/// # use std::time::Duration;
+/// # async fn run() -> Result<(), Box<dyn Error>> {
+/// let workspace = WorkspaceBuilder::new("work".as_ref(), "my-app")
+///     .retry_policy(
+///         RetryPolicy::new()
+///             .attempts(5)
+///             .backoff(Duration::from_millis(500), Duration::from_secs(60)),
+///     )
+///     .init()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    status_codes: Vec<u16>,
+    connection_errors: bool,
+    docker_failures: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            status_codes: DEFAULT_STATUS_CODES.to_vec(),
+            connection_errors: true,
+            docker_failures: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Create a new policy with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a policy never retrying failed operations.
+    pub fn disabled() -> Self {
+        Self::default().attempts(1)
+    }
+
+    /// Set how many times an operation is attempted in total, including the first attempt. At
+    /// least one attempt is always made.
+    pub fn attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    /// Set the delay before the first retry, and the maximum delay between two attempts.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Set the HTTP status codes of the failed requests that are retried, replacing the default
+    /// ones.
+    pub fn retry_status_codes(mut self, codes: &[u16]) -> Self {
+        self.status_codes = codes.to_vec();
+        self
+    }
+
+    /// Set whether connection errors (like refused or reset connections) and timeouts are
+    /// retried.
+    pub fn retry_connection_errors(mut self, enable: bool) -> Self {
+        self.connection_errors = enable;
+        self
+    }
+
+    /// Set whether sandboxed commands failing with a
+    /// [`CommandError::SpuriousDockerFailure`](cmd/enum.CommandError.html#variant.SpuriousDockerFailure)
+    /// are executed once more in a new container. The output of the failed attempt is still
+    /// passed to the command's [`process_lines`](cmd/struct.Command.html#method.process_lines)
+    /// function. By default they're not retried.
+    pub fn retry_docker_failures(mut self, enable: bool) -> Self {
+        self.docker_failures = enable;
+        self
+    }
+
+    /// Check whether sandboxed commands failing because of the Docker daemon are retried.
+    pub(crate) fn retries_docker_failures(&self) -> bool {
+        self.docker_failures
+    }
+
+    /// Get the delay before the provided retry, starting from 1.
+    fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |delay| delay.min(self.max_backoff))
+    }
+
+    /// Check whether the error of a network operation is transient.
+    pub(crate) fn is_retryable(&self, err: &Error) -> bool {
+        for cause in err.iter_chain() {
+            if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+                if let Some(status) = err.status() {
+                    return self.status_codes.contains(&status.as_u16());
+                }
+                if err.is_timeout() && self.connection_errors {
+                    return true;
+                }
+                // The underlying IO error is only reachable through the standard error chain.
+                let mut source = std::error::Error::source(err);
+                while let Some(err) = source {
+                    if let Some(err) = err.downcast_ref::<io::Error>() {
+                        return self.is_retryable_io(err);
+                    }
+                    source = err.source();
+                }
+            } else if let Some(err) = cause.downcast_ref::<io::Error>() {
+                return self.is_retryable_io(err);
+            }
+        }
+        false
+    }
+
+    fn is_retryable_io(&self, err: &io::Error) -> bool {
+        self.connection_errors
+            && matches!(
+                err.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::UnexpectedEof
+            )
+    }
+}
+
+/// Run the network operation described by `what`, retrying it according to the workspace's
+/// policy when it fails with a transient error.
+pub(crate) async fn retry<T, F, Fut>(workspace: &Workspace, what: &str, f: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let policy = workspace.retry_policy();
+    retry_if(workspace, what, |err| policy.is_retryable(err), f).await
+}
+
+/// Run the operation described by `what` like [`retry`](fn.retry.html), using a custom check
+/// to know whether a failure is transient.
+pub(crate) async fn retry_if<T, F, Fut>(
+    workspace: &Workspace,
+    what: &str,
+    is_retryable: impl Fn(&Error) -> bool,
+    mut f: F,
+) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let policy = workspace.retry_policy();
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Err(err)
+                if attempt < policy.attempts
+                    && !workspace.shutdown_tracker().is_shutting_down()
+                    && is_retryable(&err) =>
+            {
+                let delay = policy.delay(attempt);
+                warn!(
+                    "{} failed (attempt {} of {}), retrying in {:?}: {}",
+                    what, attempt, policy.attempts, delay, err
+                );
+                let delay =
+                    crate::utils::enter(workspace.runtime(), || tokio::time::delay_for(delay));
+                delay.await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryPolicy;
+    use failure::Error;
+    use std::io;
+    use std::time::Duration;
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::new().backoff(Duration::from_secs(1), Duration::from_secs(10));
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(3), Duration::from_secs(4));
+        assert_eq!(policy.delay(5), Duration::from_secs(10));
+        assert_eq!(policy.delay(100), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        let policy = RetryPolicy::new();
+        let io_error = |kind| Error::from(io::Error::new(kind, "error"));
+
+        assert!(policy.is_retryable(&io_error(io::ErrorKind::ConnectionReset)));
+        assert!(policy.is_retryable(&io_error(io::ErrorKind::TimedOut)));
+        assert!(!policy.is_retryable(&io_error(io::ErrorKind::NotFound)));
+        assert!(!policy.is_retryable(&failure::err_msg("error")));
+
+        // The cause of an error is checked too.
+        let err = io_error(io::ErrorKind::ConnectionReset).context("failed to download");
+        assert!(policy.is_retryable(&err.into()));
+
+        let policy = policy.retry_connection_errors(false);
+        assert!(!policy.is_retryable(&io_error(io::ErrorKind::ConnectionReset)));
+    }
+
+    #[test]
+    fn test_attempts() {
+        assert_eq!(RetryPolicy::disabled().attempts, 1);
+        assert_eq!(RetryPolicy::new().attempts(0).attempts, 1);
+    }
+}
@@ -0,0 +1,202 @@
+use crate::Crate;
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+
+/// Planner distributing a set of crates across multiple build directories, to maximize how many
+/// dependencies are reused from the target directories instead of being rebuilt.
+///
+/// Each crate is added along with the dependencies it builds, identified by any string unique to
+/// a compiled dependency (for example `serde 1.0.104`, taken from the index or from
+/// `cargo metadata`). Crates sharing most of their dependencies are assigned to the same build
+/// directory, and each build directory's queue is ordered so consecutive crates share as many
+/// dependencies as possible. The build directories are kept balanced, so that their queues can be
+/// processed in parallel.
+///
+/// # Example
+///
+/// ```
+/// use rustwide::{BuildScheduler, Crate};
+///
+/// let plan = BuildScheduler::new(2)
+///     .add(Crate::crates_io("a", "1.0.0").unwrap(), &["serde 1.0.104", "syn 1.0.11"])
+///     .add(Crate::crates_io("b", "1.0.0").unwrap(), &["rand 0.7.3"])
+///     .add(Crate::crates_io("c", "1.0.0").unwrap(), &["serde 1.0.104", "syn 1.0.11"])
+///     .add(Crate::crates_io("d", "1.0.0").unwrap(), &["rand 0.7.3", "libc 0.2.66"])
+///     .plan();
+///
+/// for (i, queue) in plan.build_dirs().iter().enumerate() {
+///     for krate in queue {
+///         println!("build directory {}: {}", i, krate);
+///     }
+/// }
+/// assert_eq!(plan.reused_dependencies(), 3);
+/// ```
+pub struct BuildScheduler {
+    build_dirs: usize,
+    crates: Vec<(Crate, BTreeSet<String>)>,
+}
+
+impl BuildScheduler {
+    /// Create a new scheduler distributing the crates across the provided number of build
+    /// directories. At least one build directory is always used.
+    pub fn new(build_dirs: usize) -> Self {
+        BuildScheduler {
+            build_dirs: build_dirs.max(1),
+            crates: Vec::new(),
+        }
+    }
+
+    /// Add a crate to schedule, along with the dependencies it builds.
+    pub fn add(mut self, krate: Crate, dependencies: &[&str]) -> Self {
+        let dependencies = dependencies.iter().map(|dep| dep.to_string()).collect();
+        self.crates.push((krate, dependencies));
+        self
+    }
+
+    /// Compute the plan, assigning every crate to a build directory.
+    pub fn plan(self) -> SchedulePlan {
+        let assignment = assign(
+            &self.crates.iter().map(|(_, deps)| deps).collect::<Vec<_>>(),
+            self.build_dirs,
+        );
+
+        let mut crates: Vec<_> = self.crates.into_iter().map(Some).collect();
+        let mut reused = 0;
+        let build_dirs = assignment
+            .into_iter()
+            .map(|queue| {
+                let mut built = BTreeSet::new();
+                queue
+                    .into_iter()
+                    .map(|index| {
+                        let (krate, deps) = crates[index].take().unwrap();
+                        reused += deps.intersection(&built).count();
+                        built.extend(deps);
+                        krate
+                    })
+                    .collect()
+            })
+            .collect();
+
+        SchedulePlan { build_dirs, reused }
+    }
+}
+
+/// Plan computed by a [`BuildScheduler`](struct.BuildScheduler.html).
+pub struct SchedulePlan {
+    build_dirs: Vec<Vec<Crate>>,
+    reused: usize,
+}
+
+impl SchedulePlan {
+    /// Return the queue of crates assigned to each build directory, in the order they should be
+    /// built. Different queues can be processed in parallel.
+    pub fn build_dirs(&self) -> &[Vec<Crate>] {
+        &self.build_dirs
+    }
+
+    /// Consume the plan, returning the queue of crates assigned to each build directory.
+    pub fn into_build_dirs(self) -> Vec<Vec<Crate>> {
+        self.build_dirs
+    }
+
+    /// Return how many times a crate's dependency was already built by an earlier crate in the
+    /// same build directory, and can be reused from its target directory.
+    pub fn reused_dependencies(&self) -> usize {
+        self.reused
+    }
+}
+
+/// Assign the crates (represented by their dependencies) to the build directories, returning
+/// the indexes of the crates in each build directory's queue.
+fn assign(crates: &[&BTreeSet<String>], build_dirs: usize) -> Vec<Vec<usize>> {
+    let capacity = (crates.len() + build_dirs - 1) / build_dirs;
+    let mut queues: Vec<Vec<usize>> = vec![Vec::new(); build_dirs];
+    let mut built: Vec<BTreeSet<&str>> = vec![BTreeSet::new(); build_dirs];
+
+    // Crates with more dependencies are placed first, as they shape the build directories the
+    // most. Each crate goes to the build directory sharing the most dependencies with it, and
+    // ties go to the least loaded (and then the first) build directory.
+    let mut order: Vec<_> = (0..crates.len()).collect();
+    order.sort_by_key(|&index| Reverse(crates[index].len()));
+    for index in order {
+        let shared = |dir: usize| {
+            crates[index]
+                .iter()
+                .filter(|dep| built[dir].contains(dep.as_str()))
+                .count()
+        };
+        let dir = (0..build_dirs)
+            .filter(|&dir| queues[dir].len() < capacity)
+            .max_by_key(|&dir| (shared(dir), Reverse(queues[dir].len()), Reverse(dir)))
+            .unwrap();
+        queues[dir].push(index);
+        built[dir].extend(crates[index].iter().map(|dep| dep.as_str()));
+    }
+
+    // Order each queue greedily, so that every crate shares as many dependencies as possible with
+    // the one built right before it.
+    for queue in &mut queues {
+        let mut remaining = std::mem::take(queue);
+        remaining.sort_unstable();
+        while !remaining.is_empty() {
+            let next = match queue.last() {
+                Some(&last) => remaining
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(_, &index)| {
+                        (
+                            crates[index].intersection(crates[last]).count(),
+                            Reverse(index),
+                        )
+                    })
+                    .map(|(position, _)| position)
+                    .unwrap(),
+                None => 0,
+            };
+            queue.push(remaining.remove(next));
+        }
+    }
+
+    queues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::assign;
+    use std::collections::BTreeSet;
+
+    fn deps(list: &[&[&str]]) -> Vec<BTreeSet<String>> {
+        list.iter()
+            .map(|deps| deps.iter().map(|dep| dep.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_assign() {
+        let crates = deps(&[
+            &["serde", "syn"],
+            &["rand", "libc"],
+            &["serde", "syn", "quote"],
+            &["libc"],
+            &["serde"],
+            &["rand"],
+        ]);
+        let refs: Vec<_> = crates.iter().collect();
+
+        let queues = assign(&refs, 2);
+        assert_eq!(queues, vec![vec![0, 2, 4], vec![1, 3, 5]]);
+
+        // A single build directory gets every crate, ordered by shared dependencies.
+        assert_eq!(assign(&refs, 1), vec![vec![0, 2, 4, 1, 3, 5]]);
+
+        // Build directories are balanced even if all the crates share the same dependencies.
+        let crates = deps(&[&["serde"], &["serde"], &["serde"], &["serde"]]);
+        let refs: Vec<_> = crates.iter().collect();
+        for queue in assign(&refs, 2) {
+            assert_eq!(queue.len(), 2);
+        }
+
+        assert_eq!(assign(&[], 3), vec![Vec::<usize>::new(); 3]);
+    }
+}
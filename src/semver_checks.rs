@@ -0,0 +1,374 @@
+use crate::cmd::container_dirs::TARGET_DIR;
+use crate::cmd::SandboxBuilder;
+use crate::tools::CARGO_SEMVER_CHECKS;
+use crate::{Build, BuildDirectory, Crate, Toolchain};
+use failure::{bail, Error, ResultExt};
+use log::info;
+use std::path::{Path, PathBuf};
+
+/// Directory inside the target directory the rustdoc JSON of the two crates is stored in.
+static RUSTDOC_JSON_DIR: &str = "semver-checks";
+
+/// Builder for checking the semver compatibility of two crates, created from
+/// [`BuildDirectory::semver_check`](struct.BuildDirectory.html#method.semver_check).
+///
+/// The rustdoc JSON of the library of each crate is generated in the build directory, one crate
+/// after the other, and [cargo-semver-checks][semver-checks] then compares the two, reporting
+/// the changes to the public API of `current` that would require a new major (or minor) version
+/// compared to `baseline`. Since rustdoc JSON is unstable the toolchain must be a nightly
+/// supported by the installed version of cargo-semver-checks, which needs to be enabled with
+/// [`WorkspaceBuilder::semver_checks`](struct.WorkspaceBuilder.html#method.semver_checks).
+///
+/// # Example
+///
+/// ```no_run
+/// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+/// # use std::error::Error;
+/// # async fn run() -> Result<(), Box<dyn Error>> {
+/// # let workspace = WorkspaceBuilder::new("".as_ref(), "").semver_checks(true).init().await?;
+/// let toolchain = Toolchain::dist("nightly-2020-07-01");
+/// let baseline = Crate::crates_io("lazy_static", "1.3.0")?;
+/// let current = Crate::crates_io("lazy_static", "1.4.0")?;
+/// let mut build_dir = workspace.build_dir("semver");
+/// let report = build_dir
+///     .semver_check(&toolchain, &baseline, &current, SandboxBuilder::new())
+///     .run()
+///     .await?;
+/// for finding in report.findings() {
+///     println!("{}: {:?}", finding.summary(), finding.locations());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [semver-checks]: https://github.com/obi1kenobi/cargo-semver-checks
+pub struct SemverCheck<'a> {
+    build_dir: &'a mut BuildDirectory,
+    toolchain: &'a Toolchain,
+    baseline: &'a Crate,
+    current: &'a Crate,
+    sandbox: SandboxBuilder,
+}
+
+impl<'a> SemverCheck<'a> {
+    pub(crate) fn new(
+        build_dir: &'a mut BuildDirectory,
+        toolchain: &'a Toolchain,
+        baseline: &'a Crate,
+        current: &'a Crate,
+        sandbox: SandboxBuilder,
+    ) -> Self {
+        SemverCheck {
+            build_dir,
+            toolchain,
+            baseline,
+            current,
+            sandbox,
+        }
+    }
+
+    /// Generate the rustdoc JSON of both crates and compare them.
+    ///
+    /// Breaking changes are not errors: they're recorded in the returned report. An error is
+    /// returned if the documentation of either crate can't be generated, or if
+    /// cargo-semver-checks fails without reporting any breaking change.
+    pub async fn run(self) -> Result<SemverReport, Error> {
+        if !self.build_dir.workspace().semver_checks() {
+            bail!("cargo-semver-checks is not enabled in the workspace");
+        }
+        let (toolchain, sandbox) = (self.toolchain, &self.sandbox);
+
+        let source_dir = self
+            .build_dir
+            .prepare_shared(toolchain, self.baseline, Vec::new(), None)
+            .await?;
+        let build = self
+            .build_dir
+            .start_shared(&source_dir, self.baseline, toolchain, sandbox);
+        let baseline = rustdoc_json(&build, "baseline").await;
+        self.build_dir.finish_shared().await;
+        remove_dir_all::remove_dir_all(&source_dir)?;
+        let baseline = baseline.with_context(|_| {
+            format!("failed to generate the rustdoc JSON of {}", self.baseline)
+        })?;
+
+        let source_dir = self
+            .build_dir
+            .prepare_shared(toolchain, self.current, Vec::new(), None)
+            .await?;
+        let build = self
+            .build_dir
+            .start_shared(&source_dir, self.current, toolchain, sandbox);
+        let report = match rustdoc_json(&build, "current").await {
+            Ok(current) => compare(&build, &baseline, &current).await,
+            Err(err) => Err(err
+                .context(format!(
+                    "failed to generate the rustdoc JSON of {}",
+                    self.current
+                ))
+                .into()),
+        };
+        self.build_dir.finish_shared().await;
+        remove_dir_all::remove_dir_all(&source_dir)?;
+        report
+    }
+}
+
+/// Generate the rustdoc JSON of the library of the crate, returning its path inside the sandbox.
+async fn rustdoc_json(build: &Build<'_>, name: &str) -> Result<PathBuf, Error> {
+    info!("generating the rustdoc JSON of the {} crate", name);
+    let doc_dir = build.host_target_dir().join("doc");
+    // The JSON left by previous builds in the shared target directory must not be mistaken for
+    // the one of this crate.
+    for file in json_files(&doc_dir)? {
+        std::fs::remove_file(file)?;
+    }
+    build
+        .cargo()
+        .args(&["rustdoc", "--lib", "--"])
+        .args(&["-Z", "unstable-options", "--output-format", "json"])
+        .run()
+        .await?;
+
+    let generated = match json_files(&doc_dir)?.into_iter().next() {
+        Some(generated) => generated,
+        None => bail!("rustdoc didn't generate any JSON file"),
+    };
+    let dest = build.host_target_dir().join(RUSTDOC_JSON_DIR);
+    std::fs::create_dir_all(&dest)?;
+    std::fs::rename(&generated, dest.join(format!("{}.json", name)))?;
+    Ok(TARGET_DIR
+        .join(RUSTDOC_JSON_DIR)
+        .join(format!("{}.json", name)))
+}
+
+fn json_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().map(|ext| ext == "json").unwrap_or(false) {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+async fn compare(
+    build: &Build<'_>,
+    baseline: &Path,
+    current: &Path,
+) -> Result<SemverReport, Error> {
+    info!("comparing the public API of the crates");
+    let mut parser = ReportParser::new();
+    let res = build
+        .cmd(&CARGO_SEMVER_CHECKS)
+        .args(&["--color", "never", "--baseline-rustdoc"])
+        .args(&[baseline])
+        .args(&["--current-rustdoc"])
+        .args(&[current])
+        .process_lines(&mut |line, _| parser.process_line(line))
+        .run()
+        .await;
+
+    let report = parser.finish(res.is_ok());
+    match res {
+        Err(err) if report.findings.is_empty() => Err(err
+            .context("cargo-semver-checks failed without reporting any breaking change")
+            .into()),
+        _ => Ok(report),
+    }
+}
+
+/// Change to the public API of a crate reported by a lint of cargo-semver-checks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SemverFinding {
+    lint: String,
+    level: String,
+    summary: String,
+    locations: Vec<String>,
+}
+
+impl SemverFinding {
+    /// Return the name of the lint reporting the change, like `function_missing`.
+    pub fn lint(&self) -> &str {
+        &self.lint
+    }
+
+    /// Return the level of the finding, either `failure` for breaking changes or `warning`.
+    pub fn level(&self) -> &str {
+        &self.level
+    }
+
+    /// Return the description of the change, like `pub fn removed or renamed`.
+    pub fn summary(&self) -> &str {
+        &self.summary
+    }
+
+    /// Return the items affected by the change, as described by cargo-semver-checks (for
+    /// example `function foo::bar, previously in file src/lib.rs:3`).
+    pub fn locations(&self) -> &[String] {
+        &self.locations
+    }
+}
+
+/// Result of a [`SemverCheck`](struct.SemverCheck.html).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SemverReport {
+    passed: bool,
+    required_update: Option<String>,
+    findings: Vec<SemverFinding>,
+}
+
+impl SemverReport {
+    /// Check whether no breaking change was found.
+    pub fn passed(&self) -> bool {
+        self.passed
+    }
+
+    /// Return the version update required by the changes (`major` or `minor`), or `None` if no
+    /// update is required.
+    pub fn required_update(&self) -> Option<&str> {
+        self.required_update.as_deref()
+    }
+
+    /// Return the changes reported by cargo-semver-checks, in the order they were reported.
+    pub fn findings(&self) -> &[SemverFinding] {
+        &self.findings
+    }
+}
+
+/// Parser of the human-readable output of cargo-semver-checks.
+struct ReportParser {
+    findings: Vec<SemverFinding>,
+    required_update: Option<String>,
+    in_locations: bool,
+}
+
+impl ReportParser {
+    fn new() -> Self {
+        ReportParser {
+            findings: Vec::new(),
+            required_update: None,
+            in_locations: false,
+        }
+    }
+
+    fn process_line(&mut self, line: &str) {
+        let trimmed = line.trim();
+
+        // Findings start with a header like `--- failure function_missing: pub fn removed ---`.
+        if let Some(header) = trimmed
+            .strip_prefix("--- ")
+            .and_then(|header| header.strip_suffix(" ---"))
+        {
+            let mut parts = header.splitn(2, ' ');
+            let level = parts.next().unwrap_or_default();
+            let mut rest = parts.next().unwrap_or_default().splitn(2, ": ");
+            if let (Some(lint), Some(summary)) = (rest.next(), rest.next()) {
+                self.findings.push(SemverFinding {
+                    lint: lint.to_string(),
+                    level: level.to_string(),
+                    summary: summary.to_string(),
+                    locations: Vec::new(),
+                });
+            }
+            self.in_locations = false;
+        } else if trimmed == "Failed in:" {
+            self.in_locations = true;
+        } else if trimmed.is_empty() {
+            self.in_locations = false;
+        } else if self.in_locations {
+            if let Some(finding) = self.findings.last_mut() {
+                finding.locations.push(trimmed.to_string());
+            }
+        } else if let Some(summary) = trimmed.strip_prefix("Summary ") {
+            self.required_update = parse_required_update(summary);
+        }
+    }
+
+    fn finish(self, success: bool) -> SemverReport {
+        SemverReport {
+            passed: success
+                && !self
+                    .findings
+                    .iter()
+                    .any(|finding| finding.level == "failure"),
+            required_update: self.required_update,
+            findings: self.findings,
+        }
+    }
+}
+
+/// Extract the required update from summaries like `semver requires new major version: 1 major
+/// and 0 minor checks failed`.
+fn parse_required_update(summary: &str) -> Option<String> {
+    let rest = summary.strip_prefix("semver requires new ")?;
+    Some(rest[..rest.find(' ')?].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReportParser;
+
+    #[test]
+    fn test_report_parser() {
+        let output = "\
+     Parsing foo v0.2.0 (current)
+    Checking foo v0.1.0 -> v0.2.0 (major change)
+     Checked [   0.010s] 70 checks: 68 pass, 2 fail, 0 warn, 0 skip
+
+--- failure function_missing: pub fn removed or renamed ---
+
+Description:
+A publicly-visible function cannot be imported by its prior path.
+        ref: https://doc.rust-lang.org/cargo/reference/semver.html#item-remove
+
+Failed in:
+  function foo::bar, previously in file /opt/rustwide/workdir/src/lib.rs:1
+  function foo::baz, previously in file /opt/rustwide/workdir/src/lib.rs:5
+
+--- failure enum_variant_added: enum variant added on exhaustive enum ---
+
+Failed in:
+  variant Kind:New in /opt/rustwide/workdir/src/lib.rs:10
+
+     Summary semver requires new major version: 2 major and 0 minor checks failed
+";
+        let mut parser = ReportParser::new();
+        for line in output.lines() {
+            parser.process_line(line);
+        }
+        let report = parser.finish(false);
+
+        assert!(!report.passed());
+        assert_eq!(report.required_update(), Some("major"));
+        assert_eq!(report.findings().len(), 2);
+        let first = &report.findings()[0];
+        assert_eq!(first.lint(), "function_missing");
+        assert_eq!(first.level(), "failure");
+        assert_eq!(first.summary(), "pub fn removed or renamed");
+        assert_eq!(
+            first.locations(),
+            &[
+                "function foo::bar, previously in file /opt/rustwide/workdir/src/lib.rs:1",
+                "function foo::baz, previously in file /opt/rustwide/workdir/src/lib.rs:5",
+            ]
+        );
+        assert_eq!(report.findings()[1].lint(), "enum_variant_added");
+        assert_eq!(report.findings()[1].locations().len(), 1);
+    }
+
+    #[test]
+    fn test_report_parser_passed() {
+        let mut parser = ReportParser::new();
+        parser.process_line("     Checked [   0.010s] 70 checks: 70 pass, 0 skip");
+        parser.process_line("     Summary no semver update required");
+        let report = parser.finish(true);
+        assert!(report.passed());
+        assert_eq!(report.required_update(), None);
+        assert!(report.findings().is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use crate::cmd::Command;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Tracks the processes and containers started by a workspace, to clean them up on shutdown or
+/// when all the running commands are cancelled.
+pub(crate) struct Shutdown {
+    shutting_down: AtomicBool,
+    cancellations: AtomicUsize,
+    processes: Mutex<HashSet<u32>>,
+    containers: Mutex<HashSet<String>>,
+}
+
+impl Shutdown {
+    pub(crate) fn new() -> Self {
+        Shutdown {
+            shutting_down: AtomicBool::new(false),
+            cancellations: AtomicUsize::new(0),
+            processes: Mutex::new(HashSet::new()),
+            containers: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub(crate) fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Return the number of times the running commands were cancelled, to be passed later to
+    /// [`is_cancelled`](#method.is_cancelled).
+    pub(crate) fn cancellations(&self) -> usize {
+        self.cancellations.load(Ordering::SeqCst)
+    }
+
+    /// Check whether the commands started when [`cancellations`](#method.cancellations) returned
+    /// `since` were cancelled, either by shutting down the workspace or by cancelling them.
+    pub(crate) fn is_cancelled(&self, since: usize) -> bool {
+        self.is_shutting_down() || self.cancellations() != since
+    }
+
+    /// Mark all the running commands as cancelled.
+    pub(crate) fn cancel(&self) {
+        self.cancellations.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn register_process(&self, id: u32) {
+        self.processes.lock().unwrap().insert(id);
+    }
+
+    pub(crate) fn unregister_process(&self, id: u32) {
+        self.processes.lock().unwrap().remove(&id);
+    }
+
+    pub(crate) fn register_container(&self, id: &str) {
+        self.containers.lock().unwrap().insert(id.into());
+    }
+
+    pub(crate) fn unregister_container(&self, id: &str) {
+        self.containers.lock().unwrap().remove(id);
+    }
+
+    pub(crate) fn containers(&self) -> HashSet<String> {
+        self.containers.lock().unwrap().clone()
+    }
+
+    /// Mark the workspace as shutting down, returning `false` if it was already marked.
+    pub(crate) fn begin(&self) -> bool {
+        !self.shutting_down.swap(true, Ordering::SeqCst)
+    }
+
+    pub(crate) fn take_containers(&self) -> Vec<String> {
+        self.containers.lock().unwrap().drain().collect()
+    }
+
+    pub(crate) fn take_processes(&self) -> Vec<u32> {
+        self.processes.lock().unwrap().drain().collect()
+    }
+}
+
+/// Forcibly remove a container, bypassing the workspace (which refuses to start new commands
+/// while it's shutting down).
+pub(crate) async fn remove_container(id: &str) -> Result<(), failure::Error> {
+    Command::new_workspaceless("docker")
+        .args(&["rm", "-f", id])
+        .run()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Shutdown;
+
+    #[test]
+    fn test_cancellations() {
+        let shutdown = Shutdown::new();
+        let before = shutdown.cancellations();
+        assert!(!shutdown.is_cancelled(before));
+
+        shutdown.cancel();
+        assert!(shutdown.is_cancelled(before));
+        let after = shutdown.cancellations();
+        assert!(!shutdown.is_cancelled(after));
+
+        shutdown.begin();
+        assert!(shutdown.is_cancelled(after));
+    }
+
+    #[test]
+    fn test_tracking() {
+        let shutdown = Shutdown::new();
+        shutdown.register_process(1);
+        shutdown.register_process(2);
+        shutdown.unregister_process(1);
+        shutdown.register_container("foo");
+
+        assert!(!shutdown.is_shutting_down());
+        assert!(shutdown.begin());
+        assert!(!shutdown.begin());
+        assert!(shutdown.is_shutting_down());
+
+        assert_eq!(shutdown.take_processes(), vec![2]);
+        assert_eq!(shutdown.take_containers(), vec!["foo".to_string()]);
+        assert!(shutdown.take_processes().is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use failure::Error;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::SystemTime;
+use walkdir::WalkDir;
+
+/// Files of the source directory the build created, modified or removed, retrieved with
+/// [`Build::source_writes`](struct.Build.html#method.source_writes).
+///
+/// Builds are expected to write only in the target directory, but build scripts sometimes
+/// generate files next to the source code instead, which breaks read-only checkouts and can leak
+/// between builds. Changes inside a `target` directory at the root of the crate are not reported.
+///
+/// Paths are relative to the source directory, with `/` as the separator.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct SourceWrites {
+    created: Vec<String>,
+    modified: Vec<String>,
+    removed: Vec<String>,
+}
+
+impl SourceWrites {
+    /// Return the files created by the build.
+    pub fn created(&self) -> &[String] {
+        &self.created
+    }
+
+    /// Return the files that existed before the build and were changed by it.
+    pub fn modified(&self) -> &[String] {
+        &self.modified
+    }
+
+    /// Return the files removed by the build.
+    pub fn removed(&self) -> &[String] {
+        &self.removed
+    }
+
+    /// Check whether the build left the source directory untouched.
+    pub fn is_empty(&self) -> bool {
+        self.created.is_empty() && self.modified.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// State of the files in the source directory, recorded before the build starts.
+///
+/// Files are compared by size and modification time, so the source code doesn't have to be read.
+pub(crate) struct SourceState {
+    files: BTreeMap<String, (u64, Option<SystemTime>)>,
+}
+
+impl SourceState {
+    pub(crate) fn record(source_dir: &Path) -> Result<Self, Error> {
+        let mut files = BTreeMap::new();
+        let entries = WalkDir::new(source_dir)
+            .min_depth(1)
+            .into_iter()
+            .filter_entry(|entry| entry.depth() != 1 || entry.file_name() != "target");
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let path = entry.path().strip_prefix(source_dir)?.to_string_lossy();
+            let metadata = entry.metadata()?;
+            files.insert(
+                path.replace('\\', "/"),
+                (metadata.len(), metadata.modified().ok()),
+            );
+        }
+        Ok(SourceState { files })
+    }
+
+    /// Compare this state with the current state of the source directory.
+    pub(crate) fn writes(&self, source_dir: &Path) -> Result<SourceWrites, Error> {
+        let current = SourceState::record(source_dir)?;
+        let mut writes = SourceWrites::default();
+        for (path, state) in &current.files {
+            match self.files.get(path) {
+                None => writes.created.push(path.clone()),
+                Some(before) if before != state => writes.modified.push(path.clone()),
+                Some(_) => {}
+            }
+        }
+        writes.removed = self
+            .files
+            .keys()
+            .filter(|path| !current.files.contains_key(*path))
+            .cloned()
+            .collect();
+        Ok(writes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SourceState;
+    use failure::Error;
+    use std::fs;
+
+    #[test]
+    fn test_source_writes() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let dir = dir.path();
+        fs::create_dir_all(dir.join("src"))?;
+        fs::write(dir.join("Cargo.toml"), "[package]\n")?;
+        fs::write(dir.join("src").join("lib.rs"), "")?;
+        fs::write(dir.join("build.rs"), "fn main() {}")?;
+
+        let state = SourceState::record(dir)?;
+        assert!(state.writes(dir)?.is_empty());
+
+        fs::write(dir.join("src").join("lib.rs"), "pub fn foo() {}")?;
+        fs::write(dir.join("src").join("generated.rs"), "")?;
+        fs::remove_file(dir.join("build.rs"))?;
+        fs::create_dir_all(dir.join("target").join("debug"))?;
+        fs::write(dir.join("target").join("debug").join("foo"), "")?;
+
+        let writes = state.writes(dir)?;
+        assert_eq!(writes.created(), &["src/generated.rs".to_string()]);
+        assert_eq!(writes.modified(), &["src/lib.rs".to_string()]);
+        assert_eq!(writes.removed(), &["build.rs".to_string()]);
+        Ok(())
+    }
+}
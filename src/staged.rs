@@ -0,0 +1,245 @@
+use crate::Build;
+use failure::{Error, ResultExt};
+use log::info;
+use serde::Deserialize;
+use std::collections::BTreeSet;
+
+/// Build variant compiling the dependencies of a crate before the crate itself, to tell apart
+/// failures caused by a broken dependency from failures of the crate.
+///
+/// Cargo doesn't support building only the dependencies of a crate, so this is emulated by
+/// resolving them with `cargo metadata` and building them with `cargo build -p`. The crate is then
+/// built in a second stage, reusing the artifacts of the first one. Dependencies only used on
+/// other platforms are not built in the first stage, and their failures are attributed to the
+/// crate.
+///
+/// This is useful in regression runs, where a broken dependency would otherwise be reported as a
+/// regression of every crate depending on it.
+///
+/// # Example
+///
+/// ```no_run
+/// # use rustwide::{Build, StagedBuild, StagedOutcome};
+/// # use std::error::Error;
+/// # async fn run(build: &Build<'_>) -> Result<(), Box<dyn Error>> {
+/// match StagedBuild::new().args(&["--locked"]).run(build).await? {
+///     StagedOutcome::Success => println!("the crate built successfully"),
+///     StagedOutcome::DependenciesFailed(err) => println!("a dependency is broken: {}", err),
+///     StagedOutcome::CrateFailed(err) => println!("the crate is broken: {}", err),
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct StagedBuild {
+    args: Vec<String>,
+    dev_dependencies: bool,
+}
+
+impl StagedBuild {
+    /// Create a new staged build, running `cargo build` without any additional argument.
+    pub fn new() -> Self {
+        StagedBuild {
+            args: Vec::new(),
+            dev_dependencies: false,
+        }
+    }
+
+    /// Add arguments to the `cargo build` invocations of both stages. Arguments selecting packages
+    /// or features (like `--workspace` or `--features`) conflict with the first stage and
+    /// shouldn't be used.
+    pub fn args(mut self, args: &[&str]) -> Self {
+        self.args.extend(args.iter().map(|arg| arg.to_string()));
+        self
+    }
+
+    /// Build the dev-dependencies in the first stage as well, for example when the second stage is
+    /// going to run the tests. By default they're not built.
+    pub fn dev_dependencies(mut self, enable: bool) -> Self {
+        self.dev_dependencies = enable;
+        self
+    }
+
+    /// Build the dependencies of the crate, and then the crate itself.
+    ///
+    /// Build failures are not errors: they're reported by the returned
+    /// [`StagedOutcome`](enum.StagedOutcome.html). An error is returned only if the dependencies
+    /// of the crate can't be resolved.
+    pub async fn run(&self, build: &Build<'_>) -> Result<StagedOutcome, Error> {
+        let output = build
+            .cargo()
+            .args(&["metadata", "--format-version", "1"])
+            .log_output(false)
+            .run_capture()
+            .await
+            .with_context(|_| "failed to resolve the dependencies of the crate")?;
+        let metadata: Metadata = serde_json::from_str(&output.stdout_lines().join("\n"))?;
+        let specs = dependency_specs(&metadata, self.dev_dependencies);
+
+        if specs.is_empty() {
+            info!("the crate has no dependencies to build");
+        } else {
+            info!("building {} dependencies of the crate", specs.len());
+            let mut cmd = build.cargo().args(&["build"]).args(&self.args);
+            for spec in &specs {
+                cmd = cmd.args(&["-p", spec.as_str()]);
+            }
+            if let Err(err) = cmd.run().await {
+                return Ok(StagedOutcome::DependenciesFailed(err));
+            }
+        }
+
+        info!("building the crate");
+        match build.cargo().args(&["build"]).args(&self.args).run().await {
+            Ok(()) => Ok(StagedOutcome::Success),
+            Err(err) => Ok(StagedOutcome::CrateFailed(err)),
+        }
+    }
+}
+
+/// Outcome of a [`StagedBuild`](struct.StagedBuild.html).
+#[derive(Debug)]
+pub enum StagedOutcome {
+    /// Both the dependencies and the crate built successfully.
+    Success,
+    /// Building the dependencies failed with the contained error, so the crate wasn't built.
+    DependenciesFailed(Error),
+    /// The dependencies built successfully, but building the crate failed with the contained
+    /// error.
+    CrateFailed(Error),
+}
+
+impl StagedOutcome {
+    /// Check whether both stages completed successfully.
+    pub fn is_success(&self) -> bool {
+        match self {
+            StagedOutcome::Success => true,
+            StagedOutcome::DependenciesFailed(_) | StagedOutcome::CrateFailed(_) => false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    packages: Vec<Package>,
+    workspace_members: Vec<String>,
+    resolve: Option<Resolve>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    id: String,
+    name: String,
+    version: String,
+}
+
+#[derive(Deserialize)]
+struct Resolve {
+    nodes: Vec<Node>,
+}
+
+#[derive(Deserialize)]
+struct Node {
+    id: String,
+    #[serde(default)]
+    deps: Vec<NodeDep>,
+}
+
+#[derive(Deserialize)]
+struct NodeDep {
+    pkg: String,
+    #[serde(default)]
+    dep_kinds: Vec<DepKind>,
+}
+
+#[derive(Deserialize)]
+struct DepKind {
+    kind: Option<String>,
+    target: Option<String>,
+}
+
+/// Return the package specs (`name:version`) of the direct dependencies of the workspace members
+/// that are built on every platform. Building them also builds their own dependencies.
+fn dependency_specs(metadata: &Metadata, dev_dependencies: bool) -> Vec<String> {
+    let members: BTreeSet<_> = metadata.workspace_members.iter().collect();
+    let nodes = match &metadata.resolve {
+        Some(resolve) => &resolve.nodes,
+        None => return Vec::new(),
+    };
+
+    let ids: BTreeSet<_> = nodes
+        .iter()
+        .filter(|node| members.contains(&node.id))
+        .flat_map(|node| node.deps.iter())
+        .filter(|dep| !members.contains(&dep.pkg))
+        .filter(|dep| {
+            dep.dep_kinds.iter().any(|kind| {
+                kind.target.is_none() && (dev_dependencies || kind.kind.as_deref() != Some("dev"))
+            })
+        })
+        .map(|dep| &dep.pkg)
+        .collect();
+
+    metadata
+        .packages
+        .iter()
+        .filter(|package| ids.contains(&package.id))
+        .map(|package| format!("{}:{}", package.name, package.version))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dependency_specs, Metadata};
+
+    #[test]
+    fn test_dependency_specs() {
+        let metadata: Metadata = serde_json::from_value(serde_json::json!({
+            "packages": [
+                { "id": "foo 0.1.0 (path+file:///foo)", "name": "foo", "version": "0.1.0" },
+                { "id": "bar 0.1.0 (path+file:///foo/bar)", "name": "bar", "version": "0.1.0" },
+                { "id": "log 0.4.8 (registry+crates-io)", "name": "log", "version": "0.4.8" },
+                { "id": "cfg-if 0.1.10 (registry+crates-io)", "name": "cfg-if", "version": "0.1.10" },
+                { "id": "cc 1.0.0 (registry+crates-io)", "name": "cc", "version": "1.0.0" },
+                { "id": "winapi 0.3.8 (registry+crates-io)", "name": "winapi", "version": "0.3.8" },
+                { "id": "tempfile 3.1.0 (registry+crates-io)", "name": "tempfile", "version": "3.1.0" },
+            ],
+            "workspace_members": ["foo 0.1.0 (path+file:///foo)", "bar 0.1.0 (path+file:///foo/bar)"],
+            "resolve": {
+                "root": "foo 0.1.0 (path+file:///foo)",
+                "nodes": [
+                    {
+                        "id": "foo 0.1.0 (path+file:///foo)",
+                        "deps": [
+                            { "pkg": "bar 0.1.0 (path+file:///foo/bar)", "dep_kinds": [{ "kind": null, "target": null }] },
+                            { "pkg": "log 0.4.8 (registry+crates-io)", "dep_kinds": [{ "kind": null, "target": null }] },
+                            { "pkg": "cc 1.0.0 (registry+crates-io)", "dep_kinds": [{ "kind": "build", "target": null }] },
+                            { "pkg": "winapi 0.3.8 (registry+crates-io)", "dep_kinds": [{ "kind": null, "target": "cfg(windows)" }] },
+                            { "pkg": "tempfile 3.1.0 (registry+crates-io)", "dep_kinds": [{ "kind": "dev", "target": null }] },
+                        ],
+                    },
+                    { "id": "bar 0.1.0 (path+file:///foo/bar)", "deps": [] },
+                    {
+                        "id": "log 0.4.8 (registry+crates-io)",
+                        "deps": [
+                            { "pkg": "cfg-if 0.1.10 (registry+crates-io)", "dep_kinds": [{ "kind": null, "target": null }] },
+                        ],
+                    },
+                    { "id": "cfg-if 0.1.10 (registry+crates-io)", "deps": [] },
+                    { "id": "cc 1.0.0 (registry+crates-io)", "deps": [] },
+                    { "id": "winapi 0.3.8 (registry+crates-io)", "deps": [] },
+                    { "id": "tempfile 3.1.0 (registry+crates-io)", "deps": [] },
+                ],
+            },
+        }))
+        .unwrap();
+
+        assert_eq!(
+            dependency_specs(&metadata, false),
+            vec!["log:0.4.8", "cc:1.0.0"]
+        );
+        assert_eq!(
+            dependency_specs(&metadata, true),
+            vec!["log:0.4.8", "cc:1.0.0", "tempfile:3.1.0"]
+        );
+    }
+}
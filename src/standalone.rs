@@ -0,0 +1,730 @@
+use crate::crates::unpack::{unpack_without_first_dir, READ_BUFFER_SIZE};
+use crate::toolchain::ToolchainError;
+use crate::Workspace;
+use failure::{bail, Error, ResultExt};
+use flate2::read::GzDecoder;
+use log::{info, warn};
+use remove_dir_all::remove_dir_all;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Component, Path, PathBuf};
+use walkdir::WalkDir;
+
+static DIST_SERVER: &str = "https://static.rust-lang.org/dist";
+static CI_SERVER: &str = "https://ci-artifacts.rust-lang.org";
+
+/// File inside the sysroot of standalone toolchains, containing the date of the manifest the
+/// toolchain was installed from.
+pub(crate) static MARKER_FILE: &str = ".rustwide-dist";
+
+/// File inside the sysroot of standalone CI toolchains, containing the commit the toolchain was
+/// built from.
+static CI_MARKER_FILE: &str = ".rustwide-ci";
+
+/// Components installed when the manifest doesn't define any profile.
+static FALLBACK_PROFILE: &[&str] = &["rustc", "cargo", "rust-std"];
+
+#[derive(Deserialize)]
+struct Manifest {
+    date: String,
+    pkg: HashMap<String, Package>,
+    #[serde(default)]
+    renames: HashMap<String, Rename>,
+    #[serde(default)]
+    profiles: HashMap<String, Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    #[serde(default)]
+    target: HashMap<String, PackageTarget>,
+}
+
+#[derive(Deserialize)]
+struct PackageTarget {
+    available: bool,
+    url: Option<String>,
+    hash: Option<String>,
+    xz_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Rename {
+    to: String,
+}
+
+/// Tarball of a package to install, resolved from the manifest.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Tarball {
+    package: String,
+    target: String,
+    url: String,
+    hash: String,
+    /// URL of the xz-compressed tarball, which rustup downloads instead when it's available.
+    xz_url: Option<String>,
+}
+
+/// Return the directory the toolchain is installed in, which matches the layout of rustup.
+pub(crate) fn sysroot(workspace: &Workspace, name: &str) -> PathBuf {
+    workspace.rustup_home().join("toolchains").join(name)
+}
+
+/// Install the toolchain with the components of the workspace's profile, or update it if a newer
+/// manifest was published for its channel. Updating reinstalls the toolchain from scratch, so the
+/// components and targets added later need to be added again.
+pub(crate) async fn install(workspace: &Workspace, name: &str) -> Result<(), Error> {
+    let manifest = fetch_manifest(workspace, name, None).await?;
+    let sysroot = sysroot(workspace, name);
+    if installed_date(&sysroot).as_deref() == Some(manifest.date.as_str()) {
+        info!("toolchain {} is up to date", name);
+        return Ok(());
+    }
+
+    info!("installing toolchain {} from {}", name, manifest.date);
+    let tarballs = resolve(
+        &manifest,
+        Some(workspace.rustup_profile()),
+        &[],
+        &[],
+        crate::HOST_TARGET,
+    )?;
+
+    // The toolchain is assembled in a temporary directory first, so that a failed installation
+    // doesn't leave a broken toolchain behind or replace a working one.
+    let temp = crate::utils::temp_path(&sysroot);
+    let mut result = Ok(());
+    for tarball in &tarballs {
+        result = install_tarball(workspace, tarball, &temp).await;
+        if result.is_err() {
+            break;
+        }
+    }
+    if let Err(err) = result.and_then(|()| Ok(fs::write(temp.join(MARKER_FILE), &manifest.date)?)) {
+        let _ = remove_dir_all(&temp);
+        return Err(err
+            .context(format!("unable to install toolchain {}", name))
+            .into());
+    }
+
+    if sysroot.exists() {
+        remove_dir_all(&sysroot)?;
+    }
+    fs::rename(&temp, &sysroot)?;
+    Ok(())
+}
+
+/// Install the toolchain built by CI from the provided commit, assembled from its `rustc`,
+/// `rust-std` and `cargo` artifacts. Alternate artifacts don't include `cargo`, so it comes from
+/// the normal artifacts of the same commit.
+///
+/// CI artifacts never change once published, so an installed toolchain is never updated. The
+/// artifacts are cached in the workspace, and revalidated with the server before being reused.
+pub(crate) async fn install_ci(workspace: &Workspace, sha: &str, alt: bool) -> Result<(), Error> {
+    let name = if alt {
+        format!("{}-alt", sha)
+    } else {
+        sha.to_string()
+    };
+    let sysroot = sysroot(workspace, &name);
+    if sysroot.join(CI_MARKER_FILE).is_file() {
+        info!("toolchain {} is already installed", name);
+        return Ok(());
+    }
+
+    info!("installing toolchain {}", name);
+    let rustc_builds = if alt {
+        "rustc-builds-alt"
+    } else {
+        "rustc-builds"
+    };
+    let artifacts = [
+        ("rustc", rustc_builds),
+        ("rust-std", rustc_builds),
+        ("cargo", "rustc-builds"),
+    ];
+
+    let temp = crate::utils::temp_path(&sysroot);
+    let mut result = Ok(());
+    for (package, builds) in &artifacts {
+        info!("installing {} for {}", package, crate::HOST_TARGET);
+        let file_name = format!("{}-nightly-{}.tar.gz", package, crate::HOST_TARGET);
+        let url = format!("{}/{}/{}/{}", CI_SERVER, builds, sha, file_name);
+        let cached = workspace
+            .cache_dir()
+            .join("ci")
+            .join(builds)
+            .join(sha)
+            .join(file_name);
+        result = match crate::download::download(workspace, &url, &cached).await {
+            Ok(_) => unpack_tarball(workspace, cached, &temp, None).await,
+            Err(err) => Err(err),
+        };
+        if result.is_err() {
+            break;
+        }
+    }
+    if let Err(err) = result.and_then(|()| Ok(fs::write(temp.join(CI_MARKER_FILE), sha)?)) {
+        let _ = remove_dir_all(&temp);
+        return Err(err
+            .context(format!("unable to install toolchain {}", name))
+            .into());
+    }
+
+    if sysroot.exists() {
+        remove_dir_all(&sysroot)?;
+    }
+    fs::rename(&temp, &sysroot)?;
+    Ok(())
+}
+
+/// Add components and targets to an installed toolchain, from the same manifest the toolchain was
+/// installed from.
+pub(crate) async fn add(
+    workspace: &Workspace,
+    name: &str,
+    components: &[&str],
+    targets: &[&str],
+) -> Result<(), Error> {
+    let sysroot = sysroot(workspace, name);
+    let date = installed_date(&sysroot).ok_or(ToolchainError::NotInstalled)?;
+    let manifest = fetch_manifest(workspace, name, Some(&date)).await?;
+
+    for tarball in resolve(&manifest, None, components, targets, crate::HOST_TARGET)? {
+        install_tarball(workspace, &tarball, &sysroot)
+            .await
+            .with_context(|_| format!("unable to add {} to toolchain {}", tarball.package, name))?;
+    }
+    Ok(())
+}
+
+/// Remove components and targets from an installed toolchain.
+pub(crate) fn remove(
+    workspace: &Workspace,
+    name: &str,
+    components: &[&str],
+    targets: &[&str],
+) -> Result<(), Error> {
+    let sysroot = sysroot(workspace, name);
+    if installed_date(&sysroot).is_none() {
+        return Err(ToolchainError::NotInstalled.into());
+    }
+
+    let installed = installed_components(&sysroot)?;
+    let mut wanted: Vec<_> = components.iter().map(|c| c.to_string()).collect();
+    wanted.extend(targets.iter().map(|target| format!("rust-std-{}", target)));
+    for name in wanted {
+        // Some components are packaged with a `-preview` suffix.
+        let component = installed
+            .iter()
+            .find(|c| **c == name || **c == format!("{}-preview", name))
+            .ok_or_else(|| failure::format_err!("{} is not installed", name))?;
+        uninstall_component(&sysroot, component)?;
+    }
+    Ok(())
+}
+
+/// Return the total size of the tarballs downloaded to install the toolchain with the components
+/// of the workspace's profile and the provided components and targets. The sizes are retrieved
+/// from the dist server without downloading the tarballs. Rustup downloads the xz-compressed
+/// tarballs when they're available, while standalone toolchains always use the gzipped ones.
+pub(crate) async fn download_size(
+    workspace: &Workspace,
+    name: &str,
+    components: &[&str],
+    targets: &[&str],
+) -> Result<u64, Error> {
+    let manifest = fetch_manifest(workspace, name, None).await?;
+    let tarballs = resolve(
+        &manifest,
+        Some(workspace.rustup_profile()),
+        components,
+        targets,
+        crate::HOST_TARGET,
+    )?;
+
+    let mut total = 0;
+    for tarball in &tarballs {
+        let url = match &tarball.xz_url {
+            Some(xz_url) if !workspace.standalone_toolchains() => xz_url,
+            _ => &tarball.url,
+        };
+        total += crate::download::content_length(workspace, url)
+            .await
+            .with_context(|_| {
+                format!(
+                    "unable to retrieve the size of {} for {}",
+                    tarball.package, tarball.target
+                )
+            })?;
+    }
+    Ok(total)
+}
+
+/// Return the targets the standard library is installed for.
+pub(crate) fn installed_targets(workspace: &Workspace, name: &str) -> Result<Vec<String>, Error> {
+    let sysroot = sysroot(workspace, name);
+    if installed_date(&sysroot).is_none() {
+        return Err(ToolchainError::NotInstalled.into());
+    }
+
+    Ok(installed_components(&sysroot)?
+        .iter()
+        .filter_map(|component| component.strip_prefix("rust-std-"))
+        .map(|target| target.to_string())
+        .collect())
+}
+
+fn installed_date(sysroot: &Path) -> Option<String> {
+    fs::read_to_string(sysroot.join(MARKER_FILE))
+        .ok()
+        .map(|date| date.trim().to_string())
+}
+
+fn rustlib(sysroot: &Path) -> PathBuf {
+    sysroot.join("lib").join("rustlib")
+}
+
+fn installed_components(sysroot: &Path) -> Result<Vec<String>, Error> {
+    match fs::read_to_string(rustlib(sysroot).join("components")) {
+        Ok(content) => Ok(content
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| line.to_string())
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_components(sysroot: &Path, components: &[String]) -> Result<(), Error> {
+    let mut content = components.join("\n");
+    content.push('\n');
+    crate::utils::write_atomic(&rustlib(sysroot).join("components"), content.as_bytes())
+}
+
+/// Return the URL of the manifest of the toolchain. If a date is provided the manifest published
+/// that day is returned, instead of the latest one.
+fn manifest_url(name: &str, date: Option<&str>) -> Result<String, Error> {
+    let is_date =
+        |date: &str| date.len() == 10 && date.bytes().all(|b| b.is_ascii_digit() || b == b'-');
+
+    // Dated toolchains like `nightly-2020-07-01` are stored in a directory named after the date.
+    for channel in &["nightly", "beta", "stable"] {
+        if let Some(rest) = name.strip_prefix(channel) {
+            match rest.strip_prefix('-') {
+                Some(dated) if is_date(dated) => {
+                    return Ok(format!(
+                        "{}/{}/channel-rust-{}.toml",
+                        DIST_SERVER, dated, channel
+                    ))
+                }
+                None if rest.is_empty() => {}
+                _ => bail!(
+                    "unsupported toolchain name for standalone toolchains: {}",
+                    name
+                ),
+            }
+        }
+    }
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'.') {
+        bail!(
+            "unsupported toolchain name for standalone toolchains: {}",
+            name
+        );
+    }
+
+    Ok(match date {
+        Some(date) => format!("{}/{}/channel-rust-{}.toml", DIST_SERVER, date, name),
+        None => format!("{}/channel-rust-{}.toml", DIST_SERVER, name),
+    })
+}
+
+async fn fetch_manifest(
+    workspace: &Workspace,
+    name: &str,
+    date: Option<&str>,
+) -> Result<Manifest, Error> {
+    let url = manifest_url(name, date)?;
+    let content = crate::download::download_bytes(workspace, &url)
+        .await
+        .with_context(|_| format!("unable to download the manifest of toolchain {}", name))?;
+    Ok(toml::from_slice(&content)
+        .with_context(|_| format!("invalid manifest for toolchain {}", name))?)
+}
+
+/// Resolve the tarballs to install for the provided components and targets. The components of
+/// the profile are skipped if they're not available, while the explicitly requested components
+/// and targets are required.
+fn resolve(
+    manifest: &Manifest,
+    profile: Option<&str>,
+    components: &[&str],
+    targets: &[&str],
+    host: &str,
+) -> Result<Vec<Tarball>, Error> {
+    let mut wanted = Vec::new();
+    if let Some(profile) = profile {
+        let packages = if manifest.profiles.is_empty() {
+            FALLBACK_PROFILE.iter().map(|p| p.to_string()).collect()
+        } else {
+            manifest
+                .profiles
+                .get(profile)
+                .cloned()
+                .ok_or_else(|| failure::format_err!("unknown profile {}", profile))?
+        };
+        wanted.extend(packages.into_iter().map(|p| (p, host.to_string(), false)));
+    }
+    wanted.extend(
+        components
+            .iter()
+            .map(|c| (c.to_string(), host.to_string(), true)),
+    );
+    wanted.extend(
+        targets
+            .iter()
+            .map(|t| ("rust-std".to_string(), t.to_string(), true)),
+    );
+
+    let mut result = BTreeSet::new();
+    for (package, target, required) in wanted {
+        let package = match manifest.renames.get(&package) {
+            Some(rename) => rename.to.clone(),
+            None => package,
+        };
+        let found = manifest.pkg.get(&package).and_then(|pkg| {
+            pkg.target
+                .get(&target)
+                .or_else(|| pkg.target.get("*"))
+                .filter(|t| t.available)
+                .and_then(|t| Some((t.url.clone()?, t.hash.clone()?, t.xz_url.clone())))
+        });
+        match found {
+            Some((url, hash, xz_url)) => {
+                result.insert(Tarball {
+                    package,
+                    target,
+                    url,
+                    hash,
+                    xz_url,
+                });
+            }
+            None if required => bail!("{} is not available for {}", package, target),
+            None => warn!("skipping {}, as it's not available for {}", package, target),
+        }
+    }
+    Ok(result.into_iter().collect())
+}
+
+async fn install_tarball(
+    workspace: &Workspace,
+    tarball: &Tarball,
+    sysroot: &Path,
+) -> Result<(), Error> {
+    info!("installing {} for {}", tarball.package, tarball.target);
+    let file_name = tarball
+        .url
+        .rsplit('/')
+        .next()
+        .ok_or_else(|| failure::format_err!("invalid url: {}", tarball.url))?;
+    let cached = workspace
+        .cache_dir()
+        .join("dist")
+        .join(tarball.hash.as_str())
+        .join(file_name);
+    crate::download::download(workspace, &tarball.url, &cached).await?;
+    unpack_tarball(workspace, cached, sysroot, Some(tarball.hash.clone())).await
+}
+
+/// Install the components of a downloaded tarball into the sysroot, checking its SHA-256 first
+/// when it's known.
+async fn unpack_tarball(
+    workspace: &Workspace,
+    cached: PathBuf,
+    sysroot: &Path,
+    hash: Option<String>,
+) -> Result<(), Error> {
+    let sysroot = sysroot.to_path_buf();
+    crate::utils::spawn_blocking(workspace.runtime(), move || {
+        if let Some(hash) = hash {
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut File::open(&cached)?, &mut hasher)?;
+            if format!("{:x}", hasher.finalize()) != hash {
+                let _ = fs::remove_file(&cached);
+                bail!("checksum mismatch for {}", cached.display());
+            }
+        }
+
+        let extracted = tempfile::tempdir()?;
+        let file = BufReader::with_capacity(READ_BUFFER_SIZE, File::open(&cached)?);
+        unpack_without_first_dir(
+            &mut tar::Archive::new(GzDecoder::new(file)),
+            extracted.path(),
+            false,
+        )?;
+        install_components(extracted.path(), &sysroot)
+    })
+    .await?
+}
+
+/// Install the components contained in an extracted tarball into the sysroot, recording the
+/// installed files the same way rustup does.
+fn install_components(extracted: &Path, sysroot: &Path) -> Result<(), Error> {
+    fs::create_dir_all(rustlib(sysroot))?;
+    let mut installed = installed_components(sysroot)?;
+
+    for component in fs::read_to_string(extracted.join("components"))?.lines() {
+        let component = component.trim();
+        if component.is_empty() {
+            continue;
+        }
+        if installed.iter().any(|c| c == component) {
+            uninstall_component(sysroot, component)?;
+            installed.retain(|c| c != component);
+        }
+
+        let source = extracted.join(component);
+        let mut entries = Vec::new();
+        for line in fs::read_to_string(source.join("manifest.in"))?.lines() {
+            let mut parts = line.splitn(2, ':');
+            let (kind, path) = match (parts.next(), parts.next()) {
+                (Some(kind), Some(path)) if is_relative_path(path) => (kind, path),
+                _ => bail!("invalid entry in the manifest of {}: {}", component, line),
+            };
+            match kind {
+                "file" => copy_file(&source.join(path), &sysroot.join(path))?,
+                "dir" => {
+                    for entry in WalkDir::new(source.join(path)) {
+                        let entry = entry?;
+                        let relative = entry.path().strip_prefix(&source)?;
+                        if entry.file_type().is_dir() {
+                            fs::create_dir_all(sysroot.join(relative))?;
+                        } else {
+                            copy_file(entry.path(), &sysroot.join(relative))?;
+                        }
+                    }
+                }
+                _ => bail!("invalid entry in the manifest of {}: {}", component, line),
+            }
+            entries.push(line.to_string());
+        }
+
+        let mut content = entries.join("\n");
+        content.push('\n');
+        fs::write(
+            rustlib(sysroot).join(format!("manifest-{}", component)),
+            content,
+        )?;
+        installed.push(component.to_string());
+        write_components(sysroot, &installed)?;
+    }
+    Ok(())
+}
+
+fn uninstall_component(sysroot: &Path, component: &str) -> Result<(), Error> {
+    let manifest = rustlib(sysroot).join(format!("manifest-{}", component));
+    for line in fs::read_to_string(&manifest)?.lines() {
+        let mut parts = line.splitn(2, ':');
+        let (kind, path) = match (parts.next(), parts.next()) {
+            (Some(kind), Some(path)) if is_relative_path(path) => (kind, sysroot.join(path)),
+            _ => continue,
+        };
+        match kind {
+            "file" if path.exists() => fs::remove_file(&path)?,
+            "dir" if path.exists() => remove_dir_all(&path)?,
+            _ => {}
+        }
+    }
+    fs::remove_file(&manifest)?;
+
+    let remaining: Vec<_> = installed_components(sysroot)?
+        .into_iter()
+        .filter(|c| c != component)
+        .collect();
+    write_components(sysroot, &remaining)
+}
+
+fn copy_file(source: &Path, dest: &Path) -> Result<(), Error> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // `std::fs::copy` preserves the permissions, keeping the binaries executable.
+    fs::copy(source, dest).with_context(|_| format!("failed to install {}", dest.display()))?;
+    Ok(())
+}
+
+fn is_relative_path(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{install_components, manifest_url, resolve, uninstall_component, Manifest};
+    use failure::Error;
+    use std::fs;
+
+    const HOST: &str = "x86_64-unknown-linux-gnu";
+
+    #[test]
+    fn test_manifest_url() -> Result<(), Error> {
+        let url = |name, date| manifest_url(name, date).unwrap();
+        assert_eq!(
+            url("stable", None),
+            "https://static.rust-lang.org/dist/channel-rust-stable.toml"
+        );
+        assert_eq!(
+            url("stable", Some("2020-07-16")),
+            "https://static.rust-lang.org/dist/2020-07-16/channel-rust-stable.toml"
+        );
+        assert_eq!(
+            url("1.45.0", None),
+            "https://static.rust-lang.org/dist/channel-rust-1.45.0.toml"
+        );
+        assert_eq!(
+            url("nightly-2020-07-01", Some("2020-07-01")),
+            "https://static.rust-lang.org/dist/2020-07-01/channel-rust-nightly.toml"
+        );
+
+        assert!(manifest_url("nightly-x86_64-unknown-linux-gnu", None).is_err());
+        assert!(manifest_url("../foo", None).is_err());
+        assert!(manifest_url("", None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve() -> Result<(), Error> {
+        let manifest: Manifest = toml::from_str(
+            r#"
+                date = "2020-07-16"
+
+                [pkg.rustc.target.x86_64-unknown-linux-gnu]
+                available = true
+                url = "https://example.com/rustc.tar.gz"
+                hash = "aaaa"
+                xz_url = "https://example.com/rustc.tar.xz"
+
+                [pkg.rust-std.target.x86_64-unknown-linux-gnu]
+                available = true
+                url = "https://example.com/rust-std-linux.tar.gz"
+                hash = "bbbb"
+
+                [pkg.rust-std.target.wasm32-unknown-unknown]
+                available = true
+                url = "https://example.com/rust-std-wasm.tar.gz"
+                hash = "cccc"
+
+                [pkg.rust-src.target."*"]
+                available = true
+                url = "https://example.com/rust-src.tar.gz"
+                hash = "dddd"
+
+                [pkg.rustfmt-preview.target.x86_64-unknown-linux-gnu]
+                available = false
+
+                [renames.rustfmt]
+                to = "rustfmt-preview"
+
+                [profiles]
+                minimal = ["rustc", "rust-std", "rustfmt"]
+            "#,
+        )?;
+
+        let packages = |tarballs: Vec<super::Tarball>| {
+            tarballs
+                .into_iter()
+                .map(|t| format!("{} {} {}", t.package, t.target, t.hash))
+                .collect::<Vec<_>>()
+        };
+
+        // Unavailable components of the profile are skipped.
+        assert_eq!(
+            packages(resolve(&manifest, Some("minimal"), &[], &[], HOST)?),
+            vec![
+                "rust-std x86_64-unknown-linux-gnu bbbb",
+                "rustc x86_64-unknown-linux-gnu aaaa",
+            ]
+        );
+        assert!(resolve(&manifest, Some("default"), &[], &[], HOST).is_err());
+        let tarballs = resolve(&manifest, Some("minimal"), &[], &[], HOST)?;
+        assert_eq!(tarballs[0].xz_url, None);
+        assert_eq!(
+            tarballs[1].xz_url.as_deref(),
+            Some("https://example.com/rustc.tar.xz")
+        );
+
+        assert_eq!(
+            packages(resolve(
+                &manifest,
+                None,
+                &["rust-src"],
+                &["wasm32-unknown-unknown"],
+                HOST
+            )?),
+            vec![
+                "rust-src x86_64-unknown-linux-gnu dddd",
+                "rust-std wasm32-unknown-unknown cccc",
+            ]
+        );
+
+        // Explicitly requested components are required.
+        assert!(resolve(&manifest, None, &["rustfmt"], &[], HOST).is_err());
+        assert!(resolve(&manifest, None, &[], &["i686-pc-windows-msvc"], HOST).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_install_components() -> Result<(), Error> {
+        let extracted = tempfile::tempdir()?;
+        let extracted = extracted.path();
+        fs::write(extracted.join("components"), "rustc\nrust-docs\n")?;
+        fs::create_dir_all(extracted.join("rustc").join("bin"))?;
+        fs::write(extracted.join("rustc").join("bin").join("rustc"), "rustc")?;
+        fs::write(
+            extracted.join("rustc").join("manifest.in"),
+            "file:bin/rustc\n",
+        )?;
+        let docs = extracted.join("rust-docs").join("share").join("doc");
+        fs::create_dir_all(docs.join("rust"))?;
+        fs::write(docs.join("rust").join("index.html"), "docs")?;
+        fs::write(
+            extracted.join("rust-docs").join("manifest.in"),
+            "dir:share/doc/rust\n",
+        )?;
+
+        let sysroot = tempfile::tempdir()?;
+        let sysroot = sysroot.path();
+        install_components(extracted, sysroot)?;
+        assert_eq!(
+            fs::read_to_string(sysroot.join("bin").join("rustc"))?,
+            "rustc"
+        );
+        let index = sysroot.join("share/doc/rust/index.html");
+        assert_eq!(fs::read_to_string(&index)?, "docs");
+        let rustlib = sysroot.join("lib").join("rustlib");
+        assert_eq!(
+            fs::read_to_string(rustlib.join("components"))?,
+            "rustc\nrust-docs\n"
+        );
+
+        uninstall_component(sysroot, "rust-docs")?;
+        assert!(!index.exists());
+        assert!(!rustlib.join("manifest-rust-docs").exists());
+        assert_eq!(fs::read_to_string(rustlib.join("components"))?, "rustc\n");
+
+        // Paths escaping the sysroot are rejected.
+        fs::write(extracted.join("components"), "rustc\n")?;
+        fs::write(
+            extracted.join("rustc").join("manifest.in"),
+            "file:../rustc\n",
+        )?;
+        assert!(install_components(extracted, sysroot).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,344 @@
+use crate::Build;
+use failure::Error;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Build variant capturing how long cargo spent compiling each unit (a target of a package, built
+/// in a specific mode), for tracking the compile times of the ecosystem over time.
+///
+/// The build is executed with `--timings=html,json`, which requires `-Zunstable-options` and
+/// thus a nightly toolchain. When the toolchain doesn't support it the build is executed again
+/// without timings, and
+/// [`BuildTimings::is_supported`](struct.BuildTimings.html#method.is_supported) returns false.
+///
+/// # Example
+///
+/// ```no_run
+/// # use rustwide::{Build, TimingCapture};
+/// # use std::error::Error;
+/// # async fn run(build: &Build<'_>) -> Result<(), Box<dyn Error>> {
+/// let timings = TimingCapture::new().args(&["build", "--release"]).run(build).await?;
+/// for unit in timings.slowest_units(10) {
+///     println!("{} {}: {:?}", unit.name(), unit.target(), unit.duration());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct TimingCapture {
+    args: Vec<String>,
+}
+
+impl TimingCapture {
+    /// Create a new capture, running `cargo build`.
+    pub fn new() -> Self {
+        TimingCapture {
+            args: vec!["build".into()],
+        }
+    }
+
+    /// Override the arguments passed to cargo, for example to run `check` instead of `build`.
+    pub fn args(mut self, args: &[&str]) -> Self {
+        self.args = args.iter().map(|arg| arg.to_string()).collect();
+        self
+    }
+
+    /// Build the crate, collecting the timings of each unit.
+    ///
+    /// Build failures are not errors: they're reported by
+    /// [`BuildTimings::build_succeeded`](struct.BuildTimings.html#method.build_succeeded), and
+    /// the timings of the units compiled before the failure are still collected.
+    pub async fn run(&self, build: &Build<'_>) -> Result<BuildTimings, Error> {
+        let mut units = Vec::new();
+        let mut unsupported = false;
+        let res = build
+            .cargo()
+            .args(&self.args)
+            .args(&["--timings=html,json", "-Zunstable-options"])
+            .process_lines(&mut |line, actions| {
+                if let Some(unit) = parse_timing_line(line) {
+                    units.push(unit);
+                    actions.remove_line();
+                } else if is_unsupported_error(line) {
+                    unsupported = true;
+                }
+            })
+            .run()
+            .await;
+
+        if res.is_err() && unsupported {
+            info!("the toolchain doesn't support timings, building without them");
+            let res = build.cargo().args(&self.args).run().await;
+            return Ok(BuildTimings {
+                units: Vec::new(),
+                html_report: None,
+                supported: false,
+                build_succeeded: res.is_ok(),
+            });
+        }
+
+        let report = build
+            .host_target_dir()
+            .join("cargo-timings")
+            .join("cargo-timing.html");
+        let html_report = match std::fs::read_to_string(&report) {
+            Ok(content) => Some(content),
+            Err(err) => {
+                warn!("failed to read the timings report: {}", err);
+                None
+            }
+        };
+
+        Ok(BuildTimings {
+            units,
+            html_report,
+            supported: true,
+            build_succeeded: res.is_ok(),
+        })
+    }
+}
+
+/// Timings collected by a [`TimingCapture`](struct.TimingCapture.html).
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildTimings {
+    units: Vec<UnitTiming>,
+    #[serde(skip)]
+    html_report: Option<String>,
+    supported: bool,
+    build_succeeded: bool,
+}
+
+impl BuildTimings {
+    /// Return the timings of the compiled units, in the order they finished compiling.
+    pub fn units(&self) -> &[UnitTiming] {
+        &self.units
+    }
+
+    /// Return the `count` units that took the longest to compile, slowest first.
+    pub fn slowest_units(&self, count: usize) -> Vec<&UnitTiming> {
+        let mut units: Vec<_> = self.units.iter().collect();
+        units.sort_by(|a, b| b.duration_secs.partial_cmp(&a.duration_secs).unwrap());
+        units.truncate(count);
+        units
+    }
+
+    /// Return the sum of the time spent compiling each unit. Units are compiled in parallel, so
+    /// this is usually longer than the build.
+    pub fn total_duration(&self) -> Duration {
+        self.units.iter().map(|unit| unit.duration()).sum()
+    }
+
+    /// Return the HTML report generated by cargo, if it was found in the target directory.
+    pub fn html_report(&self) -> Option<&str> {
+        self.html_report.as_deref()
+    }
+
+    /// Check whether the toolchain supports capturing timings. If it doesn't, no timing is
+    /// collected.
+    pub fn is_supported(&self) -> bool {
+        self.supported
+    }
+
+    /// Check whether the build succeeded.
+    pub fn build_succeeded(&self) -> bool {
+        self.build_succeeded
+    }
+
+    /// Render the timings as JSON.
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Time spent compiling a single unit, as reported by cargo.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UnitTiming {
+    package_id: String,
+    name: String,
+    version: String,
+    target: String,
+    mode: String,
+    duration_secs: f64,
+    rmeta_time_secs: Option<f64>,
+}
+
+impl UnitTiming {
+    /// Get the cargo ID of the package the unit belongs to.
+    pub fn package_id(&self) -> &str {
+        &self.package_id
+    }
+
+    /// Get the name of the package the unit belongs to.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the version of the package the unit belongs to.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Get the name of the target compiled by the unit.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// Get the mode the unit was compiled in, like `build`, `check` or `run-custom-build`.
+    pub fn mode(&self) -> &str {
+        &self.mode
+    }
+
+    /// Get the time spent compiling the unit.
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.duration_secs)
+    }
+
+    /// Get the time it took to generate the metadata of the unit, after which the units depending
+    /// on it can start compiling. Only available for units producing metadata.
+    pub fn rmeta_time(&self) -> Option<Duration> {
+        self.rmeta_time_secs.map(Duration::from_secs_f64)
+    }
+}
+
+#[derive(Deserialize)]
+struct TimingMessage {
+    reason: String,
+    package_id: String,
+    target: TimingTarget,
+    mode: String,
+    duration: f64,
+    rmeta_time: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct TimingTarget {
+    name: String,
+}
+
+fn parse_timing_line(line: &str) -> Option<UnitTiming> {
+    if !line.starts_with('{') {
+        return None;
+    }
+    let message: TimingMessage = serde_json::from_str(line).ok()?;
+    if message.reason != "timing-info" {
+        return None;
+    }
+
+    let (name, version) = parse_package_id(&message.package_id)?;
+    Some(UnitTiming {
+        name,
+        version,
+        target: message.target.name,
+        mode: message.mode,
+        duration_secs: message.duration,
+        rmeta_time_secs: message.rmeta_time,
+        package_id: message.package_id,
+    })
+}
+
+/// Extract the name and the version of a package from its ID, supporting both the `name version
+/// (source)` format and the package ID specification format (`source#name@version`).
+pub(crate) fn parse_package_id(id: &str) -> Option<(String, String)> {
+    if let Some(position) = id.rfind('#') {
+        let (source, fragment) = (&id[..position], &id[position + 1..]);
+        return match fragment.find('@') {
+            Some(at) => Some((fragment[..at].into(), fragment[at + 1..].into())),
+            // The name is omitted when it matches the last component of the source URL.
+            None => Some((
+                source.trim_end_matches('/').rsplit('/').next()?.into(),
+                fragment.into(),
+            )),
+        };
+    }
+
+    let mut parts = id.split(' ');
+    Some((parts.next()?.into(), parts.next()?.into()))
+}
+
+/// Check whether the line is the error cargo emits when the timings are not supported, either
+/// because the toolchain is not nightly or because cargo is too old.
+fn is_unsupported_error(line: &str) -> bool {
+    line.contains("is only accepted on the nightly channel")
+        || line.contains("unstable-options")
+        || (line.contains("--timings") && line.contains("wasn't expected"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_unsupported_error, parse_package_id, parse_timing_line, BuildTimings};
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_timing_line() {
+        let unit = parse_timing_line(
+            r#"{"reason":"timing-info","package_id":"log 0.4.8 (registry+https://github.com/rust-lang/crates.io-index)","target":{"kind":["lib"],"crate_types":["lib"],"name":"log","src_path":"/src/lib.rs","edition":"2015","doc":true,"doctest":true,"test":true},"mode":"build","duration":1.5,"rmeta_time":0.5}"#,
+        )
+        .unwrap();
+        assert_eq!(unit.name(), "log");
+        assert_eq!(unit.version(), "0.4.8");
+        assert_eq!(unit.target(), "log");
+        assert_eq!(unit.mode(), "build");
+        assert_eq!(unit.duration(), Duration::from_millis(1500));
+        assert_eq!(unit.rmeta_time(), Some(Duration::from_millis(500)));
+
+        let unit = parse_timing_line(
+            r#"{"reason":"timing-info","package_id":"path+file:///opt/rustwide/workdir#foo@0.1.0","target":{"name":"build-script-build"},"mode":"run-custom-build","duration":0.25,"rmeta_time":null}"#,
+        )
+        .unwrap();
+        assert_eq!(unit.name(), "foo");
+        assert_eq!(unit.target(), "build-script-build");
+        assert_eq!(unit.rmeta_time(), None);
+
+        assert!(parse_timing_line(r#"{"reason":"compiler-artifact"}"#).is_none());
+        assert!(parse_timing_line("   Compiling log v0.4.8").is_none());
+    }
+
+    #[test]
+    fn test_parse_package_id() {
+        let parsed = |id| parse_package_id(id).unwrap();
+        assert_eq!(
+            parsed("serde 1.0.104 (registry+https://github.com/rust-lang/crates.io-index)"),
+            ("serde".into(), "1.0.104".into())
+        );
+        assert_eq!(
+            parsed("registry+https://github.com/rust-lang/crates.io-index#serde@1.0.104"),
+            ("serde".into(), "1.0.104".into())
+        );
+        assert_eq!(
+            parsed("path+file:///opt/rustwide/workdir/foo#0.1.0"),
+            ("foo".into(), "0.1.0".into())
+        );
+    }
+
+    #[test]
+    fn test_slowest_units() {
+        let timings = BuildTimings {
+            units: ["a", "b", "c"]
+                .iter()
+                .zip(&[1.0, 3.0, 2.0])
+                .map(|(name, duration)| {
+                    parse_timing_line(&format!(
+                        r#"{{"reason":"timing-info","package_id":"{} 1.0.0 (path)","target":{{"name":"{}"}},"mode":"build","duration":{}}}"#,
+                        name, name, duration
+                    ))
+                    .unwrap()
+                })
+                .collect(),
+            html_report: None,
+            supported: true,
+            build_succeeded: true,
+        };
+
+        let slowest: Vec<_> = timings.slowest_units(2).iter().map(|u| u.name()).collect();
+        assert_eq!(slowest, vec!["b", "c"]);
+        assert_eq!(timings.total_duration(), Duration::from_secs(6));
+    }
+
+    #[test]
+    fn test_is_unsupported_error() {
+        assert!(is_unsupported_error(
+            "error: the `-Z` flag is only accepted on the nightly channel of Cargo, but this is the `stable` channel"
+        ));
+        assert!(!is_unsupported_error("error: could not compile `foo`."));
+    }
+}
@@ -1,12 +1,13 @@
 //! Tools to manage and use Rust toolchains.
 
-use crate::cmd::{Binary, Command, Runnable};
-use crate::tools::{RUSTUP, RUSTUP_TOOLCHAIN_INSTALL_MASTER};
+use crate::cmd::{exe_suffix, Binary, Command, Runnable};
+use crate::tools::{RustupProgress, RUSTUP, RUSTUP_TOOLCHAIN_INSTALL_MASTER};
 use crate::Workspace;
 use failure::{bail, Error, ResultExt};
 use log::info;
 use std::borrow::Cow;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 pub(crate) const MAIN_TOOLCHAIN_NAME: &str = "stable";
 
@@ -39,23 +40,58 @@ impl DistToolchain {
     }
 
     async fn init(&self, workspace: &Workspace) -> Result<(), Error> {
+        if workspace.standalone_toolchains() {
+            return crate::standalone::install(workspace, self.name()).await;
+        }
+
         info!("installing toolchain {}", self.name());
-        Command::new(workspace, &RUSTUP)
-            .args(&[
-                "toolchain",
-                "install",
-                self.name(),
-                "--profile",
-                workspace.rustup_profile(),
-            ])
-            .run()
-            .await
-            .with_context(|_| format!("unable to install toolchain {} via rustup", self.name()))?;
+        let network_error = &AtomicBool::new(false);
+        crate::retry::retry_if(
+            workspace,
+            &format!("installing toolchain {}", self.name()),
+            |_| network_error.swap(false, Ordering::SeqCst),
+            || async move {
+                let mut progress = RustupProgress::new();
+                Command::new(workspace, &RUSTUP)
+                    .args(&[
+                        "toolchain",
+                        "install",
+                        self.name(),
+                        "--profile",
+                        workspace.rustup_profile(),
+                    ])
+                    .process_lines(&mut |line, actions| {
+                        detect_network_error(line, network_error);
+                        progress.process_line(line, actions)
+                    })
+                    .run()
+                    .await
+            },
+        )
+        .await
+        .with_context(|_| format!("unable to install toolchain {} via rustup", self.name()))?;
 
         Ok(())
     }
 }
 
+/// Record whether a line printed while installing a toolchain reports a network error, which is
+/// retried according to the workspace's [`RetryPolicy`](../struct.RetryPolicy.html).
+fn detect_network_error(line: &str, flag: &AtomicBool) {
+    const PATTERNS: &[&str] = &[
+        "could not download",
+        "failed to download",
+        "download failed",
+        "error sending request",
+        "connection reset",
+        "timed out",
+    ];
+    let line = line.to_lowercase();
+    if PATTERNS.iter().any(|pattern| line.contains(pattern)) {
+        flag.store(true, Ordering::SeqCst);
+    }
+}
+
 #[derive(Copy, Clone)]
 enum RustupAction {
     Add,
@@ -120,6 +156,10 @@ impl CiToolchain {
     }
 
     async fn init(&self, workspace: &Workspace) -> Result<(), Error> {
+        if workspace.standalone_toolchains() {
+            return crate::standalone::install_ci(workspace, &self.sha, self.alt).await;
+        }
+
         if self.alt {
             info!("installing toolchain {}-alt", self.sha);
         } else {
@@ -131,27 +171,66 @@ impl CiToolchain {
             args.push("--alt");
         }
 
-        Command::new(workspace, &RUSTUP_TOOLCHAIN_INSTALL_MASTER)
-            .args(&args)
-            .run()
-            .await
-            .with_context(|_| {
-                format!(
-                    "unable to install toolchain {} via rustup-toolchain-install-master",
-                    self.sha
-                )
-            })?;
+        let network_error = &AtomicBool::new(false);
+        let args = &args;
+        crate::retry::retry_if(
+            workspace,
+            &format!("installing toolchain {}", self.sha),
+            |_| network_error.swap(false, Ordering::SeqCst),
+            || async move {
+                Command::new(workspace, &RUSTUP_TOOLCHAIN_INSTALL_MASTER)
+                    .args(args)
+                    .process_lines(&mut |line, _| detect_network_error(line, network_error))
+                    .run()
+                    .await
+            },
+        )
+        .await
+        .with_context(|_| {
+            format!(
+                "unable to install toolchain {} via rustup-toolchain-install-master",
+                self.sha
+            )
+        })?;
 
         Ok(())
     }
 }
 
+/// Metadata of a host toolchain. See [`Toolchain`](struct.Toolchain.html) to create and get it.
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, Hash, Debug, Clone)]
+pub struct HostToolchain {
+    sysroot: PathBuf,
+}
+
+impl HostToolchain {
+    /// Get the path of the toolchain's sysroot on the host.
+    pub fn sysroot(&self) -> &Path {
+        &self.sysroot
+    }
+
+    fn init(&self) -> Result<(), Error> {
+        for binary in &["cargo", "rustc"] {
+            let path = self.sysroot.join("bin").join(exe_suffix(binary.as_ref()));
+            if !path.is_file() {
+                bail!(
+                    "the host toolchain at {} doesn't contain {}",
+                    self.sysroot.display(),
+                    path.display()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, Hash, Debug, Clone)]
 #[serde(rename_all = "kebab-case", tag = "type")]
 enum ToolchainInner {
     Dist(DistToolchain),
     #[serde(rename = "ci")]
     CI(CiToolchain),
+    Host(HostToolchain),
 }
 
 /// Representation of a Rust compiler toolchain.
@@ -208,6 +287,46 @@ impl Toolchain {
         }
     }
 
+    /// Create a new host toolchain.
+    ///
+    /// Host toolchains use a compiler already present on the host instead of one managed by the
+    /// workspace, for example a compiler built locally from the [rust-lang/rust][repo] repository.
+    /// The provided path is the toolchain's sysroot, which must contain both `bin/rustc` and
+    /// `bin/cargo`. The toolchain is never installed, removed or changed by rustwide: components
+    /// and targets have to be managed outside of it.
+    ///
+    /// Sandboxed commands mount the sysroot read-only, so the compiler must be able to run inside
+    /// the sandbox image.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # async fn run() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init().await?;
+    /// # let krate = Crate::local("".as_ref());
+    /// let toolchain = Toolchain::host("/src/rust/build/x86_64-unknown-linux-gnu/stage2".as_ref());
+    /// toolchain.install(&workspace).await?;
+    ///
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// build_dir.build(&toolchain, &krate, SandboxBuilder::new()).run(|build| {
+    ///     build.cargo().args(&["test"]).run()?;
+    ///     Ok(())
+    /// }).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [repo]: https://github.com/rust-lang/rust
+    pub fn host(sysroot: &Path) -> Self {
+        Toolchain {
+            inner: ToolchainInner::Host(HostToolchain {
+                sysroot: sysroot.into(),
+            }),
+        }
+    }
+
     /// If this toolchain is a dist toolchain, return its metadata.
     pub fn as_dist(&self) -> Option<&DistToolchain> {
         if let ToolchainInner::Dist(dist) = &self.inner {
@@ -226,16 +345,68 @@ impl Toolchain {
         }
     }
 
+    /// If this toolchain is a host toolchain, return its metadata.
+    pub fn as_host(&self) -> Option<&HostToolchain> {
+        if let ToolchainInner::Host(host) = &self.inner {
+            Some(host)
+        } else {
+            None
+        }
+    }
+
     /// Download and install the toolchain.
+    ///
+    /// Host toolchains are not installed: this only checks their sysroot contains `rustc` and
+    /// `cargo`.
     pub async fn install(&self, workspace: &Workspace) -> Result<(), Error> {
         match &self.inner {
             ToolchainInner::Dist(dist) => dist.init(workspace).await?,
             ToolchainInner::CI(ci) => ci.init(workspace).await?,
+            ToolchainInner::Host(host) => host.init()?,
         }
 
         Ok(())
     }
 
+    /// Estimate how many bytes have to be downloaded to install the toolchain with the
+    /// workspace's [rustup profile](struct.WorkspaceBuilder.html#method.rustup_profile), and the
+    /// provided components and targets. Nothing is installed: only the dist manifest of the
+    /// toolchain is downloaded, and the size of each tarball is requested from the dist server.
+    ///
+    /// The estimate doesn't take into account the files already downloaded or installed, so it
+    /// represents a fresh installation. This is only supported for dist toolchains.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain};
+    /// # use std::error::Error;
+    /// # async fn run() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init().await?;
+    /// let toolchain = Toolchain::dist("nightly");
+    /// let size = toolchain
+    ///     .estimated_download_size(&workspace, &["rust-src"], &["wasm32-unknown-unknown"])
+    ///     .await?;
+    /// println!("installing nightly requires downloading {} bytes", size);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn estimated_download_size(
+        &self,
+        workspace: &Workspace,
+        components: &[&str],
+        targets: &[&str],
+    ) -> Result<u64, Error> {
+        match &self.inner {
+            ToolchainInner::Dist(dist) => {
+                crate::standalone::download_size(workspace, dist.name(), components, targets).await
+            }
+            ToolchainInner::CI(_) | ToolchainInner::Host(_) => {
+                Err(ToolchainError::UnsupportedOperation.into())
+            }
+        }
+    }
+
     /// Download and install a component for the toolchain.
     pub async fn add_component(&self, workspace: &Workspace, name: &str) -> Result<(), Error> {
         self.change_rustup_thing(workspace, RustupAction::Add, RustupThing::Component, name)
@@ -294,6 +465,9 @@ impl Toolchain {
         let thing = thing.to_string();
         let action = action.to_string();
 
+        if let ToolchainInner::Host(_) = self.inner {
+            return Err(ToolchainError::UnsupportedOperation.into());
+        }
         if let ToolchainInner::CI { .. } = self.inner {
             bail!(
                 "{} {} on CI toolchains is not supported yet",
@@ -307,6 +481,20 @@ impl Toolchain {
             log_action_ing, thing, name, toolchain_name
         );
 
+        if workspace.standalone_toolchains() {
+            let (components, targets) = match thing.as_str() {
+                "target" => (&[][..], &[name][..]),
+                _ => (&[name][..], &[][..]),
+            };
+            return match action.as_str() {
+                "add" => {
+                    crate::standalone::add(workspace, &toolchain_name, components, targets).await
+                }
+                _ => crate::standalone::remove(workspace, &toolchain_name, components, targets),
+            };
+        }
+
+        let mut progress = RustupProgress::new();
         Command::new(workspace, &RUSTUP)
             .args(&[
                 thing.as_str(),
@@ -315,6 +503,7 @@ impl Toolchain {
                 &toolchain_name,
                 name,
             ])
+            .process_lines(&mut |line, actions| progress.process_line(line, actions))
             .run()
             .await
             .with_context(|_| {
@@ -339,6 +528,10 @@ impl Toolchain {
             return Err(ToolchainError::UnsupportedOperation.into());
         };
 
+        if workspace.standalone_toolchains() {
+            return crate::standalone::installed_targets(workspace, name);
+        }
+
         let mut not_installed = false;
         let result = Command::new(workspace, &RUSTUP)
             .args(&[thing.as_str(), "list", "--installed", "--toolchain", name])
@@ -369,8 +562,23 @@ impl Toolchain {
     }
 
     /// Remove the toolchain from the rustwide workspace, freeing up disk space.
+    ///
+    /// This is not supported for host toolchains, which are not managed by the workspace.
     pub async fn uninstall(&self, workspace: &Workspace) -> Result<(), Error> {
+        if self.as_host().is_some() {
+            return Err(ToolchainError::UnsupportedOperation.into());
+        }
         let name = self.rustup_name();
+        if workspace.standalone_toolchains() {
+            let sysroot = crate::standalone::sysroot(workspace, &name);
+            if !sysroot.exists() {
+                return Err(ToolchainError::NotInstalled.into());
+            }
+            remove_dir_all::remove_dir_all(&sysroot)
+                .with_context(|_| format!("unable to uninstall toolchain {}", name))?;
+            return Ok(());
+        }
+
         Command::new(workspace, &RUSTUP)
             .args(&["toolchain", "uninstall", &name])
             .run()
@@ -428,11 +636,12 @@ impl Toolchain {
         }
     }
 
-    fn rustup_name(&self) -> String {
+    pub(crate) fn rustup_name(&self) -> String {
         match &self.inner {
             ToolchainInner::Dist(dist) => dist.name.to_string(),
             ToolchainInner::CI(ci) if ci.alt => format!("{}-alt", ci.sha),
             ToolchainInner::CI(ci) => ci.sha.to_string(),
+            ToolchainInner::Host(host) => host.sysroot.display().to_string(),
         }
     }
 }
@@ -454,7 +663,11 @@ impl Runnable for RustupProxy<'_> {
     }
 
     fn prepare_command<'w, 'pl>(&self, cmd: Command<'w, 'pl>) -> Command<'w, 'pl> {
-        cmd.args(&[format!("+{}", self.toolchain.rustup_name())])
+        if let Some(host) = self.toolchain.as_host() {
+            cmd.host_toolchain_binary(host.sysroot(), self.name)
+        } else {
+            cmd.toolchain_binary(&self.toolchain.rustup_name(), self.name)
+        }
     }
 }
 
@@ -471,7 +684,11 @@ pub(crate) fn list_installed_toolchains(rustup_home: &Path) -> Result<Vec<Toolch
             .to_string();
         // A toolchain installed by rustup has a corresponding file in $RUSTUP_HOME/update-hashes
         // A toolchain linked by rustup is just a symlink
-        if entry.file_type()?.is_symlink() || update_hashes.join(&name).exists() {
+        // A standalone toolchain has a marker file in its directory
+        if entry.file_type()?.is_symlink()
+            || update_hashes.join(&name).exists()
+            || entry.path().join(crate::standalone::MARKER_FILE).is_file()
+        {
             result.push(Toolchain::dist(&name));
         } else {
             let (sha, alt) = if name.ends_with("-alt") {
@@ -495,6 +712,7 @@ mod tests {
         const DIST: &str = r#"{"type": "dist", "name": "stable"}"#;
         const CI_NORMAL: &str = r#"{"type": "ci", "sha": "0000000", "alt": false}"#;
         const CI_ALT: &str = r#"{"type": "ci", "sha": "0000000", "alt": true}"#;
+        const HOST: &str = r#"{"type": "host", "sysroot": "/opt/rust/stage2"}"#;
 
         assert_eq!(Toolchain::dist("stable"), serde_json::from_str(DIST)?);
         assert_eq!(
@@ -505,6 +723,10 @@ mod tests {
             Toolchain::ci("0000000", true),
             serde_json::from_str(CI_ALT)?
         );
+        assert_eq!(
+            Toolchain::host("/opt/rust/stage2".as_ref()),
+            serde_json::from_str(HOST)?
+        );
 
         Ok(())
     }
@@ -548,10 +770,19 @@ mod tests {
                 .join(format!("{}-alt", CI_SHA)),
         )?;
 
+        // Create a standalone toolchain
+        let standalone = rustup_home.path().join("toolchains").join("beta");
+        std::fs::create_dir_all(&standalone)?;
+        std::fs::write(
+            standalone.join(crate::standalone::MARKER_FILE),
+            "2020-07-16",
+        )?;
+
         let res = super::list_installed_toolchains(rustup_home.path())?;
-        assert_eq!(4, res.len());
+        assert_eq!(5, res.len());
         assert!(res.contains(&Toolchain::dist(DIST_NAME)));
         assert!(res.contains(&Toolchain::dist(LINK_NAME)));
+        assert!(res.contains(&Toolchain::dist("beta")));
         assert!(res.contains(&Toolchain::ci(CI_SHA, false)));
         assert!(res.contains(&Toolchain::ci(CI_SHA, true)));
 
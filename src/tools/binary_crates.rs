@@ -1,14 +1,19 @@
 use crate::cmd::{Binary, Command, Runnable};
+use crate::tools::prebuilt::{self, Prebuilt};
 use crate::tools::Tool;
 use crate::{Toolchain, Workspace};
 use async_trait::async_trait;
 use failure::Error;
+use log::{info, warn};
+use std::borrow::Cow;
 use std::path::PathBuf;
 
+#[derive(Clone)]
 pub(crate) struct BinaryCrate {
-    pub(super) crate_name: &'static str,
-    pub(super) binary: &'static str,
-    pub(super) cargo_subcommand: Option<&'static str>,
+    pub(super) crate_name: Cow<'static, str>,
+    pub(super) binary: Cow<'static, str>,
+    pub(super) cargo_subcommand: Option<Cow<'static, str>>,
+    pub(super) prebuilt: Option<Prebuilt>,
 }
 
 impl BinaryCrate {
@@ -22,13 +27,13 @@ impl Runnable for BinaryCrate {
         Binary::ManagedByRustwide(if self.cargo_subcommand.is_some() {
             "cargo".into()
         } else {
-            self.binary.into()
+            self.binary.as_ref().into()
         })
     }
 
     fn prepare_command<'w, 'pl>(&self, mut cmd: Command<'w, 'pl>) -> Command<'w, 'pl> {
-        if let Some(subcommand) = self.cargo_subcommand {
-            cmd = cmd.args(&[subcommand]);
+        if let Some(subcommand) = &self.cargo_subcommand {
+            cmd = cmd.args(&[subcommand.as_ref()]);
         }
         cmd
     }
@@ -36,8 +41,12 @@ impl Runnable for BinaryCrate {
 
 #[async_trait]
 impl Tool for BinaryCrate {
-    fn name(&self) -> &'static str {
-        self.binary
+    fn name(&self) -> &str {
+        &self.binary
+    }
+
+    fn crate_name(&self) -> Option<&str> {
+        Some(&self.crate_name)
     }
 
     fn is_installed(&self, workspace: &Workspace) -> Result<bool, Error> {
@@ -50,9 +59,25 @@ impl Tool for BinaryCrate {
     }
 
     async fn install(&self, workspace: &Workspace, fast_install: bool) -> Result<(), Error> {
+        if let Some(prebuilt) = &self.prebuilt {
+            match prebuilt::install(workspace, prebuilt, &self.crate_name, &self.binary).await {
+                Ok(()) => return Ok(()),
+                Err(err) => warn!(
+                    "failed to install prebuilt {}, building it from source: {}",
+                    self.crate_name, err
+                ),
+            }
+        }
+
         let mut cmd = Command::new(workspace, &Toolchain::MAIN.cargo())
-            .args(&["install", self.crate_name])
+            .args(&["install", &self.crate_name])
             .timeout(None);
+        if let Some(version) = workspace
+            .pinned_tool_versions()
+            .get(self.crate_name.as_ref())
+        {
+            cmd = cmd.args(&["--version", &format!("={}", version)]);
+        }
         if fast_install {
             cmd = cmd.args(&["--debug"]);
         }
@@ -62,6 +87,105 @@ impl Tool for BinaryCrate {
     }
 
     async fn update(&self, workspace: &Workspace, fast_install: bool) -> Result<(), Error> {
+        if let Some(pinned) = workspace
+            .pinned_tool_versions()
+            .get(self.crate_name.as_ref())
+        {
+            let installed = super::installed_crates(workspace)?;
+            if installed.get(self.crate_name.as_ref()) == Some(pinned) {
+                info!("tool {} is pinned to version {}", self.crate_name, pinned);
+                return Ok(());
+            }
+        }
+
         self.install(workspace, fast_install).await
     }
 }
+
+/// Additional tool installed with `cargo install` into the workspace, registered with
+/// [`WorkspaceBuilder::install_tool`](struct.WorkspaceBuilder.html#method.install_tool).
+///
+/// Registered tools are installed when the workspace is initialized, and they're kept updated
+/// alongside the tools rustwide needs. They can be executed both inside and outside the sandbox
+/// by passing them to [`Command::new`](cmd/struct.Command.html#method.new) or to
+/// [`Build::cmd`](struct.Build.html#method.cmd).
+///
+/// # Example
+///
+/// ```no_run
+/// # use rustwide::{WorkspaceBuilder, InstallableTool, cmd::Command};
+/// # use std::error::Error;
+/// # async fn run() -> Result<(), Box<dyn Error>> {
+/// let udeps = InstallableTool::new("cargo-udeps").cargo_subcommand("udeps");
+///
+/// let workspace = WorkspaceBuilder::new("work".as_ref(), "my-app")
+///     .install_tool(udeps.clone())
+///     .init()
+///     .await?;
+/// Command::new(&workspace, &udeps).args(&["--help"]).run().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct InstallableTool {
+    krate: BinaryCrate,
+}
+
+impl InstallableTool {
+    /// Install the crate with the provided name from crates.io. By default the crate is expected
+    /// to contain a binary with the same name.
+    pub fn new(crate_name: &str) -> Self {
+        InstallableTool {
+            krate: BinaryCrate {
+                crate_name: crate_name.to_string().into(),
+                binary: crate_name.to_string().into(),
+                cargo_subcommand: None,
+                prebuilt: None,
+            },
+        }
+    }
+
+    /// Set the name of the binary to execute, if it's different from the name of the crate.
+    pub fn binary(mut self, binary: &str) -> Self {
+        self.krate.binary = binary.to_string().into();
+        self
+    }
+
+    /// Execute the tool as a cargo subcommand (for example `cargo udeps`) instead of calling its
+    /// binary directly.
+    pub fn cargo_subcommand(mut self, subcommand: &str) -> Self {
+        self.krate.cargo_subcommand = Some(subcommand.to_string().into());
+        self
+    }
+
+    /// Download prebuilt release binaries of the tool when they're available, instead of
+    /// building it from source with `cargo install`.
+    ///
+    /// The URL can contain the `{version}`, `{target}` and `{exe}` placeholders, which are
+    /// replaced with the version to install, the host target and the executable suffix of the
+    /// host (`.exe` on Windows). It can point either to the binary itself or to a `.tar.gz`
+    /// archive containing it. The SHA-256 checksum of the download is retrieved from the same
+    /// URL with the `.sha256` suffix added, and it's always verified.
+    ///
+    /// If the download or the verification fail the tool is built from source.
+    pub fn prebuilt(mut self, url: &str) -> Self {
+        self.krate.prebuilt = Some(Prebuilt {
+            url: url.to_string().into(),
+        });
+        self
+    }
+
+    pub(crate) fn as_tool(&self) -> &dyn Tool {
+        &self.krate
+    }
+}
+
+impl Runnable for InstallableTool {
+    fn name(&self) -> Binary {
+        Runnable::name(&self.krate)
+    }
+
+    fn prepare_command<'w, 'pl>(&self, cmd: Command<'w, 'pl>) -> Command<'w, 'pl> {
+        self.krate.prepare_command(cmd)
+    }
+}
@@ -0,0 +1,147 @@
+use super::prebuilt;
+use super::Tool;
+use crate::download::download_bytes;
+use crate::Workspace;
+use async_trait::async_trait;
+use failure::{format_err, Error, ResultExt};
+use log::info;
+use std::env::consts::EXE_SUFFIX;
+
+static RELEASES_URL: &str = "https://github.com/git-lfs/git-lfs/releases/download";
+/// Version of git-lfs installed when it's not pinned, as its releases are not published on
+/// crates.io where the latest version of the other tools is retrieved from.
+static DEFAULT_VERSION: &str = "3.4.1";
+
+/// [Git LFS](https://git-lfs.com), installed from the release binaries published on GitHub.
+pub(crate) struct GitLfs;
+
+impl GitLfs {
+    fn version(&self, workspace: &Workspace) -> String {
+        workspace
+            .pinned_tool_versions()
+            .get(self.name())
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_VERSION.into())
+    }
+}
+
+#[async_trait]
+impl Tool for GitLfs {
+    fn name(&self) -> &str {
+        "git-lfs"
+    }
+
+    fn crate_name(&self) -> Option<&str> {
+        Some(self.name())
+    }
+
+    fn is_installed(&self, workspace: &Workspace) -> Result<bool, Error> {
+        let path = self.binary_path(workspace);
+        if !path.is_file() {
+            return Ok(false);
+        }
+
+        Ok(crate::native::is_executable(path)?)
+    }
+
+    async fn install(&self, workspace: &Workspace, _fast_install: bool) -> Result<(), Error> {
+        let version = self.version(workspace);
+        let platform = release_platform(crate::HOST_TARGET).ok_or_else(|| {
+            format_err!(
+                "git-lfs can't be installed on {}, as no release is available for it",
+                crate::HOST_TARGET
+            )
+        })?;
+        let file = format!("git-lfs-{}-v{}.tar.gz", platform, version);
+        let url = format!("{}/v{}/{}", RELEASES_URL, version, file);
+
+        info!("downloading git-lfs {} from {}", version, url);
+        let content = download_bytes(workspace, &url).await?;
+        let checksums = download_bytes(
+            workspace,
+            &format!("{}/v{}/sha256sums.asc", RELEASES_URL, version),
+        )
+        .await?;
+        let checksum = find_checksum(&String::from_utf8_lossy(&checksums), &file)
+            .ok_or_else(|| format_err!("the checksum of {} is not published", file))?;
+        prebuilt::verify_checksum(&content, &checksum)
+            .with_context(|_| format!("failed to verify the download of {}", url))?;
+
+        let binary = prebuilt::extract_binary(&content, &format!("git-lfs{}", EXE_SUFFIX))?;
+        prebuilt::write_binary(&self.binary_path(workspace), &binary).await?;
+        prebuilt::record(workspace, self.name(), &version)?;
+        Ok(())
+    }
+
+    async fn update(&self, workspace: &Workspace, fast_install: bool) -> Result<(), Error> {
+        let version = self.version(workspace);
+        if prebuilt::installed(workspace)?.get(self.name()) == Some(&version) {
+            info!("git-lfs {} is already installed", version);
+            return Ok(());
+        }
+
+        self.install(workspace, fast_install).await
+    }
+}
+
+/// Return the name git-lfs uses for a target in the name of its release archives. Only the
+/// targets with a `.tar.gz` release are supported.
+fn release_platform(target: &str) -> Option<&'static str> {
+    if !target.contains("-linux-") {
+        return None;
+    }
+    match target.split('-').next()? {
+        "x86_64" => Some("linux-amd64"),
+        "aarch64" => Some("linux-arm64"),
+        "i686" => Some("linux-386"),
+        _ => None,
+    }
+}
+
+/// Find the checksum of a file in the (signed) list of checksums published with each release.
+fn find_checksum(checksums: &str, file: &str) -> Option<String> {
+    checksums.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(checksum), Some(name), None) if name == file => Some(checksum.to_string()),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_checksum, release_platform};
+
+    #[test]
+    fn test_release_platform() {
+        assert_eq!(
+            release_platform("x86_64-unknown-linux-gnu"),
+            Some("linux-amd64")
+        );
+        assert_eq!(
+            release_platform("aarch64-unknown-linux-musl"),
+            Some("linux-arm64")
+        );
+        assert_eq!(release_platform("x86_64-pc-windows-msvc"), None);
+        assert_eq!(release_platform("x86_64-apple-darwin"), None);
+    }
+
+    #[test]
+    fn test_find_checksum() {
+        let checksums = "-----BEGIN PGP SIGNED MESSAGE-----\n\
+                         Hash: SHA256\n\
+                         \n\
+                         aaaa  git-lfs-linux-amd64-v3.4.1.tar.gz\n\
+                         bbbb  git-lfs-linux-arm64-v3.4.1.tar.gz\n\
+                         -----BEGIN PGP SIGNATURE-----\n";
+        assert_eq!(
+            find_checksum(checksums, "git-lfs-linux-arm64-v3.4.1.tar.gz"),
+            Some("bbbb".into())
+        );
+        assert_eq!(
+            find_checksum(checksums, "git-lfs-linux-386-v3.4.1.tar.gz"),
+            None
+        );
+    }
+}
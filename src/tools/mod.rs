@@ -1,29 +1,59 @@
 mod binary_crates;
+mod git_lfs;
+mod prebuilt;
 mod rustup;
+mod rustup_progress;
 
 use crate::workspace::Workspace;
 use async_trait::async_trait;
 use binary_crates::BinaryCrate;
 use failure::{bail, Error};
+use git_lfs::GitLfs;
 use log::info;
 use rustup::Rustup;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::env::consts::EXE_SUFFIX;
 use std::path::PathBuf;
 
+pub use binary_crates::InstallableTool;
+pub use rustup::RustupSelfUpdate;
+pub(crate) use rustup_progress::RustupProgress;
+
 pub(crate) static RUSTUP: Rustup = Rustup;
 
 pub(crate) static RUSTUP_TOOLCHAIN_INSTALL_MASTER: BinaryCrate = BinaryCrate {
-    crate_name: "rustup-toolchain-install-master",
-    binary: "rustup-toolchain-install-master",
+    crate_name: Cow::Borrowed("rustup-toolchain-install-master"),
+    binary: Cow::Borrowed("rustup-toolchain-install-master"),
     cargo_subcommand: None,
+    // The release binaries are not published along with their checksums, so they can't be
+    // verified and the tool is always built from source.
+    prebuilt: None,
 };
 
 pub(crate) static GIT_CREDENTIAL_NULL: BinaryCrate = BinaryCrate {
-    crate_name: "git-credential-null",
-    binary: "git-credential-null",
+    crate_name: Cow::Borrowed("git-credential-null"),
+    binary: Cow::Borrowed("git-credential-null"),
     cargo_subcommand: None,
+    prebuilt: None,
+};
+
+pub(crate) static CARGO_NEXTEST: BinaryCrate = BinaryCrate {
+    crate_name: Cow::Borrowed("cargo-nextest"),
+    binary: Cow::Borrowed("cargo-nextest"),
+    cargo_subcommand: Some(Cow::Borrowed("nextest")),
+    prebuilt: None,
 };
 
+pub(crate) static CARGO_SEMVER_CHECKS: BinaryCrate = BinaryCrate {
+    crate_name: Cow::Borrowed("cargo-semver-checks"),
+    binary: Cow::Borrowed("cargo-semver-checks"),
+    cargo_subcommand: Some(Cow::Borrowed("semver-checks")),
+    prebuilt: None,
+};
+
+pub(crate) static GIT_LFS: GitLfs = GitLfs;
+
 static INSTALLABLE_TOOLS: &[&dyn Tool] = &[
     &RUSTUP,
     &RUSTUP_TOOLCHAIN_INSTALL_MASTER,
@@ -31,12 +61,18 @@ static INSTALLABLE_TOOLS: &[&dyn Tool] = &[
 ];
 
 #[async_trait]
-trait Tool: Send + Sync {
-    fn name(&self) -> &'static str;
+pub(crate) trait Tool: Send + Sync {
+    fn name(&self) -> &str;
     fn is_installed(&self, workspace: &Workspace) -> Result<bool, Error>;
     async fn install(&self, workspace: &Workspace, fast_install: bool) -> Result<(), Error>;
     async fn update(&self, workspace: &Workspace, fast_install: bool) -> Result<(), Error>;
 
+    /// Name of the crate the tool is installed from (or of the tool itself, if it's not
+    /// distributed as a crate), if its version can be pinned.
+    fn crate_name(&self) -> Option<&str> {
+        None
+    }
+
     fn binary_path(&self, workspace: &Workspace) -> PathBuf {
         crate::utils::normalize_path(&workspace.cargo_home().join("bin").join(format!(
             "{}{}",
@@ -47,7 +83,31 @@ trait Tool: Send + Sync {
 }
 
 pub(crate) async fn install(workspace: &Workspace, fast_install: bool) -> Result<(), Error> {
-    for tool in INSTALLABLE_TOOLS {
+    // Rustup and the tools depending on it are not needed with standalone toolchains.
+    let rustup_tools: &[&dyn Tool] = &[&RUSTUP, &RUSTUP_TOOLCHAIN_INSTALL_MASTER];
+    let tools = INSTALLABLE_TOOLS
+        .iter()
+        .copied()
+        .filter(|tool| {
+            !workspace.standalone_toolchains()
+                || !rustup_tools.iter().any(|other| other.name() == tool.name())
+        })
+        .chain(Some(&CARGO_NEXTEST as &dyn Tool).filter(|_| workspace.nextest()))
+        .chain(Some(&CARGO_SEMVER_CHECKS as &dyn Tool).filter(|_| workspace.semver_checks()))
+        .chain(Some(&GIT_LFS as &dyn Tool).filter(|_| workspace.git_lfs()))
+        .chain(workspace.extra_tools().iter().map(|tool| tool.as_tool()))
+        .collect::<Vec<_>>();
+
+    for tool in workspace.pinned_tool_versions().keys() {
+        if !tools
+            .iter()
+            .any(|installable| installable.crate_name() == Some(tool.as_str()))
+        {
+            bail!("cannot pin the version of unknown tool {}", tool);
+        }
+    }
+
+    for tool in tools {
         if tool.is_installed(workspace)? {
             info!("tool {} is installed, trying to update it", tool.name());
             tool.update(workspace, fast_install).await?;
@@ -63,3 +123,56 @@ pub(crate) async fn install(workspace: &Workspace, fast_install: bool) -> Result
 
     Ok(())
 }
+
+/// Return the crates installed in the workspace, either with `cargo install` or from prebuilt
+/// binaries, and their versions.
+pub(crate) fn installed_crates(workspace: &Workspace) -> Result<BTreeMap<String, String>, Error> {
+    let path = workspace.cargo_home().join(".crates.toml");
+    let mut crates = if path.is_file() {
+        parse_crates_toml(&std::fs::read_to_string(path)?)?
+    } else {
+        BTreeMap::new()
+    };
+    crates.extend(prebuilt::installed(workspace)?);
+    Ok(crates)
+}
+
+/// Parse the file cargo uses to record the crates installed with `cargo install`.
+fn parse_crates_toml(content: &str) -> Result<BTreeMap<String, String>, Error> {
+    let parsed: toml::Value = toml::from_str(content)?;
+
+    let mut crates = BTreeMap::new();
+    if let Some(installed) = parsed.get("v1").and_then(|v1| v1.as_table()) {
+        // Keys are in the `name version (source)` format.
+        for key in installed.keys() {
+            let mut parts = key.split(' ');
+            if let (Some(name), Some(version)) = (parts.next(), parts.next()) {
+                crates.insert(name.to_string(), version.to_string());
+            }
+        }
+    }
+    Ok(crates)
+}
+
+#[cfg(test)]
+mod tests {
+    use failure::Error;
+
+    #[test]
+    fn test_parse_crates_toml() -> Result<(), Error> {
+        let crates = super::parse_crates_toml(
+            r#"
+                [v1]
+                "git-credential-null 1.0.1 (registry+https://github.com/rust-lang/crates.io-index)" = ["git-credential-null"]
+                "rustup-toolchain-install-master 1.7.3 (registry+https://github.com/rust-lang/crates.io-index)" = ["rustup-toolchain-install-master"]
+            "#,
+        )?;
+
+        assert_eq!(crates.len(), 2);
+        assert_eq!(crates["git-credential-null"], "1.0.1");
+        assert_eq!(crates["rustup-toolchain-install-master"], "1.7.3");
+
+        assert!(super::parse_crates_toml("")?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+use crate::download::download_bytes;
+use crate::Workspace;
+use failure::{Error, ResultExt};
+use flate2::read::GzDecoder;
+use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::env::consts::EXE_SUFFIX;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tar::Archive;
+use tokio::fs;
+
+static CRATES_IO_API: &str = "https://crates.io/api/v1/crates";
+
+/// File in the workspace's `CARGO_HOME` recording the versions of the prebuilt tools, as cargo
+/// doesn't know about them.
+static RECORD_FILE: &str = ".rustwide-prebuilt.toml";
+
+/// Location of the prebuilt release binaries of a tool.
+///
+/// The URL can contain the `{version}`, `{target}` and `{exe}` placeholders, and it can point
+/// either to the binary itself or to a `.tar.gz` archive containing it. A file with the same URL
+/// plus `.sha256` must contain the SHA-256 checksum of the download.
+#[derive(Clone)]
+pub(crate) struct Prebuilt {
+    pub(crate) url: Cow<'static, str>,
+}
+
+impl Prebuilt {
+    fn url(&self, version: &str) -> String {
+        self.url
+            .replace("{version}", version)
+            .replace("{target}", crate::HOST_TARGET)
+            .replace("{exe}", EXE_SUFFIX)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Record {
+    tools: BTreeMap<String, String>,
+}
+
+/// Download and install the prebuilt binary of a crate, verifying its checksum. Nothing is done
+/// if the requested version is already installed.
+pub(crate) async fn install(
+    workspace: &Workspace,
+    prebuilt: &Prebuilt,
+    crate_name: &str,
+    binary: &str,
+) -> Result<(), Error> {
+    let version = match workspace.pinned_tool_versions().get(crate_name) {
+        Some(version) => version.clone(),
+        None => latest_version(workspace, crate_name).await?,
+    };
+
+    let dest = workspace
+        .cargo_home()
+        .join("bin")
+        .join(format!("{}{}", binary, EXE_SUFFIX));
+    if dest.is_file() && installed(workspace)?.get(crate_name) == Some(&version) {
+        info!("prebuilt {} {} is already installed", crate_name, version);
+        return Ok(());
+    }
+
+    let url = prebuilt.url(&version);
+    info!(
+        "downloading prebuilt {} {} from {}",
+        crate_name, version, url
+    );
+    let content = download_bytes(workspace, &url).await?;
+    let checksum = download_bytes(workspace, &format!("{}.sha256", url)).await?;
+    verify_checksum(&content, &String::from_utf8_lossy(&checksum))
+        .with_context(|_| format!("failed to verify the download of {}", url))?;
+
+    let content = if url.ends_with(".tar.gz") || url.ends_with(".tgz") {
+        extract_binary(&content, &format!("{}{}", binary, EXE_SUFFIX))?
+    } else {
+        content
+    };
+
+    write_binary(&dest, &content).await?;
+    record(workspace, crate_name, &version)?;
+    Ok(())
+}
+
+/// Write an executable to its destination.
+pub(super) async fn write_binary(dest: &Path, content: &[u8]) -> Result<(), Error> {
+    // The binary is written to a temporary file first, to avoid leaving a broken binary behind
+    // if the write fails.
+    fs::create_dir_all(dest.parent().unwrap()).await?;
+    let temp = crate::utils::temp_path(dest);
+    fs::write(&temp, content).await?;
+    crate::native::make_executable(&temp)?;
+    fs::rename(&temp, dest).await?;
+    Ok(())
+}
+
+/// Return the versions of the prebuilt tools installed in the workspace.
+pub(crate) fn installed(workspace: &Workspace) -> Result<BTreeMap<String, String>, Error> {
+    let path = record_path(workspace);
+    if !path.is_file() {
+        return Ok(BTreeMap::new());
+    }
+    let record: Record = toml::from_str(&std::fs::read_to_string(path)?)?;
+    Ok(record.tools)
+}
+
+pub(super) fn record(workspace: &Workspace, crate_name: &str, version: &str) -> Result<(), Error> {
+    let mut tools = installed(workspace)?;
+    tools.insert(crate_name.into(), version.into());
+    crate::utils::write_atomic(
+        &record_path(workspace),
+        toml::to_string(&Record { tools })?.as_bytes(),
+    )?;
+    Ok(())
+}
+
+fn record_path(workspace: &Workspace) -> PathBuf {
+    workspace.cargo_home().join(RECORD_FILE)
+}
+
+async fn latest_version(workspace: &Workspace, crate_name: &str) -> Result<String, Error> {
+    #[derive(Deserialize)]
+    struct Response {
+        #[serde(rename = "crate")]
+        krate: CrateInfo,
+    }
+
+    #[derive(Deserialize)]
+    struct CrateInfo {
+        max_stable_version: Option<String>,
+        max_version: String,
+    }
+
+    let url = format!("{}/{}", CRATES_IO_API, crate_name);
+    let response: Response = serde_json::from_slice(&download_bytes(workspace, &url).await?)?;
+    Ok(response
+        .krate
+        .max_stable_version
+        .unwrap_or(response.krate.max_version))
+}
+
+/// Check the content against a checksum file, in the `sha256sum` output format.
+pub(super) fn verify_checksum(content: &[u8], checksum_file: &str) -> Result<(), Error> {
+    let expected = match checksum_file.split_whitespace().next() {
+        Some(expected) => expected.to_lowercase(),
+        None => failure::bail!("the checksum file is empty"),
+    };
+    let actual = format!("{:x}", Sha256::digest(content));
+    if actual != expected {
+        failure::bail!("expected checksum {}, found {}", expected, actual);
+    }
+    Ok(())
+}
+
+pub(super) fn extract_binary(archive: &[u8], binary: &str) -> Result<Vec<u8>, Error> {
+    let mut archive = Archive::new(GzDecoder::new(archive));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.file_name() == Some(Path::new(binary).as_os_str()) {
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            return Ok(content);
+        }
+    }
+    failure::bail!("binary {} is missing from the archive", binary);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Prebuilt;
+    use std::borrow::Cow;
+
+    #[test]
+    fn test_url() {
+        let prebuilt = Prebuilt {
+            url: Cow::Borrowed("https://example.com/v{version}/foo-{target}{exe}"),
+        };
+        assert_eq!(
+            prebuilt.url("1.0.0"),
+            format!(
+                "https://example.com/v1.0.0/foo-{}{}",
+                crate::HOST_TARGET,
+                std::env::consts::EXE_SUFFIX
+            )
+        );
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        // SHA-256 of "hello"
+        let checksum = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        assert!(super::verify_checksum(b"hello", checksum).is_ok());
+        assert!(super::verify_checksum(b"hello", &format!("{}  hello\n", checksum)).is_ok());
+        assert!(super::verify_checksum(b"hello", &checksum.to_uppercase()).is_ok());
+        assert!(super::verify_checksum(b"world", checksum).is_err());
+        assert!(super::verify_checksum(b"hello", "").is_err());
+    }
+}
@@ -1,18 +1,66 @@
 use crate::cmd::{Binary, Command, Runnable};
 use crate::toolchain::MAIN_TOOLCHAIN_NAME;
-use crate::tools::{Tool, RUSTUP};
+use crate::tools::{RustupProgress, Tool, RUSTUP};
 use crate::workspace::Workspace;
 use async_trait::async_trait;
 use failure::{Error, ResultExt};
+use log::info;
 use std::env::consts::EXE_SUFFIX;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use tempfile::tempdir;
-use tokio::{
-    fs::{self, File},
-    io,
-};
+use tokio::fs;
 
 static RUSTUP_BASE_URL: &str = "https://static.rust-lang.org/rustup/dist";
 
+/// Proxies installed by rustup, linked into the workspace when using the system rustup.
+static RUSTUP_PROXIES: &[&str] = &[
+    "rustup",
+    "cargo",
+    "rustc",
+    "rustdoc",
+    "rust-gdb",
+    "rust-lldb",
+    "rustfmt",
+    "cargo-fmt",
+    "cargo-clippy",
+    "clippy-driver",
+    "rls",
+];
+
+/// Name of the file, inside `RUSTUP_HOME`, whose modification time records the last time rustup
+/// updated itself.
+static SELF_UPDATE_STAMP: &str = "rustwide-self-update";
+
+/// Time between two self updates of rustup with the
+/// [`Weekly`](enum.RustupSelfUpdate.html#variant.Weekly) policy.
+const WEEK: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Policy controlling when rustup updates itself, configured with
+/// [`WorkspaceBuilder::rustup_self_update`](struct.WorkspaceBuilder.html#method.rustup_self_update).
+///
+/// The policy doesn't affect the update of the main toolchain, and it's ignored when using the
+/// system rustup, as that's never updated by rustwide.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RustupSelfUpdate {
+    /// Never run `rustup self update`, keeping the version installed when the workspace was
+    /// created.
+    Never,
+    /// Run `rustup self update` every time the workspace is initialized. This is the default.
+    OnInit,
+    /// Run `rustup self update` when the workspace is initialized, but only if rustup wasn't
+    /// updated in the last seven days.
+    Weekly,
+    #[doc(hidden)]
+    __NonExaustive,
+}
+
+impl Default for RustupSelfUpdate {
+    fn default() -> Self {
+        RustupSelfUpdate::OnInit
+    }
+}
+
 pub(crate) struct Rustup;
 
 impl Runnable for Rustup {
@@ -37,6 +85,23 @@ impl Tool for Rustup {
     }
 
     async fn install(&self, workspace: &Workspace, _fast_install: bool) -> Result<(), Error> {
+        if let Some(system) = workspace.system_rustup() {
+            link_system_rustup(workspace, system).await?;
+            fs::create_dir_all(workspace.rustup_home()).await?;
+
+            let mut progress = RustupProgress::new();
+            Command::new(workspace, &RUSTUP)
+                .args(&["toolchain", "install", MAIN_TOOLCHAIN_NAME, "--profile"])
+                .args(&[workspace.rustup_profile()])
+                .process_lines(&mut |line, actions| progress.process_line(line, actions))
+                .run()
+                .await
+                .with_context(|_| {
+                    format!("unable to install main toolchain {}", MAIN_TOOLCHAIN_NAME)
+                })?;
+            return Ok(());
+        }
+
         fs::create_dir_all(workspace.cargo_home()).await?;
         fs::create_dir_all(workspace.rustup_home()).await?;
 
@@ -46,21 +111,20 @@ impl Tool for Rustup {
             crate::HOST_TARGET,
             EXE_SUFFIX
         );
-        let mut resp = workspace
-            .http_client()
-            .get(&url)
-            .send()
-            .await?
-            .error_for_status()?;
+        // The installer is cached in the workspace, and it's downloaded again only if it changed.
+        let cached = workspace
+            .cache_dir()
+            .join("rustup-init")
+            .join(crate::HOST_TARGET)
+            .join(format!("rustup-init{}", EXE_SUFFIX));
+        crate::download::download(workspace, &url, &cached).await?;
 
         let tempdir = tempdir()?;
         let installer = &tempdir.path().join(format!("rustup-init{}", EXE_SUFFIX));
-        {
-            let mut file = File::create(installer).await?;
-            io::copy(&mut resp, &mut file).await?;
-            crate::native::make_executable(installer)?;
-        }
+        fs::copy(&cached, installer).await?;
+        crate::native::make_executable(installer)?;
 
+        let mut progress = RustupProgress::new();
         Command::new(workspace, installer.to_string_lossy().as_ref())
             .args(&[
                 "-y",
@@ -72,6 +136,7 @@ impl Tool for Rustup {
             ])
             .env("RUSTUP_HOME", workspace.rustup_home())
             .env("CARGO_HOME", workspace.cargo_home())
+            .process_lines(&mut |line, actions| progress.process_line(line, actions))
             .run()
             .await
             .with_context(|_| "unable to install rustup")?;
@@ -80,14 +145,27 @@ impl Tool for Rustup {
     }
 
     async fn update(&self, workspace: &Workspace, _fast_install: bool) -> Result<(), Error> {
-        Command::new(workspace, &RUSTUP)
-            .args(&["self", "update"])
-            .run()
-            .await
-            .with_context(|_| "failed to update rustup")?;
+        // The system rustup is not managed by rustwide, so it's never updated. The links are
+        // refreshed though, as the system rustup might have been updated in the meantime.
+        if let Some(system) = workspace.system_rustup() {
+            link_system_rustup(workspace, system).await?;
+        } else if should_self_update(workspace).await? {
+            let mut progress = RustupProgress::new();
+            Command::new(workspace, &RUSTUP)
+                .args(&["self", "update"])
+                .process_lines(&mut |line, actions| progress.process_line(line, actions))
+                .run()
+                .await
+                .with_context(|_| "failed to update rustup")?;
+            fs::write(workspace.rustup_home().join(SELF_UPDATE_STAMP), b"").await?;
+        } else {
+            info!("skipping the self update of rustup");
+        }
 
+        let mut progress = RustupProgress::new();
         Command::new(workspace, &RUSTUP)
             .args(&["update", MAIN_TOOLCHAIN_NAME])
+            .process_lines(&mut |line, actions| progress.process_line(line, actions))
             .run()
             .await
             .with_context(|_| format!("failed to update main toolchain {}", MAIN_TOOLCHAIN_NAME))?;
@@ -95,3 +173,76 @@ impl Tool for Rustup {
         Ok(())
     }
 }
+
+/// Check whether rustup should update itself, according to the policy of the workspace.
+async fn should_self_update(workspace: &Workspace) -> Result<bool, Error> {
+    Ok(match workspace.rustup_self_update() {
+        RustupSelfUpdate::Never => false,
+        RustupSelfUpdate::OnInit => true,
+        RustupSelfUpdate::Weekly => {
+            let stamp = workspace.rustup_home().join(SELF_UPDATE_STAMP);
+            match fs::metadata(&stamp).await {
+                Ok(metadata) => is_stale(metadata.modified()?, SystemTime::now()),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => true,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        RustupSelfUpdate::__NonExaustive => {
+            panic!("do not create __NonExaustive variants manually")
+        }
+    })
+}
+
+/// Check whether a week passed since the last self update. Updates in the future (for example
+/// after the clock was adjusted) are treated as stale, to avoid never updating again.
+fn is_stale(last_update: SystemTime, now: SystemTime) -> bool {
+    match now.duration_since(last_update) {
+        Ok(elapsed) => elapsed >= WEEK,
+        Err(_) => true,
+    }
+}
+
+fn system_rustup_binary(system: &Path) -> PathBuf {
+    system.join("bin").join(format!("rustup{}", EXE_SUFFIX))
+}
+
+/// Make the system rustup and its proxies available in the workspace's `CARGO_HOME`, so that they
+/// can also be mounted inside the sandbox. Hard links are used when possible, falling back to
+/// copying the binary.
+async fn link_system_rustup(workspace: &Workspace, system: &Path) -> Result<(), Error> {
+    let rustup = system_rustup_binary(system);
+    if !rustup.is_file() {
+        failure::bail!("the system rustup is missing at {}", rustup.display());
+    }
+
+    let bin = workspace.cargo_home().join("bin");
+    fs::create_dir_all(&bin).await?;
+
+    info!("linking the system rustup from {}", rustup.display());
+    for proxy in RUSTUP_PROXIES {
+        let dest = bin.join(format!("{}{}", proxy, EXE_SUFFIX));
+        if dest.exists() {
+            fs::remove_file(&dest).await?;
+        }
+        if fs::hard_link(&rustup, &dest).await.is_err() {
+            fs::copy(&rustup, &dest).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_stale, WEEK};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_is_stale() {
+        let now = SystemTime::now();
+        assert!(!is_stale(now, now));
+        assert!(!is_stale(now - Duration::from_secs(60 * 60), now));
+        assert!(is_stale(now - WEEK, now));
+        assert!(is_stale(now + Duration::from_secs(60), now));
+    }
+}
@@ -0,0 +1,231 @@
+use crate::cmd::ProcessLinesActions;
+use log::debug;
+
+/// Event parsed from the output of rustup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum RustupEvent {
+    /// A message printed by rustup, like `info: downloading component 'rustc'`.
+    Message(String),
+    /// Progress of the download or installation of a component.
+    Progress {
+        /// Component being downloaded or installed, if rustup reported it.
+        component: Option<String>,
+        /// Completion percentage, from 0 to 100.
+        percent: u8,
+        /// Total size of the component, as formatted by rustup (for example `60.5 MiB`).
+        total: String,
+    },
+}
+
+/// Parser of the output of rustup, collapsing its progress bars into a single summary line for
+/// each component.
+///
+/// Rustup redraws its progress bars with carriage returns, so a whole download ends up in a
+/// single line with hundreds of updates in the logs. The parser replaces them with a summary like
+/// `downloading component 'rustc': 100% of 60.5 MiB`, emitted when the progress bar completes or
+/// when rustup prints the next message.
+pub(crate) struct RustupProgress {
+    /// What rustup is currently doing, as reported by its last `info:` message.
+    activity: Option<String>,
+    component: Option<String>,
+    pending: Option<(u8, String)>,
+}
+
+impl RustupProgress {
+    pub(crate) fn new() -> Self {
+        RustupProgress {
+            activity: None,
+            component: None,
+            pending: None,
+        }
+    }
+
+    /// Parse a line of rustup's output into events. Progress updates in the same line are
+    /// reported as separate events.
+    pub(crate) fn parse_line(&mut self, line: &str) -> Vec<RustupEvent> {
+        let mut events = Vec::new();
+        for segment in line.split('\r') {
+            if segment.trim().is_empty() {
+                continue;
+            }
+            if let Some((percent, total)) = parse_progress_bar(segment) {
+                events.push(RustupEvent::Progress {
+                    component: self.component.clone(),
+                    percent,
+                    total,
+                });
+            } else {
+                let message = segment.trim_end();
+                if let Some(activity) = message.strip_prefix("info: ") {
+                    self.component = parse_component(activity);
+                    self.activity = Some(activity.to_string());
+                }
+                events.push(RustupEvent::Message(message.to_string()));
+            }
+        }
+        events
+    }
+
+    /// Process a line of rustup's output with
+    /// [`Command::process_lines`](../cmd/struct.Command.html#method.process_lines), replacing
+    /// the progress bars with their summary.
+    pub(crate) fn process_line(&mut self, line: &str, actions: &mut ProcessLinesActions) {
+        if let Some(lines) = self.summarize_line(line) {
+            actions.replace_with_lines(lines.iter().map(|line| &**line));
+        }
+    }
+
+    /// Return the lines to log in place of the provided one, or `None` if it should be logged
+    /// as is.
+    fn summarize_line(&mut self, line: &str) -> Option<Vec<String>> {
+        if line.trim().is_empty() {
+            return None;
+        }
+
+        let mut lines = Vec::new();
+        for event in self.parse_line(line) {
+            match event {
+                RustupEvent::Message(message) => {
+                    lines.extend(self.take_summary());
+                    lines.push(message);
+                }
+                RustupEvent::Progress {
+                    component,
+                    percent,
+                    total,
+                } => {
+                    debug!(
+                        "rustup progress of {}: {}%",
+                        component.as_deref().unwrap_or("unknown component"),
+                        percent
+                    );
+                    self.pending = Some((percent, total));
+                }
+            }
+        }
+        // Completed progress bars are summarized right away, as rustup won't update them again.
+        if let Some((100, _)) = self.pending {
+            lines.extend(self.take_summary());
+        }
+
+        if lines.len() == 1 && lines[0] == line {
+            None
+        } else {
+            Some(lines)
+        }
+    }
+
+    fn take_summary(&mut self) -> Option<String> {
+        let (percent, total) = self.pending.take()?;
+        Some(format!(
+            "{}: {}% of {}",
+            self.activity.as_deref().unwrap_or("progress"),
+            percent,
+            total
+        ))
+    }
+}
+
+/// Parse a progress bar drawn by rustup, like `13.3 MiB /  60.5 MiB ( 22 %)   3.9 MiB/s in  3s
+/// ETA: 12s`, returning its percentage and total size.
+fn parse_progress_bar(segment: &str) -> Option<(u8, String)> {
+    let open = segment.find('(')?;
+    let close = open + segment[open..].find("%)")?;
+    let percent = segment[open + 1..close].trim().parse().ok()?;
+    let mut sizes = segment[..open].split(" / ");
+    let _downloaded = sizes.next()?;
+    let total = sizes.next()?.trim();
+    if total.is_empty() || sizes.next().is_some() {
+        return None;
+    }
+    Some((percent, total.to_string()))
+}
+
+/// Extract the component name from messages like `downloading component 'rustc'`.
+fn parse_component(activity: &str) -> Option<String> {
+    let rest = &activity[activity.find("component '")? + "component '".len()..];
+    Some(rest[..rest.find('\'')?].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RustupEvent, RustupProgress};
+
+    #[test]
+    fn test_summarize_line() {
+        let mut progress = RustupProgress::new();
+        assert_eq!(
+            progress.summarize_line("info: downloading component 'rustc'"),
+            None
+        );
+        assert_eq!(
+            progress.summarize_line(
+                " 13.3 MiB /  60.5 MiB ( 22 %)   3.9 MiB/s in  3s ETA: 12s\r \
+                 60.5 MiB /  60.5 MiB (100 %)  15.0 MiB/s in  4s ETA:  0s\r"
+            ),
+            Some(vec![
+                "downloading component 'rustc': 100% of 60.5 MiB".into()
+            ])
+        );
+
+        // Incomplete progress bars are summarized when the next message is printed.
+        assert_eq!(
+            progress.summarize_line("info: installing component 'rustc'"),
+            None
+        );
+        assert_eq!(
+            progress.summarize_line(" 20.0 MiB /  60.5 MiB ( 33 %)  10.0 MiB/s in  2s ETA:  4s"),
+            Some(vec![])
+        );
+        assert_eq!(
+            progress.summarize_line("error: failed to install component 'rustc'"),
+            Some(vec![
+                "installing component 'rustc': 33% of 60.5 MiB".into(),
+                "error: failed to install component 'rustc'".into(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let mut progress = RustupProgress::new();
+        assert_eq!(
+            progress.parse_line("info: downloading component 'rustc'"),
+            vec![RustupEvent::Message(
+                "info: downloading component 'rustc'".into()
+            )]
+        );
+        assert_eq!(
+            progress.parse_line(
+                " 13.3 MiB /  60.5 MiB ( 22 %)   3.9 MiB/s in  3s ETA: 12s\r \
+                 60.5 MiB /  60.5 MiB (100 %)  15.0 MiB/s in  4s ETA:  0s\r"
+            ),
+            vec![
+                RustupEvent::Progress {
+                    component: Some("rustc".into()),
+                    percent: 22,
+                    total: "60.5 MiB".into(),
+                },
+                RustupEvent::Progress {
+                    component: Some("rustc".into()),
+                    percent: 100,
+                    total: "60.5 MiB".into(),
+                },
+            ]
+        );
+        assert_eq!(
+            progress.parse_line("info: syncing channel updates for 'stable'"),
+            vec![RustupEvent::Message(
+                "info: syncing channel updates for 'stable'".into()
+            )]
+        );
+        assert_eq!(
+            progress.parse_line("  1.2 KiB /   4.0 KiB ( 30 %)"),
+            vec![RustupEvent::Progress {
+                component: None,
+                percent: 30,
+                total: "4.0 KiB".into(),
+            }]
+        );
+    }
+}
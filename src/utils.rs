@@ -1,22 +1,65 @@
-use failure::Error;
+use crate::cmd::CommandError;
+use crate::prepare::PrepareError;
+use failure::{Context, Error, Fail};
 use fs2::FileExt;
 use futures_util::future::FutureExt;
 use log::warn;
+use sha2::{Digest, Sha256};
 use std::{
-    fs::OpenOptions,
+    ffi::OsStr,
+    fs::{File, OpenOptions},
     future::Future,
+    io::{Read, Write},
     panic,
     path::{Component, Path, PathBuf, Prefix, PrefixComponent},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime},
 };
-use tokio::task;
+use tokio::runtime::Handle;
+use tokio::task::{self, JoinHandle};
+
+/// Spawn a future on the provided runtime, or on the current one if no runtime is provided.
+pub(crate) fn spawn<F>(runtime: Option<&Handle>, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match runtime {
+        Some(runtime) => runtime.spawn(future),
+        None => tokio::spawn(future),
+    }
+}
+
+/// Run a blocking closure on the provided runtime, or on the current one if no runtime is
+/// provided.
+pub(crate) fn spawn_blocking<F, R>(runtime: Option<&Handle>, f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    match runtime {
+        Some(runtime) => runtime.spawn_blocking(f),
+        None => task::spawn_blocking(f),
+    }
+}
+
+/// Call the closure in the context of the provided runtime, so that the timers and the processes
+/// it creates are driven by that runtime.
+pub(crate) fn enter<R>(runtime: Option<&Handle>, f: impl FnOnce() -> R) -> R {
+    match runtime {
+        Some(runtime) => runtime.enter(f),
+        None => f(),
+    }
+}
 
 pub(crate) async fn file_lock<T>(
+    runtime: Option<&Handle>,
     path: &Path,
     msg: &str,
     f: impl Future<Output = Result<T, Error>> + panic::UnwindSafe + Send + 'static,
 ) -> Result<T, Error> {
     let (path, msg) = (path.to_owned(), msg.to_owned());
-    let file = task::spawn_blocking(move || {
+    let file = spawn_blocking(runtime, move || {
         let file = OpenOptions::new()
             .read(true)
             .write(true)
@@ -33,12 +76,20 @@ pub(crate) async fn file_lock<T>(
             file.lock_exclusive()?;
         }
 
+        // Record who holds the lock, to detect stale locks later.
+        file.set_len(0)?;
+        write!(&file, "{}", std::process::id())?;
+
         Result::<_, Error>::Ok(file)
     })
     .await??;
 
     let res = f.catch_unwind().await;
-    let _ = task::spawn_blocking(move || file.unlock()).await?;
+    let _ = spawn_blocking(runtime, move || {
+        file.set_len(0)?;
+        file.unlock()
+    })
+    .await?;
 
     match res {
         Ok(res) => res,
@@ -46,6 +97,253 @@ pub(crate) async fn file_lock<T>(
     }
 }
 
+/// Return the total size of the files inside a directory.
+pub(crate) fn dir_size(dir: &Path) -> Result<u64, Error> {
+    let mut size = 0;
+    for entry in walkdir::WalkDir::new(dir) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Lock held on a file, released when dropped.
+pub(crate) struct FileLock(File);
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+/// Try to acquire an exclusive lock on the file at the provided path without blocking, creating
+/// the file if it doesn't exist. `None` is returned if another lock is held on the file.
+pub(crate) fn try_lock_file_exclusive(path: &Path) -> Result<Option<FileLock>, Error> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)?;
+    match file.try_lock_exclusive() {
+        Ok(()) => Ok(Some(FileLock(file))),
+        Err(err) if err.kind() == fs2::lock_contended_error().kind() => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Acquire a lock on the file at the provided path, creating it if it doesn't exist. Shared locks
+/// can be held by multiple processes at the same time, while an exclusive lock is only granted
+/// when no other lock is held.
+///
+/// Unlike [`file_lock`], the lock is held until the returned guard is dropped, so it can protect
+/// futures borrowing from their environment.
+pub(crate) async fn lock_file(
+    runtime: Option<&Handle>,
+    path: &Path,
+    shared: bool,
+    msg: &str,
+) -> Result<FileLock, Error> {
+    let (path, msg) = (path.to_owned(), msg.to_owned());
+    spawn_blocking(runtime, move || {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+
+        let try_lock = if shared {
+            FileExt::try_lock_shared
+        } else {
+            FileExt::try_lock_exclusive
+        };
+        if let Err(err) = try_lock(&file) {
+            if err.kind() != fs2::lock_contended_error().kind() {
+                return Err(err.into());
+            }
+            warn!("blocking on other processes finishing to {}", msg);
+            if shared {
+                file.lock_shared()?;
+            } else {
+                file.lock_exclusive()?;
+            }
+        }
+
+        Result::<_, Error>::Ok(FileLock(file))
+    })
+    .await?
+}
+
+/// Check whether the lock at the provided path is stale, cleaning it up or warning about it.
+///
+/// A lock is stale when the process holding it exited without releasing it (which the OS does
+/// automatically, but it leaves the holder recorded in the file), or when it has been held for
+/// longer than `max_age`.
+pub(crate) fn check_lock_staleness(path: &Path, max_age: Duration) -> Result<(), Error> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+    match file.try_lock_exclusive() {
+        Ok(()) => {
+            let mut holder = String::new();
+            file.read_to_string(&mut holder)?;
+            if !holder.is_empty() {
+                warn!(
+                    "cleaning up stale lock {} left by process {}",
+                    path.display(),
+                    holder
+                );
+                file.set_len(0)?;
+            }
+            file.unlock()?;
+        }
+        Err(err) if err.kind() == fs2::lock_contended_error().kind() => {
+            let age = lock_age(&file)?;
+            if age > max_age {
+                // Locks are mandatory on Windows, so reading the holder might fail there.
+                let mut holder = String::new();
+                let _ = file.read_to_string(&mut holder);
+                warn!(
+                    "lock {} has been held by process {} for {} seconds",
+                    path.display(),
+                    holder,
+                    age.as_secs()
+                );
+            }
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    Ok(())
+}
+
+/// Suffix of the temporary files and directories cache entries are written to, before being
+/// atomically renamed to their final location.
+const TEMP_SUFFIX: &str = ".rustwide-tmp";
+
+/// Return a temporary path next to `path`, unique to this call, that an entry is written to before
+/// being renamed to `path`.
+///
+/// The ID of the current process is part of the name, so that the entries left behind by crashed
+/// processes can be told apart from the ones other processes sharing the workspace are writing.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut name = path
+        .file_name()
+        .expect("temporary paths need a file name")
+        .to_os_string();
+    name.push(format!(
+        ".{}-{}{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst),
+        TEMP_SUFFIX
+    ));
+    path.with_file_name(name)
+}
+
+/// Return the temporary path of an entry written over multiple attempts, which can be resumed
+/// after the process writing it crashed (like a partial download). Unlike `temp_path`, the path
+/// is the same for every process, so writers must hold the lock of the entry.
+pub(crate) fn resumable_temp_path(path: &Path) -> PathBuf {
+    let mut name = path
+        .file_name()
+        .expect("temporary paths need a file name")
+        .to_os_string();
+    name.push(TEMP_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Check whether the file name is the one of a temporary entry returned by `temp_path` or
+/// `resumable_temp_path`.
+pub(crate) fn is_temp_entry(name: &OsStr) -> bool {
+    name.to_string_lossy().ends_with(TEMP_SUFFIX)
+}
+
+/// Return the ID of the process that created a temporary entry returned by `temp_path`.
+fn temp_entry_creator(name: &OsStr) -> Option<u32> {
+    let name = name.to_str()?.strip_suffix(TEMP_SUFFIX)?;
+    let unique = name.rsplit('.').next()?;
+    let mut parts = unique.splitn(2, '-');
+    let pid = parts.next()?.parse().ok()?;
+    parts.next()?.parse::<usize>().ok()?;
+    Some(pid)
+}
+
+/// Write `content` to the file at `path`, replacing it atomically: readers either see the old
+/// content or the new one, even if the process crashes while writing.
+pub(crate) fn write_atomic(path: &Path, content: &[u8]) -> Result<(), Error> {
+    let temp = temp_path(path);
+    std::fs::write(&temp, content)?;
+    std::fs::rename(&temp, path)?;
+    Ok(())
+}
+
+/// Remove the temporary entries left up to `depth` levels inside `dir` by operations interrupted
+/// by a crash, returning how many were removed.
+///
+/// Entries returned by `temp_path` are removed once the process that created them exited, however
+/// long it's been writing them. Resumable entries are removed if they were not modified in the
+/// last `min_age`, as another process sharing the workspace might still be writing them.
+pub(crate) fn remove_stale_temp_entries(
+    dir: &Path,
+    depth: usize,
+    min_age: Duration,
+) -> Result<usize, Error> {
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+
+    let now = SystemTime::now();
+    let mut removed = 0;
+    let mut entries = walkdir::WalkDir::new(dir)
+        .min_depth(1)
+        .max_depth(depth)
+        .into_iter();
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+        if !is_temp_entry(entry.file_name()) {
+            continue;
+        }
+        if entry.file_type().is_dir() {
+            entries.skip_current_dir();
+        }
+        let stale = match temp_entry_creator(entry.file_name()) {
+            Some(pid) => pid != std::process::id() && !crate::native::process_exists(pid),
+            None => {
+                let age = now
+                    .duration_since(entry.metadata()?.modified()?)
+                    .unwrap_or_default();
+                age >= min_age
+            }
+        };
+        if !stale {
+            continue;
+        }
+
+        warn!(
+            "removing {} left behind by an interrupted operation",
+            entry.path().display()
+        );
+        if entry.file_type().is_dir() {
+            remove_dir_all::remove_dir_all(entry.path())?;
+        } else {
+            std::fs::remove_file(entry.path())?;
+        }
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+fn lock_age(file: &File) -> Result<Duration, Error> {
+    let modified = file.metadata()?.modified()?;
+    Ok(SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or_default())
+}
+
 /// If a prefix uses the extended-length syntax (`\\?\`), return the equivalent version without it.
 ///
 /// Returns `None` if `prefix.kind().is_verbatim()` is `false`.
@@ -55,7 +353,13 @@ fn strip_verbatim_from_prefix(prefix: &PrefixComponent<'_>) -> Option<PathBuf> {
 
         Prefix::VerbatimDisk(drive) => [format!(r"{}:\", drive as char)].iter().collect(),
 
-        Prefix::VerbatimUNC(_, _) => unimplemented!(),
+        Prefix::VerbatimUNC(server, share) => [format!(
+            r"\\{}\{}\",
+            server.to_string_lossy(),
+            share.to_string_lossy()
+        )]
+        .iter()
+        .collect(),
 
         _ => return None,
     };
@@ -63,6 +367,12 @@ fn strip_verbatim_from_prefix(prefix: &PrefixComponent<'_>) -> Option<PathBuf> {
     Some(ret)
 }
 
+/// A conservative estimate for the maximum length of a path on Windows.
+///
+/// The additional 12 byte restriction is applied when creating directories. It ensures that files
+/// can always be created inside that directory without exceeding the path limit.
+const MAX_PATH_LEN: usize = 260 - 12;
+
 pub(crate) fn normalize_path(path: &Path) -> PathBuf {
     let mut p = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
 
@@ -70,32 +380,281 @@ pub(crate) fn normalize_path(path: &Path) -> PathBuf {
     // many programs, including rustup. We strip the `\\?\` prefix of the canonicalized path, but
     // this changes the meaning of some path components, and imposes a length of around 260
     // characters.
+    //
+    // If the stripped path is too long the extended-length path is kept instead: the stripped one
+    // would fail anyway, while the extended-length one works with the programs supporting it.
     if cfg!(windows) {
-        // A conservative estimate for the maximum length of a path on Windows.
-        //
-        // The additional 12 byte restriction is applied when creating directories. It ensures that
-        // files can always be created inside that directory without exceeding the path limit.
-        const MAX_PATH_LEN: usize = 260 - 12;
-
         let mut components = p.components();
         let first_component = components.next().unwrap();
 
         if let Component::Prefix(prefix) = first_component {
             if let Some(mut modified_path) = strip_verbatim_from_prefix(&prefix) {
                 modified_path.push(components.as_path());
-                p = modified_path;
+                if modified_path.as_os_str().len() < MAX_PATH_LEN {
+                    p = modified_path;
+                } else {
+                    warn!(
+                        "canonicalized path is too long for Windows, keeping it extended-length: {:?}",
+                        p.as_os_str(),
+                    );
+                }
             }
         }
+    }
+
+    p
+}
 
-        if p.as_os_str().len() >= MAX_PATH_LEN {
-            warn!(
-                "Canonicalized path is too long for Windows: {:?}",
-                p.as_os_str(),
-            );
+/// Convert an absolute path to an extended-length path (prefixed with `\\?\`) on Windows,
+/// lifting the limit on the path length. On other platforms the path is returned unchanged.
+///
+/// Extended-length paths are supported by the standard library, so this should be used for the
+/// filesystem operations rustwide does itself. Paths passed to external programs should be
+/// normalized with `normalize_path` instead, as many of them don't support extended-length paths.
+pub(crate) fn long_path(path: &Path) -> PathBuf {
+    if !cfg!(windows) {
+        return path.to_path_buf();
+    }
+
+    let mut components = path.components();
+    let mut long = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(drive) => PathBuf::from(format!(r"\\?\{}:\", drive as char)),
+            Prefix::UNC(server, share) => PathBuf::from(format!(
+                r"\\?\UNC\{}\{}\",
+                server.to_string_lossy(),
+                share.to_string_lossy()
+            )),
+            // Already an extended-length path or a device path.
+            _ => return path.to_path_buf(),
+        },
+        // Relative paths can't be converted.
+        _ => return path.to_path_buf(),
+    };
+
+    // Extended-length paths are passed to the system as is, so `.` and `..` need to be resolved
+    // beforehand.
+    for component in components {
+        match component {
+            Component::Normal(part) => long.push(part),
+            Component::ParentDir => {
+                long.pop();
+            }
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
         }
     }
+    long
+}
 
-    p
+/// Size of the blocks processed by SHA-256, used to derive the HMAC keys.
+const SHA256_BLOCK_SIZE: usize = 64;
+
+/// Compute the HMAC-SHA256 of a message, as described in RFC 2104.
+pub(crate) fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut block = [0; SHA256_BLOCK_SIZE];
+    if key.len() > SHA256_BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(block.iter().map(|b| b ^ 0x36).collect::<Vec<_>>());
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.iter().map(|b| b ^ 0x5c).collect::<Vec<_>>());
+    outer.update(inner.finalize());
+    outer.finalize().to_vec()
+}
+
+/// Copy an error, to report it to multiple callers. Errors can't be cloned, so only the
+/// [`PrepareError`](../prepare/enum.PrepareError.html)s and
+/// [`CommandError`](../cmd/enum.CommandError.html)s callers can match on are kept, with the
+/// message of the rest of the chain.
+pub(crate) fn clone_error(err: &Error) -> Error {
+    fn clone_as<T: Fail + Clone>(err: &Error) -> Option<Error> {
+        if let Some(fail) = err.downcast_ref::<T>() {
+            return Some(fail.clone().into());
+        }
+        if let Some(context) = err.downcast_ref::<Context<T>>() {
+            return Some(Context::new(context.get_context().clone()).into());
+        }
+        err.iter_causes()
+            .find_map(|cause| cause.downcast_ref::<T>())
+            .map(|cause| Error::from(cause.clone()).context(err.to_string()).into())
+    }
+
+    clone_as::<PrepareError>(err)
+        .or_else(|| clone_as::<CommandError>(err))
+        .unwrap_or_else(|| failure::err_msg(err.to_string()))
+}
+
+/// Encode bytes as a lowercase hexadecimal string.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clone_error, hex, hmac_sha256};
+    use crate::cmd::CommandError;
+    use crate::prepare::PrepareError;
+    use failure::{Error, ResultExt};
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn test_hmac_sha256() {
+        // Test cases 2 and 6 from RFC 4231.
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_lock_holder() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("lock");
+
+        let inner = path.clone();
+        super::file_lock(None, &path, "test", async move {
+            let holder = std::fs::read_to_string(&inner)?;
+            assert_eq!(holder, std::process::id().to_string());
+            Ok(())
+        })
+        .await?;
+        assert_eq!(std::fs::read_to_string(&path)?, "");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lock_file() -> Result<(), Error> {
+        use fs2::FileExt;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("lock");
+        let other = std::fs::File::create(&path)?;
+
+        let first = super::lock_file(None, &path, true, "test").await?;
+        let second = super::lock_file(None, &path, true, "test").await?;
+        assert!(other.try_lock_exclusive().is_err());
+        drop((first, second));
+
+        let exclusive = super::lock_file(None, &path, false, "test").await?;
+        assert!(other.try_lock_shared().is_err());
+        drop(exclusive);
+        other.try_lock_exclusive()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_stale_temp_entries() -> Result<(), Error> {
+        // Process IDs are never this high, so no process with this ID is running.
+        const EXITED: u32 = 99_999_999;
+        let exited = |path: &Path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            path.with_file_name(format!("{}.{}-0{}", name, EXITED, super::TEMP_SUFFIX))
+        };
+
+        let dir = tempfile::tempdir()?;
+        let crates = dir.path().join("cratesio-sources").join("foo");
+        std::fs::create_dir_all(&crates)?;
+
+        let krate = crates.join("foo-1.0.0.crate");
+        super::write_atomic(&krate, b"foo")?;
+        assert_eq!(std::fs::read(&krate)?, b"foo");
+        assert_eq!(std::fs::read_dir(&crates)?.count(), 1);
+
+        // A download that can be resumed, a clone interrupted by a crash and one in progress.
+        let partial = super::resumable_temp_path(&crates.join("foo-2.0.0.crate"));
+        std::fs::write(&partial, "partial")?;
+        let crashed = exited(&dir.path().join("git-repos").join("repo"));
+        std::fs::create_dir_all(crashed.join("objects"))?;
+        let running = super::temp_path(&dir.path().join("git-repos").join("other"));
+        std::fs::create_dir_all(running.join("objects"))?;
+        assert_ne!(
+            running,
+            super::temp_path(&dir.path().join("git-repos").join("other"))
+        );
+
+        let min_age = Duration::from_secs(60 * 60);
+        assert_eq!(super::remove_stale_temp_entries(dir.path(), 3, min_age)?, 1);
+        assert!(!crashed.exists());
+        assert!(partial.exists());
+        assert_eq!(
+            super::remove_stale_temp_entries(dir.path(), 3, Duration::from_secs(0))?,
+            1
+        );
+        assert!(krate.exists());
+        assert_eq!(std::fs::read_dir(&crates)?.count(), 1);
+        assert!(running.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clone_error() {
+        let err = Error::from(PrepareError::MissingCargoToml);
+        assert!(matches!(
+            clone_error(&err).downcast_ref(),
+            Some(PrepareError::MissingCargoToml)
+        ));
+
+        let err = Error::from(CommandError::Timeout(10));
+        assert!(matches!(
+            clone_error(&err).downcast_ref(),
+            Some(CommandError::Timeout(10))
+        ));
+
+        let err: Error = Err::<(), _>(failure::err_msg("invalid TOML"))
+            .context(PrepareError::InvalidCargoTomlSyntax)
+            .unwrap_err()
+            .into();
+        let cloned = clone_error(&err);
+        assert_eq!(cloned.to_string(), "invalid Cargo.toml syntax");
+        assert!(matches!(
+            cloned
+                .downcast_ref::<failure::Context<PrepareError>>()
+                .map(|context| context.get_context()),
+            Some(PrepareError::InvalidCargoTomlSyntax)
+        ));
+
+        let err: Error = Err::<(), _>(PrepareError::MissingCargoToml)
+            .context("failed to fetch the crate")
+            .unwrap_err()
+            .into();
+        let cloned = clone_error(&err);
+        assert_eq!(cloned.to_string(), "failed to fetch the crate");
+        assert!(cloned
+            .iter_causes()
+            .any(|cause| matches!(cause.downcast_ref(), Some(PrepareError::MissingCargoToml))));
+
+        let err = failure::err_msg("network error");
+        assert_eq!(clone_error(&err).to_string(), "network error");
+    }
+
+    #[test]
+    fn test_stale_lock_cleanup() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("lock");
+
+        // A lock file with a holder but no actual lock was left behind by a dead process.
+        std::fs::write(&path, "1234")?;
+        super::check_lock_staleness(&path, Duration::from_secs(60))?;
+        assert_eq!(std::fs::read_to_string(&path)?, "");
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -111,6 +670,7 @@ mod windows_tests {
             (r"\Users\carl", None),
             (r"\\?\C:\Users\carl", Some(r"C:\")),
             (r"\\?\Users\carl", Some(r"Users")),
+            (r"\\?\UNC\server\share\carl", Some(r"\\server\share\")),
         ];
 
         for (input, output) in suite {
@@ -125,4 +685,19 @@ mod windows_tests {
             }
         }
     }
+
+    #[test]
+    fn long_path() {
+        let suite = vec![
+            (r"C:\Users\carl", r"\\?\C:\Users\carl"),
+            (r"C:\Users\carl\..\.\bob", r"\\?\C:\Users\bob"),
+            (r"\\server\share\carl", r"\\?\UNC\server\share\carl"),
+            (r"\\?\C:\Users\carl", r"\\?\C:\Users\carl"),
+            (r"Users\carl", r"Users\carl"),
+        ];
+
+        for (input, output) in suite {
+            assert_eq!(super::long_path(Path::new(input)), Path::new(output));
+        }
+    }
 }
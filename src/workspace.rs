@@ -1,15 +1,30 @@
+use crate::adjustments::{AdjustmentsPolicy, SandboxAdjustments};
+use crate::budget::RunBudget;
 use crate::build::BuildDirectory;
-use crate::cmd::{Command, SandboxImage};
+use crate::cache_manager::CacheManager;
+use crate::cache_report::CacheReport;
+use crate::cargo_config::CargoNetworkConfig;
+use crate::cmd::{Command, MountOwners, SandboxImage};
+use crate::crates::InFlightFetches;
+use crate::download::{DownloadProgress, ProgressObserver, RateLimiter};
+use crate::fair_share::CpuCoordinator;
 use crate::inside_docker::CurrentContainer;
-use crate::Toolchain;
+use crate::journal::{Journal, RecoveryReport};
+use crate::maintenance::MaintenanceHandle;
+#[cfg(feature = "mock")]
+use crate::mock::MockFixtures;
+use crate::shutdown::Shutdown;
+use crate::{Crate, GitCredentials, InstallableTool, RetryPolicy, RustupSelfUpdate, Toolchain};
 use failure::{Error, ResultExt};
-use futures_util::stream::TryStreamExt;
-use log::info;
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use log::{error, info, warn};
 use remove_dir_all::remove_dir_all;
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::fs;
+use tokio::runtime::Handle;
 
 #[cfg(windows)]
 static DEFAULT_SANDBOX_IMAGE: &str = "rustops/crates-build-env-windows";
@@ -22,38 +37,135 @@ const DEFAULT_COMMAND_NO_OUTPUT_TIMEOUT: Option<Duration> = None;
 
 static DEFAULT_RUSTUP_PROFILE: &str = "minimal";
 
+/// Temporary entries not modified for longer than this are considered left behind by a crash, and
+/// are removed. Younger ones might belong to other processes sharing the workspace.
+const STALE_TEMP_ENTRY_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// The crates.io crawling policy allows at most one request per second.
+const DEFAULT_CRATESIO_RATE_LIMIT: Option<Duration> = Some(Duration::from_secs(1));
+
 /// Builder of a [`Workspace`](struct.Workspace.html).
 pub struct WorkspaceBuilder {
     user_agent: String,
+    contact: Option<String>,
+    http_headers: Vec<(String, String)>,
+    cratesio_rate_limit: Option<Duration>,
     path: PathBuf,
     sandbox_image: Option<SandboxImage>,
     command_timeout: Option<Duration>,
     command_no_output_timeout: Option<Duration>,
+    command_low_priority: bool,
     fetch_registry_index_during_builds: bool,
     running_inside_docker: bool,
     fast_init: bool,
+    parallel_extraction: bool,
     rustup_profile: String,
+    rustup_self_update: RustupSelfUpdate,
+    system_rustup: Option<PathBuf>,
+    standalone_toolchains: bool,
+    nextest: bool,
+    semver_checks: bool,
+    git_lfs: bool,
+    tool_versions: HashMap<String, String>,
+    extra_tools: Vec<InstallableTool>,
+    git_credentials: GitCredentials,
+    registry_tokens: HashMap<String, String>,
+    cargo_network: CargoNetworkConfig,
+    download_progress: Option<ProgressObserver>,
+    retry_policy: RetryPolicy,
+    min_cache_free_space: Option<u64>,
+    max_cache_size: Option<u64>,
+    min_builds_free_space: Option<u64>,
+    run_budget: Option<RunBudget>,
+    sandbox_adjustments: Option<AdjustmentsPolicy>,
+    runtime: Option<Handle>,
 }
 
 impl WorkspaceBuilder {
     /// Create a new builder.
     ///
     /// The provided path will be the home of the workspace, containing all the data generated by
-    /// rustwide (including state and caches).
+    /// rustwide (including state and caches). The user agent identifies the product using
+    /// rustwide (for example `crater/0.1.0`), and it's sent along with rustwide's own version in
+    /// the HTTP requests made by the workspace.
     pub fn new(path: &Path, user_agent: &str) -> Self {
         Self {
             user_agent: user_agent.into(),
+            contact: None,
+            http_headers: Vec::new(),
+            cratesio_rate_limit: DEFAULT_CRATESIO_RATE_LIMIT,
             path: path.into(),
             sandbox_image: None,
             command_timeout: DEFAULT_COMMAND_TIMEOUT,
             command_no_output_timeout: DEFAULT_COMMAND_NO_OUTPUT_TIMEOUT,
+            command_low_priority: false,
             fetch_registry_index_during_builds: true,
             running_inside_docker: false,
             fast_init: false,
+            parallel_extraction: false,
             rustup_profile: DEFAULT_RUSTUP_PROFILE.into(),
+            rustup_self_update: RustupSelfUpdate::default(),
+            system_rustup: None,
+            standalone_toolchains: false,
+            nextest: false,
+            semver_checks: false,
+            git_lfs: false,
+            tool_versions: HashMap::new(),
+            extra_tools: Vec::new(),
+            git_credentials: GitCredentials::default(),
+            registry_tokens: HashMap::new(),
+            cargo_network: CargoNetworkConfig::default(),
+            download_progress: None,
+            retry_policy: RetryPolicy::default(),
+            min_cache_free_space: None,
+            max_cache_size: None,
+            min_builds_free_space: None,
+            run_budget: None,
+            sandbox_adjustments: None,
+            runtime: None,
         }
     }
 
+    /// Set the contact information (for example an email address or a URL) added to the user agent
+    /// of the HTTP requests made by rustwide.
+    ///
+    /// The [crates.io crawling policy](https://crates.io/policies#crawlers) requires tools
+    /// accessing crates.io to provide a way to contact their operators, and a warning is logged
+    /// when the workspace is initialized without it.
+    pub fn contact(mut self, contact: &str) -> Self {
+        self.contact = Some(contact.into());
+        self
+    }
+
+    /// Add headers to all the HTTP requests made by rustwide, including the requests to the
+    /// crates.io API and the downloads of crates, toolchains and tools. Some proxies and mirrors
+    /// require extra headers to authenticate or route the requests.
+    ///
+    /// A `User-Agent` header replaces the user agent built from the
+    /// [`new`](#method.new) and [`contact`](#method.contact) methods. The headers are not sent
+    /// by cargo and rustup when they access the network on their own. Invalid header names or
+    /// values are reported when the workspace is initialized.
+    pub fn http_headers(mut self, headers: &[(&str, &str)]) -> Self {
+        self.http_headers.extend(
+            headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+        );
+        self
+    }
+
+    /// Set the minimum interval between two requests to the crates.io API made by the workspace,
+    /// or disable the rate limit with `None`. Downloads from static.crates.io and requests to the
+    /// sparse index on index.crates.io are not rate limited, as they're served by a CDN.
+    ///
+    /// By default at most one request per second is made, as required by the
+    /// [crates.io crawling policy](https://crates.io/policies#crawlers). The rate limit is shared
+    /// by all the operations of the workspace.
+    pub fn cratesio_rate_limit(mut self, interval: Option<Duration>) -> Self {
+        self.cratesio_rate_limit = interval;
+        self
+    }
+
     /// Override the image used for sandboxes.
     ///
     /// By default rustwide will use the [rustops/crates-build-env] image on Linux systems, and
@@ -84,6 +196,15 @@ impl WorkspaceBuilder {
         self
     }
 
+    /// Set whether [`Command`](cmd/struct.Command.html)s run with a low priority by default, which
+    /// can be overridden with the
+    /// [`Command::low_priority`](cmd/struct.Command.html#method.low_priority) method. By default
+    /// commands run with the normal priority.
+    pub fn command_low_priority(mut self, enable: bool) -> Self {
+        self.command_low_priority = enable;
+        self
+    }
+
     /// Enable or disable fast workspace initialization (disabled by default).
     ///
     /// Fast workspace initialization will change the initialization process to prefer
@@ -96,6 +217,17 @@ impl WorkspaceBuilder {
         self
     }
 
+    /// Enable or disable writing the files of extracted crates from multiple threads (disabled by
+    /// default).
+    ///
+    /// Parallel extraction can speed up preparing crates with lots of small files, especially on
+    /// filesystems with a high per-file overhead, at the cost of using more threads and memory
+    /// while extracting.
+    pub fn parallel_extraction(mut self, enable: bool) -> Self {
+        self.parallel_extraction = enable;
+        self
+    }
+
     /// Enable or disable fetching the registry's index during each build (enabled by default).
     ///
     /// When this option is disabled the index will only be fetched when the workspace is
@@ -135,6 +267,322 @@ impl WorkspaceBuilder {
         self
     }
 
+    /// Control when rustup updates itself while the workspace is initialized. The default is
+    /// [`RustupSelfUpdate::OnInit`](enum.RustupSelfUpdate.html#variant.OnInit), updating it
+    /// every time.
+    ///
+    /// Pinning rustup with [`RustupSelfUpdate::Never`](enum.RustupSelfUpdate.html#variant.Never)
+    /// avoids a network round-trip on every initialization, and keeps the rustup version
+    /// reproducible across runs.
+    pub fn rustup_self_update(mut self, policy: RustupSelfUpdate) -> Self {
+        self.rustup_self_update = policy;
+        self
+    }
+
+    /// Use an existing rustup installation instead of installing rustup inside the workspace.
+    ///
+    /// The provided path must be the `CARGO_HOME` of the installation, containing the
+    /// `bin/rustup` executable. Only the rustup binary is shared: it's linked into the workspace,
+    /// and it's never updated by rustwide. Toolchains are still installed in the workspace's own
+    /// `RUSTUP_HOME`, so installing, updating and uninstalling toolchains never changes the
+    /// toolchains of the existing installation.
+    pub fn use_system_rustup(mut self, path: &Path) -> Self {
+        self.system_rustup = Some(path.into());
+        self
+    }
+
+    /// Install dist toolchains by downloading the official tarballs from
+    /// [static.rust-lang.org][static-rlo] directly, instead of using rustup. This is useful on
+    /// platforms or configurations where rustup is unavailable. By default rustup is used.
+    ///
+    /// Rustup is not installed in the workspace when this is enabled: each toolchain is assembled
+    /// from the components of the [`rustup_profile`](#method.rustup_profile) in its own
+    /// directory, and `cargo` and `rustc` are executed from it directly. CI toolchains are
+    /// assembled from the `rustc`, `rust-std` and `cargo` artifacts of their commit. Updating a
+    /// toolchain removes the components and targets added to it later.
+    ///
+    /// [static-rlo]: https://static.rust-lang.org
+    pub fn standalone_toolchains(mut self, enable: bool) -> Self {
+        self.standalone_toolchains = enable;
+        self
+    }
+
+    /// Install [cargo-nextest][nextest] in the workspace, allowing to run the tests of crates
+    /// with a [`NextestRunner`](struct.NextestRunner.html). By default it's not installed.
+    ///
+    /// Once enabled, its version can be pinned with [`pin_tool_version`](#method.pin_tool_version)
+    /// like the other tools installed by rustwide, using the `cargo-nextest` crate name.
+    ///
+    /// [nextest]: https://nexte.st
+    pub fn nextest(mut self, enable: bool) -> Self {
+        self.nextest = enable;
+        self
+    }
+
+    /// Install [cargo-semver-checks][semver-checks] in the workspace, allowing to check the
+    /// semver compatibility of crates with
+    /// [`BuildDirectory::semver_check`](struct.BuildDirectory.html#method.semver_check). By
+    /// default it's not installed.
+    ///
+    /// Once enabled, its version can be pinned with [`pin_tool_version`](#method.pin_tool_version)
+    /// using the `cargo-semver-checks` crate name.
+    ///
+    /// [semver-checks]: https://github.com/obi1kenobi/cargo-semver-checks
+    pub fn semver_checks(mut self, enable: bool) -> Self {
+        self.semver_checks = enable;
+        self
+    }
+
+    /// Install [Git LFS][git-lfs] in the workspace, and download the files stored in it when
+    /// fetching git crates whose `.gitattributes` use it. By default it's not installed, and
+    /// those files are left as LFS pointer files. Only Linux hosts are supported.
+    ///
+    /// gitoxide, which rustwide uses to fetch git repositories, doesn't support Git LFS, so
+    /// `git-lfs` is run through `git`, which must be installed on the host. If it's missing, the
+    /// pointer files are kept and a warning is logged.
+    ///
+    /// Once enabled, its version can be pinned with [`pin_tool_version`](#method.pin_tool_version)
+    /// using the `git-lfs` name.
+    ///
+    /// [git-lfs]: https://git-lfs.com
+    pub fn git_lfs(mut self, enable: bool) -> Self {
+        self.git_lfs = enable;
+        self
+    }
+
+    /// Pin the version of a tool installed by rustwide with `cargo install`, instead of always
+    /// installing the latest version. This method can be called multiple times to pin different
+    /// tools.
+    ///
+    /// The tools that can be pinned are `rustup-toolchain-install-master`, `git-credential-null`,
+    /// `cargo-nextest` (when [enabled](#method.nextest)), `cargo-semver-checks` (when
+    /// [enabled](#method.semver_checks)), `git-lfs` (when [enabled](#method.git_lfs)) and the
+    /// ones registered with
+    /// [`install_tool`](#method.install_tool), identified by the name of their crate: pinning any
+    /// other tool will make initialization fail. The versions actually installed can be retrieved
+    /// with [`Workspace::installed_tools`](struct.Workspace.html#method.installed_tools).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::WorkspaceBuilder;
+    /// # use std::error::Error;
+    /// # async fn run() -> Result<(), Box<dyn Error>> {
+    /// let workspace = WorkspaceBuilder::new("work".as_ref(), "my-app")
+    ///     .pin_tool_version("rustup-toolchain-install-master", "1.7.3")
+    ///     .init()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn pin_tool_version(mut self, tool: &str, version: &str) -> Self {
+        self.tool_versions.insert(tool.into(), version.into());
+        self
+    }
+
+    /// Install an additional tool into the workspace during initialization, keeping it updated
+    /// alongside the tools rustwide needs. This method can be called multiple times to install
+    /// different tools. See [`InstallableTool`](struct.InstallableTool.html) for more
+    /// information.
+    pub fn install_tool(mut self, tool: InstallableTool) -> Self {
+        self.extra_tools.push(tool);
+        self
+    }
+
+    /// Set the credentials git uses when interacting with remote repositories. By default no
+    /// credentials are provided, and fetching private repositories fails. Git crates can override
+    /// them with [`Crate::git_credentials`](struct.Crate.html#method.git_credentials).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, GitCredentials};
+    /// # use std::error::Error;
+    /// # async fn run() -> Result<(), Box<dyn Error>> {
+    /// let workspace = WorkspaceBuilder::new("work".as_ref(), "my-app")
+    ///     .git_credentials(GitCredentials::Token {
+    ///         username: "my-bot".into(),
+    ///         token: std::env::var("GITHUB_TOKEN")?,
+    ///     })
+    ///     .init()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn git_credentials(mut self, credentials: GitCredentials) -> Self {
+        self.git_credentials = credentials;
+        self
+    }
+
+    /// Set the token sent in the `Authorization` header of the requests to the sparse index and
+    /// to the download endpoint of an alternate registry, identified by the URL of its index as
+    /// provided to [`Crate::registry`](struct.Crate.html#method.registry). Git indexes are
+    /// cloned with the [git credentials](#method.git_credentials) instead.
+    pub fn registry_token(mut self, index: &str, token: &str) -> Self {
+        self.registry_tokens.insert(index.into(), token.into());
+        self
+    }
+
+    /// Set the network settings of the cargo commands executed by rustwide, such as using the git
+    /// CLI to fetch git dependencies. See [`CargoNetworkConfig`](struct.CargoNetworkConfig.html)
+    /// for the available settings. By default cargo's defaults are used.
+    pub fn cargo_network(mut self, config: CargoNetworkConfig) -> Self {
+        self.cargo_network = config;
+        self
+    }
+
+    /// Call the provided function with the progress of the downloads rustwide makes, such as
+    /// crate tarballs, git repositories, the rustup installer and the prebuilt tools. The function
+    /// is called periodically while the download is in progress, and once more when it finishes.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::WorkspaceBuilder;
+    /// # use std::error::Error;
+    /// # async fn run() -> Result<(), Box<dyn Error>> {
+    /// let workspace = WorkspaceBuilder::new("work".as_ref(), "my-app")
+    ///     .download_progress(|progress| {
+    ///         if let Some(total) = progress.total() {
+    ///             println!(
+    ///                 "{}: {}/{} bytes ({:.0} B/s)",
+    ///                 progress.url(),
+    ///                 progress.downloaded(),
+    ///                 total,
+    ///                 progress.bytes_per_second(),
+    ///             );
+    ///         }
+    ///     })
+    ///     .init()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn download_progress<F>(mut self, observer: F) -> Self
+    where
+        F: Fn(&DownloadProgress) + Send + Sync + 'static,
+    {
+        self.download_progress = Some(Arc::new(observer));
+        self
+    }
+
+    /// Set how the network operations of the workspace failing with transient errors are retried.
+    /// See [`RetryPolicy`](struct.RetryPolicy.html) for the available settings and the default
+    /// policy.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Set the minimum free space (in bytes) the volume backing the workspace's caches must have
+    /// before crates and tools are downloaded into them, or disable the check with `None`.
+    ///
+    /// When there's less free space than that the operation fails early with
+    /// [`StorageError::InsufficientDiskSpace`](enum.StorageError.html), instead of failing
+    /// partway through and leaving half-written files behind. By default the check is disabled.
+    pub fn min_cache_free_space(mut self, bytes: Option<u64>) -> Self {
+        self.min_cache_free_space = bytes;
+        self
+    }
+
+    /// Set the maximum size (in bytes) of the crates cached in the workspace, or disable the limit
+    /// with `None`. By default the cache size is not limited.
+    ///
+    /// Every time a cached crate is fetched or used by a build it's marked as recently used, and
+    /// once the cache grows over the limit the least recently used crates are removed from it.
+    /// The limit only applies to the crate sources and repositories cached by rustwide, not to
+    /// cargo's registry or to the toolchains. See [`Workspace::gc_caches`](struct.Workspace.html#method.gc_caches)
+    /// to enforce the limit explicitly.
+    pub fn max_cache_size(mut self, bytes: Option<u64>) -> Self {
+        self.max_cache_size = bytes;
+        self
+    }
+
+    /// Set the minimum free space (in bytes) the volume backing the build directories must have
+    /// before a crate is extracted and built, or disable the check with `None`.
+    ///
+    /// When there's less free space than that the build fails early with
+    /// [`StorageError::InsufficientDiskSpace`](enum.StorageError.html). By default the check is
+    /// disabled.
+    pub fn min_builds_free_space(mut self, bytes: Option<u64>) -> Self {
+        self.min_builds_free_space = bytes;
+        self
+    }
+
+    /// Enforce a [`RunBudget`](struct.RunBudget.html) on all the builds of the workspace: once
+    /// it's exhausted new builds fail with [`BudgetExceeded`](enum.BudgetExceeded.html). By
+    /// default builds are not limited.
+    pub fn run_budget(mut self, budget: RunBudget) -> Self {
+        self.run_budget = Some(budget);
+        self
+    }
+
+    /// Set the policy consulted before building each crate to adjust its sandbox limits,
+    /// returning the [`SandboxAdjustments`](struct.SandboxAdjustments.html) to apply on top of
+    /// the limits of the build. By default the limits are not adjusted.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{Crate, SandboxAdjustments, WorkspaceBuilder};
+    /// # use std::error::Error;
+    /// # use std::time::Duration;
+    /// # async fn run() -> Result<(), Box<dyn Error>> {
+    /// let workspace = WorkspaceBuilder::new("work".as_ref(), "my-app")
+    ///     .sandbox_adjustments(|krate: &Crate| {
+    ///         if krate.to_string().contains("servo") {
+    ///             SandboxAdjustments::new()
+    ///                 .memory_limit(8 * 1024 * 1024 * 1024)
+    ///                 .timeout(Duration::from_secs(60 * 60))
+    ///         } else {
+    ///             SandboxAdjustments::new()
+    ///         }
+    ///     })
+    ///     .init()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn sandbox_adjustments<F>(mut self, policy: F) -> Self
+    where
+        F: Fn(&Crate) -> SandboxAdjustments + Send + Sync + 'static,
+    {
+        self.sandbox_adjustments = Some(Arc::new(policy));
+        self
+    }
+
+    /// Use the Tokio runtime behind the provided handle for the work rustwide does in the
+    /// background, instead of the runtime the workspace futures are polled on.
+    ///
+    /// Rustwide will spawn its background tasks and blocking operations on this runtime, and
+    /// register its timers and child processes with it. This is useful for applications whose
+    /// runtime is not suited for that work, for example because it's a current-thread runtime
+    /// or because its blocking pool is limited. Filesystem operations still run on the runtime
+    /// polling the futures.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::WorkspaceBuilder;
+    /// # use std::error::Error;
+    /// # async fn run() -> Result<(), Box<dyn Error>> {
+    /// let background = tokio::runtime::Builder::new()
+    ///     .threaded_scheduler()
+    ///     .enable_all()
+    ///     .build()?;
+    ///
+    /// let workspace = WorkspaceBuilder::new("work".as_ref(), "my-app")
+    ///     .runtime_handle(background.handle().clone())
+    ///     .init()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn runtime_handle(mut self, handle: Handle) -> Self {
+        self.runtime = Some(handle);
+        self
+    }
+
     /// Initialize the workspace. This will create all the necessary local files and fetch the rest from the network. It's
     /// not unexpected for this method to take minutes to run on slower network connections.
     pub async fn init(self) -> Result<Workspace, Error> {
@@ -145,7 +593,9 @@ impl WorkspaceBuilder {
             )
         })?;
 
+        let runtime = self.runtime.clone();
         crate::utils::file_lock(
+            runtime.as_ref(),
             &self.path.join("lock"),
             "initialize the workspace",
             async move {
@@ -155,48 +605,145 @@ impl WorkspaceBuilder {
                     SandboxImage::remote(DEFAULT_SANDBOX_IMAGE).await?
                 };
 
-                let mut headers = reqwest::header::HeaderMap::new();
-                headers.insert(reqwest::header::USER_AGENT, self.user_agent.parse()?);
-                let http = reqwest::ClientBuilder::new()
-                    .default_headers(headers)
-                    .build()?;
-
-                let mut ws = Workspace {
-                    inner: Arc::new(WorkspaceInner {
-                        http,
-                        path: self.path,
-                        sandbox_image,
-                        command_timeout: self.command_timeout,
-                        command_no_output_timeout: self.command_no_output_timeout,
-                        fetch_registry_index_during_builds: self.fetch_registry_index_during_builds,
-                        current_container: None,
-                        rustup_profile: self.rustup_profile,
-                    }),
-                };
+                let http = self.http_client()?;
+
+                let rustup_home = self.path.join("rustup-home");
+
+                let running_inside_docker = self.running_inside_docker;
+                let fast_init = self.fast_init;
+                let mut ws = self.build(http, rustup_home, sandbox_image);
 
-                if self.running_inside_docker {
+                if running_inside_docker {
                     let container = CurrentContainer::detect(&ws).await?;
                     Arc::get_mut(&mut ws.inner).unwrap().current_container = container;
                 }
 
-                ws.init(self.fast_init).await?;
+                ws.init(fast_init).await?;
 
                 Ok(ws)
             },
         )
         .await
     }
+
+    fn http_client(&self) -> Result<reqwest::Client, Error> {
+        use reqwest::header::{HeaderMap, HeaderName, HeaderValue, USER_AGENT};
+
+        let product = format!("{} rustwide/{}", self.user_agent, env!("CARGO_PKG_VERSION"));
+        let user_agent = match &self.contact {
+            Some(contact) => format!("{} ({})", product, contact),
+            None => {
+                warn!(
+                    "no contact information was provided for the user agent, which is \
+                     required by the crates.io crawling policy"
+                );
+                product
+            }
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_AGENT, user_agent.parse()?);
+        for (name, value) in &self.http_headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|_| format!("invalid HTTP header name: {}", name))?;
+            let value = HeaderValue::from_str(value)
+                .with_context(|_| format!("invalid value of HTTP header {}", name))?;
+            headers.insert(name, value);
+        }
+
+        Ok(reqwest::ClientBuilder::new()
+            .default_headers(headers)
+            .build()?)
+    }
+
+    fn build(
+        self,
+        http: reqwest::Client,
+        rustup_home: PathBuf,
+        sandbox_image: SandboxImage,
+    ) -> Workspace {
+        Workspace {
+            inner: Arc::new(WorkspaceInner {
+                http,
+                journal: Journal::new(self.path.join("journal")),
+                path: self.path,
+                rustup_home,
+                system_rustup: self.system_rustup,
+                standalone_toolchains: self.standalone_toolchains,
+                nextest: self.nextest,
+                semver_checks: self.semver_checks,
+                git_lfs: self.git_lfs,
+                tool_versions: self.tool_versions,
+                extra_tools: self.extra_tools,
+                git_credentials: self.git_credentials,
+                registry_tokens: self.registry_tokens,
+                cargo_network: self.cargo_network,
+                download_progress: self.download_progress,
+                retry_policy: self.retry_policy,
+                min_cache_free_space: self.min_cache_free_space,
+                cache_manager: CacheManager::new(self.max_cache_size),
+                min_builds_free_space: self.min_builds_free_space,
+                run_budget: self.run_budget,
+                sandbox_adjustments: self.sandbox_adjustments,
+                cratesio_rate_limiter: self.cratesio_rate_limit.map(RateLimiter::new),
+                sandbox_image,
+                command_timeout: self.command_timeout,
+                command_no_output_timeout: self.command_no_output_timeout,
+                command_low_priority: self.command_low_priority,
+                fetch_registry_index_during_builds: self.fetch_registry_index_during_builds,
+                parallel_extraction: self.parallel_extraction,
+                current_container: None,
+                rustup_profile: self.rustup_profile,
+                rustup_self_update: self.rustup_self_update,
+                shutdown: Shutdown::new(),
+                mount_owners: MountOwners::new(),
+                in_flight_fetches: InFlightFetches::new(),
+                runtime: self.runtime,
+                #[cfg(feature = "mock")]
+                mock: None,
+            }),
+        }
+    }
 }
 
 struct WorkspaceInner {
     http: reqwest::Client,
     path: PathBuf,
+    rustup_home: PathBuf,
+    system_rustup: Option<PathBuf>,
+    standalone_toolchains: bool,
+    nextest: bool,
+    semver_checks: bool,
+    git_lfs: bool,
+    tool_versions: HashMap<String, String>,
+    extra_tools: Vec<InstallableTool>,
+    git_credentials: GitCredentials,
+    registry_tokens: HashMap<String, String>,
+    cargo_network: CargoNetworkConfig,
+    download_progress: Option<ProgressObserver>,
+    retry_policy: RetryPolicy,
+    min_cache_free_space: Option<u64>,
+    cache_manager: CacheManager,
+    min_builds_free_space: Option<u64>,
+    run_budget: Option<RunBudget>,
+    sandbox_adjustments: Option<AdjustmentsPolicy>,
+    cratesio_rate_limiter: Option<RateLimiter>,
     sandbox_image: SandboxImage,
     command_timeout: Option<Duration>,
     command_no_output_timeout: Option<Duration>,
+    command_low_priority: bool,
     fetch_registry_index_during_builds: bool,
+    parallel_extraction: bool,
     current_container: Option<CurrentContainer>,
     rustup_profile: String,
+    rustup_self_update: RustupSelfUpdate,
+    shutdown: Shutdown,
+    mount_owners: MountOwners,
+    in_flight_fetches: InFlightFetches,
+    journal: Journal,
+    runtime: Option<Handle>,
+    #[cfg(feature = "mock")]
+    mock: Option<MockFixtures>,
 }
 
 /// Directory on the filesystem containing rustwide's state and caches.
@@ -207,7 +754,34 @@ pub struct Workspace {
 }
 
 impl Workspace {
+    /// Create a simulated workspace in the provided directory, answering the commands and the
+    /// crate fetches with the provided fixtures instead of running or downloading anything. See
+    /// the [`mock`](mock/index.html) module for more information.
+    ///
+    /// The directory is created if it doesn't exist, but nothing is installed in it.
+    #[cfg(feature = "mock")]
+    #[cfg_attr(docs_rs, doc(cfg(feature = "mock")))]
+    pub fn mock(path: &Path, fixtures: MockFixtures) -> Result<Workspace, Error> {
+        std::fs::create_dir_all(path).with_context(|_| {
+            format!("failed to create workspace directory: {}", path.display())
+        })?;
+
+        let mut ws = WorkspaceBuilder::new(path, "rustwide-mock")
+            .cratesio_rate_limit(None)
+            .build(
+                reqwest::Client::new(),
+                path.join("rustup-home"),
+                SandboxImage::mock(),
+            );
+        Arc::get_mut(&mut ws.inner).unwrap().mock = Some(fixtures);
+        Ok(ws)
+    }
+
     /// Open a named build directory inside the workspace.
+    ///
+    /// The directory on disk is named after a short hash of the name, to avoid exceeding the path
+    /// length limits with deeply nested build artifacts. See
+    /// [`lookup_build_dir`](#method.lookup_build_dir) to find a build directory from it.
     pub fn build_dir(&self, name: &str) -> BuildDirectory {
         BuildDirectory::new(
             Workspace {
@@ -217,6 +791,41 @@ impl Workspace {
         )
     }
 
+    /// Find the build directory stored on disk in the directory with the provided name, as returned
+    /// by [`BuildDirectory::dir_name`](struct.BuildDirectory.html#method.dir_name). `None` is
+    /// returned if no build was ever executed in that build directory.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::WorkspaceBuilder;
+    /// # use std::error::Error;
+    /// # async fn run() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init().await?;
+    /// if let Some(build_dir) = workspace.lookup_build_dir("3bdc4ee49ec9a5b2")? {
+    ///     println!("the directory belongs to {}", build_dir.name());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn lookup_build_dir(&self, dir_name: &str) -> Result<Option<BuildDirectory>, Error> {
+        Ok(crate::build::lookup_build_dir_name(self, dir_name)?.map(|name| self.build_dir(&name)))
+    }
+
+    /// Check whether the volumes backing the caches and the build directories have at least the
+    /// free space configured with
+    /// [`WorkspaceBuilder::min_cache_free_space`](struct.WorkspaceBuilder.html#method.min_cache_free_space)
+    /// and
+    /// [`WorkspaceBuilder::min_builds_free_space`](struct.WorkspaceBuilder.html#method.min_builds_free_space),
+    /// returning [`StorageError::InsufficientDiskSpace`](enum.StorageError.html) otherwise.
+    ///
+    /// Rustwide already performs this check before downloading and building crates, but it can be
+    /// useful to run it before scheduling new work.
+    pub fn check_disk_space(&self) -> Result<(), Error> {
+        self.check_cache_space()?;
+        self.check_builds_space()
+    }
+
     /// Remove all the contents of all the build directories, freeing disk space.
     pub async fn purge_all_build_dirs(&self) -> Result<(), Error> {
         let dir = self.builds_dir();
@@ -255,6 +864,248 @@ impl Workspace {
         Ok(())
     }
 
+    /// Evict the least recently used crates from the caches until they fit in the size configured
+    /// with [`WorkspaceBuilder::max_cache_size`](struct.WorkspaceBuilder.html#method.max_cache_size),
+    /// returning the number of bytes freed.
+    ///
+    /// The limit is already enforced every time a crate is fetched, but only considering the
+    /// crates cached when the workspace first needed to evict something. This method scans the
+    /// caches again, picking up the changes made by other processes using the same workspace.
+    /// Nothing is evicted if no limit is configured.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::WorkspaceBuilder;
+    /// # use std::error::Error;
+    /// # async fn run() -> Result<(), Box<dyn Error>> {
+    /// let workspace = WorkspaceBuilder::new("work".as_ref(), "my-app")
+    ///     .max_cache_size(Some(50 * 1024 * 1024 * 1024))
+    ///     .init()
+    ///     .await?;
+    /// let freed = workspace.gc_caches().await?;
+    /// println!("freed {} bytes", freed);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn gc_caches(&self) -> Result<u64, Error> {
+        let workspace = Workspace {
+            inner: self.inner.clone(),
+        };
+        crate::utils::spawn_blocking(self.runtime(), move || {
+            workspace.inner.cache_manager.gc(&workspace.cache_dir())
+        })
+        .await?
+    }
+
+    /// Report the disk usage of the workspace's caches: the fetched crates, the mirrors of
+    /// repositories, the downloaded archives and the installed toolchains, along with the size
+    /// of each one and the last time it was used.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{CacheKind, WorkspaceBuilder};
+    /// # use std::error::Error;
+    /// # async fn run() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init().await?;
+    /// let report = workspace.cache_report().await?;
+    /// println!("toolchains: {} bytes", report.size_of_kind(CacheKind::Toolchain));
+    /// for item in report.least_recently_used().iter().take(10) {
+    ///     println!("{} ({} bytes)", item.path().display(), item.size());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn cache_report(&self) -> Result<CacheReport, Error> {
+        let cache_dir = self.cache_dir();
+        let rustup_home = self.rustup_home();
+        crate::utils::spawn_blocking(self.runtime(), move || {
+            CacheReport::collect(&cache_dir, &rustup_home)
+        })
+        .await?
+    }
+
+    /// Fetch the provided crates and cache them in the workspace, fetching at most `concurrency`
+    /// crates at the same time. This allows caching all the crates needed by a large run upfront,
+    /// instead of fetching them one at a time while building.
+    ///
+    /// A result is returned for each crate, in the same order as the provided crates: failing to
+    /// fetch a crate doesn't stop the other ones from being fetched. Crates present multiple times
+    /// in the list, or being prefetched by another call at the same time, are fetched only once,
+    /// and if the fetch fails all the callers get a copy of the error.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{Crate, WorkspaceBuilder};
+    /// # use std::error::Error;
+    /// # async fn run() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init().await?;
+    /// let crates = vec![
+    ///     Crate::crates_io("lazy_static", "1.0.0")?,
+    ///     Crate::crates_io("log", "0.4.8")?,
+    /// ];
+    /// for (krate, res) in crates.iter().zip(workspace.prefetch_crates(&crates, 8).await) {
+    ///     if let Err(err) = res {
+    ///         eprintln!("failed to fetch {}: {}", krate, err);
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn prefetch_crates(
+        &self,
+        crates: &[Crate],
+        concurrency: usize,
+    ) -> Vec<Result<(), Error>> {
+        // Each crate is fetched by its first occurrence in the list.
+        let mut first_occurrences = HashMap::new();
+        let origins: Vec<usize> = crates
+            .iter()
+            .enumerate()
+            .map(|(index, krate)| *first_occurrences.entry(krate.to_string()).or_insert(index))
+            .collect();
+        let concurrency = concurrency.max(1);
+        info!(
+            "prefetching {} crates, {} at a time",
+            first_occurrences.len(),
+            concurrency
+        );
+
+        let mut results: Vec<Option<Result<(), Error>>> = crates.iter().map(|_| None).collect();
+        let mut fetches = stream::iter((0..crates.len()).filter(|&index| origins[index] == index))
+            .map(|index| async move {
+                let res = self.in_flight_fetches().fetch(self, &crates[index]).await;
+                (index, res)
+            })
+            .buffer_unordered(concurrency);
+        while let Some((index, res)) = fetches.next().await {
+            if let Err(err) = &res {
+                warn!("failed to prefetch {}: {}", crates[index], err);
+            }
+            results[index] = Some(res);
+        }
+
+        let duplicates: Vec<Option<Result<(), Error>>> = origins
+            .iter()
+            .enumerate()
+            .map(|(index, &origin)| match &results[origin] {
+                _ if origin == index => None,
+                Some(Err(err)) => Some(Err(crate::utils::clone_error(err))),
+                _ => Some(Ok(())),
+            })
+            .collect();
+        results
+            .into_iter()
+            .zip(duplicates)
+            .map(|(result, duplicate)| result.or(duplicate).unwrap())
+            .collect()
+    }
+
+    /// Export the caches of the workspace to a gzipped tarball, so that other workspaces (for
+    /// example on new build machines or in CI jobs) can be seeded with
+    /// [`import_cache`](#method.import_cache) instead of downloading everything again.
+    ///
+    /// The tarball contains the cached crate sources and git repositories, cargo's registry
+    /// index and cache, and the installed toolchains.
+    /// Nothing should be fetched or installed in the workspace while the export is in progress.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::WorkspaceBuilder;
+    /// # use std::error::Error;
+    /// # async fn run() -> Result<(), Box<dyn Error>> {
+    /// let workspace = WorkspaceBuilder::new("work".as_ref(), "my-app").init().await?;
+    /// workspace.export_cache("rustwide-cache.tar.gz".as_ref()).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn export_cache(&self, tarball: &Path) -> Result<(), Error> {
+        let base = self.inner.path.clone();
+        let roots = self.exported_cache_dirs();
+        let tarball = tarball.to_path_buf();
+        crate::utils::spawn_blocking(self.runtime(), move || {
+            crate::cache_archive::export(&base, &roots, &tarball)
+        })
+        .await?
+    }
+
+    /// Import the caches exported by [`export_cache`](#method.export_cache) into the workspace,
+    /// replacing the cached files already present, including the toolchains.
+    ///
+    /// The toolchains contain binaries executed outside the sandbox, so only tarballs coming from
+    /// a trusted source should be imported. No build should be running in the workspace while
+    /// the import is in progress.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::WorkspaceBuilder;
+    /// # use std::error::Error;
+    /// # async fn run() -> Result<(), Box<dyn Error>> {
+    /// let workspace = WorkspaceBuilder::new("work".as_ref(), "my-app").init().await?;
+    /// workspace.import_cache("rustwide-cache.tar.gz".as_ref()).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn import_cache(&self, tarball: &Path) -> Result<(), Error> {
+        self.check_cache_space()?;
+        let base = self.inner.path.clone();
+        let roots = self.exported_cache_dirs();
+        let tarball = tarball.to_path_buf();
+        let staging = crate::utils::temp_path(&self.cache_dir().join("import"));
+        let parallel = self.parallel_extraction();
+        crate::utils::spawn_blocking(self.runtime(), move || {
+            crate::cache_archive::import(&base, &roots, &tarball, &staging, parallel)
+        })
+        .await?
+    }
+
+    /// Return the directories included in the exported caches, relative to the workspace.
+    fn exported_cache_dirs(&self) -> Vec<PathBuf> {
+        vec![
+            PathBuf::from("cache"),
+            PathBuf::from("cargo-home").join("git"),
+            PathBuf::from("cargo-home").join("registry").join("index"),
+            PathBuf::from("cargo-home").join("registry").join("cache"),
+            PathBuf::from("rustup-home").join("toolchains"),
+            PathBuf::from("rustup-home").join("update-hashes"),
+        ]
+    }
+
+    /// Clean up the state left behind by the operations that were in progress when a previous
+    /// process using the workspace crashed, returning what was removed.
+    ///
+    /// Rustwide records the operations leaving state behind while they run (fetching crates,
+    /// building them and running sandbox containers) in a journal inside the workspace. This
+    /// method removes exactly the partial downloads, build directories and containers of the
+    /// operations that never completed, instead of purging all the build directories and the
+    /// caches. The journal is compacted afterwards.
+    ///
+    /// All the operations not started by the current process are considered interrupted, so this
+    /// must be called while no other process is using the workspace, ideally right after
+    /// [initializing it](struct.WorkspaceBuilder.html#method.init).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::WorkspaceBuilder;
+    /// # use std::error::Error;
+    /// # async fn run() -> Result<(), Box<dyn Error>> {
+    /// let workspace = WorkspaceBuilder::new("work".as_ref(), "my-app").init().await?;
+    /// let report = workspace.recover().await?;
+    /// for container in report.removed_containers() {
+    ///     println!("removed leftover container {}", container);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn recover(&self) -> Result<RecoveryReport, Error> {
+        crate::journal::recover(self.journal()).await
+    }
+
     /// Return a list of all the toolchains present in the workspace.
     ///
     /// # Example
@@ -279,6 +1130,145 @@ impl Workspace {
         crate::toolchain::list_installed_toolchains(&self.rustup_home())
     }
 
+    /// Return the name and the version of all the tools installed in the workspace, either with
+    /// `cargo install` or from prebuilt binaries.
+    pub fn installed_tools(&self) -> Result<BTreeMap<String, String>, Error> {
+        crate::tools::installed_crates(self)
+    }
+
+    /// Create a new `git` command, configured to use the workspace's
+    /// [credentials](struct.WorkspaceBuilder.html#method.git_credentials). This can be used for
+    /// example to push the results of the builds to a repository.
+    ///
+    /// Rustwide itself doesn't run `git` to fetch repositories anymore, so this requires `git` to
+    /// be installed on the host.
+    #[deprecated(note = "rustwide doesn't run git to fetch repositories anymore")]
+    pub fn git_command(&self) -> Command {
+        self.git_credentials()
+            .configure(self, Command::new(self, "git"))
+    }
+
+    /// Shut down the workspace, cleaning up everything it started.
+    ///
+    /// All the commands currently running in the workspace will be killed (returning a
+    /// [`CommandError::Cancelled`](cmd/enum.CommandError.html#variant.Cancelled) error), all the
+    /// sandbox containers will be stopped and removed, and the logs will be flushed. Any lock held
+    /// by the operations in progress is released as soon as they return the error.
+    ///
+    /// After this method is called no new command can be started in the workspace: it's meant to
+    /// be called right before the embedding service terminates. Calling it multiple times is a
+    /// no-op.
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        let shutdown = &self.inner.shutdown;
+        if !shutdown.begin() {
+            return Ok(());
+        }
+        info!("shutting down the workspace");
+
+        let result = self.kill_running().await;
+        log::logger().flush();
+        result
+    }
+
+    /// Abort all the commands currently running in the workspace, without shutting it down.
+    ///
+    /// All the running commands will be killed (returning a
+    /// [`CommandError::Cancelled`](cmd/enum.CommandError.html#variant.Cancelled) error) and all
+    /// the sandbox containers will be stopped and removed. Unlike [`shutdown`](#method.shutdown),
+    /// new commands can be started in the workspace afterwards, which is useful for example when
+    /// an experiment is cancelled while the service embedding rustwide keeps running.
+    pub async fn cancel_all(&self) -> Result<(), Error> {
+        info!("cancelling all the running commands");
+        self.inner.shutdown.cancel();
+        self.kill_running().await
+    }
+
+    async fn kill_running(&self) -> Result<(), Error> {
+        let shutdown = &self.inner.shutdown;
+
+        // Removing the containers first stops the `docker start` processes attached to them,
+        // allowing the builds to report the failure properly.
+        let mut result = Ok(());
+        for container in shutdown.take_containers() {
+            info!("removing container {}", container);
+            if let Err(err) = crate::shutdown::remove_container(&container).await {
+                error!("failed to remove container {}: {}", container, err);
+                result = Err(err);
+            }
+        }
+
+        for process in shutdown.take_processes() {
+            info!("killing process {}", process);
+            if let Err(err) = crate::native::kill_process(process) {
+                // The process might have exited in the meantime.
+                warn!("failed to kill process {}: {}", process, err);
+            }
+        }
+
+        result
+    }
+
+    /// Shut down the workspace as soon as the process receives a Ctrl-C (`SIGINT` on Unix).
+    ///
+    /// This spawns a background task listening for the signal and calling
+    /// [`shutdown`](#method.shutdown) when it arrives, so it must be called from within a Tokio
+    /// runtime (unless a [runtime handle](struct.WorkspaceBuilder.html#method.runtime_handle) was
+    /// provided). Only the workspace is shut down: the process is left running, and it's up to the
+    /// caller to exit once the in-flight builds return.
+    pub fn shutdown_on_ctrl_c(&self) {
+        let workspace = Workspace {
+            inner: self.inner.clone(),
+        };
+        crate::utils::spawn(self.runtime(), async move {
+            if let Err(err) = tokio::signal::ctrl_c().await {
+                error!("failed to listen for Ctrl-C: {}", err);
+                return;
+            }
+            info!("received Ctrl-C");
+
+            if let Err(err) = workspace.shutdown().await {
+                error!("failed to shut down the workspace: {}", err);
+            }
+        });
+    }
+
+    /// Spawn a background task sharing the CPU fairly between the concurrent builds attached to
+    /// the returned [`CpuCoordinator`](struct.CpuCoordinator.html), adjusting their CPU weights at
+    /// the end of every time slice.
+    ///
+    /// The task runs until all the clones of the coordinator are dropped, or until the workspace
+    /// is [shut down](#method.shutdown). It must be spawned from within a Tokio runtime, unless a
+    /// [runtime handle](struct.WorkspaceBuilder.html#method.runtime_handle) was provided.
+    pub fn spawn_cpu_coordinator(&self, time_slice: Duration) -> CpuCoordinator {
+        crate::fair_share::spawn(
+            Workspace {
+                inner: self.inner.clone(),
+            },
+            time_slice,
+        )
+    }
+
+    /// Spawn a background task running maintenance on the workspace at the provided interval,
+    /// so that long-running services don't have to schedule it themselves.
+    ///
+    /// Each maintenance run removes the sandbox containers leaked by previous processes using
+    /// the workspace, removes the cached crates that weren't used in the last 30 days (and the
+    /// least recently used ones if the caches exceed their
+    /// [maximum size](struct.WorkspaceBuilder.html#method.max_cache_size)), and checks whether the
+    /// workspace lock is stale. Errors are logged, and don't stop the task.
+    ///
+    /// The task runs until the returned handle is stopped or dropped, or until the workspace is
+    /// [shut down](#method.shutdown). It must be spawned from within a Tokio runtime, unless a
+    /// [runtime handle](struct.WorkspaceBuilder.html#method.runtime_handle) was provided.
+    pub fn spawn_maintenance(&self, interval: Duration) -> MaintenanceHandle {
+        crate::maintenance::spawn(
+            Workspace {
+                inner: self.inner.clone(),
+            },
+            interval,
+        )
+    }
+
     pub(crate) fn http_client(&self) -> &reqwest::Client {
         &self.inner.http
     }
@@ -288,7 +1278,106 @@ impl Workspace {
     }
 
     pub(crate) fn rustup_home(&self) -> PathBuf {
-        self.inner.path.join("rustup-home")
+        self.inner.rustup_home.clone()
+    }
+
+    pub(crate) fn system_rustup(&self) -> Option<&Path> {
+        self.inner.system_rustup.as_deref()
+    }
+
+    pub(crate) fn standalone_toolchains(&self) -> bool {
+        self.inner.standalone_toolchains
+    }
+
+    pub(crate) fn nextest(&self) -> bool {
+        self.inner.nextest
+    }
+
+    pub(crate) fn semver_checks(&self) -> bool {
+        self.inner.semver_checks
+    }
+
+    pub(crate) fn git_lfs(&self) -> bool {
+        self.inner.git_lfs
+    }
+
+    pub(crate) fn pinned_tool_versions(&self) -> &HashMap<String, String> {
+        &self.inner.tool_versions
+    }
+
+    pub(crate) fn extra_tools(&self) -> &[InstallableTool] {
+        &self.inner.extra_tools
+    }
+
+    pub(crate) fn git_credentials(&self) -> &GitCredentials {
+        &self.inner.git_credentials
+    }
+
+    pub(crate) fn registry_token(&self, index: &str) -> Option<&str> {
+        self.inner
+            .registry_tokens
+            .get(index)
+            .map(|token| token.as_str())
+    }
+
+    pub(crate) fn cargo_network(&self) -> &CargoNetworkConfig {
+        &self.inner.cargo_network
+    }
+
+    #[cfg(feature = "mock")]
+    pub(crate) fn mock_fixtures(&self) -> Option<&MockFixtures> {
+        self.inner.mock.as_ref()
+    }
+
+    pub(crate) fn download_progress(&self) -> Option<&ProgressObserver> {
+        self.inner.download_progress.as_ref()
+    }
+
+    pub(crate) fn retry_policy(&self) -> &RetryPolicy {
+        &self.inner.retry_policy
+    }
+
+    pub(crate) fn cratesio_rate_limiter(&self) -> Option<&RateLimiter> {
+        self.inner.cratesio_rate_limiter.as_ref()
+    }
+
+    /// Mark an entry of the cache as recently used, evicting the least recently used entries if
+    /// the cache is now too big. Failures are logged, as they shouldn't fail the build.
+    pub(crate) async fn record_cache_access(&self, entry: PathBuf) {
+        let workspace = Workspace {
+            inner: self.inner.clone(),
+        };
+        let res = crate::utils::spawn_blocking(self.runtime(), move || {
+            workspace
+                .inner
+                .cache_manager
+                .record_access(&workspace.cache_dir(), &entry)
+        })
+        .await
+        .map_err(Error::from)
+        .and_then(|res| res);
+        if let Err(err) = res {
+            warn!("failed to update the cache: {}", err);
+        }
+    }
+
+    pub(crate) fn check_cache_space(&self) -> Result<(), Error> {
+        crate::disk::ensure_free_space(&self.cache_dir(), self.inner.min_cache_free_space)
+    }
+
+    pub(crate) fn check_builds_space(&self) -> Result<(), Error> {
+        crate::disk::ensure_free_space(&self.builds_dir(), self.inner.min_builds_free_space)
+    }
+
+    pub(crate) fn run_budget(&self) -> Option<&RunBudget> {
+        self.inner.run_budget.as_ref()
+    }
+
+    pub(crate) fn sandbox_adjustments(&self, krate: &Crate) -> SandboxAdjustments {
+        match &self.inner.sandbox_adjustments {
+            Some(policy) => policy(krate),
+            None => SandboxAdjustments::default(),
+        }
     }
 
     pub(crate) fn cache_dir(&self) -> PathBuf {
@@ -299,6 +1388,17 @@ impl Workspace {
         self.inner.path.join("builds")
     }
 
+    pub(crate) fn lock_path(&self) -> PathBuf {
+        self.inner.path.join("lock")
+    }
+
+    pub(crate) fn container_label(&self) -> String {
+        format!(
+            "rustwide.workspace={}",
+            crate::utils::normalize_path(&self.inner.path).display()
+        )
+    }
+
     pub(crate) fn sandbox_image(&self) -> &SandboxImage {
         &self.inner.sandbox_image
     }
@@ -311,10 +1411,18 @@ impl Workspace {
         self.inner.command_no_output_timeout
     }
 
+    pub(crate) fn default_command_low_priority(&self) -> bool {
+        self.inner.command_low_priority
+    }
+
     pub(crate) fn fetch_registry_index_during_builds(&self) -> bool {
         self.inner.fetch_registry_index_during_builds
     }
 
+    pub(crate) fn parallel_extraction(&self) -> bool {
+        self.inner.parallel_extraction
+    }
+
     pub(crate) fn current_container(&self) -> Option<&CurrentContainer> {
         self.inner.current_container.as_ref()
     }
@@ -323,7 +1431,40 @@ impl Workspace {
         &self.inner.rustup_profile
     }
 
+    pub(crate) fn rustup_self_update(&self) -> RustupSelfUpdate {
+        self.inner.rustup_self_update
+    }
+
+    pub(crate) fn shutdown_tracker(&self) -> &Shutdown {
+        &self.inner.shutdown
+    }
+
+    pub(crate) fn mount_owners(&self) -> &MountOwners {
+        &self.inner.mount_owners
+    }
+
+    pub(crate) fn in_flight_fetches(&self) -> &InFlightFetches {
+        &self.inner.in_flight_fetches
+    }
+
+    pub(crate) fn journal(&self) -> &Journal {
+        &self.inner.journal
+    }
+
+    pub(crate) fn runtime(&self) -> Option<&Handle> {
+        self.inner.runtime.as_ref()
+    }
+
     async fn init(&self, fast_init: bool) -> Result<(), Error> {
+        self.remove_stale_temp_entries()?;
+        crate::cargo_config::write(self).await?;
+
+        // Without rustup the main toolchain is not installed along with it, and it's needed to
+        // install the other tools.
+        if self.standalone_toolchains() {
+            Toolchain::MAIN.install(self).await?;
+        }
+
         info!("installing tools required by rustwide");
         crate::tools::install(self, fast_init).await?;
 
@@ -335,6 +1476,19 @@ impl Workspace {
         Ok(())
     }
 
+    /// Remove the temporary files and directories left in the caches and in the tools directory
+    /// by operations interrupted by a crash.
+    pub(crate) fn remove_stale_temp_entries(&self) -> Result<(), Error> {
+        // Entries are at most three levels deep, like `cratesio-sources/{name}/{file}`.
+        crate::utils::remove_stale_temp_entries(&self.cache_dir(), 3, STALE_TEMP_ENTRY_AGE)?;
+        crate::utils::remove_stale_temp_entries(
+            &self.cargo_home().join("bin"),
+            1,
+            STALE_TEMP_ENTRY_AGE,
+        )?;
+        Ok(())
+    }
+
     async fn update_cratesio_registry(&self) -> Result<(), Error> {
         // This nop cargo command is to update the registry so we don't have to do it for each
         // crate.  using `install` is a temporary solution until
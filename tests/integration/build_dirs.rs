@@ -0,0 +1,32 @@
+use failure::Error;
+use rustwide::cmd::SandboxBuilder;
+use rustwide::{Crate, Toolchain};
+
+#[test]
+fn test_lookup_build_dir() -> Result<(), Error> {
+    let workspace = crate::utils::init_named_workspace("build-dirs")?;
+    workspace.purge_all_build_dirs()?;
+
+    let toolchain = Toolchain::dist("stable");
+    toolchain.install(&workspace)?;
+
+    let krate = Crate::crates_io("lazy_static", "1.0.0")?;
+    krate.fetch(&workspace)?;
+
+    let mut build_dir = workspace.build_dir("lookup");
+    // No build was executed in the directory yet.
+    assert!(workspace.lookup_build_dir(&build_dir.dir_name())?.is_none());
+
+    let sandbox = SandboxBuilder::new().enable_networking(false);
+    build_dir.build(&toolchain, &krate, sandbox).run(|build| {
+        build.cargo().args(&["check"]).run()?;
+        Ok(())
+    })?;
+
+    let found = workspace.lookup_build_dir(&build_dir.dir_name())?.unwrap();
+    assert_eq!(found.name(), "lookup");
+    assert!(workspace.lookup_build_dir("0000000000000000")?.is_none());
+
+    workspace.purge_all_build_dirs()?;
+    Ok(())
+}
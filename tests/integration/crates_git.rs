@@ -1,6 +1,6 @@
 use failure::Error;
 use rustwide::cmd::{Command, CommandError, SandboxBuilder};
-use rustwide::{Crate, PrepareError, Toolchain, Workspace};
+use rustwide::{Crate, GitReference, PrepareError, Toolchain, Workspace};
 
 #[test]
 fn test_fetch() -> Result<(), Error> {
@@ -9,41 +9,125 @@ fn test_fetch() -> Result<(), Error> {
     toolchain.install(&workspace)?;
 
     let mut repo = Repo::new(&workspace)?;
-    let krate = Crate::git(&repo.serve()?);
+    let krate = Crate::git(&repo.url());
     krate.fetch(&workspace)?;
 
-    // Return the commit that was used during a build.
-    let cloned_commit = || -> Result<String, Error> {
-        let mut dir = workspace.build_dir("integration-crates_git-test_fetch");
-        dir.purge()?;
-        Ok(dir
-            .build(&toolchain, &krate, SandboxBuilder::new())
-            .run(|build| {
-                Ok(Command::new(&workspace, "git")
-                    .args(&["rev-parse", "HEAD"])
-                    .cd(build.host_source_dir())
-                    .run_capture()?
-                    .stdout_lines()[0]
-                    .to_string())
-            })?)
-    };
-
     // Check if the initial commit was fetched
     let initial_commit = repo.last_commit_sha.clone().unwrap();
     assert_eq!(initial_commit, krate.git_commit(&workspace).unwrap());
-    assert_eq!(initial_commit, cloned_commit()?);
+    assert_eq!(
+        "commit 1",
+        checked_out_file(&workspace, &toolchain, &krate, "marker")?
+    );
 
     // Make a new commit
     repo.commit(&workspace)?;
-    let new_commit = repo.last_commit_sha.unwrap();
+    let new_commit = repo.last_commit_sha.clone().unwrap();
     assert_ne!(initial_commit, new_commit);
     assert_eq!(initial_commit, krate.git_commit(&workspace).unwrap());
-    assert_eq!(initial_commit, cloned_commit()?);
+    assert_eq!(
+        "commit 1",
+        checked_out_file(&workspace, &toolchain, &krate, "marker")?
+    );
 
     // Then ensure the new commit was fetched
     krate.fetch(&workspace)?;
     assert_eq!(new_commit, krate.git_commit(&workspace).unwrap());
-    assert_eq!(new_commit, cloned_commit()?);
+    assert_eq!(
+        "commit 2",
+        checked_out_file(&workspace, &toolchain, &krate, "marker")?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_checkout_by_reference() -> Result<(), Error> {
+    let workspace = crate::utils::init_workspace()?;
+    let toolchain = Toolchain::dist("stable");
+    toolchain.install(&workspace)?;
+
+    let mut repo = Repo::new(&workspace)?;
+    let first_commit = repo.last_commit_sha.clone().unwrap();
+    repo.git(&workspace, &["tag", "v1"])?;
+    repo.git(&workspace, &["branch", "stable"])?;
+    repo.commit(&workspace)?;
+    let second_commit = repo.last_commit_sha.clone().unwrap();
+
+    let references = vec![
+        (GitReference::Tag("v1".into()), &first_commit, "commit 1"),
+        (
+            GitReference::Branch("stable".into()),
+            &first_commit,
+            "commit 1",
+        ),
+        (
+            GitReference::Commit(first_commit.clone()),
+            &first_commit,
+            "commit 1",
+        ),
+        (
+            GitReference::Commit(second_commit.clone()),
+            &second_commit,
+            "commit 2",
+        ),
+    ];
+    for (reference, commit, marker) in references {
+        // Shallow clones only contain the tip of each branch, and have to be deepened to check
+        // out older commits.
+        for krate in &[
+            Crate::git_with_reference(&repo.url(), reference.clone()),
+            Crate::git_shallow_with_reference(&repo.url(), reference.clone()),
+        ] {
+            krate.purge_from_cache(&workspace)?;
+            krate.fetch(&workspace)?;
+            assert_eq!(*commit, krate.git_commit(&workspace).unwrap());
+            assert_eq!(
+                marker,
+                checked_out_file(&workspace, &toolchain, krate, "marker")?
+            );
+        }
+    }
+
+    let missing = Crate::git_with_reference(&repo.url(), GitReference::Tag("missing".into()));
+    assert!(missing.fetch(&workspace).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_fetch_submodules() -> Result<(), Error> {
+    let workspace = crate::utils::init_workspace()?;
+    let toolchain = Toolchain::dist("stable");
+    toolchain.install(&workspace)?;
+
+    let mut submodule = Repo::new(&workspace)?;
+    let mut repo = Repo::new(&workspace)?;
+    repo.git(
+        &workspace,
+        &[
+            "-c",
+            "protocol.file.allow=always",
+            "submodule",
+            "add",
+            &submodule.url(),
+            "vendor/sub",
+        ],
+    )?;
+    repo.commit(&workspace)?;
+
+    // New commits of the submodule are ignored until the repository points to them.
+    submodule.commit(&workspace)?;
+
+    let krate = Crate::git(&repo.url());
+    krate.fetch(&workspace)?;
+    assert_eq!(
+        "commit 1",
+        checked_out_file(&workspace, &toolchain, &krate, "vendor/sub/marker")?
+    );
+
+    let krate = Crate::git(&repo.url()).git_submodules(false);
+    assert!(checked_out_file(&workspace, &toolchain, &krate, "vendor/sub/marker").is_err());
 
     Ok(())
 }
@@ -52,8 +136,7 @@ fn test_fetch() -> Result<(), Error> {
 fn test_fetch_with_authentication() -> Result<(), Error> {
     let workspace = crate::utils::init_workspace()?;
 
-    let repo = Repo::new(&workspace)?.authenticated();
-    let krate = Crate::git(&repo.serve()?);
+    let krate = Crate::git(&serve_unauthorized()?);
 
     let err = krate.fetch(&workspace).unwrap_err();
     if let Some(&CommandError::Timeout(_)) = err.downcast_ref() {
@@ -67,10 +150,44 @@ fn test_fetch_with_authentication() -> Result<(), Error> {
     Ok(())
 }
 
+/// Return the content of a file in the source of a build. The marker file identifies the commit
+/// that was checked out.
+fn checked_out_file(
+    workspace: &Workspace,
+    toolchain: &Toolchain,
+    krate: &Crate,
+    path: &str,
+) -> Result<String, Error> {
+    let mut dir = workspace.build_dir("integration-crates_git");
+    dir.purge()?;
+    Ok(dir
+        .build(toolchain, krate, SandboxBuilder::new())
+        .run(|build| Ok(std::fs::read_to_string(build.host_source_dir().join(path))?))?)
+}
+
+/// Start a server requiring authentication for every request, returning its URL.
+fn serve_unauthorized() -> Result<String, Error> {
+    let server =
+        tiny_http::Server::http("localhost:0").map_err(|e| failure::err_msg(e.to_string()))?;
+    let port = server.server_addr().port();
+
+    std::thread::spawn(move || {
+        while let Ok(req) = server.recv() {
+            let resp = tiny_http::Response::new_empty(tiny_http::StatusCode(401));
+            let _ = req.respond(resp.with_header(tiny_http::Header {
+                field: "WWW-Authenticate".parse().unwrap(),
+                value: "Basic realm=\"Dummy\"".parse().unwrap(),
+            }));
+        }
+    });
+
+    Ok(format!("http://localhost:{}", port))
+}
+
 struct Repo {
     source: tempfile::TempDir,
     last_commit_sha: Option<String>,
-    require_auth: bool,
+    commits: usize,
 }
 
 impl Repo {
@@ -86,34 +203,47 @@ impl Repo {
         let mut repo = Repo {
             source,
             last_commit_sha: None,
-            require_auth: false,
+            commits: 0,
         };
         repo.commit(workspace)?;
         Ok(repo)
     }
 
-    fn authenticated(mut self) -> Self {
-        self.require_auth = true;
-        self
+    /// URL of the repository, fetched through the `file://` transport.
+    fn url(&self) -> String {
+        format!("file://{}", self.source.path().display())
     }
 
-    fn commit(&mut self, workspace: &Workspace) -> Result<(), Error> {
+    fn git(&self, workspace: &Workspace, args: &[&str]) -> Result<(), Error> {
         Command::new(workspace, "git")
-            .args(&["add", "."])
-            .cd(self.source.path())
-            .run()?;
-        Command::new(workspace, "git")
-            .args(&["-c", "commit.gpgsign=false"])
-            .args(&["-c", "user.name=test"])
-            .args(&["-c", "user.email=test@example.com"])
-            .args(&["commit", "-m", "auto commit"])
-            .args(&["--allow-empty"])
-            .cd(self.source.path())
-            .run()?;
-        Command::new(workspace, "git")
-            .args(&["update-server-info"])
+            .args(args)
             .cd(self.source.path())
             .run()?;
+        Ok(())
+    }
+
+    /// Commit a new version of the marker file, containing the number of the commit.
+    fn commit(&mut self, workspace: &Workspace) -> Result<(), Error> {
+        self.commits += 1;
+        std::fs::write(
+            self.source.path().join("marker"),
+            format!("commit {}", self.commits),
+        )?;
+        self.git(workspace, &["add", "."])?;
+        self.git(
+            workspace,
+            &[
+                "-c",
+                "commit.gpgsign=false",
+                "-c",
+                "user.name=test",
+                "-c",
+                "user.email=test@example.com",
+                "commit",
+                "-m",
+                "auto commit",
+            ],
+        )?;
 
         self.last_commit_sha = Some(
             Command::new(workspace, "git")
@@ -125,36 +255,4 @@ impl Repo {
         );
         Ok(())
     }
-
-    fn serve(&self) -> Result<String, Error> {
-        let server =
-            tiny_http::Server::http("localhost:0").map_err(|e| failure::err_msg(e.to_string()))?;
-        let port = server.server_addr().port();
-
-        let base = self.source.path().join(".git");
-        let require_auth = self.require_auth;
-        std::thread::spawn(move || {
-            while let Ok(req) = server.recv() {
-                // Remove the first char from the URL as it's the initial `/`.
-                let url = req.url().split('?').next().unwrap()[1..].to_string();
-                let file = std::fs::File::open(base.join(url));
-
-                if require_auth {
-                    let resp = tiny_http::Response::new_empty(tiny_http::StatusCode(401));
-                    let _ = req.respond(resp.with_header(tiny_http::Header {
-                        field: "WWW-Authenticate".parse().unwrap(),
-                        value: "Basic realm=\"Dummy\"".parse().unwrap(),
-                    }));
-                } else if file.is_ok() {
-                    let resp = tiny_http::Response::from_file(file.unwrap());
-                    let _ = req.respond(resp);
-                } else {
-                    let resp = tiny_http::Response::new_empty(tiny_http::StatusCode(404));
-                    let _ = req.respond(resp);
-                }
-            }
-        });
-
-        Ok(format!("http://localhost:{}", port))
-    }
 }
@@ -1,2 +1,3 @@
+mod build_dirs;
 mod crates_git;
 mod purge_caches;